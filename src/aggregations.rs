@@ -0,0 +1,491 @@
+//! Converts the results of aggregations into the "aggregations" section of a search response
+
+use serde_json::Value as Json;
+use search::aggregations::{AggregationResult, Bucket};
+use search::aggregations::metric::MetricResult;
+use search::aggregations::range::{RangeResult, format_bound};
+use search::aggregations::filter::FiltersResult;
+use search::aggregations::significant_terms::SignificantTermsResult;
+use search::aggregations::composite::{CompositeResult, CompositeValue};
+use search::aggregations::matrix_stats::MatrixStats;
+
+use fetch::field_value_to_json;
+
+
+fn metric_result_to_json(result: &MetricResult) -> Json {
+    match *result {
+        MetricResult::Value(value) => json!({"value": value}),
+        MetricResult::Stats(ref stats) => json!({
+            "count": stats.count,
+            "min": stats.min,
+            "max": stats.max,
+            "avg": stats.avg(),
+            "sum": stats.sum,
+        }),
+        MetricResult::ExtendedStats(ref extended_stats) => {
+            let stats = &extended_stats.stats;
+            let population = extended_stats.std_deviation_population();
+            let sampling = extended_stats.std_deviation_sampling();
+            let bounds = |std_deviation| {
+                match extended_stats.std_deviation_bounds(std_deviation) {
+                    Some((lower, upper)) => (Some(lower), Some(upper)),
+                    None => (None, None),
+                }
+            };
+            let (lower_population, upper_population) = bounds(population);
+            let (lower_sampling, upper_sampling) = bounds(sampling);
+
+            json!({
+                "count": stats.count,
+                "min": stats.min,
+                "max": stats.max,
+                "avg": stats.avg(),
+                "sum": stats.sum,
+                "sum_of_squares": if stats.count > 0 { Some(extended_stats.sum_of_squares) } else { None },
+                "variance": extended_stats.variance_population(),
+                "variance_population": extended_stats.variance_population(),
+                "variance_sampling": extended_stats.variance_sampling(),
+                "std_deviation": population,
+                "std_deviation_population": population,
+                "std_deviation_sampling": sampling,
+                "std_deviation_bounds": {
+                    "upper": upper_population,
+                    "lower": lower_population,
+                    "upper_population": upper_population,
+                    "lower_population": lower_population,
+                    "upper_sampling": upper_sampling,
+                    "lower_sampling": lower_sampling,
+                },
+            })
+        }
+    }
+}
+
+
+fn bucket_to_json(bucket: &Bucket) -> Json {
+    let mut json = aggregation_results_to_json(&bucket.aggregations);
+    json["doc_count"] = json!(bucket.doc_count);
+    json
+}
+
+
+/// Buckets of the filters aggregation are only keyed when the filters were given names
+fn filters_result_to_json(result: &FiltersResult) -> Json {
+    if result.keyed {
+        let mut buckets_json = json!({});
+        for &(ref key, ref bucket) in result.buckets.iter() {
+            buckets_json[key] = bucket_to_json(bucket);
+        }
+
+        json!({"buckets": buckets_json})
+    } else {
+        json!({"buckets": result.buckets.iter().map(|&(_, ref bucket)| bucket_to_json(bucket)).collect::<Vec<_>>()})
+    }
+}
+
+
+fn range_result_to_json(result: &RangeResult) -> Json {
+    let mut buckets = Vec::with_capacity(result.buckets.len());
+
+    for bucket in result.buckets.iter() {
+        let mut bucket_json = aggregation_results_to_json(&bucket.aggregations);
+        bucket_json["doc_count"] = json!(bucket.doc_count);
+
+        if !result.keyed {
+            bucket_json["key"] = json!(bucket.key);
+        }
+
+        if let Some(from) = bucket.from {
+            bucket_json["from"] = json!(from);
+
+            if result.dates {
+                bucket_json["from_as_string"] = json!(format_bound(from, true));
+            }
+        }
+
+        if let Some(to) = bucket.to {
+            bucket_json["to"] = json!(to);
+
+            if result.dates {
+                bucket_json["to_as_string"] = json!(format_bound(to, true));
+            }
+        }
+
+        buckets.push((bucket.key.clone(), bucket_json));
+    }
+
+    if result.keyed {
+        let mut buckets_json = json!({});
+        for (key, bucket_json) in buckets {
+            buckets_json[key] = bucket_json;
+        }
+
+        json!({"buckets": buckets_json})
+    } else {
+        json!({"buckets": buckets.into_iter().map(|(_, bucket_json)| bucket_json).collect::<Vec<_>>()})
+    }
+}
+
+
+fn significant_terms_result_to_json(result: &SignificantTermsResult) -> Json {
+    let buckets = result.buckets.iter().map(|bucket| {
+        let mut bucket_json = aggregation_results_to_json(&bucket.aggregations);
+        bucket_json["key"] = field_value_to_json(&bucket.key);
+        bucket_json["doc_count"] = json!(bucket.doc_count);
+        bucket_json["score"] = json!(bucket.score);
+        bucket_json["bg_count"] = json!(bucket.bg_count);
+        bucket_json
+    }).collect::<Vec<_>>();
+
+    json!({
+        "doc_count": result.doc_count,
+        "bg_count": result.bg_count,
+        "buckets": buckets,
+    })
+}
+
+
+fn composite_key_to_json(key: &[(String, CompositeValue)]) -> Json {
+    let mut json = json!({});
+
+    for &(ref name, ref value) in key.iter() {
+        json[name] = match *value {
+            CompositeValue::Null => Json::Null,
+            CompositeValue::Boolean(value) => json!(value),
+            CompositeValue::Integer(value) => json!(value),
+            CompositeValue::Float(value) => json!(value),
+            CompositeValue::String(ref value) => json!(value),
+        };
+    }
+
+    json
+}
+
+
+fn composite_result_to_json(result: &CompositeResult) -> Json {
+    let buckets = result.buckets.iter().map(|bucket| {
+        let mut bucket_json = aggregation_results_to_json(&bucket.aggregations);
+        bucket_json["key"] = composite_key_to_json(&bucket.key);
+        bucket_json["doc_count"] = json!(bucket.doc_count);
+        bucket_json
+    }).collect::<Vec<_>>();
+
+    let mut json = json!({"buckets": buckets});
+
+    if let Some(ref after_key) = result.after_key {
+        json["after_key"] = composite_key_to_json(after_key);
+    }
+
+    json
+}
+
+
+/// Fields are left out if no document had a value for all of them
+fn matrix_stats_to_json(stats: &MatrixStats) -> Json {
+    if stats.count == 0 {
+        return json!({"doc_count": 0});
+    }
+
+    let fields = stats.names.iter().enumerate().map(|(i, name)| {
+        let mut covariance = json!({});
+        let mut correlation = json!({});
+        for (j, other_name) in stats.names.iter().enumerate() {
+            covariance[other_name] = json!(stats.covariance(i, j));
+            correlation[other_name] = json!(stats.correlation(i, j));
+        }
+
+        json!({
+            "name": name,
+            "count": stats.count,
+            "mean": stats.means[i],
+            "variance": stats.variance(i),
+            "skewness": stats.skewness(i),
+            "kurtosis": stats.kurtosis(i),
+            "covariance": covariance,
+            "correlation": correlation,
+        })
+    }).collect::<Vec<_>>();
+
+    json!({
+        "doc_count": stats.count,
+        "fields": fields,
+    })
+}
+
+
+/// Converts a list of buckets with string keys into an array, with the key in each bucket
+fn keyed_buckets_to_json(buckets: &[(String, Bucket)]) -> Json {
+    let buckets = buckets.iter().map(|&(ref key, ref bucket)| {
+        let mut bucket_json = bucket_to_json(bucket);
+        bucket_json["key"] = json!(key);
+        bucket_json
+    }).collect::<Vec<_>>();
+
+    json!({"buckets": buckets})
+}
+
+
+pub fn aggregation_result_to_json(result: &AggregationResult) -> Json {
+    match *result {
+        AggregationResult::Metric(ref result) => metric_result_to_json(result),
+        AggregationResult::Range(ref result) => range_result_to_json(result),
+        AggregationResult::SingleBucket(ref bucket) => bucket_to_json(bucket),
+        AggregationResult::Filters(ref result) => filters_result_to_json(result),
+        AggregationResult::AdjacencyMatrix(ref result) => keyed_buckets_to_json(&result.buckets),
+        AggregationResult::SignificantTerms(ref result) => significant_terms_result_to_json(result),
+        AggregationResult::Composite(ref result) => composite_result_to_json(result),
+        AggregationResult::GeohashGrid(ref result) => keyed_buckets_to_json(&result.buckets),
+        AggregationResult::MatrixStats(ref stats) => matrix_stats_to_json(stats),
+    }
+}
+
+
+/// Converts a list of named results into an object, keyed by name
+pub fn aggregation_results_to_json(results: &[(String, AggregationResult)]) -> Json {
+    let mut json = json!({});
+
+    for &(ref name, ref result) in results.iter() {
+        json[name] = aggregation_result_to_json(result);
+    }
+
+    json
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::aggregations::{AggregationResult, Bucket};
+    use search::aggregations::metric::{MetricResult, Stats, ExtendedStats};
+    use search::aggregations::range::{RangeResult, RangeBucket};
+    use search::aggregations::filter::FiltersResult;
+    use search::aggregations::significant_terms::{SignificantTermsResult, SignificantTermsBucket};
+    use search::aggregations::composite::{CompositeResult, CompositeBucket, CompositeValue};
+    use search::aggregations::geo::GeohashGridResult;
+    use search::aggregations::matrix_stats::{MatrixStatsAggregation, MatrixStatsAggregator, MatrixStatsField};
+    use search::document::FieldValue;
+    use search::schema::FieldId;
+
+    use super::{aggregation_result_to_json, aggregation_results_to_json};
+
+    #[test]
+    fn test_aggregation_results_to_json() {
+        let results = vec![
+            ("min_price".to_string(), AggregationResult::Metric(MetricResult::Value(None))),
+            ("price_stats".to_string(), AggregationResult::Metric(MetricResult::Stats(Stats {
+                count: 2,
+                sum: 5.0,
+                min: Some(1.0),
+                max: Some(4.0),
+            }))),
+        ];
+
+        assert_eq!(aggregation_results_to_json(&results), json!({
+            "min_price": {"value": null},
+            "price_stats": {"count": 2, "min": 1.0, "max": 4.0, "avg": 2.5, "sum": 5.0},
+        }));
+    }
+
+    #[test]
+    fn test_extended_stats_to_json() {
+        let result = AggregationResult::Metric(MetricResult::ExtendedStats(ExtendedStats {
+            stats: Stats {
+                count: 2,
+                sum: 2.0,
+                min: Some(0.0),
+                max: Some(2.0),
+            },
+            sum_of_squares: 4.0,
+            sigma: 2.0,
+        }));
+
+        let sqrt_2 = 2.0f64.sqrt();
+        assert_eq!(aggregation_result_to_json(&result), json!({
+            "count": 2,
+            "min": 0.0,
+            "max": 2.0,
+            "avg": 1.0,
+            "sum": 2.0,
+            "sum_of_squares": 4.0,
+            "variance": 1.0,
+            "variance_population": 1.0,
+            "variance_sampling": 2.0,
+            "std_deviation": 1.0,
+            "std_deviation_population": 1.0,
+            "std_deviation_sampling": sqrt_2,
+            "std_deviation_bounds": {
+                "upper": 3.0,
+                "lower": -1.0,
+                "upper_population": 3.0,
+                "lower_population": -1.0,
+                "upper_sampling": 1.0 + 2.0 * sqrt_2,
+                "lower_sampling": 1.0 - 2.0 * sqrt_2,
+            },
+        }));
+    }
+
+    #[test]
+    fn test_range_result_to_json() {
+        let mut result = RangeResult {
+            buckets: vec![
+                RangeBucket {
+                    key: "*-1.0".to_string(),
+                    from: None,
+                    to: Some(1.0),
+                    doc_count: 3,
+                    aggregations: vec![
+                        ("max_price".to_string(), AggregationResult::Metric(MetricResult::Value(Some(0.5)))),
+                    ],
+                },
+            ],
+            dates: false,
+            keyed: false,
+        };
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Range(result.clone())), json!({
+            "buckets": [
+                {"key": "*-1.0", "to": 1.0, "doc_count": 3, "max_price": {"value": 0.5}},
+            ],
+        }));
+
+        // Dates are also returned as strings
+        result.dates = true;
+        result.keyed = true;
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Range(result)), json!({
+            "buckets": {
+                "*-1.0": {"to": 1.0, "to_as_string": "1970-01-01T00:00:00.001Z", "doc_count": 3, "max_price": {"value": 0.5}},
+            },
+        }));
+    }
+
+    #[test]
+    fn test_filters_result_to_json() {
+        let bucket = |doc_count| Bucket {
+            doc_count: doc_count,
+            aggregations: vec![],
+        };
+        let mut result = FiltersResult {
+            buckets: vec![("errors".to_string(), bucket(2)), ("_other_".to_string(), bucket(5))],
+            keyed: true,
+        };
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Filters(result.clone())), json!({
+            "buckets": {
+                "errors": {"doc_count": 2},
+                "_other_": {"doc_count": 5},
+            },
+        }));
+
+        // Anonymous filters are returned in order, without keys
+        result.keyed = false;
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Filters(result)), json!({
+            "buckets": [{"doc_count": 2}, {"doc_count": 5}],
+        }));
+    }
+
+    #[test]
+    fn test_significant_terms_result_to_json() {
+        let result = SignificantTermsResult {
+            doc_count: 6,
+            bg_count: 100,
+            buckets: vec![
+                SignificantTermsBucket {
+                    key: FieldValue::String("rare".to_string()),
+                    doc_count: 3,
+                    bg_count: 10,
+                    score: 2.0,
+                    aggregations: vec![],
+                },
+            ],
+        };
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::SignificantTerms(result)), json!({
+            "doc_count": 6,
+            "bg_count": 100,
+            "buckets": [{"key": "rare", "doc_count": 3, "score": 2.0, "bg_count": 10}],
+        }));
+    }
+
+    #[test]
+    fn test_composite_result_to_json() {
+        let key = vec![("tag".to_string(), CompositeValue::Null), ("day".to_string(), CompositeValue::Integer(86400000))];
+        let result = CompositeResult {
+            buckets: vec![
+                CompositeBucket {
+                    key: key.clone(),
+                    doc_count: 4,
+                    aggregations: vec![],
+                },
+            ],
+            after_key: Some(key),
+        };
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Composite(result)), json!({
+            "after_key": {"tag": null, "day": 86400000},
+            "buckets": [{"key": {"tag": null, "day": 86400000}, "doc_count": 4}],
+        }));
+
+        // There's no after key once there are no more buckets
+        let result = CompositeResult {
+            buckets: vec![],
+            after_key: None,
+        };
+        assert_eq!(aggregation_result_to_json(&AggregationResult::Composite(result)), json!({"buckets": []}));
+    }
+
+    #[test]
+    fn test_geohash_grid_result_to_json() {
+        let result = GeohashGridResult {
+            buckets: vec![("gcp".to_string(), Bucket {
+                doc_count: 2,
+                aggregations: vec![],
+            })],
+        };
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::GeohashGrid(result)), json!({
+            "buckets": [{"key": "gcp", "doc_count": 2}],
+        }));
+    }
+
+    #[test]
+    fn test_matrix_stats_to_json() {
+        let aggregation = MatrixStatsAggregation {
+            fields: vec![
+                MatrixStatsField { name: "a".to_string(), field: FieldId(1), missing: None },
+                MatrixStatsField { name: "b".to_string(), field: FieldId(2), missing: None },
+            ],
+        };
+
+        let mut aggregator = MatrixStatsAggregator::new(&aggregation);
+        assert_eq!(aggregation_result_to_json(&AggregationResult::MatrixStats(MatrixStatsAggregator::new(&aggregation).into_result())), json!({"doc_count": 0}));
+
+        for &(a, b) in [(1, 2), (3, 6)].iter() {
+            aggregator.collect(&mut |field_id| Some(FieldValue::Integer(if field_id == FieldId(1) { a } else { b })));
+        }
+
+        assert_eq!(aggregation_result_to_json(&AggregationResult::MatrixStats(aggregator.into_result())), json!({
+            "doc_count": 2,
+            "fields": [
+                {
+                    "name": "a",
+                    "count": 2,
+                    "mean": 2.0,
+                    "variance": 2.0,
+                    "skewness": 0.0,
+                    "kurtosis": 1.0,
+                    "covariance": {"a": 2.0, "b": 4.0},
+                    "correlation": {"a": 1.0, "b": 1.0},
+                },
+                {
+                    "name": "b",
+                    "count": 2,
+                    "mean": 4.0,
+                    "variance": 8.0,
+                    "skewness": 0.0,
+                    "kurtosis": 1.0,
+                    "covariance": {"a": 4.0, "b": 8.0},
+                    "correlation": {"a": 1.0, "b": 1.0},
+                },
+            ],
+        }));
+    }
+}
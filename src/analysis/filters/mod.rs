@@ -2,7 +2,11 @@ pub mod lowercase;
 pub mod ngram;
 pub mod asciifolding;
 
+use std::fmt;
+use std::sync::Arc;
+
 use serde::{Serialize, Serializer};
+use serde_json::Value as Json;
 use search::Token;
 
 use analysis::ngram_generator::Edge;
@@ -11,6 +15,35 @@ use analysis::filters::ngram::NGramFilter;
 use analysis::filters::asciifolding::ASCIIFoldingFilter;
 
 
+/// A token filter added by a plugin (see `plugins::AnalyzerProvider`)
+pub trait Filter: Send + Sync {
+    fn filter<'a>(&self, input: Box<Iterator<Item=Token> + 'a>) -> Box<Iterator<Item=Token> + 'a>;
+}
+
+
+/// A plugin's token filter, along with the settings it was defined with
+#[derive(Clone)]
+pub struct PluginFilter {
+    /// Includes the "type", so the filter can be built again from them
+    pub settings: Json,
+    pub filter: Arc<Filter>,
+}
+
+
+impl fmt::Debug for PluginFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PluginFilter({})", self.settings)
+    }
+}
+
+
+impl PartialEq for PluginFilter {
+    fn eq(&self, other: &PluginFilter) -> bool {
+        self.settings == other.settings
+    }
+}
+
+
 /// Defines a token filter
 ///
 /// You can use this to define a token filter before having to bind it to any data
@@ -45,6 +78,7 @@ pub enum FilterSpec {
         edge: Edge,
     },
     ASCIIFolding,
+    Plugin(PluginFilter),
 }
 
 
@@ -60,6 +94,9 @@ impl FilterSpec {
             FilterSpec::ASCIIFolding => {
                 Box::new(ASCIIFoldingFilter::new(input))
             }
+            FilterSpec::Plugin(ref plugin) => {
+                plugin.filter.filter(input)
+            }
         }
     }
 }
@@ -105,6 +142,9 @@ impl Serialize for FilterSpec {
                     "type": "asciifolding",
                 })
             }
+            FilterSpec::Plugin(ref plugin) => {
+                plugin.settings.clone()
+            }
         };
 
         json.serialize(serializer)
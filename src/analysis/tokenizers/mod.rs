@@ -1,7 +1,11 @@
 pub mod standard;
 pub mod ngram;
 
+use std::fmt;
+use std::sync::Arc;
+
 use serde::{Serialize, Serializer};
+use serde_json::Value as Json;
 use search::token::Token;
 
 use analysis::ngram_generator::Edge;
@@ -10,6 +14,35 @@ use analysis::tokenizers::standard::StandardTokenizer;
 use analysis::tokenizers::ngram::NGramTokenizer;
 
 
+/// A tokenizer added by a plugin (see `plugins::AnalyzerProvider`)
+pub trait Tokenizer: Send + Sync {
+    fn tokenize<'a>(&self, input: &'a str) -> Box<Iterator<Item=Token> + 'a>;
+}
+
+
+/// A plugin's tokenizer, along with the settings it was defined with
+#[derive(Clone)]
+pub struct PluginTokenizer {
+    /// Includes the "type", so the tokenizer can be built again from them
+    pub settings: Json,
+    pub tokenizer: Arc<Tokenizer>,
+}
+
+
+impl fmt::Debug for PluginTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PluginTokenizer({})", self.settings)
+    }
+}
+
+
+impl PartialEq for PluginTokenizer {
+    fn eq(&self, other: &PluginTokenizer) -> bool {
+        self.settings == other.settings
+    }
+}
+
+
 /// Defines a tokenizer
 ///
 /// You can use this to define a tokenizer before having to bind it to any data
@@ -38,7 +71,8 @@ pub enum TokenizerSpec {
         min_size: usize,
         max_size: usize,
         edge: Edge,
-    }
+    },
+    Plugin(PluginTokenizer),
 }
 
 
@@ -54,6 +88,9 @@ impl TokenizerSpec {
             TokenizerSpec::NGram{min_size, max_size, edge} => {
                 Box::new(NGramTokenizer::new(input, min_size, max_size, edge))
             }
+            TokenizerSpec::Plugin(ref plugin) => {
+                plugin.tokenizer.tokenize(input)
+            }
         }
     }
 }
@@ -98,6 +135,9 @@ impl Serialize for TokenizerSpec {
                     }
                 }
             }
+            TokenizerSpec::Plugin(ref plugin) => {
+                plugin.settings.clone()
+            }
         };
 
         json.serialize(serializer)
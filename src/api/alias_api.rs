@@ -1,97 +1,396 @@
-use std::collections::HashMap;
+use std::io::Read;
+use std::collections::{HashMap, HashSet};
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::router::Router;
-use api::utils::json_response;
+use serde_json;
+use serde_json::{Map, Value as Json};
+use slog::Logger;
 
+use cluster::metadata::{ClusterMetadata, IndexRef, IndicesOptions};
+use index::metadata::alias::AliasMetadata;
+use index::metadata::parse::alias::parse as parse_alias;
+use security::roles::{Permissions, IndexPrivilege};
 
-pub fn view_get_global_alias(req: &mut Request) -> IronResult<Response> {
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, forbidden_response};
+use api::security_api::{get_permissions, missing_index_privilege_message};
+
+
+/// A change to an alias on one index
+#[derive(Debug)]
+enum AliasAction {
+    Add {
+        index_ref: IndexRef,
+        alias_name: String,
+        alias: AliasMetadata,
+    },
+    Remove {
+        index_ref: IndexRef,
+        alias_name: String,
+    },
+}
+
+
+impl AliasAction {
+    fn alias_name(&self) -> &str {
+        match *self {
+            AliasAction::Add { ref alias_name, .. } | AliasAction::Remove { ref alias_name, .. } => alias_name,
+        }
+    }
+}
+
+
+fn bad_request(message: String) -> Response {
+    json_response(StatusCode::BAD_REQUEST, json!({"message": message}))
+}
+
+
+/// Finds the indices an index name, alias or wildcard pattern refers to, including closed ones
+fn find_indices(cluster_metadata: &ClusterMetadata, selector: &str) -> Result<Vec<IndexRef>, Response> {
+    let options = IndicesOptions { include_closed: true, ..IndicesOptions::default() };
+
+    match cluster_metadata.resolve_indices(selector, &options) {
+        Ok(indices) => Ok(indices.into_iter().map(|(index_ref, _)| index_ref).collect()),
+        Err((name, e)) => Err(resolve_error_response(&name, e)),
+    }
+}
+
+
+/// Reads a value that may be either a string or a list of strings
+fn read_names(key: &str, value: &Json) -> Result<Vec<String>, Response> {
+    match *value {
+        Json::String(ref name) => Ok(vec![name.clone()]),
+        Json::Array(ref names) => {
+            names.iter()
+                .map(|name| name.as_str().map(|name| name.to_string()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| bad_request(format!("{} must be a string or a list of strings", key)))
+        }
+        _ => Err(bad_request(format!("{} must be a string or a list of strings", key))),
+    }
+}
+
+
+/// Parses an "add" or "remove" action, producing an action for each index/alias pair
+fn parse_alias_action(cluster_metadata: &ClusterMetadata, permissions: &Permissions, action_type: &str, data: &Json) -> Result<Vec<AliasAction>, Response> {
+    let data = data.as_object().ok_or_else(|| bad_request(format!("{} action must be an object", action_type)))?;
+    let mut index_selectors = Vec::new();
+    let mut alias_names = Vec::new();
+    let mut alias_data = Map::new();
+
+    for (key, value) in data.iter() {
+        match (action_type, key.as_ref()) {
+            (_, "index") | (_, "indices") => index_selectors.extend(read_names(key, value)?),
+            (_, "alias") | (_, "aliases") => alias_names.extend(read_names(key, value)?),
+            ("add", "filter") | ("add", "is_write_index") => {
+                alias_data.insert(key.clone(), value.clone());
+            }
+            _ => return Err(bad_request(format!("Unrecognised key in {} action: {:?}", action_type, key))),
+        }
+    }
+
+    if index_selectors.is_empty() {
+        return Err(bad_request(format!("{} action requires an index", action_type)));
+    }
+
+    if alias_names.is_empty() {
+        return Err(bad_request(format!("{} action requires an alias", action_type)));
+    }
+
+    let alias = parse_alias(&Json::Object(alias_data)).map_err(|e| bad_request(format!("Alias error: {:?}", e)))?;
+
+    let mut actions = Vec::new();
+    for index_selector in index_selectors.iter() {
+        if !permissions.allows_index(index_selector, IndexPrivilege::Admin) {
+            return Err(forbidden_response(missing_index_privilege_message(index_selector, IndexPrivilege::Admin)));
+        }
+
+        for index_ref in find_indices(cluster_metadata, index_selector)? {
+            for alias_name in alias_names.iter() {
+                actions.push(match action_type {
+                    "add" => AliasAction::Add { index_ref: index_ref, alias_name: alias_name.clone(), alias: alias.clone() },
+                    _ => AliasAction::Remove { index_ref: index_ref, alias_name: alias_name.clone() },
+                });
+            }
+        }
+    }
+
+    Ok(actions)
+}
+
+
+/// Makes sure a set of actions can all be applied
+///
+/// Aliases can't share a name with an index, can only be removed from indices they are on,
+/// and must not end up with more than one write index.
+fn check_alias_actions(cluster_metadata: &ClusterMetadata, actions: &[AliasAction]) -> Result<(), Response> {
+    // Work out which indices each alias will end up on, and whether each is its write index
+    let mut aliases: HashMap<&str, HashMap<IndexRef, Option<bool>>> = HashMap::new();
+
+    for action in actions.iter() {
+        let alias_name = action.alias_name();
+        let indices = aliases.entry(alias_name).or_insert_with(|| {
+            if !cluster_metadata.names.is_alias(alias_name) {
+                return HashMap::new();
+            }
+
+            cluster_metadata.names.find(alias_name).into_iter()
+                .map(|index_ref| (index_ref, cluster_metadata.get_alias_metadata(&index_ref, alias_name).and_then(|alias| alias.is_write_index)))
+                .collect()
+        });
+
+        match *action {
+            AliasAction::Add { index_ref, ref alias, .. } => {
+                if alias_name.is_empty() || cluster_metadata.names.find_canonical(alias_name).is_some() {
+                    return Err(bad_request(format!("Invalid alias name {:?}, an index exists with the same name", alias_name)));
+                }
+
+                indices.insert(index_ref, alias.is_write_index);
+            }
+            AliasAction::Remove { index_ref, .. } => {
+                if indices.remove(&index_ref).is_none() {
+                    return Err(json_response(StatusCode::NOT_FOUND, json!({"message": format!("Alias {:?} not found", alias_name)})));
+                }
+            }
+        }
+    }
+
+    for (alias_name, indices) in aliases {
+        if indices.values().filter(|is_write_index| **is_write_index == Some(true)).count() > 1 {
+            return Err(bad_request(format!("Alias {:?} has more than one write index", alias_name)));
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Applies actions that have been checked with `check_alias_actions`, saving the metadata
+/// of every index that was changed
+fn apply_alias_actions(log: &Logger, cluster_metadata: &mut ClusterMetadata, actions: Vec<AliasAction>) {
+    let mut changed_indices = HashSet::new();
+
+    for action in actions {
+        match action {
+            AliasAction::Add { index_ref, alias_name, alias } => {
+                cluster_metadata.names.add_alias_index(alias_name.clone(), index_ref).unwrap();
+                cluster_metadata.with_index_metadata_mut(&index_ref, |metadata| metadata.aliases.insert(alias_name.clone(), alias));
+
+                info!(log, "added alias"; "index" => cluster_metadata.index_name(&index_ref), "alias" => alias_name);
+                changed_indices.insert(index_ref);
+            }
+            AliasAction::Remove { index_ref, alias_name } => {
+                cluster_metadata.names.delete_alias(&alias_name, index_ref).unwrap();
+                cluster_metadata.with_index_metadata_mut(&index_ref, |metadata| metadata.aliases.remove(&alias_name));
+
+                info!(log, "removed alias"; "index" => cluster_metadata.index_name(&index_ref), "alias" => alias_name);
+                changed_indices.insert(index_ref);
+            }
+        }
+    }
+
+    for index_ref in changed_indices {
+        if let Err(e) = cluster_metadata.save_index_metadata(&index_ref) {
+            error!(log, "failed to save index metadata"; "index" => cluster_metadata.index_name(&index_ref), "error" => String::from(e));
+        }
+    }
+}
+
+
+/// Lists the aliases of an index, in the format used by the get alias APIs
+fn index_aliases_to_json<F: Fn(&str) -> bool>(cluster_metadata: &ClusterMetadata, index_ref: &IndexRef, include_alias: F) -> Json {
+    let aliases = cluster_metadata.with_index_metadata(index_ref, |metadata| {
+        metadata.aliases.iter()
+            .filter(|&(alias_name, _)| include_alias(alias_name))
+            .map(|(alias_name, alias)| (alias_name.clone(), alias.to_json()))
+            .collect::<Map<_, _>>()
+    }).unwrap_or_default();
+
+    json!({"aliases": aliases})
+}
+
+
+pub fn view_post_aliases(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let permissions = get_permissions(req);
+    let data = match json_from_request_body!(req) {
+        Some(data) => data,
+        None => return Ok(bad_request("No data".to_string())),
+    };
+
+    let action_list = match data.get("actions").and_then(|actions| actions.as_array()) {
+        Some(action_list) => action_list,
+        None => return Ok(bad_request("actions must be a list".to_string())),
+    };
+
+    // Lock cluster metadata
+    let mut cluster_metadata = system.metadata.write().unwrap();
+
+    let mut actions = Vec::new();
+    for action in action_list.iter() {
+        let action = match action.as_object() {
+            Some(action) if action.len() == 1 => action,
+            _ => return Ok(bad_request("Each action must be an object with a single key".to_string())),
+        };
+
+        for (action_type, action_data) in action.iter() {
+            match action_type.as_ref() {
+                "add" | "remove" => {
+                    match parse_alias_action(&cluster_metadata, &permissions, action_type, action_data) {
+                        Ok(parsed_actions) => actions.extend(parsed_actions),
+                        Err(response) => return Ok(response),
+                    }
+                }
+                _ => return Ok(bad_request(format!("Unrecognised action: {:?}", action_type))),
+            }
+        }
+    }
+
+    if let Err(response) = check_alias_actions(&cluster_metadata, &actions) {
+        return Ok(response);
+    }
+
+    apply_alias_actions(&req.log, &mut cluster_metadata, actions);
+
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+}
+
+
+pub fn view_get_all_aliases(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
-    let ref alias_name = read_path_parameter!(req, "alias").unwrap_or("");
 
     // Lock cluster metadata
     let cluster_metadata = system.metadata.read().unwrap();
 
-    // Find alias
-    let mut found_aliases = HashMap::new();
+    let mut response = json!({});
+    for index_ref in cluster_metadata.indices.keys().chain(cluster_metadata.closed_indices.keys()) {
+        if let Some(index_name) = cluster_metadata.index_name(index_ref) {
+            response[index_name] = index_aliases_to_json(&cluster_metadata, index_ref, |_| true);
+        }
+    }
 
-    for index_ref in cluster_metadata.names.find(alias_name) {
-        let index = match cluster_metadata.indices.get(&index_ref) {
-            Some(index) => index,
-            None => continue,
-        };
+    Ok(json_response(StatusCode::OK, response))
+}
 
-        let mut inner_map = HashMap::new();
-        let mut inner_inner_map = HashMap::new();
-        inner_inner_map.insert(alias_name, HashMap::<String, String>::new());
-        inner_map.insert("aliases".to_owned(), inner_inner_map);
-        found_aliases.insert(index.canonical_name().clone(), inner_map);
+
+pub fn view_get_global_alias(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref alias_name = read_path_parameter!(req, "alias").unwrap_or("");
+
+    // Lock cluster metadata
+    let cluster_metadata = system.metadata.read().unwrap();
+
+    if !cluster_metadata.names.is_alias(alias_name) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({})));
     }
 
-    if !found_aliases.is_empty() {
-        return Ok(json_response(status::Ok, json!(found_aliases)));
-    } else {
-        return Ok(json_response(status::NotFound, json!({})));
+    let mut response = json!({});
+    for index_ref in cluster_metadata.names.find(alias_name) {
+        if let Some(index_name) = cluster_metadata.index_name(&index_ref) {
+            response[index_name] = index_aliases_to_json(&cluster_metadata, &index_ref, |name| name == *alias_name);
+        }
     }
+
+    Ok(json_response(StatusCode::OK, response))
 }
 
 
-pub fn view_get_alias_list(_req: &mut Request) -> IronResult<Response> {
-    // let ref system = get_system!(req);
-    // let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+pub fn view_get_alias_list(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_selector = read_path_parameter!(req, "index").unwrap_or("");
 
-    // TODO
+    // Lock cluster metadata
+    let cluster_metadata = system.metadata.read().unwrap();
+
+    let index_refs = match find_indices(&cluster_metadata, index_selector) {
+        Ok(index_refs) => index_refs,
+        Err(response) => return Ok(response),
+    };
 
-    return Ok(json_response(status::Ok, json!({})));
+    let mut response = json!({});
+    for index_ref in index_refs {
+        if let Some(index_name) = cluster_metadata.index_name(&index_ref) {
+            response[index_name] = index_aliases_to_json(&cluster_metadata, &index_ref, |_| true);
+        }
+    }
+
+    Ok(json_response(StatusCode::OK, response))
 }
 
-pub fn view_get_alias(req: &mut Request) -> IronResult<Response> {
+
+pub fn view_get_alias(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
-    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let ref index_selector = read_path_parameter!(req, "index").unwrap_or("");
     let ref alias_name = read_path_parameter!(req, "alias").unwrap_or("");
 
     // Lock cluster metadata
     let cluster_metadata = system.metadata.read().unwrap();
 
-    // Get index
-    let index_ref = match cluster_metadata.names.find_canonical(index_name) {
-        Some(index_ref) => index_ref,
-        None => return Ok(json_response(status::NotFound, json!({}))),
+    let index_refs = match find_indices(&cluster_metadata, index_selector) {
+        Ok(index_refs) => index_refs,
+        Err(response) => return Ok(response),
     };
 
     // Find alias
-    if cluster_metadata.names.iter_index_aliases(index_ref).any(|name| &name == alias_name) {
-        return Ok(json_response(status::Ok, json!({})));
-    } else {
-        return Ok(json_response(status::NotFound, json!({})));
+    let mut response = json!({});
+    for index_ref in index_refs {
+        if cluster_metadata.get_alias_metadata(&index_ref, alias_name).is_none() {
+            continue;
+        }
+
+        if let Some(index_name) = cluster_metadata.index_name(&index_ref) {
+            response[index_name] = index_aliases_to_json(&cluster_metadata, &index_ref, |name| name == *alias_name);
+        }
+    }
+
+    if response.as_object().map_or(true, |indices| indices.is_empty()) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({})));
     }
+
+    Ok(json_response(StatusCode::OK, response))
 }
 
 
-pub fn view_put_alias(req: &mut Request) -> IronResult<Response> {
+/// Runs a single "add" or "remove" action on the indices and alias named in the URL
+fn run_path_alias_action(req: &mut Request, action_type: &str, mut data: Map<String, Json>) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_selector = read_path_parameter!(req, "index").unwrap_or("");
     let ref alias_name = read_path_parameter!(req, "alias").unwrap_or("");
+    let permissions = get_permissions(req);
+
+    data.insert("index".to_string(), json!(index_selector));
+    data.insert("alias".to_string(), json!(alias_name));
 
     // Lock cluster metadata
     let mut cluster_metadata = system.metadata.write().unwrap();
 
-    // Insert alias into names registry
-    let index_refs = cluster_metadata.names.find(*index_selector);
-    match cluster_metadata.names.insert_or_replace_alias(alias_name.to_string(), index_refs) {
-        Ok(true) => {
-            info!(system.log, "created alias"; "index" => *index_selector, "alias" => *alias_name);
-        }
-        Ok(false) => {
-            info!(system.log, "updated alias"; "index" => *index_selector, "alias" => *alias_name);
-        }
-        Err(_) => {
-            // TODO
-            return Ok(json_response(status::Ok, json!({"acknowledged": false})));
-        }
+    let actions = match parse_alias_action(&cluster_metadata, &permissions, action_type, &Json::Object(data)) {
+        Ok(actions) => actions,
+        Err(response) => return Ok(response),
+    };
+
+    if let Err(response) = check_alias_actions(&cluster_metadata, &actions) {
+        return Ok(response);
     }
 
-    Ok(json_response(status::Ok, json!({"acknowledged": true})))
+    apply_alias_actions(&req.log, &mut cluster_metadata, actions);
+
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+}
+
+
+pub fn view_put_alias(req: &mut Request) -> ApiResult<Response> {
+    // The body can set the alias's filter and is_write_index
+    let data = match json_from_request_body!(req) {
+        Some(Json::Object(data)) => data,
+        Some(_) => return Ok(bad_request("Request body must be an object".to_string())),
+        None => Map::new(),
+    };
+
+    run_path_alias_action(req, "add", data)
+}
+
+
+pub fn view_delete_alias(req: &mut Request) -> ApiResult<Response> {
+    run_path_alias_action(req, "remove", Map::new())
 }
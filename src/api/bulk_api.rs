@@ -1,188 +1,914 @@
-use std::io::Read;
-use std::collections::HashMap;
+use std::io::{self, Read, BufRead, BufReader};
+use std::cmp;
+use std::collections::{HashSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use serde_json;
+use serde_json::{Map, Value as Json};
+use slog::Logger;
+
+use search::profile::duration_to_nanos;
+use search::backends::rocksdb::{DocumentVersion, WriteCondition, DocumentInsertError, DocumentDeleteError};
+use document::{DocumentSource, generate_doc_id};
+use index::Index;
+use index::metadata::settings::ActiveShardCount;
+use cluster::metadata::{ClusterMetadata, ResolveError};
+use cluster::state::DiscoveryNode;
+use cluster::transport::{TransportRequest, TransportResponse};
+use system::System;
+use update::{UpdateRequest, UpdateError};
+use replication::{ReplicaOperation, check_active_shards, replicate};
+use slowlog;
+use security::roles::{Permissions, IndexPrivilege};
+
+use hyper::{Method, StatusCode};
+use api::request::{Request, Response, ApiResult, OPAQUE_ID_HEADER};
+use api::utils::{json_response, get_refresh_policy, get_wait_for_active_shards};
+use api::security_api::{get_permissions, missing_index_privilege_message, document_queries_update_message};
+use api::cluster_api::{FORWARDED_HEADER, FORWARD_TIMEOUT};
+
+
+/// How many actions are run each time the cluster metadata is locked
+const BATCH_SIZE: usize = 1000;
+
+
+/// Sets an item of the response to a failure
+fn item_error(item: &mut Json, status: u16, error_type: &str, reason: String) {
+    item["status"] = json!(status);
+    item["error"] = json!({
+        "type": error_type,
+        "reason": reason,
+    });
+}
 
-use document::DocumentSource;
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::utils::{json_response};
-use api::router::Router;
+/// Sets an item of the response to a failure to find the index it writes to
+fn resolve_error_item(item: &mut Json, doc_index: &str, error: ResolveError) {
+    let (status, error_type, reason) = match error {
+        ResolveError::NotFound => (404, "index_not_found_exception", "no such index"),
+        ResolveError::Closed => (400, "index_closed_exception", "closed"),
+        ResolveError::MultipleIndices | ResolveError::NoWriteIndex => (400, "illegal_argument_exception", "no write index is defined for alias"),
+        ResolveError::IsAlias => (400, "illegal_argument_exception", "is an alias, give the name of an index instead"),
+    };
 
+    item_error(item, status, error_type, format!("[{}] {}", doc_index, reason));
+}
 
-pub fn view_post_bulk(req: &mut Request) -> IronResult<Response> {
-    let ref system = get_system!(req);
 
-    // Lock cluster metedata
-    let cluster_metadata = system.metadata.read().unwrap();
+/// Adds the version of the written document to an item of the response
+fn item_version(item: &mut Json, version: &DocumentVersion) {
+    item["_version"] = json!(version.version);
+    item["_seq_no"] = json!(version.seq_no);
+    item["_primary_term"] = json!(version.primary_term);
+}
 
-    // Load data from body
-    let mut payload = String::new();
-    req.body.read_to_string(&mut payload).unwrap();
 
-    let mut items = Vec::new();
+/// Reads the `version`, `if_seq_no` and `if_primary_term` parameters of an action
+fn get_write_condition(action_params: &Map<String, Json>) -> Result<Option<WriteCondition>, String> {
+    let get = |key: &str| {
+        match action_params.get(key) {
+            Some(value) => value.as_u64().map(Some).ok_or_else(|| format!("{} must be a positive integer", key)),
+            None => Ok(None),
+        }
+    };
+
+    match (get("version")?, get("if_seq_no")?, get("if_primary_term")?) {
+        (None, None, None) => Ok(None),
+        (Some(version), None, None) => Ok(Some(WriteCondition::Version(version))),
+        (None, Some(seq_no), Some(primary_term)) => {
+            Ok(Some(WriteCondition::SeqNo {
+                seq_no: seq_no,
+                primary_term: primary_term,
+            }))
+        }
+        (None, _, _) => Err("if_seq_no and if_primary_term must be used together".to_string()),
+        (Some(_), _, _) => Err("version can't be used with if_seq_no and if_primary_term".to_string()),
+    }
+}
 
-    // Iterate
-    let mut payload_lines = payload.split('\n');
-    loop {
-        let action_line = payload_lines.next();
-
-        // Check if end of input
-        if action_line == None || action_line == Some("") {
-            break;
-        }
-
-        // Parse action line
-        let action_json = parse_json!(&action_line.unwrap());
-
-        // Check action
-        // Action should be an object with only one key, the key name indicates the action and
-        // the value is the parameters for that action
-        let action_name = action_json.as_object().unwrap().keys().nth(0).unwrap();
-        let action_params = action_json.as_object()
-                                       .unwrap()
-                                       .get(action_name)
-                                       .unwrap()
-                                       .as_object()
-                                       .unwrap();
-
-        let doc_id = action_params.get("_id").unwrap().as_str().unwrap();
-        let doc_type = action_params.get("_type").unwrap().as_str().unwrap();
-        let doc_index = action_params.get("_index").unwrap().as_str().unwrap();
-
-        match action_name.as_ref() {
-            "index" => {
-                let doc_line = payload_lines.next();
-                let doc_json = parse_json!(&doc_line.unwrap());;
-
-                // Find index
-                let index = get_index_or_404!(cluster_metadata, doc_index);
-                let index_metadata = index.metadata.read().unwrap();
-
-                let doc = {
-                    // Find mapping
-                    let mapping = match index_metadata.mappings.get(doc_type) {
-                        Some(mapping) => mapping,
-                        None => {
-                            return Ok(json_response(status::NotFound, json!({"message": "Mapping not found"})));
-                        }
-                    };
 
-                    // Create document
-                    let document_source = DocumentSource {
-                        key: doc_id,
-                        data: doc_json.as_object().unwrap(),
-                    };
-                    document_source.prepare(mapping).unwrap()
-                };
+/// The index and type from the URL, used by actions that don't give their own
+#[derive(Debug, Clone, Copy, Default)]
+struct BulkDefaults<'a> {
+    index: Option<&'a str>,
+    doc_type: Option<&'a str>,
+}
 
-                index.store.insert_or_update_document(&doc).unwrap();
 
-                // Insert into "items" array
-                let mut item = HashMap::new();
-                // TODO: "create" may not always be right
-                item.insert("create", action_params.clone());
-                items.push(item);
-            }
-            _ => {
-                warn!(system.log, "unrecognised action! {}", action_name);
+/// What the actions of a bulk request have in common
+struct BulkContext<'a> {
+    system: &'a System,
+    log: &'a Logger,
+    permissions: &'a Permissions,
+    defaults: BulkDefaults<'a>,
+    wait_for_active_shards: Option<ActiveShardCount>,
+}
+
+
+/// Starts the item for an action, with whichever of `_index`, `_type` and `_id` it was given
+fn new_item(action_params: &Map<String, Json>, defaults: BulkDefaults) -> Json {
+    let mut item = json!({});
+    if let Some(index) = defaults.index {
+        item["_index"] = json!(index);
+    }
+
+    if let Some(doc_type) = defaults.doc_type {
+        item["_type"] = json!(doc_type);
+    }
+
+    for key in &["_index", "_type", "_id"] {
+        if let Some(value) = action_params.get(*key) {
+            item[*key] = value.clone();
+        }
+    }
+
+    item
+}
+
+
+/// The indices that a batch of actions write to, by the names the actions gave
+type BatchIndices = HashMap<String, Result<Arc<Index>, ResolveError>>;
+
+
+/// Finds the indices that a batch of actions write to
+///
+/// They're all found at once, so the cluster metadata only has to be locked briefly. It
+/// mustn't be locked again while they're held, as closing an index holds the lock while
+/// waiting for requests to stop using it.
+fn find_batch_indices(cluster_metadata: &ClusterMetadata, batch: &[(BulkAction, usize)], defaults: BulkDefaults) -> BatchIndices {
+    let mut indices = HashMap::new();
+
+    for &(ref action, _) in batch {
+        let params = match *action {
+            BulkAction::Action { ref params, .. } => params,
+            BulkAction::Invalid(_) => continue,
+        };
+
+        let index_name = match params.get("_index") {
+            Some(index_name) => index_name.as_str(),
+            None => defaults.index,
+        };
+
+        if let Some(index_name) = index_name {
+            if !indices.contains_key(index_name) {
+                let index = cluster_metadata.resolve_write_index(index_name).and_then(|index_ref| {
+                    cluster_metadata.indices.get(&index_ref).cloned().ok_or(ResolveError::NotFound)
+                });
+                indices.insert(index_name.to_string(), index);
             }
         }
     }
 
-    return Ok(json_response(status::Ok,
-                            json!({
-                                "took": items.len(),
-                                "items": items,
-                            })));
+    indices
 }
 
 
-pub fn view_post_index_bulk(req: &mut Request) -> IronResult<Response> {
-    let ref system = get_system!(req);
-    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+/// Runs one action of a bulk request, returning its item for the response
+///
+/// `source` is the line following the action, for the actions that have one. Indices that
+/// were written to are added to `modified_indices` so they can be refreshed at the end, and
+/// `operation` is set to the write that should be sent on to the index's replicas.
+fn run_action(context: &BulkContext, indices: &BatchIndices, action_name: &str, action_params: &Map<String, Json>, source: Option<&Json>, modified_indices: &mut HashSet<String>, operation: &mut Option<ReplicaOperation>) -> Json {
+    let defaults = context.defaults;
+    let mut item = new_item(action_params, defaults);
+
+    let doc_index = match action_params.get("_index") {
+        Some(&Json::String(ref doc_index)) => doc_index,
+        Some(_) => {
+            item_error(&mut item, 400, "illegal_argument_exception", "_index must be a string".to_string());
+            return item;
+        }
+        None => {
+            match defaults.index {
+                Some(doc_index) => doc_index,
+                None => {
+                    item_error(&mut item, 400, "action_request_validation_exception", "index is missing".to_string());
+                    return item;
+                }
+            }
+        }
+    };
+
+    if !context.permissions.allows_index(doc_index, IndexPrivilege::Write) {
+        item_error(&mut item, 403, "security_exception", missing_index_privilege_message(doc_index, IndexPrivilege::Write));
+        return item;
+    }
 
-    // Lock cluster metedata
-    let cluster_metadata = system.metadata.read().unwrap();
+    let doc_type = match action_params.get("_type") {
+        Some(&Json::String(ref doc_type)) => doc_type,
+        Some(_) => {
+            item_error(&mut item, 400, "illegal_argument_exception", "_type must be a string".to_string());
+            return item;
+        }
+        None => {
+            match defaults.doc_type {
+                Some(doc_type) => doc_type,
+                None => {
+                    item_error(&mut item, 400, "action_request_validation_exception", "type is missing".to_string());
+                    return item;
+                }
+            }
+        }
+    };
+
+    let doc_id = match action_params.get("_id") {
+        Some(&Json::String(ref doc_id)) => doc_id.clone(),
+        Some(_) => {
+            item_error(&mut item, 400, "illegal_argument_exception", "_id must be a string".to_string());
+            return item;
+        }
+        None if action_name == "index" || action_name == "create" => {
+            // Generate an id for new documents
+            let doc_id = generate_doc_id();
+            item["_id"] = json!(doc_id);
+            doc_id
+        }
+        None => {
+            item_error(&mut item, 400, "action_request_validation_exception", "id is missing".to_string());
+            return item;
+        }
+    };
+    let doc_id = &doc_id[..];
+
+    // Documents are put in shards by their id, unless a routing value is given
+    let routing = match action_params.get("routing").or_else(|| action_params.get("_routing")) {
+        Some(&Json::String(ref routing)) => Some(&routing[..]),
+        Some(_) => {
+            item_error(&mut item, 400, "illegal_argument_exception", "routing must be a string".to_string());
+            return item;
+        }
+        None => None,
+    };
+
+    // Find index
+    let index = match indices.get(doc_index).cloned().unwrap_or(Err(ResolveError::NotFound)) {
+        Ok(index) => index,
+        Err(e) => {
+            resolve_error_item(&mut item, doc_index, e);
+            return item;
+        }
+    };
+    item["_index"] = json!(index.canonical_name());
+
+    if action_name == "update" && context.permissions.document_queries(&[&doc_index[..], index.canonical_name()]).is_some() {
+        item_error(&mut item, 403, "security_exception", document_queries_update_message(index.canonical_name()));
+        return item;
+    }
 
-    // Get index
-    let index = get_index_or_404!(cluster_metadata, *index_name);
     let index_metadata = index.metadata.read().unwrap();
 
-    // Load data from body
-    let mut payload = String::new();
-    req.body.read_to_string(&mut payload).unwrap();
+    let blocked = if action_name == "delete" { index_metadata.settings.blocks.blocks_delete() } else { index_metadata.settings.blocks.blocks_write() };
+    if blocked {
+        let operation = if action_name == "delete" { "delete" } else { "write" };
+        item_error(&mut item, 403, "cluster_block_exception", format!("index [{}] is blocked for {} operations", index.canonical_name(), operation));
+        return item;
+    }
 
-    let mut items = Vec::new();
+    if let Err(reason) = check_active_shards(context.system, index.canonical_name(), &index_metadata.settings, context.wait_for_active_shards) {
+        item_error(&mut item, 503, "unavailable_shards_exception", reason);
+        return item;
+    }
 
-    // Iterate
-    let mut payload_lines = payload.split('\n');
-    loop {
-        let action_line = payload_lines.next();
-
-        // Check if end of input
-        if action_line == None || action_line == Some("") {
-            break;
-        }
-
-        // Parse action line
-        let action_json = parse_json!(&action_line.unwrap());
-
-        // Check action
-        // Action should be an object with only one key, the key name indicates the action and
-        // the value is the parameters for that action
-        let action_name = action_json.as_object().unwrap().keys().nth(0).unwrap();
-        let action_params = action_json.as_object()
-                                       .unwrap()
-                                       .get(action_name)
-                                       .unwrap()
-                                       .as_object()
-                                       .unwrap();
-
-        let doc_id = action_params.get("_id").unwrap().as_str().unwrap();
-        let doc_type = action_params.get("_type").unwrap().as_str().unwrap();
-
-        match action_name.as_ref() {
-            "index" => {
-                let doc_line = payload_lines.next();
-                let doc_json = parse_json!(&doc_line.unwrap());;
-
-                let doc = {
-                    // Find mapping
-                    let mapping = match index_metadata.mappings.get(doc_type) {
-                        Some(mapping) => mapping,
-                        None => {
-                            return Ok(json_response(status::NotFound, json!({"message": "Mapping not found"})));
+    let mapping = match index_metadata.mappings.get(doc_type) {
+        Some(mapping) => mapping,
+        None => {
+            item_error(&mut item, 404, "type_missing_exception", format!("type [{}] missing", doc_type));
+            return item;
+        }
+    };
+
+    let write_condition = match get_write_condition(action_params) {
+        Ok(write_condition) => write_condition,
+        Err(reason) => {
+            item_error(&mut item, 400, "illegal_argument_exception", reason);
+            return item;
+        }
+    };
+
+    match action_name {
+        "index" | "create" => {
+            let started_at = Instant::now();
+            let data = match source.unwrap().as_object() {
+                Some(data) => data,
+                None => {
+                    item_error(&mut item, 400, "mapper_parsing_exception", "failed to parse: the document must be an object".to_string());
+                    return item;
+                }
+            };
+            let doc = match (DocumentSource { key: doc_id, data: data }).prepare(mapping) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    item_error(&mut item, 400, "mapper_parsing_exception", format!("failed to parse: {:?}", e));
+                    return item;
+                }
+            };
+
+            // Create fails if the document already exists
+            let condition = if action_name == "create" { Some(WriteCondition::NotExists) } else { write_condition };
+
+            let shard = index.shard_number_for_routing(routing.unwrap_or(doc_id));
+            match index.shards()[shard].insert_or_update_document_with_condition(&doc, condition.as_ref()) {
+                Ok(write) => {
+                    *operation = Some(ReplicaOperation::index(shard, doc_id, doc_type, data, write.version.version));
+
+                    item_version(&mut item, &write.version);
+                    item["result"] = json!(if write.created { "created" } else { "updated" });
+                    item["status"] = json!(if write.created { 201 } else { 200 });
+                    modified_indices.insert(index.canonical_name().to_string());
+                    slowlog::log_index(context.log, index.canonical_name(), &index_metadata.settings.slowlog, started_at.elapsed(), doc_id, source.unwrap());
+                }
+                Err(DocumentInsertError::VersionConflict(conflict)) => {
+                    item_error(&mut item, 409, "version_conflict_engine_exception", format!("[{}][{}]: {}", doc_type, doc_id, conflict));
+                }
+                Err(e) => panic!("document insert failed: {:?}", e),
+            }
+        }
+        "delete" => {
+            let shard = index.shard_number_for_routing(routing.unwrap_or(doc_id));
+            match index.shards()[shard].remove_document_by_key_with_condition(doc_id, write_condition.as_ref()) {
+                Ok(Some(version)) => {
+                    *operation = Some(ReplicaOperation::delete(shard, doc_id, version.version));
+                    item_version(&mut item, &version);
+                    item["result"] = json!("deleted");
+                    item["status"] = json!(200);
+                    modified_indices.insert(index.canonical_name().to_string());
+                }
+                Ok(None) => {
+                    item["result"] = json!("not_found");
+                    item["status"] = json!(404);
+                }
+                Err(DocumentDeleteError::VersionConflict(conflict)) => {
+                    item_error(&mut item, 409, "version_conflict_engine_exception", format!("[{}][{}]: {}", doc_type, doc_id, conflict));
+                }
+                Err(e) => panic!("document delete failed: {:?}", e),
+            }
+        }
+        "update" => {
+            let request = match UpdateRequest::parse(source.unwrap()) {
+                Ok(request) => request,
+                Err(reason) => {
+                    item_error(&mut item, 400, "illegal_argument_exception", reason);
+                    return item;
+                }
+            };
+            let retry_on_conflict = action_params.get("retry_on_conflict").and_then(|retry_on_conflict| retry_on_conflict.as_u64()).unwrap_or(0);
+
+            match request.run(&index, &index_metadata, mapping, doc_id, routing, write_condition.as_ref(), retry_on_conflict) {
+                Ok(update) => {
+                    if let Some(ref version) = update.version {
+                        item_version(&mut item, version);
+
+                        let shard = index.shard_number_for_routing(routing.unwrap_or(doc_id));
+                        match update.source {
+                            Some(ref source) => *operation = Some(ReplicaOperation::index(shard, doc_id, doc_type, source, version.version)),
+                            None if update.result == "deleted" => *operation = Some(ReplicaOperation::delete(shard, doc_id, version.version)),
+                            None => {}
                         }
-                    };
+                    }
+
+                    item["result"] = json!(update.result);
+                    item["status"] = json!(if update.result == "created" { 201 } else { 200 });
+
+                    if update.result != "noop" {
+                        modified_indices.insert(index.canonical_name().to_string());
+                    }
+                }
+                Err(UpdateError::VersionConflict(conflict)) => {
+                    item_error(&mut item, 409, "version_conflict_engine_exception", format!("[{}][{}]: {}", doc_type, doc_id, conflict));
+                }
+                Err(UpdateError::DocumentMissing) => {
+                    item_error(&mut item, 404, "document_missing_exception", format!("[{}][{}]: document missing", doc_type, doc_id));
+                }
+                Err(UpdateError::SourceNotStored) => {
+                    item_error(&mut item, 400, "illegal_argument_exception", "the document's source isn't stored, so it can't be updated".to_string());
+                }
+                Err(UpdateError::ScriptError(e)) => {
+                    item_error(&mut item, 400, "script_exception", e);
+                }
+                Err(UpdateError::PrepareDocumentError(e)) => {
+                    item_error(&mut item, 400, "mapper_parsing_exception", format!("failed to parse: {:?}", e));
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    item
+}
+
+
+/// Reads an action line of a bulk request, returning the name of the action and its parameters
+///
+/// The line should be an object with only one key, the key name indicates the action and the
+/// value is the parameters for that action.
+fn parse_action_line(line: &[u8]) -> Result<(String, Map<String, Json>), String> {
+    let action_json = match serde_json::from_slice(line) {
+        Ok(Json::Object(action_json)) => action_json,
+        Ok(_) => return Err("the action line must be an object".to_string()),
+        Err(e) => return Err(format!("couldn't parse the action line: {}", e)),
+    };
+
+    if action_json.len() != 1 {
+        return Err(format!("the action line must have exactly one key, found {}", action_json.len()));
+    }
+
+    let (action_name, action_params) = action_json.into_iter().next().unwrap();
+    match action_params {
+        Json::Object(action_params) => Ok((action_name, action_params)),
+        _ => Err(format!("the parameters of the {} action must be an object", action_name)),
+    }
+}
+
+
+#[derive(Debug)]
+enum BulkReadError {
+    /// The body is larger than the maximum payload size
+    TooLarge,
+    Io(io::Error),
+}
+
+
+/// Reads the lines of a bulk request body one at a time, so the whole body never has to be
+/// held in memory
+struct BulkLines<R: BufRead> {
+    reader: R,
+    bytes_read: usize,
+    max_payload_size: usize,
+}
+
+
+impl<R: BufRead> BulkLines<R> {
+    fn new(reader: R, max_payload_size: usize) -> BulkLines<R> {
+        BulkLines {
+            reader: reader,
+            bytes_read: 0,
+            max_payload_size: max_payload_size,
+        }
+    }
+
+    /// Reads the next line, without its line ending. Returns `None` at the end of the body
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>, BulkReadError> {
+        let mut line = Vec::new();
+
+        // Never read more than one byte past the limit, so an oversized line can't use up memory
+        let remaining = (self.max_payload_size + 1 - self.bytes_read) as u64;
+        let length = (&mut self.reader).take(remaining).read_until(b'\n', &mut line).map_err(BulkReadError::Io)?;
+        self.bytes_read += length;
+
+        if self.bytes_read > self.max_payload_size {
+            return Err(BulkReadError::TooLarge);
+        }
+
+        if length == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+
+        Ok(Some(line))
+    }
+}
+
 
-                    // Create document
-                    let document_source = DocumentSource {
-                        key: doc_id,
-                        data: doc_json.as_object().unwrap(),
-                    };
-                    document_source.prepare(mapping).unwrap()
-                };
+/// An action read from the body of a bulk request
+enum BulkAction {
+    /// The action line couldn't be read, so there's no action name to report the error under
+    Invalid(String),
+
+    /// The action and the document that follows it, or the reason it couldn't be read
+    Action {
+        name: String,
+        params: Map<String, Json>,
+        source: Result<Option<Json>, String>,
+    },
+}
 
-                index.store.insert_or_update_document(&doc).unwrap();
 
-                // Insert into "items" array
-                let mut item = HashMap::new();
-                // TODO: "create" may not always be right
-                item.insert("create", action_params.clone());
-                items.push(item);
+/// Reads the next action from the body of a bulk request. Returns `None` at the end of the body
+fn read_action<R: BufRead>(lines: &mut BulkLines<R>) -> Result<Option<BulkAction>, BulkReadError> {
+    // Skip blank lines
+    let action_line = loop {
+        match lines.next_line()? {
+            Some(ref line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+
+    // If this fails, we can't tell whether a document follows, so only this line is skipped
+    let (name, params) = match parse_action_line(&action_line) {
+        Ok(action) => action,
+        Err(reason) => return Ok(Some(BulkAction::Invalid(reason))),
+    };
+
+    // All actions apart from delete are followed by a line with the document
+    let source = match name.as_ref() {
+        "index" | "create" | "update" => {
+            match lines.next_line()? {
+                Some(ref line) if !line.is_empty() => {
+                    serde_json::from_slice(line).map(Some).map_err(|e| format!("couldn't parse the document: {}", e))
+                }
+                _ => Err("the action must be followed by a document".to_string()),
             }
-            _ => {
-                warn!(system.log, "unrecognised action! {}", action_name);
+        }
+        "delete" => Ok(None),
+        _ => Err(format!("unrecognised action: {:?}", name)),
+    };
+
+    Ok(Some(BulkAction::Action {
+        name: name,
+        params: params,
+        source: source,
+    }))
+}
+
+
+/// Finds the node with the primary of an action's index, if it isn't this one
+fn remote_primary(system: &System, indices: &BatchIndices, action_params: &Map<String, Json>, defaults: BulkDefaults) -> Option<DiscoveryNode> {
+    let index_name = match action_params.get("_index") {
+        Some(&Json::String(ref index_name)) => index_name.as_str(),
+        Some(_) => return None,
+        None => defaults.index?,
+    };
+    let index = indices.get(index_name)?.as_ref().ok()?;
+    let primary = system.cluster.index_routing(index.canonical_name()).primary?;
+
+    if primary == system.cluster.local_node.id {
+        return None;
+    }
+
+    system.cluster.node(&primary)
+}
+
+
+/// Actions of a batch that are sent on to the node with their index's primary, as a bulk
+/// request of their own
+struct RemoteBulk {
+    node: DiscoveryNode,
+    body: Vec<u8>,
+
+    /// Where each action's item goes in the response, with its name and the item to report
+    /// if the node can't be reached
+    items: Vec<(usize, String, Json)>,
+}
+
+
+impl RemoteBulk {
+    fn new(node: DiscoveryNode) -> RemoteBulk {
+        RemoteBulk {
+            node: node,
+            body: Vec::new(),
+            items: Vec::new(),
+        }
+    }
+
+    /// Adds an action. The index and type from the URL are added to it, as the other node
+    /// doesn't get the URL
+    fn push(&mut self, position: usize, action_name: &str, action_params: &Map<String, Json>, source: Option<&Json>, defaults: BulkDefaults) {
+        let mut params = action_params.clone();
+        if let Some(index) = defaults.index {
+            params.entry("_index").or_insert_with(|| json!(index));
+        }
+
+        if let Some(doc_type) = defaults.doc_type {
+            params.entry("_type").or_insert_with(|| json!(doc_type));
+        }
+
+        let mut action_line = Map::new();
+        action_line.insert(action_name.to_string(), Json::Object(params));
+        self.body.extend(serde_json::to_vec(&action_line).unwrap_or_default());
+        self.body.push(b'\n');
+
+        if let Some(source) = source {
+            self.body.extend(serde_json::to_vec(source).unwrap_or_default());
+            self.body.push(b'\n');
+        }
+
+        self.items.push((position, action_name.to_string(), new_item(action_params, defaults)));
+    }
+
+    /// Puts the items from the other node's response in their places in `items`
+    ///
+    /// Returns true if any of them failed.
+    fn finish(self, log: &Logger, response: Result<TransportResponse, String>, items: &mut Vec<Json>) -> bool {
+        let remote_items = response.and_then(|response| {
+            let body = serde_json::from_slice::<Json>(&response.body).map_err(|e| format!("invalid response: {}", e))?;
+
+            match body.get("items").and_then(|items| items.as_array()) {
+                Some(remote_items) if remote_items.len() == self.items.len() => Ok(remote_items.clone()),
+                _ => Err(format!("{} {}", response.status, body.get("message").and_then(|message| message.as_str()).unwrap_or(""))),
+            }
+        });
+
+        let mut errors = false;
+        match remote_items {
+            Ok(remote_items) => {
+                for (&(position, _, _), remote_item) in self.items.iter().zip(remote_items) {
+                    errors |= remote_item.as_object().and_then(|item| item.values().next()).map_or(true, |item| item.get("error").is_some());
+                    items[position] = remote_item;
+                }
+            }
+            Err(e) => {
+                warn!(log, "failed to send bulk actions on to node"; "node" => &self.node.name, "error" => e.clone());
+
+                for (position, action_name, mut item) in self.items {
+                    item_error(&mut item, 503, "node_not_connected_exception", format!("couldn't reach node [{}]: {}", self.node.name, e));
+
+                    let mut item_json = Map::new();
+                    item_json.insert(action_name, item);
+                    items[position] = Json::Object(item_json);
+                }
+                errors = true;
             }
         }
+
+        errors
     }
+}
+
+
+/// Runs the actions of a bulk request
+///
+/// The body is processed as it's read, in batches of actions. The cluster metadata is only
+/// locked while the indices of each batch are found, not while it runs. Actions for indices
+/// whose primary is on another node are sent on to it, and the writes made here are sent to
+/// the replicas at the end of each batch.
+fn run_bulk(system: &System, req: &mut Request, defaults: BulkDefaults) -> ApiResult<Response> {
+    let start_time = Instant::now();
+    let log = req.log.clone();
+    let opaque_id = req.opaque_id.clone();
+    let permissions = get_permissions(req);
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
+    let context = BulkContext {
+        system: system,
+        log: &log,
+        permissions: &permissions,
+        defaults: defaults,
+        wait_for_active_shards: wait_for_active_shards,
+    };
+
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+
+    // Actions are sent on to other nodes with the same credentials and parameters. Requests
+    // that were sent on by another node are run here, so they can't go round in circles
+    let forwarded = req.header(FORWARDED_HEADER).is_some();
+    let authorization = req.header("Authorization").map(|value| value.to_string());
+    let remote_path = match req.uri.query() {
+        Some(url_query) => format!("/_bulk?{}", url_query),
+        None => "/_bulk".to_string(),
+    };
+
+    // The index in the URL must exist
+    if let Some(default_index) = defaults.index {
+        let cluster_metadata = system.metadata.read().unwrap();
+        get_write_index_or_404!(cluster_metadata, default_index);
+    }
+
+    // Bulk requests have their own limit, but can't be larger than any other request either
+    let max_payload_size = cmp::min(system.bulk_max_payload_size as u64, system.settings.max_content_length) as usize;
+
+    // Reject bodies that are too large before running any of their actions, if we can tell
+    if let Some(length) = req.content_length() {
+        if length > max_payload_size as u64 {
+            return Ok(json_response(StatusCode::PAYLOAD_TOO_LARGE,
+                                    json!({"message": format!("The request body is larger than the maximum of {} bytes", max_payload_size)})));
+        }
+    }
+
+    let mut lines = BulkLines::new(BufReader::new(&mut req.body), max_payload_size);
+    let mut items = Vec::new();
+    let mut errors = false;
+    let mut modified_indices = HashSet::new();
+
+    // The time spent on the actions for each index and their size, for the index's bulk stats
+    let mut bulk_activity: HashMap<String, (Duration, usize)> = HashMap::new();
+
+    let mut read_error = None;
+    let mut finished = false;
+
+    while !finished {
+        let mut batch = Vec::new();
+        while batch.len() < BATCH_SIZE {
+            let bytes_read = lines.bytes_read;
+            match read_action(&mut lines) {
+                Ok(Some(action)) => batch.push((action, lines.bytes_read - bytes_read)),
+                Ok(None) => {
+                    finished = true;
+                    break;
+                }
+                Err(e) => {
+                    read_error = Some(e);
+                    finished = true;
+                    break;
+                }
+            }
+        }
 
-    return Ok(json_response(status::Ok,
-                            json!({
-                                "took": items.len(),
-                                "items": items,
-                            })));
+        let indices = find_batch_indices(&system.metadata.read().unwrap(), &batch, defaults);
+        let mut remote_bulks: HashMap<String, RemoteBulk> = HashMap::new();
+
+        // The writes to send on to the replicas of each index, and the items they came from
+        let mut replica_writes: HashMap<String, (Vec<ReplicaOperation>, Vec<usize>)> = HashMap::new();
+
+        for (action, size) in batch {
+            if let BulkAction::Action { ref name, ref params, source: Ok(ref source) } = action {
+                let remote_node = if forwarded { None } else { remote_primary(system, &indices, params, defaults) };
+
+                if let Some(node) = remote_node {
+                    let remote_bulk = remote_bulks.entry(node.id.clone()).or_insert_with(|| RemoteBulk::new(node));
+                    remote_bulk.push(items.len(), name, params, source.as_ref(), defaults);
+
+                    // Filled in once the other node responds
+                    items.push(Json::Null);
+                    continue;
+                }
+            }
+
+            let action_start_time = Instant::now();
+            let mut operation = None;
+            let (action_name, item) = match action {
+                BulkAction::Invalid(reason) => {
+                    let mut item = json!({});
+                    item_error(&mut item, 400, "illegal_argument_exception", reason);
+                    ("unknown".to_string(), item)
+                }
+                BulkAction::Action { name, params, source: Ok(source) } => {
+                    let item = run_action(&context, &indices, &name, &params, source.as_ref(), &mut modified_indices, &mut operation);
+                    (name, item)
+                }
+                BulkAction::Action { name, params, source: Err(reason) } => {
+                    let mut item = new_item(&params, defaults);
+                    item_error(&mut item, 400, "illegal_argument_exception", reason);
+                    (name, item)
+                }
+            };
+
+            if item.get("error").is_some() {
+                errors = true;
+            }
+
+            // Only actions that got as far as finding their index are counted towards its stats
+            if let Some(index_name) = item.get("_index").and_then(|index_name| index_name.as_str()) {
+                if indices.values().any(|index| index.as_ref().ok().map_or(false, |index| index.canonical_name() == index_name)) {
+                    let activity = bulk_activity.entry(index_name.to_string()).or_insert((Duration::new(0, 0), 0));
+                    activity.0 += action_start_time.elapsed();
+                    activity.1 += size;
+                }
+            }
+
+            if let (Some(operation), Some(index_name)) = (operation, item.get("_index").and_then(|index_name| index_name.as_str())) {
+                let writes = replica_writes.entry(index_name.to_string()).or_insert_with(|| (Vec::new(), Vec::new()));
+                writes.0.push(operation);
+                writes.1.push(items.len());
+            }
+
+            // Insert into "items" array
+            let mut item_json = Map::new();
+            item_json.insert(action_name, item);
+            items.push(Json::Object(item_json));
+        }
+
+        let remote_bulks = remote_bulks.into_iter().map(|(_, remote_bulk)| remote_bulk).collect::<Vec<_>>();
+        if !remote_bulks.is_empty() {
+            let requests = remote_bulks.iter().map(|remote_bulk| {
+                let mut request = TransportRequest::new(&remote_bulk.node.address, Method::POST, &remote_path);
+                request.headers.push(("Content-Type".to_string(), "application/x-ndjson".to_string()));
+                request.headers.push((FORWARDED_HEADER.to_string(), system.cluster.local_node.id.clone()));
+                request.headers.push((OPAQUE_ID_HEADER.to_string(), opaque_id.clone()));
+                if let Some(ref authorization) = authorization {
+                    request.headers.push(("Authorization".to_string(), authorization.clone()));
+                }
+                request.body = remote_bulk.body.clone();
+                request
+            }).collect();
+
+            let responses = system.cluster.transport().send_all(requests, FORWARD_TIMEOUT);
+            for (remote_bulk, response) in remote_bulks.into_iter().zip(responses) {
+                errors |= remote_bulk.finish(&log, response, &mut items);
+            }
+        }
+
+        for (index_name, (operations, positions)) in replica_writes {
+            let shards = replicate(system, &index_name, &operations).to_json();
+
+            for position in positions {
+                if let Some(item) = items[position].as_object_mut().and_then(|item| item.iter_mut().next().map(|(_, item)| item)) {
+                    item["_shards"] = shards.clone();
+                }
+            }
+        }
+    }
+
+    // Find the indices again to refresh them and record their stats. Refreshing can wait
+    // for a while, so it's done after unlocking the cluster metadata
+    let find_index = |cluster_metadata: &ClusterMetadata, index_name: &str| {
+        cluster_metadata.names.find_canonical(index_name).and_then(|index_ref| cluster_metadata.indices.get(&index_ref)).cloned()
+    };
+    let (modified_indices, bulk_activity) = {
+        let cluster_metadata = system.metadata.read().unwrap();
+
+        (modified_indices.iter().filter_map(|index_name| find_index(&cluster_metadata, index_name)).collect::<Vec<_>>(),
+         bulk_activity.iter().filter_map(|(index_name, &activity)| find_index(&cluster_metadata, index_name).map(|index| (index, activity))).collect::<Vec<_>>())
+    };
+
+    for index in modified_indices {
+        if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+            error!(log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
+        }
+    }
+
+    for (index, (time, size)) in bulk_activity {
+        // Bulk requests span every shard, so they're all counted against the first one
+        index.shards()[0].record_bulk_request(time, size);
+    }
+
+    // The actions before the error have already been run, so they are still reported
+    let (status, message) = match read_error {
+        None => (StatusCode::OK, None),
+        Some(BulkReadError::TooLarge) => {
+            (StatusCode::PAYLOAD_TOO_LARGE, Some(format!("The request body is larger than the maximum of {} bytes, only the actions before the limit were run", max_payload_size)))
+        }
+        Some(BulkReadError::Io(e)) => (StatusCode::BAD_REQUEST, Some(format!("Couldn't read the request body: {}", e))),
+    };
+
+    let mut response = json!({
+        "took": duration_to_nanos(start_time.elapsed()) / 1_000_000,
+        "errors": errors || message.is_some(),
+        "items": items,
+    });
+
+    if let Some(message) = message {
+        response["message"] = json!(message);
+    }
+
+    return Ok(json_response(status, response));
+}
+
+
+pub fn view_post_bulk(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    run_bulk(system, req, BulkDefaults::default())
+}
+
+
+pub fn view_post_index_bulk(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let index_name = read_path_parameter!(req, "index").map(|index_name| index_name.to_string());
+    let mapping_name = read_path_parameter!(req, "mapping").map(|mapping_name| mapping_name.to_string());
+
+    run_bulk(system, req, BulkDefaults {
+        index: index_name.as_ref().map(|index_name| index_name.as_ref()),
+        doc_type: mapping_name.as_ref().map(|mapping_name| mapping_name.as_ref()),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cluster::metadata::ResolveError;
+
+    use super::resolve_error_item;
+
+    #[test]
+    fn test_resolve_error_item() {
+        let mut item = json!({});
+        resolve_error_item(&mut item, "logs", ResolveError::NoWriteIndex);
+        assert_eq!(item, json!({
+            "status": 400,
+            "error": {
+                "type": "illegal_argument_exception",
+                "reason": "[logs] no write index is defined for alias",
+            },
+        }));
+
+        // Aliases that can't be used at all aren't reported as missing a write index
+        let mut item = json!({});
+        resolve_error_item(&mut item, "logs", ResolveError::IsAlias);
+        assert_eq!(item, json!({
+            "status": 400,
+            "error": {
+                "type": "illegal_argument_exception",
+                "reason": "[logs] is an alias, give the name of an index instead",
+            },
+        }));
+
+        let mut item = json!({});
+        resolve_error_item(&mut item, "logs", ResolveError::NotFound);
+        assert_eq!(item["status"], json!(404));
+        assert_eq!(item["error"]["type"], json!("index_not_found_exception"));
+    }
 }
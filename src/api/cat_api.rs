@@ -0,0 +1,600 @@
+//! The `_cat` APIs, which print tables of plain text for people to read in a terminal
+//!
+//! All of them take the same URL parameters:
+//!
+//!  - `v` prints a header row
+//!  - `h` picks the columns to print, by name or alias. Wildcards are supported
+//!  - `s` sorts by a list of columns, each optionally followed by `:asc` or `:desc`
+//!  - `bytes=b` prints sizes as a number of bytes rather than with a unit
+//!  - `help` lists the columns instead
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use chrono::Utc;
+use url::form_urlencoded;
+
+use search::query::Query;
+use cluster::metadata::{IndexRef, IndicesOptions};
+use cluster::health::HealthStatus;
+use source_filter::wildcard_match;
+use plugins;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, apply_alias_filter, apply_role_filter, get_indices_options};
+use api::security_api::get_permissions;
+
+
+struct CatColumn {
+    name: &'static str,
+    alias: &'static str,
+    description: &'static str,
+}
+
+
+#[derive(Debug, Clone)]
+enum CatValue {
+    Empty,
+    Text(String),
+    Number(f64),
+    Bytes(u64),
+}
+
+
+impl CatValue {
+    fn format(&self, raw_bytes: bool) -> String {
+        match *self {
+            CatValue::Empty => String::new(),
+            CatValue::Text(ref text) => text.clone(),
+            CatValue::Number(number) => format!("{}", number),
+            CatValue::Bytes(bytes) if raw_bytes => format!("{}", bytes),
+            CatValue::Bytes(bytes) => format_bytes(bytes),
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        match *self {
+            CatValue::Number(_) | CatValue::Bytes(_) => true,
+            CatValue::Empty | CatValue::Text(_) => false,
+        }
+    }
+
+    fn compare(&self, other: &CatValue) -> Ordering {
+        match (self, other) {
+            (&CatValue::Number(a), &CatValue::Number(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            (&CatValue::Bytes(a), &CatValue::Bytes(b)) => a.cmp(&b),
+            (&CatValue::Text(ref a), &CatValue::Text(ref b)) => a.cmp(b),
+
+            // Empty cells sort last
+            (&CatValue::Empty, &CatValue::Empty) => Ordering::Equal,
+            (&CatValue::Empty, _) => Ordering::Greater,
+            (_, &CatValue::Empty) => Ordering::Less,
+            _ => self.format(true).cmp(&other.format(true)),
+        }
+    }
+}
+
+
+impl<'a> From<&'a str> for CatValue {
+    fn from(text: &'a str) -> CatValue {
+        CatValue::Text(text.to_string())
+    }
+}
+
+
+impl From<String> for CatValue {
+    fn from(text: String) -> CatValue {
+        CatValue::Text(text)
+    }
+}
+
+
+impl From<u64> for CatValue {
+    fn from(number: u64) -> CatValue {
+        CatValue::Number(number as f64)
+    }
+}
+
+
+impl From<usize> for CatValue {
+    fn from(number: usize) -> CatValue {
+        CatValue::Number(number as f64)
+    }
+}
+
+
+/// Formats a number of bytes with the largest unit that keeps it at least 1, like "4.2mb"
+fn format_bytes(bytes: u64) -> String {
+    let units = ["b", "kb", "mb", "gb", "tb", "pb"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}{}", bytes, units[0])
+    } else {
+        format!("{:.1}{}", size, units[unit])
+    }
+}
+
+
+/// The options that are common to all `_cat` APIs
+struct CatParams {
+    headers: bool,
+    columns: Option<Vec<String>>,
+    sort: Vec<(String, bool)>,
+    raw_bytes: bool,
+    help: bool,
+}
+
+
+fn parse_cat_params(req: &Request) -> Result<CatParams, Response> {
+    let mut params = CatParams {
+        headers: false,
+        columns: None,
+        sort: Vec::new(),
+        raw_bytes: false,
+        help: false,
+    };
+
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            match key.as_ref() {
+                "v" => params.headers = value != "false",
+                "help" => params.help = value != "false",
+                "h" => params.columns = Some(value.split(',').map(|column| column.trim().to_string()).collect()),
+                "s" => {
+                    for sort in value.split(',') {
+                        let mut parts = sort.trim().splitn(2, ':');
+                        let column = parts.next().unwrap().to_string();
+                        let descending = match parts.next() {
+                            None | Some("asc") => false,
+                            Some("desc") => true,
+                            Some(order) => {
+                                return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid sort order {:?}, expected asc or desc", order)})));
+                            }
+                        };
+
+                        params.sort.push((column, descending));
+                    }
+                }
+                "bytes" => {
+                    params.raw_bytes = match value.as_ref() {
+                        "b" => true,
+                        _ => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "bytes must be b"}))),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(params)
+}
+
+
+fn text_response(text: String) -> Response {
+    Response::with_body(StatusCode::OK, "text/plain; charset=UTF-8", text)
+}
+
+
+impl CatColumn {
+    fn has_alias(&self, name: &str) -> bool {
+        !self.alias.is_empty() && self.alias == name
+    }
+}
+
+
+/// Finds a column by its name or alias
+fn find_column(columns: &[CatColumn], name: &str) -> Option<usize> {
+    columns.iter().position(|column| column.name == name || column.has_alias(name))
+}
+
+
+/// Prints a table, applying the column selection and sort order from the URL
+fn render_table(req: &Request, columns: &[CatColumn], mut rows: Vec<Vec<CatValue>>) -> Response {
+    let params = match parse_cat_params(req) {
+        Ok(params) => params,
+        Err(response) => return response,
+    };
+
+    if params.help {
+        let mut help_rows = Vec::new();
+        for column in columns {
+            help_rows.push(vec![column.name.to_string(), "|".to_string(), column.alias.to_string(), "|".to_string(), column.description.to_string()]);
+        }
+
+        return text_response(format_rows(&help_rows, &[false; 5]));
+    }
+
+    // Pick columns
+    let selected = match params.columns {
+        Some(ref names) => {
+            let mut selected = Vec::new();
+            for name in names {
+                let matched = columns.iter().enumerate()
+                    .filter(|&(_, column)| wildcard_match(name, column.name) || column.has_alias(name))
+                    .map(|(position, _)| position)
+                    .collect::<Vec<_>>();
+
+                if matched.is_empty() {
+                    return json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Unrecognised column {:?}", name)}));
+                }
+
+                selected.extend(matched.into_iter().filter(|position| !selected.contains(position)).collect::<Vec<_>>());
+            }
+
+            selected
+        }
+        None => (0..columns.len()).collect(),
+    };
+
+    // Sort rows
+    let mut sort = Vec::new();
+    for &(ref name, descending) in params.sort.iter() {
+        match find_column(columns, name) {
+            Some(position) => sort.push((position, descending)),
+            None => return json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Unrecognised sort column {:?}", name)})),
+        }
+    }
+
+    rows.sort_by(|a, b| {
+        for &(position, descending) in sort.iter() {
+            let ordering = match (&a[position], &b[position]) {
+                // Empty cells sort last, whichever the order
+                (&CatValue::Empty, _) | (_, &CatValue::Empty) => a[position].compare(&b[position]),
+                (a, b) if descending => a.compare(b).reverse(),
+                (a, b) => a.compare(b),
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    });
+
+    // Numbers are right-aligned
+    let right_align = selected.iter().map(|&position| rows.iter().any(|row| row[position].is_numeric())).collect::<Vec<_>>();
+
+    let mut text_rows = Vec::new();
+    if params.headers {
+        text_rows.push(selected.iter().map(|&position| columns[position].name.to_string()).collect());
+    }
+
+    for row in rows {
+        text_rows.push(selected.iter().map(|&position| row[position].format(params.raw_bytes)).collect());
+    }
+
+    text_response(format_rows(&text_rows, &right_align))
+}
+
+
+/// Lines up the cells of each row into columns
+fn format_rows(rows: &[Vec<String>], right_align: &[bool]) -> String {
+    let mut widths = vec![0; right_align.len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut text = String::new();
+    for row in rows {
+        let cells = row.iter().zip(widths.iter()).zip(right_align.iter()).map(|((cell, &width), &right_align)| {
+            if right_align {
+                format!("{:>width$}", cell, width = width)
+            } else {
+                format!("{:<width$}", cell, width = width)
+            }
+        }).collect::<Vec<_>>();
+
+        text.push_str(cells.join(" ").trim_end());
+        text.push('\n');
+    }
+
+    text
+}
+
+
+pub fn view_get_cat(_: &mut Request) -> ApiResult<Response> {
+    Ok(text_response("=^.^=\n/_cat/aliases\n/_cat/aliases/{alias}\n/_cat/count\n/_cat/count/{index}\n/_cat/health\n/_cat/indices\n/_cat/indices/{index}\n/_cat/plugins\n".to_string()))
+}
+
+
+const INDICES_COLUMNS: &'static [CatColumn] = &[
+    CatColumn { name: "health", alias: "h", description: "current health status" },
+    CatColumn { name: "status", alias: "s", description: "open/close status" },
+    CatColumn { name: "index", alias: "i", description: "index name" },
+    CatColumn { name: "uuid", alias: "id", description: "index uuid" },
+    CatColumn { name: "pri", alias: "p", description: "number of primary shards" },
+    CatColumn { name: "rep", alias: "r", description: "number of replica shards" },
+    CatColumn { name: "docs.count", alias: "dc", description: "available docs" },
+    CatColumn { name: "docs.deleted", alias: "dd", description: "deleted docs" },
+    CatColumn { name: "store.size", alias: "ss", description: "store size of primaries & replicas" },
+    CatColumn { name: "pri.store.size", alias: "", description: "store size of primaries" },
+];
+
+
+/// Lists indices, including closed ones and ones that are still being loaded
+pub fn view_get_cat_indices(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let index_expression = read_path_parameter!(req, "index");
+
+    let cluster_metadata = system.metadata.read().unwrap();
+
+    // Closed indices are included, unlike when searching
+    let matched_indices = match index_expression {
+        Some(expression) => {
+            let indices_options = match get_indices_options(req) {
+                Ok(indices_options) => IndicesOptions { include_closed: true, ..indices_options },
+                Err(response) => return Ok(response),
+            };
+
+            match cluster_metadata.resolve_indices(expression, &indices_options) {
+                Ok(indices) => Some(indices.into_iter().map(|(index_ref, _)| index_ref).collect::<HashSet<_>>()),
+                Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+            }
+        }
+        None => None,
+    };
+    let matches = |index_ref: &IndexRef| matched_indices.as_ref().map_or(true, |matched_indices| matched_indices.contains(index_ref));
+
+    let mut rows = Vec::new();
+
+    for (index_ref, index) in cluster_metadata.indices.iter() {
+        if !matches(index_ref) {
+            continue;
+        }
+
+        let (docs, deleted_docs, size) = match index.get_store_statistics() {
+            Ok(shard_stats) => {
+                let total_docs: i64 = shard_stats.iter().flat_map(|stats| stats.segments.iter()).map(|&(_, ref s)| s.total_docs()).sum();
+                let deleted_docs: i64 = shard_stats.iter().flat_map(|stats| stats.segments.iter()).map(|&(_, ref s)| s.deleted_docs()).sum();
+                let size: u64 = shard_stats.iter().flat_map(|stats| stats.segment_sizes.values()).sum();
+
+                (CatValue::Number((total_docs - deleted_docs) as f64), CatValue::Number(deleted_docs as f64), CatValue::Bytes(size))
+            }
+            Err(e) => {
+                error!(req.log, "failed to read index statistics"; "index" => index.canonical_name(), "error" => e);
+                (CatValue::Empty, CatValue::Empty, CatValue::Empty)
+            }
+        };
+
+        // Only this node's copy is counted, so the primaries are everything
+        let number_of_replicas = index.metadata.read().unwrap().settings.number_of_replicas as usize;
+        rows.push(vec![
+            HealthStatus::Green.name().into(),
+            "open".into(),
+            index.canonical_name().into(),
+            index.id().simple().to_string().into(),
+            index.shards().len().into(),
+            number_of_replicas.into(),
+            docs,
+            deleted_docs,
+            size.clone(),
+            size,
+        ]);
+    }
+
+    for (index_ref, index) in cluster_metadata.closed_indices.iter() {
+        if !matches(index_ref) {
+            continue;
+        }
+
+        rows.push(vec![
+            CatValue::Empty,
+            "close".into(),
+            index.canonical_name().into(),
+            index.id().simple().to_string().into(),
+            CatValue::Empty,
+            CatValue::Empty,
+            CatValue::Empty,
+            CatValue::Empty,
+            CatValue::Empty,
+            CatValue::Empty,
+        ]);
+    }
+
+    // Indices that haven't been loaded (yet) aren't in the cluster metadata
+    let all_indices_listed = index_expression.map(|expression| expression.split(',').all(|name| name == "_all" || name.contains('*'))).unwrap_or(true);
+    if all_indices_listed {
+        for (index_name, recovery) in system.recoveries.read().unwrap().iter() {
+            let listed = cluster_metadata.names.find_canonical(index_name).is_some();
+            let matched = index_expression.map(|expression| expression.split(',').any(|name| wildcard_match(if name == "_all" { "*" } else { name }, index_name))).unwrap_or(true);
+
+            if !listed && matched {
+                let mut row = vec![CatValue::Empty; INDICES_COLUMNS.len()];
+                row[0] = (if recovery.is_finished() { HealthStatus::Red } else { HealthStatus::Yellow }).name().into();
+                row[1] = (if recovery.is_finished() { "failed" } else { "open" }).into();
+                row[2] = index_name.as_str().into();
+                rows.push(row);
+            }
+        }
+    }
+
+    // Sort by index name unless another order is asked for
+    rows.sort_by(|a, b| a[2].compare(&b[2]));
+
+    Ok(render_table(req, INDICES_COLUMNS, rows))
+}
+
+
+const COUNT_COLUMNS: &'static [CatColumn] = &[
+    CatColumn { name: "epoch", alias: "t", description: "seconds since 1970-01-01 00:00:00" },
+    CatColumn { name: "timestamp", alias: "ts", description: "time in HH:MM:SS" },
+    CatColumn { name: "count", alias: "dc", description: "the document count" },
+];
+
+
+/// Counts the documents in some indices, or all of them
+pub fn view_get_cat_count(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+    let permissions = get_permissions(req);
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    let mut count = 0;
+    for (index_ref, name) in indices {
+        let index = &cluster_metadata.indices[&index_ref];
+        let index_metadata = index.metadata.read().unwrap();
+
+        if index_metadata.settings.blocks.blocks_read() {
+            continue;
+        }
+
+        let index_reader = index.reader();
+        let query = apply_alias_filter(Query::all(), &name, &index_metadata, &index_reader.schema());
+        let query = apply_role_filter(query, &permissions, &name, index.canonical_name(), &index_metadata, &index_reader.schema());
+        count += index_reader.count(&query).unwrap();
+    }
+
+    let now = Utc::now();
+    let rows = vec![vec![
+        (now.timestamp() as u64).into(),
+        now.format("%H:%M:%S").to_string().into(),
+        count.into(),
+    ]];
+
+    Ok(render_table(req, COUNT_COLUMNS, rows))
+}
+
+
+const HEALTH_COLUMNS: &'static [CatColumn] = &[
+    CatColumn { name: "epoch", alias: "t", description: "seconds since 1970-01-01 00:00:00" },
+    CatColumn { name: "timestamp", alias: "ts", description: "time in HH:MM:SS" },
+    CatColumn { name: "cluster", alias: "cl", description: "cluster name" },
+    CatColumn { name: "status", alias: "st", description: "health status" },
+    CatColumn { name: "node.total", alias: "nt", description: "total number of nodes" },
+    CatColumn { name: "node.data", alias: "nd", description: "number of nodes that can store data" },
+    CatColumn { name: "shards", alias: "sh", description: "total number of shards" },
+    CatColumn { name: "pri", alias: "p", description: "number of primary shards" },
+    CatColumn { name: "relo", alias: "r", description: "number of relocating nodes" },
+    CatColumn { name: "init", alias: "i", description: "number of initializing nodes" },
+    CatColumn { name: "unassign", alias: "u", description: "number of unassigned shards" },
+    CatColumn { name: "active_shards_percent", alias: "asp", description: "active number of shards in percent" },
+];
+
+
+/// Shows the health of the node. See `cluster::health`
+pub fn view_get_cat_health(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let health = system.health();
+    let number_of_nodes = system.cluster.number_of_nodes();
+
+    let now = Utc::now();
+    let rows = vec![vec![
+        (now.timestamp() as u64).into(),
+        now.format("%H:%M:%S").to_string().into(),
+        system.settings.cluster_name.clone().into(),
+        health.status.name().into(),
+        number_of_nodes.into(),
+        number_of_nodes.into(),
+        health.active_shards.into(),
+        health.active_primary_shards.into(),
+        0usize.into(),
+        health.initializing_shards.into(),
+        health.unassigned_shards.into(),
+        format!("{:.1}%", health.active_shards_percent()).into(),
+    ]];
+
+    Ok(render_table(req, HEALTH_COLUMNS, rows))
+}
+
+
+const ALIASES_COLUMNS: &'static [CatColumn] = &[
+    CatColumn { name: "alias", alias: "a", description: "alias name" },
+    CatColumn { name: "index", alias: "i", description: "index alias points to" },
+    CatColumn { name: "filter", alias: "f", description: "filter" },
+    CatColumn { name: "routing.index", alias: "ri", description: "index routing" },
+    CatColumn { name: "routing.search", alias: "rs", description: "search routing" },
+    CatColumn { name: "is_write_index", alias: "w", description: "write index" },
+];
+
+
+/// Lists aliases, optionally only those matching a comma separated list of names or wildcards
+pub fn view_get_cat_aliases(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let alias_expression = read_path_parameter!(req, "alias");
+
+    let cluster_metadata = system.metadata.read().unwrap();
+
+    let mut rows: Vec<Vec<CatValue>> = Vec::new();
+    for (alias_name, index_refs) in cluster_metadata.names.aliases() {
+        if let Some(expression) = alias_expression {
+            if !expression.split(',').any(|name| wildcard_match(if name == "_all" { "*" } else { name }, alias_name)) {
+                continue;
+            }
+        }
+
+        for index_ref in index_refs {
+            let row = cluster_metadata.with_index_metadata(index_ref, |index_metadata| {
+                let alias = index_metadata.aliases.get(alias_name);
+
+                vec![
+                    alias_name.into(),
+                    cluster_metadata.index_name(index_ref).unwrap_or("").into(),
+                    (if alias.map(|alias| alias.filter.is_some()).unwrap_or(false) { "*" } else { "-" }).into(),
+                    "-".into(),
+                    "-".into(),
+                    match alias.and_then(|alias| alias.is_write_index) {
+                        Some(is_write_index) => is_write_index.to_string().into(),
+                        None => "-".into(),
+                    },
+                ]
+            });
+
+            if let Some(row) = row {
+                rows.push(row);
+            }
+        }
+    }
+
+    // Sort by alias then index unless another order is asked for
+    rows.sort_by(|a, b| a[0].compare(&b[0]).then_with(|| a[1].compare(&b[1])));
+
+    Ok(render_table(req, ALIASES_COLUMNS, rows))
+}
+
+
+const PLUGINS_COLUMNS: &'static [CatColumn] = &[
+    CatColumn { name: "id", alias: "", description: "unique node id" },
+    CatColumn { name: "name", alias: "n", description: "node name" },
+    CatColumn { name: "component", alias: "c", description: "component" },
+    CatColumn { name: "version", alias: "v", description: "component version" },
+    CatColumn { name: "description", alias: "d", description: "plugin details" },
+];
+
+
+/// Lists the plugins loaded on this node. See `plugins`
+pub fn view_get_cat_plugins(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref local_node = system.cluster.local_node;
+
+    let rows: Vec<Vec<CatValue>> = plugins::installed().iter().map(|plugin| {
+        vec![
+            local_node.id.clone().into(),
+            local_node.name.clone().into(),
+            plugin.name().into(),
+            plugin.version().into(),
+            plugin.description().into(),
+        ]
+    }).collect();
+
+    Ok(render_table(req, PLUGINS_COLUMNS, rows))
+}
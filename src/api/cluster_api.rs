@@ -0,0 +1,409 @@
+use std::io::Read;
+use std::time::Duration;
+
+use serde_json::{self, Value as Json};
+use hyper::{Method, StatusCode};
+use url::form_urlencoded;
+
+use system::System;
+use security::secrets_equal;
+use replication::{ReplicaOperation, PRIMARY_NODE_HEADER, check_replica_request};
+use cluster::state::{ClusterState, DiscoveryNode};
+use cluster::transport::{TransportRequest, TransportResponse, CLUSTER_SECRET_HEADER};
+
+use api::request::{Request, Response, ApiResult, OPAQUE_ID_HEADER};
+use api::utils::json_response;
+
+
+/// Set on requests that have been sent on to another node, so they aren't sent on again
+pub const FORWARDED_HEADER: &'static str = "X-Forwarded-By-Node";
+
+/// How long a node that a request was sent on to has to respond. Long enough for big bulk
+/// requests and updates by query
+pub const FORWARD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+
+pub fn view_get_cluster_health(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let health = system.health();
+    let number_of_nodes = system.cluster.number_of_nodes();
+
+    Ok(json_response(StatusCode::OK, json!({
+        "cluster_name": system.settings.cluster_name,
+        "status": health.status.name(),
+        "timed_out": false,
+        "number_of_nodes": number_of_nodes,
+        "number_of_data_nodes": number_of_nodes,
+        "number_of_indices": health.number_of_indices(),
+        "number_of_docs": health.docs,
+        "active_primary_shards": health.active_primary_shards,
+        "active_shards": health.active_shards,
+        "relocating_shards": 0,
+        "initializing_shards": health.initializing_shards,
+        "unassigned_shards": health.unassigned_shards,
+        "delayed_unassigned_shards": 0,
+        "number_of_pending_tasks": health.pending_tasks,
+        "number_of_in_flight_fetch": 0,
+        "task_max_waiting_in_queue_millis": 0,
+        "active_shards_percent_as_number": health.active_shards_percent(),
+        "disk_watermarks": health.disk_watermarks,
+    })))
+}
+
+
+/// Shows the cluster state as this node last saw it. See `cluster::state`
+pub fn view_get_cluster_state(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    Ok(json_response(StatusCode::OK, system.cluster.state().to_json()))
+}
+
+
+/// Whether a route changes the cluster state, so has to be run on the master
+pub fn changes_cluster_state(method: &Method, pattern: &str) -> bool {
+    match pattern {
+        "/:index" | "/:index/_alias/:alias" => *method == Method::PUT || *method == Method::DELETE,
+        "/:index/_close" | "/:index/_open" | "/_aliases" => *method == Method::POST,
+        "/:index/_mapping/:mapping" | "/:index/_settings" | "/_cluster/settings" => *method == Method::PUT,
+
+        // Dangling indices are looked for in the master's data directory
+        "/_dangling" | "/_dangling/:index" => true,
+        _ => false,
+    }
+}
+
+
+/// How a route uses the documents of the index named in its path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentAccess {
+    Read,
+    Write,
+}
+
+
+/// Whether a route reads or writes the documents of the index in its path, so has to be run
+/// on a node with a copy of it
+///
+/// Bulk requests can name other indices in their actions, so they're sent on action by action
+/// (see `bulk_api`).
+pub fn document_access(method: &Method, pattern: &str) -> Option<DocumentAccess> {
+    match pattern {
+        "/:index/:mapping/:doc" if *method == Method::GET || *method == Method::HEAD => Some(DocumentAccess::Read),
+        "/:index/_search" | "/:index/_count" | "/:index/_search/template" | "/:index/_mget" |
+        "/:index/:mapping/_mget" | "/:index/:mapping/:doc/_explain" | "/:index/:mapping/:doc/_termvectors" |
+        "/:index/:mapping/_termvectors" => Some(DocumentAccess::Read),
+        "/:index/:mapping/:doc" | "/:index/:mapping" | "/:index/:mapping/:doc/_create" |
+        "/:index/:mapping/:doc/_update" | "/:index/_update_by_query" | "/:index/:mapping/_update_by_query" |
+        "/:index/_delete_by_query" => Some(DocumentAccess::Write),
+        _ => None,
+    }
+}
+
+
+/// Finds the node a request for the documents of an index should be run on
+///
+/// Writes go to the node with the primary. Reads are run here if this node has an active
+/// copy, otherwise on the primary or a started replica. Returns None if the request should be
+/// run here, which includes requests that have already been sent on by another node, requests
+/// that name more than one index and scrolled searches, as scrolls are kept by the node that
+/// started them.
+pub fn document_node(req: &Request, access: DocumentAccess) -> Result<Option<DiscoveryNode>, Response> {
+    let ref system = req.system;
+
+    if req.header(FORWARDED_HEADER).is_some() {
+        return Ok(None);
+    }
+
+    let index_name = match req.params.get("index") {
+        Some(index_name) => index_name,
+        None => return Ok(None),
+    };
+
+    if access == DocumentAccess::Read && has_query_parameter(req, "scroll") {
+        return Ok(None);
+    }
+
+    index_node(system, index_name, access)
+}
+
+
+/// Finds the node that should handle an access to an index, or None if it's this one
+///
+/// Returns None if the index doesn't exist, so the request gets the usual 404.
+pub fn index_node(system: &System, index_name: &str, access: DocumentAccess) -> Result<Option<DiscoveryNode>, Response> {
+    // Aliases can only be followed by writes, where they have a single write index
+    let canonical_name = {
+        let cluster_metadata = system.metadata.read().unwrap();
+        let index_ref = match access {
+            DocumentAccess::Read => cluster_metadata.names.find_canonical(index_name),
+            DocumentAccess::Write => cluster_metadata.resolve_write_index(index_name).ok(),
+        };
+
+        match index_ref.and_then(|index_ref| cluster_metadata.index_name(&index_ref)) {
+            Some(canonical_name) => canonical_name.to_string(),
+            None => return Ok(None),
+        }
+    };
+
+    let routing = system.cluster.index_routing(&canonical_name);
+    let local_node_id = &system.cluster.local_node.id;
+    let node_id = match access {
+        DocumentAccess::Write => routing.primary.as_ref().map(|node_id| node_id.as_str()),
+        DocumentAccess::Read if routing.is_active_on(local_node_id) => return Ok(None),
+        DocumentAccess::Read => routing.active_nodes().into_iter().next(),
+    };
+
+    match node_id {
+        Some(node_id) if node_id == local_node_id.as_str() => Ok(None),
+        Some(node_id) => Ok(system.cluster.node(node_id)),
+        None => {
+            Err(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({
+                "message": format!("No active copy of index [{}] is assigned to a node", canonical_name)
+            })))
+        }
+    }
+}
+
+
+fn has_query_parameter(req: &Request, name: &str) -> bool {
+    req.uri.query().map_or(false, |url_query| form_urlencoded::parse(url_query.as_bytes()).any(|(key, _)| key == name))
+}
+
+
+fn read_body(req: &mut Request) -> Result<Vec<u8>, Response> {
+    let max_content_length = req.system.settings.max_content_length;
+    let mut body = Vec::new();
+    if req.body.by_ref().take(max_content_length).read_to_end(&mut body).is_err() {
+        return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "Couldn't read the request body"})));
+    }
+
+    Ok(body)
+}
+
+
+/// Builds a copy of a request to send on to another node
+///
+/// The other node checks the request's credentials again.
+fn forwarded_request(req: &Request, node: &DiscoveryNode, body: Vec<u8>) -> TransportRequest {
+    let path = req.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let mut request = TransportRequest::new(&node.address, req.method.clone(), path);
+    for name in &["Authorization", "Content-Type"] {
+        if let Some(value) = req.header(name) {
+            request.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    request.headers.push((FORWARDED_HEADER.to_string(), req.system.cluster.local_node.id.clone()));
+    request.headers.push((OPAQUE_ID_HEADER.to_string(), req.opaque_id.clone()));
+    request.body = body;
+
+    request
+}
+
+
+fn forwarded_response(response: TransportResponse) -> Response {
+    let content_type = response.content_type.unwrap_or_else(|| "application/json".to_string());
+    Response::with_body(response.status, &content_type, response.body)
+}
+
+
+/// Sends a request on to the master and returns its response
+///
+/// The master publishes any changes it makes to the cluster state before it responds.
+pub fn forward_to_master(req: &mut Request) -> Response {
+    let system = req.system.clone();
+    let master = match system.cluster.master() {
+        Some(master) => master,
+        None => return json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": "No master node has been elected"})),
+    };
+
+    let request = match read_body(req) {
+        Ok(body) => forwarded_request(req, &master, body),
+        Err(response) => return response,
+    };
+
+    match system.cluster.forward_to_master(request) {
+        Ok(response) => forwarded_response(response),
+        Err(e) => {
+            warn!(req.log, "failed to forward request to master"; "master" => master.name, "error" => e.clone());
+            json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": format!("Couldn't reach the master node: {}", e)}))
+        }
+    }
+}
+
+
+/// Sends a request on to another node and returns its response
+pub fn forward_to_node(req: &mut Request, node: &DiscoveryNode) -> Response {
+    match read_body(req) {
+        Ok(body) => forward_body_to_node(req, node, body),
+        Err(response) => response,
+    }
+}
+
+
+/// Sends a request on to another node with a body that has already been read
+pub fn forward_body_to_node(req: &Request, node: &DiscoveryNode, body: Vec<u8>) -> Response {
+    let ref system = req.system;
+    let request = forwarded_request(req, node, body);
+
+    match system.cluster.transport().send(request, FORWARD_TIMEOUT) {
+        Ok(response) => forwarded_response(response),
+        Err(e) => {
+            warn!(req.log, "failed to forward request"; "node" => &node.name, "error" => e.clone());
+            json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": format!("Couldn't reach node [{}]: {}", node.name, e)}))
+        }
+    }
+}
+
+
+/// Checks that a request to one of the internal endpoints came from a node of the cluster
+///
+/// Nodes prove it with the cluster secret. Without one, a node is on its own and doesn't
+/// take internal requests at all.
+pub fn authenticate_node(req: &Request) -> ApiResult<()> {
+    let ref system = get_system!(req);
+
+    let secret = match system.settings.cluster_secret {
+        Some(ref secret) => secret,
+        None => {
+            warn!(req.log, "cluster request refused as no cluster secret is configured"; "path" => req.uri.path().to_string());
+            return Err(json_response(StatusCode::FORBIDDEN, json!({"message": "This node doesn't take cluster requests as no cluster_secret is configured"})));
+        }
+    };
+
+    match req.header(CLUSTER_SECRET_HEADER) {
+        Some(value) if secrets_equal(value, secret) => Ok(()),
+        _ => {
+            warn!(req.log, "cluster request with missing or invalid secret"; "path" => req.uri.path().to_string());
+            Err(json_response(StatusCode::UNAUTHORIZED, json!({"message": "Missing or invalid cluster secret"})))
+        }
+    }
+}
+
+
+pub fn view_get_internal_ping(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    Ok(json_response(StatusCode::OK, serde_json::to_value(system.cluster.ping_response()).unwrap_or_default()))
+}
+
+
+/// Adds the node in the body to the cluster, returning the new cluster state
+pub fn view_post_internal_join(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let data = json_from_request_body!(req).unwrap_or_default();
+
+    let node = match serde_json::from_value::<DiscoveryNode>(data) {
+        Ok(node) => node,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid node: {}", e)}))),
+    };
+
+    match system.cluster.handle_join(system, node) {
+        Ok(state) => Ok(json_response(StatusCode::OK, serde_json::to_value(state).unwrap_or_default())),
+        Err(e) => Ok(json_response(StatusCode::CONFLICT, json!({"message": e}))),
+    }
+}
+
+
+/// Applies a cluster state published by the master
+pub fn view_post_internal_state(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let data = json_from_request_body!(req).unwrap_or_default();
+
+    let state = match serde_json::from_value::<ClusterState>(data) {
+        Ok(state) => state,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid cluster state: {}", e)}))),
+    };
+
+    match system.cluster.apply_state(system, state) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::CONFLICT, json!({"message": e}))),
+    }
+}
+
+
+/// Marks a replica as started, once its primary has finished recovering it. Only the master
+/// takes these
+pub fn view_post_internal_shard_started(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let data = json_from_request_body!(req).unwrap_or_default();
+    let (index_name, node_id) = match (data.get("index").and_then(|v| v.as_str()), data.get("node").and_then(|v| v.as_str())) {
+        (Some(index_name), Some(node_id)) => (index_name, node_id),
+        _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Both index and node must be given"}))),
+    };
+
+    match system.cluster.handle_shard_started(system, index_name, node_id) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::CONFLICT, json!({"message": e}))),
+    }
+}
+
+
+/// Takes a copy of an index that failed out of the routing. Only the master takes these
+pub fn view_post_internal_shard_failed(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let data = json_from_request_body!(req).unwrap_or_default();
+    let (index_name, node_id) = match (data.get("index").and_then(|v| v.as_str()), data.get("node").and_then(|v| v.as_str())) {
+        (Some(index_name), Some(node_id)) => (index_name, node_id),
+        _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Both index and node must be given"}))),
+    };
+    let reason = data.get("reason").and_then(|v| v.as_str()).unwrap_or("");
+
+    match system.cluster.handle_shard_failed(system, index_name, node_id, reason) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::CONFLICT, json!({"message": e}))),
+    }
+}
+
+
+/// Refuses requests to change the copy of an index on this node that don't come from its
+/// primary, or that the copy isn't in a state to take
+fn check_from_primary(req: &Request, index_name: &str, recovery: bool) -> ApiResult<()> {
+    check_replica_request(&req.system, index_name, req.header(PRIMARY_NODE_HEADER), recovery).map_err(|e| {
+        warn!(req.log, "refused replication request"; "index" => index_name, "path" => req.uri.path().to_string(), "error" => e.clone());
+        json_response(StatusCode::FORBIDDEN, json!({"message": e}))
+    })
+}
+
+
+/// Applies writes sent by the primary of an index to the replica on this node
+pub fn view_post_internal_replicate(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    check_from_primary(req, index_name, false)?;
+    let data = json_from_request_body!(req).unwrap_or_default();
+
+    let operations = match serde_json::from_value::<Vec<ReplicaOperation>>(data.get("operations").cloned().unwrap_or(Json::Null)) {
+        Ok(operations) => operations,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid operations: {}", e)}))),
+    };
+
+    match system.replication.apply_operations(system, index_name, &operations) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": e}))),
+    }
+}
+
+
+/// Empties the replica on this node, before its primary sends it all of its documents
+pub fn view_post_internal_recovery_start(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    check_from_primary(req, index_name, true)?;
+
+    match system.replication.start_recovery(system, index_name) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": e}))),
+    }
+}
+
+
+pub fn view_post_internal_recovery_finish(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    check_from_primary(req, index_name, true)?;
+
+    match system.replication.finish_recovery(system, index_name) {
+        Ok(()) => Ok(json_response(StatusCode::OK, json!({"acknowledged": true}))),
+        Err(e) => Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": e}))),
+    }
+}
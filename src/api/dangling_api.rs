@@ -0,0 +1,89 @@
+use url::form_urlencoded;
+
+use system::DanglingIndexError;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::json_response;
+
+
+/// Importing or deleting a dangling index must be confirmed with `accept_data_loss=true`,
+/// as its data may be older than the rest of the cluster's
+fn check_accept_data_loss(req: &Request) -> ApiResult<()> {
+    let accepted = req.uri.query().map_or(false, |url_query| {
+        form_urlencoded::parse(url_query.as_bytes()).any(|(key, value)| key == "accept_data_loss" && value == "true")
+    });
+
+    if !accepted {
+        return Err(json_response(StatusCode::BAD_REQUEST, json!({
+            "message": "accept_data_loss must be set to true"
+        })));
+    }
+
+    Ok(())
+}
+
+
+fn dangling_index_error_response(index_name: &str, error: DanglingIndexError) -> Response {
+    match error {
+        DanglingIndexError::NotFound => {
+            json_response(StatusCode::NOT_FOUND, json!({
+                "message": format!("No dangling index found with name {}", index_name)
+            }))
+        }
+        DanglingIndexError::NameInUse => {
+            json_response(StatusCode::BAD_REQUEST, json!({
+                "message": format!("Another index or alias is already named {}", index_name)
+            }))
+        }
+        DanglingIndexError::Failed(e) => {
+            json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "message": format!("Unable to load dangling index {}: {}", index_name, e)
+            }))
+        }
+    }
+}
+
+
+pub fn view_get_dangling_indices(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    let dangling_indices = system.find_dangling_indices().into_iter().map(|dangling_index| {
+        json!({
+            "index_name": dangling_index.name,
+            "creation_date_millis": dangling_index.creation_date,
+            "closed": dangling_index.closed,
+            "node_ids": [&system.cluster.local_node.id],
+        })
+    }).collect::<Vec<_>>();
+
+    Ok(json_response(StatusCode::OK, json!({"dangling_indices": dangling_indices})))
+}
+
+
+pub fn view_post_import_dangling_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    check_accept_data_loss(req)?;
+
+    let mut cluster_metadata = system.metadata.write().unwrap();
+    if let Err(e) = system.import_dangling_index(&mut cluster_metadata, index_name) {
+        return Ok(dangling_index_error_response(index_name, e));
+    }
+
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+}
+
+
+pub fn view_delete_dangling_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    check_accept_data_loss(req)?;
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    if let Err(e) = system.delete_dangling_index(&cluster_metadata, index_name) {
+        return Ok(dangling_index_error_response(index_name, e));
+    }
+
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+}
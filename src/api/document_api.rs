@@ -1,117 +1,1128 @@
 use std::io::Read;
+use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 use serde_json;
+use serde_json::{Map, Value as Json};
+use url::form_urlencoded;
 
-use document::DocumentSource;
+use search::document::DocId;
+use search::query::Query;
+use search::profile::duration_to_nanos;
+use search::cancellation::SearchCancellation;
+use search::backends::rocksdb::{RocksDBReader, DocumentVersion, WriteCondition, VersionConflict, DocumentInsertError, DocumentDeleteError};
+use document::{DocumentSource, read_source_field, generate_doc_id};
+use index::Index;
+use index::reader::split_doc_id;
+use index::metadata::IndexMetadata;
+use cluster::metadata::ResolveError;
+use source_filter::SourceFilter;
+use query_parser::{QueryBuildContext, parse as parse_query};
+use query_parser::source_filter::parse as parse_source_filter;
+use update::{UpdateRequest, UpdateError, UpdateScript, UpdateOperation};
+use reindex::ReindexStatus;
+use tasks::TaskStatus;
+use slowlog;
+use replication::{ReplicaOperation, ShardsInfo, check_active_shards, replicate};
+use security::roles::{Permissions, IndexPrivilege};
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::router::Router;
-use api::utils::json_response;
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, get_refresh_policy, get_wait_for_active_shards, unavailable_shards_response, index_blocked_response, forbidden_response, alias_filter, apply_alias_filter, role_filter, apply_role_filter, get_routing};
+use api::security_api::{get_permissions, missing_index_privilege_message, document_queries_update_message};
 
 
-pub fn view_get_doc(req: &mut Request) -> IronResult<Response> {
+/// Reads the `version`, `if_seq_no` and `if_primary_term` URL parameters
+///
+/// Returns an error response if the parameters are invalid
+fn get_write_condition(req: &Request) -> Result<Option<WriteCondition>, Response> {
+    let mut version = None;
+    let mut if_seq_no = None;
+    let mut if_primary_term = None;
+
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            let param = match key.as_ref() {
+                "version" => &mut version,
+                "if_seq_no" => &mut if_seq_no,
+                "if_primary_term" => &mut if_primary_term,
+                _ => continue,
+            };
+
+            match value.parse::<u64>() {
+                Ok(value) => *param = Some(value),
+                Err(_) => {
+                    return Err(json_response(StatusCode::BAD_REQUEST, json!({
+                        "message": format!("Invalid value for {} parameter: {:?}", key, value)
+                    })));
+                }
+            }
+        }
+    }
+
+    match (version, if_seq_no, if_primary_term) {
+        (None, None, None) => Ok(None),
+        (Some(version), None, None) => Ok(Some(WriteCondition::Version(version))),
+        (None, Some(seq_no), Some(primary_term)) => {
+            Ok(Some(WriteCondition::SeqNo {
+                seq_no: seq_no,
+                primary_term: primary_term,
+            }))
+        }
+        (None, _, _) => {
+            Err(json_response(StatusCode::BAD_REQUEST, json!({
+                "message": "if_seq_no and if_primary_term must be used together"
+            })))
+        }
+        (Some(_), _, _) => {
+            Err(json_response(StatusCode::BAD_REQUEST, json!({
+                "message": "version can't be used with if_seq_no and if_primary_term"
+            })))
+        }
+    }
+}
+
+
+/// Reads the `_source`, `_source_includes` and `_source_excludes` URL parameters
+///
+/// `_source` is either a boolean or a comma-separated list of fields to include.
+fn get_source_filter(req: &Request) -> SourceFilter {
+    let mut enabled = true;
+    let mut includes = Vec::new();
+    let mut excludes = Vec::new();
+
+    let split = |value: &str| value.split(',').map(str::trim).filter(|field| !field.is_empty()).map(str::to_string).collect::<Vec<_>>();
+
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            match key.as_ref() {
+                "_source" => {
+                    match value.as_ref() {
+                        "true" => enabled = true,
+                        "false" => enabled = false,
+                        fields => includes.extend(split(fields)),
+                    }
+                }
+                "_source_includes" | "_source_include" => includes.extend(split(&value)),
+                "_source_excludes" | "_source_exclude" => excludes.extend(split(&value)),
+                _ => {}
+            }
+        }
+    }
+
+    if !enabled {
+        return SourceFilter::Disabled;
+    }
+
+    SourceFilter::Filter {
+        includes: includes,
+        excludes: excludes,
+    }
+}
+
+
+/// Reads the `retry_on_conflict` URL parameter of the update API
+///
+/// Returns an error response if the value isn't a positive integer
+fn get_retry_on_conflict(req: &Request) -> Result<u64, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "retry_on_conflict" {
+                return value.parse::<u64>().map_err(|_| {
+                    json_response(StatusCode::BAD_REQUEST, json!({
+                        "message": format!("Invalid value for retry_on_conflict parameter: {:?}", value)
+                    }))
+                });
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+
+fn version_conflict_response(mapping_name: &str, doc_key: &str, conflict: &VersionConflict) -> Response {
+    json_response(StatusCode::CONFLICT, json!({
+        "message": format!("[{}][{}]: {}", mapping_name, doc_key, conflict)
+    }))
+}
+
+
+fn document_json(index_name: &str, mapping_name: &str, doc_key: &str, version: &DocumentVersion) -> Json {
+    json!({
+        "_index": index_name,
+        "_type": mapping_name,
+        "_id": doc_key,
+        "_version": version.version,
+        "_seq_no": version.seq_no,
+        "_primary_term": version.primary_term,
+    })
+}
+
+
+/// Reads a document's source. Returns None if it isn't stored
+fn read_source(index_reader: &RocksDBReader, index_metadata: &IndexMetadata, doc_id: DocId) -> Option<Map<String, Json>> {
+    let field_ref = index_metadata.get_field_mapping("_source")?.index_ref?;
+    read_source_field(index_reader, field_ref, doc_id)
+}
+
+
+/// The filter that documents must match to be got through `index_name`
+///
+/// This combines the filter of a filtered alias with the ones the request's roles put on the
+/// index, so gets can't see documents that a search through the same name wouldn't find.
+/// Returns None if every document can be got.
+pub fn read_filter(permissions: &Permissions, index_name: &str, index: &Index, index_metadata: &IndexMetadata) -> Option<Query> {
+    if index_metadata.alias_filter(index_name).is_none() && permissions.document_queries(&[index_name, index.canonical_name()]).is_none() {
+        return None;
+    }
+
+    let index_reader = index.reader();
+    let schema = index_reader.schema();
+
+    Some(Query::Conjunction {
+        queries: alias_filter(index_name, index_metadata, schema).into_iter()
+            .chain(role_filter(permissions, index_name, index.canonical_name(), index_metadata, schema))
+            .collect(),
+    })
+}
+
+
+/// Checks that a document matches the filter from `read_filter`, if there is one
+pub fn document_readable(index_reader: &RocksDBReader, doc_id: DocId, filter: Option<&Query>) -> bool {
+    match filter {
+        Some(filter) => index_reader.document_matches(filter, doc_id).unwrap_or(false),
+        None => true,
+    }
+}
+
+
+/// Finds a document, returning the JSON the get API responds with
+///
+/// Returns None if the document doesn't exist, or doesn't match the filter.
+fn get_document_json(index: &Index, index_metadata: &IndexMetadata, mapping_name: &str, doc_key: &str, routing: Option<&str>, source_filter: &SourceFilter, filter: Option<&Query>) -> Option<Json> {
+    let index_reader = index.shard_for_routing(routing.unwrap_or(doc_key)).reader();
+    let (doc_id, version) = index_reader.get_document_by_key(doc_key)?;
+
+    if !document_readable(&index_reader, doc_id, filter) {
+        return None;
+    }
+
+    let mut response = document_json(index.canonical_name(), mapping_name, doc_key, &version);
+    response["found"] = json!(true);
+
+    // Load source
+    if let Some(source) = read_source(&index_reader, index_metadata, doc_id) {
+        if let Some(source) = source_filter.filter(&source) {
+            response["_source"] = source;
+        }
+    }
+
+    Some(response)
+}
+
+
+fn document_not_found_json(index_name: &str, mapping_name: &str, doc_key: &str) -> Json {
+    json!({
+        "_index": index_name,
+        "_type": mapping_name,
+        "_id": doc_key,
+        "found": false,
+    })
+}
+
+
+pub fn view_get_doc(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
     let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
-    // let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let source_filter = get_source_filter(req);
+    let routing = get_routing(req);
+    let permissions = get_permissions(req);
 
     // Get index
     let cluster_metadata = system.metadata.read().unwrap();
     let index = get_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
     let index_metadata = index.metadata.read().unwrap();
 
+    if index_metadata.settings.blocks.blocks_read() {
+        return Ok(index_blocked_response(index.canonical_name(), "read"));
+    }
+
     // Check that the mapping exists
     if !index_metadata.mappings.contains_key(*mapping_name) {
-        return Ok(json_response(status::NotFound, json!({"message": "Mapping not found"})));
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"})));
     }
 
-    // Find document
-    /*
-    let index_reader = index.store.reader();
-    let doc = match index_reader.get_document_by_key(doc_key) {
-        Some(doc) => doc,
-        None => {
-            return Ok(json_response(status::NotFound, "{\"message\": \"Document not found\"}"));
+    let filter = read_filter(&permissions, index_name, &index, &index_metadata);
+    match get_document_json(&index, &index_metadata, mapping_name, doc_key, routing.as_ref().map(|routing| &routing[..]), &source_filter, filter.as_ref()) {
+        Some(response) => Ok(json_response(StatusCode::OK, response)),
+        None => Ok(json_response(StatusCode::NOT_FOUND, document_not_found_json(index.canonical_name(), mapping_name, doc_key))),
+    }
+}
+
+
+/// A document requested by the multi get API
+struct MultiGetItem {
+    index_name: Option<String>,
+    mapping_name: Option<String>,
+    doc_key: String,
+    routing: Option<String>,
+    source_filter: Option<SourceFilter>,
+}
+
+
+/// Parses the "docs" and "ids" of a multi get request
+fn parse_multi_get_items(body: &Json) -> Result<Vec<MultiGetItem>, String> {
+    let body = body.as_object().ok_or("Request body must be an object")?;
+    let mut items = Vec::new();
+
+    for (key, value) in body.iter() {
+        match key.as_ref() {
+            "docs" => {
+                for doc in value.as_array().ok_or("docs must be an array")?.iter() {
+                    let doc = doc.as_object().ok_or("Each of docs must be an object")?;
+                    let mut item = MultiGetItem {
+                        index_name: None,
+                        mapping_name: None,
+                        doc_key: String::new(),
+                        routing: None,
+                        source_filter: None,
+                    };
+                    let mut has_id = false;
+
+                    for (key, value) in doc.iter() {
+                        match key.as_ref() {
+                            "_index" => item.index_name = Some(value.as_str().ok_or("_index must be a string")?.to_string()),
+                            "_type" => item.mapping_name = Some(value.as_str().ok_or("_type must be a string")?.to_string()),
+                            "_id" => {
+                                item.doc_key = value.as_str().ok_or("_id must be a string")?.to_string();
+                                has_id = true;
+                            }
+                            "routing" | "_routing" => item.routing = Some(value.as_str().ok_or("routing must be a string")?.to_string()),
+                            "_source" => item.source_filter = Some(parse_source_filter(value).map_err(|e| format!("_source error: {:?}", e))?),
+                            _ => return Err(format!("Unrecognised key in docs: {:?}", key)),
+                        }
+                    }
+
+                    if !has_id {
+                        return Err("Each of docs must have an _id".to_string());
+                    }
+
+                    items.push(item);
+                }
+            }
+            "ids" => {
+                for id in value.as_array().ok_or("ids must be an array")?.iter() {
+                    items.push(MultiGetItem {
+                        index_name: None,
+                        mapping_name: None,
+                        doc_key: id.as_str().ok_or("ids must be strings")?.to_string(),
+                        routing: None,
+                        source_filter: None,
+                    });
+                }
+            }
+            _ => return Err(format!("Unrecognised key: {:?}", key)),
         }
+    }
+
+    Ok(items)
+}
+
+
+/// Gets several documents at once, which may be in different indices
+///
+/// Each document is returned as it would be by the get API, or with an "error" if it
+/// couldn't be looked up.
+pub fn view_post_mget(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let default_index_name = read_path_parameter!(req, "index").map(|name| name.to_string());
+    let default_mapping_name = read_path_parameter!(req, "mapping").map(|name| name.to_string());
+    let default_source_filter = get_source_filter(req);
+    let permissions = get_permissions(req);
+
+    let items = match json_from_request_body!(req).map(|body| parse_multi_get_items(&body)) {
+        Some(Ok(items)) => items,
+        Some(Err(message)) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "No data"}))),
     };
-    */
 
+    let cluster_metadata = system.metadata.read().unwrap();
+    let mut docs = Vec::with_capacity(items.len());
+
+    for item in items.iter() {
+        let index_name = match item.index_name.as_ref().or(default_index_name.as_ref()) {
+            Some(index_name) => index_name,
+            None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "_index is required for each document"}))),
+        };
+
+        let error = |message: &str| json!({"_index": index_name, "_id": item.doc_key, "error": message});
+
+        if !permissions.allows_index(index_name, IndexPrivilege::Read) {
+            docs.push(error(&missing_index_privilege_message(index_name, IndexPrivilege::Read)));
+            continue;
+        }
+
+        // Get index
+        let index = match cluster_metadata.resolve_index(index_name).map(|index_ref| cluster_metadata.indices.get(&index_ref)) {
+            Ok(Some(index)) => index,
+            Ok(None) | Err(ResolveError::NotFound) => {
+                docs.push(error("Index not found"));
+                continue;
+            }
+            Err(ResolveError::Closed) => {
+                docs.push(error("Index is closed"));
+                continue;
+            }
+            Err(_) => {
+                docs.push(error("Alias has more than one index"));
+                continue;
+            }
+        };
+        let index_metadata = index.metadata.read().unwrap();
+
+        if index_metadata.settings.blocks.blocks_read() {
+            docs.push(error("Index is blocked for read operations"));
+            continue;
+        }
 
-    // Build JSON document
-    // TODO: This is probably completely wrong
-    // let json_object = BTreeMap::new();
-    // FIXME: for (field_name, field_value) in doc.fields.iter() {
-    // FIXME:     json_object.insert(field_name.clone(), Json::Array(field_value.iter().map(|v| v.term.as_json()).collect::<Vec<_>>()));
-    // FIXME: }
+        // Documents don't need a type if the index only has one mapping
+        let mapping_name = match item.mapping_name.as_ref().or(default_mapping_name.as_ref()) {
+            Some(mapping_name) => mapping_name.as_str(),
+            None if index_metadata.mappings.len() == 1 => index_metadata.mappings.keys().next().unwrap().as_str(),
+            None => {
+                docs.push(error("_type is required as the index has more than one mapping"));
+                continue;
+            }
+        };
+
+        let source_filter = item.source_filter.as_ref().unwrap_or(&default_source_filter);
+        let doc = if index_metadata.mappings.contains_key(mapping_name) {
+            let filter = read_filter(&permissions, index_name, index, &index_metadata);
+            get_document_json(index, &index_metadata, mapping_name, &item.doc_key, item.routing.as_ref().map(|routing| &routing[..]), source_filter, filter.as_ref())
+        } else {
+            None
+        };
 
-    return Ok(json_response(status::Ok, json!({})));
+        docs.push(doc.unwrap_or_else(|| document_not_found_json(index.canonical_name(), mapping_name, &item.doc_key)));
+    }
+
+    return Ok(json_response(StatusCode::OK, json!({"docs": docs})));
 }
 
 
-pub fn view_put_doc(req: &mut Request) -> IronResult<Response> {
+/// Checks if a document exists, without returning it
+pub fn view_head_doc(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
     let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
     let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let permissions = get_permissions(req);
 
     // Get index
     let cluster_metadata = system.metadata.read().unwrap();
     let index = get_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
     let index_metadata = index.metadata.read().unwrap();
 
-    let doc = {
-        // Find mapping
-        let mapping = match index_metadata.mappings.get(*mapping_name) {
-            Some(mapping) => mapping,
-            None => {
-                return Ok(json_response(status::NotFound, json!({"message": "Mapping not found"})));
+    if index_metadata.settings.blocks.blocks_read() {
+        return Ok(index_blocked_response(index.canonical_name(), "read"));
+    }
+
+    if !index_metadata.mappings.contains_key(*mapping_name) {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+
+    let routing = get_routing(req);
+    let filter = read_filter(&permissions, index_name, &index, &index_metadata);
+    let index_reader = index.shard_for_routing(routing.as_ref().map_or(*doc_key, |routing| &routing[..])).reader();
+    let found = match index_reader.get_document_by_key(doc_key) {
+        Some((doc_id, _)) => document_readable(&index_reader, doc_id, filter.as_ref()),
+        None => false,
+    };
+    return Ok(Response::new(if found { StatusCode::OK } else { StatusCode::NOT_FOUND }));
+}
+
+
+/// Reads the `op_type` URL parameter. Returns true if it's "create"
+fn get_op_type_create(req: &Request) -> Result<bool, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "op_type" {
+                return match value.as_ref() {
+                    "index" => Ok(false),
+                    "create" => Ok(true),
+                    _ => Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "op_type must be index or create"}))),
+                };
             }
-        };
+        }
+    }
 
-        // Create document
-        if let Some(data) = json_from_request_body!(req) {
-            let document_source = DocumentSource {
-                key: doc_key,
-                data: data.as_object().unwrap(),
-            };
-            document_source.prepare(mapping).unwrap()
-        } else {
-            return Ok(json_response(status::NotFound, json!({"message": "No data"})));
+    Ok(false)
+}
+
+
+/// Indexes the document in the request body with the given key
+///
+/// If `create` is set, this fails with a conflict if the document already exists.
+fn index_doc(req: &mut Request, doc_key: &str, create: bool) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let write_condition = match get_write_condition(req) {
+        Ok(None) if create => Some(WriteCondition::NotExists),
+        Ok(Some(_)) if create => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Create operations can't have a version condition"})));
+        }
+        Ok(write_condition) => write_condition,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_write_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
+    let index_metadata = index.metadata.read().unwrap();
+
+    if index_metadata.settings.blocks.blocks_write() {
+        return Ok(index_blocked_response(index.canonical_name(), "write"));
+    }
+
+    if let Err(message) = check_active_shards(system, index.canonical_name(), &index_metadata.settings, wait_for_active_shards) {
+        return Ok(unavailable_shards_response(message));
+    }
+
+    // Find mapping
+    let mapping = match index_metadata.mappings.get(*mapping_name) {
+        Some(mapping) => mapping,
+        None => {
+            return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"})));
+        }
+    };
+
+    let data = match json_from_request_body!(req) {
+        Some(data) => data,
+        None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "No data"}))),
+    };
+
+    // Create document
+    let started_at = Instant::now();
+    let doc = DocumentSource {
+        key: doc_key,
+        data: data.as_object().unwrap(),
+    }.prepare(mapping).unwrap();
+
+    let routing = get_routing(req);
+    let shard = index.shard_number_for_routing(routing.as_ref().map_or(doc_key, |routing| &routing[..]));
+    let write = match index.shards()[shard].insert_or_update_document_with_condition(&doc, write_condition.as_ref()) {
+        Ok(write) => write,
+        Err(DocumentInsertError::VersionConflict(conflict)) => {
+            return Ok(version_conflict_response(mapping_name, doc_key, &conflict));
         }
+        Err(e) => panic!("document insert failed: {:?}", e),
     };
+    slowlog::log_index(&req.log, index.canonical_name(), &index_metadata.settings.slowlog, started_at.elapsed(), doc_key, &data);
+    drop(index_metadata);
 
-    index.store.insert_or_update_document(&doc).unwrap();
+    let shards = replicate(system, index.canonical_name(), &[ReplicaOperation::index(shard, doc_key, mapping_name, data.as_object().unwrap(), write.version.version)]);
 
-    // TODO: {"_index":"wagtail","_type":"searchtests_searchtest","_id":"searchtests_searchtest:5378","_version":1,"created":true}
-    return Ok(json_response(status::Ok, json!({})));
+    if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+        error!(req.log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
+    }
+
+    let created = write.created;
+    let mut response = document_json(index.canonical_name(), mapping_name, doc_key, &write.version);
+    response["result"] = json!(if created { "created" } else { "updated" });
+    response["created"] = json!(created);
+    response["_shards"] = shards.to_json();
+
+    return Ok(json_response(if created { StatusCode::CREATED } else { StatusCode::OK }, response));
 }
 
 
-pub fn view_delete_doc(req: &mut Request) -> IronResult<Response> {
+pub fn view_put_doc(req: &mut Request) -> ApiResult<Response> {
+    let doc_key = read_path_parameter!(req, "doc").unwrap_or("").to_string();
+    let create = match get_op_type_create(req) {
+        Ok(create) => create,
+        Err(response) => return Ok(response),
+    };
+
+    index_doc(req, &doc_key, create)
+}
+
+
+/// Indexes a document, failing if it already exists
+pub fn view_put_create_doc(req: &mut Request) -> ApiResult<Response> {
+    let doc_key = read_path_parameter!(req, "doc").unwrap_or("").to_string();
+
+    index_doc(req, &doc_key, true)
+}
+
+
+/// Indexes a document under a newly generated id
+pub fn view_post_doc(req: &mut Request) -> ApiResult<Response> {
+    let create = match get_op_type_create(req) {
+        Ok(create) => create,
+        Err(response) => return Ok(response),
+    };
+
+    index_doc(req, &generate_doc_id(), create)
+}
+
+
+pub fn view_delete_doc(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
     let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
     let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let write_condition = match get_write_condition(req) {
+        Ok(write_condition) => write_condition,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
 
     // Get index
     let cluster_metadata = system.metadata.read().unwrap();
-    let index = get_index_or_404!(cluster_metadata, *index_name);
+    let index = get_write_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
     let index_metadata = index.metadata.read().unwrap();
 
+    if index_metadata.settings.blocks.blocks_delete() {
+        return Ok(index_blocked_response(index.canonical_name(), "delete"));
+    }
+
+    if let Err(message) = check_active_shards(system, index.canonical_name(), &index_metadata.settings, wait_for_active_shards) {
+        return Ok(unavailable_shards_response(message));
+    }
+
     // Check that the mapping exists
     if !index_metadata.mappings.contains_key(*mapping_name) {
-        return Ok(json_response(status::NotFound, json!({"message": "Mapping not found"})));
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"})));
     }
 
-    // Make sure the document exists
-    if !index.store.reader().contains_document_key(doc_key) {
-        return Ok(json_response(status::NotFound, json!({"message": "Document not found"})));
+    // Delete document
+    let routing = get_routing(req);
+    let shard = index.shard_number_for_routing(routing.as_ref().map_or(*doc_key, |routing| &routing[..]));
+    let version = match index.shards()[shard].remove_document_by_key_with_condition(doc_key, write_condition.as_ref()) {
+        Ok(Some(version)) => version,
+        Ok(None) => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Document not found"}))),
+        Err(DocumentDeleteError::VersionConflict(conflict)) => {
+            return Ok(version_conflict_response(mapping_name, doc_key, &conflict));
+        }
+        Err(e) => panic!("document delete failed: {:?}", e),
+    };
+    drop(index_metadata);
+
+    let shards = replicate(system, index.canonical_name(), &[ReplicaOperation::delete(shard, doc_key, version.version)]);
+
+    if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+        error!(req.log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
     }
 
-    // Delete document
-    index.store.remove_document_by_key(doc_key).unwrap();
+    let mut response = document_json(index.canonical_name(), mapping_name, doc_key, &version);
+    response["result"] = json!("deleted");
+    response["found"] = json!(true);
+    response["_shards"] = shards.to_json();
+
+    return Ok(json_response(StatusCode::OK, response));
+}
+
+
+/// Updates a document with a partial document or a script
+///
+/// See `UpdateRequest::run` for how conflicting writes are handled.
+pub fn view_post_update_doc(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
+    let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let permissions = get_permissions(req);
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let write_condition = match get_write_condition(req) {
+        Ok(write_condition) => write_condition,
+        Err(response) => return Ok(response),
+    };
+    let retry_on_conflict = match get_retry_on_conflict(req) {
+        Ok(retry_on_conflict) => retry_on_conflict,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
+
+    // Parse the request
+    let request = match json_from_request_body!(req).map(|data| UpdateRequest::parse(&data)) {
+        Some(Ok(request)) => request,
+        Some(Err(message)) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "No data"}))),
+    };
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_write_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
+    let index_metadata = index.metadata.read().unwrap();
+
+    if permissions.document_queries(&[*index_name, index.canonical_name()]).is_some() {
+        return Ok(forbidden_response(document_queries_update_message(index.canonical_name())));
+    }
+
+    if index_metadata.settings.blocks.blocks_write() {
+        return Ok(index_blocked_response(index.canonical_name(), "write"));
+    }
+
+    if let Err(message) = check_active_shards(system, index.canonical_name(), &index_metadata.settings, wait_for_active_shards) {
+        return Ok(unavailable_shards_response(message));
+    }
+
+    let mapping = match index_metadata.mappings.get(*mapping_name) {
+        Some(mapping) => mapping,
+        None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"}))),
+    };
+
+    let routing = get_routing(req);
+    let update = match request.run(&index, &index_metadata, mapping, doc_key, routing.as_ref().map(|routing| &routing[..]), write_condition.as_ref(), retry_on_conflict) {
+        Ok(update) => update,
+        Err(UpdateError::VersionConflict(conflict)) => return Ok(version_conflict_response(mapping_name, doc_key, &conflict)),
+        Err(UpdateError::DocumentMissing) => {
+            return Ok(json_response(StatusCode::NOT_FOUND, json!({
+                "message": format!("[{}][{}]: document missing", mapping_name, doc_key)
+            })));
+        }
+        Err(UpdateError::SourceNotStored) => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "The document's source isn't stored, so it can't be updated"})));
+        }
+        Err(UpdateError::ScriptError(e)) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Script error: {}", e)}))),
+        Err(UpdateError::PrepareDocumentError(e)) => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Couldn't index document: {:?}", e)})));
+        }
+    };
+
+    drop(index_metadata);
+
+    // Noops don't write anything, so there's nothing to send to the replicas or refresh
+    let shard = index.shard_number_for_routing(routing.as_ref().map_or(*doc_key, |routing| &routing[..]));
+    let operations = match (update.version.as_ref(), update.source.as_ref()) {
+        (Some(version), Some(source)) => vec![ReplicaOperation::index(shard, doc_key, mapping_name, source, version.version)],
+        (Some(version), None) if update.result == "deleted" => vec![ReplicaOperation::delete(shard, doc_key, version.version)],
+        _ => Vec::new(),
+    };
+    let shards = replicate(system, index.canonical_name(), &operations);
+
+    if update.result != "noop" {
+        if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+            error!(req.log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
+        }
+    }
+
+    let mut response = match update.version {
+        Some(ref version) => document_json(index.canonical_name(), mapping_name, doc_key, version),
+        None => {
+            json!({
+                "_index": index.canonical_name(),
+                "_type": *mapping_name,
+                "_id": *doc_key,
+            })
+        }
+    };
+    response["result"] = json!(update.result);
+    response["_shards"] = shards.to_json();
+
+    return Ok(json_response(if update.result == "created" { StatusCode::CREATED } else { StatusCode::OK }, response));
+}
+
+
+/// Reads the "conflicts" URL parameter of the update by query API
+///
+/// Returns true if version conflicts should be counted rather than stopping the update.
+fn get_proceed_on_conflicts(req: &Request) -> Result<bool, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "conflicts" {
+                return match value.as_ref() {
+                    "abort" => Ok(false),
+                    "proceed" => Ok(true),
+                    _ => Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "conflicts must be abort or proceed"}))),
+                };
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+
+/// Rewrites every document that matches a query, optionally changing it with a script
+///
+/// Each document is reprocessed from its stored source using the current mapping, which
+/// picks up mapping and analyzer changes without having to reindex from the original data.
+/// Documents that are changed by another write while this is running are version conflicts.
+pub fn view_post_update_by_query(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let permissions = get_permissions(req);
+    let mapping_name = read_path_parameter!(req, "mapping").map(|name| name.to_string());
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let mut proceed_on_conflicts = match get_proceed_on_conflicts(req) {
+        Ok(proceed_on_conflicts) => proceed_on_conflicts,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
+    let start_time = Instant::now();
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
+    let index_metadata = index.metadata.read().unwrap();
+
+    if index_metadata.settings.blocks.blocks_write() {
+        return Ok(index_blocked_response(index.canonical_name(), "write"));
+    }
+
+    if let Err(message) = check_active_shards(system, index.canonical_name(), &index_metadata.settings, wait_for_active_shards) {
+        return Ok(unavailable_shards_response(message));
+    }
+
+    // Documents don't need a type if the index only has one mapping
+    let mapping_name = match mapping_name {
+        Some(mapping_name) => mapping_name,
+        None if index_metadata.mappings.len() == 1 => index_metadata.mappings.keys().next().unwrap().clone(),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "A type is required as the index has more than one mapping"}))),
+    };
+
+    let mapping = match index_metadata.mappings.get(&mapping_name) {
+        Some(mapping) => mapping,
+        None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"}))),
+    };
+
+    // Parse the request
+    let index_reader = index.reader();
+    let mut query = Query::all();
+    let mut script = None;
+
+    if let Some(data) = json_from_request_body!(req) {
+        let body = match data.as_object() {
+            Some(body) => body,
+            None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body must be an object"}))),
+        };
+
+        for (key, value) in body.iter() {
+            match key.as_ref() {
+                "query" => {
+                    query = match parse_query(value) {
+                        Ok(parsed_query) => parsed_query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata).no_score(), &index_reader.schema()),
+                        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {:?}", e)}))),
+                    };
+                }
+                "script" => {
+                    match UpdateScript::parse(value) {
+                        Ok(parsed_script) => script = Some(parsed_script),
+                        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Script error: {}", e)}))),
+                    }
+                }
+                "conflicts" => {
+                    proceed_on_conflicts = match value.as_str() {
+                        Some("abort") => false,
+                        Some("proceed") => true,
+                        _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "conflicts must be abort or proceed"}))),
+                    };
+                }
+                _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Unrecognised key: {:?}", key)}))),
+            }
+        }
+    }
+
+    // Find the documents to update. These are all read from the same snapshot, and each
+    // write is conditional on the document not having changed since then
+    let query = apply_alias_filter(query, index_name, &index_metadata, &index_reader.schema());
+    let query = apply_role_filter(query, &permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+    let doc_ids = match index_reader.matching_documents(&query) {
+        Ok(doc_ids) => doc_ids,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {}", e)}))),
+    };
+    let doc_keys = index_reader.document_keys();
+
+    let task_status = Arc::new(ReindexStatus::default());
+    task_status.total.store(doc_ids.len() as u64, Ordering::Relaxed);
+    let cancellation = SearchCancellation::new();
+    let _task = system.tasks.register_with_status("indices:data/write/update/byquery", format!("update-by-query [{}]", index.canonical_name()), Some(&req.opaque_id), cancellation.clone(), Some(task_status.clone()));
+
+    let mut failures = Vec::new();
+    let mut cancelled = false;
+    let mut operations = Vec::new();
+
+    for &doc_id in doc_ids.iter() {
+        if cancellation.is_cancelled() {
+            cancelled = true;
+            break;
+        }
+
+        // The document is written back to the shard it was found in, wherever it was routed to
+        let shard_reader = index_reader.shard_for_doc(doc_id);
+        let store = index.shard_for_doc(doc_id);
+        let (doc_key, version) = match doc_keys.get(&doc_id).and_then(|doc_key| shard_reader.get_document_by_key(doc_key).map(|(_, version)| (doc_key, version))) {
+            Some(doc) => doc,
+            None => continue,
+        };
+        let condition = WriteCondition::SeqNo { seq_no: version.seq_no, primary_term: version.primary_term };
+        let failure = |cause: String| json!({"index": index.canonical_name(), "type": mapping_name, "id": doc_key, "cause": cause});
+
+        let mut source = match read_source(shard_reader, &index_metadata, DocId::from_u64(doc_id)) {
+            Some(source) => source,
+            None => {
+                failures.push(failure("The document's source isn't stored, so it can't be updated".to_string()));
+                continue;
+            }
+        };
+
+        let operation = match script.as_ref().map(|script| script.run(&mut source)) {
+            Some(Ok(operation)) => operation,
+            Some(Err(e)) => {
+                failures.push(failure(format!("Script error: {}", e)));
+                continue;
+            }
+            None => UpdateOperation::Index,
+        };
+
+        let conflict = match operation {
+            UpdateOperation::Index => {
+                let doc = match (DocumentSource { key: doc_key, data: &source }).prepare(mapping) {
+                    Ok(doc) => doc,
+                    Err(e) => {
+                        failures.push(failure(format!("Couldn't index document: {:?}", e)));
+                        continue;
+                    }
+                };
+
+                match store.insert_or_update_document_with_condition(&doc, Some(&condition)) {
+                    Ok(write) => {
+                        task_status.updated.fetch_add(1, Ordering::Relaxed);
+                        operations.push(ReplicaOperation::index(split_doc_id(doc_id).0, doc_key, &mapping_name, &source, write.version.version));
+                        None
+                    }
+                    Err(DocumentInsertError::VersionConflict(conflict)) => Some(conflict),
+                    Err(e) => panic!("document insert failed: {:?}", e),
+                }
+            }
+            UpdateOperation::Delete => {
+                match store.remove_document_by_key_with_condition(doc_key, Some(&condition)) {
+                    Ok(version) => {
+                        task_status.deleted.fetch_add(1, Ordering::Relaxed);
+                        operations.extend(version.map(|version| ReplicaOperation::delete(split_doc_id(doc_id).0, doc_key, version.version)));
+                        None
+                    }
+                    Err(DocumentDeleteError::VersionConflict(conflict)) => Some(conflict),
+                    Err(e) => panic!("document delete failed: {:?}", e),
+                }
+            }
+            UpdateOperation::Noop => {
+                task_status.noops.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+
+        if let Some(conflict) = conflict {
+            task_status.version_conflicts.fetch_add(1, Ordering::Relaxed);
+
+            if !proceed_on_conflicts {
+                failures.push(failure(format!("[{}][{}]: {}", mapping_name, doc_key, conflict)));
+                break;
+            }
+        }
+    }
+
+    task_status.batches.store(1, Ordering::Relaxed);
+    drop(index_metadata);
+
+    let shards = replicate(system, index.canonical_name(), &operations);
+
+    if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+        error!(req.log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
+    }
+
+    Ok(by_query_response(&task_status, start_time, failures, cancelled, proceed_on_conflicts, shards))
+}
+
+
+/// Builds the response of the update and delete by query APIs
+fn by_query_response(task_status: &ReindexStatus, start_time: Instant, failures: Vec<Json>, cancelled: bool, proceed_on_conflicts: bool, shards: ShardsInfo) -> Response {
+    let mut response = task_status.to_json();
+    response["took"] = json!(duration_to_nanos(start_time.elapsed()) / 1_000_000);
+    response["timed_out"] = json!(false);
+    response["failures"] = json!(failures);
+    response["_shards"] = shards.to_json();
+
+    if cancelled {
+        response["canceled"] = json!("by user request");
+    }
+
+    let aborted = task_status.version_conflicts.load(Ordering::Relaxed) > 0 && !proceed_on_conflicts;
+    json_response(if aborted { StatusCode::CONFLICT } else { StatusCode::OK }, response)
+}
+
+
+/// Deletes every document that matches a query
+///
+/// Like update by query, documents that are changed by another write while this is running
+/// are version conflicts rather than being deleted.
+pub fn view_post_delete_by_query(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let permissions = get_permissions(req);
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let mut proceed_on_conflicts = match get_proceed_on_conflicts(req) {
+        Ok(proceed_on_conflicts) => proceed_on_conflicts,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_active_shards = match get_wait_for_active_shards(req) {
+        Ok(wait_for_active_shards) => wait_for_active_shards,
+        Err(response) => return Ok(response),
+    };
+    let start_time = Instant::now();
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
+    let index_metadata = index.metadata.read().unwrap();
+
+    if index_metadata.settings.blocks.blocks_delete() {
+        return Ok(index_blocked_response(index.canonical_name(), "delete"));
+    }
+
+    if let Err(message) = check_active_shards(system, index.canonical_name(), &index_metadata.settings, wait_for_active_shards) {
+        return Ok(unavailable_shards_response(message));
+    }
+
+    // Parse the request. Unlike update by query, the query is required
+    let index_reader = index.reader();
+    let mut query = None;
+
+    let data = match json_from_request_body!(req) {
+        Some(data) => data,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing query"}))),
+    };
+
+    let body = match data.as_object() {
+        Some(body) => body,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body must be an object"}))),
+    };
+
+    for (key, value) in body.iter() {
+        match key.as_ref() {
+            "query" => {
+                query = match parse_query(value) {
+                    Ok(parsed_query) => Some(parsed_query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata).no_score(), &index_reader.schema())),
+                    Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {:?}", e)}))),
+                };
+            }
+            "conflicts" => {
+                proceed_on_conflicts = match value.as_str() {
+                    Some("abort") => false,
+                    Some("proceed") => true,
+                    _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "conflicts must be abort or proceed"}))),
+                };
+            }
+            _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Unrecognised key: {:?}", key)}))),
+        }
+    }
+
+    let query = match query {
+        Some(query) => query,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing query"}))),
+    };
+
+    // Find the documents to delete
+    let query = apply_alias_filter(query, index_name, &index_metadata, &index_reader.schema());
+    let query = apply_role_filter(query, &permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+    let doc_ids = match index_reader.matching_documents(&query) {
+        Ok(doc_ids) => doc_ids,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {}", e)}))),
+    };
+    let doc_keys = index_reader.document_keys();
+
+    let task_status = Arc::new(ReindexStatus::default());
+    task_status.total.store(doc_ids.len() as u64, Ordering::Relaxed);
+    let cancellation = SearchCancellation::new();
+    let _task = system.tasks.register_with_status("indices:data/write/delete/byquery", format!("delete-by-query [{}]", index.canonical_name()), Some(&req.opaque_id), cancellation.clone(), Some(task_status.clone()));
+
+    let mut failures = Vec::new();
+    let mut cancelled = false;
+    let mut operations = Vec::new();
+
+    for &doc_id in doc_ids.iter() {
+        if cancellation.is_cancelled() {
+            cancelled = true;
+            break;
+        }
+
+        // The document is written back to the shard it was found in, wherever it was routed to
+        let shard_reader = index_reader.shard_for_doc(doc_id);
+        let store = index.shard_for_doc(doc_id);
+        let (doc_key, version) = match doc_keys.get(&doc_id).and_then(|doc_key| shard_reader.get_document_by_key(doc_key).map(|(_, version)| (doc_key, version))) {
+            Some(doc) => doc,
+            None => continue,
+        };
+        let condition = WriteCondition::SeqNo { seq_no: version.seq_no, primary_term: version.primary_term };
+
+        match store.remove_document_by_key_with_condition(doc_key, Some(&condition)) {
+            Ok(version) => {
+                task_status.deleted.fetch_add(1, Ordering::Relaxed);
+                operations.extend(version.map(|version| ReplicaOperation::delete(split_doc_id(doc_id).0, doc_key, version.version)));
+            }
+            Err(DocumentDeleteError::VersionConflict(conflict)) => {
+                task_status.version_conflicts.fetch_add(1, Ordering::Relaxed);
+
+                if !proceed_on_conflicts {
+                    failures.push(json!({"index": index.canonical_name(), "id": doc_key, "cause": format!("[{}]: {}", doc_key, conflict)}));
+                    break;
+                }
+            }
+            Err(e) => panic!("document delete failed: {:?}", e),
+        }
+    }
+
+    task_status.batches.store(1, Ordering::Relaxed);
+    drop(index_metadata);
+
+    let shards = replicate(system, index.canonical_name(), &operations);
+
+    if let Err(e) = index.apply_refresh_policy(refresh_policy) {
+        error!(req.log, "index refresh failed"; "index" => index.canonical_name(), "error" => e);
+    }
 
-    return Ok(json_response(status::Ok, json!({})));
+    Ok(by_query_response(&task_status, start_time, failures, cancelled, proceed_on_conflicts, shards))
 }
@@ -0,0 +1,136 @@
+//! Serves the gRPC API (see `grpc`)
+//!
+//! Each call is run as an HTTP request through the same `Api` as the HTTP server, so it goes
+//! through the same checks and thread pools. The call's metadata is passed on as the
+//! request's headers.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::FutureExt;
+use futures::future::{self, BoxFuture};
+use hyper::{self, Body, Method, StatusCode};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use serde_json::{self, Value as Json};
+
+use api::{Api, ApiListener, get_router, start_listener};
+use api::request::{Response, OPAQUE_ID_HEADER};
+use api::server::Handler;
+use grpc::{GrpcMethod, GrpcStatus, GRPC_CONTENT_TYPE, read_message, write_message, encode_status_message, to_rest_request, from_rest_response, is_success, error_message};
+use system::System;
+
+
+/// Metadata that's passed on to the HTTP request a call is run as
+const FORWARDED_HEADERS: &'static [&'static str] = &["authorization", "x-opaque-id"];
+
+
+/// A response that ends a call with an error
+///
+/// There's no body, so the status goes in the headers ("Trailers-Only").
+fn error_response(status: GrpcStatus, message: &str) -> Response {
+    let mut response = Response::new(StatusCode::OK);
+    response.set_header(CONTENT_TYPE.as_str(), GRPC_CONTENT_TYPE);
+    response.set_header("grpc-status", &status.code().to_string());
+    response.set_header("grpc-message", &encode_status_message(message));
+    response
+}
+
+
+/// Turns the response to a call's HTTP request into the call's response
+fn grpc_response(method: GrpcMethod, response: Response) -> Response {
+    let body: Json = serde_json::from_slice(&response.body).unwrap_or(Json::Null);
+
+    let mut grpc_response = if is_success(method, response.status, &body) {
+        let mut grpc_response = Response::with_body(StatusCode::OK, GRPC_CONTENT_TYPE, write_message(&from_rest_response(method, &body)));
+        grpc_response.set_trailer("grpc-status", &GrpcStatus::Ok.code().to_string());
+        grpc_response
+    } else {
+        error_response(GrpcStatus::from_http(response.status), &error_message(response.status, &body))
+    };
+
+    if let Some(opaque_id) = response.headers.get(OPAQUE_ID_HEADER) {
+        grpc_response.headers.insert(OPAQUE_ID_HEADER, opaque_id.clone());
+    }
+
+    grpc_response
+}
+
+
+struct GrpcApi {
+    api: Arc<Api>,
+}
+
+
+impl Handler for GrpcApi {
+    fn handle(&self, req: hyper::Request<Body>, remote_addr: SocketAddr) -> BoxFuture<'static, Response> {
+        let (parts, body) = req.into_parts();
+
+        let method = match GrpcMethod::from_path(parts.uri.path()) {
+            Some(method) if parts.method == Method::POST => method,
+            _ => return future::ready(error_response(GrpcStatus::Unimplemented, &format!("Unknown method: {}", parts.uri.path()))).boxed(),
+        };
+
+        // Calls are read whole, so they're limited before reading as well as after
+        let max_content_length = self.api.system.settings.max_content_length;
+        let content_length = parts.headers.get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()).and_then(|value| value.parse::<u64>().ok());
+        if content_length.unwrap_or(0) > max_content_length {
+            return future::ready(error_response(GrpcStatus::ResourceExhausted, &format!("The request is larger than the limit of {} bytes", max_content_length))).boxed();
+        }
+
+        let api = self.api.clone();
+        hyper::body::to_bytes(body).then(move |body| {
+            let body = match body {
+                Ok(body) => body,
+                Err(error) => return future::ready(error_response(GrpcStatus::Internal, &format!("Couldn't read request: {}", error))).boxed(),
+            };
+
+            if body.len() as u64 > max_content_length {
+                return future::ready(error_response(GrpcStatus::ResourceExhausted, &format!("The request is larger than the limit of {} bytes", max_content_length))).boxed();
+            }
+
+            let rest_request = match read_message(&body) {
+                Ok(message) => to_rest_request(method, message).map_err(|error| (GrpcStatus::InvalidArgument, error)),
+                Err(error) => Err(error),
+            };
+            let rest_request = match rest_request {
+                Ok(rest_request) => rest_request,
+                Err((status, error)) => return future::ready(error_response(status, &error)).boxed(),
+            };
+
+            let mut builder = hyper::Request::builder()
+                .method(rest_request.method)
+                .uri(rest_request.path.as_str())
+                .header(CONTENT_TYPE, rest_request.content_type)
+                .header(CONTENT_LENGTH, rest_request.body.len());
+
+            for name in FORWARDED_HEADERS {
+                if let Some(value) = parts.headers.get(*name) {
+                    builder = builder.header(*name, value.clone());
+                }
+            }
+
+            let internal_request = match builder.body(Body::from(rest_request.body)) {
+                Ok(internal_request) => internal_request,
+                Err(error) => return future::ready(error_response(GrpcStatus::InvalidArgument, &format!("Invalid request: {}", error))).boxed(),
+            };
+
+            api.handle(internal_request, remote_addr).map(move |response| grpc_response(method, response)).boxed()
+        }).boxed()
+    }
+}
+
+
+/// Starts the gRPC server in the background, on its own port
+///
+/// Returns None if the server couldn't be started.
+pub fn grpc_main(system: Arc<System>, port: u16) -> Option<ApiListener> {
+    let grpc_api = GrpcApi {
+        api: Arc::new(Api {
+            system: system.clone(),
+            router: get_router(),
+        }),
+    };
+
+    start_listener(&system, grpc_api, port, "grpc")
+}
+
@@ -0,0 +1,127 @@
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use lifecycle::LifecyclePolicy;
+use lifecycle::runner::explain;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, get_indices_options};
+
+
+pub fn view_put_lifecycle_policy(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref policy_name = read_path_parameter!(req, "name").unwrap_or("");
+
+    let policy = match json_from_request_body!(req) {
+        Some(json) => {
+            match json.get("policy").ok_or_else(|| "Missing policy".to_string()).and_then(LifecyclePolicy::from_json) {
+                Ok(policy) => policy,
+                Err(message) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+            }
+        }
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing policy"}))),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0);
+    match system.lifecycle_policies.insert(system.get_lifecycle_policies_path(), policy_name.to_string(), policy, now) {
+        Ok(version) => {
+            info!(req.log, "stored lifecycle policy"; "policy" => *policy_name, "version" => version);
+
+            Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+        }
+        Err(e) => {
+            error!(req.log, "failed to store lifecycle policy"; "policy" => *policy_name, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to store lifecycle policy"})))
+        }
+    }
+}
+
+
+pub fn view_get_lifecycle_policy(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let policy_name = read_path_parameter!(req, "name");
+
+    let mut response = json!({});
+    match policy_name {
+        Some(policy_name) => {
+            match system.lifecycle_policies.get(policy_name) {
+                Some(policy) => response[policy_name] = policy.to_json(),
+                None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("Lifecycle policy not found: {}", policy_name)}))),
+            }
+        }
+        None => {
+            for (policy_name, policy) in system.lifecycle_policies.list() {
+                response[&policy_name] = policy.to_json();
+            }
+        }
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
+
+
+pub fn view_delete_lifecycle_policy(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref policy_name = read_path_parameter!(req, "name").unwrap_or("");
+
+    // Policies can't be removed from under the indices that use them
+    {
+        let cluster_metadata = system.metadata.read().unwrap();
+        let open_indices = cluster_metadata.indices.values().filter(|index| {
+            index.metadata.read().unwrap().settings.lifecycle.name.as_ref().map(|name| name.as_ref()) == Some(*policy_name)
+        }).map(|index| index.canonical_name());
+        let closed_indices = cluster_metadata.closed_indices.values().filter(|index| {
+            index.metadata.settings.lifecycle.name.as_ref().map(|name| name.as_ref()) == Some(*policy_name)
+        }).map(|index| index.canonical_name());
+
+        let mut using_indices = open_indices.chain(closed_indices).collect::<Vec<_>>();
+        if !using_indices.is_empty() {
+            using_indices.sort();
+
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({
+                "message": format!("Lifecycle policy {:?} is in use by indices [{}]", policy_name, using_indices.join(", ")),
+            })));
+        }
+    }
+
+    match system.lifecycle_policies.remove(system.get_lifecycle_policies_path(), policy_name) {
+        Ok(true) => {
+            info!(req.log, "deleted lifecycle policy"; "policy" => *policy_name);
+
+            Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+        }
+        Ok(false) => Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("Lifecycle policy not found: {}", policy_name)}))),
+        Err(e) => {
+            error!(req.log, "failed to delete lifecycle policy"; "policy" => *policy_name, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to delete lifecycle policy"})))
+        }
+    }
+}
+
+
+/// Shows where each index is in its lifecycle policy, and which actions are due
+pub fn view_get_lifecycle_explain(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    let mut indices_json = json!({});
+    for (index_ref, _) in indices {
+        let index = &cluster_metadata.indices[&index_ref];
+        indices_json[index.canonical_name()] = explain(system, index);
+    }
+
+    Ok(json_response(StatusCode::OK, json!({"indices": indices_json})))
+}
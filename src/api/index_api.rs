@@ -1,22 +1,21 @@
-use std::fs;
 use std::io::Read;
 
 use serde_json;
-use search::backends::rocksdb::RocksDBStore;
-use uuid::Uuid;
+use url::form_urlencoded;
+
+use search::cancellation::SearchCancellation;
 
-use index::Index;
 use index::metadata::IndexMetadata;
 use index::metadata::parse::parse as parse_index_metadata;
+use cluster::metadata::IndicesOptions;
+use system::CloseIndexError;
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::router::Router;
-use api::utils::json_response;
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, get_indices_options};
 
 
-pub fn view_get_index(req: &mut Request) -> IronResult<Response> {
+pub fn view_get_index(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
 
@@ -29,24 +28,49 @@ pub fn view_get_index(req: &mut Request) -> IronResult<Response> {
         match serde_json::to_value(&index.metadata) {
             Ok(json) => json,
             Err(_) => {
-                return Ok(json_response(status::InternalServerError, json!({
+                return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
                     "message": "unable to serialise index metadata"
                 })));
             }
         }
     };
 
-    return Ok(json_response(status::Ok, json));
+    return Ok(json_response(StatusCode::OK, json));
 }
 
 
-pub fn view_put_index(req: &mut Request) -> IronResult<Response> {
+/// Checks whether an index exists. Aliases and wildcard patterns are resolved, and closed
+/// indices count as existing
+pub fn view_head_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => IndicesOptions { include_closed: true, ..indices_options },
+        Err(response) => return Ok(response),
+    };
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let exists = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => !indices.is_empty(),
+        Err(_) => false,
+    };
+
+    Ok(Response::new(if exists { StatusCode::OK } else { StatusCode::NOT_FOUND }))
+}
+
+
+pub fn view_put_index(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
 
     // Lock cluster metadata
     let mut cluster_metadata = system.metadata.write().unwrap();
 
+    // The index may exist on disk but still be loading
+    if system.is_recovering(index_name) {
+        return Ok(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": "Index is recovering"})));
+    }
+
     // Find index
     let index_ref = cluster_metadata.names.find_canonical(&index_name);
 
@@ -55,7 +79,7 @@ pub fn view_put_index(req: &mut Request) -> IronResult<Response> {
             // Update existing index
             // TODO
 
-            info!(system.log, "updated index"; "index" => *index_name);
+            info!(req.log, "updated index"; "index" => *index_name);
         }
         None => {
             // Load metadata
@@ -64,98 +88,281 @@ pub fn view_put_index(req: &mut Request) -> IronResult<Response> {
                 Some(Ok(())) | None => {}
                 Some(Err(_)) => {
                     // TODO: better error
-                    return Ok(json_response(status::BadRequest, json!({"message": "Couldn't parse index settings"})));
+                    return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Couldn't parse index settings"})));
                 }
             }
 
-            // Create index
-            let mut indices_dir = system.get_indices_dir();
-            indices_dir.push(index_name);
-            let index = Index::new(Uuid::new_v4(), index_name.clone().to_owned(), metadata, RocksDBStore::create(indices_dir).unwrap());
-            index.metadata.read().unwrap().save(index.metadata_path()).unwrap();
-            let index_ref = cluster_metadata.insert_index(index);
-
-            // If there's an alias with the new indexes name, delete it.
-            let alias_deleted = cluster_metadata.names.delete_alias_whole(index_name).unwrap();
-            if alias_deleted {
-                info!(system.log, "deleted alias"; "alias" => format!("{}", index_name), "reason" => "replaced by index");
+            // Names are shared between indices and aliases
+            if cluster_metadata.names.is_alias(index_name) {
+                return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("An alias named {:?} already exists", index_name)})));
             }
 
-            // Register canonical name
-            cluster_metadata.names.insert_canonical(index_name.clone().to_owned(), index_ref).unwrap();
+            for (alias_name, alias) in metadata.aliases.iter() {
+                if alias_name == index_name || cluster_metadata.names.find_canonical(alias_name).is_some() {
+                    return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("An index named {:?} already exists", alias_name)})));
+                }
 
-            info!(system.log, "created index"; "index" => *index_name);
+                if alias.is_write_index == Some(true) && cluster_metadata.alias_write_index(alias_name).is_some() {
+                    return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Alias {:?} already has a write index", alias_name)})));
+                }
+            }
+
+            // Create index
+            if system.create_index(&mut cluster_metadata, index_name, metadata).is_err() {
+                return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                    "message": "unable to create index"
+                })));
+            }
         }
     }
 
-    return Ok(json_response(status::Ok, json!({"acknowledged": true})));
+    return Ok(json_response(StatusCode::OK, json!({"acknowledged": true})));
 }
 
 
-pub fn view_delete_index(req: &mut Request) -> IronResult<Response> {
+/// Deletes indices, along with their data
+///
+/// This takes a comma separated list of index names. Wildcards and `_all` can be used too,
+/// unless `action.destructive_requires_name` is set. Aliases can't be used, so deleting
+/// through an alias can't remove more than was meant.
+pub fn view_delete_index(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_selector = read_path_parameter!(req, "index").unwrap_or("");
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => IndicesOptions { include_closed: true, ignore_aliases: true, ..indices_options },
+        Err(response) => return Ok(response),
+    };
+
+    if system.cluster_settings.current().destructive_requires_name && index_selector.split(',').any(|name| name == "_all" || name.contains('*')) {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({
+            "message": "Wildcard expressions and _all can't be used to delete indices while action.destructive_requires_name is set"
+        })));
+    }
 
     // Lock cluster metadata
     let mut cluster_metadata = system.metadata.write().unwrap();
 
-    // Make sure the index exists
-    get_index_or_404!(cluster_metadata, *index_selector);
+    // Find the indices. All of them are checked before any are deleted
+    let indices = match cluster_metadata.resolve_indices(index_selector, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
 
     // Remove indices
-    for index_ref in cluster_metadata.names.find(*index_selector) {
-        // Get the index name
-        let index_name = {
-            if let Some(index) = cluster_metadata.indices.get(&index_ref) {
-                index.canonical_name().to_string()
-            } else {
-                // Index doesn't exist
-                continue;
+    for (index_ref, _) in indices {
+        system.delete_index(&mut cluster_metadata, index_ref);
+    }
+
+    return Ok(json_response(StatusCode::OK, json!({"acknowledged": true})));
+}
+
+
+pub fn view_post_refresh_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+
+    match index.refresh() {
+        Ok(()) => {
+            return Ok(json_response(StatusCode::OK, json!({
+                "_shards": {
+                    "total": 1,
+                    "successful": 1,
+                    "failed": 0,
+                }
+            })));
+        }
+        Err(e) => {
+            error!(req.log, "index refresh failed"; "index" => *index_name, "error" => e);
+
+            return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "_shards": {
+                    "total": 1,
+                    "successful": 0,
+                    "failed": 1,
+                }
+            })));
+        }
+    }
+}
+
+
+pub fn view_post_flush_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+
+    match index.flush() {
+        Ok(()) => {
+            return Ok(json_response(StatusCode::OK, json!({
+                "_shards": {
+                    "total": 1,
+                    "successful": 1,
+                    "failed": 0,
+                }
+            })));
+        }
+        Err(e) => {
+            error!(req.log, "index flush failed"; "index" => *index_name, "error" => e);
+
+            return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "_shards": {
+                    "total": 1,
+                    "successful": 0,
+                    "failed": 1,
+                }
+            })));
+        }
+    }
+}
+
+
+/// Reads the `max_num_segments` URL parameter of the force merge API. Defaults to 1
+fn get_max_num_segments(req: &Request) -> Result<usize, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "max_num_segments" {
+                return match value.parse::<usize>() {
+                    Ok(max_num_segments) if max_num_segments > 0 => Ok(max_num_segments),
+                    _ => Err(json_response(StatusCode::BAD_REQUEST, json!({
+                        "message": format!("Invalid value for max_num_segments parameter: {:?}", value)
+                    }))),
+                };
             }
-        };
+        }
+    }
+
+    Ok(1)
+}
 
-        // Remove index from array
-        cluster_metadata.indices.remove(&index_ref);
 
-        // Delete canonical name
-        cluster_metadata.names.delete_canonical(&index_name, index_ref).unwrap();
+/// Merges the segments of one or more indices, then flushes them
+///
+/// This runs as a task, which can be cancelled between merges.
+pub fn view_post_forcemerge(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+    let max_num_segments = match get_max_num_segments(req) {
+        Ok(max_num_segments) => max_num_segments,
+        Err(response) => return Ok(response),
+    };
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    let index_names = indices.iter().map(|&(ref index_ref, _)| cluster_metadata.indices[index_ref].canonical_name()).collect::<Vec<_>>();
+    let cancellation = SearchCancellation::new();
+    let _task = system.tasks.register("indices:admin/forcemerge", format!("Force-merge indices [{}], maxSegments[{}]", index_names.join(","), max_num_segments), Some(&req.opaque_id), cancellation.clone());
 
-        // Delete file
-        let mut indices_dir = system.get_indices_dir();
-        indices_dir.push(&index_name);
-        match fs::remove_dir_all(&indices_dir) {
-            Ok(()) => {},
+    let mut successful = 0;
+    let mut cancelled = false;
+
+    for &(ref index_ref, _) in indices.iter() {
+        let index = &cluster_metadata.indices[index_ref];
+
+        match index.force_merge(max_num_segments, &cancellation).and_then(|finished| index.flush().map(|_| finished)) {
+            Ok(true) => successful += 1,
+            Ok(false) => {
+                cancelled = true;
+                break;
+            }
             Err(e) => {
-                warn!(system.log, "failed to delete index data"; "index" => format!("{}", index_name), "error" => format!("{}", e));
+                error!(req.log, "index force merge failed"; "index" => index.canonical_name(), "error" => e);
             }
         }
+    }
 
-        info!(system.log, "deleted index"; "index" => index_name);
+    let mut response = json!({
+        "_shards": {
+            "total": indices.len(),
+            "successful": successful,
+            "failed": indices.len() - successful,
+        }
+    });
 
-        // Delete aliases
-        let alias_names = cluster_metadata.names.iter_index_aliases(index_ref).map(|n| n.to_string()).collect::<Vec<String>>();
-        for alias_name in alias_names {
-            let alias_deleted = cluster_metadata.names.delete_alias(&alias_name, index_ref).unwrap();
+    if cancelled {
+        response["canceled"] = json!("by user request");
+    }
 
-            // If this was the only index being referenced by the alias, the alias would be deleted
-            if alias_deleted {
-                info!(system.log, "deleted alias"; "alias" => format!("{}", alias_name), "reason" => "no indices left");
+    let response_status = if successful == indices.len() || cancelled { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+    return Ok(json_response(response_status, response));
+}
+
+
+pub fn view_post_close_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => IndicesOptions { include_closed: true, ..indices_options },
+        Err(response) => return Ok(response),
+    };
+
+    // Lock cluster metadata
+    let mut cluster_metadata = system.metadata.write().unwrap();
+
+    // Find indices
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    for (index_ref, _) in indices {
+        match system.close_index(&mut cluster_metadata, index_ref) {
+            Ok(()) => {}
+            Err(CloseIndexError::InUse) => {
+                return Ok(json_response(StatusCode::CONFLICT, json!({
+                    "message": format!("Index {} is still in use, try again later", cluster_metadata.index_name(&index_ref).unwrap_or(""))
+                })));
+            }
+            Err(CloseIndexError::Failed(_)) => {
+                return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                    "message": "unable to close index"
+                })));
             }
         }
     }
 
-    return Ok(json_response(status::Ok, json!({"acknowledged": true})));
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
 }
 
 
-pub fn view_post_refresh_index(_req: &mut Request) -> IronResult<Response> {
-    // let ref system = get_system!(req);
-    // let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+pub fn view_post_open_index(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => IndicesOptions { include_closed: true, ..indices_options },
+        Err(response) => return Ok(response),
+    };
+
+    // Lock cluster metadata
+    let mut cluster_metadata = system.metadata.write().unwrap();
+
+    // Find indices
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
 
-    // Lock index array
-    // TODO
-    // let mut indices = system.indices.write().unwrap();
+    for (index_ref, _) in indices {
+        if system.open_index(&mut cluster_metadata, index_ref).is_err() {
+            return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "message": "unable to open index"
+            })));
+        }
+    }
 
-    // TODO: {"_shards":{"total":10,"successful":5,"failed":0}}
-    return Ok(json_response(status::Ok, json!({"acknowledged": true})));
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
 }
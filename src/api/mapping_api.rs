@@ -1,29 +1,23 @@
 use std::io::Read;
-use std::collections::HashMap;
 
 use serde_json;
-use search::schema::{FieldType, FieldFlags, FIELD_INDEXED, FIELD_STORED};
+use search::schema::{FIELD_INDEXED, FIELD_STORED};
 
-use mapping::{self, MappingProperty};
-use mapping::parse::parse as parse_mapping;
+use mapping::parse::{parse as parse_mapping, check_similarities};
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::router::Router;
-use api::utils::json_response;
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, index_blocked_response};
 
 
-pub fn view_put_mapping(req: &mut Request) -> IronResult<Response> {
+pub fn view_put_mapping(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
     let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
 
-    // Lock cluster metadata
-    let mut cluster_metadata = system.metadata.write().unwrap();
-
-    // Get index
-    let index = get_index_or_404_mut!(cluster_metadata, *index_name);
+    // Get index. Only its own metadata is locked for writing
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
 
     // Load data from body
     let data = json_from_request_body!(req);
@@ -32,7 +26,7 @@ pub fn view_put_mapping(req: &mut Request) -> IronResult<Response> {
         Some(data) => data,
         None => {
             // TODO: Better error
-            return Ok(json_response(status::BadRequest, json!({"acknowledged": false})));
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"acknowledged": false})));
         }
     };
 
@@ -43,90 +37,55 @@ pub fn view_put_mapping(req: &mut Request) -> IronResult<Response> {
         Ok(mapping_builder) => mapping_builder,
         Err(_) => {
             // TODO: Better error
-            return Ok(json_response(status::BadRequest, json!({"acknowledged": false})));
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"acknowledged": false})));
         }
     };
     let mut index_metadata = index.metadata.write().unwrap();
+
+    if index_metadata.settings.blocks.blocks_metadata_write() {
+        return Ok(index_blocked_response(index.canonical_name(), "metadata write"));
+    }
+
+    if check_similarities(&mapping_builder, &index_metadata.settings).is_err() {
+        // TODO: Better error
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({"acknowledged": false})));
+    }
+
     let mut mapping = mapping_builder.build(&index_metadata);
     //debug!("{:#?}", mapping);
     let is_updating = index_metadata.mappings.contains_key(*mapping_name);
 
     // Find list of new fields that need to be added to the store
-    let new_fields = {
-        let index_reader = index.store.reader();
-        let schema = index_reader.schema();
-        let mut new_fields: HashMap<String, (FieldType, FieldFlags)>  = HashMap::new();
-        for (name, property) in mapping.properties.iter() {
-            if let MappingProperty::Field(ref field_mapping) = *property {
-                let field_type = match field_mapping.data_type {
-                    mapping::FieldType::String => FieldType::Text,
-                    mapping::FieldType::Integer => FieldType::I64,
-                    mapping::FieldType::Boolean => FieldType::Boolean,
-                    mapping::FieldType::Date => FieldType::DateTime,
-                };
-
-                // Flags
-                let mut field_flags = FieldFlags::empty();
-
-                if field_mapping.is_indexed {
-                    field_flags |= FIELD_INDEXED;
-                }
-
-                if field_mapping.is_stored {
-                    field_flags |= FIELD_STORED;
-                }
-
-                // Check if this field already exists
-                if let Some(field_ref) = schema.get_field_by_name(&name) {
-                    let field_info = schema.get(&field_ref).expect("get_field_by_name returned an invalid FieldId");
-
-                    // Field already exists. Check for conflicting type or flags, otherwise ignore.
-                    if field_info.field_type == field_type && field_info.field_flags == field_flags {
-                        continue;
-                    } else {
-                        // Conflict!
-                        // TODO: Better error
-                        return Ok(json_response(status::BadRequest, json!({"acknowledged": false})));
-                    }
-                }
-
-                new_fields.insert(name.clone(), (field_type, field_flags));
-            }
+    let new_fields = match index.new_mapping_fields(&mapping) {
+        Ok(new_fields) => new_fields,
+        Err(_) => {
+            // Conflict!
+            // TODO: Better error
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"acknowledged": false})));
         }
-
-        new_fields
     };
 
     // Add new fields into the store
     for (field_name, (field_type, field_flags)) in new_fields {
         let indexed_yesno = if field_flags.contains(FIELD_INDEXED) { "yes" } else { "no" };
         let stored_yesno = if field_flags.contains(FIELD_STORED) { "yes" } else { "no" };
-        info!(system.log, "adding field"; "index" => *index_name, "field" => &field_name, "type" => format!("{:?}", field_type), "indexed" => indexed_yesno, "stored" => stored_yesno);
+        info!(req.log, "adding field"; "index" => *index_name, "field" => &field_name, "type" => format!("{:?}", field_type), "indexed" => indexed_yesno, "stored" => stored_yesno);
 
-        index.store.add_field(field_name, field_type, field_flags).unwrap();
+        index.add_field(field_name, field_type, field_flags).unwrap();
     }
 
     // Link the mapping
-    {
-        let index_reader = index.store.reader();
-        let schema = index_reader.schema();
-
-        for (name, property) in mapping.properties.iter_mut() {
-            if let MappingProperty::Field(ref mut field_mapping) = *property {
-                field_mapping.index_ref = schema.get_field_by_name(&name)
-            }
-        }
-    }
+    index.link_mapping(&mut mapping);
 
     index_metadata.mappings.insert(mapping_name.clone().to_owned(), mapping);
     index_metadata.save(index.metadata_path()).unwrap();
 
     if is_updating {
         // TODO: New mapping should be merged with existing one
-        info!(system.log, "updated mapping"; "index" => *index_name, "mapping" => *mapping_name);
+        info!(req.log, "updated mapping"; "index" => *index_name, "mapping" => *mapping_name);
     } else {
-        info!(system.log, "created mapping"; "index" => *index_name, "mapping" => *mapping_name);
+        info!(req.log, "created mapping"; "index" => *index_name, "mapping" => *mapping_name);
     }
 
-    return Ok(json_response(status::Ok, json!({"acknowledged": true})));
+    return Ok(json_response(StatusCode::OK, json!({"acknowledged": true})));
 }
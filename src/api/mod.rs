@@ -1,7 +1,7 @@
-extern crate iron;
-extern crate router;
-extern crate persistent;
-
+mod request;
+#[macro_use]
+mod router;
+mod server;
 #[macro_use]
 mod utils;
 mod search_api;
@@ -9,82 +9,536 @@ mod alias_api;
 mod document_api;
 mod index_api;
 mod mapping_api;
+mod settings_api;
+mod stats_api;
+mod recovery_api;
 mod bulk_api;
+mod tasks_api;
+mod reindex_api;
+mod validate_api;
+mod cat_api;
+mod cluster_api;
+mod script_api;
+mod rank_eval_api;
+mod ilm_api;
+mod term_vectors_api;
+mod security_api;
+mod dangling_api;
+mod grpc;
 
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::{self, BoxFuture};
+use hyper::{self, Body, Method, StatusCode};
+use slog::Logger;
+
+use api::request::{RequestBody, OPAQUE_ID_HEADER};
+use api::router::{Router, Match};
+use api::server::{Handler, ApiServer};
+use api::utils::content_too_long_response;
 
-use api::iron::prelude::*;
-use api::iron::status;
-use api::iron::typemap::Key;
-use api::router::Router;
-use api::utils::json_response;
+// For plugins' endpoints (see `plugins::RestHandlerProvider`)
+pub use api::request::{Request, Response, ApiResult};
+pub use api::utils::json_response;
+
+pub use api::grpc::grpc_main;
 
 use system::System;
+use audit;
+use settings::CorsSettings;
+use plugins;
+use shutdown::shutdown_requested;
+use thread_pool::{ThreadPool, ThreadPools, QueueFull};
 use VERSION;
 
 
-fn view_home(_: &mut Request) -> IronResult<Response> {
-    Ok(json_response(status::Ok, json!({
-        "cluster_name": "rusticsearch",
+/// Requests between the nodes of a cluster are made to paths under this
+const INTERNAL_PATH_PREFIX: &'static str = "/_internal/";
+
+
+fn view_home(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    Ok(json_response(StatusCode::OK, json!({
+        "name": system.cluster.local_node.name,
+        "cluster_name": system.settings.cluster_name,
         "version": {
-            "number": VERSION
-        }
+            "number": VERSION,
+            "build_flavor": "default",
+            "build_type": if cfg!(debug_assertions) { "debug" } else { "release" },
+            "build_snapshot": VERSION.contains('-'),
+        },
+        "tagline": "You Know, for Search",
     })))
 }
 
 
 fn get_router() -> Router {
-    router!(get "/" => view_home,
+    let mut router = router!(get "/" => view_home,
+            head "/" => view_home,
+            get "/_cluster/health" => cluster_api::view_get_cluster_health,
+            get "/_cluster/state" => cluster_api::view_get_cluster_state,
+            get "/_cluster/settings" => settings_api::view_get_cluster_settings,
+            put "/_cluster/settings" => settings_api::view_put_cluster_settings,
+            get "/_internal/cluster/ping" => cluster_api::view_get_internal_ping,
+            post "/_internal/cluster/join" => cluster_api::view_post_internal_join,
+            post "/_internal/cluster/state" => cluster_api::view_post_internal_state,
+            post "/_internal/cluster/shard/started" => cluster_api::view_post_internal_shard_started,
+            post "/_internal/cluster/shard/failed" => cluster_api::view_post_internal_shard_failed,
+            post "/_internal/replication/:index" => cluster_api::view_post_internal_replicate,
+            post "/_internal/replication/:index/_recovery/start" => cluster_api::view_post_internal_recovery_start,
+            post "/_internal/replication/:index/_recovery/finish" => cluster_api::view_post_internal_recovery_finish,
             get "/:index/_count" => search_api::view_count,
             post "/:index/_count" => search_api::view_count,
+            get "/_search" => search_api::view_search,
+            post "/_search" => search_api::view_search,
             get "/:index/_search" => search_api::view_search,
             post "/:index/_search" => search_api::view_search,
+            post "/_search/scroll" => search_api::view_post_scroll,
+            get "/_search/template" => search_api::view_search_template,
+            post "/_search/template" => search_api::view_search_template,
+            get "/:index/_search/template" => search_api::view_search_template,
+            post "/:index/_search/template" => search_api::view_search_template,
+            get "/_rank_eval" => rank_eval_api::view_rank_eval,
+            post "/_rank_eval" => rank_eval_api::view_rank_eval,
+            get "/:index/_rank_eval" => rank_eval_api::view_rank_eval,
+            post "/:index/_rank_eval" => rank_eval_api::view_rank_eval,
+            get "/_scripts/:id" => script_api::view_get_script,
+            put "/_scripts/:id" => script_api::view_put_script,
+            post "/_scripts/:id" => script_api::view_put_script,
+            delete "/_scripts/:id" => script_api::view_delete_script,
+            get "/_ilm/policy" => ilm_api::view_get_lifecycle_policy,
+            get "/_ilm/policy/:name" => ilm_api::view_get_lifecycle_policy,
+            put "/_ilm/policy/:name" => ilm_api::view_put_lifecycle_policy,
+            delete "/_ilm/policy/:name" => ilm_api::view_delete_lifecycle_policy,
+            get "/:index/_ilm/explain" => ilm_api::view_get_lifecycle_explain,
+            get "/_security/_authenticate" => security_api::view_get_authenticate,
+            put "/_security/api_key" => security_api::view_put_api_key,
+            post "/_security/api_key" => security_api::view_put_api_key,
+            delete "/_security/api_key" => security_api::view_delete_api_key,
+            put "/_security/api_key/:id" => security_api::view_put_api_key_roles,
+            get "/_security/role" => security_api::view_get_role,
+            get "/_security/role/:name" => security_api::view_get_role,
+            put "/_security/role/:name" => security_api::view_put_role,
+            post "/_security/role/:name" => security_api::view_put_role,
+            delete "/_security/role/:name" => security_api::view_delete_role,
+            get "/_security/user" => security_api::view_get_user,
+            get "/_security/user/:username" => security_api::view_get_user,
+            put "/_security/user/:username" => security_api::view_put_user,
+            post "/_security/user/:username" => security_api::view_put_user,
+            get "/_cat" => cat_api::view_get_cat,
+            get "/_cat/indices" => cat_api::view_get_cat_indices,
+            get "/_cat/indices/:index" => cat_api::view_get_cat_indices,
+            get "/_cat/count" => cat_api::view_get_cat_count,
+            get "/_cat/count/:index" => cat_api::view_get_cat_count,
+            get "/_cat/health" => cat_api::view_get_cat_health,
+            get "/_cat/aliases" => cat_api::view_get_cat_aliases,
+            get "/_cat/aliases/:alias" => cat_api::view_get_cat_aliases,
+            get "/_cat/plugins" => cat_api::view_get_cat_plugins,
+            get "/_validate/query" => validate_api::view_validate_query,
+            post "/_validate/query" => validate_api::view_validate_query,
+            get "/:index/_validate/query" => validate_api::view_validate_query,
+            post "/:index/_validate/query" => validate_api::view_validate_query,
+            delete "/_search/scroll" => search_api::view_delete_scroll,
+            get "/_tasks" => tasks_api::view_get_tasks,
+            get "/_tasks/:task_id" => tasks_api::view_get_task,
+            post "/_tasks/:task_id/_cancel" => tasks_api::view_post_cancel_task,
+            post "/_aliases" => alias_api::view_post_aliases,
+            get "/_alias" => alias_api::view_get_all_aliases,
             get "/_alias/:alias" => alias_api::view_get_global_alias,
             get "/:index/_alias" => alias_api::view_get_alias_list,
             get "/:index/_alias/:alias" => alias_api::view_get_alias,
             put "/:index/_alias/:alias" => alias_api::view_put_alias,
+            delete "/:index/_alias/:alias" => alias_api::view_delete_alias,
             get "/:index/:mapping/:doc" => document_api::view_get_doc,
+            head "/:index/:mapping/:doc" => document_api::view_head_doc,
             put "/:index/:mapping/:doc" => document_api::view_put_doc,
+            post "/:index/:mapping" => document_api::view_post_doc,
+            put "/:index/:mapping/:doc/_create" => document_api::view_put_create_doc,
+            post "/:index/:mapping/:doc/_create" => document_api::view_put_create_doc,
             delete "/:index/:mapping/:doc" => document_api::view_delete_doc,
+            post "/:index/:mapping/:doc/_update" => document_api::view_post_update_doc,
+            post "/:index/_update_by_query" => document_api::view_post_update_by_query,
+            post "/:index/:mapping/_update_by_query" => document_api::view_post_update_by_query,
+            post "/:index/_delete_by_query" => document_api::view_post_delete_by_query,
+            get "/:index/:mapping/:doc/_explain" => search_api::view_explain,
+            post "/:index/:mapping/:doc/_explain" => search_api::view_explain,
+            get "/:index/:mapping/:doc/_termvectors" => term_vectors_api::view_get_term_vectors,
+            post "/:index/:mapping/:doc/_termvectors" => term_vectors_api::view_get_term_vectors,
+            get "/:index/:mapping/_termvectors" => term_vectors_api::view_get_term_vectors,
+            post "/:index/:mapping/_termvectors" => term_vectors_api::view_get_term_vectors,
             get "/:index" => index_api::view_get_index,
+            head "/:index" => index_api::view_head_index,
             put "/:index" => index_api::view_put_index,
             delete "/:index" => index_api::view_delete_index,
             post "/:index/_refresh" => index_api::view_post_refresh_index,
+            post "/:index/_flush" => index_api::view_post_flush_index,
+            post "/_forcemerge" => index_api::view_post_forcemerge,
+            post "/:index/_forcemerge" => index_api::view_post_forcemerge,
+            post "/:index/_close" => index_api::view_post_close_index,
+            post "/:index/_open" => index_api::view_post_open_index,
             put "/:index/_mapping/:mapping" => mapping_api::view_put_mapping,
+            get "/:index/_settings" => settings_api::view_get_settings,
+            put "/:index/_settings" => settings_api::view_put_settings,
+            get "/:index/_segments" => stats_api::view_get_segments,
+            get "/_stats" => stats_api::view_get_stats,
+            get "/:index/_stats" => stats_api::view_get_stats,
+            get "/_nodes/stats" => stats_api::view_get_nodes_stats,
+            get "/_recovery" => recovery_api::view_get_all_recoveries,
+            get "/:index/_recovery" => recovery_api::view_get_recovery,
+            get "/_dangling" => dangling_api::view_get_dangling_indices,
+            post "/_dangling/:index" => dangling_api::view_post_import_dangling_index,
+            delete "/_dangling/:index" => dangling_api::view_delete_dangling_index,
+            get "/_mget" => document_api::view_post_mget,
+            post "/_mget" => document_api::view_post_mget,
+            get "/:index/_mget" => document_api::view_post_mget,
+            post "/:index/_mget" => document_api::view_post_mget,
+            get "/:index/:mapping/_mget" => document_api::view_post_mget,
+            post "/:index/:mapping/_mget" => document_api::view_post_mget,
+            post "/_reindex" => reindex_api::view_post_reindex,
             post "/_bulk" => bulk_api::view_post_bulk,
-            post "/:index/_bulk" => bulk_api::view_post_index_bulk)
+            post "/:index/_bulk" => bulk_api::view_post_index_bulk,
+            post "/:index/:mapping/_bulk" => bulk_api::view_post_index_bulk);
+
+    // Plugins' routes go last, so the built-in ones win if they have the same pattern
+    for plugin in plugins::installed() {
+        let handlers = match plugin.rest_handlers() {
+            Some(handlers) => handlers,
+            None => continue,
+        };
+
+        for (method, pattern) in handlers.routes() {
+            let handlers = handlers.clone();
+            let route_pattern = pattern.clone();
+            router.route(method, &pattern, move |req: &mut Request| handlers.handle(&route_pattern, req));
+        }
+    }
+
+    router
 }
 
 
-// The "Context" struct just wraps Arc<System> so we can put it into chain.link()
-// Workaround for: https://github.com/iron/persistent/issues/55
+/// How long to wait for connections to finish their requests when stopping
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
 
-struct Context {
+/// Decrements the count of requests in flight when dropped, even if the handler panics
+struct InFlightRequest {
     system: Arc<System>,
 }
 
 
-impl Context {
-    fn new(system: Arc<System>) -> Context {
-        Context {
+impl InFlightRequest {
+    fn new(system: Arc<System>) -> InFlightRequest {
+        system.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+
+        InFlightRequest {
             system: system,
         }
     }
 }
 
 
-impl Key for Context {
-    type Value = Context;
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.system.requests_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Handles API requests by running them on the node's thread pools
+///
+/// Requests are counted from when they arrive, so a shutdown can wait for them to finish.
+/// Once a shutdown has started, new requests are refused. So are requests whose bodies are
+/// larger than `max_content_length`, and requests that arrive while the queue of the thread
+/// pool they'd run on is full.
+struct Api {
+    system: Arc<System>,
+    router: Router,
+}
+
+
+/// Chooses the thread pool that a request runs on from the route it matched
+///
+/// Searches and document gets use the search pool and requests that change documents use the
+/// write pool, so neither can hold up the other. Everything else, including requests that
+/// didn't match a route, uses the management pool.
+fn thread_pool_for<'a>(thread_pools: &'a ThreadPools, method: &Method, route: Option<&Match>) -> &'a ThreadPool {
+    let pattern = match route {
+        Some(route) => route.pattern,
+        None => return &thread_pools.management,
+    };
+
+    match pattern.split('/').find(|segment| segment.starts_with('_')) {
+        Some("_search") | Some("_count") | Some("_mget") | Some("_explain") | Some("_termvectors") |
+        Some("_validate") | Some("_rank_eval") => &thread_pools.search,
+        Some("_bulk") | Some("_create") | Some("_update") | Some("_update_by_query") |
+        Some("_delete_by_query") | Some("_reindex") => &thread_pools.write,
+        None if pattern.starts_with("/:index/:mapping") => {
+            if *method == Method::GET || *method == Method::HEAD {
+                &thread_pools.search
+            } else {
+                &thread_pools.write
+            }
+        }
+        _ => &thread_pools.management,
+    }
+}
+
+
+impl Api {
+    /// Runs a request on the thread pool for its route
+    fn dispatch(&self, req: Request) -> BoxFuture<'static, Response> {
+        let in_flight = InFlightRequest::new(self.system.clone());
+
+        if shutdown_requested() {
+            return future::ready(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": "Node is shutting down"}))).boxed();
+        }
+
+        if req.content_length().unwrap_or(0) > self.system.settings.max_content_length {
+            return future::ready(content_too_long_response(self.system.settings.max_content_length)).boxed();
+        }
+
+        let route = self.router.recognize(&req.method, &req.path());
+        let thread_pool = thread_pool_for(&self.system.thread_pools, &req.method, route.as_ref());
+        let route = route.map(|route| (route.handler, route.params, route.pattern.to_string()));
+        let log = req.log.clone();
+
+        let result = thread_pool.spawn(move || {
+            let _in_flight = in_flight;
+            handle_request(req, route)
+        });
+
+        match result {
+            Ok(response) => {
+                response.map(move |response| match response {
+                    Ok(Ok(response)) => response,
+                    _ => {
+                        error!(log, "request handler panicked");
+                        json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Internal server error"}))
+                    }
+                }).boxed()
+            }
+            Err(QueueFull) => {
+                warn!(log, "thread pool queue is full"; "thread_pool" => thread_pool.name(), "queue_size" => thread_pool.queue_size());
+                future::ready(json_response(StatusCode::TOO_MANY_REQUESTS, json!({
+                    "message": format!("Too many requests, the queue of {} requests waiting for the {} thread pool is full", thread_pool.queue_size(), thread_pool.name())
+                }))).boxed()
+            }
+        }
+    }
+}
+
+
+impl Handler for Api {
+    fn handle(&self, req: hyper::Request<Body>, remote_addr: SocketAddr) -> BoxFuture<'static, Response> {
+        let (parts, body) = req.into_parts();
+        let req = Request::new(parts.method, parts.uri, parts.headers, RequestBody::new(body), remote_addr, self.system.clone());
+        let opaque_id = req.opaque_id.clone();
+
+        // Every response carries the opaque id, so it can be found in the logs
+        self.dispatch(req).map(move |mut response| {
+            response.set_header(OPAQUE_ID_HEADER, &opaque_id);
+            response
+        }).boxed()
+    }
+}
+
+
+/// Handles a request on a thread pool
+///
+/// Requests that would take the memory used by the requests in flight over the limit of the
+/// request breaker are refused. Bodies without a `Content-Length` are limited as they're read.
+fn handle_request(mut req: Request, route: Option<(router::Handler, HashMap<String, String>, String)>) -> Response {
+    let system = req.system.clone();
+    let content_length = req.content_length().unwrap_or(0);
+
+    let _reservation = match system.request_breaker.reserve(content_length as usize) {
+        Ok(reservation) => reservation,
+        Err(bytes_wanted) => {
+            let limit = system.request_breaker.limit().unwrap_or(0);
+            warn!(req.log, "request breaker tripped"; "bytes_wanted" => bytes_wanted, "limit" => limit);
+
+            return json_response(StatusCode::TOO_MANY_REQUESTS, json!({
+                "message": format!("Data too large, the requests in flight would use {} bytes, which is more than the limit of {} bytes", bytes_wanted, limit),
+                "bytes_wanted": bytes_wanted,
+                "bytes_limit": limit,
+            }));
+        }
+    };
+
+    handle_cors(&system.settings.cors, &mut req, |req| {
+        match route_request(req, route) {
+            Ok(response) | Err(response) => response,
+        }
+    })
+}
+
+
+/// Checks the credentials and permissions of a request, then passes it to its view
+///
+/// Requests between the nodes of a cluster are checked against the cluster secret instead.
+/// Requests that change the cluster state are forwarded to the master if this node isn't it,
+/// and published to the other nodes once the master has run them. Requests for the documents
+/// of an index are forwarded to a node with a copy of it (see `cluster_api::document_node`).
+/// Admin and destructive requests are recorded in the audit log once they've been handled.
+fn route_request(req: &mut Request, route: Option<(router::Handler, HashMap<String, String>, String)>) -> ApiResult<Response> {
+    if req.uri.path().starts_with(INTERNAL_PATH_PREFIX) {
+        cluster_api::authenticate_node(req)?;
+    } else {
+        security_api::authenticate_request(req)?;
+        security_api::authorize_request(req)?;
+    }
+
+    let (view, params, pattern) = match route {
+        Some(route) => route,
+        None => {
+            return Err(json_response(StatusCode::NOT_FOUND, json!({
+                "message": format!("No handler found for {} {}", req.method, req.uri.path())
+            })));
+        }
+    };
+
+    req.params = params;
+    let response = run_view(req, view, &pattern);
+
+    if let Some(event_type) = audit::route_event_type(&req.method, &pattern) {
+        let status = match response {
+            Ok(ref response) | Err(ref response) => response.status,
+        };
+        security_api::audit_request(req, event_type, status);
+    }
+
+    response
+}
+
+
+/// Runs the view of a request's route, on this node or the one it must be forwarded to
+fn run_view(req: &mut Request, view: router::Handler, pattern: &str) -> ApiResult<Response> {
+    let system = req.system.clone();
+    let changes_cluster_state = cluster_api::changes_cluster_state(&req.method, pattern);
+    if changes_cluster_state && !system.cluster.is_master() {
+        return Ok(cluster_api::forward_to_master(req));
+    }
+
+    if let Some(access) = cluster_api::document_access(&req.method, pattern) {
+        if let Some(node) = cluster_api::document_node(req, access)? {
+            return Ok(cluster_api::forward_to_node(req, &node));
+        }
+    }
+
+    let response = view(req);
+
+    if changes_cluster_state {
+        system.cluster.publish_changes(&system);
+    }
+
+    response
 }
 
 
-pub fn api_main(system: Arc<System>) {
-    let router = get_router();
-    let mut chain = Chain::new(router);
-    chain.link(persistent::Read::<Context>::both(Context::new(system.clone())));
-    info!(system.log, "listening"; "scheme" => "http", "address" => "localhost", "port" => 9200);
+fn set_cors_headers(response: &mut Response, allowed_origin: &str, cors: &CorsSettings) {
+    response.set_header("Access-Control-Allow-Origin", allowed_origin);
+    response.set_header("Vary", "Origin");
 
-    if let Err(error) = Iron::new(chain).http("localhost:9200") {
-        crit!(system.log, "unable to start api server"; "error" => format!("{}", error));
+    if cors.allow_credentials {
+        response.set_header("Access-Control-Allow-Credentials", "true");
     }
 }
+
+
+/// Handles cross-origin requests from browsers, as allowed by the CORS settings
+///
+/// Preflight requests are answered here, before they reach authentication, as browsers don't
+/// send credentials with them. Other requests from origins that aren't allowed are handled
+/// as usual, but without the headers that would let the browser read the response.
+fn handle_cors<F>(cors: &CorsSettings, req: &mut Request, handle: F) -> Response
+    where F: FnOnce(&mut Request) -> Response
+{
+    let origin = match req.header("Origin") {
+        Some(origin) if cors.is_enabled() => origin.to_string(),
+        _ => return handle(req),
+    };
+    let allowed_origin = cors.allowed_origin(&origin);
+
+    if req.method == Method::OPTIONS && req.header("Access-Control-Request-Method").is_some() {
+        let allowed_origin = match allowed_origin {
+            Some(allowed_origin) => allowed_origin,
+            None => return json_response(StatusCode::FORBIDDEN, json!({"message": format!("Origin not allowed: {}", origin)})),
+        };
+
+        let mut response = Response::new(StatusCode::OK);
+        set_cors_headers(&mut response, &allowed_origin, cors);
+        response.set_header("Access-Control-Allow-Methods", &cors.allow_methods.join(", "));
+        response.set_header("Access-Control-Allow-Headers", &cors.allow_headers.join(", "));
+        response.set_header("Access-Control-Max-Age", &cors.max_age.to_string());
+        return response;
+    }
+
+    let mut response = handle(req);
+    if let Some(allowed_origin) = allowed_origin {
+        set_cors_headers(&mut response, &allowed_origin, cors);
+    }
+
+    response
+}
+
+
+/// A running API server
+pub struct ApiListener {
+    server: ApiServer,
+    log: Logger,
+}
+
+
+impl ApiListener {
+    /// Stops the server, after waiting for the requests that are being handled to finish
+    pub fn stop(self) {
+        if !self.server.stop(STOP_TIMEOUT) {
+            warn!(self.log, "api server connections still open after timeout"; "timeout_secs" => STOP_TIMEOUT.as_secs());
+        }
+    }
+}
+
+
+/// Serves requests to a handler on the given port of the bind host
+///
+/// Returns None if the server couldn't be started.
+fn start_listener<H: Handler>(system: &System, handler: H, port: u16, scheme: &'static str) -> Option<ApiListener> {
+    let result = TcpListener::bind(format!("{}:{}", system.settings.bind_host, port).as_str()).and_then(|listener| {
+        server::serve(listener, handler, system.log.clone())
+    });
+
+    match result {
+        Ok(server) => {
+            info!(system.log, "listening"; "scheme" => scheme, "address" => system.settings.bind_host.clone(), "port" => port);
+
+            Some(ApiListener {
+                server: server,
+                log: system.log.clone(),
+            })
+        }
+        Err(error) => {
+            crit!(system.log, "unable to start api server"; "scheme" => scheme, "error" => format!("{}", error));
+            None
+        }
+    }
+}
+
+
+/// Starts the API server in the background
+///
+/// Returns None if the server couldn't be started.
+pub fn api_main(system: Arc<System>) -> Option<ApiListener> {
+    let api = Api {
+        system: system.clone(),
+        router: get_router(),
+    };
+
+    start_listener(&system, api, system.settings.port, "http")
+}
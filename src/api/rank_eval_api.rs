@@ -0,0 +1,272 @@
+use std::io::Read;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde_json;
+use serde_json::Value as Json;
+
+use search::query::Query;
+use search::collectors::top_score::TopScoreCollector;
+use cluster::metadata::{ClusterMetadata, IndexRef};
+use security::roles::Permissions;
+use query_parser::{QueryBuildContext, parse as parse_query};
+use rank_eval::Metric;
+use template::{render_search_template, template_source_to_string};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, apply_alias_filter, apply_role_filter, get_indices_options};
+use api::security_api::get_permissions;
+
+
+/// A document that has been rated for a query
+struct RatedDocument {
+    index: String,
+    id: String,
+    rating: u64,
+}
+
+
+/// A query along with the ratings of the documents it should find
+struct RatedRequest {
+    id: String,
+
+    /// The body of the search request. Requests that use a template are rendered when they're parsed
+    body: Json,
+
+    ratings: Vec<RatedDocument>,
+}
+
+
+fn parse_rating(json: &Json) -> Result<RatedDocument, String> {
+    let index = match json.get("_index") {
+        Some(&Json::String(ref index)) => index.clone(),
+        _ => return Err("Each rating must have an _index".to_string()),
+    };
+
+    let id = match json.get("_id") {
+        Some(&Json::String(ref id)) => id.clone(),
+        _ => return Err("Each rating must have an _id".to_string()),
+    };
+
+    let rating = match json.get("rating").and_then(|rating| rating.as_u64()) {
+        Some(rating) => rating,
+        None => return Err("rating must be a positive integer".to_string()),
+    };
+
+    Ok(RatedDocument {
+        index: index,
+        id: id,
+        rating: rating,
+    })
+}
+
+
+/// Parses the body of a rank evaluation request
+///
+/// Queries can either be given inline as "request", or as a "template_id" referring to one of
+/// the "templates" along with its "params".
+fn parse_rank_eval_request(body: &Json) -> Result<(Vec<RatedRequest>, Metric), String> {
+    let metric = match body.get("metric") {
+        Some(metric_json) => Metric::from_json(metric_json)?,
+        None => return Err("Missing metric".to_string()),
+    };
+
+    let mut templates = HashMap::new();
+    if let Some(templates_json) = body.get("templates") {
+        let templates_array = templates_json.as_array().ok_or_else(|| "templates must be an array".to_string())?;
+
+        for template_json in templates_array.iter() {
+            let id = match template_json.get("id") {
+                Some(&Json::String(ref id)) => id.clone(),
+                _ => return Err("Each template must have an id".to_string()),
+            };
+
+            let source = match template_json.get("template").and_then(|template| template.get("source")) {
+                Some(source) => template_source_to_string(source),
+                None => return Err(format!("Template {:?} is missing its source", id)),
+            };
+
+            templates.insert(id, source);
+        }
+    }
+
+    let requests_json = match body.get("requests").and_then(|requests| requests.as_array()) {
+        Some(requests_json) => requests_json,
+        None => return Err("requests must be an array".to_string()),
+    };
+
+    let mut requests = Vec::new();
+    for request_json in requests_json.iter() {
+        let id = match request_json.get("id") {
+            Some(&Json::String(ref id)) => id.clone(),
+            _ => return Err("Each request must have an id".to_string()),
+        };
+
+        let body = match (request_json.get("request"), request_json.get("template_id")) {
+            (Some(request), None) => request.clone(),
+            (None, Some(&Json::String(ref template_id))) => {
+                let source = templates.get(template_id).ok_or_else(|| format!("Template {:?} not found", template_id))?;
+                let params = request_json.get("params").cloned().unwrap_or_else(|| json!({}));
+                render_search_template(source, &params).map_err(|e| format!("Template error in request {:?}: {}", id, e))?
+            }
+            _ => return Err(format!("Request {:?} must have either a request or a template_id", id)),
+        };
+
+        let ratings = match request_json.get("ratings").and_then(|ratings| ratings.as_array()) {
+            Some(ratings_json) => ratings_json.iter().map(parse_rating).collect::<Result<Vec<_>, _>>()?,
+            None => return Err(format!("Request {:?} must have an array of ratings", id)),
+        };
+
+        requests.push(RatedRequest {
+            id: id,
+            body: body,
+            ratings: ratings,
+        });
+    }
+
+    Ok((requests, metric))
+}
+
+
+/// A hit of a rated request, identified by the name of its index and its id
+struct RankedHit {
+    index: String,
+    id: String,
+    score: Option<f32>,
+}
+
+
+/// Runs a rated request against the indices, returning its top `size` hits
+fn run_rated_request(cluster_metadata: &ClusterMetadata, permissions: &Permissions, indices: &[(IndexRef, String)], body: &Json, size: usize) -> Result<Vec<RankedHit>, String> {
+    let mut hits = Vec::new();
+
+    for &(index_ref, ref index_name) in indices.iter() {
+        let index = match cluster_metadata.indices.get(&index_ref) {
+            Some(index) => index,
+            None => continue,
+        };
+        let index_metadata = index.metadata.read().unwrap();
+
+        if index_metadata.settings.blocks.blocks_read() {
+            return Err(format!("index {} is blocked for reads", index.canonical_name()));
+        }
+
+        let index_reader = index.reader();
+
+        let query = match body.get("query") {
+            Some(query_json) => {
+                let build_context = QueryBuildContext::new().set_index_metadata(&index_metadata);
+                parse_query(query_json).map_err(|e| format!("Query error: {:?}", e))?.build(&build_context, &index_reader.schema())
+            }
+            None => Query::all(),
+        };
+        let query = apply_alias_filter(query, index_name, &index_metadata, &index_reader.schema());
+        let query = apply_role_filter(query, permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+
+        let mut collector = TopScoreCollector::new(size);
+        index_reader.search(&mut collector, &query)?;
+
+        let doc_keys = index_reader.document_keys();
+        for doc_match in collector.into_page() {
+            if let Some(id) = doc_keys.get(&doc_match.doc_id()) {
+                hits.push(RankedHit {
+                    index: index.canonical_name().to_string(),
+                    id: id.clone(),
+                    score: doc_match.score(),
+                });
+            }
+        }
+    }
+
+    // The sort is stable, so hits with the same score stay in the order of the indices
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    hits.truncate(size);
+
+    Ok(hits)
+}
+
+
+pub fn view_rank_eval(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+    let permissions = get_permissions(req);
+
+    let (requests, metric) = match json_from_request_body!(req) {
+        Some(body) => {
+            match parse_rank_eval_request(&body) {
+                Ok(parsed) => parsed,
+                Err(message) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+            }
+        }
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing rank evaluation request"}))),
+    };
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    let mut details = json!({});
+    let mut failures = json!({});
+    let mut total_score = 0.0;
+    let mut num_scored = 0;
+
+    for request in requests.iter() {
+        let hits = match run_rated_request(&cluster_metadata, &permissions, &indices, &request.body, metric.k()) {
+            Ok(hits) => hits,
+            Err(e) => {
+                failures[&request.id] = json!({"error": e});
+                continue;
+            }
+        };
+
+        let hit_ratings = hits.iter().map(|hit| {
+            request.ratings.iter()
+                .find(|rating| rating.index == hit.index && rating.id == hit.id)
+                .map(|rating| rating.rating)
+        }).collect::<Vec<_>>();
+        let ratings = request.ratings.iter().map(|rating| rating.rating).collect::<Vec<_>>();
+
+        let (score, metric_details) = metric.evaluate(&hit_ratings, &ratings);
+        total_score += score;
+        num_scored += 1;
+
+        let unrated_docs = hits.iter().zip(hit_ratings.iter())
+            .filter(|&(_, rating)| rating.is_none())
+            .map(|(hit, _)| json!({"_index": hit.index, "_id": hit.id}))
+            .collect::<Vec<_>>();
+
+        let hits_json = hits.iter().zip(hit_ratings.iter()).map(|(hit, rating)| json!({
+            "hit": {
+                "_index": hit.index,
+                "_id": hit.id,
+                "_score": hit.score,
+            },
+            "rating": rating,
+        })).collect::<Vec<_>>();
+
+        let mut request_metric_details = json!({});
+        request_metric_details[metric.name()] = metric_details;
+
+        details[&request.id] = json!({
+            "metric_score": score,
+            "unrated_docs": unrated_docs,
+            "hits": hits_json,
+            "metric_details": request_metric_details,
+        });
+    }
+
+    let metric_score = if num_scored > 0 { total_score / num_scored as f64 } else { 0.0 };
+
+    Ok(json_response(StatusCode::OK, json!({
+        "metric_score": metric_score,
+        "details": details,
+        "failures": failures,
+    })))
+}
@@ -0,0 +1,47 @@
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, index_not_found_response};
+
+
+pub fn view_get_recovery(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Indices that are still loading aren't in the cluster metadata yet, so look for the
+    // recovery first
+    if let Some(recovery) = system.recoveries.read().unwrap().get(*index_name) {
+        return Ok(json_response(StatusCode::OK, json!({
+            *index_name: {
+                "shards": [&**recovery],
+            }
+        })));
+    }
+
+    // Indices that were closed when the server started haven't been recovered
+    let cluster_metadata = system.metadata.read().unwrap();
+    if cluster_metadata.names.find_canonical(*index_name).is_none() {
+        return Ok(index_not_found_response(index_name));
+    }
+
+    Ok(json_response(StatusCode::OK, json!({
+        *index_name: {
+            "shards": [],
+        }
+    })))
+}
+
+
+/// Lists the recoveries of every index that's been loaded or opened since the server
+/// started, including the ones that failed
+pub fn view_get_all_recoveries(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    let mut response = json!({});
+    for (index_name, recovery) in system.recoveries.read().unwrap().iter() {
+        response[index_name] = json!({
+            "shards": [&**recovery],
+        });
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
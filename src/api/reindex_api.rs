@@ -0,0 +1,316 @@
+use std::io::Read;
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+use serde_json;
+use serde_json::Value as Json;
+use url::form_urlencoded;
+
+use search::cancellation::SearchCancellation;
+use search::query::Query;
+use query_parser::{QueryBuildContext, parse as parse_query};
+use reindex::{Reindex, ReindexSource, ReindexStatus, DEFAULT_BATCH_SIZE};
+use reindex::remote::RemoteHost;
+use tasks::{TaskStatus, format_task_id};
+use update::UpdateScript;
+use security::roles::IndexPrivilege;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, get_refresh_policy, index_blocked_response, apply_alias_filter, apply_role_filter, forbidden_response};
+use api::security_api::{get_permissions, missing_index_privilege_message};
+use api::cluster_api::{DocumentAccess, FORWARDED_HEADER, index_node, forward_body_to_node};
+
+
+/// The body of a reindex request
+struct ReindexRequest {
+    source_index_name: String,
+    source_query: Option<Json>,
+
+    /// The cluster to copy from, if it isn't this one
+    remote: Option<RemoteHost>,
+
+    /// How many documents to copy at a time
+    batch_size: Option<usize>,
+
+    dest_index_name: String,
+    dest_mapping_name: Option<String>,
+    create_only: bool,
+    script: Option<UpdateScript>,
+    proceed_on_conflicts: bool,
+    max_docs: Option<usize>,
+}
+
+
+fn parse_reindex_request(body: &Json) -> Result<ReindexRequest, String> {
+    let body = body.as_object().ok_or("Request body must be an object")?;
+    let mut request = ReindexRequest {
+        source_index_name: String::new(),
+        source_query: None,
+        remote: None,
+        batch_size: None,
+        dest_index_name: String::new(),
+        dest_mapping_name: None,
+        create_only: false,
+        script: None,
+        proceed_on_conflicts: false,
+        max_docs: None,
+    };
+
+    let source = body.get("source").and_then(|source| source.as_object()).ok_or("source must be an object")?;
+    for (key, value) in source.iter() {
+        match key.as_ref() {
+            "index" => request.source_index_name = value.as_str().ok_or("source.index must be a string")?.to_string(),
+            "query" => request.source_query = Some(value.clone()),
+            "remote" => request.remote = Some(RemoteHost::parse(value)?),
+            "size" => {
+                request.batch_size = match value.as_u64() {
+                    Some(size) if size > 0 => Some(size as usize),
+                    _ => return Err("source.size must be a positive integer".to_string()),
+                };
+            }
+            _ => return Err(format!("Unrecognised key in source: {:?}", key)),
+        }
+    }
+
+    let dest = body.get("dest").and_then(|dest| dest.as_object()).ok_or("dest must be an object")?;
+    for (key, value) in dest.iter() {
+        match key.as_ref() {
+            "index" => request.dest_index_name = value.as_str().ok_or("dest.index must be a string")?.to_string(),
+            "type" => request.dest_mapping_name = Some(value.as_str().ok_or("dest.type must be a string")?.to_string()),
+            "op_type" => {
+                request.create_only = match value.as_str() {
+                    Some("index") => false,
+                    Some("create") => true,
+                    _ => return Err("dest.op_type must be index or create".to_string()),
+                };
+            }
+            _ => return Err(format!("Unrecognised key in dest: {:?}", key)),
+        }
+    }
+
+    if request.source_index_name.is_empty() {
+        return Err("source.index is required".to_string());
+    }
+
+    if request.dest_index_name.is_empty() {
+        return Err("dest.index is required".to_string());
+    }
+
+    for (key, value) in body.iter() {
+        match key.as_ref() {
+            "source" | "dest" => {}
+            "script" => request.script = Some(UpdateScript::parse(value).map_err(|e| format!("Script error: {}", e))?),
+            "conflicts" => {
+                request.proceed_on_conflicts = match value.as_str() {
+                    Some("abort") => false,
+                    Some("proceed") => true,
+                    _ => return Err("conflicts must be abort or proceed".to_string()),
+                };
+            }
+            "max_docs" => request.max_docs = Some(value.as_u64().ok_or("max_docs must be a positive integer")? as usize),
+            _ => return Err(format!("Unrecognised key: {:?}", key)),
+        }
+    }
+
+    Ok(request)
+}
+
+
+/// Reads the "wait_for_completion" URL parameter. Defaults to true
+fn get_wait_for_completion(req: &Request) -> Result<bool, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "wait_for_completion" {
+                return match value.as_ref() {
+                    "true" | "" => Ok(true),
+                    "false" => Ok(false),
+                    _ => Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "wait_for_completion must be true or false"}))),
+                };
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+
+/// Copies documents that match a query from one index into another
+///
+/// This is registered as a task, so it can be followed and cancelled through the tasks API.
+/// With `wait_for_completion=false`, the task id is returned straight away and the result
+/// can be fetched from the tasks API once it has finished.
+pub fn view_post_reindex(req: &mut Request) -> ApiResult<Response> {
+    let system = get_system!(req);
+    let refresh_policy = match get_refresh_policy(req) {
+        Ok(refresh_policy) => refresh_policy,
+        Err(response) => return Ok(response),
+    };
+    let wait_for_completion = match get_wait_for_completion(req) {
+        Ok(wait_for_completion) => wait_for_completion,
+        Err(response) => return Ok(response),
+    };
+
+    let body = match json_from_request_body!(req) {
+        Some(body) => body,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "No data"}))),
+    };
+    let request = match parse_reindex_request(&body) {
+        Ok(request) => request,
+        Err(message) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+    };
+
+    // Remote clusters check their own credentials, but they can only be reached if they're
+    // listed in the settings
+    let permissions = get_permissions(req);
+    match request.remote {
+        Some(ref host) if !system.settings.allows_remote_reindex(&host.address) => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("[{}] isn't in reindex_remote_whitelist", host.address)})));
+        }
+        Some(_) => {}
+        None if !permissions.allows_index(&request.source_index_name, IndexPrivilege::Read) => {
+            return Ok(forbidden_response(missing_index_privilege_message(&request.source_index_name, IndexPrivilege::Read)));
+        }
+        None => {}
+    }
+
+    if !permissions.allows_index(&request.dest_index_name, IndexPrivilege::Write) {
+        return Ok(forbidden_response(missing_index_privilege_message(&request.dest_index_name, IndexPrivilege::Write)));
+    }
+
+    // The copies are written on the node with the destination's primary, which reads a local
+    // source from its own copy
+    if req.header(FORWARDED_HEADER).is_none() {
+        match index_node(&system, &request.dest_index_name, DocumentAccess::Write) {
+            Ok(Some(node)) => return Ok(forward_body_to_node(req, &node, serde_json::to_vec(&body).unwrap())),
+            Ok(None) => {}
+            Err(response) => return Ok(response),
+        }
+    }
+
+    let reindex = {
+        let cluster_metadata = system.metadata.read().unwrap();
+
+        // Check the destination
+        let dest_index = get_write_index_or_404!(cluster_metadata, &request.dest_index_name);
+        let dest_metadata = dest_index.metadata.read().unwrap();
+
+        if dest_metadata.settings.blocks.blocks_write() {
+            return Ok(index_blocked_response(dest_index.canonical_name(), "write"));
+        }
+
+        // Documents don't need a type if the destination only has one mapping
+        let dest_mapping_name = match request.dest_mapping_name {
+            Some(ref mapping_name) if dest_metadata.mappings.contains_key(mapping_name) => mapping_name.clone(),
+            Some(_) => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"}))),
+            None if dest_metadata.mappings.len() == 1 => dest_metadata.mappings.keys().next().unwrap().clone(),
+            None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "dest.type is required as the destination has more than one mapping"}))),
+        };
+
+        let dest_index_name = dest_index.canonical_name().to_string();
+        drop(dest_metadata);
+
+        let source = match request.remote {
+            Some(ref host) => {
+                ReindexSource::Remote {
+                    host: host.clone(),
+                    index_name: request.source_index_name.clone(),
+                    query: request.source_query.clone(),
+                    max_docs: request.max_docs,
+                }
+            }
+            None => {
+                // Find the documents to copy
+                let source_index = get_index_or_404!(cluster_metadata, &request.source_index_name);
+                let source_metadata = source_index.metadata.read().unwrap();
+
+                if source_metadata.settings.blocks.blocks_read() {
+                    return Ok(index_blocked_response(source_index.canonical_name(), "read"));
+                }
+
+                let source_field = match source_metadata.get_field_mapping("_source").and_then(|field_mapping| field_mapping.index_ref) {
+                    Some(source_field) => source_field,
+                    None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "The source index doesn't store the source of its documents"}))),
+                };
+
+                let source_reader = source_index.reader();
+                let query = match request.source_query {
+                    Some(ref query_json) => {
+                        match parse_query(query_json) {
+                            Ok(query) => query.build(&QueryBuildContext::new().set_index_metadata(&source_metadata).no_score(), &source_reader.schema()),
+                            Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {:?}", e)}))),
+                        }
+                    }
+                    None => Query::all(),
+                };
+                let query = apply_alias_filter(query, &request.source_index_name, &source_metadata, &source_reader.schema());
+                let query = apply_role_filter(query, &permissions, &request.source_index_name, source_index.canonical_name(), &source_metadata, &source_reader.schema());
+
+                let doc_ids = match source_reader.matching_documents(&query) {
+                    Ok(doc_ids) => doc_ids,
+                    Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {}", e)}))),
+                };
+                let mut doc_keys = source_reader.document_keys();
+                let mut docs = doc_ids.into_iter()
+                    .filter_map(|doc_id| doc_keys.remove(&doc_id).map(|doc_key| (doc_id, doc_key)))
+                    .collect::<Vec<_>>();
+
+                if let Some(max_docs) = request.max_docs {
+                    docs.truncate(max_docs);
+                }
+
+                ReindexSource::Local {
+                    index_id: *source_index.id(),
+                    pins: source_reader.pin_segments(),
+                    source_field: source_field,
+                    docs: docs,
+                }
+            }
+        };
+
+        Reindex {
+            source: source,
+            batch_size: request.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            dest_index_name: dest_index_name,
+            dest_mapping_name: dest_mapping_name,
+            create_only: request.create_only,
+            script: request.script,
+            proceed_on_conflicts: request.proceed_on_conflicts,
+            refresh_policy: refresh_policy,
+            status: Arc::new(ReindexStatus::default()),
+        }
+    };
+
+    let description = match request.remote {
+        Some(ref host) => format!("reindex from [{}][{}] to [{}]", host.address, request.source_index_name, reindex.dest_index_name),
+        None => format!("reindex from [{}] to [{}]", request.source_index_name, reindex.dest_index_name),
+    };
+    let cancellation = SearchCancellation::new();
+    let task_status = reindex.status.clone() as Arc<TaskStatus>;
+
+    if wait_for_completion {
+        let _task = system.tasks.register_with_status("indices:data/write/reindex", description, Some(&req.opaque_id), cancellation.clone(), Some(task_status));
+        let response = reindex.run(&system, &req.log, &cancellation);
+        let aborted = reindex.status.version_conflicts() > 0 && !reindex.proceed_on_conflicts;
+
+        return Ok(json_response(if aborted { StatusCode::CONFLICT } else { StatusCode::OK }, response));
+    }
+
+    // Run in the background. The task is registered by the new thread, which sends its id back
+    let (sender, receiver) = mpsc::channel();
+    {
+        let system = system.clone();
+        let log = req.log.clone();
+        let opaque_id = req.opaque_id.clone();
+        thread::spawn(move || {
+            let task = system.tasks.register_with_status("indices:data/write/reindex", description, Some(&opaque_id), cancellation.clone(), Some(task_status));
+            sender.send(task.id()).unwrap();
+
+            let response = reindex.run(&system, &log, &cancellation);
+            task.finish(response);
+        });
+    }
+
+    let task_id = receiver.recv().unwrap();
+    return Ok(json_response(StatusCode::OK, json!({"task": format_task_id(task_id)})));
+}
@@ -0,0 +1,231 @@
+//! The requests and responses that API handlers work with
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{FutureExt, StreamExt};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use hyper::{self, Body, Method, Uri, HeaderMap, StatusCode};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use slog::Logger;
+use tokio;
+use uuid::Uuid;
+
+use system::System;
+use security::Principal;
+use security::roles::Permissions;
+
+
+/// Handlers and the checks that run before them return an error response to stop the request
+pub type ApiResult<T> = Result<T, Response>;
+
+
+/// How many chunks of a request body can be received before the handler reads them
+const BODY_BUFFER_CHUNKS: usize = 4;
+
+
+/// Clients can set this header to find their requests in the logs and the tasks API. It's
+/// sent back with the response
+pub const OPAQUE_ID_HEADER: &'static str = "X-Opaque-Id";
+
+
+/// Longer ids are replaced with generated ones, so they can't bloat the logs
+const MAX_OPAQUE_ID_LENGTH: usize = 256;
+
+
+/// Reads the request's opaque id, or generates one if the client didn't send one
+fn opaque_id(headers: &HeaderMap) -> String {
+    headers.get(OPAQUE_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_OPAQUE_ID_LENGTH)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string())
+}
+
+
+/// The body of a request, read as it arrives
+///
+/// Handlers run on worker threads rather than the server's, so reading blocks until the next
+/// part of the body has been received.
+pub struct RequestBody {
+    chunks: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+    current: Bytes,
+}
+
+
+impl RequestBody {
+    /// Starts receiving a body. This must be called on the server's runtime
+    pub fn new(body: Body) -> RequestBody {
+        let (sender, chunks) = mpsc::channel(BODY_BUFFER_CHUNKS);
+
+        // Stops early if the body is dropped before it's all been read
+        tokio::spawn(body.map(Ok).forward(sender).map(|_| ()));
+
+        RequestBody {
+            chunks: chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match block_on(self.chunks.next()) {
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                None => return Ok(0),
+            }
+        }
+
+        let length = cmp::min(buf.len(), self.current.len());
+        buf[..length].copy_from_slice(&self.current[..length]);
+        self.current = self.current.slice(length..);
+
+        Ok(length)
+    }
+}
+
+
+pub struct Request {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: RequestBody,
+
+    /// The client's end of the connection. This is the last proxy if there were any
+    pub remote_addr: SocketAddr,
+
+    pub system: Arc<System>,
+
+    /// Values of the parameters in the route, such as "index" in "/:index/_search"
+    pub params: HashMap<String, String>,
+
+    /// Who made the request. Set by `authenticate_request`
+    pub principal: Principal,
+
+    /// What the request is allowed to do. Nothing is allowed until `authorize_request` has run
+    pub permissions: Permissions,
+
+    /// From the `X-Opaque-Id` header, or generated if there wasn't one
+    pub opaque_id: String,
+
+    /// The system's logger, with the opaque id added to everything that's logged
+    pub log: Logger,
+}
+
+
+impl Request {
+    pub fn new(method: Method, uri: Uri, headers: HeaderMap, body: RequestBody, remote_addr: SocketAddr, system: Arc<System>) -> Request {
+        let opaque_id = opaque_id(&headers);
+        let log = system.log.new(o!("opaque_id" => opaque_id.clone()));
+
+        Request {
+            method: method,
+            uri: uri,
+            headers: headers,
+            body: body,
+            remote_addr: remote_addr,
+            system: system,
+            params: HashMap::new(),
+            principal: Principal::Anonymous,
+            permissions: Permissions::default(),
+            opaque_id: opaque_id,
+            log: log,
+        }
+    }
+
+    /// The segments of the URL path. The root path has a single empty segment
+    pub fn path(&self) -> Vec<&str> {
+        self.uri.path().trim_start_matches('/').split('/').collect()
+    }
+
+    /// Returns the value of a header, if it's there and is valid text
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers.get(CONTENT_LENGTH).and_then(|value| value.to_str().ok()).and_then(|value| value.parse().ok())
+    }
+}
+
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+
+    /// Headers sent after the body. Only HTTP/2 clients receive them
+    pub trailers: HeaderMap,
+}
+
+
+impl Response {
+    /// A response with no body
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status: status,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(status: StatusCode, content_type: &str, body: B) -> Response {
+        let mut response = Response::new(status);
+        response.set_header(CONTENT_TYPE.as_str(), content_type);
+        response.body = body.into();
+        response
+    }
+
+    /// Sets a header, replacing any values it had. Values that aren't valid in a header are
+    /// ignored
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            self.headers.insert(name, value);
+        }
+    }
+
+    /// Sets a trailer, replacing any values it had. Values that aren't valid in a header are
+    /// ignored
+    pub fn set_trailer(&mut self, name: &str, value: &str) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            self.trailers.insert(name, value);
+        }
+    }
+
+    pub fn into_hyper(self) -> hyper::Response<Body> {
+        let body = if self.trailers.is_empty() {
+            Body::from(self.body)
+        } else {
+            // The channel holds one chunk, and the trailers are sent straight away, so
+            // neither waits for the body to be read
+            let (mut sender, body) = Body::channel();
+            if !self.body.is_empty() {
+                let _ = sender.try_send_data(Bytes::from(self.body));
+            }
+            let _ = block_on(sender.send_trailers(self.trailers));
+            body
+        };
+
+        let mut response = hyper::Response::new(body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+
+    /// Adds another value to a header
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            self.headers.append(name, value);
+        }
+    }
+}
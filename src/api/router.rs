@@ -0,0 +1,159 @@
+//! Finds the handler for a request from its method and path
+//!
+//! Routes are patterns such as "/:index/_search", where segments starting with a colon match
+//! any value. Where more than one route matches, the one with a fixed segment in the first
+//! place they differ wins, so "/_search" is used rather than "/:index". Between routes with
+//! the same pattern, the one added first wins.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use hyper::Method;
+
+use api::request::{Request, Response, ApiResult};
+
+
+/// Views are plain functions, but plugins' handlers need to keep the plugin they came from
+pub type Handler = Arc<Fn(&mut Request) -> ApiResult<Response> + Send + Sync>;
+
+
+/// Builds a router from a list of routes, e.g. `router!(get "/" => view_home)`
+macro_rules! router {
+    ($($method:ident $pattern:expr => $handler:expr),+ $(,)*) => {{
+        let mut router = ::api::router::Router::new();
+        $(router.$method($pattern, $handler);)+
+        router
+    }};
+}
+
+
+enum Segment {
+    Fixed(String),
+    Parameter(String),
+}
+
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+
+impl Route {
+    fn matches(&self, method: &Method, path: &[&str]) -> bool {
+        *method == self.method && path.len() == self.segments.len() && self.segments.iter().zip(path.iter()).all(|(segment, value)| {
+            match *segment {
+                Segment::Fixed(ref fixed) => fixed == value,
+                Segment::Parameter(_) => !value.is_empty(),
+            }
+        })
+    }
+
+    /// Ranks routes that match the same path. Fixed segments rank above parameters
+    fn precedence(&self) -> Vec<bool> {
+        self.segments.iter().map(|segment| match *segment {
+            Segment::Fixed(_) => true,
+            Segment::Parameter(_) => false,
+        }).collect()
+    }
+}
+
+
+/// The route that a request matched
+pub struct Match<'a> {
+    pub handler: Handler,
+
+    /// Values of the route's parameters, by name
+    pub params: HashMap<String, String>,
+
+    /// The route's pattern, e.g. "/:index/_search"
+    pub pattern: &'a str,
+}
+
+
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn route<H>(&mut self, method: Method, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        let segments = pattern.trim_start_matches('/').split('/').map(|segment| {
+            if segment.starts_with(':') {
+                Segment::Parameter(segment[1..].to_string())
+            } else {
+                Segment::Fixed(segment.to_string())
+            }
+        }).collect();
+
+        self.routes.push(Route {
+            method: method,
+            pattern: pattern.to_string(),
+            segments: segments,
+            handler: Arc::new(handler),
+        });
+    }
+
+    pub fn get<H>(&mut self, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        self.route(Method::GET, pattern, handler);
+    }
+
+    pub fn head<H>(&mut self, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        self.route(Method::HEAD, pattern, handler);
+    }
+
+    pub fn put<H>(&mut self, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        self.route(Method::PUT, pattern, handler);
+    }
+
+    pub fn post<H>(&mut self, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        self.route(Method::POST, pattern, handler);
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handler: H)
+        where H: Fn(&mut Request) -> ApiResult<Response> + Send + Sync + 'static
+    {
+        self.route(Method::DELETE, pattern, handler);
+    }
+
+    /// Finds the route for a request
+    pub fn recognize<'a>(&'a self, method: &Method, path: &[&str]) -> Option<Match<'a>> {
+        let mut best: Option<&Route> = None;
+        for route in self.routes.iter().filter(|route| route.matches(method, path)) {
+            if best.map_or(true, |best| route.precedence() > best.precedence()) {
+                best = Some(route);
+            }
+        }
+
+        best.map(|route| {
+            let params = route.segments.iter().zip(path.iter()).filter_map(|(segment, value)| match *segment {
+                Segment::Parameter(ref name) => Some((name.clone(), value.to_string())),
+                Segment::Fixed(_) => None,
+            }).collect();
+
+            Match {
+                handler: route.handler.clone(),
+                params: params,
+                pattern: &route.pattern,
+            }
+        })
+    }
+}
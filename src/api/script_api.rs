@@ -0,0 +1,109 @@
+use std::io::Read;
+
+use serde_json;
+use serde_json::Value as Json;
+
+use stored_scripts::{StoredScript, MUSTACHE_LANG};
+use template::{Template, template_source_to_string};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::json_response;
+
+
+/// Reads the script from the body of a request to store one
+///
+/// The script's "source" can be a string or JSON containing mustache tags. Either way it's
+/// stored as a string.
+fn parse_stored_script(json: &Json) -> Result<StoredScript, String> {
+    let script_json = json.get("script").ok_or_else(|| "Missing script".to_string())?;
+
+    let lang = match script_json.get("lang") {
+        Some(&Json::String(ref lang)) => lang.clone(),
+        Some(_) => return Err("lang must be a string".to_string()),
+        None => return Err("Missing lang".to_string()),
+    };
+
+    if lang != MUSTACHE_LANG {
+        return Err(format!("Only {} scripts can be stored", MUSTACHE_LANG));
+    }
+
+    let source = match script_json.get("source") {
+        Some(source) => template_source_to_string(source),
+        None => return Err("Missing source".to_string()),
+    };
+
+    // Check the template can be parsed now rather than when it's used
+    if let Err(e) = Template::parse(&source) {
+        return Err(format!("Template error: {}", e));
+    }
+
+    Ok(StoredScript {
+        lang: lang,
+        source: source,
+    })
+}
+
+
+pub fn view_put_script(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref script_id = read_path_parameter!(req, "id").unwrap_or("");
+
+    let script = match json_from_request_body!(req) {
+        Some(json) => {
+            match parse_stored_script(&json) {
+                Ok(script) => script,
+                Err(message) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+            }
+        }
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing script"}))),
+    };
+
+    if let Err(e) = system.scripts.insert(system.get_stored_scripts_path(), script_id.to_string(), script) {
+        error!(req.log, "failed to store script"; "id" => *script_id, "error" => e);
+        return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to store script"})));
+    }
+
+    info!(req.log, "stored script"; "id" => *script_id);
+
+    Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+}
+
+
+pub fn view_get_script(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref script_id = read_path_parameter!(req, "id").unwrap_or("");
+
+    match system.scripts.get(script_id) {
+        Some(script) => {
+            Ok(json_response(StatusCode::OK, json!({
+                "_id": script_id,
+                "found": true,
+                "script": {
+                    "lang": script.lang,
+                    "source": script.source,
+                },
+            })))
+        }
+        None => Ok(json_response(StatusCode::NOT_FOUND, json!({"_id": script_id, "found": false}))),
+    }
+}
+
+
+pub fn view_delete_script(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref script_id = read_path_parameter!(req, "id").unwrap_or("");
+
+    match system.scripts.remove(system.get_stored_scripts_path(), script_id) {
+        Ok(true) => {
+            info!(req.log, "deleted script"; "id" => *script_id);
+
+            Ok(json_response(StatusCode::OK, json!({"acknowledged": true})))
+        }
+        Ok(false) => Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Script not found"}))),
+        Err(e) => {
+            error!(req.log, "failed to delete script"; "id" => *script_id, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to delete script"})))
+        }
+    }
+}
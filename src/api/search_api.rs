@@ -1,164 +1,1273 @@
 use std::io::Read;
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use serde_json;
 use url::form_urlencoded;
-use search::document::DocId;
+use serde_json::Value as Json;
+use slog::Logger;
 use search::query::Query;
+use search::aggregations::{Aggregation, AggregationResult};
 use search::collectors::top_score::TopScoreCollector;
+use search::collectors::top_field::TopFieldCollector;
 use search::collectors::total_count::TotalCountCollector;
+use search::collectors::profile::ProfileCollector;
+use search::collectors::min_score::MinScoreCollector;
+use search::collectors::aggregation::AggregationCollector;
+use search::collectors::Collector;
+use search::profile::{CollectorProfile, duration_to_nanos};
+use search::cancellation::SearchCancellation;
+use search::aggregations::breaker::CircuitBreaker;
+use search::sort::{SortField, compare_sort_values};
 
-use query_parser::{QueryBuildContext, parse as parse_query};
+use system::System;
+use index::Index;
+use index::reader::IndexReader;
+use security::roles::Permissions;
+use query_parser::{QueryBuilder, QueryBuildContext, parse as parse_query};
+use query_parser::sort::{parse as parse_sort, parse_search_after};
+use query_parser::highlight::parse as parse_highlight;
+use query_parser::source_filter::{parse as parse_source_filter, parse_stored_fields, parse_docvalue_fields};
+use query_parser::indices_boost::parse as parse_indices_boost;
+use query_parser::script::parse_script_fields;
+use query_parser::knn::parse as parse_knn;
+use query_parser::aggregations::parse as parse_aggregations;
+use query_parser::suggest::parse as parse_suggest;
+use source_filter::{SourceFilter, wildcard_match};
+use scroll::{ScrollSearch, ScrollContext, ScrollHit, parse_keep_alive};
+use fetch::{FetchPhase, hit_to_json};
+use response_format::{ResponseFormat, hits_to_csv, hits_to_ndjson};
+use aggregations::aggregation_results_to_json;
+use template::{render_search_template, template_source_to_string};
+use slowlog;
 
-use api::persistent;
-use api::iron::prelude::*;
-use api::iron::status;
-use api::router::Router;
-use api::utils::json_response;
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, index_blocked_response, resolve_error_response, apply_alias_filter, apply_role_filter, document_filter, get_indices_options, get_routing};
+use api::security_api::get_permissions;
 
 
-pub fn view_count(req: &mut Request) -> IronResult<Response> {
+/// How many hits to count towards the total. Set by the "track_total_hits" option
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrackTotalHits {
+    /// Count every hit
+    Exact,
+
+    /// Only report the total exactly up to this number
+    UpTo(u64),
+
+    /// Don't return the total
+    Disabled,
+}
+
+
+impl TrackTotalHits {
+    fn from_json(json: &Json) -> Option<TrackTotalHits> {
+        match *json {
+            Json::Bool(true) => Some(TrackTotalHits::Exact),
+            Json::Bool(false) => Some(TrackTotalHits::Disabled),
+            _ => json.as_u64().map(TrackTotalHits::UpTo),
+        }
+    }
+
+    fn total_to_json(&self, total_hits: u64) -> Option<Json> {
+        match *self {
+            TrackTotalHits::Exact => Some(json!({"value": total_hits, "relation": "eq"})),
+            TrackTotalHits::UpTo(limit) if total_hits > limit => Some(json!({"value": limit, "relation": "gte"})),
+            TrackTotalHits::UpTo(_) => Some(json!({"value": total_hits, "relation": "eq"})),
+            TrackTotalHits::Disabled => None,
+        }
+    }
+}
+
+
+/// Runs the search, dropping hits below `min_score` and timing the collector if profiling is enabled
+///
+/// The last value returned is false if the search was stopped by a timeout or cancellation
+fn run_search<C: Collector>(index_reader: &IndexReader, query: &Query, collector: C, collector_name: &str, min_score: Option<f32>, profile: bool, cancellation: &SearchCancellation) -> (C, Option<CollectorProfile>, bool) {
+    let collector = MinScoreCollector::new(collector, min_score);
+
+    if profile {
+        let mut collector = ProfileCollector::new(collector_name, collector);
+        let finished = index_reader.search_cancellable(&mut collector, query, cancellation).unwrap();
+        let collector_profile = collector.profile();
+        (collector.into_inner().into_inner(), Some(collector_profile), finished)
+    } else {
+        let mut collector = collector;
+        let finished = index_reader.search_cancellable(&mut collector, query, cancellation).unwrap();
+        (collector.into_inner(), None, finished)
+    }
+}
+
+
+pub fn view_count(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let permissions = get_permissions(req);
 
     // Get index
     let cluster_metadata = system.metadata.read().unwrap();
     let index = get_index_or_404!(cluster_metadata, *index_name);
-    let index_reader = index.store.reader();
+    drop(cluster_metadata);
     let index_metadata = index.metadata.read().unwrap();
 
-    let count = match json_from_request_body!(req) {
-        Some(query_json) => {
-            // Parse query
-            let query = parse_query(query_json.as_object().unwrap().get("query").unwrap());
-            //debug!("{:#?}", query);
-
-            match query {
-                Ok(query) => {
-                    let mut collector = TotalCountCollector::new();
-                    index_reader.search(&mut collector, &query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata).no_score(), &index_reader.schema())).unwrap();
-                    collector.get_total_count()
+    if index_metadata.settings.blocks.blocks_read() {
+        return Ok(index_blocked_response(index.canonical_name(), "read"));
+    }
+
+    let index_reader = index.reader();
+
+    // Documents are only counted so the query is built without scoring, unless min_score needs the scores
+    let mut query = Query::all();
+    let mut min_score = None;
+
+    if let Some(body_json) = json_from_request_body!(req) {
+        let body = match body_json.as_object() {
+            Some(body) => body,
+            None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body must be an object"}))),
+        };
+
+        for (key, value) in body.iter() {
+            match key.as_ref() {
+                "query" => {}
+                "min_score" => {
+                    min_score = match value.as_f64() {
+                        Some(min_score) => Some(min_score as f32),
+                        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "min_score must be a number"}))),
+                    };
+                }
+                _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Unrecognised key {:?}", key)}))),
+            }
+        }
+
+        if let Some(query_json) = body.get("query") {
+            let mut build_context = QueryBuildContext::new().set_index_metadata(&index_metadata);
+            if min_score.is_none() {
+                build_context = build_context.no_score();
+            }
+
+            query = match parse_query(query_json) {
+                Ok(query) => query.build(&build_context, &index_reader.schema()),
+                Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Query error: {:?}", e)}))),
+            };
+        }
+    }
+
+    let query = apply_alias_filter(query, index_name, &index_metadata, &index_reader.schema());
+    let query = apply_role_filter(query, &permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+    let count = match min_score {
+        Some(_) => {
+            let mut collector = MinScoreCollector::new(TotalCountCollector::new(), min_score);
+            index_reader.search(&mut collector, &query).unwrap();
+            collector.into_inner().get_total_count()
+        }
+        None => index_reader.count(&query).unwrap(),
+    };
+
+    return Ok(json_response(StatusCode::OK, json!({
+        "count": count,
+        "_shards": {
+            "total": index.shards().len(),
+            "successful": index.shards().len(),
+            "skipped": 0,
+            "failed": 0,
+        },
+    })));
+}
+
+
+/// Options for a search that are given in the URL
+struct SearchParams {
+    from: usize,
+    size: usize,
+
+    /// Stored fields to return, looked up in each index that's searched
+    field_names: Vec<String>,
+
+    scroll: Option<Duration>,
+    timeout: Option<Duration>,
+
+    /// How the hits are returned. Taken from the "format" parameter, or the Accept header
+    format: ResponseFormat,
+}
+
+
+/// Checks that a search of several indices doesn't use anything that can only be used on one
+///
+/// Aggregations, suggestions, scrolls and profiles are worked out separately for each index,
+/// and can't be merged yet. Returns the error message if the search uses one of them
+fn check_multiple_indices_search(query_json: &Json, params: &SearchParams) -> Option<String> {
+    let unsupported = if query_json.get("aggs").or(query_json.get("aggregations")).is_some() {
+        "Aggregations"
+    } else if query_json.get("suggest").is_some() {
+        "Suggesters"
+    } else if params.scroll.is_some() {
+        "Scrolls"
+    } else if query_json.get("profile").and_then(|profile| profile.as_bool()).unwrap_or(false) {
+        "Profiling"
+    } else {
+        return None;
+    };
+
+    Some(format!("{} can only be used when searching a single index", unsupported))
+}
+
+
+fn parse_search_params(req: &Request) -> Result<SearchParams, Response> {
+    let mut params = SearchParams {
+        from: 0,
+        size: 10,
+        field_names: Vec::new(),
+        scroll: None,
+        timeout: None,
+        format: req.header("Accept").map_or(ResponseFormat::Json, ResponseFormat::from_accept),
+    };
+
+    if let Some(ref url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            match key.as_ref() {
+                "from" => {
+                    params.from = match value.as_ref().parse() {
+                        Ok(from) => from,
+                        Err(_) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "from must be a positive integer"}))),
+                    };
                 }
-                Err(_) => {
-                    // TODO: What specifically is bad about the Query?
-                    let mut response = Response::with((status::BadRequest,
-                                                       "{\"message\": \"Query error\"}"));
-                    response.headers.set_raw("Content-Type", vec![b"application/json".to_vec()]);
-                    return Ok(response);
+                "size" => {
+                    params.size = match value.as_ref().parse() {
+                        Ok(size) => size,
+                        Err(_) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "size must be a positive integer"}))),
+                    };
+                }
+                "fields" => {
+                    params.field_names.extend(value.split(",").map(|field_name| field_name.to_owned()));
+                }
+                "scroll" => {
+                    params.scroll = match parse_keep_alive(value.as_ref()) {
+                        Some(keep_alive) => Some(keep_alive),
+                        None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "scroll must be a time value, eg 1m"}))),
+                    };
+                }
+                "timeout" => {
+                    params.timeout = match parse_keep_alive(value.as_ref()) {
+                        Some(timeout) => Some(timeout),
+                        None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "timeout must be a time value, eg 10s"}))),
+                    };
+                }
+                "format" => {
+                    params.format = match ResponseFormat::from_name(value.as_ref()) {
+                        Some(format) => format,
+                        None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "format must be json, csv or ndjson"}))),
+                    };
+                }
+                // terminate_after
+                // explain
+                // version
+                // fielddata_fields
+                // track_scores
+                // stats
+                // suggest_field
+                _ => warn!(req.log, "unrecognised GET parameter {:?}", key),
+            }
+        }
+    }
+
+    Ok(params)
+}
+
+
+/// A parsed search request. This is shared by the searches of each index
+struct SearchRequest<'a> {
+    body: &'a Json,
+    query: Option<Box<QueryBuilder>>,
+    params: SearchParams,
+
+    /// When the request started to be processed. Used to time the query rewrite when profiling
+    started_at: Instant,
+
+    cancellation: SearchCancellation,
+
+    /// What the roles of whoever made the request let them read
+    permissions: Permissions,
+}
+
+
+/// The results of searching one index
+struct IndexSearch {
+    total_hits: u64,
+    max_score: Option<f32>,
+
+    /// The hits on the page, along with the data loaded for them by the fetch phase
+    hits: Vec<(ScrollHit, Json)>,
+
+    timed_out: bool,
+
+    /// How the hits are sorted, if they aren't sorted by score
+    sort: Option<Vec<SortField>>,
+
+    aggregations: Option<Json>,
+    suggest: Option<Json>,
+    scroll_id: Option<String>,
+    profile: Option<Json>,
+}
+
+
+/// An index to search, found while the cluster metadata was locked
+struct SearchTarget {
+    index: Arc<Index>,
+
+    /// The name the index was found through, which may be an alias
+    name: String,
+
+    /// Aliases of the index, for matching `indices_boost` patterns
+    aliases: Vec<String>,
+}
+
+
+/// Checks that a search doesn't ask for more hits than the index allows
+fn check_result_window(from: usize, size: usize, max_result_window: u32) -> Result<(), Response> {
+    match from.checked_add(size) {
+        Some(window) if window <= max_result_window as usize => Ok(()),
+        window => {
+            let window = window.map_or("more than the largest possible".to_string(), |window| window.to_string());
+            Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Result window is too large, from + size must be less than or equal to {} but was {}. Use a scroll or search_after to page through large results, or raise the index.max_result_window setting", max_result_window, window)})))
+        }
+    }
+}
+
+
+/// Checks that a scroll isn't kept alive for longer than the cluster allows
+fn check_keep_alive(system: &System, keep_alive: Duration) -> Result<(), Response> {
+    let max_keep_alive = system.cluster_settings.current().max_scroll_keep_alive;
+
+    if keep_alive > max_keep_alive {
+        Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("scroll keep alive of {}s is too large, it must be less than or equal to {}s. Raise the search.max_keep_alive setting to keep scrolls open for longer", keep_alive.as_secs(), max_keep_alive.as_secs())})))
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Runs a search against one index, returning the hits from `from` to `from + size`
+fn search_index(system: &System, log: &Logger, target: &SearchTarget, request: &SearchRequest, from: usize, size: usize) -> Result<IndexSearch, Response> {
+    let index = &target.index;
+    let index_name = &target.name[..];
+    let query_json = request.body;
+    let params = &request.params;
+    let cancellation = &request.cancellation;
+    let index_metadata = index.metadata.read().unwrap();
+
+    if index_metadata.settings.blocks.blocks_read() {
+        return Err(index_blocked_response(index.canonical_name(), "read"));
+    }
+
+    check_result_window(params.from, params.size, index_metadata.settings.max_result_window)?;
+
+    // Documents hidden by a filtered alias or the roles of whoever made the request are left
+    // out of everything the search reads, including aggregations and suggestions
+    let index_reader = index.reader();
+    let filter = document_filter(&request.permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+    let index_reader = match filter {
+        Some(filter) => index_reader.restrict(filter),
+        None => index_reader,
+    };
+
+    // Look up the fields requested in the URL. Unknown ones are skipped
+    let mut fields = Vec::new();
+    for field_name in params.field_names.iter() {
+        match index_reader.schema().get_field_by_name(field_name) {
+            Some(field_ref) => fields.push((field_name.clone(), field_ref)),
+            None => warn!(log, "unknown field {:?}", field_name),
+        }
+    }
+
+    // Parse sort
+    let sort = match query_json.get("sort") {
+        Some(sort_json) => {
+            match parse_sort(sort_json, &index_metadata) {
+                Ok(sort) => Some(sort),
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Sort error: {:?}", e)}))),
+            }
+        }
+        None => None,
+    };
+
+    // The sort is needed again to merge hits from several indices
+    let merge_sort = sort.clone();
+
+    // Parse search_after. This continues from the sort values of the last hit of the previous page
+    let search_after = match (query_json.get("search_after"), sort.as_ref()) {
+        (Some(search_after_json), Some(sort)) => {
+            if params.from != 0 {
+                return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "from must be 0 when search_after is used"})));
+            }
+
+            match parse_search_after(search_after_json, sort, &index_metadata) {
+                Ok(search_after) => Some(search_after),
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("search_after error: {:?}", e)}))),
+            }
+        }
+        (Some(_), None) => {
+            return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "search_after requires a sort"})));
+        }
+        (None, _) => None,
+    };
+
+    let min_score = match query_json.get("min_score") {
+        Some(min_score_json) => {
+            match min_score_json.as_f64() {
+                Some(min_score) => Some(min_score as f32),
+                None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "min_score must be a number"}))),
+            }
+        }
+        None => None,
+    };
+
+    let profile = query_json.get("profile").and_then(|profile| profile.as_bool()).unwrap_or(false);
+    let explain = query_json.get("explain").and_then(|explain| explain.as_bool()).unwrap_or(false);
+
+    // Parse source filtering and field selection
+    let source_filter = match query_json.get("_source") {
+        Some(source_json) => {
+            match parse_source_filter(source_json) {
+                Ok(source_filter) => source_filter,
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("_source error: {:?}", e)}))),
+            }
+        }
+
+        // Asking for stored fields turns off the source unless it was asked for too
+        None if query_json.get("stored_fields").is_some() => SourceFilter::Disabled,
+        None => SourceFilter::default(),
+    };
+
+    if let Some(stored_fields_json) = query_json.get("stored_fields") {
+        match parse_stored_fields(stored_fields_json, &index_metadata) {
+            Ok(stored_fields) => fields.extend(stored_fields),
+            Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("stored_fields error: {:?}", e)}))),
+        }
+    }
+
+    if let Some(docvalue_fields_json) = query_json.get("docvalue_fields") {
+        match parse_docvalue_fields(docvalue_fields_json, &index_metadata) {
+            Ok(docvalue_fields) => fields.extend(docvalue_fields),
+            Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("docvalue_fields error: {:?}", e)}))),
+        }
+    }
+
+    let script_fields = match query_json.get("script_fields") {
+        Some(script_fields_json) => {
+            match parse_script_fields(script_fields_json, &index_metadata) {
+                Ok(script_fields) => script_fields,
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("script_fields error: {:?}", e)}))),
+            }
+        }
+        None => Vec::new(),
+    };
+
+    // Parse aggregations
+    let mut aggregations = match query_json.get("aggs").or(query_json.get("aggregations")) {
+        Some(aggregations_json) => {
+            match parse_aggregations(aggregations_json, &index_metadata, &index_reader.schema()) {
+                Ok(aggregations) => Some(aggregations),
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Aggregation error: {:?}", e)}))),
+            }
+        }
+        None => None,
+    };
+
+    // Find the documents that match the filters of filter aggregations
+    for &mut (_, ref mut aggregation) in aggregations.iter_mut().flat_map(|aggregations| aggregations.iter_mut()) {
+        for filter in aggregation.filters_mut() {
+            filter.matches = match index_reader.matching_documents(&filter.query) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    error!(log, "aggregation filter failed"; "index" => index.canonical_name(), "error" => e);
+                    return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Aggregation filter failed"})));
                 }
+            };
+        }
+    }
+
+    // Count the terms in the background sets of significant_terms aggregations
+    let mut all_doc_ids = None;
+    for &mut (_, ref mut aggregation) in aggregations.iter_mut().flat_map(|aggregations| aggregations.iter_mut()) {
+        for (field, background) in aggregation.backgrounds_mut() {
+            if all_doc_ids.is_none() && background.filter.is_none() {
+                all_doc_ids = match index_reader.matching_documents(&Query::all()) {
+                    Ok(doc_ids) => Some(doc_ids),
+                    Err(e) => {
+                        error!(log, "aggregation background failed"; "index" => index.canonical_name(), "error" => e);
+                        return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Aggregation background failed"})));
+                    }
+                };
+            }
+
+            background.count(field, all_doc_ids.as_ref().map_or(&[], |doc_ids| &doc_ids[..]), &mut |field_ref, doc_id| {
+                index_reader.read_stored_field(field_ref, doc_id).ok().and_then(|value| value)
+            });
+        }
+    }
+
+    let source_field_ref = match index_metadata.get_field_mapping("_source") {
+        Some(field_mapping) => field_mapping.index_ref,
+        None => None,
+    };
+
+    // Parse highlight
+    let highlight_fields = match query_json.get("highlight") {
+        Some(highlight_json) => {
+            match parse_highlight(highlight_json, &index_metadata) {
+                Ok(highlight_fields) => highlight_fields,
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Highlight error: {:?}", e)}))),
+            }
+        }
+        None => Vec::new(),
+    };
+
+    // Parse suggest
+    let suggesters = match query_json.get("suggest") {
+        Some(suggest_json) => {
+            match parse_suggest(suggest_json, &index_metadata) {
+                Ok(suggesters) => Some(suggesters),
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Suggest error: {}", e)}))),
+            }
+        }
+        None => None,
+    };
+
+    // Parse knn
+    let knn_searches = match query_json.get("knn") {
+        Some(knn_json) => {
+            match parse_knn(knn_json, &index_metadata) {
+                Ok(knn_searches) => knn_searches,
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("knn error: {:?}", e)}))),
+            }
+        }
+        None => Vec::new(),
+    };
+
+    // Parse indices_boost. The first pattern that matches the index's name or one of
+    // its aliases is used, otherwise the index's "search.boost" setting applies
+    let index_boost = match query_json.get("indices_boost") {
+        Some(indices_boost_json) => {
+            let indices_boost = match parse_indices_boost(indices_boost_json) {
+                Ok(indices_boost) => indices_boost,
+                Err(e) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("indices_boost error: {:?}", e)}))),
+            };
+
+            let mut index_names = vec![index.canonical_name()];
+            index_names.extend(target.aliases.iter().map(|alias| &alias[..]));
+
+            indices_boost.iter()
+                .find(|&&(ref pattern, _)| index_names.iter().any(|name| wildcard_match(pattern, name)))
+                .map(|&(_, boost)| boost)
+                .unwrap_or(index_metadata.settings.search_boost)
+        }
+        None => index_metadata.settings.search_boost,
+    };
+
+    if params.scroll.is_some() && (params.from != 0 || search_after.is_some()) {
+        return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "from and search_after can't be used with scroll"})));
+    }
+
+    // Do the search
+    let query_started_at = Instant::now();
+    let build_context = QueryBuildContext::new().set_index_metadata(&index_metadata);
+    let mut queries = Vec::new();
+    if let Some(ref query) = request.query {
+        queries.push(query.build(&build_context, &index_reader.schema()));
+    }
+
+    // Find the nearest neighbours of each kNN search up front, they're then matched
+    // alongside the query
+    for knn in knn_searches.iter() {
+        let knn = knn.build(&build_context, &index_reader.schema());
+        match index_reader.knn_search(&knn) {
+            Ok(neighbours) => queries.push(knn.to_query(&neighbours)),
+            Err(e) => {
+                error!(log, "knn search failed"; "index" => index.canonical_name(), "error" => e);
+                return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "kNN search failed"})));
             }
         }
+    }
+
+    let query = match queries.len() {
+        0 => Query::all(),
+        1 => queries.pop().unwrap(),
+        _ => Query::Disjunction { queries: queries },
+    }.boost(index_boost);
+    let rewrite_time = duration_to_nanos(request.started_at.elapsed());
+
+    // Aggregations that grow too large stop the search rather than running out of memory
+    let breaker = CircuitBreaker::new(system.cluster_settings.current().aggregation_memory_limit_bytes()).cancel_on_trip(cancellation.clone());
+
+    let mut read_doc_value = |field_ref, doc_id| {
+        index_reader.read_stored_field(field_ref, doc_id).ok().and_then(|value| value)
+    };
+    let no_aggregations = Vec::new();
+    let aggregations_to_run = aggregations.as_ref().unwrap_or(&no_aggregations);
+
+    let mut collector_profiles = Vec::new();
+    let (total_hits, max_score, page, finished, mut aggregation_results) = match sort {
+        Some(sort) => {
+            let mut collector = TopFieldCollector::page(sort, from, size, read_doc_value);
+            if let Some(search_after) = search_after {
+                collector = collector.search_after(search_after);
+            }
+            let collector = AggregationCollector::new(collector, aggregations_to_run, &breaker, read_doc_value);
+            let (collector, collector_profile, finished) = run_search(&index_reader, &query, collector, "TopFieldCollector", min_score, profile, cancellation);
+            collector_profiles.extend(collector_profile);
+            let (collector, aggregation_results) = collector.into_inner();
+
+            let total_hits = collector.total_hits();
+            let max_score = collector.max_score();
+            let page = collector.into_page().into_iter().map(|doc| ScrollHit {
+                doc_id: doc.id,
+                score: doc.score,
+                sort_values: Some(doc.sort_values),
+            }).collect::<Vec<_>>();
+            (total_hits, max_score, page, finished, aggregation_results)
+        }
         None => {
-            let mut collector = TotalCountCollector::new();
-            index_reader.search(&mut collector, &Query::all()).unwrap();
-            collector.get_total_count()
+            let collector = AggregationCollector::new(TopScoreCollector::page(from, size), aggregations_to_run, &breaker, read_doc_value);
+            let (collector, collector_profile, finished) = run_search(&index_reader, &query, collector, "TopScoreCollector", min_score, profile, cancellation);
+            collector_profiles.extend(collector_profile);
+            let (collector, aggregation_results) = collector.into_inner();
+
+            let total_hits = collector.total_hits();
+            let max_score = collector.max_score();
+            let page = collector.into_page().iter().map(|doc_match| ScrollHit {
+                doc_id: doc_match.doc_id(),
+                score: doc_match.score(),
+                sort_values: None,
+            }).collect::<Vec<_>>();
+            (total_hits, max_score, page, finished, aggregation_results)
         }
     };
 
-    return Ok(json_response(status::Ok, json!({"count": count})));
+    // Global aggregations ignore the query, so run on every document once the search is done
+    for &(ref name, ref aggregation) in aggregations_to_run.iter() {
+        if let Aggregation::Global(ref global) = *aggregation {
+            if all_doc_ids.is_none() {
+                all_doc_ids = match index_reader.matching_documents(&Query::all()) {
+                    Ok(doc_ids) => Some(doc_ids),
+                    Err(e) => {
+                        error!(log, "global aggregation failed"; "index" => index.canonical_name(), "error" => e);
+                        return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Global aggregation failed"})));
+                    }
+                };
+            }
+
+            let bucket = global.run(all_doc_ids.as_ref().map_or(&[], |doc_ids| &doc_ids[..]), &breaker, &mut read_doc_value);
+            aggregation_results.push((name.clone(), AggregationResult::SingleBucket(bucket)));
+        }
+    }
+
+    if breaker.is_tripped() {
+        return Err(json_response(StatusCode::TOO_MANY_REQUESTS, json!({"message": format!("Aggregations would use more than the limit of {} bytes", breaker.limit())})));
+    }
+
+    // A timed out search returns the hits it found so far, but a cancelled one is abandoned
+    if !finished && cancellation.is_cancelled() {
+        return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "Search was cancelled"})));
+    }
+    let timed_out = !finished;
+
+    // Keep the reader open for the scroll, so the following pages are found from the same
+    // point in time. Each page carries on from the last hit of the one before
+    let mut scroll_id = None;
+    if let Some(keep_alive) = params.scroll {
+        let scroll_search = ScrollSearch {
+            index_id: index.id().clone(),
+            pins: index_reader.pin_segments(),
+            query: index_reader.restrict_query(&query).into_owned(),
+            min_score: min_score,
+            sort: merge_sort.clone(),
+            size: size,
+            total_hits: total_hits,
+            max_score: max_score,
+        };
+        let context = ScrollContext::new(scroll_search, page.last().cloned(), keep_alive);
+
+        match system.scrolls.insert(context, system.cluster_settings.current().max_open_scrolls) {
+            Ok(id) => scroll_id = Some(id),
+            Err(context) => {
+                index.unpin_segments(&context.search.pins);
+                return Err(json_response(StatusCode::TOO_MANY_REQUESTS, json!({"message": "Too many scrolls are open, clear some or raise the search.max_open_scroll_context setting"})));
+            }
+        }
+    }
+
+    let query_took = query_started_at.elapsed();
+
+    // Fetch the data for the hits that are being returned
+    let fetch_started_at = Instant::now();
+    let fetch_phase = FetchPhase {
+        source_field: source_field_ref,
+        source_filter: source_filter,
+        fields: fields,
+        script_fields: script_fields,
+        highlight_fields: highlight_fields,
+        explain: explain,
+        ..FetchPhase::new(&query)
+    };
+    // Each hit is tagged with the index it came from, as results from several indices can be merged
+    let hits = fetch_phase.fetch(&index_reader, &page).into_iter().map(|mut hit| {
+        hit["_index"] = json!(index.canonical_name());
+        hit
+    });
+    slowlog::log_search(log, index.canonical_name(), &index_metadata.settings.slowlog, query_took, fetch_started_at.elapsed(), total_hits, query_json);
+
+    // Suggestions don't depend on the query, so they're found separately
+    let suggest = match suggesters {
+        Some(suggesters) => {
+            let mut suggest = json!({});
+
+            for suggester in suggesters.iter() {
+                suggest[suggester.name()] = match suggester.run(&index_reader, index.canonical_name()) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!(log, "suggester failed"; "index" => index.canonical_name(), "error" => e);
+                        return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Suggester failed"})));
+                    }
+                };
+            }
+
+            Some(suggest)
+        }
+        None => None,
+    };
+
+    let profile = if profile {
+        // One profile for each shard
+        let query_profiles = match index_reader.profile(&query, true) {
+            Ok(query_profiles) => query_profiles,
+            Err(e) => {
+                error!(log, "query profiling failed"; "index" => index.canonical_name(), "error" => e);
+                return Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Query profiling failed"})));
+            }
+        };
+
+        Some(json!({
+            "query": query_profiles,
+            "rewrite_time": rewrite_time,
+            "collector": collector_profiles,
+        }))
+    } else {
+        None
+    };
+
+    Ok(IndexSearch {
+        total_hits: total_hits,
+        max_score: max_score,
+        hits: page.into_iter().zip(hits).collect(),
+        timed_out: timed_out,
+        sort: merge_sort,
+        aggregations: aggregations.as_ref().map(|_| aggregation_results_to_json(&aggregation_results)),
+        suggest: suggest,
+        scroll_id: scroll_id,
+        profile: profile,
+    })
+}
+
+
+/// Runs a search with the given body against the indices that `index_name` refers to
+fn run_search_request(system: &System, req: &Request, index_name: &str, query_json: Json) -> ApiResult<Response> {
+    // Find the indices to search. This can be a list of index names, aliases and wildcard patterns.
+    // The cluster metadata is only locked while they're found, not during the search
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let targets = {
+        let cluster_metadata = system.metadata.read().unwrap();
+        let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+            Ok(indices) => indices,
+            Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+        };
+
+        indices.into_iter().filter_map(|(index_ref, name)| {
+            cluster_metadata.indices.get(&index_ref).map(|index| {
+                SearchTarget {
+                    index: index.clone(),
+                    name: name,
+                    aliases: cluster_metadata.names.iter_index_aliases(index_ref).map(|alias| alias.to_string()).collect(),
+                }
+            })
+        }).collect::<Vec<_>>()
+    };
+
+    // Parse query
+    let started_at = Instant::now();
+    let query = match query_json.get("query").map(parse_query) {
+        Some(Ok(query)) => Some(query),
+        Some(Err(_)) => {
+            // TODO: What specifically is bad about the Query?
+            return Ok(Response::with_body(StatusCode::BAD_REQUEST, "application/json", "{\"message\": \"Query error\"}"));
+        }
+        None => None,
+    };
+
+    let mut params = match parse_search_params(req) {
+        Ok(params) => params,
+        Err(response) => return Ok(response),
+    };
+
+    // Only the hits are returned as CSV or NDJSON, so there'd be no scroll id to carry on with
+    if params.format != ResponseFormat::Json && params.scroll.is_some() {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "scroll can only be used with the json format"})));
+    }
+
+    if let Some(keep_alive) = params.scroll {
+        if let Err(response) = check_keep_alive(system, keep_alive) {
+            return Ok(response);
+        }
+    }
+
+    let track_total_hits = match query_json.get("track_total_hits") {
+        Some(track_total_hits_json) => {
+            match TrackTotalHits::from_json(track_total_hits_json) {
+                Some(track_total_hits) => Some(track_total_hits),
+                None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "track_total_hits must be a boolean or a positive integer"}))),
+            }
+        }
+        None => None,
+    };
+
+    // The timeout in the body takes precedence over the URL parameter
+    match query_json.get("timeout") {
+        Some(&Json::String(ref timeout_str)) => {
+            params.timeout = match parse_keep_alive(timeout_str) {
+                Some(timeout) => Some(timeout),
+                None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "timeout must be a time value, eg 10s"}))),
+            };
+        }
+        Some(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "timeout must be a time value, eg 10s"}))),
+        None => {}
+    }
+
+    // Hits from several indices are merged, so each index has to return enough of them to fill the page
+    let multiple_indices = targets.len() != 1;
+    if multiple_indices {
+        if let Some(message) = check_multiple_indices_search(&query_json, &params) {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message})));
+        }
+    }
+
+    // Each index is checked against its own max_result_window when it's searched
+    let (from, size) = if multiple_indices { (0, params.from.saturating_add(params.size)) } else { (params.from, params.size) };
+
+    // Register the search as a task so it can be cancelled
+    let cancellation = match params.timeout {
+        Some(timeout) => SearchCancellation::with_timeout(timeout),
+        None => SearchCancellation::new(),
+    };
+    let index_names = targets.iter().map(|target| target.index.canonical_name()).collect::<Vec<_>>();
+    let task = system.tasks.register("indices:data/read/search", format!("indices[{}]", index_names.join(",")), Some(&req.opaque_id), cancellation.clone());
+
+    let request = SearchRequest {
+        body: &query_json,
+        query: query,
+        params: params,
+        started_at: started_at,
+        cancellation: cancellation,
+        permissions: get_permissions(req),
+    };
+
+    let mut searches = Vec::new();
+    for target in targets.iter() {
+        match search_index(system, &req.log, target, &request, from, size) {
+            Ok(search) => searches.push(search),
+            Err(response) => return Ok(response),
+        }
+    }
+
+    drop(task);
+
+    let total_hits = searches.iter().map(|search| search.total_hits).sum();
+    let max_score = searches.iter().filter_map(|search| search.max_score).fold(None, |max_score: Option<f32>, score| {
+        Some(max_score.map_or(score, |max_score| max_score.max(score)))
+    });
+    let timed_out = searches.iter().any(|search| search.timed_out);
+
+    let mut hits = Vec::new();
+    for search in searches.iter_mut() {
+        hits.extend(search.hits.drain(..));
+    }
+
+    if multiple_indices {
+        // The sort is stable, so hits that compare equal stay in the order of the indices
+        match searches.first().and_then(|search| search.sort.as_ref()) {
+            Some(sort) => {
+                hits.sort_by(|a, b| {
+                    let a_values = a.0.sort_values.as_ref().map_or(&[][..], |values| &values[..]);
+                    let b_values = b.0.sort_values.as_ref().map_or(&[][..], |values| &values[..]);
+                    compare_sort_values(sort, a_values, b_values)
+                });
+            }
+            None => hits.sort_by(|a, b| b.0.score.partial_cmp(&a.0.score).unwrap_or(Ordering::Equal)),
+        }
+
+        hits = hits.into_iter().skip(request.params.from).take(request.params.size).collect();
+    }
+
+    let mut response = json!({
+        "timed_out": timed_out,
+        "hits": {
+            "total": total_hits,
+            "max_score": max_score,
+            "hits": hits.into_iter().map(|(_, hit)| hit).collect::<Vec<_>>(),
+        }
+    });
+
+    // The total is returned as a plain number unless "track_total_hits" was given
+    if let Some(track_total_hits) = track_total_hits {
+        match track_total_hits.total_to_json(total_hits) {
+            Some(total) => response["hits"]["total"] = total,
+            None => {
+                response["hits"].as_object_mut().unwrap().remove("total");
+            }
+        }
+    }
+
+    // These are only set when searching a single index
+    if let Some(search) = searches.pop() {
+        if let Some(aggregations) = search.aggregations {
+            response["aggregations"] = aggregations;
+        }
+
+        if let Some(suggest) = search.suggest {
+            response["suggest"] = suggest;
+        }
+
+        if let Some(scroll_id) = search.scroll_id {
+            response["_scroll_id"] = json!(scroll_id);
+        }
+
+        if let Some(profile) = search.profile {
+            response["profile"] = profile;
+        }
+    }
+
+    // Only the hits are returned in the other formats
+    let format = request.params.format;
+    let body = match format {
+        ResponseFormat::Json => return Ok(json_response(StatusCode::OK, response)),
+        ResponseFormat::Csv => hits_to_csv(response["hits"]["hits"].as_array().unwrap()),
+        ResponseFormat::Ndjson => hits_to_ndjson(response["hits"]["hits"].as_array().unwrap()),
+    };
+
+    Ok(Response::with_body(StatusCode::OK, format.content_type(), body))
 }
 
 
-pub fn view_search(req: &mut Request) -> IronResult<Response> {
+pub fn view_search(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+
+    let query_json = match json_from_request_body!(req) {
+        Some(query_json) => query_json,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing query"}))),
+    };
+
+    run_search_request(system, req, index_name, query_json)
+}
+
+
+/// Renders a search template with its parameters and runs the search
+///
+/// The template is either given inline as "source" or is a stored script referred to by "id".
+pub fn view_search_template(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+
+    let body = match json_from_request_body!(req) {
+        Some(body) => body,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing template"}))),
+    };
+
+    let source = match (body.get("id"), body.get("source")) {
+        (Some(&Json::String(ref script_id)), None) => {
+            match system.scripts.get(script_id) {
+                Some(script) => script.source,
+                None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("Stored script {:?} not found", script_id)}))),
+            }
+        }
+        (None, Some(source)) => template_source_to_string(source),
+        _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Either id or source must be given"}))),
+    };
+
+    let params = body.get("params").cloned().unwrap_or_else(|| json!({}));
+    let mut query_json = match render_search_template(&source, &params) {
+        Ok(query_json) => query_json,
+        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Template error: {}", e)}))),
+    };
+
+    // These can be set alongside the template rather than in it
+    if query_json.is_object() {
+        for key in &["explain", "profile"] {
+            if let Some(value) = body.get(*key) {
+                query_json[*key] = value.clone();
+            }
+        }
+    }
+
+    run_search_request(system, req, index_name, query_json)
+}
+
+
+pub fn view_explain(req: &mut Request) -> ApiResult<Response> {
     let ref system = get_system!(req);
     let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
+    let ref doc_key = read_path_parameter!(req, "doc").unwrap_or("");
+    let permissions = get_permissions(req);
 
     // Get index
     let cluster_metadata = system.metadata.read().unwrap();
     let index = get_index_or_404!(cluster_metadata, *index_name);
-    let index_reader = index.store.reader();
+    drop(cluster_metadata);
     let index_metadata = index.metadata.read().unwrap();
 
-    match json_from_request_body!(req) {
+    if index_metadata.settings.blocks.blocks_read() {
+        return Ok(index_blocked_response(index.canonical_name(), "read"));
+    }
+
+    // Check that the mapping exists
+    if !index_metadata.mappings.contains_key(*mapping_name) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"})));
+    }
+
+    // Parse query
+    let query = match json_from_request_body!(req) {
         Some(query_json) => {
-            // Parse query
-            let query = parse_query(query_json.as_object().unwrap().get("query").unwrap());
-            //debug!("{:#?}", query);
-
-            match query {
-                Ok(query) => {
-                    let mut from = 0;
-                    let mut size = 10;
-                    let mut fields = Vec::new();
-
-                    // TODO: Rewrite this
-                    if let Some(ref url_query) = req.url.query() {
-                        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
-                            match key.as_ref() {
-                                "from" => {
-                                    from = value.as_ref().parse().expect("need a number");
-                                }
-                                "size" => {
-                                    size = value.as_ref().parse().expect("need a number");
-                                }
-                                "fields" => {
-                                    for field_name in value.split(",") {
-                                        let field_ref = match index_reader.schema().get_field_by_name(field_name) {
-                                            Some(field_ref) => field_ref,
-                                            None => {
-                                                warn!(system.log, "unknown field {:?}", field_name);
-                                                continue;
-                                            }
-                                        };
-
-                                        fields.push((field_name.to_owned(), field_ref));
-                                    }
-                                }
-                                // terminate_after
-                                // explain
-                                // version
-                                // timeout
-                                // fielddata_fields
-                                // track_scores
-                                // stats
-                                // suggest_field
-                                _ => warn!(system.log, "unrecognised GET parameter {:?}", key),
-                            }
-                        }
-                    }
+            match query_json.get("query").map(parse_query) {
+                Some(Ok(query)) => query,
+                Some(Err(_)) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Query error"}))),
+                None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing query"}))),
+            }
+        }
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing query"}))),
+    };
 
-                    // Do the search
-                    let mut collector = TopScoreCollector::new(from + size);
-                    index_reader.search(&mut collector, &query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata), &index_reader.schema())).unwrap();
-
-                    // Convert hits into JSON
-                    let mut hits = Vec::new();
-                    for doc_match in collector.into_sorted_vec().iter().skip(from) {
-                        let mut field_values = BTreeMap::new();
-
-                        for &(ref field_name, field_ref) in fields.iter() {
-                            let value = match index_reader.read_stored_field(field_ref, DocId::from_u64(doc_match.doc_id())) {
-                                Ok(Some(value)) => vec![value],
-                                Ok(None) => vec![],
-                                Err(_) => vec![],
-                            };
-
-                            field_values.insert(field_name.clone(), value);
-                        }
-
-                        hits.push(json!({
-                            "_score": doc_match.score().unwrap(),
-                            "fields": "FIXME",
-                        }));
-                    }
+    // Find document
+    let routing = get_routing(req);
+    let index_reader = index.shard_for_routing(routing.as_ref().map_or(*doc_key, |routing| &routing[..])).reader();
+    let doc_id = match index_reader.get_document_id_by_key(doc_key) {
+        Some(doc_id) => doc_id,
+        None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Document not found"}))),
+    };
+
+    let query = query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata), &index_reader.schema());
+    let query = apply_alias_filter(query, index_name, &index_metadata, &index_reader.schema());
+    let query = apply_role_filter(query, &permissions, index_name, index.canonical_name(), &index_metadata, &index_reader.schema());
+    let explanation = match index_reader.explain(&query, doc_id) {
+        Ok(explanation) => explanation,
+        Err(e) => {
+            error!(req.log, "explain failed"; "index" => index.canonical_name(), "error" => e);
+            return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Explain failed"})));
+        }
+    };
+
+    let mut response = json!({
+        "_index": index.canonical_name(),
+        "_type": mapping_name,
+        "_id": doc_key,
+        "matched": explanation.is_some(),
+    });
+
+    if let Some(explanation) = explanation {
+        response["explanation"] = json!(explanation);
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
+
+
+/// Reads the scroll id from the request body, or the "scroll_id" URL parameter
+fn read_scroll_ids(req: &mut Request, body: Option<&Json>) -> Vec<String> {
+    if let Some(scroll_id) = body.and_then(|body| body.get("scroll_id")) {
+        return match *scroll_id {
+            Json::String(ref scroll_id) => vec![scroll_id.clone()],
+            Json::Array(ref scroll_ids) => scroll_ids.iter().filter_map(|scroll_id| scroll_id.as_str().map(|s| s.to_string())).collect(),
+            _ => vec![],
+        };
+    }
+
+    let mut scroll_ids = Vec::new();
+    if let Some(ref url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "scroll_id" {
+                scroll_ids.extend(value.split(",").map(|scroll_id| scroll_id.to_string()));
+            }
+        }
+    }
+
+    scroll_ids
+}
 
-                    // TODO: {"took":5,"timed_out":false,"_shards":{"total":5,"successful":5,"failed":0},"hits":{"total":4,"max_score":1.0,"hits":[{"_index":"wagtail","_type":"searchtests_searchtest_searchtests_searchtestchild","_id":"searchtests_searchtest:5380","_score":1.0,"fields":{"pk":["5380"]}},{"_index":"wagtail","_type":"searchtests_searchtest","_id":"searchtests_searchtest:5379","_score":1.0,"fields":{"pk":["5379"]}}]}}
-                    Ok(json_response(status::Ok,
-                                     json!({
-                                         "hits": {
-                                             "total": hits.len(),
-                                             "hits": hits
-                                        }})))
+
+pub fn view_post_scroll(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let body = json_from_request_body!(req);
+
+    let scroll_id = match read_scroll_ids(req, body.as_ref()).into_iter().next() {
+        Some(scroll_id) => scroll_id,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing scroll_id"}))),
+    };
+
+    // The keep alive can optionally be extended
+    let keep_alive = match body.as_ref().and_then(|body| body.get("scroll")) {
+        Some(&Json::String(ref keep_alive)) => {
+            match parse_keep_alive(keep_alive) {
+                Some(keep_alive) => Some(keep_alive),
+                None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "scroll must be a time value, eg 1m"}))),
+            }
+        }
+        Some(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "scroll must be a time value, eg 1m"}))),
+        None => None,
+    };
+
+    if let Some(keep_alive) = keep_alive {
+        if let Err(response) = check_keep_alive(system, keep_alive) {
+            return Ok(response);
+        }
+    }
+
+    let not_found = || json_response(StatusCode::NOT_FOUND, json!({"message": "No search context found for scroll id"}));
+
+    // The lock on the scrolls isn't held while searching, so other scrolls can carry on
+    let scroll = system.scrolls.with_context(&scroll_id, |context| {
+        if let Some(keep_alive) = keep_alive {
+            context.keep_alive(keep_alive);
+        }
+
+        (context.search.clone(), context.last_hit.clone(), context.finished)
+    });
+    let (scroll_search, last_hit, finished) = match scroll {
+        Some(scroll) => scroll,
+        None => return Ok(not_found()),
+    };
+
+    let page = if finished {
+        Vec::new()
+    } else {
+        let index = {
+            let cluster_metadata = system.metadata.read().unwrap();
+            match cluster_metadata.indices.values().find(|index| *index.id() == scroll_search.index_id) {
+                Some(index) => index.clone(),
+                None => return Ok(not_found()),
+            }
+        };
+        let index_reader = match index.pinned_reader(&scroll_search.pins) {
+            Some(index_reader) => index_reader,
+            None => return Ok(not_found()),
+        };
+
+        let query = &scroll_search.query;
+        let size = scroll_search.size;
+        let cancellation = SearchCancellation::new();
+        let page = match scroll_search.sort {
+            Some(ref sort) => {
+                let read_doc_value = |field_ref, doc_id| {
+                    index_reader.read_stored_field(field_ref, doc_id).ok().and_then(|value| value)
+                };
+                let mut collector = TopFieldCollector::page(sort.clone(), 0, size, read_doc_value);
+                if let Some(ref last_hit) = last_hit {
+                    if let Some(ref sort_values) = last_hit.sort_values {
+                        collector = collector.search_after_doc(sort_values.clone(), last_hit.doc_id);
+                    }
                 }
-                Err(_) => {
-                    // TODO: What specifically is bad about the Query?
-                    let mut response = Response::with((status::BadRequest,
-                                                       "{\"message\": \"Query error\"}"));
-                    response.headers.set_raw("Content-Type", vec![b"application/json".to_vec()]);
-                    Ok(response)
+                let (collector, _, _) = run_search(&index_reader, query, collector, "TopFieldCollector", scroll_search.min_score, false, &cancellation);
+
+                collector.into_page().into_iter().map(|doc| ScrollHit {
+                    doc_id: doc.id,
+                    score: doc.score,
+                    sort_values: Some(doc.sort_values),
+                }).collect::<Vec<_>>()
+            }
+            None => {
+                let mut collector = TopScoreCollector::page(0, size);
+                if let Some(ref last_hit) = last_hit {
+                    collector = collector.search_after(last_hit.score.unwrap_or(0.0), last_hit.doc_id);
                 }
+                let (collector, _, _) = run_search(&index_reader, query, collector, "TopScoreCollector", scroll_search.min_score, false, &cancellation);
+
+                collector.into_page().iter().map(|doc_match| ScrollHit {
+                    doc_id: doc_match.doc_id(),
+                    score: doc_match.score(),
+                    sort_values: None,
+                }).collect::<Vec<_>>()
             }
+        };
+
+        // The scroll may have been cleared while searching, in which case there's nothing to update
+        system.scrolls.with_context(&scroll_id, |context| context.returned_page(&page));
+        page
+    };
+
+    let hits = page.iter().map(hit_to_json).collect::<Vec<_>>();
+
+    Ok(json_response(StatusCode::OK, json!({
+        "_scroll_id": scroll_id,
+        "hits": {
+            "total": scroll_search.total_hits,
+            "max_score": scroll_search.max_score,
+            "hits": hits,
         }
-        None => Ok(json_response(status::BadRequest, json!({"message": "Missing query"}))),
+    })))
+}
+
+
+pub fn view_delete_scroll(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let body = json_from_request_body!(req);
+    let scroll_ids = read_scroll_ids(req, body.as_ref());
+
+    let contexts = if scroll_ids.iter().any(|scroll_id| scroll_id == "_all") {
+        system.scrolls.remove_all()
+    } else {
+        scroll_ids.iter().filter_map(|scroll_id| system.scrolls.remove(scroll_id)).collect()
+    };
+
+    let num_freed = contexts.len();
+    for context in contexts {
+        system.release_scroll(context);
+    }
+
+    let status = if num_freed > 0 || scroll_ids.is_empty() { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    Ok(json_response(status, json!({"succeeded": true, "num_freed": num_freed})))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use response_format::ResponseFormat;
+
+    use super::{SearchParams, check_multiple_indices_search};
+
+    #[test]
+    fn test_check_multiple_indices_search() {
+        let mut params = SearchParams {
+            from: 0,
+            size: 10,
+            field_names: Vec::new(),
+            scroll: None,
+            timeout: None,
+            format: ResponseFormat::Json,
+        };
+
+        assert_eq!(check_multiple_indices_search(&json!({"query": {"match_all": {}}, "profile": false}), &params), None);
+
+        let error = |query_json, params: &SearchParams| check_multiple_indices_search(&query_json, params).unwrap();
+        assert_eq!(error(json!({"aggs": {}}), &params), "Aggregations can only be used when searching a single index");
+        assert_eq!(error(json!({"aggregations": {}}), &params), "Aggregations can only be used when searching a single index");
+        assert_eq!(error(json!({"suggest": {}}), &params), "Suggesters can only be used when searching a single index");
+        assert_eq!(error(json!({"profile": true}), &params), "Profiling can only be used when searching a single index");
+
+        params.scroll = Some(Duration::from_secs(60));
+        assert_eq!(error(json!({}), &params), "Scrolls can only be used when searching a single index");
     }
 }
@@ -0,0 +1,475 @@
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use serde_json::Value as Json;
+
+use system::System;
+use security::{Credentials, Principal, authenticate};
+use security::roles::{Role, Permissions, ClusterPrivilege, IndexPrivilege, SUPERUSER_ROLE};
+use security::authorization::{RequiredPrivilege, required_privilege};
+use lifecycle::parse_time_value;
+use audit::{AuditEvent, AuditEventType};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, forbidden_response};
+use api::cluster_api::FORWARDED_HEADER;
+
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0)
+}
+
+
+/// Refuses requests that don't have valid credentials, and records who made the others
+pub fn authenticate_request(req: &mut Request) -> ApiResult<()> {
+    let system = req.system.clone();
+    let credentials = match req.headers.get("Authorization") {
+        Some(value) => {
+            match value.to_str().ok().and_then(Credentials::parse) {
+                Some(credentials) => Some(credentials),
+                None => {
+                    let response = unauthorized("Unsupported or malformed Authorization header");
+                    record_audit_event(req, AuditEventType::AuthenticationFailed, "_unknown".to_string(), "unknown", response.status);
+                    return Err(response);
+                }
+            }
+        }
+        None => None,
+    };
+
+    match authenticate(&system.settings, &system.api_keys, credentials.as_ref(), now_millis()) {
+        Some(principal) => {
+            req.principal = principal;
+            Ok(())
+        }
+        None => {
+            let (username, authentication_type) = match credentials {
+                Some(Credentials::Basic { ref username, .. }) => (username.clone(), "realm"),
+                Some(Credentials::ApiKey { ref id, .. }) => (id.clone(), "api_key"),
+                None => ("_anonymous".to_string(), "anonymous"),
+            };
+            warn!(req.log, "authentication failed"; "user" => username.clone(), "path" => req.uri.path().to_string());
+
+            let response = unauthorized("Missing or invalid credentials");
+            record_audit_event(req, AuditEventType::AuthenticationFailed, username, authentication_type, response.status);
+            Err(response)
+        }
+    }
+}
+
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = json_response(StatusCode::UNAUTHORIZED, json!({"message": message}));
+    response.append_header("WWW-Authenticate", "Basic realm=\"security\" charset=\"UTF-8\"");
+    response.append_header("WWW-Authenticate", "ApiKey");
+    response
+}
+
+
+/// Refuses requests that the roles of their principal don't allow
+///
+/// The APIs that read index names from the request body check them with the permissions
+/// this leaves on the request.
+pub fn authorize_request(req: &mut Request) -> ApiResult<()> {
+    let system = req.system.clone();
+    let permissions = system.roles.permissions(&system.settings, &req.principal);
+
+    let missing = match required_privilege(req.method.as_str(), &req.path()) {
+        RequiredPrivilege::Cluster(privilege) if !permissions.allows_cluster(privilege) => {
+            Some(missing_cluster_privilege_message(privilege))
+        }
+        RequiredPrivilege::Index(ref index_name, privilege) if !permissions.allows_index(index_name, privilege) => {
+            Some(missing_index_privilege_message(index_name, privilege))
+        }
+        _ => None,
+    };
+
+    if let Some(message) = missing {
+        warn!(req.log, "request not authorized"; "user" => req.principal.name(), "method" => req.method.to_string(), "path" => req.uri.path().to_string());
+
+        let response = forbidden_response(message);
+        audit_request(req, AuditEventType::AccessDenied, response.status);
+        return Err(response);
+    }
+
+    req.permissions = permissions;
+    Ok(())
+}
+
+
+/// Records an event in the audit log, if auditing is enabled
+fn record_audit_event(req: &Request, event_type: AuditEventType, user: String, authentication_type: &str, status: StatusCode) {
+    let audit_log = match req.system.audit_log {
+        Some(ref audit_log) => audit_log,
+        None => return,
+    };
+
+    let event = AuditEvent {
+        event_type: event_type,
+        user: user,
+        authentication_type: authentication_type.to_string(),
+        source_ip: req.remote_addr.ip(),
+        method: req.method.to_string(),
+        path: req.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/").to_string(),
+        opaque_id: req.opaque_id.clone(),
+        index: req.params.get("index").cloned(),
+        forwarded_by: req.header(FORWARDED_HEADER).map(|node_id| node_id.to_string()),
+        status: status.as_u16(),
+    };
+
+    if let Err(e) = audit_log.record(&event) {
+        error!(req.log, "failed to write to audit log"; "error" => format!("{}", e));
+    }
+}
+
+
+/// Records a request made by its principal in the audit log, if auditing is enabled
+pub fn audit_request(req: &Request, event_type: AuditEventType, status: StatusCode) {
+    record_audit_event(req, event_type, req.principal.name().to_string(), req.principal.authentication_type(), status);
+}
+
+
+pub fn missing_cluster_privilege_message(privilege: ClusterPrivilege) -> String {
+    format!("The {} cluster privilege is required", privilege.name())
+}
+
+
+pub fn missing_index_privilege_message(index_name: &str, privilege: IndexPrivilege) -> String {
+    format!("The {} privilege is required on [{}]", privilege.name(), index_name)
+}
+
+
+/// Why an update was refused on an index that the request's roles only let some documents of
+/// be read
+///
+/// Updates read the document they change and can return it, so they'd get around the queries.
+pub fn document_queries_update_message(index_name: &str) -> String {
+    format!("Documents in [{}] can't be updated, as the roles only let some of its documents be read", index_name)
+}
+
+
+/// The permissions of whoever made the request. Nothing is allowed if they weren't checked
+pub fn get_permissions(req: &Request) -> Permissions {
+    req.permissions.clone()
+}
+
+
+/// Shows who the request was authenticated as
+pub fn view_get_authenticate(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let principal = req.principal.clone();
+
+    Ok(json_response(StatusCode::OK, json!({
+        "username": principal.name(),
+        "authentication_type": principal.authentication_type(),
+        "roles": system.roles.principal_roles(&system.settings, &principal),
+    })))
+}
+
+
+/// Reads the "roles" of a request body, which must all exist
+fn parse_role_names(system: &System, json: &Json) -> Result<Vec<String>, Response> {
+    let roles = match json.get("roles").and_then(|roles| roles.as_array()) {
+        Some(roles) => roles,
+        None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "roles must be an array of strings"}))),
+    };
+
+    let mut names = Vec::with_capacity(roles.len());
+    for role in roles.iter() {
+        match role.as_str() {
+            Some(name) if system.roles.get_role(name).is_some() => names.push(name.to_string()),
+            Some(name) => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Role not found: {}", name)}))),
+            None => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": "roles must be an array of strings"}))),
+        }
+    }
+
+    Ok(names)
+}
+
+
+/// Creates an API key. The key's secret is only ever returned by this request
+///
+/// The key has the "roles" given in the body, which needs the manage_security privilege, or
+/// else the roles of whoever created it.
+pub fn view_put_api_key(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let principal = req.principal.clone();
+    let permissions = get_permissions(req);
+
+    let (name, expiration, roles) = match json_from_request_body!(req) {
+        Some(json) => {
+            let name = match json.get("name").and_then(|name| name.as_str()) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "name must be a non-empty string"}))),
+            };
+
+            let expiration = match json.get("expiration") {
+                Some(&Json::String(ref expiration)) => {
+                    match parse_time_value(expiration) {
+                        Some(expiration) => Some(expiration),
+                        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Invalid expiration: {:?}", expiration)}))),
+                    }
+                }
+                Some(&Json::Null) | None => None,
+                Some(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "expiration must be a time value such as \"1d\""}))),
+            };
+
+            let roles = if json.get("roles").is_some() {
+                if !permissions.allows_cluster(ClusterPrivilege::ManageSecurity) {
+                    return Ok(forbidden_response(missing_cluster_privilege_message(ClusterPrivilege::ManageSecurity)));
+                }
+
+                match parse_role_names(system, &json) {
+                    Ok(roles) => roles,
+                    Err(response) => return Ok(response),
+                }
+            } else {
+                system.roles.principal_roles(&system.settings, &principal)
+            };
+
+            (name, expiration, roles)
+        }
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing name"}))),
+    };
+
+    let now = now_millis();
+    let expiration = expiration.map(|expiration| now + expiration.as_secs() * 1000 + expiration.subsec_nanos() as u64 / 1_000_000);
+    match system.api_keys.create(system.get_api_keys_path(), name, now, expiration) {
+        Ok(api_key) => {
+            if let Err(e) = system.roles.assign_api_key_roles(system.get_roles_path(), &api_key.id, roles) {
+                error!(req.log, "failed to assign roles to api key"; "id" => &api_key.id, "error" => e);
+
+                // Without its roles, the key would be a superuser
+                let _ = system.api_keys.invalidate(system.get_api_keys_path(), |key| key.id == api_key.id);
+                return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to create api key"})));
+            }
+
+            info!(req.log, "created api key"; "id" => &api_key.id, "name" => &api_key.name);
+
+            let mut response = json!({
+                "id": api_key.id,
+                "name": api_key.name,
+                "api_key": api_key.key,
+                "encoded": api_key.encoded(),
+            });
+
+            if let Some(expiration) = api_key.expiration {
+                response["expiration"] = json!(expiration);
+            }
+
+            Ok(json_response(StatusCode::OK, response))
+        }
+        Err(e) => {
+            error!(req.log, "failed to create api key"; "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to create api key"})))
+        }
+    }
+}
+
+
+/// Invalidates API keys by id or name. Keys from the config file can't be invalidated
+pub fn view_delete_api_key(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    let (ids, name) = match json_from_request_body!(req) {
+        Some(json) => {
+            let ids = match json.get("ids") {
+                Some(&Json::Array(ref ids)) => {
+                    match ids.iter().map(|id| id.as_str().map(|id| id.to_string())).collect::<Option<Vec<_>>>() {
+                        Some(ids) => Some(ids),
+                        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "ids must be an array of strings"}))),
+                    }
+                }
+                Some(&Json::String(ref id)) => Some(vec![id.clone()]),
+                None => None,
+                Some(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "ids must be an array of strings"}))),
+            };
+            let name = json.get("name").and_then(|name| name.as_str()).map(|name| name.to_string());
+
+            (ids, name)
+        }
+        None => (None, None),
+    };
+
+    if ids.is_none() && name.is_none() {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "One of ids or name must be given"})));
+    }
+
+    let result = system.api_keys.invalidate(system.get_api_keys_path(), |api_key| {
+        ids.as_ref().map_or(true, |ids| ids.contains(&api_key.id)) && name.as_ref().map_or(true, |name| *name == api_key.name)
+    });
+
+    match result {
+        Ok(invalidated) => {
+            info!(req.log, "invalidated api keys"; "ids" => invalidated.join(","));
+
+            if let Err(e) = system.roles.remove_api_keys(system.get_roles_path(), &invalidated) {
+                warn!(req.log, "failed to remove the roles of invalidated api keys"; "error" => e);
+            }
+
+            Ok(json_response(StatusCode::OK, json!({
+                "invalidated_api_keys": invalidated,
+                "previously_invalidated_api_keys": [],
+                "error_count": 0,
+            })))
+        }
+        Err(e) => {
+            error!(req.log, "failed to invalidate api keys"; "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to invalidate api keys"})))
+        }
+    }
+}
+
+
+/// Sets the roles of an API key, by its id. Keys from the config file are identified by name
+pub fn view_put_api_key_roles(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref id = read_path_parameter!(req, "id").unwrap_or("").to_string();
+
+    if !system.settings.api_keys.contains_key(id) && !system.api_keys.contains(id) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("API key not found: {}", id)})));
+    }
+
+    let roles = match json_from_request_body!(req).map(|json| parse_role_names(system, &json)) {
+        Some(Ok(roles)) => roles,
+        Some(Err(response)) => return Ok(response),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing roles"}))),
+    };
+
+    match system.roles.assign_api_key_roles(system.get_roles_path(), id, roles) {
+        Ok(()) => {
+            info!(req.log, "assigned roles to api key"; "id" => id);
+            Ok(json_response(StatusCode::OK, json!({"updated": true})))
+        }
+        Err(e) => {
+            error!(req.log, "failed to assign roles to api key"; "id" => id, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to assign roles"})))
+        }
+    }
+}
+
+
+/// Returns all roles, or the one named in the URL
+pub fn view_get_role(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let role_name = read_path_parameter!(req, "name");
+
+    let mut response = json!({});
+    match role_name {
+        Some(role_name) => {
+            match system.roles.get_role(role_name) {
+                Some(role) => response[role_name] = role.to_json(),
+                None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("Role not found: {}", role_name)}))),
+            }
+        }
+        None => {
+            for (role_name, role) in system.roles.roles() {
+                response[role_name] = role.to_json();
+            }
+        }
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
+
+
+pub fn view_put_role(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref role_name = read_path_parameter!(req, "name").unwrap_or("").to_string();
+
+    if role_name == SUPERUSER_ROLE {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Role {} is reserved and can't be changed", role_name)})));
+    }
+
+    let role = match json_from_request_body!(req).map(|json| Role::from_json(&json)) {
+        Some(Ok(role)) => role,
+        Some(Err(message)) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": message}))),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing role"}))),
+    };
+
+    match system.roles.put_role(system.get_roles_path(), role_name, role) {
+        Ok(created) => {
+            info!(req.log, "saved role"; "name" => role_name);
+            Ok(json_response(StatusCode::OK, json!({"role": {"created": created}})))
+        }
+        Err(e) => {
+            error!(req.log, "failed to save role"; "name" => role_name, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to save role"})))
+        }
+    }
+}
+
+
+pub fn view_delete_role(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref role_name = read_path_parameter!(req, "name").unwrap_or("").to_string();
+
+    if role_name == SUPERUSER_ROLE {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Role {} is reserved and can't be deleted", role_name)})));
+    }
+
+    match system.roles.delete_role(system.get_roles_path(), role_name) {
+        Ok(true) => {
+            info!(req.log, "deleted role"; "name" => role_name);
+            Ok(json_response(StatusCode::OK, json!({"found": true})))
+        }
+        Ok(false) => Ok(json_response(StatusCode::NOT_FOUND, json!({"found": false}))),
+        Err(e) => {
+            error!(req.log, "failed to delete role"; "name" => role_name, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to delete role"})))
+        }
+    }
+}
+
+
+/// Returns the roles of all users in the config file, or of the one named in the URL
+pub fn view_get_user(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let username = read_path_parameter!(req, "username");
+
+    let usernames = match username {
+        Some(username) if system.settings.users.contains_key(username) => vec![username],
+        Some(username) => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("User not found: {}", username)}))),
+        None => system.settings.users.keys().map(|username| username.as_str()).collect(),
+    };
+
+    let mut response = json!({});
+    for username in usernames {
+        response[username] = json!({
+            "username": username,
+            "roles": system.roles.principal_roles(&system.settings, &Principal::User(username.to_string())),
+        });
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
+
+
+/// Sets the roles of a user. Users themselves can only be added in the config file
+pub fn view_put_user(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref username = read_path_parameter!(req, "username").unwrap_or("").to_string();
+
+    if !system.settings.users.contains_key(username) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": format!("User not found: {}", username)})));
+    }
+
+    let roles = match json_from_request_body!(req).map(|json| parse_role_names(system, &json)) {
+        Some(Ok(roles)) => roles,
+        Some(Err(response)) => return Ok(response),
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing roles"}))),
+    };
+
+    match system.roles.assign_user_roles(system.get_roles_path(), username, roles) {
+        Ok(()) => {
+            info!(req.log, "assigned roles to user"; "user" => username);
+            Ok(json_response(StatusCode::OK, json!({"updated": true})))
+        }
+        Err(e) => {
+            error!(req.log, "failed to assign roles to user"; "user" => username, "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to assign roles"})))
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Serves the API over HTTP/1.1 and HTTP/2
+//!
+//! Connections are handled by hyper on a tokio runtime, so connections that are kept alive
+//! between requests cost little. HTTP/2 is used by clients that start with it (prior knowledge).
+
+use std::convert::Infallible;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::{self, BoxFuture};
+use futures::channel::oneshot;
+use hyper::{self, Body, Server};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use slog::Logger;
+use tokio::runtime;
+
+use api::request::Response;
+
+
+/// Handles the requests made to the server
+///
+/// This is called on the server's runtime, so it mustn't block. Work that does should be
+/// given to a `ThreadPool`. `remote_addr` is the address of the client's end of the connection.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, req: hyper::Request<Body>, remote_addr: SocketAddr) -> BoxFuture<'static, Response>;
+}
+
+
+/// A running server
+pub struct ApiServer {
+    shutdown: oneshot::Sender<()>,
+    stopped: mpsc::Receiver<()>,
+}
+
+
+impl ApiServer {
+    /// Stops accepting connections and waits for the open ones to finish their requests
+    ///
+    /// Returns false if some were still open after `timeout`.
+    pub fn stop(self, timeout: Duration) -> bool {
+        let _ = self.shutdown.send(());
+        self.stopped.recv_timeout(timeout).is_ok()
+    }
+}
+
+
+/// Starts serving requests from a listener in the background
+pub fn serve<H: Handler>(listener: TcpListener, handler: H, log: Logger) -> io::Result<ApiServer> {
+    let runtime = runtime::Builder::new_multi_thread().thread_name("api-server").enable_all().build()?;
+
+    listener.set_nonblocking(true)?;
+    let builder = {
+        // The listener is registered with the runtime it's created in
+        let _runtime = runtime.enter();
+        Server::from_tcp(listener).map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+    };
+
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let handler = handler.clone();
+        let remote_addr = conn.remote_addr();
+
+        future::ok::<_, Infallible>(service_fn(move |req| {
+            handler.handle(req, remote_addr).map(|response| Ok::<_, Infallible>(response.into_hyper()))
+        }))
+    });
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let server = builder.serve(make_service).with_graceful_shutdown(shutdown_receiver.map(|_| ()));
+
+    let (stopped_sender, stopped_receiver) = mpsc::channel();
+    thread::Builder::new().name("api-server".to_string()).spawn(move || {
+        if let Err(error) = runtime.block_on(server) {
+            crit!(log, "api server failed"; "error" => format!("{}", error));
+        }
+
+        let _ = stopped_sender.send(());
+    })?;
+
+    Ok(ApiServer {
+        shutdown: shutdown_sender,
+        stopped: stopped_receiver,
+    })
+}
@@ -0,0 +1,161 @@
+use std::io::Read;
+use std::collections::BTreeMap;
+
+use serde_json;
+use url::form_urlencoded;
+
+use index::metadata::parse::index_settings::parse as parse_index_settings;
+use cluster::settings::{flatten, nest};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, index_blocked_response};
+
+
+pub fn view_get_settings(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+    let index_metadata = index.metadata.read().unwrap();
+
+    return Ok(json_response(StatusCode::OK, json!({
+        index.canonical_name(): {
+            "settings": {
+                "index": index_metadata.settings,
+            }
+        }
+    })));
+}
+
+
+pub fn view_put_settings(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Get index. Only its own metadata is locked for writing
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+
+    // Load data from body
+    let data = match json_from_request_body!(req) {
+        Some(data) => data,
+        None => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body is required"})));
+        }
+    };
+
+    // The settings may optionally be wrapped in a "settings" object
+    let data = match data.get("settings") {
+        Some(settings) => settings.clone(),
+        None => data,
+    };
+
+    // Apply the settings. Only dynamic settings may be changed once an index has been created
+    let mut index_metadata = index.metadata.write().unwrap();
+    let mut settings = index_metadata.settings.clone();
+    if let Err(e) = parse_index_settings(&mut settings, &data, true) {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({
+            "message": format!("Couldn't parse index settings: {:?}", e)
+        })));
+    }
+
+    // When the metadata is blocked, only the blocks themselves can be changed (so they can be lifted)
+    if index_metadata.settings.blocks.blocks_metadata_write() {
+        let mut settings_without_blocks = settings.clone();
+        settings_without_blocks.blocks = index_metadata.settings.blocks.clone();
+
+        if settings_without_blocks != index_metadata.settings {
+            return Ok(index_blocked_response(index.canonical_name(), "metadata write"));
+        }
+    }
+
+    index_metadata.settings = settings;
+    index_metadata.save(index.metadata_path()).unwrap();
+    index.apply_settings(&index_metadata.settings);
+
+    info!(req.log, "updated index settings"; "index" => *index_name);
+
+    return Ok(json_response(StatusCode::OK, json!({"acknowledged": true})));
+}
+
+
+/// Whether a boolean URL parameter is set. A parameter without a value counts as true
+fn flag_parameter(req: &Request, name: &str) -> bool {
+    req.uri.query().map_or(false, |url_query| {
+        form_urlencoded::parse(url_query.as_bytes()).any(|(key, value)| key == name && (value == "" || value == "true"))
+    })
+}
+
+
+pub fn view_get_cluster_settings(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let flat_settings = flag_parameter(req, "flat_settings");
+    let show = |settings: &BTreeMap<String, serde_json::Value>| {
+        if flat_settings {
+            serde_json::to_value(settings).unwrap()
+        } else {
+            nest(settings)
+        }
+    };
+
+    let state = system.cluster_settings.state();
+    let mut response = json!({
+        "persistent": show(&state.persistent),
+        "transient": show(&state.transient),
+    });
+
+    if flag_parameter(req, "include_defaults") {
+        response["defaults"] = show(&system.cluster_settings.defaults().to_flat_json());
+    }
+
+    Ok(json_response(StatusCode::OK, response))
+}
+
+
+pub fn view_put_cluster_settings(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    // Load data from body
+    let data = match json_from_request_body!(req) {
+        Some(data) => data,
+        None => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body is required"})));
+        }
+    };
+
+    let mut persistent = BTreeMap::new();
+    let mut transient = BTreeMap::new();
+    match data.as_object() {
+        Some(data) => {
+            for (key, value) in data.iter() {
+                match key.as_ref() {
+                    "persistent" => flatten("", value, &mut persistent),
+                    "transient" => flatten("", value, &mut transient),
+                    _ => {
+                        return Ok(json_response(StatusCode::BAD_REQUEST, json!({
+                            "message": format!("Unrecognised key in cluster settings: {:?}", key)
+                        })));
+                    }
+                }
+            }
+        }
+        None => {
+            return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Request body must be an object"})));
+        }
+    }
+
+    if let Err(e) = system.update_cluster_settings(&persistent, &transient) {
+        return Ok(json_response(StatusCode::BAD_REQUEST, json!({
+            "message": format!("Couldn't update cluster settings: {}", e)
+        })));
+    }
+
+    Ok(json_response(StatusCode::OK, json!({
+        "acknowledged": true,
+        "persistent": nest(&persistent),
+        "transient": nest(&transient),
+    })))
+}
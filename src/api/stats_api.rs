@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value as Json;
+use slog::Logger;
+use chrono::Utc;
+use search::backends::rocksdb::StoreStatistics;
+use cluster::metadata::{ClusterMetadata, IndexRef};
+use process_stats::process_statistics;
+use tasks::NODE_ID;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, get_indices_options};
+
+
+fn get_store_statistics_or_500(log: &Logger, index_name: &str, result: Result<Vec<StoreStatistics>, String>) -> Result<Vec<StoreStatistics>, Response> {
+    result.map_err(|e| {
+        error!(log, "failed to read index statistics"; "index" => index_name, "error" => e);
+
+        json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+            "message": "unable to read index statistics"
+        }))
+    })
+}
+
+
+pub fn view_get_segments(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+
+    let shard_stats = match get_store_statistics_or_500(&req.log, index_name, index.get_store_statistics()) {
+        Ok(stats) => stats,
+        Err(response) => return Ok(response),
+    };
+
+    let shards_json = shard_stats.iter().enumerate().map(|(shard, stats)| {
+        (shard.to_string(), json!([shard_segments_json(stats)]))
+    }).collect::<BTreeMap<_, _>>();
+
+    return Ok(json_response(StatusCode::OK, json!({
+        "_shards": {
+            "total": shard_stats.len(),
+            "successful": shard_stats.len(),
+            "failed": 0,
+        },
+        "indices": {
+            index.canonical_name(): {
+                "shards": shards_json,
+            }
+        }
+    })));
+}
+
+
+/// Lists the segments of one shard
+fn shard_segments_json(stats: &StoreStatistics) -> Json {
+    let mut segments_json = BTreeMap::new();
+
+    for &(segment, ref segment_stats) in stats.segments.iter() {
+        segments_json.insert(format!("_{}", segment), json!({
+            "generation": segment,
+            "num_docs": segment_stats.total_docs() - segment_stats.deleted_docs(),
+            "deleted_docs": segment_stats.deleted_docs(),
+            "size_in_bytes": stats.segment_sizes.get(&segment).cloned().unwrap_or(0),
+            "committed": true,
+            "search": true,
+        }));
+    }
+
+    // Segments that will become searchable on the next refresh
+    for &segment in stats.pending_segments.iter() {
+        segments_json.insert(format!("_{}", segment), json!({
+            "generation": segment,
+            "size_in_bytes": stats.segment_sizes.get(&segment).cloned().unwrap_or(0),
+            "committed": true,
+            "search": false,
+        }));
+    }
+
+    json!({
+        "routing": {
+            "state": "STARTED",
+            "primary": true,
+        },
+        "num_committed_segments": stats.segments.len() + stats.pending_segments.len(),
+        "num_search_segments": stats.segments.len(),
+        "segments": segments_json,
+    })
+}
+
+
+/// Converts a store's statistics into the per-index section of a stats response
+fn index_stats_json(stats: &StoreStatistics) -> Json {
+    let total_docs: i64 = stats.segments.iter().map(|&(_, ref s)| s.total_docs()).sum();
+    let deleted_docs: i64 = stats.segments.iter().map(|&(_, ref s)| s.deleted_docs()).sum();
+    let size_in_bytes: u64 = stats.segment_sizes.values().sum();
+    let activity = &stats.activity;
+
+    json!({
+        "docs": {
+            "count": total_docs - deleted_docs,
+            "deleted": deleted_docs,
+        },
+        "store": {
+            "size_in_bytes": size_in_bytes,
+        },
+        "indexing": {
+            "index_total": activity.index.total,
+            "index_time_in_millis": activity.index.time_in_millis,
+            "index_current": activity.index.current,
+            "index_failed": activity.index.failed,
+            "delete_total": activity.delete.total,
+            "delete_time_in_millis": activity.delete.time_in_millis,
+            "delete_current": activity.delete.current,
+        },
+        "search": {
+            "query_total": activity.query.total,
+            "query_time_in_millis": activity.query.time_in_millis,
+            "query_current": activity.query.current,
+        },
+        "bulk": {
+            "total_operations": activity.bulk.total_operations,
+            "total_time_in_millis": activity.bulk.total_time_in_millis,
+            "total_size_in_bytes": activity.bulk.total_size_in_bytes,
+        },
+        "segments": {
+            "count": stats.segments.len(),
+            "memory_in_bytes": stats.memory_in_bytes,
+        },
+        "merges": {
+            "current": stats.merges.current,
+            "total": stats.merges.total,
+            "total_docs": stats.merges.total_docs,
+            "total_time_in_millis": stats.merges.total_time_in_millis,
+        },
+        "query_cache": {
+            "memory_size_in_bytes": stats.filter_cache.memory_size_in_bytes,
+            "total_count": stats.filter_cache.hit_count + stats.filter_cache.miss_count,
+            "hit_count": stats.filter_cache.hit_count,
+            "miss_count": stats.filter_cache.miss_count,
+            "cache_size": stats.filter_cache.cache_size,
+            "cache_count": stats.filter_cache.cache_count,
+            "evictions": stats.filter_cache.evictions,
+        },
+        "request_cache": {
+            "memory_size_in_bytes": stats.request_cache.memory_size_in_bytes,
+            "evictions": stats.request_cache.evictions,
+            "hit_count": stats.request_cache.hit_count,
+            "miss_count": stats.request_cache.miss_count,
+        },
+    })
+}
+
+
+/// Adds the numbers in one stats section to another with the same layout
+fn add_stats(total: &mut Json, stats: &Json) {
+    match (total, stats) {
+        (&mut Json::Object(ref mut total), &Json::Object(ref stats)) => {
+            for (key, value) in stats.iter() {
+                match total.get_mut(key) {
+                    Some(total_value) => add_stats(total_value, value),
+                    None => {
+                        total.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (total, &Json::Number(ref stats)) => {
+            let sum = match (total.as_u64(), stats.as_u64()) {
+                (Some(a), Some(b)) => json!(a + b),
+                _ => json!(total.as_f64().unwrap_or(0.0) + stats.as_f64().unwrap_or(0.0)),
+            };
+            *total = sum;
+        }
+        _ => {}
+    }
+}
+
+
+/// Returns the stats of each index, and the stats of all of them added together
+///
+/// Also returns the number of shards that the stats were read from
+fn get_indices_stats(log: &Logger, cluster_metadata: &ClusterMetadata, indices: &[IndexRef]) -> Result<(Json, BTreeMap<String, Json>, usize), Response> {
+    let mut all_stats = json!({});
+    let mut indices_stats = BTreeMap::new();
+    let mut num_shards = 0;
+
+    for index_ref in indices {
+        let index = &cluster_metadata.indices[index_ref];
+        let shard_stats = get_store_statistics_or_500(log, index.canonical_name(), index.get_store_statistics())?;
+        let mut index_stats = json!({});
+
+        for stats in shard_stats.iter() {
+            add_stats(&mut index_stats, &index_stats_json(stats));
+        }
+
+        num_shards += shard_stats.len();
+        add_stats(&mut all_stats, &index_stats);
+        indices_stats.insert(index.canonical_name().to_string(), index_stats);
+    }
+
+    Ok((all_stats, indices_stats, num_shards))
+}
+
+
+pub fn view_get_stats(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices.into_iter().map(|(index_ref, _)| index_ref).collect::<Vec<_>>(),
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    let (all_stats, indices_stats, num_shards) = match get_indices_stats(&req.log, &cluster_metadata, &indices) {
+        Ok(stats) => stats,
+        Err(response) => return Ok(response),
+    };
+
+    // Only this node's copy is counted, so the primaries are the total
+    let indices_json = indices_stats.into_iter().map(|(name, index_stats)| {
+        (name, json!({
+            "primaries": index_stats,
+            "total": index_stats,
+        }))
+    }).collect::<BTreeMap<_, _>>();
+
+    return Ok(json_response(StatusCode::OK, json!({
+        "_shards": {
+            "total": num_shards,
+            "successful": num_shards,
+            "failed": 0,
+        },
+        "_all": {
+            "primaries": all_stats,
+            "total": all_stats,
+        },
+        "indices": indices_json,
+    })));
+}
+
+
+pub fn view_get_nodes_stats(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices = cluster_metadata.indices.keys().cloned().collect::<Vec<_>>();
+
+    let (all_stats, _, _) = match get_indices_stats(&req.log, &cluster_metadata, &indices) {
+        Ok(stats) => stats,
+        Err(response) => return Ok(response),
+    };
+
+    let mut node_stats = json!({
+        "name": NODE_ID,
+        "timestamp": Utc::now().timestamp() * 1000,
+        "indices": all_stats,
+        "breakers": {
+            "in_flight_requests": {
+                "limit_size_in_bytes": system.request_breaker.limit().map_or(-1, |limit| limit as i64),
+                "estimated_size_in_bytes": system.request_breaker.used(),
+                "tripped": system.request_breaker.tripped(),
+            },
+        },
+        "thread_pool": {},
+    });
+
+    for thread_pool in system.thread_pools.all().iter() {
+        let stats = thread_pool.stats();
+        node_stats["thread_pool"][thread_pool.name()] = json!({
+            "threads": stats.threads,
+            "queue": stats.queue,
+            "active": stats.active,
+            "rejected": stats.rejected,
+            "largest": stats.largest,
+            "completed": stats.completed,
+        });
+    }
+
+    match process_statistics() {
+        Ok(Some(process)) => {
+            node_stats["process"] = json!({
+                "open_file_descriptors": process.open_file_descriptors,
+                "threads": process.threads,
+                "cpu": {
+                    "total_in_millis": process.cpu_time_in_millis,
+                },
+                "mem": {
+                    "resident_in_bytes": process.resident_in_bytes,
+                    "total_virtual_in_bytes": process.virtual_in_bytes,
+                },
+            });
+        }
+        Ok(None) => {}
+        Err(e) => {
+            warn!(req.log, "failed to read process statistics"; "error" => format!("{}", e));
+        }
+    }
+
+    return Ok(json_response(StatusCode::OK, json!({
+        "_nodes": {
+            "total": 1,
+            "successful": 1,
+            "failed": 0,
+        },
+        "cluster_name": system.settings.cluster_name,
+        "nodes": {
+            NODE_ID: node_stats,
+        }
+    })));
+}
@@ -0,0 +1,151 @@
+use serde_json;
+use serde_json::Value as Json;
+use url::form_urlencoded;
+
+use search::profile::duration_to_nanos;
+use source_filter::wildcard_match;
+use tasks::{Task, CompletedTask, NODE_ID, format_task_id, parse_task_id};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::json_response;
+
+
+fn task_to_json(id: u64, task: &Task) -> Json {
+    let mut task_json = json!({
+        "node": NODE_ID,
+        "id": id,
+        "type": "transport",
+        "action": task.action,
+        "description": task.description,
+        "start_time_in_millis": task.start_time_in_millis,
+        "running_time_in_nanos": duration_to_nanos(task.running_time()),
+        "cancellable": true,
+    });
+
+    if let Some(status) = task.status() {
+        task_json["status"] = status;
+    }
+
+    if let Some(ref opaque_id) = task.opaque_id {
+        task_json["headers"] = json!({"X-Opaque-Id": opaque_id});
+    }
+
+    task_json
+}
+
+
+fn completed_task_to_json(id: u64, task: &CompletedTask) -> Json {
+    let mut task_json = json!({
+        "completed": true,
+        "task": {
+            "node": NODE_ID,
+            "id": id,
+            "type": "transport",
+            "action": task.action,
+            "description": task.description,
+            "start_time_in_millis": task.start_time_in_millis,
+            "running_time_in_nanos": duration_to_nanos(task.running_time),
+            "cancellable": true,
+        },
+        "response": task.result,
+    });
+
+    if let Some(ref opaque_id) = task.opaque_id {
+        task_json["task"]["headers"] = json!({"X-Opaque-Id": opaque_id});
+    }
+
+    task_json
+}
+
+
+fn tasks_response(tasks: Vec<(String, Json)>) -> Json {
+    let tasks = tasks.into_iter().collect::<serde_json::Map<_, _>>();
+
+    json!({
+        "nodes": {
+            NODE_ID: {
+                "name": NODE_ID,
+                "tasks": tasks,
+            }
+        }
+    })
+}
+
+
+/// Reads the `actions` URL parameter, a comma separated list of action names that may contain wildcards
+fn get_actions_filter(req: &Request) -> Option<Vec<String>> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "actions" {
+                return Some(value.split(',').map(|action| action.to_string()).collect());
+            }
+        }
+    }
+
+    None
+}
+
+
+/// Lists the running tasks. These can be filtered by action with the `actions` parameter
+pub fn view_get_tasks(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let actions = get_actions_filter(req);
+
+    let tasks = system.tasks.with_tasks(|id, task| {
+        let matches = match actions {
+            Some(ref actions) => actions.iter().any(|pattern| wildcard_match(pattern, &task.action)),
+            None => true,
+        };
+
+        if matches {
+            Some((format_task_id(id), task_to_json(id, task)))
+        } else {
+            None
+        }
+    });
+
+    Ok(json_response(StatusCode::OK, tasks_response(tasks.into_iter().filter_map(|task| task).collect())))
+}
+
+
+/// Gets a task that's running, or the result of a background task that has finished
+pub fn view_get_task(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref task_id = read_path_parameter!(req, "task_id").unwrap_or("");
+
+    let id = match parse_task_id(task_id) {
+        Some(id) => id,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Malformed task id"}))),
+    };
+
+    if let Some(task_json) = system.tasks.with_task(id, |task| task_to_json(id, task)) {
+        return Ok(json_response(StatusCode::OK, json!({"completed": false, "task": task_json})));
+    }
+
+    match system.tasks.with_completed_task(id, |task| completed_task_to_json(id, task)) {
+        Some(task_json) => Ok(json_response(StatusCode::OK, task_json)),
+        None => Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Task not found"}))),
+    }
+}
+
+
+pub fn view_post_cancel_task(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref task_id = read_path_parameter!(req, "task_id").unwrap_or("");
+
+    let id = match parse_task_id(task_id) {
+        Some(id) => id,
+        None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Malformed task id"}))),
+    };
+
+    let task_json = match system.tasks.with_task(id, |task| task_to_json(id, task)) {
+        Some(task_json) => task_json,
+        None => return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Task not found"}))),
+    };
+
+    // The task may have finished in the meantime, in which case there's nothing to cancel
+    system.tasks.cancel(id);
+
+    Ok(json_response(StatusCode::OK, tasks_response(vec![(format_task_id(id), task_json)])))
+}
@@ -0,0 +1,297 @@
+use std::io::Read;
+use std::time::Instant;
+
+use serde_json;
+use serde_json::{Map, Value as Json};
+use url::form_urlencoded;
+
+use search::term::Term;
+use search::profile::duration_to_nanos;
+use search::backends::rocksdb::RocksDBReader;
+use document::read_source_field;
+use index::metadata::IndexMetadata;
+use source_filter::wildcard_match;
+use term_vectors::field_term_vector;
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, index_blocked_response};
+use api::security_api::get_permissions;
+use api::document_api::{read_filter, document_readable};
+
+
+/// What to include in a term vectors response
+struct TermVectorsOptions {
+    /// Field names or wildcard patterns. All text fields are included if this isn't set
+    fields: Option<Vec<String>>,
+    positions: bool,
+    offsets: bool,
+    term_statistics: bool,
+    field_statistics: bool,
+    /// The routing value the document was indexed with, if not its id
+    routing: Option<String>,
+}
+
+
+impl Default for TermVectorsOptions {
+    fn default() -> TermVectorsOptions {
+        TermVectorsOptions {
+            fields: None,
+            positions: true,
+            offsets: true,
+            term_statistics: false,
+            field_statistics: true,
+            routing: None,
+        }
+    }
+}
+
+
+impl TermVectorsOptions {
+    fn set_option(&mut self, key: &str, value: &Json) -> Result<(), String> {
+        if key == "fields" {
+            let fields = match *value {
+                Json::String(ref fields) => fields.split(',').map(|field| field.to_string()).collect(),
+                Json::Array(ref fields) => {
+                    let mut names = Vec::new();
+                    for field in fields.iter() {
+                        names.push(field.as_str().ok_or("fields must be an array of strings")?.to_string());
+                    }
+                    names
+                }
+                _ => return Err("fields must be an array of strings".to_string()),
+            };
+
+            self.fields = Some(fields);
+            return Ok(());
+        }
+
+        if key == "routing" {
+            self.routing = Some(value.as_str().ok_or("routing must be a string")?.to_string());
+            return Ok(());
+        }
+
+        let option = match key {
+            "positions" => &mut self.positions,
+            "offsets" => &mut self.offsets,
+            "term_statistics" => &mut self.term_statistics,
+            "field_statistics" => &mut self.field_statistics,
+            _ => return Err(format!("Unrecognised option: {:?}", key)),
+        };
+
+        *option = match *value {
+            Json::Bool(value) => value,
+            Json::String(ref value) if value == "true" || value.is_empty() => true,
+            Json::String(ref value) if value == "false" => false,
+            _ => return Err(format!("{} must be true or false", key)),
+        };
+
+        Ok(())
+    }
+
+    fn includes_field(&self, name: &str) -> bool {
+        match self.fields {
+            Some(ref fields) => fields.iter().any(|pattern| wildcard_match(pattern, name)),
+            None => true,
+        }
+    }
+}
+
+
+/// Reads the options from the request body, then from the URL parameters, which take
+/// precedence
+fn get_term_vectors_options(req: &Request, body: Option<&Json>) -> Result<TermVectorsOptions, Response> {
+    let mut options = TermVectorsOptions::default();
+
+    if let Some(body) = body.and_then(|body| body.as_object()) {
+        for (key, value) in body.iter() {
+            if key == "doc" {
+                continue;
+            }
+
+            if let Err(message) = options.set_option(key, value) {
+                return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": message})));
+            }
+        }
+    }
+
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if let Err(message) = options.set_option(&key, &Json::String(value.into_owned())) {
+                return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": message})));
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+
+/// Builds the term vectors of each text field in a document's source
+fn term_vectors_json(index_reader: &RocksDBReader, index_metadata: &IndexMetadata, source: &Map<String, Json>, options: &TermVectorsOptions) -> Result<Json, String> {
+    let mut term_vectors = Map::new();
+
+    for (field_name, value) in source.iter() {
+        if !options.includes_field(field_name) {
+            continue;
+        }
+
+        let field_mapping = match index_metadata.get_field_mapping(field_name) {
+            Some(field_mapping) => field_mapping,
+            None => continue,
+        };
+
+        let terms = match field_term_vector(field_mapping, value) {
+            Some(terms) => terms,
+            None => continue,
+        };
+
+        let mut terms_json = Map::new();
+        for (term, occurrences) in terms {
+            let mut term_json = json!({
+                "term_freq": occurrences.len(),
+            });
+
+            if options.term_statistics {
+                let doc_freq = match field_mapping.index_ref {
+                    Some(field_ref) => index_reader.term_document_frequency(field_ref, &Term::from_string(&term))?,
+                    None => 0,
+                };
+
+                term_json["doc_freq"] = json!(doc_freq);
+            }
+
+            if options.positions || options.offsets {
+                term_json["tokens"] = Json::Array(occurrences.iter().map(|occurrence| {
+                    let mut token_json = json!({});
+
+                    if options.positions {
+                        token_json["position"] = json!(occurrence.position);
+                    }
+
+                    if options.offsets {
+                        if let Some((start_offset, end_offset)) = occurrence.offsets {
+                            token_json["start_offset"] = json!(start_offset);
+                            token_json["end_offset"] = json!(end_offset);
+                        }
+                    }
+
+                    token_json
+                }).collect());
+            }
+
+            terms_json.insert(term, term_json);
+        }
+
+        let mut field_json = json!({
+            "terms": terms_json,
+        });
+
+        if options.field_statistics {
+            let (doc_count, sum_ttf) = match field_mapping.index_ref {
+                Some(field_ref) => index_reader.field_statistics(field_ref)?,
+                None => (0, 0),
+            };
+
+            field_json["field_statistics"] = json!({
+                "doc_count": doc_count,
+                "sum_ttf": sum_ttf,
+
+                // Not tracked
+                "sum_doc_freq": -1,
+            });
+        }
+
+        term_vectors.insert(field_name.clone(), field_json);
+    }
+
+    Ok(Json::Object(term_vectors))
+}
+
+
+/// Returns the terms in each text field of a document, along with their positions and offsets
+///
+/// The document is either read from the index, or given in the "doc" key of the request body.
+pub fn view_get_term_vectors(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("");
+    let ref mapping_name = read_path_parameter!(req, "mapping").unwrap_or("");
+    let doc_key = read_path_parameter!(req, "doc");
+    let permissions = get_permissions(req);
+    let start_time = Instant::now();
+
+    let body = json_from_request_body!(req);
+    let options = match get_term_vectors_options(req, body.as_ref()) {
+        Ok(options) => options,
+        Err(response) => return Ok(response),
+    };
+
+    // Get index
+    let cluster_metadata = system.metadata.read().unwrap();
+    let index = get_index_or_404!(cluster_metadata, *index_name);
+    drop(cluster_metadata);
+    let index_metadata = index.metadata.read().unwrap();
+
+    if index_metadata.settings.blocks.blocks_read() {
+        return Ok(index_blocked_response(index.canonical_name(), "read"));
+    }
+
+    // Check that the mapping exists
+    if !index_metadata.mappings.contains_key(*mapping_name) {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({"message": "Mapping not found"})));
+    }
+
+    // Statistics are read from the shard that the document is in. Documents given in the
+    // request body use the first shard, unless routed somewhere else
+    let index_reader = match (options.routing.as_ref(), doc_key) {
+        (Some(routing), _) => index.shard_for_routing(routing),
+        (None, Some(doc_key)) => index.shard_for_routing(doc_key),
+        (None, None) => &index.shards()[0],
+    }.reader();
+    let mut response = json!({
+        "_index": index.canonical_name(),
+        "_type": mapping_name,
+    });
+
+    let source = match doc_key {
+        Some(doc_key) => {
+            response["_id"] = json!(doc_key);
+
+            // Documents hidden by a filtered alias or the request's roles are reported as missing
+            let filter = read_filter(&permissions, index_name, &index, &index_metadata);
+            let (doc_id, version) = match index_reader.get_document_by_key(doc_key) {
+                Some((doc_id, version)) if document_readable(&index_reader, doc_id, filter.as_ref()) => (doc_id, version),
+                _ => {
+                    response["found"] = json!(false);
+                    return Ok(json_response(StatusCode::NOT_FOUND, response));
+                }
+            };
+
+            response["_version"] = json!(version.version);
+
+            let field_ref = index_metadata.get_field_mapping("_source").and_then(|field_mapping| field_mapping.index_ref);
+            field_ref.and_then(|field_ref| read_source_field(&index_reader, field_ref, doc_id)).unwrap_or_else(Map::new)
+        }
+        None => {
+            match body.as_ref().and_then(|body| body.get("doc")) {
+                Some(&Json::Object(ref doc)) => doc.clone(),
+                Some(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "doc must be an object"}))),
+                None => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Missing doc"}))),
+            }
+        }
+    };
+
+    match term_vectors_json(&index_reader, &index_metadata, &source, &options) {
+        Ok(term_vectors) => {
+            response["found"] = json!(true);
+            response["took"] = json!(duration_to_nanos(start_time.elapsed()) / 1_000_000);
+            response["term_vectors"] = term_vectors;
+
+            Ok(json_response(StatusCode::OK, response))
+        }
+        Err(e) => {
+            error!(req.log, "failed to build term vectors"; "index" => index.canonical_name(), "error" => e);
+            Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"message": "Unable to build term vectors"})))
+        }
+    }
+}
@@ -1,87 +1,276 @@
 use serde_json;
+use url::form_urlencoded;
 
-use api::iron::prelude::*;
-use api::iron::status;
+use search::query::Query;
+use search::schema::Schema;
+use index::refresh::RefreshPolicy;
+use index::metadata::IndexMetadata;
+use index::metadata::settings::ActiveShardCount;
+use cluster::metadata::{ResolveError, IndicesOptions};
+use query_parser::build_filter;
+use security::roles::Permissions;
+use hyper::StatusCode;
+use api::request::{Request, Response};
 
 
 macro_rules! get_system {
     ($req: expr) => {{
-        use api::Context;
-
-        $req.get::<persistent::Read<Context>>().unwrap().system.clone()
+        $req.system.clone()
     }}
 }
 
 
 macro_rules! read_path_parameter {
     ($req: expr, $name: expr) => {{
-        $req.extensions.get::<Router>().unwrap().find($name)
+        $req.params.get($name).map(|value| value.as_str())
     }}
 }
 
 
-pub fn json_response(status: status::Status, content: serde_json::Value) -> Response {
-    let mut response = Response::with((status, format!("{}", content)));
-    response.headers.set_raw("Content-Type", vec![b"application/json".to_vec()]);
-    response
+pub fn json_response(status: StatusCode, content: serde_json::Value) -> Response {
+    Response::with_body(status, "application/json", format!("{}", content))
 }
 
 
-pub fn index_not_found_response() -> Response {
-    json_response(status::NotFound, json!({"message": "Index not found"}))
+pub fn index_not_found_response(name: &str) -> Response {
+    json_response(StatusCode::NOT_FOUND, json!({
+        "message": format!("Index not found: {}", name),
+        "index": name,
+    }))
 }
 
 
+/// Reads the `ignore_unavailable` and `allow_no_indices` URL parameters, which control how
+/// the index names in the URL are resolved
+pub fn get_indices_options(req: &Request) -> Result<IndicesOptions, Response> {
+    let mut options = IndicesOptions::default();
+
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            let option = match key.as_ref() {
+                "ignore_unavailable" => &mut options.ignore_unavailable,
+                "allow_no_indices" => &mut options.allow_no_indices,
+                _ => continue,
+            };
+
+            *option = match value.as_ref() {
+                "true" | "" => true,
+                "false" => false,
+                _ => return Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("{} must be true or false", key)}))),
+            };
+        }
+    }
+
+    Ok(options)
+}
+
+
+/// Reads the "refresh" URL parameter that's accepted by the write APIs
+///
+/// Returns an error response if the value isn't recognised
+pub fn get_refresh_policy(req: &Request) -> Result<RefreshPolicy, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "refresh" {
+                return RefreshPolicy::from_param(&value).ok_or_else(|| {
+                    json_response(StatusCode::BAD_REQUEST, json!({
+                        "message": format!("Unrecognised value for refresh parameter: {:?}", value)
+                    }))
+                });
+            }
+        }
+    }
+
+    Ok(RefreshPolicy::None)
+}
+
+
+/// Reads the "wait_for_active_shards" URL parameter that's accepted by the write APIs
+///
+/// Returns None if it isn't given, in which case the index's setting is used
+pub fn get_wait_for_active_shards(req: &Request) -> Result<Option<ActiveShardCount>, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == "wait_for_active_shards" {
+                return ActiveShardCount::parse(&value).map(Some).ok_or_else(|| {
+                    json_response(StatusCode::BAD_REQUEST, json!({
+                        "message": format!("Invalid value for wait_for_active_shards parameter: {:?}", value)
+                    }))
+                });
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+
+/// The response to a write that there aren't enough active copies of the index for
+pub fn unavailable_shards_response(message: String) -> Response {
+    json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"message": message}))
+}
+
+
+/// Reads the "routing" URL parameter
+///
+/// Documents are put in shards by their id, unless a routing value is given. The same value
+/// must then be given to find the document again.
+pub fn get_routing(req: &Request) -> Option<String> {
+    let url_query = req.uri.query()?;
+    form_urlencoded::parse(url_query.as_bytes()).find(|&(ref key, _)| key == "routing").map(|(_, value)| value.into_owned())
+}
+
+
+/// The filter of `index_name`, if it's a filtered alias
+pub fn alias_filter(index_name: &str, index_metadata: &IndexMetadata, schema: &Schema) -> Option<Query> {
+    let filter_json = index_metadata.alias_filter(index_name)?;
+    Some(build_filter(filter_json, index_metadata, schema))
+}
+
+
+/// Restricts a query to the documents that can be seen through the name it was run against
+///
+/// If `index_name` is a filtered alias, only documents that match the filter are kept.
+pub fn apply_alias_filter(query: Query, index_name: &str, index_metadata: &IndexMetadata, schema: &Schema) -> Query {
+    match alias_filter(index_name, index_metadata, schema) {
+        Some(filter) => query.filter(filter),
+        None => query,
+    }
+}
+
+
+/// The filter that the roles of whoever made a request put on the documents of an index
+///
+/// `index_name` is the name the index was found through, which may be one of its aliases.
+/// Returns None if the roles let every document be read.
+pub fn role_filter(permissions: &Permissions, index_name: &str, canonical_name: &str, index_metadata: &IndexMetadata, schema: &Schema) -> Option<Query> {
+    permissions.document_filter(&[index_name, canonical_name], index_metadata, schema)
+}
+
+
+/// Restricts a query to the documents that the roles of whoever made the request can read
+pub fn apply_role_filter(query: Query, permissions: &Permissions, index_name: &str, canonical_name: &str, index_metadata: &IndexMetadata, schema: &Schema) -> Query {
+    match role_filter(permissions, index_name, canonical_name, index_metadata, schema) {
+        Some(filter) => query.filter(filter),
+        None => query,
+    }
+}
+
+
+/// Combines the alias and role filters of a request into the filter of the documents it can see
+///
+/// Returns None if every document can be seen.
+pub fn document_filter(permissions: &Permissions, index_name: &str, canonical_name: &str, index_metadata: &IndexMetadata, schema: &Schema) -> Option<Query> {
+    let alias_filter = alias_filter(index_name, index_metadata, schema);
+    let role_filter = role_filter(permissions, index_name, canonical_name, index_metadata, schema);
+
+    match (alias_filter, role_filter) {
+        (Some(alias_filter), Some(role_filter)) => Some(Query::Conjunction { queries: vec![alias_filter, role_filter] }),
+        (alias_filter, role_filter) => alias_filter.or(role_filter),
+    }
+}
+
+
+/// Returned when an operation isn't allowed by the index's `index.blocks.*` settings
+pub fn index_blocked_response(index_name: &str, operation: &str) -> Response {
+    json_response(StatusCode::FORBIDDEN, json!({
+        "message": format!("Index {} is blocked for {} operations", index_name, operation)
+    }))
+}
+
+
+/// Returned when a request body is larger than `max_content_length`
+pub fn content_too_long_response(max_content_length: u64) -> Response {
+    json_response(StatusCode::PAYLOAD_TOO_LARGE, json!({
+        "message": format!("The request body is larger than the maximum of {} bytes", max_content_length)
+    }))
+}
+
+
+/// Returned when the roles of whoever made a request don't allow it
+pub fn forbidden_response(message: String) -> Response {
+    json_response(StatusCode::FORBIDDEN, json!({"message": message}))
+}
+
+
+pub fn index_closed_response() -> Response {
+    json_response(StatusCode::BAD_REQUEST, json!({"message": "Index is closed"}))
+}
+
+
+/// Returned when an index name or alias can't be used for a request
+pub fn resolve_error_response(name: &str, error: ResolveError) -> Response {
+    match error {
+        ResolveError::NotFound => index_not_found_response(name),
+        ResolveError::Closed => index_closed_response(),
+        ResolveError::MultipleIndices => {
+            json_response(StatusCode::BAD_REQUEST, json!({"message": format!("[{}] matches more than one index", name)}))
+        }
+        ResolveError::NoWriteIndex => {
+            json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Alias [{}] has no write index", name)}))
+        }
+        ResolveError::IsAlias => {
+            json_response(StatusCode::BAD_REQUEST, json!({"message": format!("[{}] is an alias, give the names of its indices instead", name)}))
+        }
+    }
+}
+
+
+/// Finds an open index by its name or an alias of it
+///
+/// The index is shared, so the cluster metadata can be unlocked once it's been found.
 macro_rules! get_index_or_404 {
     ($cluster_metadata: expr, $index_name: expr) => {{
-        use api::utils::index_not_found_response;
+        use api::utils::{index_not_found_response, resolve_error_response};
 
-        let index_ref = match $cluster_metadata.names.find_canonical($index_name) {
-            Some(index_ref) => index_ref,
-            None => {
-                return Ok(index_not_found_response());
+        let index_ref = match $cluster_metadata.resolve_index($index_name) {
+            Ok(index_ref) => index_ref,
+            Err(error) => {
+                return Ok(resolve_error_response($index_name, error));
             }
         };
 
         match $cluster_metadata.indices.get(&index_ref) {
-            Some(index) => index,
+            Some(index) => index.clone(),
             None => {
-                return Ok(index_not_found_response());
+                return Ok(index_not_found_response($index_name));
             }
         }
     }}
 }
 
 
-macro_rules! get_index_or_404_mut {
+/// Finds the index that writes to a name should go to. Aliases resolve to their write index
+macro_rules! get_write_index_or_404 {
     ($cluster_metadata: expr, $index_name: expr) => {{
-        use api::utils::index_not_found_response;
+        use api::utils::{index_not_found_response, resolve_error_response};
 
-        let index_ref = match $cluster_metadata.names.find_canonical($index_name) {
-            Some(index_ref) => index_ref,
-            None => {
-                return Ok(index_not_found_response());
+        let index_ref = match $cluster_metadata.resolve_write_index($index_name) {
+            Ok(index_ref) => index_ref,
+            Err(error) => {
+                return Ok(resolve_error_response($index_name, error));
             }
         };
 
-        match $cluster_metadata.indices.get_mut(&index_ref) {
-            Some(index) => index,
+        match $cluster_metadata.indices.get(&index_ref) {
+            Some(index) => index.clone(),
             None => {
-                return Ok(index_not_found_response());
+                return Ok(index_not_found_response($index_name));
             }
         }
     }}
 }
 
 
+/// Parses JSON from bytes, returning a 400 that says where the JSON is invalid if it can't be
 macro_rules! parse_json {
-    ($string: expr) => {{
+    ($bytes: expr) => {{
         use api::utils::json_response;
 
-        let value: serde_json::Value = match serde_json::from_str($string) {
+        let value: serde_json::Value = match serde_json::from_slice($bytes) {
             Ok(data) => data,
-            Err(_) => {
-                return Ok(json_response(status::BadRequest, json!({"message": "Couldn't parse JSON"})));
+            Err(error) => {
+                return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("Couldn't parse JSON: {}", error)})));
             }
         };
 
@@ -92,9 +281,18 @@ macro_rules! parse_json {
 
 macro_rules! json_from_request_body {
     ($req: expr) => {{
-        // Read request body to a string
-        let mut payload = String::new();
-        $req.body.read_to_string(&mut payload).unwrap();
+        use api::utils::content_too_long_response;
+
+        // Read the request body, stopping if it's too long. Bodies with a Content-Length
+        // have already been checked, but others haven't. It's parsed from the bytes, which
+        // checks that strings are UTF-8 as it goes
+        let max_content_length = $req.system.settings.max_content_length;
+        let mut payload = Vec::new();
+        match $req.body.by_ref().take(max_content_length.saturating_add(1)).read_to_end(&mut payload) {
+            Ok(_) if payload.len() as u64 > max_content_length => return Ok(content_too_long_response(max_content_length)),
+            Ok(_) => {}
+            Err(_) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({"message": "Couldn't read the request body"}))),
+        }
 
         if !payload.is_empty() {
             Some(parse_json!(&payload))
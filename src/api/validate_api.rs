@@ -0,0 +1,125 @@
+use std::io::Read;
+
+use serde_json;
+use serde_json::Value as Json;
+use url::form_urlencoded;
+
+use query_parser::{QueryBuildContext, parse as parse_query};
+
+use hyper::StatusCode;
+use api::request::{Request, Response, ApiResult};
+use api::utils::{json_response, resolve_error_response, apply_alias_filter, get_indices_options};
+
+
+/// Reads a boolean URL parameter. Defaults to false
+fn get_flag(req: &Request, name: &str) -> Result<bool, Response> {
+    if let Some(url_query) = req.uri.query() {
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            if key == name {
+                return match value.as_ref() {
+                    "true" | "" => Ok(true),
+                    "false" => Ok(false),
+                    _ => Err(json_response(StatusCode::BAD_REQUEST, json!({"message": format!("{} must be true or false", name)}))),
+                };
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+
+/// Checks whether a query is valid, without running it
+///
+/// With `explain=true`, the response says what's wrong with an invalid query, or shows how a
+/// valid one was parsed. With `rewrite=true`, it shows the query that would actually be run
+/// against each index instead.
+pub fn view_validate_query(req: &mut Request) -> ApiResult<Response> {
+    let ref system = get_system!(req);
+    let ref index_name = read_path_parameter!(req, "index").unwrap_or("_all");
+    let explain = match get_flag(req, "explain") {
+        Ok(explain) => explain,
+        Err(response) => return Ok(response),
+    };
+    let rewrite = match get_flag(req, "rewrite") {
+        Ok(rewrite) => rewrite,
+        Err(response) => return Ok(response),
+    };
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    let indices_options = match get_indices_options(req) {
+        Ok(indices_options) => indices_options,
+        Err(response) => return Ok(response),
+    };
+    let indices = match cluster_metadata.resolve_indices(index_name, &indices_options) {
+        Ok(indices) => indices,
+        Err((name, e)) => return Ok(resolve_error_response(&name, e)),
+    };
+
+    // The query defaults to match_all, like a search without one
+    let query_json = match json_from_request_body!(req) {
+        Some(Json::Object(body)) => {
+            let unrecognised_key = body.keys().find(|key| *key != "query").cloned();
+            match unrecognised_key {
+                Some(key) => Err(format!("unrecognised key {:?}", key)),
+                None => Ok(body.get("query").cloned().unwrap_or_else(|| json!({"match_all": {}}))),
+            }
+        }
+        Some(_) => Err("request body must be an object".to_string()),
+        None => Ok(json!({"match_all": {}})),
+    };
+
+    let query = query_json.and_then(|query_json| parse_query(&query_json).map_err(|e| e.to_string()));
+
+    let mut response = json!({
+        "valid": query.is_ok(),
+        "_shards": {
+            "total": indices.len(),
+            "successful": indices.len(),
+            "failed": 0,
+        },
+    });
+
+    if explain || rewrite {
+        let explanations = indices.iter().map(|&(ref index_ref, ref name)| {
+            let index = &cluster_metadata.indices[index_ref];
+
+            match query {
+                Ok(ref query) if rewrite => {
+                    let index_metadata = index.metadata.read().unwrap();
+                    let index_reader = index.reader();
+                    let built_query = query.build(&QueryBuildContext::new().set_index_metadata(&index_metadata), &index_reader.schema());
+                    let built_query = apply_alias_filter(built_query, name, &index_metadata, &index_reader.schema());
+
+                    json!({
+                        "index": index.canonical_name(),
+                        "valid": true,
+                        "explanation": format!("{:?}", built_query),
+                    })
+                }
+                Ok(ref query) => {
+                    json!({
+                        "index": index.canonical_name(),
+                        "valid": true,
+                        "explanation": format!("{:?}", query),
+                    })
+                }
+                Err(ref e) => {
+                    json!({
+                        "index": index.canonical_name(),
+                        "valid": false,
+                        "error": e,
+                    })
+                }
+            }
+        }).collect::<Vec<_>>();
+
+        response["explanations"] = json!(explanations);
+
+        if let Err(ref e) = query {
+            response["error"] = json!(e);
+        }
+    }
+
+    return Ok(json_response(StatusCode::OK, response));
+}
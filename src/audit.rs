@@ -0,0 +1,251 @@
+//! Records security-relevant events in a file of their own, one JSON object per line
+//!
+//! Failed authentications, refused requests, changes to indices, security and cluster
+//! settings, and requests that delete data are recorded with who made them, the address
+//! they came from and a summary of the request. Request bodies aren't recorded, as they can
+//! contain passwords and API key secrets.
+//!
+//! Each node records the requests it handles, so a request that's forwarded to the master
+//! is recorded by both nodes. The master's record has the forwarding node's address and id.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use hyper::Method;
+use serde_json::Value as Json;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditEventType {
+    /// Credentials were missing, malformed or wrong
+    AuthenticationFailed,
+
+    /// The principal's roles don't allow the request
+    AccessDenied,
+
+    /// Creating, opening, closing or merging indices, or changing their mappings, settings
+    /// or aliases
+    IndexAdmin,
+
+    /// Deleting indices or documents in bulk
+    Destructive,
+
+    /// Changing users, roles or API keys
+    SecurityAdmin,
+
+    /// Changing cluster settings, lifecycle policies or stored scripts
+    ClusterAdmin,
+}
+
+
+impl AuditEventType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuditEventType::AuthenticationFailed => "authentication_failed",
+            AuditEventType::AccessDenied => "access_denied",
+            AuditEventType::IndexAdmin => "index_admin",
+            AuditEventType::Destructive => "destructive",
+            AuditEventType::SecurityAdmin => "security_admin",
+            AuditEventType::ClusterAdmin => "cluster_admin",
+        }
+    }
+}
+
+
+/// The type of event that requests to a route are recorded as, or None if they aren't
+/// recorded once they've been authorized
+pub fn route_event_type(method: &Method, pattern: &str) -> Option<AuditEventType> {
+    let changes = *method != Method::GET && *method != Method::HEAD;
+
+    match pattern {
+        "/:index" | "/_dangling/:index" if *method == Method::DELETE => Some(AuditEventType::Destructive),
+        "/:index/_delete_by_query" => Some(AuditEventType::Destructive),
+        "/:index" | "/:index/_alias/:alias" | "/:index/_mapping/:mapping" | "/:index/_settings" |
+        "/:index/_close" | "/:index/_open" | "/:index/_forcemerge" | "/_forcemerge" | "/_aliases" |
+        "/_dangling/:index" if changes => Some(AuditEventType::IndexAdmin),
+        "/_cluster/settings" | "/_ilm/policy/:name" | "/_scripts/:id" if changes => Some(AuditEventType::ClusterAdmin),
+        "/_security/_authenticate" => None,
+        _ if pattern.starts_with("/_security/") && changes => Some(AuditEventType::SecurityAdmin),
+        _ => None,
+    }
+}
+
+
+/// Something that happened to a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub event_type: AuditEventType,
+
+    /// Who made the request, or the username or API key id they tried if they couldn't be
+    /// authenticated
+    pub user: String,
+
+    /// "anonymous", "realm" or "api_key", as in `_security/_authenticate`. "unknown" if the
+    /// `Authorization` header couldn't be read
+    pub authentication_type: String,
+
+    pub source_ip: IpAddr,
+    pub method: String,
+
+    /// Includes the query string
+    pub path: String,
+
+    pub opaque_id: String,
+
+    /// The index named in the request's path
+    pub index: Option<String>,
+
+    /// The node that forwarded the request, if it wasn't made to this node directly
+    pub forwarded_by: Option<String>,
+
+    /// The status of the response
+    pub status: u16,
+}
+
+
+impl AuditEvent {
+    pub fn to_json(&self, timestamp: DateTime<Utc>) -> Json {
+        let mut json = json!({
+            "@timestamp": timestamp.to_rfc3339(),
+            "event.type": self.event_type.name(),
+            "user.name": self.user,
+            "authentication.type": self.authentication_type,
+            "origin.address": self.source_ip.to_string(),
+            "request.method": self.method,
+            "request.path": self.path,
+            "request.id": self.opaque_id,
+            "response.status": self.status,
+        });
+
+        if let Json::Object(ref mut object) = json {
+            if let Some(ref index) = self.index {
+                object.insert("indices".to_string(), json!([index]));
+            }
+
+            if let Some(ref forwarded_by) = self.forwarded_by {
+                object.insert("forwarded_by.node_id".to_string(), Json::String(forwarded_by.clone()));
+            }
+        }
+
+        json
+    }
+}
+
+
+/// The audit log file. Events are appended to it, so it can be rotated by moving it and
+/// restarting the node
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+
+impl AuditLog {
+    /// Opens the file, creating it and its directory if they don't exist
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes an event as a line of JSON
+    pub fn record(&self, event: &AuditEvent) -> io::Result<()> {
+        let mut line = event.to_json(Utc::now()).to_string();
+        line.push('\n');
+
+        // Lines are written whole, so events from different threads don't interleave
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    use chrono::{TimeZone, Utc};
+    use hyper::Method;
+    use serde_json::{self, Value as Json};
+
+    use super::{AuditLog, AuditEvent, AuditEventType, route_event_type};
+
+    fn make_event(event_type: AuditEventType) -> AuditEvent {
+        AuditEvent {
+            event_type: event_type,
+            user: "admin".to_string(),
+            authentication_type: "realm".to_string(),
+            source_ip: "10.0.0.7".parse().unwrap(),
+            method: "DELETE".to_string(),
+            path: "/logs".to_string(),
+            opaque_id: "cleanup-1".to_string(),
+            index: Some("logs".to_string()),
+            forwarded_by: None,
+            status: 200,
+        }
+    }
+
+    #[test]
+    fn test_route_event_type() {
+        assert_eq!(route_event_type(&Method::DELETE, "/:index"), Some(AuditEventType::Destructive));
+        assert_eq!(route_event_type(&Method::PUT, "/:index"), Some(AuditEventType::IndexAdmin));
+        assert_eq!(route_event_type(&Method::GET, "/:index"), None);
+        assert_eq!(route_event_type(&Method::POST, "/:index/_delete_by_query"), Some(AuditEventType::Destructive));
+        assert_eq!(route_event_type(&Method::DELETE, "/_dangling/:index"), Some(AuditEventType::Destructive));
+        assert_eq!(route_event_type(&Method::POST, "/_dangling/:index"), Some(AuditEventType::IndexAdmin));
+        assert_eq!(route_event_type(&Method::PUT, "/_cluster/settings"), Some(AuditEventType::ClusterAdmin));
+        assert_eq!(route_event_type(&Method::PUT, "/_security/role/:name"), Some(AuditEventType::SecurityAdmin));
+        assert_eq!(route_event_type(&Method::GET, "/_security/role/:name"), None);
+        assert_eq!(route_event_type(&Method::GET, "/_security/_authenticate"), None);
+        assert_eq!(route_event_type(&Method::POST, "/:index/_search"), None);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = make_event(AuditEventType::Destructive).to_json(Utc.ymd(2017, 7, 14).and_hms(2, 40, 0));
+        assert_eq!(json, json!({
+            "@timestamp": "2017-07-14T02:40:00+00:00",
+            "event.type": "destructive",
+            "user.name": "admin",
+            "authentication.type": "realm",
+            "origin.address": "10.0.0.7",
+            "request.method": "DELETE",
+            "request.path": "/logs",
+            "request.id": "cleanup-1",
+            "indices": ["logs"],
+            "response.status": 200,
+        }));
+    }
+
+    #[test]
+    fn test_record() {
+        let path = Path::new("test_indices/test_audit/audit.json");
+        let _ = fs::remove_file(path);
+
+        let audit_log = AuditLog::open(path).unwrap();
+        audit_log.record(&make_event(AuditEventType::Destructive)).unwrap();
+        audit_log.record(&make_event(AuditEventType::AccessDenied)).unwrap();
+
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        let event_types = contents.lines().map(|line| {
+            let json: Json = serde_json::from_str(line).unwrap();
+            json["event.type"].as_str().unwrap().to_string()
+        }).collect::<Vec<_>>();
+        assert_eq!(event_types, vec!["destructive", "access_denied"]);
+    }
+}
@@ -0,0 +1,143 @@
+//! The threads that look after a node's indices while it runs
+//!
+//! Each thread only holds a weak reference to the system between runs, so they stop once
+//! the system has been dropped.
+
+use std::panic;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use futures::executor::block_on;
+
+use system::System;
+use thread_pool::QueueFull;
+use cluster::coordinator::PING_INTERVAL;
+use lifecycle;
+
+
+/// Runs `task` every time `interval` returns, until the system is dropped
+fn spawn_loop<T, I>(system: &Arc<System>, task: T, interval: I) where T: Fn(&Arc<System>) + Send + 'static, I: Fn(&System) -> Duration + Send + 'static {
+    let system = Arc::downgrade(system);
+
+    thread::spawn(move || {
+        loop {
+            let sleep_for = match Weak::upgrade(&system) {
+                Some(system) => {
+                    task(&system);
+                    interval(&system)
+                }
+                None => return,
+            };
+
+            thread::sleep(sleep_for);
+        }
+    });
+}
+
+
+/// Expires scrolls and merges segments
+pub fn start_maintenance(system: &Arc<System>) {
+    spawn_loop(system, |system| {
+        system.expire_scrolls();
+
+        // Indices are merged in parallel on the management pool, at most
+        // `indices.merge.scheduler.max_thread_count` at a time. The next round waits
+        // for all of this one's to finish
+        let indices = system.metadata.read().unwrap().indices.values().cloned().collect::<Vec<_>>();
+        let max_concurrent_merges = system.cluster_settings.current().max_concurrent_merges.unwrap_or_else(|| indices.len()).max(1);
+        for batch in indices.chunks(max_concurrent_merges) {
+            let mut tasks = Vec::new();
+            for index in batch.iter().cloned() {
+                let index_name = index.canonical_name().to_string();
+
+                match system.thread_pools.management.spawn(move || index.run_maintenance_task()) {
+                    Ok(result) => tasks.push((index_name, result)),
+                    Err(QueueFull) => {
+                        debug!(system.log, "management queue is full, skipping index maintenance"; "index" => index_name);
+                    }
+                }
+            }
+
+            for (index_name, result) in tasks {
+                match block_on(result) {
+                    Ok(Ok(Ok(()))) => {}
+                    Ok(Ok(Err(error))) => {
+                        error!(system.log, "index maintenance task failed"; "index" => index_name, "error" => error);
+                    }
+                    Ok(Err(_)) | Err(_) => {
+                        error!(system.log, "index maintenance task panicked"; "index" => index_name);
+                    }
+                }
+            }
+        }
+    }, |_| Duration::new(1, 0));
+}
+
+
+/// Checks the data disk against the disk watermarks
+pub fn start_disk_monitor(system: &Arc<System>) {
+    spawn_loop(system, |system| system.check_disk_usage(), |system| system.cluster_settings.current().disk_check_interval);
+}
+
+
+/// Refreshes indices when their refresh interval is due
+pub fn start_refresh(system: &Arc<System>) {
+    spawn_loop(system, |system| {
+        let cluster_metadata = system.metadata.read().unwrap();
+        for index in cluster_metadata.indices.values() {
+            if let Err(error) = index.refresh_if_due() {
+                error!(system.log, "index refresh failed"; "index" => index.canonical_name(), "error" => error);
+            }
+        }
+    }, |_| Duration::from_millis(100));
+}
+
+
+/// Pings the other nodes of the cluster and recovers replicas
+pub fn start_coordinator(system: &Arc<System>) {
+    info!(system.log, "starting cluster coordinator"; "node" => system.cluster.local_node.name.clone(), "cluster_name" => system.settings.cluster_name.clone());
+
+    spawn_loop(system, |system| {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            system.cluster.coordinate(system);
+            system.replication.recover_replicas(system);
+        }));
+
+        if let Err(error) = result {
+            error!(system.log, "cluster coordinator panicked"; "error" => format!("{:?}", error));
+        }
+    }, |_| PING_INTERVAL);
+}
+
+
+/// Runs index lifecycle policies
+pub fn start_lifecycle(system: &Arc<System>) {
+    // The policies first run after one poll interval rather than straight away
+    let interval = |system: &System| system.cluster_settings.current().lifecycle_poll_interval;
+    let first_run = interval(system);
+    let system = Arc::downgrade(system);
+
+    thread::spawn(move || {
+        thread::sleep(first_run);
+
+        loop {
+            let sleep_for = match Weak::upgrade(&system) {
+                Some(system) => {
+                    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        lifecycle::runner::run_lifecycle_policies(&system);
+                    }));
+
+                    if let Err(error) = result {
+                        error!(system.log, "lifecycle policies panicked"; "error" => format!("{:?}", error));
+                    }
+
+                    interval(&system)
+                }
+                None => return,
+            };
+
+            thread::sleep(sleep_for);
+        }
+    });
+}
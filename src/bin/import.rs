@@ -0,0 +1,95 @@
+extern crate rusticsearch;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::process;
+use std::sync::Arc;
+
+use rusticsearch::import::{ImportOptions, ImportStats, Target, DocumentReader, Destination, HttpDestination, EngineDestination, USAGE, run_import};
+
+
+fn report_progress(stats: &ImportStats) {
+    eprintln!("indexed {} documents ({:.0}/s), {} failed", stats.indexed, stats.rate(), stats.failed);
+}
+
+
+/// Imports the documents, refreshes the index and reports how it went. Returns false if any
+/// documents failed
+fn import<R: BufRead, D: Destination + 'static>(documents: DocumentReader<R>, destination: D, options: &ImportOptions) -> bool {
+    let destination = Arc::new(destination);
+    let stats = run_import(documents, destination.clone(), options.batch_size, options.concurrency, report_progress);
+
+    let refreshed = match destination.refresh() {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("rusticsearch-import: couldn't refresh {}: {}", options.index, error);
+            false
+        }
+    };
+
+    for &(line_number, ref error) in stats.errors.iter() {
+        eprintln!("line {}: {}", line_number, error);
+    }
+    if stats.failed > stats.errors.len() as u64 {
+        eprintln!("... and {} more failures", stats.failed - stats.errors.len() as u64);
+    }
+
+    let seconds = stats.elapsed.as_secs() as f64 + stats.elapsed.subsec_nanos() as f64 / 1e9;
+    println!("indexed {} documents into {} in {:.1}s ({:.0}/s), {} failed", stats.indexed, options.index, seconds, stats.rate(), stats.failed);
+
+    refreshed && stats.failed == 0
+}
+
+
+fn main() {
+    let options = match ImportOptions::parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("rusticsearch-import: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
+    let input: Box<dyn BufRead> = if options.input == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        match File::open(&options.input) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                eprintln!("rusticsearch-import: couldn't open {}: {}", options.input, e);
+                process::exit(1);
+            }
+        }
+    };
+    let documents = DocumentReader::new(input, &options);
+
+    let succeeded = match options.target {
+        Target::Http(ref url, ref credentials) => {
+            match HttpDestination::new(url, credentials.as_ref().map(|credentials| credentials.as_str()), &options.index, &options.doc_type) {
+                Ok(destination) => import(documents, destination, &options),
+                Err(e) => {
+                    eprintln!("rusticsearch-import: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Target::DataDir(ref data_dir) => {
+            match EngineDestination::open(data_dir, &options.index, &options.doc_type) {
+                Ok(destination) => import(documents, destination, &options),
+                Err(e) => {
+                    eprintln!("rusticsearch-import: couldn't open {}: {}", data_dir.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+
+    if !succeeded {
+        process::exit(1);
+    }
+}
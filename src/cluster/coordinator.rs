@@ -0,0 +1,699 @@
+//! Forms a cluster with the nodes at the seed addresses and keeps its state in step
+//!
+//! A node that isn't following a master pings the seed hosts. If one of them is already the
+//! master, the node joins it. Otherwise the nodes that answered elect one between them (see
+//! `elect_master`), and the others join it once it has taken over. A node without any seed
+//! hosts is the master of a cluster of its own from the start.
+//!
+//! The master owns the cluster state. Requests that change indices, mappings, settings or
+//! aliases are forwarded to it, and once it has made a change it publishes the whole new
+//! state to the other nodes, which bring their own indices in line with it. The master pings
+//! the other nodes and drops the ones that stop answering, and nodes that lose their master
+//! go back to discovery.
+//!
+//! The master also decides which nodes hold the copies of each index (see `cluster::routing`),
+//! and moves them whenever nodes join or leave, indices change or a copy fails. Each node
+//! reports the disk watermark it's over in its pings, so the master can keep new copies off
+//! nodes that are running out of space.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{RwLock, Mutex};
+use std::time::Duration;
+use std::io;
+
+use hyper::Method;
+use serde_json::{self, Value as Json};
+use uuid::Uuid;
+
+use system::System;
+use settings::Settings;
+use index::metadata::IndexMetadata;
+use mapping::Mapping;
+use index::metadata::parse::parse as parse_index_metadata;
+use cluster::metadata::{ClusterMetadata, IndexRef};
+use cluster::state::{ClusterState, DiscoveryNode, IndexState, PingResponse, index_states, elect_master};
+use cluster::routing::IndexRouting;
+use cluster::transport::{Transport, TransportRequest, TransportResponse, parse_json_response};
+use disk_usage::DiskWatermark;
+
+
+/// Time between rounds of discovery or pings
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a node has to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a node has to apply a new state, and the master has to handle a forwarded request
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pings a node can miss in a row before it's taken to have left
+const MAX_MISSED_PINGS: usize = 3;
+
+pub const PING_PATH: &'static str = "/_internal/cluster/ping";
+pub const JOIN_PATH: &'static str = "/_internal/cluster/join";
+pub const PUBLISH_PATH: &'static str = "/_internal/cluster/state";
+pub const SHARD_STARTED_PATH: &'static str = "/_internal/cluster/shard/started";
+pub const SHARD_FAILED_PATH: &'static str = "/_internal/cluster/shard/failed";
+
+
+pub struct Coordinator {
+    pub local_node: DiscoveryNode,
+    cluster_name: String,
+    seed_addresses: Vec<String>,
+    minimum_master_nodes: usize,
+    state: RwLock<ClusterState>,
+    transport: Transport,
+
+    /// Pings that each node has failed to answer in a row, by node id. Only the master's
+    /// are counted on the other nodes
+    missed_pings: Mutex<BTreeMap<String, usize>>,
+
+    /// Held while the state is being changed, so new versions are published in order
+    publish_lock: Mutex<()>,
+
+    /// The disk watermark this node's data disk is over (see `System::check_disk_usage`)
+    local_disk_watermark: RwLock<Option<DiskWatermark>>,
+
+    /// The disk watermarks that the other nodes reported in their last pings, by node id.
+    /// Only the master keeps these
+    reported_disk_watermarks: Mutex<BTreeMap<String, DiskWatermark>>,
+}
+
+
+impl Coordinator {
+    /// Fails if the thread that requests to other nodes are made on couldn't be started
+    pub fn new(settings: &Settings) -> io::Result<Coordinator> {
+        let id = Uuid::new_v4().simple().to_string();
+        let local_node = DiscoveryNode {
+            name: settings.node_name.clone().unwrap_or_else(|| id[..7].to_string()),
+            id: id,
+            address: settings.publish_address(),
+        };
+
+        // A node on its own doesn't need to wait for anyone else
+        let seed_addresses = settings.seed_addresses().into_iter().filter(|address| *address != local_node.address).collect::<Vec<_>>();
+        let mut state = ClusterState::new(&settings.cluster_name);
+        if seed_addresses.is_empty() && settings.minimum_master_nodes <= 1 {
+            state.master_node = Some(local_node.id.clone());
+            state.nodes.insert(local_node.id.clone(), local_node.clone());
+        }
+
+        Ok(Coordinator {
+            local_node: local_node,
+            cluster_name: settings.cluster_name.clone(),
+            seed_addresses: seed_addresses,
+            minimum_master_nodes: settings.minimum_master_nodes,
+            state: RwLock::new(state),
+            transport: Transport::new(settings.cluster_secret.clone())?,
+            missed_pings: Mutex::new(BTreeMap::new()),
+            publish_lock: Mutex::new(()),
+            local_disk_watermark: RwLock::new(None),
+            reported_disk_watermarks: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// A copy of the current cluster state
+    pub fn state(&self) -> ClusterState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// The master this node is following, which may be itself
+    pub fn master(&self) -> Option<DiscoveryNode> {
+        self.state.read().unwrap().master().cloned()
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.state.read().unwrap().master_node.as_ref() == Some(&self.local_node.id)
+    }
+
+    /// Finds where the copies of an index are. See `ClusterState::index_routing`
+    pub fn index_routing(&self, index_name: &str) -> IndexRouting {
+        self.state.read().unwrap().index_routing(index_name, &self.local_node.id)
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<DiscoveryNode> {
+        self.state.read().unwrap().nodes.get(node_id).cloned()
+    }
+
+    /// Sends requests to the other nodes
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    pub fn number_of_nodes(&self) -> usize {
+        // A node that hasn't joined a cluster yet still counts itself
+        self.state.read().unwrap().nodes.len().max(1)
+    }
+
+    /// What this node says about itself when it's pinged
+    pub fn ping_response(&self) -> PingResponse {
+        let state = self.state.read().unwrap();
+
+        PingResponse {
+            cluster_name: self.cluster_name.clone(),
+            node: self.local_node.clone(),
+            master_node: state.master_node.clone(),
+            version: state.version,
+            disk_watermark: *self.local_disk_watermark.read().unwrap(),
+        }
+    }
+
+    /// The disk watermark this node is over, as of the last check
+    pub fn local_disk_watermark(&self) -> Option<DiskWatermark> {
+        *self.local_disk_watermark.read().unwrap()
+    }
+
+    /// Records the disk watermark this node is over. The master picks up the change with
+    /// its next ping
+    pub fn set_local_disk_watermark(&self, watermark: Option<DiskWatermark>) {
+        *self.local_disk_watermark.write().unwrap() = watermark;
+    }
+
+    /// The disk watermark that each node is over, by node name. This node is always
+    /// included, even before it has joined a cluster
+    pub fn disk_watermarks(&self) -> BTreeMap<String, DiskWatermark> {
+        let state = self.state.read().unwrap();
+        let mut watermarks = state.disk_watermarks.iter()
+            .filter_map(|(node_id, watermark)| state.nodes.get(node_id).map(|node| (node.name.clone(), *watermark)))
+            .collect::<BTreeMap<_, _>>();
+
+        watermarks.remove(&self.local_node.name);
+        if let Some(watermark) = *self.local_disk_watermark.read().unwrap() {
+            watermarks.insert(self.local_node.name.clone(), watermark);
+        }
+
+        watermarks
+    }
+
+    /// The watermarks of the nodes in the state, as they were last reported
+    fn current_disk_watermarks(&self, state: &ClusterState) -> BTreeMap<String, DiskWatermark> {
+        let mut watermarks = self.reported_disk_watermarks.lock().unwrap().iter()
+            .filter(|&(node_id, _)| state.nodes.contains_key(node_id) && *node_id != self.local_node.id)
+            .map(|(node_id, watermark)| (node_id.clone(), *watermark))
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(watermark) = *self.local_disk_watermark.read().unwrap() {
+            watermarks.insert(self.local_node.id.clone(), watermark);
+        }
+
+        watermarks
+    }
+
+    /// Runs a round of discovery, or checks on the other nodes once there's a master
+    ///
+    /// This is called every `PING_INTERVAL`.
+    pub fn coordinate(&self, system: &System) {
+        let master_node = self.state.read().unwrap().master_node.clone();
+
+        match master_node {
+            None => self.discover(system),
+            Some(ref master_node) if *master_node == self.local_node.id => {
+                self.check_nodes(system);
+
+                // Picks up changes that weren't made through the API, such as indices that
+                // have finished loading
+                self.publish_changes(system);
+            }
+            Some(_) => self.check_master(system),
+        }
+    }
+
+    fn ping_all(&self, addresses: &[String]) -> Vec<Result<PingResponse, String>> {
+        let requests = addresses.iter().map(|address| TransportRequest::new(address, Method::GET, PING_PATH)).collect();
+
+        self.transport.send_all(requests, PING_TIMEOUT).into_iter().map(|response| {
+            parse_json_response(response).and_then(|json| {
+                serde_json::from_value::<PingResponse>(json).map_err(|e| format!("invalid ping response: {}", e))
+            })
+        }).collect()
+    }
+
+    fn discover(&self, system: &System) {
+        let mut responses = Vec::new();
+        for (address, response) in self.seed_addresses.iter().zip(self.ping_all(&self.seed_addresses)) {
+            match response {
+                Ok(ref response) if response.cluster_name != self.cluster_name => {
+                    warn!(system.log, "seed host belongs to a different cluster"; "address" => address, "cluster_name" => &response.cluster_name);
+                }
+                Ok(response) => {
+                    if response.node.id != self.local_node.id {
+                        responses.push(response);
+                    }
+                }
+                Err(e) => {
+                    debug!(system.log, "seed host didn't answer ping"; "address" => address, "error" => e);
+                }
+            }
+        }
+
+        // Join the master if there already is one
+        if let Some(master) = responses.iter().find(|response| response.is_master()) {
+            if let Err(e) = self.join(system, &master.node) {
+                warn!(system.log, "failed to join master"; "master" => &master.node.name, "error" => e);
+            }
+
+            return;
+        }
+
+        let version = self.state.read().unwrap().version;
+        let mut candidates = vec![(self.local_node.id.as_str(), version)];
+        candidates.extend(responses.iter().map(|response| (response.node.id.as_str(), response.version)));
+
+        match elect_master(&candidates, self.minimum_master_nodes) {
+            Some(ref elected) if *elected == self.local_node.id => self.become_master(system),
+            Some(_) => {
+                // The elected node joins the others once it has taken over
+            }
+            None => {
+                debug!(system.log, "not enough nodes to elect a master"; "nodes" => candidates.len(), "minimum_master_nodes" => self.minimum_master_nodes);
+            }
+        }
+    }
+
+    fn become_master(&self, system: &System) {
+        let _publishing = self.publish_lock.lock().unwrap();
+        let indices = index_states(&system.metadata.read().unwrap());
+
+        let mut state = self.state.write().unwrap();
+        state.master_node = Some(self.local_node.id.clone());
+        state.nodes.clear();
+        state.nodes.insert(self.local_node.id.clone(), self.local_node.clone());
+        state.indices = indices;
+        state.settings = system.cluster_settings.state();
+        self.reported_disk_watermarks.lock().unwrap().clear();
+        state.disk_watermarks = self.current_disk_watermarks(&state);
+        state.reroute();
+        state.version += 1;
+        self.missed_pings.lock().unwrap().clear();
+
+        info!(system.log, "elected as master"; "node" => &self.local_node.name, "version" => state.version);
+    }
+
+    /// Asks the master to add this node to the cluster, and applies the state it sends back
+    fn join(&self, system: &System, master: &DiscoveryNode) -> Result<(), String> {
+        let request = TransportRequest::new(&master.address, Method::POST, JOIN_PATH).with_json(&serde_json::to_value(&self.local_node).unwrap_or(Json::Null));
+        let json = parse_json_response(self.transport.send(request, PUBLISH_TIMEOUT))?;
+        let state = serde_json::from_value::<ClusterState>(json).map_err(|e| format!("invalid cluster state: {}", e))?;
+
+        self.apply_state(system, state)?;
+        info!(system.log, "joined cluster"; "master" => &master.name, "cluster_name" => &self.cluster_name);
+
+        Ok(())
+    }
+
+    /// Adds a node to the cluster. Only the master takes joins
+    ///
+    /// The other nodes are told about the new one, which gets the state in the response.
+    pub fn handle_join(&self, system: &System, node: DiscoveryNode) -> Result<ClusterState, String> {
+        let _publishing = self.publish_lock.lock().unwrap();
+        if !self.is_master() {
+            return Err("this node isn't the master".to_string());
+        }
+
+        let state = {
+            let mut state = self.state.write().unwrap();
+            state.nodes.insert(node.id.clone(), node.clone());
+            state.indices = index_states(&system.metadata.read().unwrap());
+            state.settings = system.cluster_settings.state();
+            state.disk_watermarks = self.current_disk_watermarks(&state);
+            state.reroute();
+            state.version += 1;
+            state.clone()
+        };
+        self.missed_pings.lock().unwrap().remove(&node.id);
+
+        info!(system.log, "node joined"; "node" => &node.name, "address" => &node.address, "version" => state.version);
+        self.publish(system, &state, &[&node.id]);
+
+        Ok(state)
+    }
+
+    /// Publishes a new version of the state if the master's indices or settings, or the
+    /// nodes' disk watermarks, have changed
+    ///
+    /// This is called after each request that could have changed them, and after each round
+    /// of pings.
+    pub fn publish_changes(&self, system: &System) {
+        let _publishing = self.publish_lock.lock().unwrap();
+        if !self.is_master() {
+            return;
+        }
+
+        let indices = index_states(&system.metadata.read().unwrap());
+        let settings = system.cluster_settings.state();
+        let state = {
+            let mut state = self.state.write().unwrap();
+            let disk_watermarks = self.current_disk_watermarks(&state);
+            let changed = state.indices != indices || state.settings != settings || state.disk_watermarks != disk_watermarks;
+            state.indices = indices;
+            state.settings = settings;
+            state.disk_watermarks = disk_watermarks;
+
+            // Changing number_of_replicas, opening an index or a node freeing up disk space
+            // moves copies around
+            if !state.reroute() && !changed {
+                return;
+            }
+
+            state.version += 1;
+            state.clone()
+        };
+
+        self.publish(system, &state, &[]);
+    }
+
+    /// Sends the state to every node apart from this one and the ones in `except`
+    ///
+    /// Nodes that fail to apply it are left to the pings to deal with.
+    fn publish(&self, system: &System, state: &ClusterState, except: &[&str]) {
+        let body = serde_json::to_value(state).unwrap_or(Json::Null);
+        let nodes = state.nodes.values().filter(|node| node.id != self.local_node.id && !except.contains(&node.id.as_str())).collect::<Vec<_>>();
+        let requests = nodes.iter().map(|node| TransportRequest::new(&node.address, Method::POST, PUBLISH_PATH).with_json(&body)).collect();
+
+        for (node, response) in nodes.iter().zip(self.transport.send_all(requests, PUBLISH_TIMEOUT)) {
+            if let Err(e) = parse_json_response(response) {
+                warn!(system.log, "failed to publish cluster state"; "node" => &node.name, "version" => state.version, "error" => e);
+            }
+        }
+    }
+
+    /// Applies a state that was published by the master
+    ///
+    /// States from the current master that are older than the one this node has are ignored.
+    pub fn apply_state(&self, system: &System, state: ClusterState) -> Result<(), String> {
+        let _publishing = self.publish_lock.lock().unwrap();
+
+        if state.cluster_name != self.cluster_name {
+            return Err(format!("state is for a different cluster: {}", state.cluster_name));
+        }
+
+        let previous_indices = {
+            let current = self.state.read().unwrap();
+            if current.master_node.as_ref() == Some(&self.local_node.id) {
+                return Err("this node is the master".to_string());
+            }
+
+            if current.master_node.is_some() && current.master_node == state.master_node && state.version <= current.version {
+                return Ok(());
+            }
+
+            current.indices.clone()
+        };
+
+        apply_indices(system, &previous_indices, &state.indices);
+        system.replace_cluster_settings(state.settings.clone());
+
+        debug!(system.log, "applied cluster state"; "version" => state.version);
+        *self.state.write().unwrap() = state;
+        self.missed_pings.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    /// Counts a missed ping, returning true once the node has missed too many
+    fn missed_ping(&self, node_id: &str) -> bool {
+        let mut missed_pings = self.missed_pings.lock().unwrap();
+        let missed = missed_pings.entry(node_id.to_string()).or_insert(0);
+        *missed += 1;
+        *missed >= MAX_MISSED_PINGS
+    }
+
+    /// Drops nodes that have stopped answering the master's pings
+    fn check_nodes(&self, system: &System) {
+        let nodes = self.state.read().unwrap().nodes.values().filter(|node| node.id != self.local_node.id).cloned().collect::<Vec<_>>();
+        let addresses = nodes.iter().map(|node| node.address.clone()).collect::<Vec<_>>();
+
+        let mut left = Vec::new();
+        for (node, response) in nodes.iter().zip(self.ping_all(&addresses)) {
+            match response {
+                Ok(ref response) if response.node.id == node.id => {
+                    self.missed_pings.lock().unwrap().remove(&node.id);
+
+                    let mut reported_disk_watermarks = self.reported_disk_watermarks.lock().unwrap();
+                    match response.disk_watermark {
+                        Some(watermark) => reported_disk_watermarks.insert(node.id.clone(), watermark),
+                        None => reported_disk_watermarks.remove(&node.id),
+                    };
+                }
+                Ok(_) | Err(_) => {
+                    if self.missed_ping(&node.id) {
+                        left.push(node.clone());
+                    }
+                }
+            }
+        }
+
+        if left.is_empty() {
+            return;
+        }
+
+        let _publishing = self.publish_lock.lock().unwrap();
+        let state = {
+            let mut state = self.state.write().unwrap();
+            for node in left.iter() {
+                state.nodes.remove(&node.id);
+                warn!(system.log, "node left"; "node" => &node.name, "reason" => "stopped answering pings");
+            }
+
+            // Promotes replicas of the primaries that were on the nodes that left
+            state.reroute();
+            state.version += 1;
+            state.clone()
+        };
+        for node in left.iter() {
+            self.missed_pings.lock().unwrap().remove(&node.id);
+            self.reported_disk_watermarks.lock().unwrap().remove(&node.id);
+        }
+
+        self.publish(system, &state, &[]);
+    }
+
+    /// Goes back to discovery if the master has gone away. Rejoins if it has published
+    /// states this node missed
+    fn check_master(&self, system: &System) {
+        let master = match self.master() {
+            Some(master) => master,
+            None => return,
+        };
+        let version = self.state.read().unwrap().version;
+
+        let response = self.ping_all(&[master.address.clone()]).pop().unwrap_or_else(|| Err("no response".to_string()));
+        match response {
+            Ok(ref response) if response.node.id == master.id && response.is_master() => {
+                self.missed_pings.lock().unwrap().remove(&master.id);
+
+                if response.version > version {
+                    if let Err(e) = self.join(system, &master) {
+                        warn!(system.log, "failed to rejoin master"; "master" => &master.name, "error" => e);
+                    }
+                }
+
+                return;
+            }
+            Ok(_) => {}
+            Err(_) => {
+                if !self.missed_ping(&master.id) {
+                    return;
+                }
+            }
+        }
+
+        warn!(system.log, "master left"; "master" => &master.name);
+        let mut state = self.state.write().unwrap();
+        state.master_node = None;
+        state.nodes.clear();
+        self.missed_pings.lock().unwrap().clear();
+    }
+
+    /// Sends a request that changes the cluster state on to the master
+    pub fn forward_to_master(&self, request: TransportRequest) -> Result<TransportResponse, String> {
+        self.transport.send(request, PUBLISH_TIMEOUT)
+    }
+
+    /// Tells the master that a replica has finished recovering from its primary
+    pub fn shard_started(&self, system: &System, index_name: &str, node_id: &str) -> Result<(), String> {
+        self.send_shard_state(system, SHARD_STARTED_PATH, index_name, node_id, "")
+    }
+
+    /// Tells the master that a copy of an index couldn't apply a write or be recovered
+    pub fn shard_failed(&self, system: &System, index_name: &str, node_id: &str, reason: &str) -> Result<(), String> {
+        self.send_shard_state(system, SHARD_FAILED_PATH, index_name, node_id, reason)
+    }
+
+    fn send_shard_state(&self, system: &System, path: &str, index_name: &str, node_id: &str, reason: &str) -> Result<(), String> {
+        if self.is_master() {
+            return if path == SHARD_STARTED_PATH {
+                self.handle_shard_started(system, index_name, node_id)
+            } else {
+                self.handle_shard_failed(system, index_name, node_id, reason)
+            };
+        }
+
+        let master = self.master().ok_or("no master node has been elected")?;
+        let body = json!({"index": index_name, "node": node_id, "reason": reason});
+        parse_json_response(self.transport.send(TransportRequest::new(&master.address, Method::POST, path).with_json(&body), PUBLISH_TIMEOUT))?;
+        Ok(())
+    }
+
+    /// Marks a replica as started, so it serves reads and counts towards
+    /// `wait_for_active_shards`. Only the master takes these
+    pub fn handle_shard_started(&self, system: &System, index_name: &str, node_id: &str) -> Result<(), String> {
+        let _publishing = self.publish_lock.lock().unwrap();
+        if !self.is_master() {
+            return Err("this node isn't the master".to_string());
+        }
+
+        let state = {
+            let mut state = self.state.write().unwrap();
+            let replica = state.routing.get_mut(index_name).and_then(|routing| routing.replicas.iter_mut().find(|replica| replica.node == node_id));
+            match replica {
+                Some(replica) if !replica.started => replica.started = true,
+                Some(_) => return Ok(()),
+                None => return Err(format!("index [{}] has no replica on node [{}]", index_name, node_id)),
+            }
+
+            state.version += 1;
+            state.clone()
+        };
+
+        info!(system.log, "replica started"; "index" => index_name, "node" => node_id, "version" => state.version);
+        self.publish(system, &state, &[]);
+
+        Ok(())
+    }
+
+    /// Takes a failed copy of an index out of the routing. Only the master takes these
+    ///
+    /// A replica takes over from a failed primary, and the copy is assigned again, so it's
+    /// recovered from scratch.
+    pub fn handle_shard_failed(&self, system: &System, index_name: &str, node_id: &str, reason: &str) -> Result<(), String> {
+        let _publishing = self.publish_lock.lock().unwrap();
+        if !self.is_master() {
+            return Err("this node isn't the master".to_string());
+        }
+
+        let state = {
+            let mut state = self.state.write().unwrap();
+            match state.routing.get_mut(index_name) {
+                Some(routing) => {
+                    if routing.primary.as_ref().map(|node| node.as_str()) == Some(node_id) {
+                        routing.primary = None;
+                    }
+
+                    routing.replicas.retain(|replica| replica.node != node_id);
+                }
+                None => return Ok(()),
+            }
+
+            state.reroute();
+            state.version += 1;
+            state.clone()
+        };
+
+        warn!(system.log, "shard failed"; "index" => index_name, "node" => node_id, "reason" => reason, "version" => state.version);
+        self.publish(system, &state, &[]);
+
+        Ok(())
+    }
+}
+
+
+/// Brings the node's indices in line with a state published by the master
+///
+/// Indices that were in the previous state but aren't in this one have been deleted. Indices
+/// that have never been in a state are left alone.
+fn apply_indices(system: &System, previous_indices: &BTreeMap<String, IndexState>, indices: &BTreeMap<String, IndexState>) {
+    let mut cluster_metadata = system.metadata.write().unwrap();
+
+    for index_name in previous_indices.keys() {
+        if indices.contains_key(index_name) {
+            continue;
+        }
+
+        if let Some(index_ref) = cluster_metadata.names.find_canonical(index_name) {
+            system.delete_index(&mut cluster_metadata, index_ref);
+        }
+    }
+
+    for (index_name, index_state) in indices.iter() {
+        if let Err(e) = apply_index(system, &mut cluster_metadata, index_name, index_state) {
+            error!(system.log, "failed to apply cluster state to index"; "index" => index_name, "error" => e);
+        }
+    }
+}
+
+
+fn apply_index(system: &System, cluster_metadata: &mut ClusterMetadata, index_name: &str, index_state: &IndexState) -> Result<(), String> {
+    let mut metadata = IndexMetadata::default();
+    parse_index_metadata(&mut metadata, index_state.metadata.clone()).map_err(|e| format!("invalid index metadata: {:?}", e))?;
+
+    let index_ref = match cluster_metadata.names.find_canonical(index_name) {
+        Some(index_ref) => {
+            let current = cluster_metadata.with_index_metadata(&index_ref, |metadata| serde_json::to_value(metadata).unwrap_or(Json::Null));
+            if current.as_ref() != Some(&index_state.metadata) {
+                replace_index_metadata(cluster_metadata, index_ref, metadata)?;
+                info!(system.log, "updated index metadata from cluster state"; "index" => index_name);
+            }
+
+            index_ref
+        }
+        None => {
+            let mappings = mem::replace(&mut metadata.mappings, Default::default());
+            let index_ref = system.create_index(cluster_metadata, index_name, metadata)?;
+            link_mappings(cluster_metadata, index_ref, mappings)?;
+            index_ref
+        }
+    };
+
+    if index_state.open && cluster_metadata.is_closed(&index_ref) {
+        system.open_index(cluster_metadata, index_ref)?;
+    } else if !index_state.open && !cluster_metadata.is_closed(&index_ref) {
+        system.close_index(cluster_metadata, index_ref).map_err(|e| format!("failed to close index: {:?}", e))?;
+    }
+
+    Ok(())
+}
+
+
+/// Adds the fields of an open index's mappings to its store, then sets them on its metadata
+fn link_mappings(cluster_metadata: &ClusterMetadata, index_ref: IndexRef, mut mappings: HashMap<String, Mapping>) -> Result<(), String> {
+    let index = match cluster_metadata.indices.get(&index_ref) {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+
+    for mapping in mappings.values_mut() {
+        index.add_mapping_fields(mapping)?;
+    }
+
+    let mut index_metadata = index.metadata.write().unwrap();
+    index_metadata.mappings = mappings;
+    index_metadata.save(index.metadata_path()).map_err(String::from)
+}
+
+
+/// Swaps an existing index's metadata for the master's, and registers its aliases again
+fn replace_index_metadata(cluster_metadata: &mut ClusterMetadata, index_ref: IndexRef, mut metadata: IndexMetadata) -> Result<(), String> {
+    let alias_names = cluster_metadata.names.iter_index_aliases(index_ref).map(|name| name.to_string()).collect::<Vec<_>>();
+    for alias_name in alias_names {
+        let _ = cluster_metadata.names.delete_alias(&alias_name, index_ref);
+    }
+
+    if cluster_metadata.is_closed(&index_ref) {
+        cluster_metadata.with_index_metadata_mut(&index_ref, |index_metadata| *index_metadata = metadata);
+        cluster_metadata.save_index_metadata(&index_ref).map_err(String::from)?;
+    } else {
+        let mappings = mem::replace(&mut metadata.mappings, Default::default());
+        if let Some(index) = cluster_metadata.indices.get(&index_ref) {
+            let mut index_metadata = index.metadata.write().unwrap();
+            *index_metadata = metadata;
+            index.apply_settings(&index_metadata.settings);
+        }
+
+        link_mappings(cluster_metadata, index_ref, mappings)?;
+    }
+
+    cluster_metadata.register_aliases(index_ref);
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! Works out the health of the node from the state of its indices
+//!
+//! The shards of an index are all loaded together, and its copies are assigned to nodes as a
+//! whole (see `routing`), so each copy of an index is counted as one shard. An index is red
+//! if it couldn't be loaded or has no primary, yellow while it's still loading or while any
+//! of its replicas are being recovered or have no node to go on, and green otherwise.
+//!
+//! The nodes whose data disks are over a disk watermark are listed alongside, as they may
+//! not be able to take any more copies or writes (see `disk_usage`).
+
+use std::collections::BTreeMap;
+
+use disk_usage::DiskWatermark;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Green,
+    Yellow,
+    Red,
+}
+
+
+impl HealthStatus {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HealthStatus::Green => "green",
+            HealthStatus::Yellow => "yellow",
+            HealthStatus::Red => "red",
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct ClusterHealth {
+    pub status: HealthStatus,
+
+    /// Open indices that have been loaded
+    pub active_primary_shards: usize,
+
+    /// Loaded indices and started replicas
+    pub active_shards: usize,
+
+    /// Indices that are still being loaded and replicas that are being recovered
+    pub initializing_shards: usize,
+
+    /// Indices that couldn't be loaded or have no primary, and replicas that have no node
+    pub unassigned_shards: usize,
+
+    pub open_indices: usize,
+
+    pub closed_indices: usize,
+
+    /// Documents in all of the loaded indices
+    pub docs: u64,
+
+    /// Tasks, such as searches and reindexes, that haven't finished yet
+    pub pending_tasks: usize,
+
+    /// The disk watermark that each node is over, by node name. Nodes under all of them
+    /// aren't listed
+    pub disk_watermarks: BTreeMap<String, DiskWatermark>,
+}
+
+
+impl ClusterHealth {
+    pub fn new(active_shards: usize, initializing_shards: usize, unassigned_shards: usize) -> ClusterHealth {
+        let status = if unassigned_shards > 0 {
+            HealthStatus::Red
+        } else if initializing_shards > 0 {
+            HealthStatus::Yellow
+        } else {
+            HealthStatus::Green
+        };
+
+        ClusterHealth {
+            status: status,
+            active_primary_shards: active_shards,
+            active_shards: active_shards,
+            initializing_shards: initializing_shards,
+            unassigned_shards: unassigned_shards,
+            open_indices: active_shards + initializing_shards + unassigned_shards,
+            closed_indices: 0,
+            docs: 0,
+            pending_tasks: 0,
+            disk_watermarks: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the replicas of the open indices. Any that aren't started turn green to yellow
+    pub fn add_replicas(&mut self, active: usize, initializing: usize, unassigned: usize) {
+        self.active_shards += active;
+        self.initializing_shards += initializing;
+        self.unassigned_shards += unassigned;
+
+        if self.status == HealthStatus::Green && initializing + unassigned > 0 {
+            self.status = HealthStatus::Yellow;
+        }
+    }
+
+    pub fn number_of_indices(&self) -> usize {
+        self.open_indices + self.closed_indices
+    }
+
+    /// The percentage of copies of open indices that are active
+    pub fn active_shards_percent(&self) -> f64 {
+        let total = self.active_shards + self.initializing_shards + self.unassigned_shards;
+
+        if total == 0 {
+            100.0
+        } else {
+            self.active_shards as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ClusterHealth, HealthStatus};
+
+    #[test]
+    fn test_status() {
+        assert_eq!(ClusterHealth::new(0, 0, 0).status, HealthStatus::Green);
+        assert_eq!(ClusterHealth::new(2, 0, 0).status, HealthStatus::Green);
+        assert_eq!(ClusterHealth::new(2, 1, 0).status, HealthStatus::Yellow);
+        assert_eq!(ClusterHealth::new(2, 1, 1).status, HealthStatus::Red);
+    }
+
+    #[test]
+    fn test_replicas() {
+        let mut health = ClusterHealth::new(2, 0, 0);
+        health.add_replicas(2, 0, 0);
+        assert_eq!(health.status, HealthStatus::Green);
+        assert_eq!(health.active_primary_shards, 2);
+        assert_eq!(health.active_shards, 4);
+
+        health.add_replicas(0, 1, 0);
+        assert_eq!(health.status, HealthStatus::Yellow);
+        assert_eq!(health.number_of_indices(), 2);
+
+        let mut health = ClusterHealth::new(1, 0, 1);
+        health.add_replicas(0, 0, 1);
+        assert_eq!(health.status, HealthStatus::Red);
+    }
+
+    #[test]
+    fn test_active_shards_percent() {
+        assert_eq!(ClusterHealth::new(0, 0, 0).active_shards_percent(), 100.0);
+        assert_eq!(ClusterHealth::new(3, 1, 0).active_shards_percent(), 75.0);
+    }
+}
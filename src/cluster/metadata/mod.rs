@@ -1,10 +1,15 @@
 pub mod name_registry;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use uuid::Uuid;
 
-use index::Index;
+use index::{Index, ClosedIndex};
+use source_filter::wildcard_match;
+use index::metadata::IndexMetadata;
+use index::metadata::alias::AliasMetadata;
+use index::metadata::file::SaveIndexMetadataError;
 
 use self::name_registry::NameRegistry;
 
@@ -20,9 +25,59 @@ impl IndexRef {
 }
 
 
+/// Why a name couldn't be resolved to an index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResolveError {
+    NotFound,
+    Closed,
+
+    /// The name is an alias of more than one index
+    MultipleIndices,
+
+    /// The name is an alias that doesn't have an index to write to
+    NoWriteIndex,
+
+    /// The name is an alias, where only index names are allowed
+    IsAlias,
+}
+
+
+/// How an expression naming indices is resolved. Set from the `ignore_unavailable` and
+/// `allow_no_indices` URL parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndicesOptions {
+    /// Skip names that don't exist or refer to closed indices, rather than failing
+    pub ignore_unavailable: bool,
+
+    /// Whether wildcards (and the expression as a whole) are allowed to match nothing
+    pub allow_no_indices: bool,
+
+    /// Return closed indices as well as open ones. Wildcards match them too
+    pub include_closed: bool,
+
+    /// Only allow index names. Naming an alias is an error and wildcards don't match aliases
+    pub ignore_aliases: bool,
+}
+
+
+impl Default for IndicesOptions {
+    fn default() -> IndicesOptions {
+        IndicesOptions {
+            ignore_unavailable: false,
+            allow_no_indices: true,
+            include_closed: false,
+            ignore_aliases: false,
+        }
+    }
+}
+
+
 #[derive(Debug)]
 pub struct ClusterMetadata {
-    pub indices: HashMap<IndexRef, Index>,
+    /// Open indices. They're shared so requests can keep using an index after unlocking the
+    /// cluster metadata, which mustn't be locked again while an index is held
+    pub indices: HashMap<IndexRef, Arc<Index>>,
+    pub closed_indices: HashMap<IndexRef, ClosedIndex>,
     pub names: NameRegistry,
 }
 
@@ -31,14 +86,239 @@ impl ClusterMetadata {
     pub fn new() -> ClusterMetadata {
         ClusterMetadata {
             indices: HashMap::new(),
+            closed_indices: HashMap::new(),
             names: NameRegistry::new(),
         }
     }
 
     pub fn insert_index(&mut self, index: Index) -> IndexRef {
         let index_ref = IndexRef(index.id().clone());
-        self.indices.insert(index_ref, index);
+        self.indices.insert(index_ref, Arc::new(index));
+
+        index_ref
+    }
+
+    pub fn insert_closed_index(&mut self, index: ClosedIndex) -> IndexRef {
+        let index_ref = IndexRef(index.id().clone());
+        self.closed_indices.insert(index_ref, index);
 
         index_ref
     }
+
+    pub fn is_closed(&self, index_ref: &IndexRef) -> bool {
+        self.closed_indices.contains_key(index_ref)
+    }
+
+    /// Finds how an alias applies to one of its indices, whether the index is open or closed
+    pub fn get_alias_metadata(&self, index_ref: &IndexRef, alias_name: &str) -> Option<AliasMetadata> {
+        if let Some(index) = self.indices.get(index_ref) {
+            return index.metadata.read().unwrap().aliases.get(alias_name).cloned();
+        }
+
+        self.closed_indices.get(index_ref).and_then(|index| index.metadata.aliases.get(alias_name).cloned())
+    }
+
+    pub fn index_name(&self, index_ref: &IndexRef) -> Option<&str> {
+        if let Some(index) = self.indices.get(index_ref) {
+            return Some(index.canonical_name());
+        }
+
+        self.closed_indices.get(index_ref).map(|index| index.canonical_name())
+    }
+
+    /// Runs a function against an index's metadata, whether the index is open or closed
+    pub fn with_index_metadata<T, F: FnOnce(&IndexMetadata) -> T>(&self, index_ref: &IndexRef, f: F) -> Option<T> {
+        if let Some(index) = self.indices.get(index_ref) {
+            return Some(f(&index.metadata.read().unwrap()));
+        }
+
+        self.closed_indices.get(index_ref).map(|index| f(&index.metadata))
+    }
+
+    /// Runs a function against an index's metadata, allowing it to be changed
+    ///
+    /// The metadata isn't saved, use `save_index_metadata` for that
+    pub fn with_index_metadata_mut<T, F: FnOnce(&mut IndexMetadata) -> T>(&mut self, index_ref: &IndexRef, f: F) -> Option<T> {
+        if let Some(index) = self.indices.get(index_ref) {
+            return Some(f(&mut index.metadata.write().unwrap()));
+        }
+
+        self.closed_indices.get_mut(index_ref).map(|index| f(&mut index.metadata))
+    }
+
+    /// Writes an index's metadata to its metadata.json file
+    pub fn save_index_metadata(&self, index_ref: &IndexRef) -> Result<(), SaveIndexMetadataError> {
+        if let Some(index) = self.indices.get(index_ref) {
+            return index.metadata.read().unwrap().save(index.metadata_path());
+        }
+
+        match self.closed_indices.get(index_ref) {
+            Some(index) => index.metadata.save(index.metadata_path()),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the aliases in an index's metadata to the name registry
+    ///
+    /// Returns the names of any aliases that couldn't be added because there's an index
+    /// with the same name.
+    pub fn register_aliases(&mut self, index_ref: IndexRef) -> Vec<String> {
+        let alias_names = self.with_index_metadata(&index_ref, |metadata| metadata.aliases.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+
+        alias_names.into_iter().filter(|alias_name| self.names.add_alias_index(alias_name.clone(), index_ref).is_err()).collect()
+    }
+
+    /// Finds the index an alias explicitly marks as its write index
+    pub fn alias_write_index(&self, alias_name: &str) -> Option<IndexRef> {
+        if !self.names.is_alias(alias_name) {
+            return None;
+        }
+
+        self.names.find(alias_name).into_iter().find(|index_ref| {
+            self.get_alias_metadata(index_ref, alias_name).and_then(|alias| alias.is_write_index) == Some(true)
+        })
+    }
+
+    fn check_open(&self, index_ref: IndexRef) -> Result<IndexRef, ResolveError> {
+        if self.is_closed(&index_ref) {
+            Err(ResolveError::Closed)
+        } else if self.indices.contains_key(&index_ref) {
+            Ok(index_ref)
+        } else {
+            Err(ResolveError::NotFound)
+        }
+    }
+
+    /// Finds the open index that an expression refers to, which must only match one index
+    ///
+    /// This goes through `resolve_indices`, so the expression may be an alias or a wildcard
+    /// pattern as long as it matches a single index.
+    pub fn resolve_index(&self, expression: &str) -> Result<IndexRef, ResolveError> {
+        let indices = self.resolve_indices(expression, &IndicesOptions::default()).map_err(|(_, e)| e)?;
+
+        match indices.len() {
+            0 => Err(ResolveError::NotFound),
+            1 => Ok(indices[0].0),
+            _ => Err(ResolveError::MultipleIndices),
+        }
+    }
+
+    /// Finds the indices that a comma separated list of names, aliases and wildcard patterns
+    /// refers to. `_all` matches every index
+    ///
+    /// Each index is returned with the name it was found through, so the filter of an alias
+    /// can be applied. An index found through its own name has no filter, so that takes
+    /// precedence over any aliases. Only open indices are returned unless `options` includes
+    /// closed ones. Names that can't be resolved are returned with the error.
+    pub fn resolve_indices(&self, expression: &str, options: &IndicesOptions) -> Result<Vec<(IndexRef, String)>, (String, ResolveError)> {
+        let mut found: Vec<(IndexRef, String)> = Vec::new();
+        {
+            let mut add = |index_ref: IndexRef, name: &str| {
+                let is_canonical = self.names.find_canonical(name).is_some();
+
+                match found.iter().position(|&(found_index_ref, _)| found_index_ref == index_ref) {
+                    Some(position) => {
+                        if is_canonical {
+                            found[position].1 = name.to_string();
+                        }
+                    }
+                    None => found.push((index_ref, name.to_string())),
+                }
+            };
+
+            let is_included = |index_ref: &IndexRef| {
+                self.indices.contains_key(index_ref) || (options.include_closed && self.closed_indices.contains_key(index_ref))
+            };
+
+            for name in expression.split(',') {
+                if name == "_all" || name.contains('*') {
+                    let pattern = if name == "_all" { "*" } else { name };
+
+                    // Sorted by name, so results are returned in a consistent order
+                    let mut matched_indices = self.indices.keys().chain(self.closed_indices.keys())
+                        .filter(|index_ref| is_included(index_ref))
+                        .filter_map(|index_ref| self.index_name(index_ref).map(|index_name| (*index_ref, index_name)))
+                        .filter(|&(_, index_name)| wildcard_match(pattern, index_name))
+                        .collect::<Vec<_>>();
+                    matched_indices.sort_by_key(|&(_, index_name)| index_name);
+
+                    let mut matched_aliases = self.names.aliases().into_iter()
+                        .filter(|&(alias_name, _)| !options.ignore_aliases && wildcard_match(pattern, alias_name))
+                        .collect::<Vec<_>>();
+                    matched_aliases.sort_by_key(|&(alias_name, _)| alias_name);
+
+                    let mut matched = false;
+                    for (index_ref, index_name) in matched_indices {
+                        add(index_ref, index_name);
+                        matched = true;
+                    }
+
+                    for (alias_name, index_refs) in matched_aliases {
+                        for index_ref in index_refs.iter().filter(|index_ref| is_included(index_ref)) {
+                            add(*index_ref, alias_name);
+                            matched = true;
+                        }
+                    }
+
+                    if !matched && !options.allow_no_indices {
+                        return Err((name.to_string(), ResolveError::NotFound));
+                    }
+
+                    continue;
+                }
+
+                if options.ignore_aliases && self.names.is_alias(name) {
+                    return Err((name.to_string(), ResolveError::IsAlias));
+                }
+
+                let index_refs = self.names.find(name);
+                if index_refs.is_empty() {
+                    if options.ignore_unavailable {
+                        continue;
+                    }
+
+                    return Err((name.to_string(), ResolveError::NotFound));
+                }
+
+                for index_ref in index_refs {
+                    if is_included(&index_ref) {
+                        add(index_ref, name);
+                    } else if !options.ignore_unavailable {
+                        return Err((name.to_string(), self.check_open(index_ref).err().unwrap_or(ResolveError::NotFound)));
+                    }
+                }
+            }
+        }
+
+        if found.is_empty() && !options.allow_no_indices {
+            return Err((expression.to_string(), ResolveError::NotFound));
+        }
+
+        Ok(found)
+    }
+
+    /// Finds the open index that writes to a name should go to
+    ///
+    /// If the name is an alias, this is the index it marks as its write index. An alias of
+    /// a single index can be written to unless the index opts out with `is_write_index: false`.
+    pub fn resolve_write_index(&self, name: &str) -> Result<IndexRef, ResolveError> {
+        // Writes always go to a single named index, so wildcards aren't expanded
+        if !self.names.is_alias(name) {
+            return match self.names.find_canonical(name) {
+                Some(index_ref) => self.check_open(index_ref),
+                None => Err(ResolveError::NotFound),
+            };
+        }
+
+        if let Some(index_ref) = self.alias_write_index(name) {
+            return self.check_open(index_ref);
+        }
+
+        let index_refs = self.names.find(name);
+        if index_refs.len() == 1 && self.get_alias_metadata(&index_refs[0], name).and_then(|alias| alias.is_write_index) != Some(false) {
+            return self.check_open(index_refs[0]);
+        }
+
+        Err(ResolveError::NoWriteIndex)
+    }
 }
@@ -78,6 +78,26 @@ impl NameRegistry {
         }
     }
 
+    /// Adds an index to an alias, creating the alias if it doesn't exist yet
+    ///
+    /// Returns true if the alias was created.
+    pub fn add_alias_index(&mut self, name: String, index_ref: IndexRef) -> Result<bool, ()> {
+        match self.names.get_mut(&name) {
+            Some(&mut Name::Alias(ref mut indices)) => {
+                if !indices.contains(&index_ref) {
+                    indices.push(index_ref);
+                }
+
+                return Ok(false);
+            }
+            Some(&mut Name::Canonical(_)) => return Err(()),
+            None => {}
+        }
+
+        self.names.insert(name, Name::Alias(vec![index_ref]));
+        Ok(true)
+    }
+
     pub fn delete_alias(&mut self, name: &str, index_ref: IndexRef) -> Result<bool, ()> {
         let mut remove_alias = false;
 
@@ -143,6 +163,23 @@ impl NameRegistry {
         }
     }
 
+    pub fn is_alias(&self, name: &str) -> bool {
+        match self.names.get(name) {
+            Some(&Name::Alias(_)) => true,
+            Some(&Name::Canonical(_)) | None => false,
+        }
+    }
+
+    /// Lists every alias along with the indices it points to
+    pub fn aliases(&self) -> Vec<(&str, &[IndexRef])> {
+        self.names.iter().filter_map(|(name, value)| {
+            match *value {
+                Name::Alias(ref indices) => Some((name.as_str(), &indices[..])),
+                Name::Canonical(_) => None,
+            }
+        }).collect()
+    }
+
     pub fn iter_index_aliases<'a>(&'a self, index_ref: IndexRef) -> IndexAliasesIterator<'a> {
         IndexAliasesIterator {
             index_ref: index_ref,
@@ -1 +1,7 @@
 pub mod metadata;
+pub mod health;
+pub mod state;
+pub mod transport;
+pub mod routing;
+pub mod coordinator;
+pub mod settings;
@@ -0,0 +1,283 @@
+//! Decides which nodes hold the copies of each index
+//!
+//! Every open index has a primary copy, which all writes go to first, and `number_of_replicas`
+//! replica copies on other nodes that the primary copies its writes to (see `replication`).
+//! The shards of an index are always kept together, so an index's copies are assigned to
+//! nodes as a whole.
+//!
+//! A new replica starts out initializing, while the primary copies its documents across. It
+//! starts serving reads once the master has been told it's finished. If the node holding a
+//! primary leaves the cluster, one of the started replicas is promoted in its place.
+//!
+//! New copies aren't put on nodes whose data disk is too full. Replicas aren't put on nodes
+//! over the low disk watermark, and no copies at all on nodes over the high watermark (see
+//! `disk_usage`). Copies that are already on a node stay there.
+
+use std::collections::BTreeMap;
+
+use disk_usage::DiskWatermark;
+
+
+/// A replica copy of an index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaRouting {
+    /// Id of the node it's on
+    pub node: String,
+
+    /// False while it's being recovered from the primary
+    pub started: bool,
+}
+
+
+/// Where the copies of an index are
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexRouting {
+    /// Id of the node holding the primary. None if there's no node to put it on
+    pub primary: Option<String>,
+
+    pub replicas: Vec<ReplicaRouting>,
+}
+
+
+impl IndexRouting {
+    /// Ids of the nodes with a copy that can serve reads, starting with the primary
+    pub fn active_nodes(&self) -> Vec<&str> {
+        let mut nodes = self.primary.iter().map(|node| node.as_str()).collect::<Vec<_>>();
+        nodes.extend(self.replicas.iter().filter(|replica| replica.started).map(|replica| replica.node.as_str()));
+        nodes
+    }
+
+    /// Checks if the node has a copy that can serve reads
+    pub fn is_active_on(&self, node_id: &str) -> bool {
+        self.active_nodes().contains(&node_id)
+    }
+
+    /// Ids of the nodes with a replica, including the ones that are still initializing
+    pub fn replica_nodes(&self) -> Vec<&str> {
+        self.replicas.iter().map(|replica| replica.node.as_str()).collect()
+    }
+
+    /// The node's replica, if it has one
+    pub fn replica_on(&self, node_id: &str) -> Option<&ReplicaRouting> {
+        self.replicas.iter().find(|replica| replica.node == node_id)
+    }
+
+    /// Counts the copies that there aren't enough nodes for
+    pub fn number_of_unassigned(&self, number_of_replicas: usize) -> usize {
+        (1 + number_of_replicas).saturating_sub(self.primary.iter().count() + self.replicas.len())
+    }
+
+    /// Checks if the node has any copy of the index
+    fn is_on(&self, node_id: &str) -> bool {
+        self.primary.as_ref().map(|node| node.as_str()) == Some(node_id) || self.replicas.iter().any(|replica| replica.node == node_id)
+    }
+}
+
+
+/// Picks the node with the fewest copies of any index, leaving out nodes that already have
+/// a copy of this one
+///
+/// If `primaries` is given, the node with the fewest primaries is picked first, so the work
+/// of indexing is spread out too.
+fn least_loaded<'a>(nodes: &[&'a str], load: &BTreeMap<&'a str, usize>, primaries: Option<&BTreeMap<&'a str, usize>>, routing: &IndexRouting) -> Option<&'a str> {
+    let count = |counts: &BTreeMap<&'a str, usize>, node: &'a str| counts.get(node).cloned().unwrap_or(0);
+
+    nodes.iter().cloned()
+        .filter(|node| !routing.is_on(node))
+        .min_by_key(|&node| (primaries.map_or(0, |primaries| count(primaries, node)), count(load, node), node))
+}
+
+
+/// Finds a node's id in the list of nodes
+fn find_node<'a>(nodes: &[&'a str], node_id: &str) -> Option<&'a str> {
+    nodes.iter().cloned().find(|node| *node == node_id)
+}
+
+
+/// Works out where the copies of each index should be, given where they were before
+///
+/// `indices` has the name and number of replicas of each open index, and `nodes` has the
+/// ids of the nodes in the cluster. Copies stay where they are while their node is in the
+/// cluster. If a primary's node has gone, the first started replica takes over. If there
+/// isn't one, a new primary is put on another node, which starts from whatever that node
+/// has of the index. Replicas are added to the nodes with the fewest copies, and never to a
+/// node that already has a copy of the index, so there can be at most one fewer replicas
+/// than nodes.
+///
+/// `disk_watermarks` has the disk watermark that each node is over, if any.
+pub fn allocate(indices: &[(&str, usize)], nodes: &[&str], previous: &BTreeMap<String, IndexRouting>, disk_watermarks: &BTreeMap<String, DiskWatermark>) -> BTreeMap<String, IndexRouting> {
+    let below = |node: &str, watermark: DiskWatermark| disk_watermarks.get(node).map_or(true, |exceeded| *exceeded < watermark);
+    let primary_nodes = nodes.iter().cloned().filter(|node| below(*node, DiskWatermark::High)).collect::<Vec<_>>();
+    let replica_nodes = nodes.iter().cloned().filter(|node| below(*node, DiskWatermark::Low)).collect::<Vec<_>>();
+
+    let mut routing_table = BTreeMap::new();
+    let mut load = BTreeMap::new();
+    let mut primaries = BTreeMap::new();
+
+    // Keep the copies on nodes that are still here
+    for &(index_name, _) in indices {
+        let mut routing = previous.get(index_name).cloned().unwrap_or_default();
+        routing.replicas.retain(|replica| nodes.contains(&replica.node.as_str()));
+
+        if routing.primary.as_ref().map_or(false, |node| !nodes.contains(&node.as_str())) {
+            routing.primary = None;
+        }
+
+        if routing.primary.is_none() {
+            match routing.replicas.iter().position(|replica| replica.started) {
+                Some(position) => routing.primary = Some(routing.replicas.remove(position).node),
+
+                // Initializing replicas are recovered from the primary, so they'd have to
+                // start again from a new one anyway
+                None => routing.replicas.clear(),
+            }
+        }
+
+        if let Some(node) = routing.primary.as_ref().and_then(|node| find_node(nodes, node)) {
+            *primaries.entry(node).or_insert(0) += 1;
+        }
+
+        for node in routing.primary.iter().chain(routing.replicas.iter().map(|replica| &replica.node)) {
+            if let Some(node) = find_node(nodes, node) {
+                *load.entry(node).or_insert(0) += 1;
+            }
+        }
+
+        routing_table.insert(index_name.to_string(), routing);
+    }
+
+    // Then assign the copies that are missing
+    for &(index_name, number_of_replicas) in indices {
+        let routing = routing_table.get_mut(index_name).unwrap();
+
+        if routing.primary.is_none() {
+            if let Some(node) = least_loaded(&primary_nodes, &load, Some(&primaries), routing) {
+                routing.primary = Some(node.to_string());
+                *primaries.entry(node).or_insert(0) += 1;
+                *load.entry(node).or_insert(0) += 1;
+            }
+        }
+
+        // Drop extra replicas if number_of_replicas was lowered, keeping the started ones
+        if routing.replicas.len() > number_of_replicas {
+            routing.replicas.sort_by_key(|replica| !replica.started);
+            for replica in routing.replicas.drain(number_of_replicas..) {
+                if let Some(node) = find_node(nodes, &replica.node) {
+                    *load.entry(node).or_insert(1) -= 1;
+                }
+            }
+        }
+
+        while routing.primary.is_some() && routing.replicas.len() < number_of_replicas {
+            let node = match least_loaded(&replica_nodes, &load, None, routing) {
+                Some(node) => node,
+                None => break,
+            };
+
+            routing.replicas.push(ReplicaRouting {
+                node: node.to_string(),
+                started: false,
+            });
+            *load.entry(node).or_insert(0) += 1;
+        }
+    }
+
+    routing_table
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use disk_usage::DiskWatermark;
+
+    use super::{IndexRouting, ReplicaRouting, allocate};
+
+    fn routing(primary: Option<&str>, replicas: &[(&str, bool)]) -> IndexRouting {
+        IndexRouting {
+            primary: primary.map(|node| node.to_string()),
+            replicas: replicas.iter().map(|&(node, started)| ReplicaRouting { node: node.to_string(), started: started }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_allocate_new_indices() {
+        let routing_table = allocate(&[("a", 1), ("b", 1)], &["n1", "n2"], &BTreeMap::new(), &BTreeMap::new());
+
+        assert_eq!(routing_table["a"], routing(Some("n1"), &[("n2", false)]));
+        assert_eq!(routing_table["b"], routing(Some("n2"), &[("n1", false)]));
+    }
+
+    #[test]
+    fn test_allocate_keeps_copies() {
+        let mut previous = BTreeMap::new();
+        previous.insert("a".to_string(), routing(Some("n2"), &[("n1", true)]));
+
+        assert_eq!(allocate(&[("a", 1)], &["n1", "n2", "n3"], &previous, &BTreeMap::new())["a"], routing(Some("n2"), &[("n1", true)]));
+    }
+
+    #[test]
+    fn test_allocate_promotes_started_replica() {
+        let mut previous = BTreeMap::new();
+        previous.insert("a".to_string(), routing(Some("n1"), &[("n2", false), ("n3", true)]));
+
+        assert_eq!(allocate(&[("a", 2)], &["n2", "n3"], &previous, &BTreeMap::new())["a"], routing(Some("n3"), &[("n2", false)]));
+    }
+
+    #[test]
+    fn test_allocate_without_started_replica() {
+        let mut previous = BTreeMap::new();
+        previous.insert("a".to_string(), routing(Some("n1"), &[("n2", false)]));
+
+        // The initializing replica isn't promoted, but it's the only node left
+        assert_eq!(allocate(&[("a", 1)], &["n2"], &previous, &BTreeMap::new())["a"], routing(Some("n2"), &[]));
+    }
+
+    #[test]
+    fn test_allocate_not_enough_nodes() {
+        let routing_table = allocate(&[("a", 2)], &["n1", "n2"], &BTreeMap::new(), &BTreeMap::new());
+
+        assert_eq!(routing_table["a"], routing(Some("n1"), &[("n2", false)]));
+        assert_eq!(routing_table["a"].number_of_unassigned(2), 1);
+        assert_eq!(allocate(&[("a", 1)], &[], &BTreeMap::new(), &BTreeMap::new())["a"], routing(None, &[]));
+    }
+
+    #[test]
+    fn test_allocate_fewer_replicas() {
+        let mut previous = BTreeMap::new();
+        previous.insert("a".to_string(), routing(Some("n1"), &[("n2", false), ("n3", true)]));
+
+        assert_eq!(allocate(&[("a", 1)], &["n1", "n2", "n3"], &previous, &BTreeMap::new())["a"], routing(Some("n1"), &[("n3", true)]));
+        assert_eq!(allocate(&[("a", 0)], &["n1", "n2", "n3"], &previous, &BTreeMap::new())["a"], routing(Some("n1"), &[]));
+    }
+
+    #[test]
+    fn test_allocate_disk_watermarks() {
+        let mut disk_watermarks = BTreeMap::new();
+        disk_watermarks.insert("n1".to_string(), DiskWatermark::High);
+        disk_watermarks.insert("n2".to_string(), DiskWatermark::Low);
+
+        // Primaries can go on nodes over the low watermark, but replicas can't
+        let routing_table = allocate(&[("a", 1)], &["n1", "n2", "n3"], &BTreeMap::new(), &disk_watermarks);
+        assert_eq!(routing_table["a"], routing(Some("n2"), &[("n3", false)]));
+
+        // Copies that are already on a node stay there
+        let mut previous = BTreeMap::new();
+        previous.insert("a".to_string(), routing(Some("n1"), &[("n2", true)]));
+        assert_eq!(allocate(&[("a", 1)], &["n1", "n2"], &previous, &disk_watermarks)["a"], routing(Some("n1"), &[("n2", true)]));
+
+        // With no node to go on, the index is left without a primary
+        assert_eq!(allocate(&[("a", 0)], &["n1"], &BTreeMap::new(), &disk_watermarks)["a"], routing(None, &[]));
+    }
+
+    #[test]
+    fn test_active_nodes() {
+        let routing = routing(Some("n1"), &[("n2", false), ("n3", true)]);
+
+        assert_eq!(routing.active_nodes(), vec!["n1", "n3"]);
+        assert!(routing.is_active_on("n3"));
+        assert!(!routing.is_active_on("n2"));
+        assert_eq!(routing.replica_nodes(), vec!["n2", "n3"]);
+    }
+}
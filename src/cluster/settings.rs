@@ -0,0 +1,540 @@
+//! Settings of the cluster that can be changed while it's running
+//!
+//! They're changed with `PUT /_cluster/settings`, either as persistent settings, which are
+//! saved in the data directory and kept when the cluster restarts, or as transient settings,
+//! which are lost when it does. Transient settings take precedence over persistent ones, and
+//! settings that haven't been given either way have their defaults, some of which come from
+//! the node's config. The master publishes them with the cluster state, so every node uses
+//! the same values. The rest of the node's settings (see `settings::Settings`) are static.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde_json::{self, Value as Json};
+use atomicwrites::{AtomicFile, AllowOverwrite};
+
+use search::backends::rocksdb::StoreOptions;
+use search::aggregations::breaker::DEFAULT_AGGREGATION_MEMORY_LIMIT;
+use settings::{Settings, MemoryLimit};
+use lifecycle::{parse_time_value, format_time_value, parse_byte_size};
+use process_stats::total_memory;
+
+
+/// Default disk usage above which all indices are made read-only
+const DEFAULT_DISK_FLOOD_STAGE_WATERMARK: f64 = 0.95;
+
+/// Default disk usage above which no copies of indices are put on a node, and below which
+/// the flood stage block is lifted again
+const DEFAULT_DISK_HIGH_WATERMARK: f64 = 0.90;
+
+/// Default disk usage above which no replicas are put on a node
+const DEFAULT_DISK_LOW_WATERMARK: f64 = 0.85;
+
+/// Default time between checks of the data disk's usage
+const DEFAULT_DISK_CHECK_INTERVAL: u64 = 30;
+
+/// Default for `action.destructive_requires_name`
+const DEFAULT_DESTRUCTIVE_REQUIRES_NAME: bool = true;
+
+/// Default time between runs of the index lifecycle policies
+const DEFAULT_LIFECYCLE_POLL_INTERVAL: u64 = 10 * 60;
+
+/// Default for `search.max_open_scroll_context`
+const DEFAULT_MAX_OPEN_SCROLLS: usize = 500;
+
+/// Default for `search.max_keep_alive`
+const DEFAULT_MAX_SCROLL_KEEP_ALIVE: u64 = 24 * 60 * 60;
+
+
+/// The settings that have been given, by their flattened keys, such as
+/// "action.destructive_requires_name"
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClusterSettingsState {
+    #[serde(default)]
+    pub persistent: BTreeMap<String, Json>,
+
+    #[serde(default)]
+    pub transient: BTreeMap<String, Json>,
+}
+
+
+/// The values of the settings that are in effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicSettings {
+    /// `action.destructive_requires_name`. When set, indices can only be deleted by naming
+    /// them, not with wildcards or `_all`
+    pub destructive_requires_name: bool,
+
+    /// `network.breaker.inflight_requests.limit`. Defaults to `in_flight_requests_limit`
+    /// from the node's config
+    pub in_flight_requests_limit: MemoryLimit,
+
+    /// `indices.breaker.request.limit`. Memory the aggregations of a single search can use
+    /// before it's stopped
+    pub aggregation_memory_limit: MemoryLimit,
+
+    /// `indices.lifecycle.poll_interval`. How often the lifecycle policies are run
+    pub lifecycle_poll_interval: Duration,
+
+    /// `cluster.routing.allocation.disk.watermark.flood_stage`. Fraction of the data disk
+    /// that can be used before indices are made read-only
+    pub disk_flood_stage_watermark: f64,
+
+    /// `cluster.routing.allocation.disk.watermark.high`. Fraction of the data disk that can
+    /// be used before no more copies of indices are put on the node. The usage must drop
+    /// below it before the read-only block is lifted
+    pub disk_high_watermark: f64,
+
+    /// `cluster.routing.allocation.disk.watermark.low`. Fraction of the data disk that can be
+    /// used before no more replicas are put on the node
+    pub disk_low_watermark: f64,
+
+    /// `cluster.routing.allocation.disk.threshold_enabled`. When unset, the watermarks are
+    /// ignored and any read-only blocks they caused are lifted
+    pub disk_threshold_enabled: bool,
+
+    /// `cluster.info.update.interval`. How often the data disk's usage is checked
+    pub disk_check_interval: Duration,
+
+    /// `indices.merge.scheduler.max_thread_count`. How many indices can be merged at once.
+    /// None if they're only limited by the size of the management thread pool
+    pub max_concurrent_merges: Option<usize>,
+
+    /// `indices.queries.cache.size`. Bytes the filter cache of each shard can use
+    pub filter_cache_size: usize,
+
+    /// `search.max_open_scroll_context`. How many scrolls can be open on the node at once
+    pub max_open_scrolls: usize,
+
+    /// `search.max_keep_alive`. Longest time that a scroll can be kept open for between requests
+    pub max_scroll_keep_alive: Duration,
+}
+
+
+fn parse_bool(key: &str, value: &Json) -> Result<bool, String> {
+    match *value {
+        Json::Bool(value) => Ok(value),
+        Json::String(ref value) if value == "true" => Ok(true),
+        Json::String(ref value) if value == "false" => Ok(false),
+        _ => Err(format!("[{}] must be true or false", key)),
+    }
+}
+
+
+fn parse_memory_limit(key: &str, value: &Json) -> Result<MemoryLimit, String> {
+    match *value {
+        Json::String(ref value) => MemoryLimit::parse(value).map_err(|e| format!("[{}] has an {}", key, e)),
+        _ => value.as_u64().map(MemoryLimit::Bytes).ok_or_else(|| format!("[{}] must be a byte size or a percentage", key)),
+    }
+}
+
+
+fn parse_bytes(key: &str, value: &Json) -> Result<u64, String> {
+    match *value {
+        Json::String(ref value) => parse_byte_size(value).ok_or_else(|| format!("[{}] has an invalid byte size: {:?}", key, value)),
+        _ => value.as_u64().ok_or_else(|| format!("[{}] must be a byte size", key)),
+    }
+}
+
+
+/// Parses a time value such as "10m". Numbers are taken as milliseconds
+fn parse_time(key: &str, value: &Json) -> Result<Duration, String> {
+    let time = match *value {
+        Json::Number(ref millis) => millis.as_u64().map(Duration::from_millis),
+        Json::String(ref value) => parse_time_value(value),
+        _ => None,
+    };
+
+    match time {
+        Some(time) if time > Duration::from_secs(0) => Ok(time),
+        _ => Err(format!("[{}] must be a time value, such as 10m", key)),
+    }
+}
+
+
+fn parse_positive_integer(key: &str, value: &Json) -> Result<usize, String> {
+    let number = match *value {
+        Json::Number(ref number) => number.as_u64(),
+        Json::String(ref value) => value.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    match number {
+        Some(number) if number > 0 => Ok(number as usize),
+        _ => Err(format!("[{}] must be a positive integer", key)),
+    }
+}
+
+
+/// Parses a disk watermark, which is either a percentage such as "90%" or a ratio such as 0.9
+fn parse_watermark(key: &str, value: &Json) -> Result<f64, String> {
+    let ratio = match *value {
+        Json::Number(ref ratio) => ratio.as_f64(),
+        Json::String(ref value) if value.trim().ends_with('%') => {
+            let value = value.trim();
+            value[..value.len() - 1].trim().parse::<f64>().ok().map(|percentage| percentage / 100.0)
+        }
+        Json::String(ref value) => value.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+
+    match ratio {
+        Some(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("[{}] must be a percentage, such as 90%, or a ratio, such as 0.9", key)),
+    }
+}
+
+
+impl DynamicSettings {
+    pub fn defaults(settings: &Settings) -> DynamicSettings {
+        DynamicSettings {
+            destructive_requires_name: DEFAULT_DESTRUCTIVE_REQUIRES_NAME,
+            in_flight_requests_limit: settings.in_flight_requests_limit,
+            aggregation_memory_limit: MemoryLimit::Bytes(DEFAULT_AGGREGATION_MEMORY_LIMIT as u64),
+            lifecycle_poll_interval: Duration::from_secs(DEFAULT_LIFECYCLE_POLL_INTERVAL),
+            disk_flood_stage_watermark: DEFAULT_DISK_FLOOD_STAGE_WATERMARK,
+            disk_high_watermark: DEFAULT_DISK_HIGH_WATERMARK,
+            disk_low_watermark: DEFAULT_DISK_LOW_WATERMARK,
+            disk_threshold_enabled: true,
+            disk_check_interval: Duration::from_secs(DEFAULT_DISK_CHECK_INTERVAL),
+            max_concurrent_merges: None,
+            filter_cache_size: StoreOptions::default().filter_cache_size,
+            max_open_scrolls: DEFAULT_MAX_OPEN_SCROLLS,
+            max_scroll_keep_alive: Duration::from_secs(DEFAULT_MAX_SCROLL_KEEP_ALIVE),
+        }
+    }
+
+    /// Changes a setting, given by its flattened key
+    fn set(&mut self, key: &str, value: &Json) -> Result<(), String> {
+        match key {
+            "action.destructive_requires_name" => self.destructive_requires_name = parse_bool(key, value)?,
+            "network.breaker.inflight_requests.limit" => self.in_flight_requests_limit = parse_memory_limit(key, value)?,
+            "indices.breaker.request.limit" => self.aggregation_memory_limit = parse_memory_limit(key, value)?,
+            "indices.lifecycle.poll_interval" => self.lifecycle_poll_interval = parse_time(key, value)?,
+            "cluster.routing.allocation.disk.watermark.flood_stage" => self.disk_flood_stage_watermark = parse_watermark(key, value)?,
+            "cluster.routing.allocation.disk.watermark.high" => self.disk_high_watermark = parse_watermark(key, value)?,
+            "cluster.routing.allocation.disk.watermark.low" => self.disk_low_watermark = parse_watermark(key, value)?,
+            "cluster.routing.allocation.disk.threshold_enabled" => self.disk_threshold_enabled = parse_bool(key, value)?,
+            "cluster.info.update.interval" => self.disk_check_interval = parse_time(key, value)?,
+            "indices.merge.scheduler.max_thread_count" => self.max_concurrent_merges = Some(parse_positive_integer(key, value)?),
+            "indices.queries.cache.size" => self.filter_cache_size = parse_bytes(key, value)? as usize,
+            "search.max_open_scroll_context" => self.max_open_scrolls = parse_positive_integer(key, value)?,
+            "search.max_keep_alive" => self.max_scroll_keep_alive = parse_time(key, value)?,
+            _ => return Err(format!("unknown setting [{}], only dynamic settings can be changed", key)),
+        }
+
+        Ok(())
+    }
+
+    /// Works out the values in effect from the defaults and the settings that have been given
+    pub fn resolve(defaults: &DynamicSettings, state: &ClusterSettingsState) -> Result<DynamicSettings, String> {
+        let mut settings = defaults.clone();
+
+        // Transient settings override persistent ones
+        for (key, value) in state.persistent.iter().chain(state.transient.iter()) {
+            settings.set(key, value)?;
+        }
+
+        if settings.disk_low_watermark > settings.disk_high_watermark {
+            return Err("the low disk watermark can't be above the high watermark".to_string());
+        }
+
+        if settings.disk_high_watermark > settings.disk_flood_stage_watermark {
+            return Err("the high disk watermark can't be above the flood stage watermark".to_string());
+        }
+
+        Ok(settings)
+    }
+
+    /// The memory the aggregations of a search can use, in bytes
+    pub fn aggregation_memory_limit_bytes(&self) -> usize {
+        self.aggregation_memory_limit.to_bytes(total_memory()).map_or(DEFAULT_AGGREGATION_MEMORY_LIMIT, |limit| limit as usize)
+    }
+
+    /// Every setting by its flattened key, for `include_defaults`
+    pub fn to_flat_json(&self) -> BTreeMap<String, Json> {
+        let mut settings = BTreeMap::new();
+        settings.insert("action.destructive_requires_name".to_string(), json!(self.destructive_requires_name.to_string()));
+        settings.insert("network.breaker.inflight_requests.limit".to_string(), json!(self.in_flight_requests_limit.format()));
+        settings.insert("indices.breaker.request.limit".to_string(), json!(self.aggregation_memory_limit.format()));
+        settings.insert("indices.lifecycle.poll_interval".to_string(), json!(format_time_value(self.lifecycle_poll_interval)));
+        settings.insert("cluster.routing.allocation.disk.watermark.flood_stage".to_string(), json!(format!("{}%", self.disk_flood_stage_watermark * 100.0)));
+        settings.insert("cluster.routing.allocation.disk.watermark.high".to_string(), json!(format!("{}%", self.disk_high_watermark * 100.0)));
+        settings.insert("cluster.routing.allocation.disk.watermark.low".to_string(), json!(format!("{}%", self.disk_low_watermark * 100.0)));
+        settings.insert("cluster.routing.allocation.disk.threshold_enabled".to_string(), json!(self.disk_threshold_enabled.to_string()));
+        settings.insert("cluster.info.update.interval".to_string(), json!(format_time_value(self.disk_check_interval)));
+        if let Some(max_concurrent_merges) = self.max_concurrent_merges {
+            settings.insert("indices.merge.scheduler.max_thread_count".to_string(), json!(max_concurrent_merges.to_string()));
+        }
+        settings.insert("indices.queries.cache.size".to_string(), json!(format!("{}b", self.filter_cache_size)));
+        settings.insert("search.max_open_scroll_context".to_string(), json!(self.max_open_scrolls.to_string()));
+        settings.insert("search.max_keep_alive".to_string(), json!(format_time_value(self.max_scroll_keep_alive)));
+        settings
+    }
+}
+
+
+/// Flattens nested settings objects into dotted keys. Other values, including nulls, are
+/// left as they are
+pub fn flatten(prefix: &str, json: &Json, flattened: &mut BTreeMap<String, Json>) {
+    match *json {
+        Json::Object(ref object) => {
+            for (key, value) in object.iter() {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+
+                flatten(&key, value, flattened);
+            }
+        }
+        _ => {
+            flattened.insert(prefix.to_string(), json.clone());
+        }
+    }
+}
+
+
+/// Nests flattened settings into objects, the way Elasticsearch shows them unless
+/// `flat_settings` is set
+pub fn nest(flattened: &BTreeMap<String, Json>) -> Json {
+    let mut nested = json!({});
+
+    for (key, value) in flattened.iter() {
+        let mut parts = key.split('.').collect::<Vec<_>>();
+        let last = parts.pop().unwrap_or("");
+
+        let mut object = &mut nested;
+        for part in parts {
+            if !object.get(part).map_or(false, |child| child.is_object()) {
+                object[part] = json!({});
+            }
+
+            object = &mut object[part];
+        }
+
+        object[last] = value.clone();
+    }
+
+    nested
+}
+
+
+/// Changes the given settings. Null values remove a setting, so it goes back to its default
+fn apply_changes(settings: &mut BTreeMap<String, Json>, changes: &BTreeMap<String, Json>) {
+    for (key, value) in changes.iter() {
+        if value.is_null() {
+            settings.remove(key);
+        } else {
+            settings.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+
+/// The cluster's settings, as this node last saw them
+#[derive(Debug)]
+pub struct ClusterSettings {
+    defaults: DynamicSettings,
+
+    /// The settings that have been given, and the values they resolve to
+    current: RwLock<(ClusterSettingsState, DynamicSettings)>,
+}
+
+
+impl ClusterSettings {
+    pub fn new(defaults: DynamicSettings) -> ClusterSettings {
+        ClusterSettings {
+            current: RwLock::new((ClusterSettingsState::default(), defaults.clone())),
+            defaults: defaults,
+        }
+    }
+
+    /// Loads the persistent settings from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load cluster settings: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load cluster settings: {}", e))?;
+
+        let state = ClusterSettingsState {
+            persistent: serde_json::from_str(&s).map_err(|e| format!("failed to load cluster settings: {}", e))?,
+            transient: BTreeMap::new(),
+        };
+        let settings = DynamicSettings::resolve(&self.defaults, &state).map_err(|e| format!("failed to load cluster settings: {}", e))?;
+
+        *self.current.write().unwrap() = (state, settings);
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, persistent: &BTreeMap<String, Json>) -> Result<(), String> {
+        let s = serde_json::to_string(persistent).map_err(|e| format!("failed to save cluster settings: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save cluster settings: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn defaults(&self) -> &DynamicSettings {
+        &self.defaults
+    }
+
+    /// The values of the settings that are in effect
+    pub fn current(&self) -> DynamicSettings {
+        self.current.read().unwrap().1.clone()
+    }
+
+    /// The settings that have been given
+    pub fn state(&self) -> ClusterSettingsState {
+        self.current.read().unwrap().0.clone()
+    }
+
+    /// Replaces the settings, saving the persistent ones if they've changed
+    ///
+    /// Nothing is changed if any of the settings are invalid. Returns the values that were
+    /// in effect before.
+    pub fn replace<P: AsRef<Path>>(&self, path: P, state: ClusterSettingsState) -> Result<DynamicSettings, String> {
+        let settings = DynamicSettings::resolve(&self.defaults, &state)?;
+        let mut current = self.current.write().unwrap();
+
+        if state.persistent != current.0.persistent {
+            self.save(path.as_ref(), &state.persistent)?;
+        }
+
+        let (_, previous) = mem::replace(&mut *current, (state, settings));
+        Ok(previous)
+    }
+
+    /// Changes some of the persistent and transient settings. See `replace`
+    pub fn update<P: AsRef<Path>>(&self, path: P, persistent: &BTreeMap<String, Json>, transient: &BTreeMap<String, Json>) -> Result<DynamicSettings, String> {
+        let mut state = self.state();
+        apply_changes(&mut state.persistent, persistent);
+        apply_changes(&mut state.transient, transient);
+
+        self.replace(path, state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::Duration;
+
+    use serde_json::Value as Json;
+
+    use settings::MemoryLimit;
+    use super::{ClusterSettings, ClusterSettingsState, DynamicSettings, flatten, nest};
+
+    fn defaults() -> DynamicSettings {
+        DynamicSettings {
+            destructive_requires_name: true,
+            in_flight_requests_limit: MemoryLimit::Fraction(0.5),
+            aggregation_memory_limit: MemoryLimit::Bytes(1024),
+            lifecycle_poll_interval: Duration::from_secs(600),
+            disk_flood_stage_watermark: 0.95,
+            disk_high_watermark: 0.9,
+            disk_low_watermark: 0.85,
+            disk_threshold_enabled: true,
+            disk_check_interval: Duration::from_secs(30),
+            max_concurrent_merges: None,
+            filter_cache_size: 1024,
+            max_open_scrolls: 500,
+            max_scroll_keep_alive: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    fn flat(json: Json) -> BTreeMap<String, Json> {
+        let mut flattened = BTreeMap::new();
+        flatten("", &json, &mut flattened);
+        flattened
+    }
+
+    #[test]
+    fn test_resolve() {
+        let state = ClusterSettingsState {
+            persistent: flat(json!({
+                "action": {"destructive_requires_name": false},
+                "indices.queries.cache.size": "1mb",
+                "cluster.routing.allocation.disk.watermark.high": "80%",
+                "cluster.routing.allocation.disk.watermark.low": 0.75,
+            })),
+            transient: flat(json!({
+                "indices.queries.cache.size": 2048,
+                "indices.merge.scheduler.max_thread_count": "2",
+                "search": {"max_open_scroll_context": 10, "max_keep_alive": "1h"},
+            })),
+        };
+        let settings = DynamicSettings::resolve(&defaults(), &state).unwrap();
+
+        assert!(!settings.destructive_requires_name);
+        assert_eq!(settings.disk_high_watermark, 0.8);
+        assert_eq!(settings.disk_low_watermark, 0.75);
+        assert_eq!(settings.max_concurrent_merges, Some(2));
+        assert_eq!(settings.max_open_scrolls, 10);
+        assert_eq!(settings.max_scroll_keep_alive, Duration::from_secs(60 * 60));
+
+        // Transient settings take precedence
+        assert_eq!(settings.filter_cache_size, 2048);
+
+        let invalid = |json| DynamicSettings::resolve(&defaults(), &ClusterSettingsState { persistent: flat(json), transient: BTreeMap::new() }).is_err();
+        assert!(invalid(json!({"action.destructive_requires_name": "yes"})));
+        assert!(invalid(json!({"cluster.routing.allocation.disk.watermark.high": "97%"})));
+        assert!(invalid(json!({"cluster.routing.allocation.disk.watermark.low": "92%"})));
+        assert!(invalid(json!({"indices.merge.scheduler.max_thread_count": 0})));
+        assert!(invalid(json!({"search.max_keep_alive": "forever"})));
+        assert!(invalid(json!({"node.name": "node-1"})));
+    }
+
+    #[test]
+    fn test_update() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_cluster_settings.json";
+        let _ = fs::remove_file(path);
+
+        let cluster_settings = ClusterSettings::new(defaults());
+        let previous = cluster_settings.update(path, &flat(json!({"indices.lifecycle.poll_interval": "1m"})), &flat(json!({"indices.breaker.request.limit": "10%"}))).unwrap();
+        assert_eq!(previous, defaults());
+        assert_eq!(cluster_settings.current().lifecycle_poll_interval, Duration::from_secs(60));
+        assert_eq!(cluster_settings.current().aggregation_memory_limit, MemoryLimit::Fraction(0.1));
+
+        // Invalid changes are refused as a whole
+        assert!(cluster_settings.update(path, &flat(json!({"action.destructive_requires_name": false, "foo": 1})), &BTreeMap::new()).is_err());
+        assert!(cluster_settings.current().destructive_requires_name);
+
+        // Only the persistent settings are loaded again
+        let loaded = ClusterSettings::new(defaults());
+        loaded.load(path).unwrap();
+        assert_eq!(loaded.current().lifecycle_poll_interval, Duration::from_secs(60));
+        assert_eq!(loaded.current().aggregation_memory_limit, MemoryLimit::Bytes(1024));
+
+        // Null resets a setting
+        cluster_settings.update(path, &flat(json!({"indices": {"lifecycle": {"poll_interval": null}}})), &BTreeMap::new()).unwrap();
+        assert_eq!(cluster_settings.current().lifecycle_poll_interval, Duration::from_secs(600));
+        assert!(cluster_settings.state().persistent.is_empty());
+    }
+
+    #[test]
+    fn test_nest() {
+        let flattened = flat(json!({"indices.queries.cache.size": "1mb", "indices.lifecycle.poll_interval": "1m"}));
+
+        assert_eq!(nest(&flattened), json!({
+            "indices": {
+                "queries": {"cache": {"size": "1mb"}},
+                "lifecycle": {"poll_interval": "1m"},
+            }
+        }));
+    }
+}
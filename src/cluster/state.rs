@@ -0,0 +1,316 @@
+//! The state that the master shares with the other nodes of a cluster
+//!
+//! It's made up of the nodes in the cluster, the metadata of every index (its settings,
+//! mappings and aliases, and whether it's open), the nodes that hold the copies of each
+//! open index, the cluster settings and the disk watermarks the nodes are over. Each change the master makes is published as a new version of the whole state.
+
+use std::collections::BTreeMap;
+
+use serde_json::{self, Value as Json};
+
+use cluster::metadata::ClusterMetadata;
+use cluster::routing::{IndexRouting, allocate};
+use cluster::settings::ClusterSettingsState;
+use disk_usage::DiskWatermark;
+
+
+/// A node of the cluster
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveryNode {
+    pub id: String,
+    pub name: String,
+
+    /// "host:port" that the other nodes reach it at
+    pub address: String,
+}
+
+
+/// An index as it's shared with the other nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexState {
+    pub open: bool,
+
+    /// The index's metadata, as saved in its metadata.json file
+    pub metadata: Json,
+}
+
+
+impl IndexState {
+    pub fn number_of_replicas(&self) -> usize {
+        self.metadata["settings"]["index"]["number_of_replicas"].as_u64().unwrap_or(0) as usize
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterState {
+    pub cluster_name: String,
+
+    /// Goes up by one each time the master publishes a change
+    pub version: u64,
+
+    /// Id of the elected master. None while there isn't one
+    pub master_node: Option<String>,
+
+    /// The nodes that have joined, including the master, by id
+    pub nodes: BTreeMap<String, DiscoveryNode>,
+
+    /// Every index, open or closed, by name
+    pub indices: BTreeMap<String, IndexState>,
+
+    /// Where the copies of each open index are, by index name (see `cluster::routing`)
+    #[serde(default)]
+    pub routing: BTreeMap<String, IndexRouting>,
+
+    /// The cluster settings that have been changed from their defaults (see `cluster::settings`)
+    #[serde(default)]
+    pub settings: ClusterSettingsState,
+
+    /// The disk watermark that each node's data disk is over, by node id. Nodes under all of
+    /// them aren't listed
+    #[serde(default)]
+    pub disk_watermarks: BTreeMap<String, DiskWatermark>,
+}
+
+
+impl ClusterState {
+    pub fn new(cluster_name: &str) -> ClusterState {
+        ClusterState {
+            cluster_name: cluster_name.to_string(),
+            version: 0,
+            master_node: None,
+            nodes: BTreeMap::new(),
+            indices: BTreeMap::new(),
+            routing: BTreeMap::new(),
+            settings: ClusterSettingsState::default(),
+            disk_watermarks: BTreeMap::new(),
+        }
+    }
+
+    pub fn master(&self) -> Option<&DiscoveryNode> {
+        self.master_node.as_ref().and_then(|master_node| self.nodes.get(master_node))
+    }
+
+    /// Assigns the copies of the open indices to the nodes. Returns true if anything moved
+    ///
+    /// This is run by the master whenever the nodes or indices change.
+    pub fn reroute(&mut self) -> bool {
+        let indices = self.indices.iter()
+            .filter(|&(_, index)| index.open)
+            .map(|(name, index)| (name.as_str(), index.number_of_replicas()))
+            .collect::<Vec<_>>();
+        let nodes = self.nodes.keys().map(|id| id.as_str()).collect::<Vec<_>>();
+
+        let routing = allocate(&indices, &nodes, &self.routing, &self.disk_watermarks);
+        if routing == self.routing {
+            return false;
+        }
+
+        self.routing = routing;
+        true
+    }
+
+    /// Finds where the copies of an index are
+    ///
+    /// Indices that haven't been assigned yet, such as ones created since the last state was
+    /// published, are taken to be on this node only.
+    pub fn index_routing(&self, index_name: &str, local_node_id: &str) -> IndexRouting {
+        match self.routing.get(index_name) {
+            Some(routing) => routing.clone(),
+            None => {
+                IndexRouting {
+                    primary: Some(local_node_id.to_string()),
+                    replicas: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Formats the state as the `_cluster/state` API returns it
+    pub fn to_json(&self) -> Json {
+        let mut nodes = serde_json::Map::new();
+        for node in self.nodes.values() {
+            let mut node_json = json!({
+                "name": node.name,
+                "transport_address": node.address,
+            });
+            if let Some(watermark) = self.disk_watermarks.get(&node.id) {
+                node_json["disk_watermark"] = json!(watermark.name());
+            }
+            nodes.insert(node.id.clone(), node_json);
+        }
+
+        let mut indices = serde_json::Map::new();
+        for (name, index) in self.indices.iter() {
+            let mut index_json = index.metadata.clone();
+            index_json["state"] = json!(if index.open { "open" } else { "close" });
+            indices.insert(name.clone(), index_json);
+        }
+
+        let mut routing_table = serde_json::Map::new();
+        for (name, routing) in self.routing.iter() {
+            let replicas = routing.replicas.iter().map(|replica| {
+                json!({
+                    "node": replica.node,
+                    "state": if replica.started { "STARTED" } else { "INITIALIZING" },
+                })
+            }).collect::<Vec<_>>();
+
+            routing_table.insert(name.clone(), json!({
+                "primary": routing.primary,
+                "replicas": replicas,
+            }));
+        }
+
+        json!({
+            "cluster_name": self.cluster_name,
+            "version": self.version,
+            "master_node": self.master_node,
+            "nodes": nodes,
+            "metadata": {
+                "indices": indices,
+            },
+            "routing_table": {
+                "indices": routing_table,
+            },
+        })
+    }
+}
+
+
+/// Takes a copy of the metadata of the node's indices
+pub fn index_states(cluster_metadata: &ClusterMetadata) -> BTreeMap<String, IndexState> {
+    let mut indices = BTreeMap::new();
+
+    for index in cluster_metadata.indices.values() {
+        indices.insert(index.canonical_name().to_string(), IndexState {
+            open: true,
+            metadata: serde_json::to_value(&*index.metadata.read().unwrap()).unwrap_or(Json::Null),
+        });
+    }
+
+    for index in cluster_metadata.closed_indices.values() {
+        indices.insert(index.canonical_name().to_string(), IndexState {
+            open: false,
+            metadata: serde_json::to_value(&index.metadata).unwrap_or(Json::Null),
+        });
+    }
+
+    indices
+}
+
+
+/// What a node says about itself when it's pinged
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PingResponse {
+    pub cluster_name: String,
+    pub node: DiscoveryNode,
+
+    /// The master it's following, or its own id if it's the master
+    pub master_node: Option<String>,
+
+    /// The version of the cluster state it has
+    pub version: u64,
+
+    /// The disk watermark its data disk is over. None if it's under them all, or is a node
+    /// from before this was reported
+    #[serde(default)]
+    pub disk_watermark: Option<DiskWatermark>,
+}
+
+
+impl PingResponse {
+    pub fn is_master(&self) -> bool {
+        self.master_node.as_ref() == Some(&self.node.id)
+    }
+}
+
+
+/// Works out which node should become master, from the nodes that can see each other
+///
+/// The node with the newest cluster state wins, so no changes are lost, then the one with
+/// the lowest id. No node is chosen unless there are at least `minimum_master_nodes`, so
+/// the two sides of a network partition can't both elect a master.
+pub fn elect_master(candidates: &[(&str, u64)], minimum_master_nodes: usize) -> Option<String> {
+    if candidates.len() < minimum_master_nodes {
+        return None;
+    }
+
+    candidates.iter().fold(None, |best: Option<&(&str, u64)>, candidate| {
+        match best {
+            Some(best) if best.1 > candidate.1 || (best.1 == candidate.1 && best.0 < candidate.0) => Some(best),
+            _ => Some(candidate),
+        }
+    }).map(|&(id, _)| id.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ClusterState, DiscoveryNode, IndexState, elect_master};
+
+    #[test]
+    fn test_elect_master() {
+        assert_eq!(elect_master(&[("b", 0), ("a", 0), ("c", 0)], 1), Some("a".to_string()));
+        assert_eq!(elect_master(&[("b", 3), ("a", 2), ("c", 3)], 2), Some("b".to_string()));
+        assert_eq!(elect_master(&[("b", 0)], 2), None);
+        assert_eq!(elect_master(&[], 0), None);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut state = ClusterState::new("logging");
+        state.version = 3;
+        state.master_node = Some("a".to_string());
+        state.nodes.insert("a".to_string(), DiscoveryNode {
+            id: "a".to_string(),
+            name: "node-a".to_string(),
+            address: "10.0.0.1:9200".to_string(),
+        });
+
+        assert_eq!(state.master().map(|master| master.name.as_str()), Some("node-a"));
+        assert_eq!(state.to_json(), json!({
+            "cluster_name": "logging",
+            "version": 3,
+            "master_node": "a",
+            "nodes": {
+                "a": {"name": "node-a", "transport_address": "10.0.0.1:9200"},
+            },
+            "metadata": {
+                "indices": {},
+            },
+            "routing_table": {
+                "indices": {},
+            },
+        }));
+    }
+
+    #[test]
+    fn test_reroute() {
+        let mut state = ClusterState::new("logging");
+        for id in &["a", "b"] {
+            state.nodes.insert(id.to_string(), DiscoveryNode {
+                id: id.to_string(),
+                name: format!("node-{}", id),
+                address: "10.0.0.1:9200".to_string(),
+            });
+        }
+        state.indices.insert("logs".to_string(), IndexState {
+            open: true,
+            metadata: json!({"settings": {"index": {"number_of_replicas": 1}}}),
+        });
+        state.indices.insert("closed".to_string(), IndexState {
+            open: false,
+            metadata: json!({}),
+        });
+
+        assert!(state.reroute());
+        assert!(!state.reroute());
+        assert_eq!(state.routing.keys().collect::<Vec<_>>(), vec!["logs"]);
+        assert_eq!(state.routing["logs"].active_nodes(), vec!["a"]);
+        assert_eq!(state.routing["logs"].replica_nodes(), vec!["b"]);
+
+        // Indices that haven't been assigned yet are on the local node
+        assert_eq!(state.index_routing("new", "b").active_nodes(), vec!["b"]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Requests between the nodes of a cluster
+//!
+//! Nodes talk to each other over the same HTTP API that clients use, through the endpoints
+//! under `/_internal/cluster`. If a cluster secret is set, it's sent with every request in
+//! the `X-Cluster-Secret` header.
+
+use std::io;
+use std::time::Duration;
+
+use futures::{FutureExt, TryFutureExt};
+use futures::future::{self, Future};
+use hyper::{self, Body, Client, Method, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use serde_json::{self, Value as Json};
+use tokio::runtime::{self, Runtime};
+use tokio::time;
+
+
+pub const CLUSTER_SECRET_HEADER: &'static str = "X-Cluster-Secret";
+
+
+/// A response from another node
+#[derive(Debug)]
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+
+/// A request to another node
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    /// "host:port" of the node
+    pub address: String,
+    pub method: Method,
+
+    /// The path, including any query string
+    pub path: String,
+
+    /// Headers to send, apart from the cluster secret
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+
+impl TransportRequest {
+    pub fn new(address: &str, method: Method, path: &str) -> TransportRequest {
+        TransportRequest {
+            address: address.to_string(),
+            method: method,
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_json(mut self, body: &Json) -> TransportRequest {
+        self.headers.push((CONTENT_TYPE.as_str().to_string(), "application/json".to_string()));
+        self.body = serde_json::to_vec(body).unwrap_or_default();
+        self
+    }
+}
+
+
+/// Sends requests to other nodes
+///
+/// Requests are made on a runtime of their own, so they can be sent from any thread. The
+/// calling thread blocks until the responses arrive. It can also be used to talk to servers
+/// outside of the cluster (see `external`).
+pub struct Transport {
+    runtime: Runtime,
+    client: Client<HttpConnector>,
+    secret: Option<String>,
+}
+
+
+impl Transport {
+    pub fn new(secret: Option<String>) -> io::Result<Transport> {
+        let runtime = runtime::Builder::new_multi_thread().worker_threads(1).thread_name("cluster-transport").enable_all().build()?;
+
+        Ok(Transport {
+            runtime: runtime,
+            client: Client::new(),
+            secret: secret,
+        })
+    }
+
+    /// Makes a transport for requests to servers that aren't part of the cluster, such as
+    /// another cluster that's being reindexed from. The cluster secret isn't sent to them
+    pub fn external(connect_timeout: Duration) -> io::Result<Transport> {
+        let runtime = runtime::Builder::new_multi_thread().worker_threads(1).thread_name("external-transport").enable_all().build()?;
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(connect_timeout));
+
+        Ok(Transport {
+            runtime: runtime,
+            client: Client::builder().build(connector),
+            secret: None,
+        })
+    }
+
+    fn request(&self, request: TransportRequest, timeout: Duration) -> impl Future<Output = Result<TransportResponse, String>> {
+        let address = request.address.clone();
+        let uri = match format!("http://{}{}", request.address, request.path).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(e) => return future::ready(Err(format!("invalid address {:?}: {}", address, e))).left_future(),
+        };
+
+        let mut builder = hyper::Request::builder().method(request.method).uri(uri);
+        for &(ref name, ref value) in request.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        if let Some(ref secret) = self.secret {
+            builder = builder.header(CLUSTER_SECRET_HEADER, secret.as_str());
+        }
+
+        let request = match builder.body(Body::from(request.body)) {
+            Ok(request) => request,
+            Err(e) => return future::ready(Err(format!("invalid request: {}", e))).left_future(),
+        };
+
+        let response = self.client.request(request).and_then(|response| {
+            let (parts, body) = response.into_parts();
+            let content_type = parts.headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+
+            hyper::body::to_bytes(body).map_ok(move |body| {
+                TransportResponse {
+                    status: parts.status,
+                    content_type: content_type,
+                    body: body.to_vec(),
+                }
+            })
+        }).map_err(|e| e.to_string());
+
+        // The timer has to be created on the runtime
+        let _runtime = self.runtime.enter();
+        time::timeout(timeout, response).map(move |result| match result {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out waiting for {}", address)),
+        }).right_future()
+    }
+
+    /// Sends a request and waits up to `timeout` for the response
+    pub fn send(&self, request: TransportRequest, timeout: Duration) -> Result<TransportResponse, String> {
+        self.runtime.block_on(self.request(request, timeout))
+    }
+
+    /// Sends requests at the same time, returning their responses in the same order
+    pub fn send_all(&self, requests: Vec<TransportRequest>, timeout: Duration) -> Vec<Result<TransportResponse, String>> {
+        let requests = requests.into_iter().map(|request| self.request(request, timeout)).collect::<Vec<_>>();
+        self.runtime.block_on(future::join_all(requests))
+    }
+}
+
+
+/// Reads the JSON body of a successful response
+pub fn parse_json_response(response: Result<TransportResponse, String>) -> Result<Json, String> {
+    let response = response?;
+    let body = serde_json::from_slice::<Json>(&response.body).map_err(|e| format!("invalid response: {}", e))?;
+
+    if !response.status.is_success() {
+        let message = body.get("message").and_then(|message| message.as_str()).unwrap_or("");
+        return Err(format!("{} {}", response.status, message));
+    }
+
+    Ok(body)
+}
@@ -0,0 +1,173 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::process;
+
+
+const LOCK_FILE_NAME: &'static str = "rusticsearch.lock";
+
+
+#[derive(Debug)]
+pub enum DirLockError {
+    /// The directory is locked by another process. The pid of that process is included if
+    /// it's known
+    AlreadyLocked(PathBuf, Option<u32>),
+    IoError(io::Error),
+}
+
+
+impl From<DirLockError> for String {
+    fn from(e: DirLockError) -> String {
+        match e {
+            DirLockError::AlreadyLocked(path, Some(pid)) => format!("{} is locked by another rusticsearch process (pid {}). Make sure no other instance is using this data directory", path.display(), pid).to_string(),
+            DirLockError::AlreadyLocked(path, None) => format!("{} is locked by another rusticsearch process. Make sure no other instance is using this data directory", path.display()).to_string(),
+            DirLockError::IoError(e) => format!("failed to lock directory: {}", e).to_string(),
+        }
+    }
+}
+
+
+impl From<io::Error> for DirLockError {
+    fn from(e: io::Error) -> DirLockError {
+        DirLockError::IoError(e)
+    }
+}
+
+
+/// An exclusive lock on a directory
+///
+/// This stops two rusticsearch processes from opening the same data at the same time. The
+/// lock is an `flock` on a lock file in the directory, which is held for as long as the
+/// `DirLock` keeps the file open. The operating system releases it when the process
+/// exits, so there are no stale locks to clean up after a crash.
+///
+/// The pid of the process holding the lock is written to the file, but only so it can be
+/// reported. The file is left behind when the lock is released, as removing it could let
+/// two processes lock different files at the same path.
+#[derive(Debug)]
+pub struct DirLock {
+    path: PathBuf,
+
+    // Closing this releases the lock
+    _file: File,
+}
+
+
+/// Takes an exclusive lock on the file without waiting. Returns false if another open
+/// file holds a lock on it
+#[cfg(unix)]
+fn try_lock_file(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(error)
+    }
+}
+
+
+/// File locks aren't supported on this platform, so the lock is always taken
+#[cfg(not(unix))]
+fn try_lock_file(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+
+fn read_lock_pid(file: &mut File) -> io::Result<Option<u32>> {
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+
+    Ok(s.trim().parse().ok())
+}
+
+
+impl DirLock {
+    /// Takes the lock on the given directory, creating the directory if it doesn't exist
+    pub fn acquire<P: AsRef<Path>>(dir: P) -> Result<DirLock, DirLockError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(LOCK_FILE_NAME);
+
+        // The file mustn't be truncated until the lock is held, so the pid of the process
+        // holding it can still be read
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+        if !try_lock_file(&file)? {
+            let pid = read_lock_pid(&mut file).unwrap_or(None);
+            return Err(DirLockError::AlreadyLocked(dir.to_path_buf(), pid));
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+
+        Ok(DirLock {
+            path: path,
+            _file: file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File, remove_dir_all};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::process;
+
+    use super::{DirLock, DirLockError};
+
+    fn remove_dir_all_ignore_error<P: AsRef<Path>>(path: P) {
+        match remove_dir_all(&path) {
+            Ok(_) => {}
+            Err(_) => {}  // Don't care if this fails
+        }
+    }
+
+    #[test]
+    fn test_lock() {
+        remove_dir_all_ignore_error("test_indices/test_dir_lock");
+
+        let lock = DirLock::acquire("test_indices/test_dir_lock").unwrap();
+        assert!(lock.path().exists());
+
+        // Can't take the lock twice
+        match DirLock::acquire("test_indices/test_dir_lock") {
+            Err(DirLockError::AlreadyLocked(_, pid)) => assert_eq!(pid, Some(process::id())),
+            result => panic!("expected AlreadyLocked error, got {:?}", result),
+        }
+
+        // Dropping the lock releases it, but leaves the file behind
+        drop(lock);
+        assert!(Path::new("test_indices/test_dir_lock/rusticsearch.lock").exists());
+        assert!(DirLock::acquire("test_indices/test_dir_lock").is_ok());
+    }
+
+    #[test]
+    fn test_stale_lock() {
+        remove_dir_all_ignore_error("test_indices/test_dir_lock_stale");
+        fs::create_dir_all("test_indices/test_dir_lock_stale").unwrap();
+
+        // Leave a lock file behind with the pid of a process that has since exited. Nothing
+        // holds a lock on it, so it's taken over
+        let mut file = File::create("test_indices/test_dir_lock_stale/rusticsearch.lock").unwrap();
+        write!(file, "{}", u32::max_value()).unwrap();
+
+        let lock = DirLock::acquire("test_indices/test_dir_lock_stale").unwrap();
+        let mut pid = String::new();
+        File::open(lock.path()).unwrap().read_to_string(&mut pid).unwrap();
+        assert_eq!(pid, process::id().to_string());
+    }
+}
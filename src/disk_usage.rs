@@ -0,0 +1,220 @@
+//! Works out how full the data disk is, and which of the disk watermarks it's over
+//!
+//! The watermarks are cluster settings. Over the low watermark, no new replicas are put on
+//! the node. Over the high watermark, no new copies of any kind are, and over the flood
+//! stage every index on the node is made read-only (see `System::check_disk_usage`).
+
+use std::path::Path;
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::sync::Mutex;
+use std::collections::BTreeSet;
+
+use serde_json;
+use uuid::Uuid;
+use atomicwrites::{AtomicFile, AllowOverwrite};
+
+use cluster::settings::DynamicSettings;
+
+
+/// A disk watermark that a node's data disk is over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskWatermark {
+    Low,
+    High,
+    FloodStage,
+}
+
+
+impl DiskWatermark {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DiskWatermark::Low => "low",
+            DiskWatermark::High => "high",
+            DiskWatermark::FloodStage => "flood_stage",
+        }
+    }
+
+    /// The highest watermark that the usage is at or over. None if it's under them all, or
+    /// the disk threshold decider is disabled
+    pub fn exceeded(usage: f64, settings: &DynamicSettings) -> Option<DiskWatermark> {
+        if !settings.disk_threshold_enabled {
+            None
+        } else if usage >= settings.disk_flood_stage_watermark {
+            Some(DiskWatermark::FloodStage)
+        } else if usage >= settings.disk_high_watermark {
+            Some(DiskWatermark::High)
+        } else if usage >= settings.disk_low_watermark {
+            Some(DiskWatermark::Low)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Returns the fraction of the disk containing the given path that is in use (0.0 - 1.0)
+///
+/// Space reserved for the superuser is counted as used, as we can't write to it. Returns
+/// None on platforms where this can't be worked out.
+#[cfg(unix)]
+pub fn disk_usage(path: &Path) -> io::Result<Option<f64>> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use libc;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let stats = unsafe {
+        let mut stats: libc::statvfs = mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stats) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stats
+    };
+
+    if stats.f_blocks == 0 {
+        return Ok(None);
+    }
+
+    let available = stats.f_bavail as f64 / stats.f_blocks as f64;
+    Ok(Some(1.0 - available))
+}
+
+
+#[cfg(not(unix))]
+pub fn disk_usage(_path: &Path) -> io::Result<Option<f64>> {
+    Ok(None)
+}
+
+
+/// The indices that were made read-only because the disk went over the flood stage watermark
+///
+/// Only these have their block lifted once the usage drops, so blocks that were set through
+/// the settings API are left alone. They're written to "disk_blocks.json" in the data
+/// directory, so the blocks are still lifted if the node is restarted in between.
+#[derive(Debug)]
+pub struct DiskBlocks {
+    indices: Mutex<BTreeSet<String>>,
+}
+
+
+impl DiskBlocks {
+    pub fn new() -> DiskBlocks {
+        DiskBlocks {
+            indices: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Loads the blocked indices from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load disk blocks: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load disk blocks: {}", e))?;
+
+        let indices = serde_json::from_str(&s).map_err(|e| format!("failed to load disk blocks: {}", e))?;
+        *self.indices.lock().unwrap() = indices;
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, indices: &BTreeSet<String>) -> Result<(), String> {
+        let s = serde_json::to_string(indices).map_err(|e| format!("failed to save disk blocks: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save disk blocks: {}", e))?;
+
+        Ok(())
+    }
+
+    /// Returns true if the index was blocked because of the disk usage
+    pub fn contains(&self, index_id: &Uuid) -> bool {
+        self.indices.lock().unwrap().contains(&index_id.to_string())
+    }
+
+    /// Records whether the index is blocked because of the disk usage, saving the blocked
+    /// indices to the file
+    ///
+    /// The record is kept in memory even if it couldn't be saved, as the disk may be too
+    /// full to write to.
+    pub fn set<P: AsRef<Path>>(&self, path: P, index_id: &Uuid, blocked: bool) -> Result<(), String> {
+        let mut indices = self.indices.lock().unwrap();
+        let changed = if blocked {
+            indices.insert(index_id.to_string())
+        } else {
+            indices.remove(&index_id.to_string())
+        };
+
+        if changed {
+            self.save(path.as_ref(), &indices)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use uuid::Uuid;
+    use settings::Settings;
+    use cluster::settings::DynamicSettings;
+
+    use super::{DiskWatermark, DiskBlocks, disk_usage};
+
+    #[test]
+    fn test_disk_usage() {
+        if let Some(usage) = disk_usage(Path::new(".")).unwrap() {
+            assert!(usage >= 0.0 && usage <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let mut settings = DynamicSettings::defaults(&Settings::default());
+        settings.disk_low_watermark = 0.8;
+        settings.disk_high_watermark = 0.9;
+        settings.disk_flood_stage_watermark = 0.95;
+
+        assert_eq!(DiskWatermark::exceeded(0.5, &settings), None);
+        assert_eq!(DiskWatermark::exceeded(0.8, &settings), Some(DiskWatermark::Low));
+        assert_eq!(DiskWatermark::exceeded(0.92, &settings), Some(DiskWatermark::High));
+        assert_eq!(DiskWatermark::exceeded(0.99, &settings), Some(DiskWatermark::FloodStage));
+
+        settings.disk_threshold_enabled = false;
+        assert_eq!(DiskWatermark::exceeded(0.99, &settings), None);
+    }
+
+    #[test]
+    fn test_disk_blocks() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_disk_blocks.json";
+        let _ = fs::remove_file(path);
+
+        let blocked = Uuid::new_v4();
+        let unblocked = Uuid::new_v4();
+
+        let disk_blocks = DiskBlocks::new();
+        disk_blocks.set(path, &blocked, true).unwrap();
+        disk_blocks.set(path, &unblocked, true).unwrap();
+        disk_blocks.set(path, &unblocked, false).unwrap();
+        assert!(disk_blocks.contains(&blocked));
+        assert!(!disk_blocks.contains(&unblocked));
+
+        // The blocks are still known after a restart
+        let loaded = DiskBlocks::new();
+        loaded.load(path).unwrap();
+        assert!(loaded.contains(&blocked));
+        assert!(!loaded.contains(&unblocked));
+    }
+}
@@ -1,10 +1,24 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde_json;
+use uuid::Uuid;
 use search::Document;
+use search::document::{DocId, FieldValue};
+use search::schema::FieldId;
+use search::backends::rocksdb::RocksDBReader;
 use fnv::FnvHashMap;
 
 use mapping::{Mapping, MappingProperty, FieldValueError};
 
 
+/// The timestamp and sequence number of the last generated document id
+static LAST_DOC_ID: Mutex<(u64, u32)> = Mutex::new((0, 0));
+
+/// Largest sequence number of a generated document id (24 bits)
+const MAX_DOC_ID_SEQUENCE: u32 = 0xff_ffff;
+
+
 #[derive(Debug)]
 pub struct DocumentSource<'a> {
     pub key: &'a str,
@@ -65,7 +79,8 @@ impl<'a> DocumentSource<'a> {
                         }
                     }
 
-                    if field_mapping.is_stored {
+                    // Doc values are kept in the same per-document value store as stored fields
+                    if field_mapping.is_stored || field_mapping.has_doc_values {
                         let value = field_mapping.process_value_for_store(field_value);
 
                         match value {
@@ -118,6 +133,14 @@ impl<'a> DocumentSource<'a> {
             }
         }
 
+        // Insert _source field
+        if let Some(&MappingProperty::Field(ref field_mapping)) = mapping.properties.get("_source") {
+            if let Some(field_ref) = field_mapping.index_ref {
+                let source = serde_json::to_string(self.data).unwrap();
+                stored_fields.insert(field_ref, FieldValue::String(source));
+            }
+        }
+
         Ok(Document {
             key: self.key.to_string(),
             indexed_fields: indexed_fields,
@@ -125,3 +148,64 @@ impl<'a> DocumentSource<'a> {
         })
     }
 }
+
+
+/// Reads a document's source from the field it's stored in. Returns None if it isn't stored
+pub fn read_source_field(index_reader: &RocksDBReader, field_ref: FieldId, doc_id: DocId) -> Option<serde_json::Map<String, serde_json::Value>> {
+    match index_reader.read_stored_field(field_ref, doc_id) {
+        Ok(Some(FieldValue::String(source))) => {
+            match serde_json::from_str(&source) {
+                Ok(serde_json::Value::Object(source)) => Some(source),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+
+/// Generates an id for a document that was indexed without one
+///
+/// Ids start with the time they were generated at in milliseconds, followed by a sequence
+/// number and some random bytes, all in hex. Ids generated by this process sort in the order
+/// they were generated, even if the clock goes backwards.
+pub fn generate_doc_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0);
+
+    let (timestamp, sequence) = {
+        let mut last = LAST_DOC_ID.lock().unwrap();
+        *last = if now > last.0 {
+            (now, 0)
+        } else if last.1 < MAX_DOC_ID_SEQUENCE {
+            (last.0, last.1 + 1)
+        } else {
+            (last.0 + 1, 0)
+        };
+        *last
+    };
+
+    let random = Uuid::new_v4();
+    let random_hex = random.as_bytes()[..6].iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+
+    format!("{:012x}{:06x}{}", timestamp & 0xffff_ffff_ffff, sequence, random_hex)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::generate_doc_id;
+
+    #[test]
+    fn test_generate_doc_id() {
+        let ids = (0..1000).map(|_| generate_doc_id()).collect::<Vec<_>>();
+
+        for id in ids.iter() {
+            assert_eq!(id.len(), 30);
+        }
+
+        // Ids are unique and in the order they were generated
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Runs rusticsearch inside another program
+//!
+//! An `Engine` keeps its indices in a data directory, like a node does, but doesn't start the
+//! HTTP API or join a cluster. Settings, mappings, documents and search requests are given
+//! as the same JSON that the API takes.
+//!
+//! ```no_run
+//! #[macro_use]
+//! extern crate serde_json;
+//! extern crate rusticsearch;
+//!
+//! use rusticsearch::Engine;
+//!
+//! fn main() {
+//!     let engine = Engine::open("data").unwrap();
+//!     engine.create_index("articles", &json!({})).unwrap();
+//!     engine.put_mapping("articles", "article", &json!({
+//!         "properties": {
+//!             "title": {"type": "string"},
+//!         },
+//!     })).unwrap();
+//!     engine.index_document("articles", "article", "1", &json!({"title": "Hello world"})).unwrap();
+//!     engine.refresh("articles").unwrap();
+//!
+//!     let results = engine.search("articles", &json!({"query": {"match": {"title": "hello"}}})).unwrap();
+//!     for hit in results.hits {
+//!         println!("{} {:?}", hit.id, hit.score);
+//!     }
+//! }
+//! ```
+
+use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::Value as Json;
+use slog::{self, Logger};
+
+use search::query::Query;
+use search::collectors::top_score::TopScoreCollector;
+use system::System;
+use settings::Settings;
+use index::Index;
+use index::metadata::IndexMetadata;
+use index::metadata::parse::parse as parse_index_metadata;
+use mapping::parse::{parse as parse_mapping, check_similarities};
+use document::DocumentSource;
+use query_parser::{QueryBuildContext, parse as parse_query};
+use fetch::FetchPhase;
+use scroll::ScrollHit;
+use background;
+
+
+/// How many hits a search returns if the request doesn't give a "size"
+const DEFAULT_SIZE: usize = 10;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    /// The thread pools couldn't be started or the data directory couldn't be locked
+    Startup(String),
+
+    IndexNotFound(String),
+    IndexAlreadyExists(String),
+    MappingNotFound(String),
+
+    /// The index's `index.blocks.*` settings don't allow the operation
+    IndexBlocked(String, &'static str),
+
+    /// The settings, mapping, document or search request couldn't be parsed
+    InvalidRequest(String),
+
+    /// The index's store couldn't be read or written
+    StoreError(String),
+}
+
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::Startup(ref error) => write!(f, "failed to start: {}", error),
+            EngineError::IndexNotFound(ref name) => write!(f, "index {:?} not found", name),
+            EngineError::IndexAlreadyExists(ref name) => write!(f, "an index or alias named {:?} already exists", name),
+            EngineError::MappingNotFound(ref name) => write!(f, "mapping {:?} not found", name),
+            EngineError::IndexBlocked(ref name, operation) => write!(f, "index {} is blocked for {} operations", name, operation),
+            EngineError::InvalidRequest(ref error) => write!(f, "invalid request: {}", error),
+            EngineError::StoreError(ref error) => write!(f, "store error: {}", error),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub index: String,
+    pub id: String,
+    pub score: Option<f32>,
+
+    /// The document as it was indexed
+    pub source: Option<Json>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResults {
+    /// How many documents matched, including ones that aren't on this page
+    pub total_hits: u64,
+
+    pub max_score: Option<f32>,
+
+    /// The hits from "from" to "from" + "size", best first
+    pub hits: Vec<SearchHit>,
+}
+
+
+/// A search engine that runs in-process
+///
+/// Indices are refreshed and merged in the background, as they are on a node. Everything is
+/// flushed to disk when the engine is dropped.
+pub struct Engine {
+    system: Arc<System>,
+}
+
+
+impl Engine {
+    /// Opens the indices in a data directory, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(data_dir: P) -> Result<Engine, EngineError> {
+        let settings = Settings {
+            data_dir: data_dir.as_ref().to_path_buf(),
+            ..Settings::default()
+        };
+
+        Engine::with_settings(settings, Logger::root(slog::Discard, o!()))
+    }
+
+    /// Opens the indices in `settings.data_dir`, logging to `log`
+    ///
+    /// Settings for the API server and cluster are ignored.
+    pub fn with_settings(settings: Settings, log: Logger) -> Result<Engine, EngineError> {
+        let mut system = System::new(log, settings).map_err(|e| EngineError::Startup(e.to_string()))?;
+        system.lock_data_dir().map_err(|e| EngineError::Startup(String::from(e)))?;
+        system.load_stored_scripts();
+        system.load_lifecycle_policies();
+        system.load_cluster_settings();
+        system.load_disk_blocks();
+
+        // Unlike on a node, nothing can be done until the indices have loaded
+        system.load_indices();
+
+        let system = Arc::new(system);
+        background::start_maintenance(&system);
+        background::start_disk_monitor(&system);
+        background::start_refresh(&system);
+        background::start_lifecycle(&system);
+
+        Ok(Engine {
+            system: system,
+        })
+    }
+
+    /// The system the engine runs on, for anything the engine doesn't have a method for
+    pub fn system(&self) -> &Arc<System> {
+        &self.system
+    }
+
+    /// Finds an open index by its name
+    fn get_index(&self, index_name: &str) -> Result<Arc<Index>, EngineError> {
+        let cluster_metadata = self.system.metadata.read().unwrap();
+
+        cluster_metadata.names.find_canonical(index_name)
+            .and_then(|index_ref| cluster_metadata.indices.get(&index_ref))
+            .cloned()
+            .ok_or_else(|| EngineError::IndexNotFound(index_name.to_string()))
+    }
+
+    /// Creates an index. `body` is the same as the body of a create index request, and can
+    /// have "settings", "mappings" and "aliases"
+    pub fn create_index(&self, index_name: &str, body: &Json) -> Result<(), EngineError> {
+        let mut metadata = IndexMetadata::default();
+        parse_index_metadata(&mut metadata, body.clone()).map_err(|e| EngineError::InvalidRequest(format!("couldn't parse index settings: {:?}", e)))?;
+
+        let mut cluster_metadata = self.system.metadata.write().unwrap();
+
+        // Names are shared between indices and aliases
+        if cluster_metadata.names.find_canonical(index_name).is_some() || cluster_metadata.names.is_alias(index_name) {
+            return Err(EngineError::IndexAlreadyExists(index_name.to_string()));
+        }
+
+        for (alias_name, alias) in metadata.aliases.iter() {
+            if alias_name == index_name || cluster_metadata.names.find_canonical(alias_name).is_some() {
+                return Err(EngineError::IndexAlreadyExists(alias_name.to_string()));
+            }
+
+            if alias.is_write_index == Some(true) && cluster_metadata.alias_write_index(alias_name).is_some() {
+                return Err(EngineError::InvalidRequest(format!("alias {:?} already has a write index", alias_name)));
+            }
+        }
+
+        // The mappings' fields can only be added once the store exists
+        let mut mappings = mem::replace(&mut metadata.mappings, Default::default());
+        let index_ref = self.system.create_index(&mut cluster_metadata, index_name, metadata).map_err(EngineError::StoreError)?;
+        let index = &cluster_metadata.indices[&index_ref];
+
+        for mapping in mappings.values_mut() {
+            index.add_mapping_fields(mapping).map_err(EngineError::InvalidRequest)?;
+        }
+
+        let mut index_metadata = index.metadata.write().unwrap();
+        index_metadata.mappings = mappings;
+        index_metadata.save(index.metadata_path()).map_err(|e| EngineError::StoreError(String::from(e)))
+    }
+
+    /// Deletes an index, along with its data
+    pub fn delete_index(&self, index_name: &str) -> Result<(), EngineError> {
+        let mut cluster_metadata = self.system.metadata.write().unwrap();
+
+        match cluster_metadata.names.find_canonical(index_name) {
+            Some(index_ref) => {
+                self.system.delete_index(&mut cluster_metadata, index_ref);
+                Ok(())
+            }
+            None => Err(EngineError::IndexNotFound(index_name.to_string())),
+        }
+    }
+
+    /// Adds or replaces a mapping. `mapping` is an object with "properties", as in a put
+    /// mapping request
+    pub fn put_mapping(&self, index_name: &str, mapping_name: &str, mapping: &Json) -> Result<(), EngineError> {
+        let index = self.get_index(index_name)?;
+        let mapping_builder = parse_mapping(mapping).map_err(|e| EngineError::InvalidRequest(format!("couldn't parse mapping: {:?}", e)))?;
+        let mut index_metadata = index.metadata.write().unwrap();
+
+        if index_metadata.settings.blocks.blocks_metadata_write() {
+            return Err(EngineError::IndexBlocked(index.canonical_name().to_string(), "metadata write"));
+        }
+
+        check_similarities(&mapping_builder, &index_metadata.settings).map_err(|e| EngineError::InvalidRequest(format!("couldn't parse mapping: {:?}", e)))?;
+
+        let mut mapping = mapping_builder.build(&index_metadata);
+        index.add_mapping_fields(&mut mapping).map_err(EngineError::InvalidRequest)?;
+
+        index_metadata.mappings.insert(mapping_name.to_string(), mapping);
+        index_metadata.save(index.metadata_path()).map_err(|e| EngineError::StoreError(String::from(e)))
+    }
+
+    /// Indexes a document, replacing any document with the same id. Returns its version
+    ///
+    /// The document can be searched once the index has been refreshed.
+    pub fn index_document(&self, index_name: &str, mapping_name: &str, id: &str, document: &Json) -> Result<u64, EngineError> {
+        let index = self.get_index(index_name)?;
+        let index_metadata = index.metadata.read().unwrap();
+
+        if index_metadata.settings.blocks.blocks_write() {
+            return Err(EngineError::IndexBlocked(index.canonical_name().to_string(), "write"));
+        }
+
+        let mapping = match index_metadata.mappings.get(mapping_name) {
+            Some(mapping) => mapping,
+            None => return Err(EngineError::MappingNotFound(mapping_name.to_string())),
+        };
+
+        let data = match document.as_object() {
+            Some(data) => data,
+            None => return Err(EngineError::InvalidRequest("document must be an object".to_string())),
+        };
+
+        let doc = DocumentSource {
+            key: id,
+            data: data,
+        }.prepare(mapping).map_err(|e| EngineError::InvalidRequest(format!("couldn't prepare document: {:?}", e)))?;
+
+        match index.shard_for_routing(id).insert_or_update_document(&doc) {
+            Ok(version) => Ok(version.version),
+            Err(e) => Err(EngineError::StoreError(format!("document insert failed: {:?}", e))),
+        }
+    }
+
+    /// Makes the documents indexed so far searchable
+    pub fn refresh(&self, index_name: &str) -> Result<(), EngineError> {
+        self.get_index(index_name)?.refresh().map_err(EngineError::StoreError)
+    }
+
+    /// Searches an index. `request` can have a "query", along with "from" and "size" to
+    /// choose the page of hits. With no query, every document matches
+    pub fn search(&self, index_name: &str, request: &Json) -> Result<SearchResults, EngineError> {
+        let index = self.get_index(index_name)?;
+        let index_metadata = index.metadata.read().unwrap();
+
+        if index_metadata.settings.blocks.blocks_read() {
+            return Err(EngineError::IndexBlocked(index.canonical_name().to_string(), "read"));
+        }
+
+        let read_usize = |key: &str, default: usize| {
+            match request.get(key) {
+                Some(value) => value.as_u64().map(|value| value as usize).ok_or_else(|| EngineError::InvalidRequest(format!("{} must be a positive integer", key))),
+                None => Ok(default),
+            }
+        };
+        let from = read_usize("from", 0)?;
+        let size = read_usize("size", DEFAULT_SIZE)?;
+
+        let max_result_window = index_metadata.settings.max_result_window as usize;
+        match from.checked_add(size) {
+            Some(window) if window <= max_result_window => {}
+            _ => return Err(EngineError::InvalidRequest(format!("from + size must be less than or equal to {}", max_result_window))),
+        }
+
+        let index_reader = index.reader();
+        let query = match request.get("query") {
+            Some(query_json) => {
+                let build_context = QueryBuildContext::new().set_index_metadata(&index_metadata);
+                parse_query(query_json).map_err(|e| EngineError::InvalidRequest(format!("query error: {}", e)))?.build(&build_context, &index_reader.schema())
+            }
+            None => Query::all(),
+        };
+
+        let mut collector = TopScoreCollector::page(from, size);
+        index_reader.search(&mut collector, &query).map_err(EngineError::StoreError)?;
+
+        let total_hits = collector.total_hits();
+        let max_score = collector.max_score();
+        let page = collector.into_page().into_iter().map(|doc| ScrollHit {
+            doc_id: doc.doc_id(),
+            score: doc.score(),
+            sort_values: None,
+        }).collect::<Vec<_>>();
+
+        // Load the source of each hit
+        let fetch_phase = FetchPhase {
+            source_field: index_metadata.get_field_mapping("_source").and_then(|field_mapping| field_mapping.index_ref),
+            ..FetchPhase::new(&query)
+        };
+        let doc_keys = index_reader.document_keys();
+        let hits = page.iter().zip(fetch_phase.fetch(&index_reader, &page)).filter_map(|(hit, mut hit_json)| {
+            doc_keys.get(&hit.doc_id).map(|id| {
+                SearchHit {
+                    index: index.canonical_name().to_string(),
+                    id: id.clone(),
+                    score: hit.score,
+                    source: hit_json.as_object_mut().and_then(|hit_object| hit_object.remove("_source")),
+                }
+            })
+        }).collect();
+
+        Ok(SearchResults {
+            total_hits: total_hits,
+            max_score: max_score,
+            hits: hits,
+        })
+    }
+}
+
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.system.shutdown();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::{Engine, EngineError};
+
+    fn open_engine(name: &str) -> Engine {
+        let path = format!("test_indices/{}", name);
+        let _ = remove_dir_all(&path);
+        Engine::open(path).unwrap()
+    }
+
+    fn create_articles(engine: &Engine) {
+        engine.create_index("articles", &json!({})).unwrap();
+        engine.put_mapping("articles", "article", &json!({
+            "properties": {
+                "title": {"type": "string"},
+            },
+        })).unwrap();
+    }
+
+    #[test]
+    fn test_search() {
+        let engine = open_engine("test_engine_search");
+        create_articles(&engine);
+        engine.index_document("articles", "article", "1", &json!({"title": "Hello world"})).unwrap();
+        engine.index_document("articles", "article", "2", &json!({"title": "Goodbye world"})).unwrap();
+        engine.refresh("articles").unwrap();
+
+        let results = engine.search("articles", &json!({"query": {"match": {"title": "hello"}}})).unwrap();
+        assert_eq!(results.total_hits, 1);
+        assert_eq!(results.hits.len(), 1);
+        assert_eq!(results.hits[0].index, "articles");
+        assert_eq!(results.hits[0].id, "1");
+        assert_eq!(results.hits[0].source, Some(json!({"title": "Hello world"})));
+
+        let results = engine.search("articles", &json!({"size": 1})).unwrap();
+        assert_eq!(results.total_hits, 2);
+        assert_eq!(results.hits.len(), 1);
+    }
+
+    #[test]
+    fn test_reopen() {
+        {
+            let engine = open_engine("test_engine_reopen");
+            create_articles(&engine);
+            engine.index_document("articles", "article", "1", &json!({"title": "Hello world"})).unwrap();
+        }
+
+        // The document was flushed when the engine was dropped
+        let engine = Engine::open("test_indices/test_engine_reopen").unwrap();
+        let results = engine.search("articles", &json!({})).unwrap();
+        assert_eq!(results.total_hits, 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let engine = open_engine("test_engine_errors");
+        create_articles(&engine);
+
+        assert_eq!(engine.create_index("articles", &json!({})), Err(EngineError::IndexAlreadyExists("articles".to_string())));
+        assert_eq!(engine.search("missing", &json!({})), Err(EngineError::IndexNotFound("missing".to_string())));
+        assert_eq!(engine.index_document("articles", "missing", "1", &json!({})), Err(EngineError::MappingNotFound("missing".to_string())));
+        assert!(engine.search("articles", &json!({"size": "ten"})).is_err());
+        assert!(engine.search("articles", &json!({"from": 10000, "size": 1})).is_err());
+        assert!(engine.search("articles", &json!({"from": u64::max_value(), "size": u64::max_value()})).is_err());
+
+        engine.delete_index("articles").unwrap();
+        assert_eq!(engine.refresh("articles"), Err(EngineError::IndexNotFound("articles".to_string())));
+    }
+}
@@ -0,0 +1,215 @@
+//! Loads the data that's returned with each hit of a search
+//!
+//! Searches run in two phases. The query phase matches and scores documents, keeping
+//! only the id, score and sort values of each hit (see `ScrollHit`). Once the page of
+//! hits to return is known, the fetch phase reads the stored data for just those
+//! documents. This keeps the cost of reading the source and stored fields proportional
+//! to the page size rather than the number of documents that matched.
+
+use std::collections::BTreeMap;
+
+use serde_json;
+use serde_json::Value as Json;
+use search::schema::FieldId;
+use search::document::{DocId, FieldValue};
+use search::query::Query;
+use search::script::Script;
+use search::sort::SortValue;
+use search::backends::rocksdb::RocksDBReader;
+
+use index::reader::IndexReader;
+
+use source_filter::SourceFilter;
+use scroll::ScrollHit;
+use highlight::{HighlightField, highlight};
+
+
+pub fn field_value_to_json(value: &FieldValue) -> Json {
+    match *value {
+        FieldValue::String(ref string) => json!(string),
+        FieldValue::Integer(value) => json!(value),
+        FieldValue::Boolean(value) => json!(value),
+        FieldValue::DateTime(ref value) => json!(value.to_rfc3339()),
+        FieldValue::Vector(ref vector) => json!(vector),
+        FieldValue::GeoPoint(ref point) => json!({"lat": point.lat, "lon": point.lon}),
+    }
+}
+
+
+pub fn sort_value_to_json(value: &SortValue) -> Json {
+    match *value {
+        SortValue::Score(score) => json!(score),
+        SortValue::Doc(doc_id) => json!(doc_id),
+        SortValue::Field(None) => Json::Null,
+        SortValue::Field(Some(FieldValue::DateTime(ref value))) => {
+            json!(value.timestamp() * 1000 + value.timestamp_subsec_millis() as i64)
+        }
+        SortValue::Field(Some(ref value)) => field_value_to_json(value),
+        SortValue::Script(Some(value)) => json!(value),
+        SortValue::Script(None) => Json::Null,
+    }
+}
+
+
+/// Converts a hit into JSON without fetching anything for it
+pub fn hit_to_json(hit: &ScrollHit) -> Json {
+    let mut json = json!({
+        "_score": hit.score,
+    });
+
+    if let Some(ref sort_values) = hit.sort_values {
+        json["sort"] = Json::Array(sort_values.iter().map(sort_value_to_json).collect());
+    }
+
+    json
+}
+
+
+/// Describes what to load for each hit
+#[derive(Debug)]
+pub struct FetchPhase<'a> {
+    /// The query that was run. Used for highlighting and explanations
+    pub query: &'a Query,
+
+    /// The field the document source is stored in, if the index has one
+    pub source_field: Option<FieldId>,
+    pub source_filter: SourceFilter,
+
+    /// Stored and doc value fields to return, by name
+    pub fields: Vec<(String, FieldId)>,
+
+    pub script_fields: Vec<(String, Script)>,
+    pub highlight_fields: Vec<HighlightField>,
+    pub explain: bool,
+}
+
+
+impl<'a> FetchPhase<'a> {
+    pub fn new(query: &'a Query) -> FetchPhase<'a> {
+        FetchPhase {
+            query: query,
+            source_field: None,
+            source_filter: SourceFilter::default(),
+            fields: Vec::new(),
+            script_fields: Vec::new(),
+            highlight_fields: Vec::new(),
+            explain: false,
+        }
+    }
+
+    fn read_field(&self, index_reader: &RocksDBReader, field_ref: FieldId, doc_id: DocId) -> Option<FieldValue> {
+        index_reader.read_stored_field(field_ref, doc_id).ok().and_then(|value| value)
+    }
+
+    fn fetch_source(&self, index_reader: &RocksDBReader, doc_id: DocId) -> Option<Json> {
+        let source = match self.source_field.and_then(|field_ref| self.read_field(index_reader, field_ref, doc_id)) {
+            Some(FieldValue::String(source)) => serde_json::from_str::<Json>(&source).ok(),
+            _ => None,
+        };
+
+        match source {
+            Some(Json::Object(source)) => self.source_filter.filter(&source),
+            _ => None,
+        }
+    }
+
+    fn fetch_fields(&self, index_reader: &RocksDBReader, hit: &ScrollHit, doc_id: DocId) -> BTreeMap<String, Vec<Json>> {
+        let mut field_values = BTreeMap::new();
+
+        for &(ref field_name, field_ref) in self.fields.iter() {
+            let value = self.read_field(index_reader, field_ref, doc_id).map(|value| field_value_to_json(&value));
+            field_values.insert(field_name.clone(), value.into_iter().collect());
+        }
+
+        for &(ref field_name, ref script) in self.script_fields.iter() {
+            let value = script.evaluate(hit.score, &mut |field_ref| self.read_field(index_reader, field_ref, doc_id));
+            field_values.insert(field_name.clone(), value.map(|value| json!(value)).into_iter().collect());
+        }
+
+        field_values
+    }
+
+    fn fetch_highlights(&self, index_reader: &RocksDBReader, doc_id: DocId) -> serde_json::Map<String, Json> {
+        let mut highlights = serde_json::Map::new();
+
+        for highlight_field in self.highlight_fields.iter() {
+            let text = match self.read_field(index_reader, highlight_field.field_ref, doc_id) {
+                Some(FieldValue::String(text)) => text,
+                _ => continue,
+            };
+
+            let fragments = highlight(&text, highlight_field.analyzer.as_ref(), |term| self.query.matches_term(highlight_field.field_ref, term), &highlight_field.options);
+            if !fragments.is_empty() {
+                highlights.insert(highlight_field.name.clone(), json!(fragments));
+            }
+        }
+
+        highlights
+    }
+
+    /// Loads the data for a hit and converts it into JSON
+    ///
+    /// The data is read from the shard that the hit was found in
+    pub fn fetch_hit(&self, index_reader: &IndexReader, hit: &ScrollHit) -> Json {
+        let shard_reader = index_reader.shard_for_doc(hit.doc_id);
+        let doc_id = DocId::from_u64(hit.doc_id);
+        let mut hit_json = hit_to_json(hit);
+
+        if let Some(source) = self.fetch_source(shard_reader, doc_id) {
+            hit_json["_source"] = source;
+        }
+
+        if !self.fields.is_empty() || !self.script_fields.is_empty() {
+            hit_json["fields"] = json!(self.fetch_fields(shard_reader, hit, doc_id));
+        }
+
+        let highlights = self.fetch_highlights(shard_reader, doc_id);
+        if !highlights.is_empty() {
+            hit_json["highlight"] = Json::Object(highlights);
+        }
+
+        if self.explain {
+            if let Ok(Some(explanation)) = index_reader.explain(self.query, hit.doc_id) {
+                hit_json["_explanation"] = json!(explanation);
+            }
+        }
+
+        hit_json
+    }
+
+    /// Loads the data for a page of hits
+    pub fn fetch(&self, index_reader: &IndexReader, hits: &[ScrollHit]) -> Vec<Json> {
+        hits.iter().map(|hit| self.fetch_hit(index_reader, hit)).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use search::document::FieldValue;
+    use search::sort::SortValue;
+    use scroll::ScrollHit;
+
+    use super::hit_to_json;
+
+    #[test]
+    fn test_hit_to_json() {
+        let hit = ScrollHit {
+            doc_id: 1,
+            score: Some(1.5f32),
+            sort_values: Some(vec![
+                SortValue::Field(Some(FieldValue::DateTime(Utc.timestamp(1500000000, 123000000)))),
+                SortValue::Field(None),
+                SortValue::Script(Some(2.5)),
+            ]),
+        };
+
+        // Dates are sorted by milliseconds since the epoch, so that's what is returned
+        assert_eq!(hit_to_json(&hit), json!({
+            "_score": 1.5,
+            "sort": [1500000000123i64, null, 2.5],
+        }));
+    }
+}
@@ -0,0 +1,153 @@
+//! Checks the indices in the data directory before they're loaded, and finds the ones that
+//! aren't in the cluster metadata
+//!
+//! When the node starts, each index is rebuilt from its stores, which replay their
+//! write-ahead logs over the last flushed state (see `System::load_indices`). Before that,
+//! the index's saved settings are checked against what's on disk, so an index that's been
+//! partly copied or had its settings edited by hand fails to load instead of coming up with
+//! missing shards.
+//!
+//! Index directories that aren't in the cluster metadata are "dangling". They're left
+//! behind when an index fails to load or its name is taken by an alias, or are copied into
+//! the data directory by hand, and can be imported or deleted with the `_dangling` API.
+
+use std::fs;
+use std::path::Path;
+
+use index::{self, shard_path};
+use index::metadata::IndexMetadata;
+
+
+/// An index directory that isn't in the cluster metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct DanglingIndex {
+    pub name: String,
+
+    /// Milliseconds since the epoch. None for indices from before creation dates were saved
+    pub creation_date: Option<u64>,
+
+    pub closed: bool,
+}
+
+
+/// Checks that the shards on disk are the ones the index's settings say it has
+pub fn verify_index(index_path: &Path, metadata: &IndexMetadata) -> Result<(), String> {
+    let number_of_shards = metadata.settings.number_of_shards as usize;
+
+    // The first shard is stored in the index's own directory
+    for shard in 1..number_of_shards {
+        if !shard_path(index_path, shard).is_dir() {
+            return Err(format!("shard {} is missing, the index's settings say it has {} shards", shard, number_of_shards));
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(index_path.join("shards")) {
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("failed to list shards: {}", e))?;
+
+            if let Some(shard) = entry.file_name().to_str().and_then(|name| name.parse::<usize>().ok()) {
+                if shard >= number_of_shards {
+                    return Err(format!("found data for shard {}, but the index's settings say it has {} shards", shard, number_of_shards));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Lists the index directories whose names aren't known, in name order
+///
+/// Directories without readable metadata are skipped, as they couldn't be imported.
+pub fn find_dangling_indices(indices_dir: &Path, is_known: &Fn(&str) -> bool) -> Vec<DanglingIndex> {
+    let mut dangling_indices = Vec::new();
+
+    let entries = match fs::read_dir(indices_dir) {
+        Ok(entries) => entries,
+        Err(_) => return dangling_indices,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if !path.is_dir() || is_known(&name) {
+            continue;
+        }
+
+        if let Ok(metadata) = IndexMetadata::load(path.join("metadata.json")) {
+            dangling_indices.push(DanglingIndex {
+                name: name,
+                creation_date: metadata.settings.creation_date,
+                closed: index::is_closed(&path),
+            });
+        }
+    }
+
+    dangling_indices.sort_by(|a, b| a.name.cmp(&b.name));
+    dangling_indices
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use index::metadata::IndexMetadata;
+
+    use super::{DanglingIndex, verify_index, find_dangling_indices};
+
+    fn make_index(path: &Path, number_of_shards: u32) -> IndexMetadata {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+
+        let mut metadata = IndexMetadata::default();
+        metadata.settings.number_of_shards = number_of_shards;
+        metadata.settings.creation_date = Some(1500000000000);
+        metadata.save(path.join("metadata.json")).unwrap();
+
+        for shard in 1..number_of_shards {
+            fs::create_dir_all(path.join("shards").join(shard.to_string())).unwrap();
+        }
+
+        metadata
+    }
+
+    #[test]
+    fn test_verify_index() {
+        let path = Path::new("test_indices/test_gateway_verify");
+        let mut metadata = make_index(path, 3);
+        assert!(verify_index(path, &metadata).is_ok());
+
+        // The settings say there are fewer shards than there are on disk
+        metadata.settings.number_of_shards = 2;
+        assert!(verify_index(path, &metadata).is_err());
+
+        // Or more
+        metadata.settings.number_of_shards = 4;
+        assert!(verify_index(path, &metadata).is_err());
+    }
+
+    #[test]
+    fn test_find_dangling_indices() {
+        let indices_dir = Path::new("test_indices/test_gateway_dangling");
+        let _ = fs::remove_dir_all(indices_dir);
+        make_index(&indices_dir.join("logs"), 1);
+        make_index(&indices_dir.join("metrics"), 1);
+        fs::create_dir_all(indices_dir.join("empty")).unwrap();
+
+        let dangling_indices = find_dangling_indices(indices_dir, &|name| name == "metrics");
+        assert_eq!(dangling_indices, vec![
+            DanglingIndex {
+                name: "logs".to_string(),
+                creation_date: Some(1500000000000),
+                closed: false,
+            },
+        ]);
+    }
+}
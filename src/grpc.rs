@@ -0,0 +1,677 @@
+//! The gRPC API (see proto/rusticsearch.proto)
+//!
+//! Calls don't have handlers of their own. Each is turned into the HTTP request that does the
+//! same thing (`to_rest_request`), which is run like any other, and the JSON response is
+//! turned into the call's response message (`from_rest_response`). Messages are framed as
+//! gRPC requires, but compressed messages aren't supported.
+
+use hyper::{Method, StatusCode};
+use serde_json::{self, Map, Value as Json};
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use protobuf::{FieldReader, MessageWriter, Value, DecodeError};
+
+
+/// Calls are made to this followed by the name of the method
+const SERVICE_PATH: &'static str = "/rusticsearch.Rusticsearch/";
+
+pub const GRPC_CONTENT_TYPE: &'static str = "application/grpc";
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrpcMethod {
+    Index,
+    Bulk,
+    Search,
+    Get,
+    Delete,
+}
+
+
+impl GrpcMethod {
+    /// Finds the method a call is for from its path, e.g. "/rusticsearch.Rusticsearch/Search"
+    pub fn from_path(path: &str) -> Option<GrpcMethod> {
+        if !path.starts_with(SERVICE_PATH) {
+            return None;
+        }
+
+        match &path[SERVICE_PATH.len()..] {
+            "Index" => Some(GrpcMethod::Index),
+            "Bulk" => Some(GrpcMethod::Bulk),
+            "Search" => Some(GrpcMethod::Search),
+            "Get" => Some(GrpcMethod::Get),
+            "Delete" => Some(GrpcMethod::Delete),
+            _ => None,
+        }
+    }
+}
+
+
+/// The gRPC status codes that calls can end with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrpcStatus {
+    Ok = 0,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Aborted = 10,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+
+impl GrpcStatus {
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+
+    /// The status of a call whose HTTP request got a response with this status
+    pub fn from_http(status: StatusCode) -> GrpcStatus {
+        match status {
+            _ if status.is_success() => GrpcStatus::Ok,
+            StatusCode::BAD_REQUEST => GrpcStatus::InvalidArgument,
+            StatusCode::UNAUTHORIZED => GrpcStatus::Unauthenticated,
+            StatusCode::FORBIDDEN => GrpcStatus::PermissionDenied,
+            StatusCode::NOT_FOUND => GrpcStatus::NotFound,
+            StatusCode::CONFLICT => GrpcStatus::Aborted,
+            StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => GrpcStatus::ResourceExhausted,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => GrpcStatus::DeadlineExceeded,
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => GrpcStatus::Unimplemented,
+            StatusCode::SERVICE_UNAVAILABLE => GrpcStatus::Unavailable,
+            _ => GrpcStatus::Internal,
+        }
+    }
+}
+
+
+/// Reads the message from the body of a call
+///
+/// A message is prefixed by a byte that says if it's compressed, then its length as four
+/// big endian bytes. Unary calls have exactly one.
+pub fn read_message(body: &[u8]) -> Result<&[u8], (GrpcStatus, String)> {
+    if body.len() < 5 {
+        return Err((GrpcStatus::InvalidArgument, "The request must contain a message".to_string()));
+    }
+
+    if body[0] != 0 {
+        return Err((GrpcStatus::Unimplemented, "Compressed messages aren't supported".to_string()));
+    }
+
+    let length = body[1..5].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+    if length != body.len() - 5 {
+        return Err((GrpcStatus::InvalidArgument, "The request must contain a single message".to_string()));
+    }
+
+    Ok(&body[5..])
+}
+
+
+/// Frames a message to send in the body of a response
+pub fn write_message(message: &[u8]) -> Vec<u8> {
+    let length = message.len() as u32;
+    let mut body = vec![0, (length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+    body.extend_from_slice(message);
+    body
+}
+
+
+/// Percent-encodes an error message for the "grpc-message" trailer
+pub fn encode_status_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
+
+
+/// An HTTP request that does the same as a call
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestRequest {
+    pub method: Method,
+
+    /// Includes the query string
+    pub path: String,
+
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+
+fn read_string(field_number: u32, value: &Value) -> Result<String, DecodeError> {
+    value.as_str().map(|value| value.to_string()).ok_or(DecodeError::WrongType(field_number))
+}
+
+
+fn read_bytes<'a>(field_number: u32, value: &Value<'a>) -> Result<&'a [u8], DecodeError> {
+    value.as_bytes().ok_or(DecodeError::WrongType(field_number))
+}
+
+
+fn encode_path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()
+}
+
+
+/// Adds the parameters that aren't empty to a path as a query string
+fn with_query(path: String, params: &[(&str, &str)]) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    let mut has_params = false;
+
+    for &(name, value) in params.iter().filter(|&&(_, value)| !value.is_empty()) {
+        query.append_pair(name, value);
+        has_params = true;
+    }
+
+    if has_params {
+        format!("{}?{}", path, query.finish())
+    } else {
+        path
+    }
+}
+
+
+/// The fields of IndexRequest, GetRequest and DeleteRequest, which share their numbering
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DocumentRequest {
+    index: String,
+    doc_type: String,
+    id: String,
+    source: Vec<u8>,
+    refresh: String,
+    routing: String,
+}
+
+
+impl DocumentRequest {
+    fn decode(bytes: &[u8]) -> Result<DocumentRequest, DecodeError> {
+        let mut request = DocumentRequest::default();
+
+        for field in FieldReader::new(bytes) {
+            let (field_number, value) = field?;
+            match field_number {
+                1 => request.index = read_string(field_number, &value)?,
+                2 => request.doc_type = read_string(field_number, &value)?,
+                3 => request.id = read_string(field_number, &value)?,
+                4 => request.source = read_bytes(field_number, &value)?.to_vec(),
+                5 => request.refresh = read_string(field_number, &value)?,
+                6 => request.routing = read_string(field_number, &value)?,
+                _ => {}
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// The path of the document, or of its type if it has no id
+    fn path(&self) -> Result<String, String> {
+        if self.index.is_empty() || self.doc_type.is_empty() {
+            return Err("index and type are required".to_string());
+        }
+
+        let mut path = format!("/{}/{}", encode_path_segment(&self.index), encode_path_segment(&self.doc_type));
+        if !self.id.is_empty() {
+            path.push('/');
+            path.push_str(&encode_path_segment(&self.id));
+        }
+
+        Ok(path)
+    }
+
+    fn path_with_id(&self) -> Result<String, String> {
+        if self.id.is_empty() {
+            return Err("id is required".to_string());
+        }
+
+        self.path()
+    }
+}
+
+
+/// Turns the items of a BulkRequest into the body of a bulk request
+fn bulk_body(items: &[(&'static str, DocumentRequest)]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+
+    for (position, (action, request)) in items.iter().enumerate() {
+        let mut params = Map::new();
+        params.insert("_index".to_string(), Json::String(request.index.clone()));
+        params.insert("_type".to_string(), Json::String(request.doc_type.clone()));
+
+        if !request.id.is_empty() {
+            params.insert("_id".to_string(), Json::String(request.id.clone()));
+        }
+
+        if !request.routing.is_empty() {
+            params.insert("routing".to_string(), Json::String(request.routing.clone()));
+        }
+
+        let mut action_json = Map::new();
+        action_json.insert(action.to_string(), Json::Object(params));
+        body.extend(Json::Object(action_json).to_string().into_bytes());
+        body.push(b'\n');
+
+        // The source has to be on one line, so it's written out again
+        if *action == "index" {
+            let source: Json = serde_json::from_slice(&request.source).map_err(|e| format!("source of item {} isn't valid JSON: {}", position, e))?;
+            body.extend(source.to_string().into_bytes());
+            body.push(b'\n');
+        }
+    }
+
+    Ok(body)
+}
+
+
+/// The items of a BulkRequest, with the action of each, and its refresh
+type BulkRequest = (Vec<(&'static str, DocumentRequest)>, String);
+
+
+fn decode_bulk_request(bytes: &[u8]) -> Result<BulkRequest, String> {
+    let mut items = Vec::new();
+    let mut refresh = String::new();
+
+    for field in FieldReader::new(bytes) {
+        let (field_number, value) = field.map_err(|e| format!("{:?}", e))?;
+        match field_number {
+            1 => {
+                let mut item = None;
+                for item_field in FieldReader::new(read_bytes(field_number, &value).map_err(|e| format!("{:?}", e))?) {
+                    let (item_field_number, item_value) = item_field.map_err(|e| format!("{:?}", e))?;
+                    let action = match item_field_number {
+                        1 => "index",
+                        2 => "delete",
+                        _ => continue,
+                    };
+
+                    // Only the last action of a oneof counts
+                    let request = DocumentRequest::decode(read_bytes(item_field_number, &item_value).map_err(|e| format!("{:?}", e))?).map_err(|e| format!("{:?}", e))?;
+                    item = Some((action, request));
+                }
+
+                match item {
+                    Some(item) => items.push(item),
+                    None => return Err(format!("item {} has no action", items.len())),
+                }
+            }
+            2 => refresh = read_string(field_number, &value).map_err(|e| format!("{:?}", e))?,
+            _ => {}
+        }
+    }
+
+    Ok((items, refresh))
+}
+
+
+/// Turns the message of a call into the HTTP request that does the same thing
+///
+/// Returns an error message if the call's message is invalid.
+pub fn to_rest_request(method: GrpcMethod, message: &[u8]) -> Result<RestRequest, String> {
+    let json_request = |method: Method, path: String, body: Vec<u8>| {
+        RestRequest {
+            method: method,
+            path: path,
+            content_type: "application/json",
+            body: body,
+        }
+    };
+
+    match method {
+        GrpcMethod::Index => {
+            let request = DocumentRequest::decode(message).map_err(|e| format!("couldn't decode request: {:?}", e))?;
+            if request.source.is_empty() {
+                return Err("source is required".to_string());
+            }
+
+            let http_method = if request.id.is_empty() { Method::POST } else { Method::PUT };
+            let path = with_query(request.path()?, &[("refresh", &request.refresh), ("routing", &request.routing)]);
+            Ok(json_request(http_method, path, request.source))
+        }
+        GrpcMethod::Get => {
+            let request = DocumentRequest::decode(message).map_err(|e| format!("couldn't decode request: {:?}", e))?;
+            let path = with_query(request.path_with_id()?, &[("routing", &request.routing)]);
+            Ok(json_request(Method::GET, path, Vec::new()))
+        }
+        GrpcMethod::Delete => {
+            let request = DocumentRequest::decode(message).map_err(|e| format!("couldn't decode request: {:?}", e))?;
+            let path = with_query(request.path_with_id()?, &[("refresh", &request.refresh), ("routing", &request.routing)]);
+            Ok(json_request(Method::DELETE, path, Vec::new()))
+        }
+        GrpcMethod::Search => {
+            let mut index = String::new();
+            let mut body = b"{}".to_vec();
+            for field in FieldReader::new(message) {
+                let (field_number, value) = field.map_err(|e| format!("couldn't decode request: {:?}", e))?;
+                match field_number {
+                    1 => index = read_string(field_number, &value).map_err(|e| format!("couldn't decode request: {:?}", e))?,
+                    2 => body = read_bytes(field_number, &value).map_err(|e| format!("couldn't decode request: {:?}", e))?.to_vec(),
+                    _ => {}
+                }
+            }
+
+            let path = if index.is_empty() { "/_search".to_string() } else { format!("/{}/_search", encode_path_segment(&index)) };
+            Ok(json_request(Method::POST, path, body))
+        }
+        GrpcMethod::Bulk => {
+            let (items, refresh) = decode_bulk_request(message).map_err(|e| format!("couldn't decode request: {}", e))?;
+
+            Ok(RestRequest {
+                method: Method::POST,
+                path: with_query("/_bulk".to_string(), &[("refresh", &refresh)]),
+                content_type: "application/x-ndjson",
+                body: bulk_body(&items)?,
+            })
+        }
+    }
+}
+
+
+fn json_str<'a>(json: &'a Json, key: &str) -> &'a str {
+    json.get(key).and_then(|value| value.as_str()).unwrap_or("")
+}
+
+
+fn json_u64(json: &Json, key: &str) -> u64 {
+    json.get(key).and_then(|value| value.as_u64()).unwrap_or(0)
+}
+
+
+fn json_f32(json: &Json, key: &str) -> f32 {
+    json.get(key).and_then(|value| value.as_f64()).unwrap_or(0.0) as f32
+}
+
+
+/// Documents and aggregations are sent as JSON. Missing ones are left empty
+fn json_bytes(json: &Json, key: &str) -> Vec<u8> {
+    match json.get(key) {
+        Some(&Json::Null) | None => Vec::new(),
+        Some(value) => value.to_string().into_bytes(),
+    }
+}
+
+
+/// Checks if the response to a call's HTTP request means the call succeeded
+///
+/// Getting a document that doesn't exist succeeds, with `found` set to false.
+pub fn is_success(method: GrpcMethod, status: StatusCode, body: &Json) -> bool {
+    status.is_success() || (method == GrpcMethod::Get && status == StatusCode::NOT_FOUND && body.get("found") == Some(&Json::Bool(false)))
+}
+
+
+/// The message of an error response
+pub fn error_message(status: StatusCode, body: &Json) -> String {
+    let reason = body.get("message").and_then(|message| message.as_str())
+        .or_else(|| body.get("error").and_then(|error| error.get("reason").or(Some(error))).and_then(|reason| reason.as_str()));
+
+    match reason {
+        Some(reason) => reason.to_string(),
+        None => status.canonical_reason().unwrap_or("Unknown error").to_string(),
+    }
+}
+
+
+/// Turns the JSON response to a call's HTTP request into the call's response message
+pub fn from_rest_response(method: GrpcMethod, body: &Json) -> Vec<u8> {
+    let mut message = MessageWriter::new();
+
+    match method {
+        GrpcMethod::Index | GrpcMethod::Delete => {
+            message.string(1, json_str(body, "_index"));
+            message.string(2, json_str(body, "_type"));
+            message.string(3, json_str(body, "_id"));
+            message.uint64(4, json_u64(body, "_version"));
+            message.string(5, json_str(body, "result"));
+        }
+        GrpcMethod::Get => {
+            message.string(1, json_str(body, "_index"));
+            message.string(2, json_str(body, "_type"));
+            message.string(3, json_str(body, "_id"));
+            message.bool(4, body.get("found").and_then(|found| found.as_bool()).unwrap_or(false));
+            message.uint64(5, json_u64(body, "_version"));
+            message.bytes(6, &json_bytes(body, "_source"));
+        }
+        GrpcMethod::Bulk => {
+            message.uint64(1, json_u64(body, "took"));
+            message.bool(2, body.get("errors").and_then(|errors| errors.as_bool()).unwrap_or(false));
+
+            for item in body.get("items").and_then(|items| items.as_array()).map_or(&[][..], |items| &items[..]) {
+                // Each item is keyed by its action
+                let item = match item.as_object().and_then(|item| item.values().next()) {
+                    Some(item) => item,
+                    None => continue,
+                };
+
+                let mut item_message = MessageWriter::new();
+                item_message.string(1, json_str(item, "_index"));
+                item_message.string(2, json_str(item, "_type"));
+                item_message.string(3, json_str(item, "_id"));
+                item_message.uint64(4, json_u64(item, "status"));
+                item_message.uint64(5, json_u64(item, "_version"));
+                item_message.string(6, json_str(item, "result"));
+
+                if item.get("error").is_some() {
+                    item_message.string(7, &error_message(StatusCode::INTERNAL_SERVER_ERROR, item));
+                }
+
+                message.message(3, &item_message);
+            }
+        }
+        GrpcMethod::Search => {
+            let hits = body.get("hits").unwrap_or(&Json::Null);
+
+            // The total is an object if "track_total_hits" was given
+            let total_hits = match hits.get("total") {
+                Some(Json::Object(total)) => total.get("value").and_then(|value| value.as_u64()).unwrap_or(0),
+                Some(total) => total.as_u64().unwrap_or(0),
+                None => 0,
+            };
+
+            message.bool(1, body.get("timed_out").and_then(|timed_out| timed_out.as_bool()).unwrap_or(false));
+            message.uint64(2, total_hits);
+            message.float(3, json_f32(hits, "max_score"));
+
+            for hit in hits.get("hits").and_then(|hits| hits.as_array()).map_or(&[][..], |hits| &hits[..]) {
+                let mut hit_message = MessageWriter::new();
+                hit_message.string(1, json_str(hit, "_index"));
+                hit_message.string(2, json_str(hit, "_id"));
+                hit_message.float(3, json_f32(hit, "_score"));
+                hit_message.bytes(4, &json_bytes(hit, "_source"));
+                message.message(4, &hit_message);
+            }
+
+            message.bytes(5, &json_bytes(body, "aggregations"));
+        }
+    }
+
+    message.into_bytes()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Method, StatusCode};
+    use serde_json::{self, Value as Json};
+
+    use protobuf::{FieldReader, MessageWriter, Value};
+    use super::{GrpcMethod, GrpcStatus, RestRequest, read_message, write_message, encode_status_message, to_rest_request, from_rest_response, is_success, error_message};
+
+    fn document_request(index: &str, doc_type: &str, id: &str, source: &str) -> MessageWriter {
+        let mut message = MessageWriter::new();
+        message.string(1, index);
+        message.string(2, doc_type);
+        message.string(3, id);
+        message.bytes(4, source.as_bytes());
+        message
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(GrpcMethod::from_path("/rusticsearch.Rusticsearch/Search"), Some(GrpcMethod::Search));
+        assert_eq!(GrpcMethod::from_path("/rusticsearch.Rusticsearch/Bulk"), Some(GrpcMethod::Bulk));
+        assert_eq!(GrpcMethod::from_path("/rusticsearch.Rusticsearch/Reindex"), None);
+        assert_eq!(GrpcMethod::from_path("/_search"), None);
+    }
+
+    #[test]
+    fn test_from_http() {
+        assert_eq!(GrpcStatus::from_http(StatusCode::CREATED), GrpcStatus::Ok);
+        assert_eq!(GrpcStatus::from_http(StatusCode::NOT_FOUND), GrpcStatus::NotFound);
+        assert_eq!(GrpcStatus::from_http(StatusCode::TOO_MANY_REQUESTS), GrpcStatus::ResourceExhausted);
+        assert_eq!(GrpcStatus::from_http(StatusCode::BAD_GATEWAY), GrpcStatus::Internal);
+        assert_eq!(GrpcStatus::Unauthenticated.code(), 16);
+    }
+
+    #[test]
+    fn test_messages() {
+        let body = write_message(b"hello");
+        assert_eq!(body, vec![0, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(read_message(&body), Ok(&b"hello"[..]));
+
+        assert_eq!(read_message(&[0, 0, 0, 0, 6, b'h']).unwrap_err().0, GrpcStatus::InvalidArgument);
+        assert_eq!(read_message(&[1, 0, 0, 0, 1, b'h']).unwrap_err().0, GrpcStatus::Unimplemented);
+        assert_eq!(read_message(&[]).unwrap_err().0, GrpcStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn test_encode_status_message() {
+        assert_eq!(encode_status_message("Index not found"), "Index not found");
+        assert_eq!(encode_status_message("100% café\n"), "100%25 caf%C3%A9%0A");
+    }
+
+    #[test]
+    fn test_document_requests() {
+        let message = document_request("articles", "article", "a b", "{\"title\": \"Hello\"}").into_bytes();
+        assert_eq!(to_rest_request(GrpcMethod::Index, &message), Ok(RestRequest {
+            method: Method::PUT,
+            path: "/articles/article/a%20b".to_string(),
+            content_type: "application/json",
+            body: b"{\"title\": \"Hello\"}".to_vec(),
+        }));
+
+        let mut message = document_request("articles", "article", "", "{}");
+        message.string(5, "wait_for");
+        let request = to_rest_request(GrpcMethod::Index, &message.into_bytes()).unwrap();
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/articles/article?refresh=wait_for");
+
+        let mut message = document_request("articles", "article", "1", "");
+        message.string(6, "user1");
+        let request = to_rest_request(GrpcMethod::Get, &message.into_bytes()).unwrap();
+        assert_eq!(request.method, Method::GET);
+        assert_eq!(request.path, "/articles/article/1?routing=user1");
+
+        let request = to_rest_request(GrpcMethod::Delete, &document_request("articles", "article", "1", "").into_bytes()).unwrap();
+        assert_eq!(request.method, Method::DELETE);
+        assert_eq!(request.path, "/articles/article/1");
+
+        assert!(to_rest_request(GrpcMethod::Get, &document_request("articles", "article", "", "").into_bytes()).is_err());
+        assert!(to_rest_request(GrpcMethod::Index, &document_request("articles", "", "1", "{}").into_bytes()).is_err());
+        assert!(to_rest_request(GrpcMethod::Index, &document_request("articles", "article", "1", "").into_bytes()).is_err());
+
+        // The index must be a string
+        let mut message = MessageWriter::new();
+        message.uint64(1, 5);
+        assert!(to_rest_request(GrpcMethod::Get, &message.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_search_request() {
+        let request = to_rest_request(GrpcMethod::Search, &[]).unwrap();
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/_search");
+        assert_eq!(request.body, b"{}".to_vec());
+
+        let mut message = MessageWriter::new();
+        message.string(1, "logs-*,articles");
+        message.bytes(2, b"{\"size\": 1}");
+        let request = to_rest_request(GrpcMethod::Search, &message.into_bytes()).unwrap();
+        assert_eq!(request.path, "/logs-*,articles/_search");
+        assert_eq!(request.body, b"{\"size\": 1}".to_vec());
+    }
+
+    #[test]
+    fn test_bulk_request() {
+        let mut index_item = MessageWriter::new();
+        index_item.message(1, &document_request("articles", "article", "1", "{\n  \"title\": \"Hello\"\n}"));
+        let mut delete_item = MessageWriter::new();
+        delete_item.message(2, &document_request("articles", "article", "2", ""));
+
+        let mut message = MessageWriter::new();
+        message.message(1, &index_item);
+        message.message(1, &delete_item);
+        message.string(2, "true");
+
+        let request = to_rest_request(GrpcMethod::Bulk, &message.into_bytes()).unwrap();
+        assert_eq!(request.path, "/_bulk?refresh=true");
+        assert_eq!(request.content_type, "application/x-ndjson");
+
+        let lines = String::from_utf8(request.body).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<Json>>();
+        assert_eq!(lines, vec![
+            json!({"index": {"_index": "articles", "_type": "article", "_id": "1"}}),
+            json!({"title": "Hello"}),
+            json!({"delete": {"_index": "articles", "_type": "article", "_id": "2"}}),
+        ]);
+
+        // Sources have to be valid JSON to be put on one line
+        let mut index_item = MessageWriter::new();
+        index_item.message(1, &document_request("articles", "article", "1", "{"));
+        let mut message = MessageWriter::new();
+        message.message(1, &index_item);
+        assert!(to_rest_request(GrpcMethod::Bulk, &message.into_bytes()).is_err());
+
+        let mut message = MessageWriter::new();
+        message.message(1, &MessageWriter::new());
+        assert!(to_rest_request(GrpcMethod::Bulk, &message.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_search_response() {
+        let bytes = from_rest_response(GrpcMethod::Search, &json!({
+            "timed_out": false,
+            "hits": {
+                "total": {"value": 2, "relation": "eq"},
+                "max_score": 1.5,
+                "hits": [
+                    {"_index": "articles", "_score": 1.5, "_source": {"title": "Hello"}},
+                    {"_index": "articles", "_score": 0.5},
+                ],
+            },
+        }));
+
+        let fields = FieldReader::new(&bytes).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(fields[0], (2, Value::Varint(2)));
+        assert_eq!(fields[1].1.as_f32(), Some(1.5));
+        assert_eq!(fields.iter().filter(|&&(field_number, _)| field_number == 4).count(), 2);
+
+        let hit = FieldReader::new(fields[2].1.as_bytes().unwrap()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(hit[0], (1, Value::LengthDelimited(b"articles")));
+        assert_eq!(hit[1].1.as_f32(), Some(1.5));
+        assert_eq!(serde_json::from_slice::<Json>(hit[2].1.as_bytes().unwrap()).unwrap(), json!({"title": "Hello"}));
+    }
+
+    #[test]
+    fn test_get_response() {
+        let not_found = json!({"_index": "articles", "_type": "article", "_id": "1", "found": false});
+        assert!(is_success(GrpcMethod::Get, StatusCode::NOT_FOUND, &not_found));
+        assert!(!is_success(GrpcMethod::Get, StatusCode::NOT_FOUND, &json!({"message": "Index not found"})));
+        assert!(!is_success(GrpcMethod::Delete, StatusCode::NOT_FOUND, &not_found));
+
+        let bytes = from_rest_response(GrpcMethod::Get, &not_found);
+        let fields = FieldReader::new(&bytes).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(fields.len(), 3);
+
+        assert_eq!(error_message(StatusCode::NOT_FOUND, &json!({"message": "Index not found"})), "Index not found");
+        assert_eq!(error_message(StatusCode::CONFLICT, &json!({"error": {"type": "version_conflict_engine_exception", "reason": "version conflict"}})), "version conflict");
+        assert_eq!(error_message(StatusCode::BAD_GATEWAY, &Json::Null), "Bad Gateway");
+    }
+}
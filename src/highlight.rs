@@ -0,0 +1,213 @@
+//! Finds the parts of a field's value that matched a query and marks them up
+
+use unicode_segmentation::UnicodeSegmentation;
+use search::term::Term;
+use search::schema::FieldId;
+
+use analysis::AnalyzerSpec;
+
+
+const DEFAULT_PRE_TAG: &'static str = "<em>";
+const DEFAULT_POST_TAG: &'static str = "</em>";
+const DEFAULT_FRAGMENT_SIZE: usize = 100;
+const DEFAULT_NUMBER_OF_FRAGMENTS: usize = 5;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HighlightOptions {
+    pub pre_tag: String,
+    pub post_tag: String,
+
+    /// The approximate length of each fragment, in bytes
+    pub fragment_size: usize,
+
+    /// The maximum number of fragments to return. If zero, the whole value is returned as one fragment
+    pub number_of_fragments: usize,
+}
+
+
+impl Default for HighlightOptions {
+    fn default() -> HighlightOptions {
+        HighlightOptions {
+            pre_tag: DEFAULT_PRE_TAG.to_string(),
+            post_tag: DEFAULT_POST_TAG.to_string(),
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            number_of_fragments: DEFAULT_NUMBER_OF_FRAGMENTS,
+        }
+    }
+}
+
+
+/// A field to highlight in each hit
+#[derive(Debug, Clone, PartialEq)]
+pub struct HighlightField {
+    pub name: String,
+    pub field_ref: FieldId,
+
+    /// The analyzer the field was indexed with
+    pub analyzer: Option<AnalyzerSpec>,
+
+    pub options: HighlightOptions,
+}
+
+
+/// Returns the words in the text along with their byte offsets
+///
+/// These are the same words that the standard tokenizer produces
+pub fn word_indices<'a>(text: &'a str) -> Box<Iterator<Item=(usize, &'a str)> + 'a> {
+    Box::new(text.split_word_bound_indices().filter(|&(_, word)| word.chars().any(|c| c.is_alphanumeric())))
+}
+
+
+/// Finds the byte ranges of the words in the text that match
+///
+/// As tokens don't record their offsets, each word is re-analyzed on its own. If there
+/// isn't an analyzer, the whole text is treated as a single term.
+fn find_matches<F: Fn(&Term) -> bool>(text: &str, analyzer: Option<&AnalyzerSpec>, is_match: &F) -> Vec<(usize, usize)> {
+    let analyzer = match analyzer {
+        Some(analyzer) => analyzer,
+        None => {
+            if is_match(&Term::from_string(text)) {
+                return vec![(0, text.len())];
+            } else {
+                return vec![];
+            }
+        }
+    };
+
+    word_indices(text)
+        .filter(|&(_, word)| analyzer.initialise(word).any(|token| is_match(&token.term)))
+        .map(|(start, word)| (start, start + word.len()))
+        .collect()
+}
+
+
+/// Splits the text into fragments on word boundaries
+fn find_fragments(text: &str, fragment_size: usize) -> Vec<(usize, usize)> {
+    let mut fragments = Vec::new();
+    let mut fragment_start = 0;
+    let mut fragment_end = 0;
+
+    for (start, word) in word_indices(text) {
+        let end = start + word.len();
+
+        if end - fragment_start > fragment_size && fragment_end > fragment_start {
+            fragments.push((fragment_start, fragment_end));
+            fragment_start = start;
+        }
+
+        fragment_end = end;
+    }
+
+    if fragment_end > fragment_start {
+        fragments.push((fragment_start, fragment_end));
+    }
+
+    fragments
+}
+
+
+fn mark_up(text: &str, start: usize, end: usize, matches: &[(usize, usize)], options: &HighlightOptions) -> String {
+    let mut fragment = String::with_capacity(end - start);
+    let mut position = start;
+
+    for &(match_start, match_end) in matches.iter().filter(|&&(s, e)| s >= start && e <= end) {
+        fragment.push_str(&text[position..match_start]);
+        fragment.push_str(&options.pre_tag);
+        fragment.push_str(&text[match_start..match_end]);
+        fragment.push_str(&options.post_tag);
+        position = match_end;
+    }
+
+    fragment.push_str(&text[position..end]);
+    fragment
+}
+
+
+/// Returns the highlighted fragments of the text, in the order they appear
+///
+/// Only fragments that contain a match are returned. `is_match` is called with each
+/// term produced by the analyzer.
+pub fn highlight<F: Fn(&Term) -> bool>(text: &str, analyzer: Option<&AnalyzerSpec>, is_match: F, options: &HighlightOptions) -> Vec<String> {
+    let matches = find_matches(text, analyzer, &is_match);
+    if matches.is_empty() {
+        return Vec::new();
+    }
+
+    if options.number_of_fragments == 0 {
+        return vec![mark_up(text, 0, text.len(), &matches, options)];
+    }
+
+    // Pick the fragments with the most matches
+    let mut fragments = find_fragments(text, options.fragment_size).into_iter()
+        .map(|(start, end)| {
+            let num_matches = matches.iter().filter(|&&(s, e)| s >= start && e <= end).count();
+            (start, end, num_matches)
+        })
+        .filter(|&(_, _, num_matches)| num_matches > 0)
+        .collect::<Vec<_>>();
+
+    fragments.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    fragments.truncate(options.number_of_fragments);
+    fragments.sort_by_key(|&(start, _, _)| start);
+
+    fragments.iter().map(|&(start, end, _)| mark_up(text, start, end, &matches, options)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::term::Term;
+
+    use analysis::AnalyzerSpec;
+    use analysis::tokenizers::TokenizerSpec;
+    use analysis::filters::FilterSpec;
+
+    use super::{highlight, HighlightOptions};
+
+    fn lowercase_analyzer() -> AnalyzerSpec {
+        AnalyzerSpec {
+            tokenizer: TokenizerSpec::Standard,
+            filters: vec![FilterSpec::Lowercase],
+        }
+    }
+
+    #[test]
+    fn test_highlight() {
+        let options = HighlightOptions::default();
+        let fragments = highlight("The Quick brown fox", Some(&lowercase_analyzer()), |term| *term == Term::from_string("quick"), &options);
+
+        assert_eq!(fragments, vec!["The <em>Quick</em> brown fox".to_string()]);
+    }
+
+    #[test]
+    fn test_highlight_fragments() {
+        let options = HighlightOptions {
+            pre_tag: "[".to_string(),
+            post_tag: "]".to_string(),
+            fragment_size: 15,
+            number_of_fragments: 1,
+        };
+        let text = "one two three four two six seven";
+        let fragments = highlight(text, Some(&lowercase_analyzer()), |term| *term == Term::from_string("two") || *term == Term::from_string("six"), &options);
+
+        // The fragment with both matches wins
+        assert_eq!(fragments, vec!["four [two] [six]".to_string()]);
+    }
+
+    #[test]
+    fn test_highlight_no_match() {
+        let options = HighlightOptions::default();
+        let fragments = highlight("The Quick brown fox", Some(&lowercase_analyzer()), |_| false, &options);
+
+        assert!(fragments.is_empty());
+    }
+
+    #[test]
+    fn test_highlight_not_analyzed() {
+        let options = HighlightOptions::default();
+        let fragments = highlight("New York", None, |term| *term == Term::from_string("New York"), &options);
+
+        assert_eq!(fragments, vec!["<em>New York</em>".to_string()]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Reads CSV files, as described in RFC 4180
+//!
+//! Fields are separated by commas. A field in double quotes can contain commas, line breaks
+//! and quotes, which are written twice.
+
+use std::io::{self, BufRead};
+use std::mem;
+
+
+pub struct CsvReader<R: BufRead> {
+    reader: R,
+
+    /// How many lines have been read so far
+    lines_read: u64,
+}
+
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R) -> CsvReader<R> {
+        CsvReader {
+            reader: reader,
+            lines_read: 0,
+        }
+    }
+
+    /// Reads the fields of the next record, and the line it starts on. Returns None at the end
+    /// of the input
+    pub fn read_record(&mut self) -> io::Result<Option<(u64, Vec<String>)>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        self.lines_read += 1;
+        let line_number = self.lines_read;
+
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut quoted = false;
+
+        loop {
+            {
+                let mut chars = line.trim_end_matches(&['\n', '\r'][..]).chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' if in_quotes => {
+                            if chars.peek() == Some(&'"') {
+                                chars.next();
+                                field.push('"');
+                            } else {
+                                in_quotes = false;
+                            }
+                        }
+                        '"' if field.is_empty() && !quoted => {
+                            in_quotes = true;
+                            quoted = true;
+                        }
+                        ',' if !in_quotes => {
+                            fields.push(mem::take(&mut field));
+                            quoted = false;
+                        }
+                        c => field.push(c),
+                    }
+                }
+            }
+
+            if !in_quotes {
+                break;
+            }
+
+            // The quoted field carries on over the next line
+            field.push('\n');
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the quoted field on line {} isn't closed", line_number)));
+            }
+            self.lines_read += 1;
+        }
+
+        fields.push(field);
+        Ok(Some((line_number, fields)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::CsvReader;
+
+    fn read_all(input: &str) -> Vec<(u64, Vec<String>)> {
+        let mut reader = CsvReader::new(Cursor::new(input.as_bytes()));
+        let mut records = Vec::new();
+        while let Some(record) = reader.read_record().unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    fn fields(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(read_all("id,title\r\n1,Hello\n2,\n"), vec![
+            (1, fields(&["id", "title"])),
+            (2, fields(&["1", "Hello"])),
+            (3, fields(&["2", ""])),
+        ]);
+
+        assert_eq!(read_all(""), vec![]);
+    }
+
+    #[test]
+    fn test_quoted() {
+        assert_eq!(read_all("\"Hello, world\",\"She said \"\"hi\"\"\",\"\"\n"), vec![
+            (1, fields(&["Hello, world", "She said \"hi\"", ""])),
+        ]);
+
+        // Quotes only start a quoted field at its start
+        assert_eq!(read_all("5\" disk,x\n"), vec![(1, fields(&["5\" disk", "x"]))]);
+    }
+
+    #[test]
+    fn test_multiline() {
+        assert_eq!(read_all("1,\"first line\nsecond line\"\n2,x\n"), vec![
+            (1, fields(&["1", "first line\nsecond line"])),
+            (3, fields(&["2", "x"])),
+        ]);
+
+        let mut reader = CsvReader::new(Cursor::new(&b"1,\"never closed\n"[..]));
+        assert!(reader.read_record().is_err());
+    }
+}
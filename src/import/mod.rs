@@ -0,0 +1,811 @@
+//! Imports NDJSON and CSV files into an index (the `rusticsearch-import` binary)
+//!
+//! Documents are read from the file on one thread and indexed in batches by a few others,
+//! either through the bulk API of a running node (`HttpDestination`) or straight into a data
+//! directory with the embedded engine (`EngineDestination`). A document that can't be read
+//! or indexed is counted as failed, and the import carries on without it.
+
+pub mod csv;
+
+use std::io::BufRead;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64;
+use hyper::Method;
+use serde_json::{self, Map, Number, Value as Json};
+use url::Url;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use cluster::transport::{Transport, TransportRequest, parse_json_response};
+use document::generate_doc_id;
+use engine::Engine;
+use import::csv::CsvReader;
+
+
+pub const USAGE: &'static str = "Usage: rusticsearch-import [options] FILE
+
+Imports the documents in FILE into an index. FILE is either newline-delimited JSON, with a
+document on each line, or CSV, with the field names in the first row. Use - to read from
+standard input.
+
+Options:
+    --index NAME        Index to import the documents into (required)
+    --type NAME         Mapping type of the documents (default: doc)
+    --format FORMAT     ndjson or csv (default: csv if FILE ends with .csv, ndjson otherwise)
+    --url URL           Address of a running node to send the documents to with the bulk
+                        API (default: http://localhost:9200)
+    --user USER:PASSWORD
+                        Credentials for the node
+    --data-dir PATH     Index the documents into a data directory with the embedded
+                        engine instead of sending them to a node. No node can be using it
+    --batch-size COUNT  Documents indexed in each batch (default: 1000)
+    --concurrency COUNT Batches indexed at the same time (default: 4)
+    --id-field NAME     Field or column to take each document's id from (default: none,
+                        ids are generated)
+    --infer-types BOOL  Turn CSV values that look like numbers or booleans into them
+                        (default: true)
+    --help              Show this message";
+
+
+const DEFAULT_URL: &'static str = "http://localhost:9200";
+const DEFAULT_DOC_TYPE: &'static str = "doc";
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// How often progress is reported while importing
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Only the first few failures are kept, so a bad file can't use up memory
+const MAX_REPORTED_ERRORS: usize = 10;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Ndjson,
+    Csv,
+}
+
+
+/// Where the documents are imported to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// The bulk API of a running node, with "user:password" credentials
+    Http(String, Option<String>),
+
+    /// A data directory, through the embedded engine
+    DataDir(PathBuf),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportOptions {
+    /// Path of the file to read, or "-" for standard input
+    pub input: String,
+
+    pub format: InputFormat,
+    pub index: String,
+    pub doc_type: String,
+    pub target: Target,
+    pub batch_size: usize,
+    pub concurrency: usize,
+
+    /// The field that documents' ids are taken from. Ids are generated if this is None
+    pub id_field: Option<String>,
+
+    /// Whether CSV values that look like numbers or booleans are imported as them, rather
+    /// than as strings
+    pub infer_types: bool,
+}
+
+
+fn parse_count(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("--{} must be a positive number: {:?}", name, value)),
+    }
+}
+
+
+impl ImportOptions {
+    /// Parses the command line arguments, without the program name. Returns None if help
+    /// was asked for
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Option<ImportOptions>, String> {
+        let mut input = None;
+        let mut format = None;
+        let mut index = None;
+        let mut doc_type = DEFAULT_DOC_TYPE.to_string();
+        let mut url = None;
+        let mut user = None;
+        let mut data_dir = None;
+        let mut batch_size = DEFAULT_BATCH_SIZE;
+        let mut concurrency = DEFAULT_CONCURRENCY;
+        let mut id_field = None;
+        let mut infer_types = true;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                return Ok(None);
+            }
+
+            if !arg.starts_with("--") {
+                if input.is_some() {
+                    return Err(format!("unexpected argument: {:?}", arg));
+                }
+                input = Some(arg);
+                continue;
+            }
+
+            let (name, value) = match arg.find('=') {
+                Some(equals) => (arg[2..equals].to_string(), arg[equals + 1..].to_string()),
+                None => {
+                    let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+                    (arg[2..].to_string(), value)
+                }
+            };
+
+            match name.as_ref() {
+                "index" => index = Some(value),
+                "type" => doc_type = value,
+                "format" => {
+                    format = Some(match value.as_ref() {
+                        "ndjson" => InputFormat::Ndjson,
+                        "csv" => InputFormat::Csv,
+                        _ => return Err(format!("invalid format: {:?}", value)),
+                    });
+                }
+                "url" => url = Some(value),
+                "user" => user = Some(value),
+                "data-dir" => data_dir = Some(PathBuf::from(value)),
+                "batch-size" => batch_size = parse_count(&name, &value)?,
+                "concurrency" => concurrency = parse_count(&name, &value)?,
+                "id-field" => id_field = Some(value),
+                "infer-types" => infer_types = value.parse().map_err(|_| format!("invalid boolean: {:?}", value))?,
+                _ => return Err(format!("unrecognised option: --{}", name)),
+            }
+        }
+
+        let input = input.ok_or("no file to import was given")?;
+        let index = index.ok_or("--index is required")?;
+        let target = match (url, data_dir) {
+            (Some(_), Some(_)) => return Err("only one of --url and --data-dir can be given".to_string()),
+            (_, Some(data_dir)) => Target::DataDir(data_dir),
+            (url, None) => Target::Http(url.unwrap_or_else(|| DEFAULT_URL.to_string()), user),
+        };
+
+        let format = format.unwrap_or_else(|| if input.ends_with(".csv") { InputFormat::Csv } else { InputFormat::Ndjson });
+
+        Ok(Some(ImportOptions {
+            input: input,
+            format: format,
+            index: index,
+            doc_type: doc_type,
+            target: target,
+            batch_size: batch_size,
+            concurrency: concurrency,
+            id_field: id_field,
+            infer_types: infer_types,
+        }))
+    }
+}
+
+
+/// Turns a CSV value into a boolean or number if it looks like one
+///
+/// Numbers with leading zeros are left as strings, as they're more likely to be codes, such
+/// as zip codes or phone numbers, than quantities.
+pub fn infer_value(value: &str) -> Json {
+    match value {
+        "true" => return Json::Bool(true),
+        "false" => return Json::Bool(false),
+        _ => {}
+    }
+
+    let digits = value.trim_start_matches('-');
+    if digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.") {
+        return Json::String(value.to_string());
+    }
+
+    if let Ok(integer) = value.parse::<i64>() {
+        return Json::from(integer);
+    }
+
+    // Rust would also parse "inf" and "NaN"
+    if value.bytes().all(|byte| byte.is_ascii_digit() || b".-+eE".contains(&byte)) {
+        if let Some(number) = value.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Json::Number(number);
+        }
+    }
+
+    Json::String(value.to_string())
+}
+
+
+/// A document read from the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    /// The line of the input the document starts on
+    pub line_number: u64,
+
+    pub id: Option<String>,
+
+    /// Always an object
+    pub source: Json,
+}
+
+
+enum Input<R: BufRead> {
+    Ndjson {
+        reader: R,
+        lines_read: u64,
+    },
+    Csv {
+        reader: CsvReader<R>,
+
+        /// The field names, from the first row
+        header: Option<Vec<String>>,
+    },
+}
+
+
+/// Why a document couldn't be read, as (line number, reason)
+pub type ReadError = (u64, String);
+
+
+/// Reads documents from the input
+///
+/// Reading stops after an error reading from the input itself.
+pub struct DocumentReader<R: BufRead> {
+    input: Input<R>,
+    id_field: Option<String>,
+    infer_types: bool,
+    finished: bool,
+}
+
+
+impl<R: BufRead> DocumentReader<R> {
+    pub fn new(reader: R, options: &ImportOptions) -> DocumentReader<R> {
+        let input = match options.format {
+            InputFormat::Ndjson => Input::Ndjson { reader: reader, lines_read: 0 },
+            InputFormat::Csv => Input::Csv { reader: CsvReader::new(reader), header: None },
+        };
+
+        DocumentReader {
+            input: input,
+            id_field: options.id_field.clone(),
+            infer_types: options.infer_types,
+            finished: false,
+        }
+    }
+
+    /// Reads the next document, without its id, skipping blank lines
+    fn read_source(&mut self) -> Option<Result<Document, ReadError>> {
+        let infer_types = self.infer_types;
+
+        match self.input {
+            Input::Ndjson { ref mut reader, ref mut lines_read } => {
+                loop {
+                    let mut line = String::new();
+                    match reader.read_line(&mut line) {
+                        Ok(0) => return None,
+                        Ok(_) => *lines_read += 1,
+                        Err(error) => {
+                            self.finished = true;
+                            return Some(Err((*lines_read + 1, format!("couldn't read the input: {}", error))));
+                        }
+                    }
+
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+
+                    return Some(match serde_json::from_str(&line) {
+                        Ok(source @ Json::Object(_)) => Ok(Document { line_number: *lines_read, id: None, source: source }),
+                        Ok(_) => Err((*lines_read, "the document isn't an object".to_string())),
+                        Err(error) => Err((*lines_read, format!("invalid JSON: {}", error))),
+                    });
+                }
+            }
+            Input::Csv { ref mut reader, ref mut header } => {
+                loop {
+                    let (line_number, fields) = match reader.read_record() {
+                        Ok(Some(record)) => record,
+                        Ok(None) => return None,
+                        Err(error) => {
+                            self.finished = true;
+                            return Some(Err((0, format!("couldn't read the input: {}", error))));
+                        }
+                    };
+
+                    if fields.len() == 1 && fields[0].is_empty() {
+                        continue;
+                    }
+
+                    let names = match *header {
+                        Some(ref names) => names,
+                        None => {
+                            *header = Some(fields);
+                            continue;
+                        }
+                    };
+
+                    if fields.len() != names.len() {
+                        return Some(Err((line_number, format!("the row has {} values, but the header has {}", fields.len(), names.len()))));
+                    }
+
+                    // Empty values are left out, rather than indexed as empty strings
+                    let mut source = Map::new();
+                    for (name, value) in names.iter().zip(fields) {
+                        if !value.is_empty() {
+                            let value = if infer_types { infer_value(&value) } else { Json::String(value) };
+                            source.insert(name.clone(), value);
+                        }
+                    }
+
+                    return Some(Ok(Document { line_number: line_number, id: None, source: Json::Object(source) }));
+                }
+            }
+        }
+    }
+
+    fn read_id(&self, source: &Json) -> Result<Option<String>, String> {
+        let id_field = match self.id_field {
+            Some(ref id_field) => id_field,
+            None => return Ok(None),
+        };
+
+        match source.get(id_field) {
+            Some(Json::String(id)) if !id.is_empty() => Ok(Some(id.clone())),
+            Some(Json::Number(id)) => Ok(Some(id.to_string())),
+            _ => Err(format!("the document has no {:?} to take its id from", id_field)),
+        }
+    }
+}
+
+
+impl<R: BufRead> Iterator for DocumentReader<R> {
+    type Item = Result<Document, ReadError>;
+
+    fn next(&mut self) -> Option<Result<Document, ReadError>> {
+        if self.finished {
+            return None;
+        }
+
+        Some(self.read_source()?.and_then(|mut document| {
+            document.id = self.read_id(&document.source).map_err(|error| (document.line_number, error))?;
+            Ok(document)
+        }))
+    }
+}
+
+
+/// Somewhere documents can be imported to
+pub trait Destination: Send + Sync {
+    /// Indexes a batch of documents. Returns why each one failed, if it did, in the same
+    /// order, or an error if none of them could be indexed
+    fn index_batch(&self, documents: &[Document]) -> Result<Vec<Option<String>>, String>;
+
+    /// Makes the imported documents searchable
+    fn refresh(&self) -> Result<(), String>;
+}
+
+
+/// Sends documents to a running node with the bulk API
+pub struct HttpDestination {
+    transport: Transport,
+
+    /// "host:port" of the node
+    address: String,
+
+    /// Put before the path of every request, for nodes behind a proxy
+    path_prefix: String,
+
+    authorization: Option<String>,
+    index: String,
+    doc_type: String,
+}
+
+
+impl HttpDestination {
+    pub fn new(url: &str, credentials: Option<&str>, index: &str, doc_type: &str) -> Result<HttpDestination, String> {
+        let parsed_url = Url::parse(url).map_err(|e| format!("invalid url {:?}: {}", url, e))?;
+        if parsed_url.scheme() != "http" {
+            return Err(format!("only http urls are supported, not {:?}", url));
+        }
+
+        let address = match (parsed_url.host_str(), parsed_url.port_or_known_default()) {
+            (Some(host_name), Some(port)) => format!("{}:{}", host_name, port),
+            _ => return Err(format!("the url must have a host name: {:?}", url)),
+        };
+
+        Ok(HttpDestination {
+            transport: Transport::external(CONNECT_TIMEOUT).map_err(|e| format!("couldn't start the transport: {}", e))?,
+            address: address,
+            path_prefix: parsed_url.path().trim_end_matches('/').to_string(),
+            authorization: credentials.map(|credentials| format!("Basic {}", base64::encode(credentials))),
+            index: index.to_string(),
+            doc_type: doc_type.to_string(),
+        })
+    }
+
+    fn request(&self, path: &str) -> TransportRequest {
+        let mut request = TransportRequest::new(&self.address, Method::POST, &format!("{}{}", self.path_prefix, path));
+
+        if let Some(ref authorization) = self.authorization {
+            request.headers.push(("Authorization".to_string(), authorization.clone()));
+        }
+
+        request
+    }
+}
+
+
+/// Reads why a bulk item failed, if it did
+fn bulk_item_error(item: &Json) -> Option<String> {
+    // Each item is keyed by its action
+    let item = item.as_object().and_then(|item| item.values().next()).unwrap_or(&Json::Null);
+    let status = item.get("status").and_then(|status| status.as_u64()).unwrap_or(0);
+
+    match item.get("error") {
+        Some(error) => {
+            let reason = error.get("reason").and_then(|reason| reason.as_str()).or_else(|| error.as_str());
+            Some(reason.map(|reason| reason.to_string()).unwrap_or_else(|| error.to_string()))
+        }
+        None if status >= 300 => Some(format!("status {}", status)),
+        None => None,
+    }
+}
+
+
+impl Destination for HttpDestination {
+    fn index_batch(&self, documents: &[Document]) -> Result<Vec<Option<String>>, String> {
+        let mut body = Vec::new();
+        for document in documents {
+            let mut action = json!({"_index": self.index, "_type": self.doc_type});
+            if let Some(ref id) = document.id {
+                action["_id"] = Json::String(id.clone());
+            }
+
+            body.extend(json!({"index": action}).to_string().into_bytes());
+            body.push(b'\n');
+            body.extend(document.source.to_string().into_bytes());
+            body.push(b'\n');
+        }
+
+        let mut request = self.request("/_bulk");
+        request.headers.push(("Content-Type".to_string(), "application/x-ndjson".to_string()));
+        request.body = body;
+
+        let response = parse_json_response(self.transport.send(request, REQUEST_TIMEOUT))?;
+        let items = response.get("items").and_then(|items| items.as_array()).ok_or("the response has no items")?;
+        if items.len() != documents.len() {
+            return Err(format!("the response has {} items, but {} documents were sent", items.len(), documents.len()));
+        }
+
+        Ok(items.iter().map(bulk_item_error).collect())
+    }
+
+    fn refresh(&self) -> Result<(), String> {
+        let path = format!("/{}/_refresh", utf8_percent_encode(&self.index, PATH_SEGMENT_ENCODE_SET));
+        parse_json_response(self.transport.send(self.request(&path), REQUEST_TIMEOUT)).map(|_| ())
+    }
+}
+
+
+/// Indexes documents into a data directory with the embedded engine
+pub struct EngineDestination {
+    engine: Engine,
+    index: String,
+    doc_type: String,
+}
+
+
+impl EngineDestination {
+    pub fn open(data_dir: &Path, index: &str, doc_type: &str) -> Result<EngineDestination, String> {
+        Ok(EngineDestination {
+            engine: Engine::open(data_dir).map_err(|e| e.to_string())?,
+            index: index.to_string(),
+            doc_type: doc_type.to_string(),
+        })
+    }
+}
+
+
+impl Destination for EngineDestination {
+    fn index_batch(&self, documents: &[Document]) -> Result<Vec<Option<String>>, String> {
+        Ok(documents.iter().map(|document| {
+            let id = document.id.clone().unwrap_or_else(generate_doc_id);
+            self.engine.index_document(&self.index, &self.doc_type, &id, &document.source).err().map(|error| error.to_string())
+        }).collect())
+    }
+
+    fn refresh(&self) -> Result<(), String> {
+        self.engine.refresh(&self.index).map_err(|e| e.to_string())
+    }
+}
+
+
+/// How an import is going
+#[derive(Debug, Clone)]
+pub struct ImportStats {
+    pub indexed: u64,
+    pub failed: u64,
+
+    /// The first failures, as (line number, reason). The line number is 0 if it isn't known
+    pub errors: Vec<(u64, String)>,
+
+    /// Time since the import started
+    pub elapsed: Duration,
+
+    started: Instant,
+    last_report: Instant,
+}
+
+
+impl ImportStats {
+    fn new() -> ImportStats {
+        let now = Instant::now();
+
+        ImportStats {
+            indexed: 0,
+            failed: 0,
+            errors: Vec::new(),
+            elapsed: Duration::from_secs(0),
+            started: now,
+            last_report: now,
+        }
+    }
+
+    fn record_failure(&mut self, line_number: u64, error: String) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push((line_number, error));
+        }
+    }
+
+    fn record_batch(&mut self, documents: &[Document], result: Result<Vec<Option<String>>, String>) {
+        match result {
+            Ok(errors) => {
+                for (document, error) in documents.iter().zip(errors) {
+                    match error {
+                        Some(error) => self.record_failure(document.line_number, error),
+                        None => self.indexed += 1,
+                    }
+                }
+            }
+            Err(error) => {
+                for document in documents {
+                    self.record_failure(document.line_number, error.clone());
+                }
+            }
+        }
+
+        self.elapsed = self.started.elapsed();
+    }
+
+    /// Documents indexed per second
+    pub fn rate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if seconds > 0.0 {
+            self.indexed as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+
+/// Indexes the documents in batches of `batch_size`, `concurrency` batches at a time
+///
+/// `on_progress` is called every few seconds while the import runs. It doesn't refresh the
+/// index at the end.
+pub fn run_import<R, D, F>(documents: DocumentReader<R>, destination: Arc<D>, batch_size: usize, concurrency: usize, on_progress: F) -> ImportStats
+    where R: BufRead,
+          D: Destination + 'static,
+          F: Fn(&ImportStats) + Send + Sync + 'static
+{
+    let stats = Arc::new(Mutex::new(ImportStats::new()));
+    let on_progress = Arc::new(on_progress);
+
+    // Reading waits while every worker is busy and another batch is queued for each
+    let (sender, receiver) = mpsc::sync_channel::<Vec<Document>>(concurrency);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let workers = (0..concurrency).map(|_| {
+        let receiver = receiver.clone();
+        let destination = destination.clone();
+        let stats = stats.clone();
+        let on_progress = on_progress.clone();
+
+        thread::spawn(move || {
+            loop {
+                let batch = match receiver.lock().unwrap().recv() {
+                    Ok(batch) => batch,
+                    Err(_) => return,
+                };
+
+                let result = destination.index_batch(&batch);
+
+                let mut stats = stats.lock().unwrap();
+                stats.record_batch(&batch, result);
+                if stats.last_report.elapsed() >= PROGRESS_INTERVAL {
+                    stats.last_report = Instant::now();
+                    on_progress(&stats);
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let mut batch = Vec::with_capacity(batch_size);
+    for document in documents {
+        match document {
+            Ok(document) => {
+                batch.push(document);
+                if batch.len() >= batch_size && sender.send(mem::replace(&mut batch, Vec::with_capacity(batch_size))).is_err() {
+                    break;
+                }
+            }
+            Err((line_number, error)) => stats.lock().unwrap().record_failure(line_number, error),
+        }
+    }
+
+    if !batch.is_empty() {
+        let _ = sender.send(batch);
+    }
+
+    drop(sender);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let mut stats = stats.lock().unwrap().clone();
+    stats.elapsed = stats.started.elapsed();
+    stats
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value as Json;
+
+    use super::{ImportOptions, InputFormat, Target, Document, DocumentReader, ReadError, Destination, infer_value, run_import};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn options(format: InputFormat, id_field: Option<&str>) -> ImportOptions {
+        let mut options = ImportOptions::parse(args(&["--index", "test", "-"])).unwrap().unwrap();
+        options.format = format;
+        options.id_field = id_field.map(|id_field| id_field.to_string());
+        options
+    }
+
+    fn read_all(input: &str, options: &ImportOptions) -> Vec<Result<Document, ReadError>> {
+        DocumentReader::new(Cursor::new(input.as_bytes().to_vec()), options).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = ImportOptions::parse(args(&["--index=articles", "--batch-size", "500", "articles.csv"])).unwrap().unwrap();
+        assert_eq!(options.input, "articles.csv");
+        assert_eq!(options.format, InputFormat::Csv);
+        assert_eq!(options.index, "articles");
+        assert_eq!(options.doc_type, "doc");
+        assert_eq!(options.target, Target::Http("http://localhost:9200".to_string(), None));
+        assert_eq!(options.batch_size, 500);
+        assert_eq!(options.concurrency, 4);
+        assert!(options.infer_types);
+
+        let options = ImportOptions::parse(args(&["--index", "articles", "--data-dir", "data", "--format", "ndjson", "--infer-types", "false", "articles.csv"])).unwrap().unwrap();
+        assert_eq!(options.format, InputFormat::Ndjson);
+        assert_eq!(options.target, Target::DataDir(PathBuf::from("data")));
+        assert!(!options.infer_types);
+
+        assert_eq!(ImportOptions::parse(args(&["--help"])), Ok(None));
+        assert!(ImportOptions::parse(args(&["articles.json"])).is_err());
+        assert!(ImportOptions::parse(args(&["--index", "articles"])).is_err());
+        assert!(ImportOptions::parse(args(&["--index", "articles", "a.json", "b.json"])).is_err());
+        assert!(ImportOptions::parse(args(&["--index", "articles", "--batch-size", "0", "a.json"])).is_err());
+        assert!(ImportOptions::parse(args(&["--index", "articles", "--url", "http://localhost:9200", "--data-dir", "data", "a.json"])).is_err());
+        assert!(ImportOptions::parse(args(&["--index", "articles", "--format", "xml", "a.json"])).is_err());
+    }
+
+    #[test]
+    fn test_infer_value() {
+        assert_eq!(infer_value("true"), Json::Bool(true));
+        assert_eq!(infer_value("42"), json!(42));
+        assert_eq!(infer_value("-1.5"), json!(-1.5));
+        assert_eq!(infer_value("0.5"), json!(0.5));
+        assert_eq!(infer_value("0"), json!(0));
+        assert_eq!(infer_value("02134"), json!("02134"));
+        assert_eq!(infer_value("NaN"), json!("NaN"));
+        assert_eq!(infer_value("inf"), json!("inf"));
+        assert_eq!(infer_value("1.2.3"), json!("1.2.3"));
+        assert_eq!(infer_value("True"), json!("True"));
+    }
+
+    #[test]
+    fn test_read_ndjson() {
+        let documents = read_all("{\"id\": 1, \"title\": \"Hello\"}\n\n[1, 2]\n{\"title\": \"No id\"}\n{\n", &options(InputFormat::Ndjson, Some("id")));
+        assert_eq!(documents.len(), 4);
+        assert_eq!(documents[0], Ok(Document {
+            line_number: 1,
+            id: Some("1".to_string()),
+            source: json!({"id": 1, "title": "Hello"}),
+        }));
+        assert_eq!(documents[1].as_ref().unwrap_err().0, 3);
+        assert_eq!(documents[2].as_ref().unwrap_err().0, 4);
+        assert_eq!(documents[3].as_ref().unwrap_err().0, 5);
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let input = "id,title,views,published\n1,Hello,10,true\n\n2,\"Hello, again\",,false\n3,Short\n";
+        let documents = read_all(input, &options(InputFormat::Csv, None));
+        assert_eq!(documents.len(), 3);
+        assert_eq!(documents[0], Ok(Document {
+            line_number: 2,
+            id: None,
+            source: json!({"id": 1, "title": "Hello", "views": 10, "published": true}),
+        }));
+        assert_eq!(documents[1].as_ref().unwrap().source, json!({"id": 2, "title": "Hello, again", "published": false}));
+        assert_eq!(documents[2].as_ref().unwrap_err().0, 5);
+
+        let mut strings = options(InputFormat::Csv, Some("id"));
+        strings.infer_types = false;
+        let documents = read_all("id,views\n007,10\n", &strings);
+        assert_eq!(documents, vec![Ok(Document {
+            line_number: 2,
+            id: Some("007".to_string()),
+            source: json!({"id": "007", "views": "10"}),
+        })]);
+    }
+
+    /// Keeps the documents it's given, failing ones without a title
+    #[derive(Default)]
+    struct TestDestination {
+        documents: Mutex<Vec<Document>>,
+    }
+
+    impl Destination for TestDestination {
+        fn index_batch(&self, documents: &[Document]) -> Result<Vec<Option<String>>, String> {
+            assert!(documents.len() <= 2);
+            self.documents.lock().unwrap().extend(documents.iter().cloned());
+            Ok(documents.iter().map(|document| if document.source.get("title").is_some() { None } else { Some("no title".to_string()) }).collect())
+        }
+
+        fn refresh(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_import() {
+        let input = "{\"title\": \"a\"}\n{\"title\": \"b\"}\n{\"body\": \"c\"}\nnot json\n{\"title\": \"d\"}\n";
+        let options = options(InputFormat::Ndjson, None);
+        let destination = Arc::new(TestDestination::default());
+
+        let stats = run_import(DocumentReader::new(Cursor::new(input.as_bytes().to_vec()), &options), destination.clone(), 2, 3, |_| {});
+        assert_eq!(stats.indexed, 3);
+        assert_eq!(stats.failed, 2);
+
+        let mut errors = stats.errors.clone();
+        errors.sort();
+        assert_eq!(errors[0], (3, "no title".to_string()));
+        assert_eq!(errors[1].0, 4);
+
+        assert_eq!(destination.documents.lock().unwrap().len(), 4);
+    }
+}
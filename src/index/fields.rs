@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use search::schema::{FieldType, FieldFlags, FIELD_INDEXED, FIELD_STORED};
+
+use mapping::{self, Mapping, MappingProperty};
+use index::Index;
+
+
+impl Index {
+    /// Finds the fields of a mapping that aren't in the store yet, along with how they
+    /// should be created
+    ///
+    /// Returns an error if a field is already in the store with a different type or flags
+    pub fn new_mapping_fields(&self, mapping: &Mapping) -> Result<HashMap<String, (FieldType, FieldFlags)>, String> {
+        let schema = self.shards()[0].schema();
+        let mut new_fields: HashMap<String, (FieldType, FieldFlags)>  = HashMap::new();
+        for (name, property) in mapping.properties.iter() {
+            if let MappingProperty::Field(ref field_mapping) = *property {
+                let field_type = match field_mapping.data_type {
+                    mapping::FieldType::String => FieldType::Text,
+                    mapping::FieldType::Integer => FieldType::I64,
+                    mapping::FieldType::Boolean => FieldType::Boolean,
+                    mapping::FieldType::Date => FieldType::DateTime,
+                    mapping::FieldType::DenseVector => FieldType::DenseVector,
+                    mapping::FieldType::GeoPoint => FieldType::GeoPoint,
+
+                    // Shapes are indexed as geohash cells and stored as GeoJSON text
+                    mapping::FieldType::GeoShape => FieldType::Text,
+
+                    // Completion inputs are stored as JSON text
+                    mapping::FieldType::Completion => FieldType::Text,
+                };
+
+                // Flags
+                let mut field_flags = FieldFlags::empty();
+
+                if field_mapping.is_indexed {
+                    field_flags |= FIELD_INDEXED;
+                }
+
+                if field_mapping.is_stored {
+                    field_flags |= FIELD_STORED;
+                }
+
+                // Check if this field already exists
+                if let Some(field_ref) = schema.get_field_by_name(&name) {
+                    let field_info = schema.get(&field_ref).expect("get_field_by_name returned an invalid FieldId");
+
+                    // Field already exists. Check for conflicting type or flags, otherwise ignore.
+                    if field_info.field_type == field_type && field_info.field_flags == field_flags {
+                        continue;
+                    } else {
+                        return Err(format!("field {:?} already exists with a different type", name));
+                    }
+                }
+
+                new_fields.insert(name.clone(), (field_type, field_flags));
+            }
+        }
+
+        Ok(new_fields)
+    }
+
+    /// Adds the fields of a mapping that aren't in the store yet, then links the mapping
+    pub fn add_mapping_fields(&self, mapping: &mut Mapping) -> Result<(), String> {
+        for (field_name, (field_type, field_flags)) in self.new_mapping_fields(mapping)? {
+            self.add_field(field_name, field_type, field_flags).map_err(|e| format!("failed to add field: {:?}", e))?;
+        }
+
+        self.link_mapping(mapping);
+        Ok(())
+    }
+
+    /// Points each field of a mapping at its field in the store
+    pub fn link_mapping(&self, mapping: &mut Mapping) {
+        let schema = self.shards()[0].schema();
+
+        for (name, property) in mapping.properties.iter_mut() {
+            if let MappingProperty::Field(ref mut field_mapping) = *property {
+                field_mapping.index_ref = schema.get_field_by_name(&name)
+            }
+        }
+    }
+}
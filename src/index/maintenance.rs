@@ -1,83 +1,170 @@
+use std::sync::TryLockError;
+
+use search::cancellation::SearchCancellation;
+use search::backends::rocksdb::RocksDBStore;
 use index::Index;
 
 
+/// The most documents a segment can hold
+const MAX_SEGMENT_DOCS: i64 = 65536;
+
+
 impl Index {
     /// Run a maintenance task on the index
-    /// This must be run periodically by a background thread. It is not currently thread-safe
+    /// This must be run periodically by a background thread. Nothing is done while a force
+    /// merge is running
     pub fn run_maintenance_task(&self) -> Result<(), String> {
-        let segment_stats = self.store.get_segment_statistics()?;
+        let _merge_lock = match self.merge_lock.try_lock() {
+            Ok(merge_lock) => merge_lock,
+            Err(TryLockError::WouldBlock) => return Ok(()),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+
+        for store in self.shards().iter() {
+            merge_shard_segments(store)?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges segments until there are no more than `max_num_segments` of them
+    ///
+    /// The smallest segments are merged first. Segments can't be merged if the result would
+    /// be too big, so there may be more segments left than were asked for. Returns false if
+    /// this was stopped by the cancellation before it finished.
+    pub fn force_merge(&self, max_num_segments: usize, cancellation: &SearchCancellation) -> Result<bool, String> {
+        let _merge_lock = self.merge_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        for store in self.shards().iter() {
+            if !force_merge_shard(store, max_num_segments, cancellation)? {
+                return Ok(false);
+            }
+        }
 
-        // TODO: Deactivate segments with 100% deletions
-        // TODO: Vacuum segments with many deletions
+        Ok(true)
+    }
+}
 
-        // Merge segments
 
-        // Firstly we classify each active segment into one of 5 groups (based on the number of
-        // total documents they have):
-        // Group 1: 1 - 9 docs
-        // Group 2: 10 - 99 docs
-        // Group 3: 100 - 999 docs
-        // Group 4: 1000 - 9999 docs
-        // Group 5: 10000 - 65536 docs
+/// Merges the smaller segments of a shard's store together, if there are enough of them
+fn merge_shard_segments(store: &RocksDBStore) -> Result<(), String> {
+    // Purge segments from previous merges once no searches are using them any more
+    store.purge_retired_segments()?;
 
-        // The group with the most active segments can perform a merge. A merge can be done on
-        // between 5 - 1000 segments at a time. The smallest segments get merged first.
+    let segment_stats = store.get_segment_statistics()?;
 
-        let mut segments_g1 = Vec::new();
-        let mut segments_g2 = Vec::new();
-        let mut segments_g3 = Vec::new();
-        let mut segments_g4 = Vec::new();
-        let mut segments_g5 = Vec::new();
+    // TODO: Deactivate segments with 100% deletions
+    // TODO: Vacuum segments with many deletions
 
-        for (segment, stats) in segment_stats {
-            match stats.total_docs() {
-                1 ... 9 => segments_g1.push((segment, stats)),
-                10 ... 99 => segments_g2.push((segment, stats)),
-                100 ... 999 => segments_g3.push((segment, stats)),
-                1000 ... 9999 => segments_g4.push((segment, stats)),
-                10000 ... 65536 => segments_g5.push((segment, stats)),
-                _ => {},
-            }
+    // Merge segments
+
+    // Firstly we classify each active segment into one of 5 groups (based on the number of
+    // total documents they have):
+    // Group 1: 1 - 9 docs
+    // Group 2: 10 - 99 docs
+    // Group 3: 100 - 999 docs
+    // Group 4: 1000 - 9999 docs
+    // Group 5: 10000 - 65536 docs
+
+    // The group with the most active segments can perform a merge. A merge can be done on
+    // between 5 - 1000 segments at a time. The smallest segments get merged first.
+
+    let mut segments_g1 = Vec::new();
+    let mut segments_g2 = Vec::new();
+    let mut segments_g3 = Vec::new();
+    let mut segments_g4 = Vec::new();
+    let mut segments_g5 = Vec::new();
+
+    for (segment, stats) in segment_stats {
+        match stats.total_docs() {
+            1 ... 9 => segments_g1.push((segment, stats)),
+            10 ... 99 => segments_g2.push((segment, stats)),
+            100 ... 999 => segments_g3.push((segment, stats)),
+            1000 ... 9999 => segments_g4.push((segment, stats)),
+            10000 ... 65536 => segments_g5.push((segment, stats)),
+            _ => {},
         }
+    }
 
-        // Now sort the groups by length in ascending order
-        let mut segments_grouped = vec![segments_g1, segments_g2, segments_g3, segments_g4, segments_g5];
-        segments_grouped.sort_by_key(|group| group.len());
+    // Now sort the groups by length in ascending order
+    let mut segments_grouped = vec![segments_g1, segments_g2, segments_g3, segments_g4, segments_g5];
+    segments_grouped.sort_by_key(|group| group.len());
 
-        // The group with the most segments is our merge candidate. Check that it has above the
-        // minimum number of documents to start a merge and truncate it to be less than the maximum.
-        let mut group_to_merge = segments_grouped.pop().unwrap();
+    // The group with the most segments is our merge candidate. Check that it has above the
+    // minimum number of documents to start a merge and truncate it to be less than the maximum.
+    let mut group_to_merge = segments_grouped.pop().unwrap();
 
-        if group_to_merge.len() < 3 {
-            // No point in merging these
-            return Ok(());
+    if group_to_merge.len() < 3 {
+        // No point in merging these
+        return Ok(());
+    }
+
+    // Now we've found a group of segments to merge, we must check that all the docs will fit in a
+    // single segment. If not, we choose the largest sub-group of segments to merge that fills the
+    // quota as much as possible
+
+    let mut current_doc_count: u32 = 0;
+    let mut segment_ids = Vec::new();
+
+    // Sort segments total_docs in descending order
+    // TODO: Check that this is descending order
+    group_to_merge.sort_by_key(|&(_, ref stats)| -stats.total_docs());
+
+    for (segment, stats) in group_to_merge {
+        if current_doc_count + stats.total_docs() as u32 > MAX_SEGMENT_DOCS as u32 {
+            // No space for this segment
+            continue;
         }
 
-        // Now we've found a group of segments to merge, we must check that all the docs will fit in a
-        // single segment. If not, we choose the largest sub-group of segments to merge that fills the
-        // quota as much as possible
+        segment_ids.push(segment);
+        current_doc_count += stats.total_docs() as u32;
+    }
+
+    // Merge segments
+    store.merge_segments(&segment_ids)?;
+    store.purge_retired_segments()?;
 
-        let mut current_doc_count: u32 = 0;
-        let mut segment_ids = Vec::new();
+    Ok(())
+}
 
-        // Sort segments total_docs in descending order
-        // TODO: Check that this is descending order
-        group_to_merge.sort_by_key(|&(_, ref stats)| -stats.total_docs());
 
-        for (segment, stats) in group_to_merge {
-            if current_doc_count + stats.total_docs() as u32 > 65536 {
-                // No space for this segment
-                continue;
+/// Merges the segments of a shard's store down to `max_num_segments`. See `Index::force_merge`
+fn force_merge_shard(store: &RocksDBStore, max_num_segments: usize, cancellation: &SearchCancellation) -> Result<bool, String> {
+    loop {
+        if cancellation.is_cancelled() {
+            return Ok(false);
+        }
+
+        let mut segment_stats = store.get_segment_statistics()?;
+        if segment_stats.len() <= max_num_segments {
+            break;
+        }
+
+        // Merge as many of the smallest segments as will fit in one. Only enough to get
+        // down to the target are merged, so the last merge doesn't overshoot it
+        segment_stats.sort_by_key(|&(_, ref stats)| stats.total_docs());
+        let max_merged_segments = segment_stats.len() - max_num_segments + 1;
+
+        let mut current_doc_count = 0;
+        let mut segment_ids = Vec::new();
+
+        for (segment, stats) in segment_stats {
+            if segment_ids.len() == max_merged_segments || current_doc_count + stats.total_docs() > MAX_SEGMENT_DOCS {
+                break;
             }
 
             segment_ids.push(segment);
-            current_doc_count += stats.total_docs() as u32;
+            current_doc_count += stats.total_docs();
         }
 
-        // Merge segments
-        self.store.merge_segments(&segment_ids)?;
-        self.store.purge_segments(&segment_ids)?;
+        if segment_ids.len() < 2 {
+            // The remaining segments are too big to be merged together
+            break;
+        }
 
-        Ok(())
+        store.merge_segments(&segment_ids)?;
+        store.purge_retired_segments()?;
     }
+
+    Ok(true)
 }
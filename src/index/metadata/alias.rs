@@ -0,0 +1,30 @@
+use serde_json::Value as Json;
+
+
+/// How an alias applies to one of its indices
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AliasMetadata {
+    /// A query that documents must match to be seen through the alias
+    pub filter: Option<Json>,
+
+    /// Whether writes through the alias go to this index. If not set, writes are allowed
+    /// only if this is the alias's only index
+    pub is_write_index: Option<bool>,
+}
+
+
+impl AliasMetadata {
+    pub fn to_json(&self) -> Json {
+        let mut json = json!({});
+
+        if let Some(ref filter) = self.filter {
+            json["filter"] = filter.clone();
+        }
+
+        if let Some(is_write_index) = self.is_write_index {
+            json["is_write_index"] = json!(is_write_index);
+        }
+
+        json
+    }
+}
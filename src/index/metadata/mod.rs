@@ -1,33 +1,45 @@
 pub mod parse;
 pub mod file;
+pub mod settings;
+pub mod alias;
 
 use std::collections::{HashMap, BTreeMap};
 
 use serde::{Serialize, Serializer};
 use serde_json;
+use search::schema::FieldId;
 
 use analysis::AnalyzerSpec;
 use analysis::tokenizers::TokenizerSpec;
 use analysis::filters::FilterSpec;
 use mapping::{Mapping, MappingProperty, FieldMapping};
 
+use self::settings::IndexSettings;
+use self::alias::AliasMetadata;
+
 
 #[derive(Debug)]
 pub struct IndexMetadata {
+    pub settings: IndexSettings,
     analyzers: HashMap<String, AnalyzerSpec>,
     tokenizers: HashMap<String, TokenizerSpec>,
     filters: HashMap<String, FilterSpec>,
     pub mappings: HashMap<String, Mapping>,
+
+    /// The aliases that include this index, by alias name
+    pub aliases: BTreeMap<String, AliasMetadata>,
 }
 
 
 impl Default for IndexMetadata {
     fn default() -> IndexMetadata {
         let mut metadata = IndexMetadata {
+            settings: IndexSettings::default(),
             analyzers: HashMap::new(),
             tokenizers: HashMap::new(),
             filters: HashMap::new(),
             mappings: HashMap::new(),
+            aliases: BTreeMap::new(),
         };
 
         // Builtin tokenizers
@@ -120,6 +132,26 @@ impl IndexMetadata {
 
         None
     }
+
+    /// Finds the field mapping for a field in the schema
+    pub fn get_field_mapping_by_ref(&self, field_ref: FieldId) -> Option<&FieldMapping> {
+        for mapping in self.mappings.values() {
+            for property in mapping.properties.values() {
+                if let MappingProperty::Field(ref field_mapping) = *property {
+                    if field_mapping.index_ref == Some(field_ref) {
+                        return Some(field_mapping);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The filter of an alias of this index. None if the name isn't an alias or it has no filter
+    pub fn alias_filter(&self, name: &str) -> Option<&serde_json::Value> {
+        self.aliases.get(name).and_then(|alias| alias.filter.as_ref())
+    }
 }
 
 
@@ -143,8 +175,15 @@ impl Serialize for IndexMetadata {
             mappings_json.insert(name.to_string(), serde_json::to_value(&mapping).unwrap());
         }
 
+        // Aliases
+        let mut aliases_json = BTreeMap::new();
+        for (name, alias) in self.aliases.iter() {
+            aliases_json.insert(name.to_string(), alias.to_json());
+        }
+
         let json = json!({
             "settings": {
+                "index": self.settings,
                 "analysis": {
                     "tokenizers": tokenizers_json,
                     "filters": filters_json,
@@ -152,6 +191,7 @@ impl Serialize for IndexMetadata {
                 },
             },
             "mappings": mappings_json,
+            "aliases": aliases_json,
         });
 
         json.serialize(serializer)
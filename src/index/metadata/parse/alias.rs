@@ -0,0 +1,73 @@
+use serde_json;
+
+use index::metadata::alias::AliasMetadata;
+use query_parser::parse as parse_query;
+
+
+#[derive(Debug, PartialEq)]
+pub enum AliasParseError {
+    ExpectedObject,
+    ExpectedBoolean,
+    UnrecognisedKey(String),
+    InvalidFilter(String),
+}
+
+
+pub fn parse(json: &serde_json::Value) -> Result<AliasMetadata, AliasParseError> {
+    let data = json.as_object().ok_or(AliasParseError::ExpectedObject)?;
+    let mut alias = AliasMetadata::default();
+
+    for (key, value) in data.iter() {
+        match key.as_ref() {
+            "filter" => {
+                if let Err(e) = parse_query(value) {
+                    return Err(AliasParseError::InvalidFilter(format!("{:?}", e)));
+                }
+
+                alias.filter = Some(value.clone());
+            }
+            "is_write_index" => {
+                alias.is_write_index = Some(value.as_bool().ok_or(AliasParseError::ExpectedBoolean)?);
+            }
+            _ => return Err(AliasParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(alias)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use index::metadata::alias::AliasMetadata;
+
+    use super::{parse, AliasParseError};
+
+    #[test]
+    fn test_parse() {
+        let json = serde_json::from_str("{\"filter\": {\"term\": {\"user\": \"kimchy\"}}, \"is_write_index\": true}").unwrap();
+        let alias = parse(&json).unwrap();
+
+        assert_eq!(alias, AliasMetadata {
+            filter: Some(json!({"term": {"user": "kimchy"}})),
+            is_write_index: Some(true),
+        });
+        assert_eq!(alias.to_json(), json);
+
+        assert_eq!(parse(&json!({})).unwrap().to_json(), json!({}));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(&json!([])), Err(AliasParseError::ExpectedObject));
+        assert_eq!(parse(&json!({"is_write_index": "yes"})), Err(AliasParseError::ExpectedBoolean));
+        assert_eq!(parse(&json!({"routing": "1"})), Err(AliasParseError::UnrecognisedKey("routing".to_string())));
+
+        match parse(&json!({"filter": {"foo": {}}})) {
+            Err(AliasParseError::InvalidFilter(_)) => {}
+            result => panic!("expected InvalidFilter error, got {:?}", result),
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use serde_json;
 
 use analysis::ngram_generator::Edge;
-use analysis::filters::FilterSpec;
+use analysis::filters::{FilterSpec, PluginFilter};
+use plugins;
 
 
 #[derive(Debug, PartialEq)]
@@ -12,6 +13,9 @@ pub enum FilterParseError {
     ExpectedKey(String),
     UnrecognisedType(String),
     InvalidSideValue,
+
+    /// A plugin's filter couldn't be built from the settings
+    InvalidPluginSettings(String),
 }
 
 
@@ -154,6 +158,17 @@ pub fn parse(json: &serde_json::Value) -> Result<FilterSpec, FilterParseError> {
         // classic
         // decimal_digit
         // fingerprint
-        _ => Err(FilterParseError::UnrecognisedType(filter_type.to_string())),
+        _ => {
+            match plugins::build_filter(filter_type, json) {
+                Some(Ok(filter)) => {
+                    Ok(FilterSpec::Plugin(PluginFilter {
+                        settings: json.clone(),
+                        filter: filter,
+                    }))
+                }
+                Some(Err(e)) => Err(FilterParseError::InvalidPluginSettings(e)),
+                None => Err(FilterParseError::UnrecognisedType(filter_type.to_string())),
+            }
+        }
     }
 }
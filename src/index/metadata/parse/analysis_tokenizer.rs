@@ -1,7 +1,8 @@
 use serde_json;
 
 use analysis::ngram_generator::Edge;
-use analysis::tokenizers::TokenizerSpec;
+use analysis::tokenizers::{TokenizerSpec, PluginTokenizer};
+use plugins;
 
 
 #[derive(Debug, PartialEq)]
@@ -12,6 +13,9 @@ pub enum TokenizerParseError {
     ExpectedKey(String),
     UnrecognisedType(String),
     InvalidSideValue,
+
+    /// A plugin's tokenizer couldn't be built from the settings
+    InvalidPluginSettings(String),
 }
 
 
@@ -113,6 +117,17 @@ pub fn parse(json: &serde_json::Value) -> Result<TokenizerSpec, TokenizerParseEr
         // pattern
         // classic
         // thai
-        _ => Err(TokenizerParseError::UnrecognisedType(tokenizer_type.to_owned())),
+        _ => {
+            match plugins::build_tokenizer(tokenizer_type, json) {
+                Some(Ok(tokenizer)) => {
+                    Ok(TokenizerSpec::Plugin(PluginTokenizer {
+                        settings: json.clone(),
+                        tokenizer: tokenizer,
+                    }))
+                }
+                Some(Err(e)) => Err(TokenizerParseError::InvalidPluginSettings(e)),
+                None => Err(TokenizerParseError::UnrecognisedType(tokenizer_type.to_owned())),
+            }
+        }
     }
 }
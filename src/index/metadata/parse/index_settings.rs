@@ -0,0 +1,677 @@
+use std::time::Duration;
+use std::collections::BTreeMap;
+
+use serde_json;
+
+use search::similarity::{SimilarityModel, DEFAULT_BM25_K1, DEFAULT_BM25_B};
+use index::metadata::settings::{IndexSettings, StoreType, SlowLogSettings, ActiveShardCount};
+
+
+#[derive(Debug, PartialEq)]
+pub enum IndexSettingsParseError {
+    ExpectedObject,
+    ExpectedPositiveInteger(String),
+    ExpectedString(String),
+    ExpectedBoolean(String),
+    InvalidValue(String),
+    UnrecognisedSetting(String),
+
+    /// Tried to change a static setting on an existing index
+    NonDynamicSetting(String),
+
+    /// A store type that Elasticsearch has but we don't, such as "mmapfs"
+    UnsupportedStoreType(String),
+}
+
+
+/// Flattens nested settings objects into dotted keys
+///
+/// Elasticsearch accepts settings in both nested (`{"index": {"number_of_shards": 1}}`)
+/// and flat (`{"index.number_of_shards": 1}`) forms, and the "index." prefix is optional.
+fn flatten(prefix: &str, json: &serde_json::Value, flattened: &mut Vec<(String, serde_json::Value)>) {
+    match *json {
+        serde_json::Value::Object(ref object) => {
+            for (key, value) in object.iter() {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+
+                flatten(&key, value, flattened);
+            }
+        }
+        _ => {
+            let key = if prefix.starts_with("index.") {
+                prefix[6..].to_string()
+            } else {
+                prefix.to_string()
+            };
+
+            flattened.push((key, json.clone()));
+        }
+    }
+}
+
+
+/// Parses a value that may be given as either a JSON number or a string containing one
+fn parse_u32(key: &str, json: &serde_json::Value) -> Result<u32, IndexSettingsParseError> {
+    let value = match *json {
+        serde_json::Value::Number(ref number) => number.as_u64(),
+        serde_json::Value::String(ref string) => string.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    match value {
+        Some(value) if value <= u32::max_value() as u64 => Ok(value as u32),
+        _ => Err(IndexSettingsParseError::ExpectedPositiveInteger(key.to_string())),
+    }
+}
+
+
+/// Parses a value that may be given as either a JSON number or a string containing one
+fn parse_u64(key: &str, json: &serde_json::Value) -> Result<u64, IndexSettingsParseError> {
+    let value = match *json {
+        serde_json::Value::Number(ref number) => number.as_u64(),
+        serde_json::Value::String(ref string) => string.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    value.ok_or_else(|| IndexSettingsParseError::ExpectedPositiveInteger(key.to_string()))
+}
+
+
+/// Parses an Elasticsearch time value such as "1s" or "500ms"
+///
+/// Numbers without a unit are taken as milliseconds. "-1" returns None
+fn parse_time_value(key: &str, json: &serde_json::Value) -> Result<Option<Duration>, IndexSettingsParseError> {
+    let value = match *json {
+        serde_json::Value::Number(ref number) => {
+            if number.as_i64() == Some(-1) {
+                return Ok(None);
+            }
+
+            match number.as_u64() {
+                Some(millis) => return Ok(Some(Duration::from_millis(millis))),
+                None => return Err(IndexSettingsParseError::InvalidValue(key.to_string())),
+            }
+        }
+        serde_json::Value::String(ref string) => string.trim(),
+        _ => return Err(IndexSettingsParseError::InvalidValue(key.to_string())),
+    };
+
+    if value == "-1" {
+        return Ok(None);
+    }
+
+    let (number, millis_per_unit) = if value.ends_with("ms") {
+        (&value[..value.len() - 2], 1)
+    } else if value.ends_with('s') {
+        (&value[..value.len() - 1], 1000)
+    } else if value.ends_with('m') {
+        (&value[..value.len() - 1], 60 * 1000)
+    } else if value.ends_with('h') {
+        (&value[..value.len() - 1], 60 * 60 * 1000)
+    } else {
+        (value, 1)
+    };
+
+    // Values too large to be held in milliseconds are rejected rather than overflowing
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(millis_per_unit)) {
+        Some(millis) => Ok(Some(Duration::from_millis(millis))),
+        None => Err(IndexSettingsParseError::InvalidValue(key.to_string())),
+    }
+}
+
+
+/// Parses a value that may be given as either a JSON boolean or the strings "true"/"false"
+fn parse_bool(key: &str, json: &serde_json::Value) -> Result<bool, IndexSettingsParseError> {
+    match *json {
+        serde_json::Value::Bool(value) => Ok(value),
+        serde_json::Value::String(ref string) if string == "true" => Ok(true),
+        serde_json::Value::String(ref string) if string == "false" => Ok(false),
+        _ => Err(IndexSettingsParseError::ExpectedBoolean(key.to_string())),
+    }
+}
+
+
+/// Parses a value that may be given as either a JSON number or a string containing one
+fn parse_f32(key: &str, json: &serde_json::Value) -> Result<f32, IndexSettingsParseError> {
+    let value = match *json {
+        serde_json::Value::Number(ref number) => number.as_f64(),
+        serde_json::Value::String(ref string) => string.parse::<f64>().ok(),
+        _ => None,
+    };
+
+    match value {
+        Some(value) if value >= 0.0 => Ok(value as f32),
+        _ => Err(IndexSettingsParseError::InvalidValue(key.to_string())),
+    }
+}
+
+
+/// Builds a similarity model from its "similarity.<name>.*" settings
+fn parse_similarity(name: &str, params: &BTreeMap<String, serde_json::Value>) -> Result<SimilarityModel, IndexSettingsParseError> {
+    let key = |param: &str| format!("similarity.{}.{}", name, param);
+
+    let similarity_type = match params.get("type") {
+        Some(value) => try!(parse_string(&key("type"), value)),
+        None => return Err(IndexSettingsParseError::InvalidValue(key("type"))),
+    };
+
+    match similarity_type {
+        "BM25" => {
+            let mut k1 = DEFAULT_BM25_K1;
+            let mut b = DEFAULT_BM25_B;
+
+            for (param, value) in params.iter() {
+                match param.as_ref() {
+                    "type" => {}
+                    "k1" => k1 = try!(parse_f32(&key(param), value)),
+                    "b" => {
+                        b = try!(parse_f32(&key(param), value));
+
+                        if b > 1.0 {
+                            return Err(IndexSettingsParseError::InvalidValue(key(param)));
+                        }
+                    }
+                    _ => return Err(IndexSettingsParseError::UnrecognisedSetting(key(param))),
+                }
+            }
+
+            Ok(SimilarityModel::Bm25 {
+                k1: k1,
+                b: b,
+            })
+        }
+        "classic" => {
+            for param in params.keys() {
+                if param != "type" {
+                    return Err(IndexSettingsParseError::UnrecognisedSetting(key(param)));
+                }
+            }
+
+            Ok(SimilarityModel::TfIdf)
+        }
+        _ => Err(IndexSettingsParseError::InvalidValue(key("type"))),
+    }
+}
+
+
+fn parse_string<'a>(key: &str, json: &'a serde_json::Value) -> Result<&'a str, IndexSettingsParseError> {
+    json.as_str().ok_or_else(|| IndexSettingsParseError::ExpectedString(key.to_string()))
+}
+
+
+/// Parses a setting that can be unset by giving it a null value
+fn parse_optional<T, F>(key: &str, json: &serde_json::Value, parse: F) -> Result<Option<T>, IndexSettingsParseError>
+    where F: Fn(&str, &serde_json::Value) -> Result<T, IndexSettingsParseError>
+{
+    if json.is_null() {
+        Ok(None)
+    } else {
+        parse(key, json).map(Some)
+    }
+}
+
+
+/// Finds the slow log threshold that a key such as "search.slowlog.threshold.query.warn"
+/// sets
+fn slowlog_threshold<'a>(slowlog: &'a mut SlowLogSettings, key: &str) -> Option<&'a mut Option<Duration>> {
+    let (thresholds, level) = if key.starts_with("search.slowlog.threshold.query.") {
+        (&mut slowlog.query, &key[31..])
+    } else if key.starts_with("search.slowlog.threshold.fetch.") {
+        (&mut slowlog.fetch, &key[31..])
+    } else if key.starts_with("indexing.slowlog.threshold.index.") {
+        (&mut slowlog.index, &key[33..])
+    } else {
+        return None;
+    };
+
+    match level {
+        "warn" => Some(&mut thresholds.warn),
+        "info" => Some(&mut thresholds.info),
+        "debug" => Some(&mut thresholds.debug),
+        "trace" => Some(&mut thresholds.trace),
+        _ => None,
+    }
+}
+
+
+/// Parses the "indexing.slowlog.source" setting. This is either a number of characters, or
+/// a boolean where true logs the whole source and false logs none of it
+fn parse_slowlog_source(key: &str, json: &serde_json::Value) -> Result<Option<usize>, IndexSettingsParseError> {
+    match *json {
+        serde_json::Value::Bool(true) => Ok(None),
+        serde_json::Value::Bool(false) => Ok(Some(0)),
+        serde_json::Value::String(ref string) if string == "true" => Ok(None),
+        serde_json::Value::String(ref string) if string == "false" => Ok(Some(0)),
+        _ => parse_u32(key, json).map(|chars| Some(chars as usize)),
+    }
+}
+
+
+/// Parses index settings into the given IndexSettings object
+///
+/// Analysis settings are ignored as they are handled separately. If `dynamic_only` is set,
+/// an error is returned if the settings contain anything that cannot be changed on an
+/// existing index.
+pub fn parse(settings: &mut IndexSettings, json: &serde_json::Value, dynamic_only: bool) -> Result<(), IndexSettingsParseError> {
+    if !json.is_object() {
+        return Err(IndexSettingsParseError::ExpectedObject);
+    }
+
+    let mut flattened = Vec::new();
+    flatten("", json, &mut flattened);
+
+    // Check everything before making any changes, so the settings are left untouched on error
+    let mut new_settings = settings.clone();
+
+    // Parameters for each custom similarity, these are parsed once they have all been collected
+    let mut similarities: BTreeMap<String, BTreeMap<String, serde_json::Value>> = BTreeMap::new();
+
+    for (key, value) in flattened {
+        if key.starts_with("analysis.") {
+            continue;
+        }
+
+        if key.starts_with("similarity.") {
+            if dynamic_only {
+                return Err(IndexSettingsParseError::NonDynamicSetting(key));
+            }
+
+            match key[11..].rfind('.') {
+                Some(pos) => {
+                    let name = key[11..11 + pos].to_string();
+                    let param = key[11 + pos + 1..].to_string();
+                    similarities.entry(name).or_insert_with(BTreeMap::new).insert(param, value);
+                }
+                None => return Err(IndexSettingsParseError::UnrecognisedSetting(key)),
+            }
+
+            continue;
+        }
+
+        if let Some(threshold) = slowlog_threshold(&mut new_settings.slowlog, &key) {
+            *threshold = try!(parse_time_value(&key, &value));
+            continue;
+        }
+
+        match key.as_ref() {
+            "number_of_shards" => {
+                if dynamic_only {
+                    return Err(IndexSettingsParseError::NonDynamicSetting(key));
+                }
+
+                new_settings.number_of_shards = try!(parse_u32(&key, &value));
+
+                if new_settings.number_of_shards == 0 {
+                    return Err(IndexSettingsParseError::InvalidValue(key));
+                }
+            }
+            "number_of_replicas" => {
+                new_settings.number_of_replicas = try!(parse_u32(&key, &value));
+            }
+            "write.wait_for_active_shards" => {
+                new_settings.wait_for_active_shards = match value {
+                    serde_json::Value::Number(_) => ActiveShardCount::Count(try!(parse_u32(&key, &value))),
+                    _ => try!(ActiveShardCount::parse(try!(parse_string(&key, &value))).ok_or_else(|| IndexSettingsParseError::InvalidValue(key.clone()))),
+                };
+            }
+            "refresh_interval" => {
+                new_settings.refresh_interval = try!(parse_time_value(&key, &value));
+            }
+            "gc_deletes" => {
+                new_settings.gc_deletes = try!(try!(parse_time_value(&key, &value)).ok_or_else(|| IndexSettingsParseError::InvalidValue(key.clone())));
+            }
+            "store.type" => {
+                if dynamic_only {
+                    return Err(IndexSettingsParseError::NonDynamicSetting(key));
+                }
+
+                new_settings.store_type = match try!(parse_string(&key, &value)) {
+                    "default" | "fs" => StoreType::Default,
+                    store_type @ "mmapfs" | store_type @ "niofs" | store_type @ "simplefs" | store_type @ "hybridfs" => {
+                        return Err(IndexSettingsParseError::UnsupportedStoreType(store_type.to_string()));
+                    }
+                    _ => return Err(IndexSettingsParseError::InvalidValue(key)),
+                };
+            }
+            "blocks.read_only" => {
+                new_settings.blocks.read_only = try!(parse_bool(&key, &value));
+            }
+            "blocks.read_only_allow_delete" => {
+                new_settings.blocks.read_only_allow_delete = try!(parse_bool(&key, &value));
+            }
+            "blocks.read" => {
+                new_settings.blocks.read = try!(parse_bool(&key, &value));
+            }
+            "blocks.write" => {
+                new_settings.blocks.write = try!(parse_bool(&key, &value));
+            }
+            "max_result_window" => {
+                new_settings.max_result_window = try!(parse_u32(&key, &value));
+            }
+            "search.boost" => {
+                new_settings.search_boost = try!(parse_f32(&key, &value));
+            }
+            "creation_date" => {
+                if dynamic_only {
+                    return Err(IndexSettingsParseError::NonDynamicSetting(key));
+                }
+
+                new_settings.creation_date = try!(parse_optional(&key, &value, parse_u64));
+            }
+            "lifecycle.name" => {
+                new_settings.lifecycle.name = try!(parse_optional(&key, &value, |key, value| parse_string(key, value).map(|name| name.to_string())));
+            }
+            "lifecycle.rollover_alias" => {
+                new_settings.lifecycle.rollover_alias = try!(parse_optional(&key, &value, |key, value| parse_string(key, value).map(|alias| alias.to_string())));
+            }
+            "lifecycle.indexing_complete" => {
+                new_settings.lifecycle.indexing_complete = try!(parse_bool(&key, &value));
+            }
+            "lifecycle.origination_date" => {
+                new_settings.lifecycle.origination_date = try!(parse_optional(&key, &value, parse_u64));
+            }
+            "indexing.slowlog.source" => {
+                new_settings.slowlog.index_source_chars = try!(parse_slowlog_source(&key, &value));
+            }
+            _ => return Err(IndexSettingsParseError::UnrecognisedSetting(key)),
+        }
+    }
+
+    for (name, params) in similarities {
+        let similarity = try!(parse_similarity(&name, &params));
+        new_settings.similarities.insert(name, similarity);
+    }
+
+    *settings = new_settings;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use index::metadata::settings::{IndexSettings, StoreType, ActiveShardCount};
+    use search::similarity::SimilarityModel;
+
+    use super::{parse, IndexSettingsParseError};
+
+    #[test]
+    fn test_nested() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "index": {
+                "number_of_shards": 3,
+                "number_of_replicas": "2",
+                "store": {
+                    "type": "fs"
+                }
+            }
+        }), false).expect("parse() returned an error");
+
+        assert_eq!(settings.number_of_shards, 3);
+        assert_eq!(settings.number_of_replicas, 2);
+        assert_eq!(settings.store_type, StoreType::Default);
+    }
+
+    #[test]
+    fn test_flat() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "index.number_of_replicas": 1,
+            "number_of_shards": 2
+        }), false).expect("parse() returned an error");
+
+        assert_eq!(settings.number_of_shards, 2);
+        assert_eq!(settings.number_of_replicas, 1);
+    }
+
+    #[test]
+    fn test_analysis_ignored() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "analysis": {
+                "analyzer": {}
+            }
+        }), false).expect("parse() returned an error");
+
+        assert_eq!(settings, IndexSettings::default());
+    }
+
+    #[test]
+    fn test_static_setting_on_existing_index() {
+        let mut settings = IndexSettings::default();
+        let error = parse(&mut settings, &json!({
+            "index": {
+                "number_of_replicas": 2,
+                "number_of_shards": 3
+            }
+        }), true).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexSettingsParseError::NonDynamicSetting("number_of_shards".to_string()));
+
+        // Settings must be left untouched
+        assert_eq!(settings, IndexSettings::default());
+    }
+
+    #[test]
+    fn test_unsupported_store_type() {
+        let mut settings = IndexSettings::default();
+        let error = parse(&mut settings, &json!({
+            "index.store.type": "mmapfs"
+        }), false).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexSettingsParseError::UnsupportedStoreType("mmapfs".to_string()));
+
+        let error = parse(&mut settings, &json!({
+            "index.store.type": "foo"
+        }), false).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("store.type".to_string()));
+    }
+
+    #[test]
+    fn test_wait_for_active_shards() {
+        let mut settings = IndexSettings::default();
+        assert_eq!(settings.wait_for_active_shards, ActiveShardCount::Count(1));
+
+        parse(&mut settings, &json!({"index.write.wait_for_active_shards": "all"}), true).expect("parse() returned an error");
+        assert_eq!(settings.wait_for_active_shards, ActiveShardCount::All);
+
+        parse(&mut settings, &json!({"index": {"write": {"wait_for_active_shards": 2}}}), true).expect("parse() returned an error");
+        assert_eq!(settings.wait_for_active_shards, ActiveShardCount::Count(2));
+
+        let error = parse(&mut settings, &json!({"index.write.wait_for_active_shards": "some"}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("write.wait_for_active_shards".to_string()));
+    }
+
+    #[test]
+    fn test_unrecognised_setting() {
+        let mut settings = IndexSettings::default();
+        let error = parse(&mut settings, &json!({
+            "index": {
+                "foo": "bar"
+            }
+        }), false).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexSettingsParseError::UnrecognisedSetting("foo".to_string()));
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let mut settings = IndexSettings::default();
+        assert_eq!(settings.refresh_interval, Some(Duration::from_secs(1)));
+
+        parse(&mut settings, &json!({"index": {"refresh_interval": "500ms"}}), true).unwrap();
+        assert_eq!(settings.refresh_interval, Some(Duration::from_millis(500)));
+
+        parse(&mut settings, &json!({"index": {"refresh_interval": "30s"}}), true).unwrap();
+        assert_eq!(settings.refresh_interval, Some(Duration::from_secs(30)));
+
+        parse(&mut settings, &json!({"index": {"refresh_interval": 250}}), true).unwrap();
+        assert_eq!(settings.refresh_interval, Some(Duration::from_millis(250)));
+
+        parse(&mut settings, &json!({"index": {"refresh_interval": "-1"}}), true).unwrap();
+        assert_eq!(settings.refresh_interval, None);
+
+        let error = parse(&mut settings, &json!({"index": {"refresh_interval": "soon"}}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("refresh_interval".to_string()));
+
+        let error = parse(&mut settings, &json!({"index": {"refresh_interval": "18446744073709551615h"}}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("refresh_interval".to_string()));
+    }
+
+    #[test]
+    fn test_gc_deletes() {
+        let mut settings = IndexSettings::default();
+        assert_eq!(settings.gc_deletes, Duration::from_secs(60));
+
+        parse(&mut settings, &json!({"index": {"gc_deletes": "5m"}}), true).unwrap();
+        assert_eq!(settings.gc_deletes, Duration::from_secs(300));
+
+        let error = parse(&mut settings, &json!({"index": {"gc_deletes": "-1"}}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("gc_deletes".to_string()));
+    }
+
+    #[test]
+    fn test_max_result_window() {
+        let mut settings = IndexSettings::default();
+        assert_eq!(settings.max_result_window, 10000);
+
+        parse(&mut settings, &json!({"index": {"max_result_window": 50000}}), true).expect("parse() returned an error");
+        assert_eq!(settings.max_result_window, 50000);
+
+        let error = parse(&mut settings, &json!({"index.max_result_window": "lots"}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::ExpectedPositiveInteger("max_result_window".to_string()));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut settings = IndexSettings::default();
+        assert!(!settings.blocks.blocks_write());
+
+        parse(&mut settings, &json!({"index": {"blocks": {"read_only_allow_delete": true}}}), true).unwrap();
+        assert!(settings.blocks.read_only_allow_delete);
+        assert!(settings.blocks.blocks_write());
+        assert!(!settings.blocks.blocks_delete());
+
+        parse(&mut settings, &json!({"index.blocks.write": "true", "index.blocks.read_only_allow_delete": false}), true).unwrap();
+        assert!(settings.blocks.write);
+        assert!(!settings.blocks.read_only_allow_delete);
+        assert!(settings.blocks.blocks_delete());
+        assert!(!settings.blocks.blocks_metadata_write());
+
+        let error = parse(&mut settings, &json!({"index": {"blocks": {"read": "yes"}}}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::ExpectedBoolean("blocks.read".to_string()));
+    }
+
+    #[test]
+    fn test_search_boost() {
+        let mut settings = IndexSettings::default();
+        assert_eq!(settings.search_boost, 1.0f32);
+
+        parse(&mut settings, &json!({"index": {"search": {"boost": 0.5}}}), true).unwrap();
+        assert_eq!(settings.search_boost, 0.5f32);
+
+        parse(&mut settings, &json!({"index.search.boost": "2"}), true).unwrap();
+        assert_eq!(settings.search_boost, 2.0f32);
+
+        let error = parse(&mut settings, &json!({"index": {"search": {"boost": -1}}}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("search.boost".to_string()));
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "index": {
+                "lifecycle": {
+                    "name": "logs",
+                    "rollover_alias": "logs-write",
+                    "origination_date": "1500000000000"
+                }
+            }
+        }), true).expect("parse() returned an error");
+
+        assert_eq!(settings.lifecycle.name, Some("logs".to_string()));
+        assert_eq!(settings.lifecycle.rollover_alias, Some("logs-write".to_string()));
+        assert_eq!(settings.lifecycle.origination_date, Some(1500000000000));
+        assert!(!settings.lifecycle.indexing_complete);
+
+        // Null removes a setting
+        parse(&mut settings, &json!({"index.lifecycle.name": null, "index.lifecycle.indexing_complete": true}), true).unwrap();
+        assert_eq!(settings.lifecycle.name, None);
+        assert!(settings.lifecycle.indexing_complete);
+
+        // The creation date can only be set when the index is created
+        let error = parse(&mut settings, &json!({"index.creation_date": 1500000000000u64}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::NonDynamicSetting("creation_date".to_string()));
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "index": {
+                "search.slowlog.threshold": {
+                    "query": {"warn": "10s", "info": "5s"},
+                    "fetch.debug": "500ms"
+                },
+                "indexing.slowlog": {
+                    "threshold.index.trace": 0,
+                    "source": false
+                }
+            }
+        }), true).expect("parse() returned an error");
+
+        assert_eq!(settings.slowlog.query.warn, Some(Duration::from_secs(10)));
+        assert_eq!(settings.slowlog.query.info, Some(Duration::from_secs(5)));
+        assert_eq!(settings.slowlog.query.debug, None);
+        assert_eq!(settings.slowlog.fetch.debug, Some(Duration::from_millis(500)));
+        assert_eq!(settings.slowlog.index.trace, Some(Duration::from_millis(0)));
+        assert_eq!(settings.slowlog.index_source_chars, Some(0));
+
+        // "-1" disables a threshold
+        parse(&mut settings, &json!({"index.search.slowlog.threshold.query.warn": "-1", "index.indexing.slowlog.source": true}), true).unwrap();
+        assert_eq!(settings.slowlog.query.warn, None);
+        assert_eq!(settings.slowlog.index_source_chars, None);
+
+        let error = parse(&mut settings, &json!({"index.search.slowlog.threshold.query.error": "1s"}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::UnrecognisedSetting("search.slowlog.threshold.query.error".to_string()));
+    }
+
+    #[test]
+    fn test_similarity() {
+        let mut settings = IndexSettings::default();
+        parse(&mut settings, &json!({
+            "index": {
+                "similarity": {
+                    "short_text": {
+                        "type": "BM25",
+                        "k1": 1.5,
+                        "b": 0.3
+                    },
+                    "old": {
+                        "type": "classic"
+                    }
+                }
+            }
+        }), false).expect("parse() returned an error");
+
+        assert_eq!(settings.get_similarity("short_text"), Some(SimilarityModel::Bm25 { k1: 1.5, b: 0.3 }));
+        assert_eq!(settings.get_similarity("old"), Some(SimilarityModel::TfIdf));
+        assert_eq!(settings.get_similarity("BM25"), Some(SimilarityModel::default()));
+        assert_eq!(settings.get_similarity("foo"), None);
+
+        // Similarities can't be changed on an existing index
+        let error = parse(&mut settings, &json!({"index.similarity.short_text.k1": 2}), true).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::NonDynamicSetting("similarity.short_text.k1".to_string()));
+
+        let error = parse(&mut settings, &json!({"index.similarity.foo.type": "bar"}), false).err().expect("parse() was supposed to return an error, but didn't");
+        assert_eq!(error, IndexSettingsParseError::InvalidValue("similarity.foo.type".to_string()));
+    }
+}
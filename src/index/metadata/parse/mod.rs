@@ -1,15 +1,19 @@
 pub mod analysis_tokenizer;
 pub mod analysis_filter;
 pub mod analysis_analyzer;
+pub mod index_settings;
+pub mod alias;
 
 use serde_json;
 
 use index::metadata::IndexMetadata;
-use mapping::parse::{MappingParseError, parse as parse_mapping};
+use mapping::parse::{MappingParseError, parse as parse_mapping, check_similarities};
 
 use self::analysis_tokenizer::{TokenizerParseError, parse as parse_tokenizer};
 use self::analysis_filter::{FilterParseError, parse as parse_filter};
 use self::analysis_analyzer::{AnalyzerParseError, parse as parse_analyzer};
+use self::index_settings::{IndexSettingsParseError, parse as parse_index_settings};
+use self::alias::{AliasParseError, parse as parse_alias};
 
 
 #[derive(Debug, PartialEq)]
@@ -19,6 +23,8 @@ pub enum IndexMetadataParseError {
     FilterParseError(String, FilterParseError),
     AnalyzerParseError(String, AnalyzerParseError),
     MappingParseError(String, MappingParseError),
+    IndexSettingsParseError(IndexSettingsParseError),
+    AliasParseError(String, AliasParseError),
 }
 
 
@@ -36,6 +42,11 @@ pub fn parse(metadata: &mut IndexMetadata, data: serde_json::Value) -> Result<()
             None => return Err(IndexMetadataParseError::ExpectedObject),
         };
 
+        // Index settings
+        if let Err(e) = parse_index_settings(&mut metadata.settings, &data["settings"], false) {
+            return Err(IndexMetadataParseError::IndexSettingsParseError(e));
+        }
+
         if let Some(analysis) = settings.get("analysis") {
             let analysis = match analysis.as_object() {
                 Some(object) => object,
@@ -106,11 +117,30 @@ pub fn parse(metadata: &mut IndexMetadata, data: serde_json::Value) -> Result<()
                 Ok(mapping) => mapping,
                 Err(e) => return Err(IndexMetadataParseError::MappingParseError(name.to_string(), e)),
             };
+            if let Err(e) = check_similarities(&mapping_builder, &metadata.settings) {
+                return Err(IndexMetadataParseError::MappingParseError(name.to_string(), e));
+            }
             let mapping = mapping_builder.build(&metadata);
             metadata.mappings.insert(name.clone(), mapping);
         }
     }
 
+    if let Some(aliases) = data.get("aliases") {
+        let aliases = match aliases.as_object() {
+            Some(object) => object,
+            None => return Err(IndexMetadataParseError::ExpectedObject),
+        };
+
+        for (name, data) in aliases {
+            let alias = match parse_alias(data) {
+                Ok(alias) => alias,
+                Err(e) => return Err(IndexMetadataParseError::AliasParseError(name.to_string(), e)),
+            };
+
+            metadata.aliases.insert(name.clone(), alias);
+        }
+    }
+
     Ok(())
 }
 
@@ -123,11 +153,14 @@ mod tests {
     use analysis::tokenizers::TokenizerSpec;
     use analysis::filters::FilterSpec;
     use analysis::AnalyzerSpec;
-    use mapping::parse::MappingParseError;
+    use mapping::parse::{MappingParseError, FieldMappingParseError};
     use index::metadata::IndexMetadata;
+    use index::metadata::settings::StoreType;
 
     use super::{parse, IndexMetadataParseError};
     use super::analysis_tokenizer::TokenizerParseError;
+    use super::index_settings::IndexSettingsParseError;
+    use super::alias::AliasParseError;
     use super::analysis_filter::FilterParseError;
 
     #[test]
@@ -360,4 +393,78 @@ mod tests {
 
         assert_eq!(error, IndexMetadataParseError::MappingParseError("test_mapping".to_string(), MappingParseError::UnrecognisedKeys(vec!["foo".to_string()])));
     }
+
+    #[test]
+    fn test_mapping_unrecognised_similarity() {
+        let mut metadata = IndexMetadata::default();
+        let error = parse(&mut metadata, json!({
+            "settings": {
+                "similarity": {
+                    "short_text": {"type": "BM25", "b": 0.3}
+                }
+            },
+            "mappings": {
+                "test_mapping": {
+                    "properties": {
+                        "title": {"type": "string", "similarity": "short_text"},
+                        "body": {"type": "string", "similarity": "long_text"},
+                    }
+                }
+            }
+        })).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexMetadataParseError::MappingParseError("test_mapping".to_string(), MappingParseError::FieldMappingParseError("body".to_string(), FieldMappingParseError::UnrecognisedSimilarity("long_text".to_string()))));
+    }
+
+    #[test]
+    fn test_index_settings() {
+        let mut metadata = IndexMetadata::default();
+        parse(&mut metadata, json!({
+            "settings": {
+                "index": {
+                    "number_of_replicas": 1,
+                    "store": {
+                        "type": "fs"
+                    }
+                }
+            }
+        })).expect("parse() returned an error");
+
+        assert_eq!(metadata.settings.number_of_replicas, 1);
+        assert_eq!(metadata.settings.store_type, StoreType::Default);
+    }
+
+    #[test]
+    fn test_index_settings_error() {
+        let mut metadata = IndexMetadata::default();
+        let error = parse(&mut metadata, json!({
+            "settings": {
+                "number_of_shards": "foo"
+            }
+        })).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexMetadataParseError::IndexSettingsParseError(IndexSettingsParseError::ExpectedPositiveInteger("number_of_shards".to_string())));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut metadata = IndexMetadata::default();
+        parse(&mut metadata, json!({
+            "aliases": {
+                "alias1": {},
+                "alias2": {"is_write_index": true}
+            }
+        })).expect("parse() returned an error");
+
+        assert_eq!(metadata.aliases.keys().collect::<Vec<_>>(), vec!["alias1", "alias2"]);
+        assert_eq!(metadata.aliases["alias2"].is_write_index, Some(true));
+
+        let error = parse(&mut metadata, json!({
+            "aliases": {
+                "alias3": {"is_write_index": 1}
+            }
+        })).err().expect("parse() was supposed to return an error, but didn't");
+
+        assert_eq!(error, IndexMetadataParseError::AliasParseError("alias3".to_string(), AliasParseError::ExpectedBoolean));
+    }
 }
@@ -0,0 +1,369 @@
+use std::time::Duration;
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Serializer};
+use slog::Level;
+
+use search::similarity::SimilarityModel;
+
+
+/// How the index's store reads its data files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreType {
+    /// Use the server-wide default
+    ///
+    /// This is currently the only store type. Others, such as "mmapfs", are rejected when
+    /// the index is created as data files are always read through RocksDB
+    Default,
+}
+
+
+impl StoreType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StoreType::Default => "default",
+        }
+    }
+}
+
+
+/// How many copies of an index must be active for a write to go ahead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActiveShardCount {
+    /// At least this many copies, counting the primary
+    Count(u32),
+
+    /// The primary and every replica
+    All,
+}
+
+
+impl ActiveShardCount {
+    /// Parses "all" or a number of copies
+    pub fn parse(value: &str) -> Option<ActiveShardCount> {
+        match value {
+            "all" => Some(ActiveShardCount::All),
+            value => value.parse().ok().map(ActiveShardCount::Count),
+        }
+    }
+
+    /// The number of active copies needed, for an index with this many replicas
+    pub fn required(&self, number_of_replicas: u32) -> usize {
+        match *self {
+            ActiveShardCount::Count(count) => count as usize,
+            ActiveShardCount::All => number_of_replicas as usize + 1,
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        match *self {
+            ActiveShardCount::Count(count) => count.to_string(),
+            ActiveShardCount::All => "all".to_string(),
+        }
+    }
+}
+
+
+impl Default for ActiveShardCount {
+    fn default() -> ActiveShardCount {
+        ActiveShardCount::Count(1)
+    }
+}
+
+
+/// Operations that are blocked on the index
+///
+/// All blocks are dynamic settings. `read_only_allow_delete` is also set automatically
+/// when the data disk is nearly full (see `System::check_disk_usage`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexBlocks {
+    /// Block all writes, including changes to the index's metadata
+    pub read_only: bool,
+
+    /// Like `read_only`, but documents can still be deleted to free up space
+    pub read_only_allow_delete: bool,
+
+    /// Block searches and document reads
+    pub read: bool,
+
+    /// Block writes to documents. Metadata can still be changed
+    pub write: bool,
+}
+
+
+impl IndexBlocks {
+    /// Returns true if documents can't be indexed
+    pub fn blocks_write(&self) -> bool {
+        self.read_only || self.read_only_allow_delete || self.write
+    }
+
+    /// Returns true if documents can't be deleted
+    pub fn blocks_delete(&self) -> bool {
+        self.read_only || self.write
+    }
+
+    /// Returns true if documents can't be searched or read
+    pub fn blocks_read(&self) -> bool {
+        self.read
+    }
+
+    /// Returns true if mappings and settings (other than the blocks themselves) can't be changed
+    pub fn blocks_metadata_write(&self) -> bool {
+        self.read_only || self.read_only_allow_delete
+    }
+}
+
+
+/// Settings that put the index under a lifecycle policy (see lifecycle/mod.rs)
+///
+/// All of these are dynamic.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LifecycleSettings {
+    /// Name of the policy that manages the index
+    pub name: Option<String>,
+
+    /// The alias that is moved to a new index when the index is rolled over
+    pub rollover_alias: Option<String>,
+
+    /// Set once the index has been rolled over, so it won't be rolled over again
+    pub indexing_complete: bool,
+
+    /// Milliseconds since the epoch that the index's age is measured from, instead of its
+    /// creation date. This is set when the index is rolled over
+    pub origination_date: Option<u64>,
+}
+
+
+/// How long an operation can take before it's written to the slow log at each level
+///
+/// None disables logging at that level.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SlowLogThresholds {
+    pub warn: Option<Duration>,
+    pub info: Option<Duration>,
+    pub debug: Option<Duration>,
+    pub trace: Option<Duration>,
+}
+
+
+impl SlowLogThresholds {
+    /// Returns the most severe level whose threshold an operation that took `took` reached
+    pub fn level(&self, took: Duration) -> Option<Level> {
+        let levels = [(self.warn, Level::Warning), (self.info, Level::Info), (self.debug, Level::Debug), (self.trace, Level::Trace)];
+        levels.iter().find(|&&(threshold, _)| threshold.map_or(false, |threshold| took >= threshold)).map(|&(_, level)| level)
+    }
+
+    fn to_json(&self) -> ::serde_json::Value {
+        json!({
+            "warn": format_time_value(self.warn),
+            "info": format_time_value(self.info),
+            "debug": format_time_value(self.debug),
+            "trace": format_time_value(self.trace),
+        })
+    }
+}
+
+
+/// Thresholds for logging slow searches and indexing (see slowlog.rs)
+///
+/// All of these are dynamic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowLogSettings {
+    /// Finding the matching documents of a search
+    pub query: SlowLogThresholds,
+
+    /// Loading the hits of a search
+    pub fetch: SlowLogThresholds,
+
+    /// Indexing a document
+    pub index: SlowLogThresholds,
+
+    /// Number of characters of a slow indexed document's source to log. None logs all of it
+    pub index_source_chars: Option<usize>,
+}
+
+
+impl Default for SlowLogSettings {
+    fn default() -> SlowLogSettings {
+        SlowLogSettings {
+            query: SlowLogThresholds::default(),
+            fetch: SlowLogThresholds::default(),
+            index: SlowLogThresholds::default(),
+            index_source_chars: Some(1000),
+        }
+    }
+}
+
+
+/// Index-level settings
+///
+/// Static settings can only be set when the index is created. Dynamic settings can be
+/// changed at any time through the `_settings` API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSettings {
+    /// Number of primary shards (static)
+    pub number_of_shards: u32,
+
+    /// Number of replica shards (dynamic)
+    pub number_of_replicas: u32,
+
+    /// Copies that must be active for writes that don't give `wait_for_active_shards` (dynamic)
+    pub wait_for_active_shards: ActiveShardCount,
+
+    /// How the store reads its data files (static)
+    pub store_type: StoreType,
+
+
+    /// How often recent changes are made searchable (dynamic)
+    /// None disables automatic refreshes
+    pub refresh_interval: Option<Duration>,
+
+    /// How long the versions of deleted documents are kept for (dynamic)
+    /// A document that's indexed again within this carries on from the version of its deletion
+    pub gc_deletes: Duration,
+
+    /// Operations that are blocked on the index (dynamic)
+    pub blocks: IndexBlocks,
+
+    /// Custom similarity models that fields can select in their mappings (static)
+    pub similarities: BTreeMap<String, SimilarityModel>,
+
+    /// Largest value of from + size that a search of the index can ask for (dynamic)
+    pub max_result_window: u32,
+
+    /// Multiplies the score of every document found in the index (dynamic)
+    /// Searches can override this with the "indices_boost" option
+    pub search_boost: f32,
+
+    /// Milliseconds since the epoch when the index was created (static)
+    /// Indices created before this was recorded don't have one
+    pub creation_date: Option<u64>,
+
+    /// Lifecycle management (dynamic)
+    pub lifecycle: LifecycleSettings,
+
+    /// Thresholds for logging slow operations (dynamic)
+    pub slowlog: SlowLogSettings,
+}
+
+
+impl Default for IndexSettings {
+    fn default() -> IndexSettings {
+        IndexSettings {
+            number_of_shards: 1,
+            number_of_replicas: 0,
+            wait_for_active_shards: ActiveShardCount::default(),
+            store_type: StoreType::Default,
+            refresh_interval: Some(Duration::from_secs(1)),
+            gc_deletes: Duration::from_secs(60),
+            blocks: IndexBlocks::default(),
+            similarities: BTreeMap::new(),
+            max_result_window: 10000,
+            search_boost: 1.0f32,
+            creation_date: None,
+            lifecycle: LifecycleSettings::default(),
+            slowlog: SlowLogSettings::default(),
+        }
+    }
+}
+
+
+impl IndexSettings {
+    /// Finds a similarity model by name
+    ///
+    /// Custom similarities take precedence over the builtin "BM25" and "classic" ones
+    pub fn get_similarity(&self, name: &str) -> Option<SimilarityModel> {
+        if let Some(similarity) = self.similarities.get(name) {
+            return Some(similarity.clone());
+        }
+
+        match name {
+            "BM25" => Some(SimilarityModel::default()),
+            "classic" => Some(SimilarityModel::TfIdf),
+            _ => None,
+        }
+    }
+}
+
+
+/// Formats a duration as an Elasticsearch time value, eg "1s" or "500ms"
+fn format_time_value(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => {
+            if duration.subsec_nanos() == 0 {
+                format!("{}s", duration.as_secs())
+            } else {
+                format!("{}ms", duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000)
+            }
+        }
+        None => "-1".to_string(),
+    }
+}
+
+
+fn similarity_to_json(similarity: &SimilarityModel) -> ::serde_json::Value {
+    match *similarity {
+        SimilarityModel::TfIdf => json!({"type": "classic"}),
+        SimilarityModel::Bm25{k1, b} => json!({"type": "BM25", "k1": k1, "b": b}),
+    }
+}
+
+
+impl Serialize for IndexSettings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut similarities_json = BTreeMap::new();
+        for (name, similarity) in self.similarities.iter() {
+            similarities_json.insert(name.clone(), similarity_to_json(similarity));
+        }
+
+        let json = json!({
+            "number_of_shards": self.number_of_shards,
+            "number_of_replicas": self.number_of_replicas,
+            "write": {
+                "wait_for_active_shards": self.wait_for_active_shards.to_string(),
+            },
+            "store": {
+                "type": self.store_type.name(),
+            },
+            "refresh_interval": format_time_value(self.refresh_interval),
+            "gc_deletes": format_time_value(Some(self.gc_deletes)),
+            "blocks": {
+                "read_only": self.blocks.read_only,
+                "read_only_allow_delete": self.blocks.read_only_allow_delete,
+                "read": self.blocks.read,
+                "write": self.blocks.write,
+            },
+            "similarity": similarities_json,
+            "max_result_window": self.max_result_window,
+            "search": {
+                "boost": self.search_boost,
+                "slowlog": {
+                    "threshold": {
+                        "query": self.slowlog.query.to_json(),
+                        "fetch": self.slowlog.fetch.to_json(),
+                    },
+                },
+            },
+            "indexing": {
+                "slowlog": {
+                    "threshold": {
+                        "index": self.slowlog.index.to_json(),
+                    },
+                    "source": match self.slowlog.index_source_chars {
+                        Some(chars) => json!(chars.to_string()),
+                        None => json!("true"),
+                    },
+                },
+            },
+            "creation_date": self.creation_date.map(|date| date.to_string()),
+            "lifecycle": {
+                "name": self.lifecycle.name,
+                "rollover_alias": self.lifecycle.rollover_alias,
+                "indexing_complete": self.lifecycle.indexing_complete,
+                "origination_date": self.lifecycle.origination_date,
+            },
+        });
+
+        json.serialize(serializer)
+    }
+}
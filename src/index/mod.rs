@@ -1,13 +1,28 @@
+pub mod fields;
 pub mod maintenance;
 pub mod metadata;
+pub mod reader;
+pub mod recovery;
+pub mod refresh;
 
-use std::sync::RwLock;
-use std::path::PathBuf;
+use std::sync::{Arc, RwLock, Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io;
 
-use search::backends::rocksdb::RocksDBStore;
+use search::schema::{FieldType, FieldFlags, FieldId, AddFieldError};
+use search::backends::rocksdb::{RocksDBStore, StoreOptions, StoreOpenStage, StoreStatistics};
+use fnv::FnvHasher;
 use uuid::Uuid;
 
 use index::metadata::IndexMetadata;
+use index::metadata::settings::IndexSettings;
+use index::reader::IndexReader;
+use index::recovery::IndexRecovery;
+use dir_lock::DirLock;
 
 
 #[derive(Debug)]
@@ -15,17 +30,52 @@ pub struct Index {
     id: Uuid,
     canonical_name: String,
     pub metadata: RwLock<IndexMetadata>,
-    pub store: RocksDBStore,
+
+    /// The store of each shard, in order. Documents are spread between them by `route_to_shard`
+    shards: Vec<RocksDBStore>,
+
+    lock: DirLock,
+    last_refresh: Mutex<Instant>,
+    refreshed: Condvar,
+
+    /// Held while segments are being merged, so two merges can't pick the same segments
+    merge_lock: Mutex<()>,
 }
 
 
 impl Index {
-    pub fn new(id: Uuid, canonical_name: String, metadata: IndexMetadata, store: RocksDBStore) -> Index {
-        Index {
+    /// Creates a new Index object
+    ///
+    /// The lock must be held on the index's directory. It is released when the index is dropped
+    pub fn new(id: Uuid, canonical_name: String, metadata: IndexMetadata, shards: Vec<RocksDBStore>, lock: DirLock) -> Index {
+        // Changes are made searchable by refreshes (see refresh.rs)
+        for store in shards.iter() {
+            store.set_deferred_refresh(true);
+        }
+
+        let index = Index {
             id: id,
             canonical_name: canonical_name,
             metadata: RwLock::new(metadata),
-            store: store,
+            shards: shards,
+            lock: lock,
+            last_refresh: Mutex::new(Instant::now()),
+            refreshed: Condvar::new(),
+            merge_lock: Mutex::new(()),
+        };
+
+        index.apply_settings(&index.metadata.read().unwrap().settings);
+        index
+    }
+
+    /// Applies settings that the store needs to know about
+    ///
+    /// This must be called whenever the index's settings are changed. The settings are
+    /// passed in so this can be called while the metadata is locked
+    pub fn apply_settings(&self, settings: &IndexSettings) {
+        for store in self.shards.iter() {
+            store.set_write_blocks(settings.blocks.blocks_write(), settings.blocks.blocks_delete());
+            store.set_gc_deletes(settings.gc_deletes);
         }
     }
 
@@ -37,9 +87,269 @@ impl Index {
         &self.canonical_name
     }
 
+    /// The index's directory. This is also where the first shard is stored
+    pub fn path(&self) -> &Path {
+        self.shards[0].path()
+    }
+
     pub fn metadata_path(&self) -> PathBuf {
-        let mut path = self.store.path().to_path_buf();
+        let mut path = self.path().to_path_buf();
         path.push("metadata.json");
         path
     }
+
+    pub fn shards(&self) -> &[RocksDBStore] {
+        &self.shards
+    }
+
+    /// Finds the shard that documents with the routing value are stored in
+    ///
+    /// The routing value is the document's id, unless the request gave another one
+    pub fn shard_for_routing(&self, routing: &str) -> &RocksDBStore {
+        &self.shards[self.shard_number_for_routing(routing)]
+    }
+
+    /// Finds the number of the shard that documents with the routing value are stored in
+    pub fn shard_number_for_routing(&self, routing: &str) -> usize {
+        route_to_shard(routing, self.shards.len())
+    }
+
+    /// Finds the shard that a document id from an `IndexReader` belongs to
+    pub fn shard_for_doc(&self, doc_id: u64) -> &RocksDBStore {
+        &self.shards[reader::split_doc_id(doc_id).0]
+    }
+
+    /// Opens a point-in-time reader on all of the index's shards
+    pub fn reader(&self) -> IndexReader {
+        IndexReader::new(self.shards.iter().map(|store| store.reader()).collect())
+    }
+
+    /// Opens a reader that sees the index as it was when `IndexReader::pin_segments` was called
+    ///
+    /// Returns None if the pins have been released.
+    pub fn pinned_reader(&self, pins: &[u64]) -> Option<IndexReader> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for (store, pin) in self.shards.iter().zip(pins) {
+            shards.push(store.pinned_reader(*pin)?);
+        }
+
+        Some(IndexReader::new(shards))
+    }
+
+    /// Releases segments that were pinned with `IndexReader::pin_segments`
+    pub fn unpin_segments(&self, pins: &[u64]) {
+        for (store, pin) in self.shards.iter().zip(pins) {
+            store.unpin_segments(*pin);
+        }
+    }
+
+    /// Adds a field to the schema of every shard
+    ///
+    /// Fields are added to the shards in the same order, so they get the same ids in all of them
+    pub fn add_field(&self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        let mut field_id = None;
+
+        for store in self.shards.iter() {
+            field_id = Some(store.add_field(name.clone(), field_type.clone(), field_flags)?);
+        }
+
+        Ok(field_id.unwrap())
+    }
+
+    /// Reads the statistics of each shard's store
+    pub fn get_store_statistics(&self) -> Result<Vec<StoreStatistics>, String> {
+        self.shards.iter().map(|store| store.get_store_statistics()).collect()
+    }
+
+    /// Makes sure all changes to the index are safely on disk
+    pub fn flush(&self) -> Result<(), String> {
+        for store in self.shards.iter() {
+            store.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Index {
+    /// Takes an index back from the requests that are still using it, waiting up to `timeout`
+    /// for them to finish
+    ///
+    /// The index is handed back if it's still in use after that.
+    pub fn take_shared(index: Arc<Index>, timeout: Duration) -> Result<Index, Arc<Index>> {
+        let started_at = Instant::now();
+        let mut index = index;
+
+        loop {
+            match Arc::try_unwrap(index) {
+                Ok(index) => return Ok(index),
+                Err(shared) => {
+                    if started_at.elapsed() >= timeout {
+                        return Err(shared);
+                    }
+
+                    index = shared;
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+
+    /// Closes the index, releasing its store
+    ///
+    /// The metadata is kept so the index can be reopened later. A marker file is written
+    /// into the index directory so the index stays closed across restarts.
+    ///
+    /// On failure, the index is returned along with the error so it isn't lost
+    pub fn close(self) -> Result<ClosedIndex, (Index, io::Error)> {
+        let path = self.path().to_path_buf();
+
+        // Publish any pending changes
+        if let Err(e) = self.refresh() {
+            return Err((self, io::Error::new(io::ErrorKind::Other, e)));
+        }
+
+        if let Err(e) = File::create(closed_marker_path(&path)) {
+            return Err((self, e));
+        }
+
+        Ok(ClosedIndex {
+            id: self.id,
+            canonical_name: self.canonical_name,
+            metadata: self.metadata.into_inner().unwrap(),
+            path: path,
+            lock: self.lock,
+        })
+    }
+}
+
+
+/// An index that has been closed
+///
+/// Closed indices keep their metadata in memory but don't hold their store open, so they
+/// don't consume any resources apart from disk space. The directory lock is kept so no
+/// other process can open the index while it's closed.
+#[derive(Debug)]
+pub struct ClosedIndex {
+    id: Uuid,
+    canonical_name: String,
+    pub metadata: IndexMetadata,
+    path: PathBuf,
+    lock: DirLock,
+}
+
+
+impl ClosedIndex {
+    pub fn new(id: Uuid, canonical_name: String, metadata: IndexMetadata, path: PathBuf, lock: DirLock) -> ClosedIndex {
+        ClosedIndex {
+            id: id,
+            canonical_name: canonical_name,
+            metadata: metadata,
+            path: path,
+            lock: lock,
+        }
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn canonical_name(&self) -> &str {
+        &self.canonical_name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metadata_path(&self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("metadata.json");
+        path
+    }
+
+    /// Reopens the index's store
+    ///
+    /// The given store options are combined with the index's own settings. Progress is
+    /// reported to `recovery`. On failure, the closed index is returned along with the
+    /// error so it isn't lost
+    pub fn open(self, store_options: &StoreOptions, recovery: &IndexRecovery) -> Result<Index, (ClosedIndex, String)> {
+        let shards = match open_shards(&self.path, &self.metadata.settings, store_options, &|stage| recovery.store_progress(stage)) {
+            Ok(shards) => shards,
+            Err(e) => return Err((self, e)),
+        };
+
+        match fs::remove_file(closed_marker_path(&self.path)) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err((self, format!("failed to remove closed marker: {}", e))),
+        }
+
+        Ok(Index::new(self.id, self.canonical_name, self.metadata, shards, self.lock))
+    }
+}
+
+
+/// Works out which of an index's shards a document with the routing value belongs in
+///
+/// FNV hashes are the same on every platform and version, so documents are always looked
+/// for in the shard they were written to.
+pub fn route_to_shard(routing: &str, number_of_shards: usize) -> usize {
+    let mut hasher = FnvHasher::default();
+    hasher.write(routing.as_bytes());
+    (hasher.finish() % number_of_shards as u64) as usize
+}
+
+
+/// Finds the directory that a shard's store is kept in
+///
+/// The first shard is kept in the index's own directory, so indices that were created
+/// before they could have more than one shard are still found.
+pub fn shard_path(index_path: &Path, shard: usize) -> PathBuf {
+    let mut path = index_path.to_path_buf();
+    if shard > 0 {
+        path.push("shards");
+        path.push(shard.to_string());
+    }
+    path
+}
+
+
+/// Creates the stores of a new index's shards
+pub fn create_shards(index_path: &Path, settings: &IndexSettings, store_options: &StoreOptions) -> Result<Vec<RocksDBStore>, String> {
+    let mut shards = Vec::new();
+
+    for shard in 0..settings.number_of_shards as usize {
+        let path = shard_path(index_path, shard);
+        fs::create_dir_all(&path).map_err(|e| format!("failed to create shard directory: {}", e))?;
+        shards.push(RocksDBStore::create_with_options(&path, store_options)?);
+    }
+
+    Ok(shards)
+}
+
+
+/// Opens the stores of an existing index's shards, reporting progress to the given callback
+pub fn open_shards(index_path: &Path, settings: &IndexSettings, store_options: &StoreOptions, progress: &Fn(StoreOpenStage)) -> Result<Vec<RocksDBStore>, String> {
+    let mut shards = Vec::new();
+
+    for shard in 0..settings.number_of_shards as usize {
+        shards.push(RocksDBStore::open_with_progress(&shard_path(index_path, shard), store_options, progress)?);
+    }
+
+    Ok(shards)
+}
+
+
+fn closed_marker_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.to_path_buf();
+    path.push("closed");
+    path
+}
+
+
+/// Checks if the index stored at the given path has been closed
+pub fn is_closed(index_path: &Path) -> bool {
+    closed_marker_path(index_path).exists()
 }
@@ -0,0 +1,341 @@
+//! Reads from all of the shards of an index at once
+//!
+//! Each shard is a separate store, so the same document id can be used by documents in
+//! different shards. To tell them apart, ids returned by an `IndexReader` have the
+//! shard's number in their top 16 bits, which `DocId::as_u64` leaves free. Ids from the
+//! first shard are unchanged, so an index with one shard behaves exactly like its store.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use fnv::FnvHashMap;
+
+use search::document::{DocId, FieldValue};
+use search::term::Term;
+use search::schema::{Schema, FieldId};
+use search::query::Query;
+use search::knn::KnnSearch;
+use search::explanation::Explanation;
+use search::profile::QueryProfile;
+use search::cancellation::SearchCancellation;
+use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+use search::backends::rocksdb::{RocksDBReader, StoredFieldReadError};
+
+
+/// The bit that shard numbers start at in document ids
+const SHARD_SHIFT: u64 = 48;
+
+
+/// Adds the shard a document was found in to its id
+pub fn shard_doc_id(shard: usize, doc_id: u64) -> u64 {
+    ((shard as u64) << SHARD_SHIFT) | doc_id
+}
+
+
+/// Splits a document id from an `IndexReader` into the shard and the id within that shard
+pub fn split_doc_id(doc_id: u64) -> (usize, u64) {
+    ((doc_id >> SHARD_SHIFT) as usize, doc_id & ((1 << SHARD_SHIFT) - 1))
+}
+
+
+/// Passes documents found in one shard on to a collector that's shared by all of them
+struct ShardCollector<'a, C: Collector + 'a> {
+    inner: &'a mut C,
+    shard: usize,
+}
+
+
+impl<'a, C: Collector> Collector for ShardCollector<'a, C> {
+    fn needs_score(&self) -> bool {
+        self.inner.needs_score()
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let doc_id = shard_doc_id(self.shard, doc.doc_id());
+
+        self.inner.collect(match doc.score() {
+            Some(score) => DocumentMatch::new_scored(doc_id, score),
+            None => DocumentMatch::new_unscored(doc_id),
+        });
+    }
+
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        self.inner.top_docs()
+    }
+
+    fn collect_skipped(&mut self, count: u64, max_score: Option<f32>) {
+        self.inner.collect_skipped(count, max_score);
+    }
+}
+
+
+/// Point-in-time readers for every shard of an index (see `Index::reader`)
+pub struct IndexReader<'a> {
+    shards: Vec<RocksDBReader<'a>>,
+
+    /// Limits which documents can be read (see `IndexReader::restrict`)
+    filter: Option<Query>,
+}
+
+
+impl<'a> IndexReader<'a> {
+    pub fn new(shards: Vec<RocksDBReader<'a>>) -> IndexReader<'a> {
+        IndexReader {
+            shards: shards,
+            filter: None,
+        }
+    }
+
+    /// Only lets the documents that match `filter` be read
+    ///
+    /// Searches, counts and term frequencies only see these documents, so nothing can be
+    /// learnt about the others through the reader. Used for filtered aliases and role queries.
+    pub fn restrict(mut self, filter: Query) -> IndexReader<'a> {
+        self.filter = Some(match self.filter.take() {
+            Some(previous) => Query::Conjunction { queries: vec![previous, filter] },
+            None => filter,
+        });
+        self
+    }
+
+    /// Limits a query to the documents the reader can read, for running it again on
+    /// another reader
+    pub fn restrict_query<'q>(&self, query: &'q Query) -> Cow<'q, Query> {
+        match self.filter {
+            Some(ref filter) => Cow::Owned(query.clone().filter(filter.clone())),
+            None => Cow::Borrowed(query),
+        }
+    }
+
+    pub fn shards(&self) -> &[RocksDBReader<'a>] {
+        &self.shards
+    }
+
+    /// Fields are added to every shard in the same order (see `Index::add_field`), so all
+    /// shards have the same schema as the first one
+    pub fn schema(&self) -> &Schema {
+        self.shards[0].schema()
+    }
+
+    /// Finds the reader of the shard that a document id belongs to
+    pub fn shard_for_doc(&self, doc_id: u64) -> &RocksDBReader<'a> {
+        &self.shards[split_doc_id(doc_id).0]
+    }
+
+    /// Narrows the document ids in the query down to the ones in the shard, and the
+    /// documents down to the ones the reader can read
+    fn shard_query<'q>(&self, query: &'q Query, shard: usize) -> Cow<'q, Query> {
+        let query = self.restrict_query(query);
+        if self.shards.len() == 1 {
+            return query;
+        }
+
+        Cow::Owned(query.map_document_ids(&|doc_id| {
+            let (doc_shard, doc_id) = split_doc_id(doc_id);
+            if doc_shard == shard { Some(doc_id) } else { None }
+        }))
+    }
+
+    pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), String> {
+        self.search_cancellable(collector, query, &SearchCancellation::new())?;
+        Ok(())
+    }
+
+    /// Searches each shard in turn with the same collector
+    ///
+    /// Returns false if the search was stopped early by the cancellation
+    pub fn search_cancellable<C: Collector>(&self, collector: &mut C, query: &Query, cancellation: &SearchCancellation) -> Result<bool, String> {
+        for (shard, reader) in self.shards.iter().enumerate() {
+            let mut shard_collector = ShardCollector {
+                inner: &mut *collector,
+                shard: shard,
+            };
+
+            if !reader.search_cancellable(&mut shard_collector, &self.shard_query(query, shard), cancellation)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Counts the documents that match a query in all shards (see `RocksDBReader::count`)
+    pub fn count(&self, query: &Query) -> Result<u64, String> {
+        let mut count = 0;
+
+        for (shard, reader) in self.shards.iter().enumerate() {
+            count += reader.count(&self.shard_query(query, shard))?;
+        }
+
+        Ok(count)
+    }
+
+    pub fn matching_documents(&self, query: &Query) -> Result<Vec<u64>, String> {
+        let mut doc_ids = Vec::new();
+
+        for (shard, reader) in self.shards.iter().enumerate() {
+            let matches = reader.matching_documents(&self.shard_query(query, shard))?;
+            doc_ids.extend(matches.into_iter().map(|doc_id| shard_doc_id(shard, doc_id)));
+        }
+
+        Ok(doc_ids)
+    }
+
+    /// Finds the nearest neighbours in each shard, keeping the best `k` of all of them
+    pub fn knn_search(&self, knn: &KnnSearch) -> Result<Vec<(u64, f32)>, String> {
+        let mut neighbours = Vec::new();
+
+        for (shard, reader) in self.shards.iter().enumerate() {
+            let shard_neighbours = reader.knn_search(knn)?;
+            neighbours.extend(shard_neighbours.into_iter().map(|(doc_id, score)| (shard_doc_id(shard, doc_id), score)));
+        }
+
+        neighbours.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        neighbours.truncate(knn.k);
+        Ok(neighbours)
+    }
+
+    /// Finds the keys of all documents, by their ids
+    pub fn document_keys(&self) -> FnvHashMap<u64, String> {
+        let mut keys = FnvHashMap::default();
+
+        for (shard, reader) in self.shards.iter().enumerate() {
+            keys.extend(reader.document_keys().into_iter().map(|(doc_id, key)| (shard_doc_id(shard, doc_id.as_u64()), key)));
+        }
+
+        keys
+    }
+
+    /// Counts the documents in every shard that have the term in the field
+    pub fn term_document_frequency(&self, field_id: FieldId, term: &Term) -> Result<u64, String> {
+        // The term dictionary counts every document, so the ones that can be read must be searched for
+        if self.filter.is_some() {
+            return self.count(&Query::term(field_id, term.clone()));
+        }
+
+        let mut frequency = 0;
+
+        for reader in self.shards.iter() {
+            frequency += reader.term_document_frequency(field_id, term)?;
+        }
+
+        Ok(frequency)
+    }
+
+    /// Finds the terms in the field that match the predicate, with the number of documents
+    /// they're in across all shards
+    pub fn find_field_terms<F: FnMut(&str) -> bool>(&self, field_id: FieldId, mut predicate: F) -> Result<Vec<(String, u64)>, String> {
+        let mut terms: FnvHashMap<String, u64> = FnvHashMap::default();
+
+        for reader in self.shards.iter() {
+            for (term, frequency) in reader.find_field_terms(field_id, &mut predicate)? {
+                *terms.entry(term).or_insert(0) += frequency;
+            }
+        }
+
+        // Count the documents that can be read again, leaving out terms that are only in
+        // documents that can't be
+        if self.filter.is_some() {
+            let mut visible_terms = Vec::new();
+            for (term, _) in terms {
+                let frequency = self.term_document_frequency(field_id, &Term::from_string(&term))?;
+                if frequency > 0 {
+                    visible_terms.push((term, frequency));
+                }
+            }
+
+            return Ok(visible_terms);
+        }
+
+        Ok(terms.into_iter().collect())
+    }
+
+    pub fn read_stored_field(&self, field_id: FieldId, doc_id: u64) -> Result<Option<FieldValue>, StoredFieldReadError> {
+        self.shard_for_doc(doc_id).read_stored_field(field_id, DocId::from_u64(doc_id))
+    }
+
+    pub fn explain(&self, query: &Query, doc_id: u64) -> Result<Option<Explanation>, String> {
+        let shard = split_doc_id(doc_id).0;
+        self.shards[shard].explain(&self.shard_query(query, shard), DocId::from_u64(doc_id))
+    }
+
+    /// Profiles the query against each shard
+    pub fn profile(&self, query: &Query, score: bool) -> Result<Vec<QueryProfile>, String> {
+        let mut profiles = Vec::new();
+
+        for (shard, reader) in self.shards.iter().enumerate() {
+            profiles.push(reader.profile(&self.shard_query(query, shard), score)?);
+        }
+
+        Ok(profiles)
+    }
+
+    /// Pins the view of every shard. See `RocksDBReader::pin_segments`
+    ///
+    /// `Index::pinned_reader` opens the same view again. The pins must be passed to
+    /// `Index::unpin_segments` when finished
+    pub fn pin_segments(&self) -> Vec<u64> {
+        self.shards.iter().map(|reader| reader.pin_segments()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use fnv::FnvHashMap;
+    use search::{Term, Token, Document};
+    use search::schema::{FieldType, FIELD_INDEXED};
+    use search::query::Query;
+    use search::backends::rocksdb::RocksDBStore;
+
+    use super::{IndexReader, shard_doc_id, split_doc_id};
+
+    #[test]
+    fn test_shard_doc_id() {
+        assert_eq!(shard_doc_id(0, 1234), 1234);
+        assert_eq!(split_doc_id(shard_doc_id(0, 1234)), (0, 1234));
+        assert_eq!(split_doc_id(shard_doc_id(3, 1234)), (3, 1234));
+        assert_eq!(split_doc_id(shard_doc_id(3, (1 << 48) - 1)), (3, (1 << 48) - 1));
+    }
+
+    #[test]
+    fn test_restrict() {
+        let _ = remove_dir_all("test_indices/test_index_reader_restrict");
+
+        let store = RocksDBStore::create("test_indices/test_index_reader_restrict").unwrap();
+        let tenant_field = store.add_field("tenant".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        let secret_field = store.add_field("secret".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+
+        for &(key, tenant, secret) in [("1", "acme", "acmeplan"), ("2", "globex", "globexmergerplan")].iter() {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(tenant_field, vec![Token { term: Term::from_string(tenant), position: 1 }].into());
+            indexed_fields.insert(secret_field, vec![Token { term: Term::from_string(secret), position: 1 }].into());
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+            }).unwrap();
+        }
+
+        let acme = Query::term(tenant_field, Term::from_string("acme"));
+        let index_reader = IndexReader::new(vec![store.reader()]).restrict(acme.clone());
+
+        // Global aggregations and significant_terms backgrounds only see what can be read
+        let acme_doc_ids = IndexReader::new(vec![store.reader()]).matching_documents(&acme).unwrap();
+        assert_eq!(index_reader.matching_documents(&Query::all()), Ok(acme_doc_ids));
+        assert_eq!(index_reader.count(&Query::all()), Ok(1));
+
+        // So do the term frequencies that suggesters use
+        assert_eq!(index_reader.term_document_frequency(secret_field, &Term::from_string("globexmergerplan")), Ok(0));
+        assert_eq!(index_reader.term_document_frequency(secret_field, &Term::from_string("acmeplan")), Ok(1));
+        assert_eq!(index_reader.find_field_terms(secret_field, |_| true), Ok(vec![("acmeplan".to_string(), 1)]));
+
+        // Queries restricted by the reader stay restricted when run on another one, as scrolls are
+        let query = index_reader.restrict_query(&Query::all()).into_owned();
+        assert_eq!(IndexReader::new(vec![store.reader()]).count(&query), Ok(1));
+    }
+}
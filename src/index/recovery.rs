@@ -0,0 +1,291 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+
+use search::backends::rocksdb::StoreOpenStage;
+
+
+/// Where an index's data is being recovered from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoverySource {
+    /// A new index, there's nothing to recover
+    EmptyStore,
+
+    /// Data that's already on disk, eg after a restart
+    ExistingStore,
+}
+
+
+impl RecoverySource {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RecoverySource::EmptyStore => "EMPTY_STORE",
+            RecoverySource::ExistingStore => "EXISTING_STORE",
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryStage {
+    /// Waiting to be recovered
+    Init,
+
+    /// Replaying the store's write-ahead log
+    Translog,
+
+    /// Loading segments, the term dictionary and the document index
+    Index,
+
+    /// Publishing changes that were waiting for a refresh
+    Finalize,
+
+    /// The index is searchable
+    Done,
+
+    /// The index couldn't be loaded
+    Failed,
+}
+
+
+impl RecoveryStage {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RecoveryStage::Init => "INIT",
+            RecoveryStage::Translog => "TRANSLOG",
+            RecoveryStage::Index => "INDEX",
+            RecoveryStage::Finalize => "FINALIZE",
+            RecoveryStage::Done => "DONE",
+            RecoveryStage::Failed => "FAILED",
+        }
+    }
+
+    fn from_store_open_stage(stage: StoreOpenStage) -> RecoveryStage {
+        match stage {
+            StoreOpenStage::ReplayingLog => RecoveryStage::Translog,
+            StoreOpenStage::LoadingSegments |
+            StoreOpenStage::LoadingTermDictionary |
+            StoreOpenStage::LoadingDocumentIndex => RecoveryStage::Index,
+            StoreOpenStage::PublishingPendingChanges => RecoveryStage::Finalize,
+        }
+    }
+}
+
+
+#[derive(Debug)]
+struct RecoveryState {
+    stage: RecoveryStage,
+
+    /// The step of opening the store that's in progress
+    store_stage: Option<StoreOpenStage>,
+
+    started: Instant,
+    stage_started: Instant,
+
+    /// How long each finished stage took
+    stage_times: Vec<(RecoveryStage, Duration)>,
+
+    stop_time: Option<DateTime<Utc>>,
+    total_time: Option<Duration>,
+    error: Option<String>,
+}
+
+
+/// Tracks the progress of loading an index's data so it can be reported by the `_recovery` API
+#[derive(Debug)]
+pub struct IndexRecovery {
+    source: RecoverySource,
+    start_time: DateTime<Utc>,
+    state: Mutex<RecoveryState>,
+}
+
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000
+}
+
+
+impl IndexRecovery {
+    pub fn new(source: RecoverySource) -> IndexRecovery {
+        let now = Instant::now();
+
+        IndexRecovery {
+            source: source,
+            start_time: Utc::now(),
+            state: Mutex::new(RecoveryState {
+                stage: RecoveryStage::Init,
+                store_stage: None,
+                started: now,
+                stage_started: now,
+                stage_times: Vec::new(),
+                stop_time: None,
+                total_time: None,
+                error: None,
+            }),
+        }
+    }
+
+    /// Creates a recovery for a new index, which is finished straight away
+    pub fn empty_store() -> IndexRecovery {
+        let recovery = IndexRecovery::new(RecoverySource::EmptyStore);
+        recovery.finish();
+        recovery
+    }
+
+    fn set_stage(state: &mut RecoveryState, stage: RecoveryStage) {
+        if state.stage == stage {
+            return;
+        }
+
+        let now = Instant::now();
+        if state.stage != RecoveryStage::Init {
+            let elapsed = now.duration_since(state.stage_started);
+            state.stage_times.push((state.stage, elapsed));
+        }
+
+        state.stage = stage;
+        state.stage_started = now;
+
+        if stage == RecoveryStage::Done || stage == RecoveryStage::Failed {
+            state.store_stage = None;
+            state.stop_time = Some(Utc::now());
+            state.total_time = Some(now.duration_since(state.started));
+        }
+    }
+
+    /// Records progress reported while opening the store
+    ///
+    /// Pass this to `RocksDBStore::open_with_progress`
+    pub fn store_progress(&self, store_stage: StoreOpenStage) {
+        let mut state = self.state.lock().unwrap();
+        IndexRecovery::set_stage(&mut state, RecoveryStage::from_store_open_stage(store_stage));
+        state.store_stage = Some(store_stage);
+    }
+
+    /// Marks the index as searchable
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        IndexRecovery::set_stage(&mut state, RecoveryStage::Done);
+    }
+
+    /// Marks the recovery as failed
+    pub fn fail(&self, error: String) {
+        let mut state = self.state.lock().unwrap();
+        IndexRecovery::set_stage(&mut state, RecoveryStage::Failed);
+        state.error = Some(error);
+    }
+
+    pub fn source(&self) -> RecoverySource {
+        self.source
+    }
+
+    pub fn stage(&self) -> RecoveryStage {
+        self.state.lock().unwrap().stage
+    }
+
+    /// Returns true if the recovery is over, whether or not it succeeded
+    pub fn is_finished(&self) -> bool {
+        match self.stage() {
+            RecoveryStage::Done | RecoveryStage::Failed => true,
+            _ => false,
+        }
+    }
+}
+
+
+impl Serialize for IndexRecovery {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let state = self.state.lock().unwrap();
+
+        let stage_time = |stage: RecoveryStage| -> u64 {
+            if state.stage == stage {
+                duration_millis(state.stage_started.elapsed())
+            } else {
+                state.stage_times.iter()
+                    .filter(|&&(s, _)| s == stage)
+                    .map(|&(_, duration)| duration_millis(duration))
+                    .sum()
+            }
+        };
+
+        let total_time = state.total_time.unwrap_or_else(|| state.started.elapsed());
+
+        let mut json = json!({
+            "id": 0,
+            "type": self.source.name(),
+            "stage": state.stage.name(),
+            "primary": true,
+            "start_time_in_millis": self.start_time.timestamp() * 1000 + self.start_time.timestamp_subsec_millis() as i64,
+            "total_time_in_millis": duration_millis(total_time),
+            "translog": {
+                "total_time_in_millis": stage_time(RecoveryStage::Translog),
+            },
+            "index": {
+                "total_time_in_millis": stage_time(RecoveryStage::Index),
+            },
+            "finalize": {
+                "total_time_in_millis": stage_time(RecoveryStage::Finalize),
+            },
+        });
+
+        if let Some(stop_time) = state.stop_time {
+            json["stop_time_in_millis"] = json!(stop_time.timestamp() * 1000 + stop_time.timestamp_subsec_millis() as i64);
+        }
+
+        if let Some(store_stage) = state.store_stage {
+            json["index"]["step"] = json!(format!("{:?}", store_stage));
+        }
+
+        if let Some(ref error) = state.error {
+            json["error"] = json!(error);
+        }
+
+        json.serialize(serializer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::backends::rocksdb::StoreOpenStage;
+
+    use super::{IndexRecovery, RecoverySource, RecoveryStage};
+
+    #[test]
+    fn test_stages() {
+        let recovery = IndexRecovery::new(RecoverySource::ExistingStore);
+        assert_eq!(recovery.stage(), RecoveryStage::Init);
+
+        recovery.store_progress(StoreOpenStage::ReplayingLog);
+        assert_eq!(recovery.stage(), RecoveryStage::Translog);
+
+        recovery.store_progress(StoreOpenStage::LoadingSegments);
+        recovery.store_progress(StoreOpenStage::LoadingDocumentIndex);
+        assert_eq!(recovery.stage(), RecoveryStage::Index);
+        assert!(!recovery.is_finished());
+
+        recovery.finish();
+        assert_eq!(recovery.stage(), RecoveryStage::Done);
+        assert!(recovery.is_finished());
+
+        let json = ::serde_json::to_value(&recovery).unwrap();
+        assert_eq!(json["type"], "EXISTING_STORE");
+        assert_eq!(json["stage"], "DONE");
+        assert!(json.get("stop_time_in_millis").is_some());
+    }
+
+    #[test]
+    fn test_failed() {
+        let recovery = IndexRecovery::new(RecoverySource::ExistingStore);
+        recovery.store_progress(StoreOpenStage::ReplayingLog);
+        recovery.fail("corrupted".to_string());
+
+        assert_eq!(recovery.stage(), RecoveryStage::Failed);
+        assert!(recovery.is_finished());
+
+        let json = ::serde_json::to_value(&recovery).unwrap();
+        assert_eq!(json["error"], "corrupted");
+    }
+}
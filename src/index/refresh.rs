@@ -0,0 +1,96 @@
+use std::time::{Duration, Instant};
+
+use index::Index;
+
+
+/// What to do after a write to make it visible to searches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshPolicy {
+    /// Leave it to the next scheduled refresh
+    None,
+
+    /// Refresh the index straight away
+    Immediate,
+
+    /// Wait until the next scheduled refresh has happened
+    WaitFor,
+}
+
+
+impl RefreshPolicy {
+    /// Parses the value of a "refresh" URL parameter
+    pub fn from_param(value: &str) -> Option<RefreshPolicy> {
+        match value {
+            "false" => Some(RefreshPolicy::None),
+            "" | "true" => Some(RefreshPolicy::Immediate),
+            "wait_for" => Some(RefreshPolicy::WaitFor),
+            _ => None,
+        }
+    }
+}
+
+
+impl Index {
+    /// Makes all changes since the last refresh searchable
+    pub fn refresh(&self) -> Result<(), String> {
+        for store in self.shards.iter() {
+            store.refresh()?;
+        }
+
+        *self.last_refresh.lock().unwrap() = Instant::now();
+        self.refreshed.notify_all();
+
+        Ok(())
+    }
+
+    /// Refreshes the index if the refresh interval has passed since the last refresh
+    ///
+    /// This is called periodically by a background thread
+    pub fn refresh_if_due(&self) -> Result<(), String> {
+        let refresh_interval = match self.metadata.read().unwrap().settings.refresh_interval {
+            Some(refresh_interval) => refresh_interval,
+            None => return Ok(()),
+        };
+
+        if self.last_refresh.lock().unwrap().elapsed() >= refresh_interval {
+            self.refresh()?;
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until a refresh has happened
+    ///
+    /// If the index doesn't refresh on its own, or the scheduled refresh doesn't happen in
+    /// time, the index is refreshed immediately instead.
+    pub fn wait_for_refresh(&self) -> Result<(), String> {
+        let refresh_interval = match self.metadata.read().unwrap().settings.refresh_interval {
+            Some(refresh_interval) => refresh_interval,
+            None => return self.refresh(),
+        };
+
+        let started = Instant::now();
+        let timeout = refresh_interval * 2 + Duration::from_millis(100);
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+
+        while *last_refresh < started {
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                drop(last_refresh);
+                return self.refresh();
+            }
+
+            last_refresh = self.refreshed.wait_timeout(last_refresh, timeout - elapsed).unwrap().0;
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_refresh_policy(&self, policy: RefreshPolicy) -> Result<(), String> {
+        match policy {
+            RefreshPolicy::None => Ok(()),
+            RefreshPolicy::Immediate => self.refresh(),
+            RefreshPolicy::WaitFor => self.wait_for_refresh(),
+        }
+    }
+}
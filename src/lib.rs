@@ -0,0 +1,80 @@
+//! A lightweight search engine that speaks the Elasticsearch API
+//!
+//! The `rusticsearch` binary runs it as a server. It can also be embedded in another program
+//! through `Engine`, which stores indices in a data directory and searches them in-process,
+//! without starting the HTTP API or joining a cluster.
+
+extern crate chrono;
+extern crate hyper;
+extern crate tokio;
+extern crate futures;
+extern crate url;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate maplit;
+extern crate unicode_segmentation;
+extern crate uuid;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate atomicwrites;
+extern crate fnv;
+#[macro_use]
+extern crate bitflags;
+extern crate roaring;
+extern crate byteorder;
+extern crate rocksdb;
+extern crate libc;
+extern crate toml;
+extern crate base64;
+
+pub mod search;
+pub mod analysis;
+pub mod query_parser;
+pub mod mapping;
+pub mod document;
+pub mod index;
+pub mod cluster;
+pub mod system;
+pub mod settings;
+pub mod shutdown;
+pub mod security;
+pub mod dir_lock;
+pub mod disk_usage;
+pub mod process_stats;
+pub mod scroll;
+pub mod tasks;
+pub mod highlight;
+pub mod suggest;
+pub mod fetch;
+pub mod response_format;
+pub mod aggregations;
+pub mod source_filter;
+pub mod update;
+pub mod reindex;
+pub mod template;
+pub mod stored_scripts;
+pub mod rank_eval;
+pub mod lifecycle;
+pub mod term_vectors;
+pub mod slowlog;
+pub mod request_breaker;
+pub mod thread_pool;
+pub mod replication;
+pub mod plugins;
+pub mod gateway;
+pub mod audit;
+pub mod protobuf;
+pub mod grpc;
+pub mod api;
+pub mod background;
+pub mod engine;
+pub mod import;
+
+pub use engine::{Engine, EngineError, SearchResults, SearchHit};
+
+
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,448 @@
+//! Index lifecycle management
+//!
+//! A lifecycle policy moves an index through up to four phases ("hot", "warm", "cold" and
+//! "delete") as it gets older, running each phase's actions once the index reaches the
+//! phase's `min_age`. Indices opt in with the `index.lifecycle.name` setting, and the
+//! policies are run in the background by `run_lifecycle_policies`.
+//!
+//! An index's age is measured from its `index.lifecycle.origination_date`, which is set when
+//! the index is rolled over, or from its creation date otherwise. An index whose hot phase
+//! has a rollover action stays in the hot phase until it has been rolled over.
+
+pub mod registry;
+pub mod runner;
+
+use std::time::Duration;
+
+use serde_json::Value as Json;
+
+
+/// The phases an index goes through, in order
+pub const PHASES: &'static [&'static str] = &["hot", "warm", "cold", "delete"];
+
+
+/// Conditions that trigger a rollover. It's triggered when any one of them is met
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RolloverConditions {
+    pub max_age: Option<Duration>,
+    pub max_docs: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+
+impl RolloverConditions {
+    pub fn is_met(&self, age: Duration, num_docs: u64, size_in_bytes: u64) -> bool {
+        self.max_age.map_or(false, |max_age| age >= max_age)
+            || self.max_docs.map_or(false, |max_docs| num_docs >= max_docs)
+            || self.max_size.map_or(false, |max_size| size_in_bytes >= max_size)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LifecycleAction {
+    /// Creates a new index and points the index's rollover alias at it
+    Rollover(RolloverConditions),
+
+    /// Blocks writes to the index
+    ReadOnly,
+
+    /// Merges the index's segments down to at most this many
+    ForceMerge { max_num_segments: usize },
+
+    /// Deletes the index
+    Delete,
+}
+
+
+impl LifecycleAction {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LifecycleAction::Rollover(_) => "rollover",
+            LifecycleAction::ReadOnly => "readonly",
+            LifecycleAction::ForceMerge { .. } => "forcemerge",
+            LifecycleAction::Delete => "delete",
+        }
+    }
+
+    fn allowed_in_phase(&self, phase: &str) -> bool {
+        match *self {
+            LifecycleAction::Rollover(_) => phase == "hot",
+            LifecycleAction::ReadOnly | LifecycleAction::ForceMerge { .. } => phase == "hot" || phase == "warm" || phase == "cold",
+            LifecycleAction::Delete => phase == "delete",
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        match *self {
+            LifecycleAction::Rollover(ref conditions) => {
+                let mut json = json!({});
+
+                if let Some(max_age) = conditions.max_age {
+                    json["max_age"] = json!(format_time_value(max_age));
+                }
+
+                if let Some(max_docs) = conditions.max_docs {
+                    json["max_docs"] = json!(max_docs);
+                }
+
+                if let Some(max_size) = conditions.max_size {
+                    json["max_size"] = json!(format_byte_size(max_size));
+                }
+
+                json
+            }
+            LifecycleAction::ForceMerge { max_num_segments } => json!({"max_num_segments": max_num_segments}),
+            LifecycleAction::ReadOnly | LifecycleAction::Delete => json!({}),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifecyclePhase {
+    pub name: String,
+
+    /// How old an index must be before it enters the phase
+    pub min_age: Duration,
+
+    /// Run in order. Rollover always comes first and delete always comes last
+    pub actions: Vec<LifecycleAction>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifecyclePolicy {
+    /// The phases that the policy defines, in the order of `PHASES`
+    pub phases: Vec<LifecyclePhase>,
+}
+
+
+/// Parses a time value such as "30d" or "12h". Numbers without a unit are milliseconds
+pub fn parse_time_value(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let (number, millis_per_unit) = if value.ends_with("ms") {
+        (&value[..value.len() - 2], 1)
+    } else if value.ends_with('s') {
+        (&value[..value.len() - 1], 1000)
+    } else if value.ends_with('m') {
+        (&value[..value.len() - 1], 60 * 1000)
+    } else if value.ends_with('h') {
+        (&value[..value.len() - 1], 60 * 60 * 1000)
+    } else if value.ends_with('d') {
+        (&value[..value.len() - 1], 24 * 60 * 60 * 1000)
+    } else {
+        (value, 1)
+    };
+
+    number.parse::<u64>().ok().map(|number| Duration::from_millis(number * millis_per_unit))
+}
+
+
+/// Formats a duration with the largest unit that it's a whole number of
+pub fn format_time_value(duration: Duration) -> String {
+    let millis = duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000;
+    let units = [("d", 24 * 60 * 60 * 1000), ("h", 60 * 60 * 1000), ("m", 60 * 1000), ("s", 1000)];
+
+    for &(unit, millis_per_unit) in units.iter() {
+        if millis > 0 && millis % millis_per_unit == 0 {
+            return format!("{}{}", millis / millis_per_unit, unit);
+        }
+    }
+
+    format!("{}ms", millis)
+}
+
+
+const BYTE_UNITS: &'static [(&'static str, u64)] = &[
+    ("tb", 1 << 40),
+    ("gb", 1 << 30),
+    ("mb", 1 << 20),
+    ("kb", 1 << 10),
+    ("b", 1),
+];
+
+
+/// Parses a byte size such as "50gb". Numbers without a unit are bytes
+pub fn parse_byte_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_lowercase();
+
+    for &(unit, bytes_per_unit) in BYTE_UNITS.iter() {
+        if value.ends_with(unit) {
+            return value[..value.len() - unit.len()].trim().parse::<u64>().ok().map(|number| number * bytes_per_unit);
+        }
+    }
+
+    value.parse::<u64>().ok()
+}
+
+
+fn format_byte_size(bytes: u64) -> String {
+    for &(unit, bytes_per_unit) in BYTE_UNITS.iter() {
+        if bytes > 0 && bytes % bytes_per_unit == 0 {
+            return format!("{}{}", bytes / bytes_per_unit, unit);
+        }
+    }
+
+    format!("{}b", bytes)
+}
+
+
+fn parse_action(phase: &str, name: &str, options: &Json) -> Result<LifecycleAction, String> {
+    if !options.is_object() {
+        return Err(format!("{} must be an object", name));
+    }
+
+    let action = match name {
+        "rollover" => {
+            let mut conditions = RolloverConditions::default();
+
+            for (key, value) in options.as_object().unwrap().iter() {
+                match key.as_ref() {
+                    "max_age" => conditions.max_age = Some(value.as_str().and_then(parse_time_value).ok_or_else(|| format!("Invalid rollover max_age: {}", value))?),
+                    "max_docs" => conditions.max_docs = Some(value.as_u64().ok_or_else(|| format!("Invalid rollover max_docs: {}", value))?),
+                    "max_size" => conditions.max_size = Some(value.as_str().and_then(parse_byte_size).ok_or_else(|| format!("Invalid rollover max_size: {}", value))?),
+                    _ => return Err(format!("Unrecognised rollover condition {:?}", key)),
+                }
+            }
+
+            if conditions == RolloverConditions::default() {
+                return Err("rollover must have at least one condition".to_string());
+            }
+
+            LifecycleAction::Rollover(conditions)
+        }
+        "readonly" => LifecycleAction::ReadOnly,
+        "forcemerge" => {
+            match options.get("max_num_segments").and_then(|value| value.as_u64()) {
+                Some(max_num_segments) if max_num_segments > 0 => LifecycleAction::ForceMerge { max_num_segments: max_num_segments as usize },
+                _ => return Err("forcemerge must have a positive max_num_segments".to_string()),
+            }
+        }
+        "delete" => LifecycleAction::Delete,
+        _ => return Err(format!("Unrecognised action {:?}", name)),
+    };
+
+    if !action.allowed_in_phase(phase) {
+        return Err(format!("The {} action can't be used in the {} phase", name, phase));
+    }
+
+    Ok(action)
+}
+
+
+/// The order actions run in within a phase
+fn action_order(action: &LifecycleAction) -> usize {
+    match *action {
+        LifecycleAction::Rollover(_) => 0,
+        LifecycleAction::ReadOnly => 1,
+        LifecycleAction::ForceMerge { .. } => 2,
+        LifecycleAction::Delete => 3,
+    }
+}
+
+
+impl LifecyclePolicy {
+    /// Parses a policy, which is an object with a "phases" key
+    pub fn from_json(json: &Json) -> Result<LifecyclePolicy, String> {
+        let phases_json = match json.get("phases").and_then(|phases| phases.as_object()) {
+            Some(phases_json) => phases_json,
+            None => return Err("policy must have an object of phases".to_string()),
+        };
+
+        if let Some(name) = phases_json.keys().find(|name| !PHASES.contains(&name.as_ref())) {
+            return Err(format!("Unrecognised phase {:?}", name));
+        }
+
+        let mut phases: Vec<LifecyclePhase> = Vec::new();
+        for &phase_name in PHASES.iter() {
+            let phase_json = match phases_json.get(phase_name) {
+                Some(phase_json) => phase_json,
+                None => continue,
+            };
+
+            let min_age = match phase_json.get("min_age") {
+                Some(min_age) => min_age.as_str().and_then(parse_time_value).ok_or_else(|| format!("Invalid min_age in the {} phase: {}", phase_name, min_age))?,
+                None => Duration::from_secs(0),
+            };
+
+            if let Some(previous) = phases.last() {
+                if min_age < previous.min_age {
+                    return Err(format!("The {} phase's min_age is less than the {} phase's", phase_name, previous.name));
+                }
+            }
+
+            let mut actions = Vec::new();
+            if let Some(actions_json) = phase_json.get("actions") {
+                let actions_json = actions_json.as_object().ok_or_else(|| format!("The {} phase's actions must be an object", phase_name))?;
+
+                for (action_name, options) in actions_json.iter() {
+                    actions.push(parse_action(phase_name, action_name, options)?);
+                }
+            }
+            actions.sort_by_key(action_order);
+
+            phases.push(LifecyclePhase {
+                name: phase_name.to_string(),
+                min_age: min_age,
+                actions: actions,
+            });
+        }
+
+        Ok(LifecyclePolicy {
+            phases: phases,
+        })
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut phases_json = json!({});
+
+        for phase in self.phases.iter() {
+            let mut actions_json = json!({});
+            for action in phase.actions.iter() {
+                actions_json[action.name()] = action.to_json();
+            }
+
+            phases_json[&phase.name] = json!({
+                "min_age": format_time_value(phase.min_age),
+                "actions": actions_json,
+            });
+        }
+
+        json!({"phases": phases_json})
+    }
+
+    /// Finds the phase an index is in
+    ///
+    /// This is the last phase whose `min_age` the index has reached. If the hot phase has a
+    /// rollover action, indices that haven't been rolled over yet stay in it.
+    pub fn current_phase(&self, age: Duration, rolled_over: bool) -> Option<&LifecyclePhase> {
+        let mut current = None;
+
+        for phase in self.phases.iter() {
+            if phase.min_age > age {
+                break;
+            }
+
+            current = Some(phase);
+
+            let has_rollover = phase.actions.iter().any(|action| match *action {
+                LifecycleAction::Rollover(_) => true,
+                _ => false,
+            });
+
+            if has_rollover && !rolled_over {
+                break;
+            }
+        }
+
+        current
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LifecyclePolicy, LifecycleAction, RolloverConditions, parse_time_value, parse_byte_size};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn logs_policy() -> LifecyclePolicy {
+        LifecyclePolicy::from_json(&json!({
+            "phases": {
+                "hot": {
+                    "actions": {
+                        "rollover": {"max_age": "1d", "max_size": "50gb"}
+                    }
+                },
+                "warm": {
+                    "min_age": "7d",
+                    "actions": {
+                        "forcemerge": {"max_num_segments": 1},
+                        "readonly": {}
+                    }
+                },
+                "delete": {
+                    "min_age": "30d",
+                    "actions": {
+                        "delete": {}
+                    }
+                }
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_from_json() {
+        let policy = logs_policy();
+        let names = policy.phases.iter().map(|phase| phase.name.as_ref()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["hot", "warm", "delete"]);
+
+        assert_eq!(policy.phases[0].actions, vec![LifecycleAction::Rollover(RolloverConditions {
+            max_age: Some(Duration::from_secs(DAY)),
+            max_docs: None,
+            max_size: Some(50 << 30),
+        })]);
+
+        // Actions are sorted into the order they run in
+        assert_eq!(policy.phases[1].min_age, Duration::from_secs(7 * DAY));
+        assert_eq!(policy.phases[1].actions, vec![LifecycleAction::ReadOnly, LifecycleAction::ForceMerge { max_num_segments: 1 }]);
+
+        // Converting back to JSON gives the same policy
+        assert_eq!(LifecyclePolicy::from_json(&policy.to_json()), Ok(policy.clone()));
+        assert_eq!(policy.to_json()["phases"]["warm"]["min_age"], json!("7d"));
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        assert!(LifecyclePolicy::from_json(&json!({})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"lukewarm": {}}})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"warm": {"actions": {"rollover": {"max_docs": 10}}}}})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"hot": {"actions": {"rollover": {}}}}})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"hot": {"actions": {"delete": {}}}}})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"warm": {"min_age": "soon"}}})).is_err());
+        assert!(LifecyclePolicy::from_json(&json!({"phases": {"warm": {"min_age": "10d"}, "delete": {"min_age": "5d"}}})).is_err());
+    }
+
+    #[test]
+    fn test_current_phase() {
+        let policy = logs_policy();
+        let phase_name = |age: u64, rolled_over: bool| policy.current_phase(Duration::from_secs(age), rolled_over).map(|phase| phase.name.clone());
+
+        assert_eq!(phase_name(0, false), Some("hot".to_string()));
+        assert_eq!(phase_name(7 * DAY, true), Some("warm".to_string()));
+        assert_eq!(phase_name(40 * DAY, true), Some("delete".to_string()));
+
+        // Indices stay in the hot phase until they have been rolled over
+        assert_eq!(phase_name(40 * DAY, false), Some("hot".to_string()));
+
+        // Indices aren't in any phase until they reach the first phase's min_age
+        let policy = LifecyclePolicy::from_json(&json!({"phases": {"delete": {"min_age": "1h", "actions": {"delete": {}}}}})).unwrap();
+        assert_eq!(policy.current_phase(Duration::from_secs(60), false), None);
+        assert_eq!(policy.current_phase(Duration::from_secs(3600), false).map(|phase| phase.name.as_ref()), Some("delete"));
+    }
+
+    #[test]
+    fn test_rollover_conditions() {
+        let conditions = RolloverConditions { max_age: Some(Duration::from_secs(DAY)), max_docs: Some(1000), max_size: None };
+        assert!(!conditions.is_met(Duration::from_secs(60), 10, 1 << 40));
+        assert!(conditions.is_met(Duration::from_secs(DAY), 10, 0));
+        assert!(conditions.is_met(Duration::from_secs(60), 1000, 0));
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse_time_value("30d"), Some(Duration::from_secs(30 * DAY)));
+        assert_eq!(parse_time_value("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_time_value("0"), Some(Duration::from_secs(0)));
+        assert_eq!(parse_time_value("d"), None);
+
+        assert_eq!(parse_byte_size("50gb"), Some(50 << 30));
+        assert_eq!(parse_byte_size("10KB"), Some(10 << 10));
+        assert_eq!(parse_byte_size("123"), Some(123));
+        assert_eq!(parse_byte_size("lots"), None);
+    }
+}
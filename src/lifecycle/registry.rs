@@ -0,0 +1,186 @@
+//! Lifecycle policies that have been stored on the node
+//!
+//! Policies are kept in memory and written to "ilm_policies.json" in the data directory
+//! whenever they change.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json::{self, Value as Json};
+use atomicwrites::{AtomicFile, AllowOverwrite};
+
+use lifecycle::LifecyclePolicy;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredPolicy {
+    /// Starts at 1 and goes up by one each time the policy is replaced
+    pub version: u64,
+
+    /// Milliseconds since the epoch when the policy was last stored
+    pub modified_date: u64,
+
+    pub policy: LifecyclePolicy,
+}
+
+
+impl StoredPolicy {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "version": self.version,
+            "modified_date": self.modified_date,
+            "policy": self.policy.to_json(),
+        })
+    }
+
+    fn from_json(json: &Json) -> Result<StoredPolicy, String> {
+        Ok(StoredPolicy {
+            version: json.get("version").and_then(|version| version.as_u64()).unwrap_or(1),
+            modified_date: json.get("modified_date").and_then(|date| date.as_u64()).unwrap_or(0),
+            policy: LifecyclePolicy::from_json(json.get("policy").unwrap_or(&Json::Null))?,
+        })
+    }
+}
+
+
+#[derive(Debug)]
+pub struct LifecyclePolicyRegistry {
+    policies: RwLock<BTreeMap<String, StoredPolicy>>,
+}
+
+
+impl LifecyclePolicyRegistry {
+    pub fn new() -> LifecyclePolicyRegistry {
+        LifecyclePolicyRegistry {
+            policies: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Loads the policies from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load lifecycle policies: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load lifecycle policies: {}", e))?;
+
+        let json: BTreeMap<String, Json> = serde_json::from_str(&s).map_err(|e| format!("failed to load lifecycle policies: {}", e))?;
+        let mut policies = BTreeMap::new();
+        for (name, policy_json) in json {
+            let policy = StoredPolicy::from_json(&policy_json).map_err(|e| format!("failed to load lifecycle policy {:?}: {}", name, e))?;
+            policies.insert(name, policy);
+        }
+
+        *self.policies.write().unwrap() = policies;
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, policies: &BTreeMap<String, StoredPolicy>) -> Result<(), String> {
+        let json = policies.iter().map(|(name, policy)| (name.clone(), policy.to_json())).collect::<BTreeMap<_, _>>();
+        let s = serde_json::to_string(&json).map_err(|e| format!("failed to save lifecycle policies: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save lifecycle policies: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<StoredPolicy> {
+        self.policies.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns all of the policies, sorted by name
+    pub fn list(&self) -> Vec<(String, StoredPolicy)> {
+        self.policies.read().unwrap().iter().map(|(name, policy)| (name.clone(), policy.clone())).collect()
+    }
+
+    /// Adds or replaces a policy, saving all of the policies to the file
+    ///
+    /// Replacing a policy bumps its version. Returns the version that was stored.
+    pub fn insert<P: AsRef<Path>>(&self, path: P, name: String, policy: LifecyclePolicy, modified_date: u64) -> Result<u64, String> {
+        let mut policies = self.policies.write().unwrap();
+        let version = policies.get(&name).map_or(1, |previous| previous.version + 1);
+        let previous = policies.insert(name.clone(), StoredPolicy {
+            version: version,
+            modified_date: modified_date,
+            policy: policy,
+        });
+
+        if let Err(e) = self.save(path.as_ref(), &policies) {
+            // Keep what's in memory the same as what's on disk
+            match previous {
+                Some(previous) => policies.insert(name, previous),
+                None => policies.remove(&name),
+            };
+
+            return Err(e);
+        }
+
+        Ok(version)
+    }
+
+    /// Removes a policy, saving the rest to the file. Returns false if there wasn't a policy
+    /// with the name
+    pub fn remove<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<bool, String> {
+        let mut policies = self.policies.write().unwrap();
+        let previous = match policies.remove(name) {
+            Some(previous) => previous,
+            None => return Ok(false),
+        };
+
+        if let Err(e) = self.save(path.as_ref(), &policies) {
+            policies.insert(name.to_string(), previous);
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lifecycle::LifecyclePolicy;
+
+    use super::LifecyclePolicyRegistry;
+
+    #[test]
+    fn test_save_and_load() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_ilm_policies.json";
+        let _ = fs::remove_file(path);
+
+        let policy = LifecyclePolicy::from_json(&json!({
+            "phases": {
+                "delete": {"min_age": "30d", "actions": {"delete": {}}}
+            }
+        })).unwrap();
+
+        let policies = LifecyclePolicyRegistry::new();
+        policies.load(path).unwrap();
+        assert_eq!(policies.get("logs"), None);
+
+        assert_eq!(policies.insert(path, "logs".to_string(), policy.clone(), 1000), Ok(1));
+        assert_eq!(policies.insert(path, "logs".to_string(), policy.clone(), 2000), Ok(2));
+        policies.insert(path, "other".to_string(), policy.clone(), 1000).unwrap();
+        assert_eq!(policies.remove(path, "other"), Ok(true));
+        assert_eq!(policies.remove(path, "other"), Ok(false));
+
+        let loaded = LifecyclePolicyRegistry::new();
+        loaded.load(path).unwrap();
+        let stored = loaded.get("logs").unwrap();
+        assert_eq!(stored.version, 2);
+        assert_eq!(stored.modified_date, 2000);
+        assert_eq!(stored.policy, policy);
+        assert_eq!(loaded.list().len(), 1);
+    }
+}
@@ -0,0 +1,376 @@
+//! Runs the lifecycle policies of indices in the background
+//!
+//! Each run looks at every open index that has a policy, works out which of its phase's
+//! actions are due, then carries them out. Actions are written so they can safely be run
+//! again (a merged index isn't merged twice, an index that's already read-only is left
+//! alone), so the state of each index is worked out from its settings every time rather
+//! than being tracked separately.
+
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{self, Value as Json};
+
+use search::cancellation::SearchCancellation;
+use index::Index;
+use index::metadata::IndexMetadata;
+use index::metadata::alias::AliasMetadata;
+use index::metadata::parse::parse as parse_index_metadata;
+use cluster::metadata::{ClusterMetadata, IndexRef};
+use system::System;
+use lifecycle::{LifecyclePolicy, LifecycleAction};
+
+
+/// Something a policy wants done to an index
+#[derive(Debug, Clone, PartialEq)]
+pub enum LifecycleStep {
+    Rollover { alias: String },
+    ReadOnly,
+    ForceMerge { max_num_segments: usize },
+    Delete,
+}
+
+
+impl LifecycleStep {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LifecycleStep::Rollover { .. } => "rollover",
+            LifecycleStep::ReadOnly => "readonly",
+            LifecycleStep::ForceMerge { .. } => "forcemerge",
+            LifecycleStep::Delete => "delete",
+        }
+    }
+}
+
+
+/// Where an index is in its policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifecyclePlan {
+    /// When the index's age is measured from, in milliseconds since the epoch
+    pub lifecycle_date: u64,
+
+    pub age: Duration,
+
+    /// None if the index hasn't reached the first phase yet
+    pub phase: Option<String>,
+
+    /// The steps that are due, in the order they're run
+    pub steps: Vec<LifecycleStep>,
+}
+
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0)
+}
+
+
+/// Works out the name of the index that an index is rolled over to by incrementing the
+/// number at the end of its name, eg "logs-000001" becomes "logs-000002"
+pub fn next_index_name(index_name: &str) -> Result<String, String> {
+    let number_start = index_name.rfind('-').map(|position| position + 1).unwrap_or(0);
+    let number = &index_name[number_start..];
+
+    match number.parse::<u64>() {
+        Ok(number_value) if number_start > 0 => Ok(format!("{}{:02$}", &index_name[..number_start], number_value + 1, number.len())),
+        _ => Err(format!("index name {:?} doesn't end with a dash and a number, so it can't be rolled over", index_name)),
+    }
+}
+
+
+/// Works out which steps of an index's policy are due
+pub fn plan(index: &Index, policy: &LifecyclePolicy, now: u64) -> Result<LifecyclePlan, String> {
+    let index_metadata = index.metadata.read().unwrap();
+    let settings = &index_metadata.settings;
+
+    // Indices created before creation dates were recorded start aging now
+    let lifecycle_date = settings.lifecycle.origination_date.or(settings.creation_date).unwrap_or(now);
+    let age = Duration::from_millis(now.saturating_sub(lifecycle_date));
+
+    let phase = match policy.current_phase(age, settings.lifecycle.indexing_complete) {
+        Some(phase) => phase,
+        None => {
+            return Ok(LifecyclePlan {
+                lifecycle_date: lifecycle_date,
+                age: age,
+                phase: None,
+                steps: Vec::new(),
+            });
+        }
+    };
+
+    let mut steps = Vec::new();
+    for action in phase.actions.iter() {
+        match *action {
+            LifecycleAction::Rollover(ref conditions) => {
+                if settings.lifecycle.indexing_complete {
+                    continue;
+                }
+
+                let alias = match settings.lifecycle.rollover_alias {
+                    Some(ref alias) => alias.clone(),
+                    None => return Err("index.lifecycle.rollover_alias must be set to roll the index over".to_string()),
+                };
+
+                let shard_stats = index.get_store_statistics()?;
+                let num_docs: i64 = shard_stats.iter().flat_map(|stats| stats.segments.iter()).map(|&(_, ref s)| s.total_docs() - s.deleted_docs()).sum();
+                let size_in_bytes: u64 = shard_stats.iter().flat_map(|stats| stats.segment_sizes.values()).sum();
+
+                if conditions.is_met(age, num_docs as u64, size_in_bytes) {
+                    steps.push(LifecycleStep::Rollover { alias: alias });
+                }
+
+                // The rest of the phase waits until the index has been rolled over
+                break;
+            }
+            LifecycleAction::ReadOnly => {
+                if !settings.blocks.write {
+                    steps.push(LifecycleStep::ReadOnly);
+                }
+            }
+            LifecycleAction::ForceMerge { max_num_segments } => {
+                // Each shard is merged down to the limit on its own
+                let mut needs_merge = false;
+                for store in index.shards() {
+                    needs_merge |= store.get_segment_statistics()?.len() > max_num_segments;
+                }
+
+                if needs_merge {
+                    steps.push(LifecycleStep::ForceMerge { max_num_segments: max_num_segments });
+                }
+            }
+            LifecycleAction::Delete => steps.push(LifecycleStep::Delete),
+        }
+    }
+
+    Ok(LifecyclePlan {
+        lifecycle_date: lifecycle_date,
+        age: age,
+        phase: Some(phase.name.clone()),
+        steps: steps,
+    })
+}
+
+
+/// Rolls an index over to a new one
+///
+/// The new index copies the old one's settings and mappings, and takes over as the write
+/// index of the rollover alias. The old index is marked as complete so its policy can move
+/// on to the next phase.
+fn rollover(system: &System, cluster_metadata: &mut ClusterMetadata, index_ref: IndexRef, alias_name: &str, now: u64) -> Result<String, String> {
+    if cluster_metadata.resolve_write_index(alias_name) != Ok(index_ref) {
+        return Err(format!("the index isn't the write index of its rollover alias {:?}", alias_name));
+    }
+
+    let (new_index_name, mut new_metadata, old_alias) = {
+        let index = &cluster_metadata.indices[&index_ref];
+        let new_index_name = next_index_name(index.canonical_name())?;
+
+        if cluster_metadata.names.find_canonical(&new_index_name).is_some() || cluster_metadata.names.is_alias(&new_index_name) {
+            return Err(format!("can't roll over to {:?} as it already exists", new_index_name));
+        }
+
+        let index_metadata = index.metadata.read().unwrap();
+        let old_alias = index_metadata.aliases.get(alias_name).cloned().unwrap_or_default();
+
+        // Copy the metadata by saving and loading it, without the aliases
+        let mut json = serde_json::to_value(&*index_metadata).map_err(|e| format!("failed to copy index metadata: {}", e))?;
+        json["aliases"] = json!({});
+        let mut new_metadata = IndexMetadata::default();
+        parse_index_metadata(&mut new_metadata, json).map_err(|e| format!("failed to copy index metadata: {:?}", e))?;
+
+        (new_index_name, new_metadata, old_alias)
+    };
+
+    new_metadata.settings.creation_date = None;
+    new_metadata.settings.blocks = Default::default();
+    new_metadata.settings.lifecycle.indexing_complete = false;
+    new_metadata.settings.lifecycle.origination_date = None;
+
+    // If the alias names a write index, the old index stays in the alias for searches.
+    // Otherwise the alias is moved over to the new index
+    let move_alias = old_alias.is_write_index != Some(true);
+    if !move_alias {
+        new_metadata.aliases.insert(alias_name.to_string(), AliasMetadata { is_write_index: Some(true), ..old_alias });
+    } else {
+        new_metadata.aliases.insert(alias_name.to_string(), old_alias);
+    }
+
+    let mappings = mem::replace(&mut new_metadata.mappings, Default::default());
+    let new_index_ref = system.create_index(cluster_metadata, &new_index_name, new_metadata)?;
+
+    // The mappings' fields have to be added to the new store
+    {
+        let new_index = &cluster_metadata.indices[&new_index_ref];
+        let mut linked_mappings = mappings;
+        for mapping in linked_mappings.values_mut() {
+            new_index.add_mapping_fields(mapping)?;
+        }
+
+        let mut new_index_metadata = new_index.metadata.write().unwrap();
+        new_index_metadata.mappings = linked_mappings;
+        new_index_metadata.save(new_index.metadata_path()).map_err(String::from)?;
+    }
+
+    // Mark the old index as rolled over
+    {
+        let index = &cluster_metadata.indices[&index_ref];
+        let mut index_metadata = index.metadata.write().unwrap();
+
+        if move_alias {
+            index_metadata.aliases.remove(alias_name);
+        } else if let Some(alias) = index_metadata.aliases.get_mut(alias_name) {
+            alias.is_write_index = Some(false);
+        }
+
+        index_metadata.settings.lifecycle.indexing_complete = true;
+        if index_metadata.settings.lifecycle.origination_date.is_none() {
+            index_metadata.settings.lifecycle.origination_date = Some(now);
+        }
+
+        index_metadata.save(index.metadata_path()).map_err(String::from)?;
+    }
+
+    if move_alias {
+        cluster_metadata.names.delete_alias(alias_name, index_ref).unwrap();
+    }
+
+    Ok(new_index_name)
+}
+
+
+fn run_step(system: &System, index_name: &str, step: &LifecycleStep, now: u64) -> Result<(), String> {
+    match *step {
+        LifecycleStep::Rollover { ref alias } => {
+            let mut cluster_metadata = system.metadata.write().unwrap();
+            let index_ref = cluster_metadata.names.find_canonical(index_name).ok_or_else(|| "index no longer exists".to_string())?;
+            let new_index_name = rollover(system, &mut cluster_metadata, index_ref, alias, now)?;
+
+            info!(system.log, "rolled over index"; "index" => index_name, "new_index" => new_index_name, "alias" => alias.clone());
+        }
+        LifecycleStep::ReadOnly => {
+            let cluster_metadata = system.metadata.read().unwrap();
+            let index = cluster_metadata.names.find_canonical(index_name).and_then(|index_ref| cluster_metadata.indices.get(&index_ref)).ok_or_else(|| "index no longer exists".to_string())?;
+
+            let mut index_metadata = index.metadata.write().unwrap();
+            index_metadata.settings.blocks.write = true;
+            index.apply_settings(&index_metadata.settings);
+            index_metadata.save(index.metadata_path()).map_err(String::from)?;
+
+            info!(system.log, "made index read-only"; "index" => index_name);
+        }
+        LifecycleStep::ForceMerge { max_num_segments } => {
+            let cluster_metadata = system.metadata.read().unwrap();
+            let index = cluster_metadata.names.find_canonical(index_name).and_then(|index_ref| cluster_metadata.indices.get(&index_ref)).ok_or_else(|| "index no longer exists".to_string())?;
+
+            index.force_merge(max_num_segments, &SearchCancellation::new())?;
+            index.flush()?;
+
+            info!(system.log, "force merged index"; "index" => index_name, "max_num_segments" => max_num_segments);
+        }
+        LifecycleStep::Delete => {
+            let mut cluster_metadata = system.metadata.write().unwrap();
+            let index_ref = cluster_metadata.names.find_canonical(index_name).ok_or_else(|| "index no longer exists".to_string())?;
+            system.delete_index(&mut cluster_metadata, index_ref);
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Runs the lifecycle policy of every open index that has one
+///
+/// This must be called periodically by a background thread. Indices whose policy doesn't
+/// exist are skipped until it's created.
+pub fn run_lifecycle_policies(system: &System) {
+    let now = now_millis();
+
+    let mut due = Vec::new();
+    {
+        let cluster_metadata = system.metadata.read().unwrap();
+        for index in cluster_metadata.indices.values() {
+            let policy_name = match index.metadata.read().unwrap().settings.lifecycle.name {
+                Some(ref policy_name) => policy_name.clone(),
+                None => continue,
+            };
+
+            let policy = match system.lifecycle_policies.get(&policy_name) {
+                Some(policy) => policy.policy,
+                None => continue,
+            };
+
+            match plan(index, &policy, now) {
+                Ok(plan) => {
+                    if !plan.steps.is_empty() {
+                        due.push((index.canonical_name().to_string(), plan.steps));
+                    }
+                }
+                Err(e) => {
+                    warn!(system.log, "could not run lifecycle policy"; "index" => index.canonical_name(), "policy" => policy_name, "error" => e);
+                }
+            }
+        }
+    }
+
+    for (index_name, steps) in due {
+        for step in steps.iter() {
+            if let Err(e) = run_step(system, &index_name, step, now) {
+                error!(system.log, "lifecycle action failed"; "index" => index_name.clone(), "action" => step.name(), "error" => e);
+                break;
+            }
+        }
+    }
+}
+
+
+/// Describes where an index is in its lifecycle policy
+pub fn explain(system: &System, index: &Index) -> Json {
+    let policy_name = match index.metadata.read().unwrap().settings.lifecycle.name {
+        Some(ref policy_name) => policy_name.clone(),
+        None => return json!({"index": index.canonical_name(), "managed": false}),
+    };
+
+    let mut json = json!({
+        "index": index.canonical_name(),
+        "managed": true,
+        "policy": policy_name,
+    });
+
+    let policy = match system.lifecycle_policies.get(&policy_name) {
+        Some(policy) => policy.policy,
+        None => {
+            json["step_info"] = json!({"reason": format!("policy {:?} does not exist", policy_name)});
+            return json;
+        }
+    };
+
+    match plan(index, &policy, now_millis()) {
+        Ok(plan) => {
+            json["lifecycle_date_millis"] = json!(plan.lifecycle_date);
+            json["age_in_millis"] = json!(plan.age.as_secs() * 1000 + plan.age.subsec_nanos() as u64 / 1_000_000);
+            json["phase"] = json!(plan.phase);
+            json["pending_actions"] = json!(plan.steps.iter().map(|step| step.name()).collect::<Vec<_>>());
+        }
+        Err(e) => {
+            json["step_info"] = json!({"reason": e});
+        }
+    }
+
+    json
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::next_index_name;
+
+    #[test]
+    fn test_next_index_name() {
+        assert_eq!(next_index_name("logs-000001"), Ok("logs-000002".to_string()));
+        assert_eq!(next_index_name("logs-2019-000009"), Ok("logs-2019-000010".to_string()));
+        assert_eq!(next_index_name("logs-9"), Ok("logs-10".to_string()));
+        assert!(next_index_name("logs").is_err());
+        assert!(next_index_name("000001").is_err());
+        assert!(next_index_name("logs-").is_err());
+    }
+}
@@ -1,88 +1,129 @@
-extern crate chrono;
-#[macro_use]
-extern crate router;
-extern crate url;
+extern crate rusticsearch;
 #[macro_use]
 extern crate slog;
 extern crate slog_term;
 extern crate slog_async;
-#[macro_use]
-extern crate maplit;
-extern crate unicode_segmentation;
-extern crate uuid;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-#[macro_use]
-extern crate serde_json;
-extern crate atomicwrites;
-extern crate fnv;
-#[macro_use]
-extern crate bitflags;
-extern crate roaring;
-extern crate byteorder;
-extern crate rocksdb;
-
-pub mod search;
-pub mod analysis;
-pub mod query_parser;
-pub mod mapping;
-pub mod document;
-pub mod index;
-pub mod cluster;
-pub mod system;
-mod api;
-
-use std::path::Path;
+
+use std::env;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use std::panic;
+use std::process;
 
 use slog::Drain;
 
-use system::System;
-
-
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+use rusticsearch::{api, background, plugins, shutdown, VERSION};
+use rusticsearch::system::System;
+use rusticsearch::settings::{Settings, USAGE};
 
 
 fn main() {
+    let settings = match Settings::load(env::args().skip(1), |name| env::var(name).ok()) {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("rusticsearch: {}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
     // Setup logging
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let (drain, log_guard) = slog_async::Async::new(drain).build_with_guard();
+    let drain = drain.fuse();
+    let drain = slog::LevelFilter::new(drain, settings.log_level).fuse();
     let log = slog::Logger::root(drain, o!());
 
-    info!(log, "starting rusticsearch"; "version" => VERSION);
+    info!(log, "starting rusticsearch"; "version" => VERSION, "data_dir" => settings.data_dir.to_string_lossy().into_owned());
 
-    let system = Arc::new(System::new(log, Path::new("data/").to_path_buf()));
+    // Plugins can add tokenizers that index settings use, so they're loaded first
+    plugins::register_compiled_in(&log);
 
-    info!(system.log, "loading indices");
-    system.load_indices();
+    let mut system = match System::new(log.clone(), settings) {
+        Ok(system) => system,
+        Err(e) => {
+            crit!(log, "unable to start thread pools or cluster transport, or open audit log"; "error" => format!("{}", e));
+            drop(log);
+            drop(log_guard);
+            process::exit(1);
+        }
+    };
 
+    if let Err(e) = system.lock_data_dir() {
+        error!(system.log, "could not lock data directory"; "error" => String::from(e));
+
+        // Make sure the log message is written out before exiting
+        drop(system);
+        drop(log_guard);
+        process::exit(1);
+    }
+
+    system.load_stored_scripts();
+    system.load_lifecycle_policies();
+    system.load_api_keys();
+    system.load_roles();
+    system.load_cluster_settings();
+    system.load_disk_blocks();
+
+    if !system.settings.anonymous_access && system.settings.users.is_empty() && system.settings.api_keys.is_empty() {
+        warn!(system.log, "anonymous access is disabled and no users or api keys are configured, only api keys created before will be able to access the node");
+    }
+
+    let system = Arc::new(system);
+
+    // Load indices in the background so the API can report their recovery progress
     {
         let system = system.clone();
         thread::spawn(move || {
-            loop {
-                {
-                    let cluster_metadata = system.metadata.read().unwrap();
-                    for index in cluster_metadata.indices.values() {
-                        let result = panic::catch_unwind(|| {
-                            index.run_maintenance_task().unwrap();
-                        });
-
-                        if let Err(error) = result {
-                            error!(system.log, "index maintenance task panicked"; "index" => index.canonical_name(), "error" => format!("{:?}", error));
-                        }
-                    }
-                }
-
-                thread::sleep(Duration::new(1, 0));
-            }
+            info!(system.log, "loading indices");
+            system.load_indices();
         });
     }
 
+    background::start_maintenance(&system);
+    background::start_disk_monitor(&system);
+    background::start_refresh(&system);
+    background::start_coordinator(&system);
+    background::start_lifecycle(&system);
+
+    shutdown::install_signal_handlers();
+
     info!(system.log, "starting api server");
-    api::api_main(system);
+    let listener = match api::api_main(system.clone()) {
+        Some(listening) => listening,
+        None => {
+            drop(log_guard);
+            process::exit(1);
+        }
+    };
+
+    let grpc_listener = match system.settings.grpc_port {
+        Some(port) => {
+            info!(system.log, "starting grpc server");
+            match api::grpc_main(system.clone(), port) {
+                Some(listening) => Some(listening),
+                None => {
+                    drop(log_guard);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    shutdown::wait_for_shutdown();
+    info!(system.log, "shutting down");
+    system.shutdown();
+    listener.stop();
+    if let Some(grpc_listener) = grpc_listener {
+        grpc_listener.stop();
+    }
+    info!(system.log, "shut down");
+
+    // Write out the log messages before exiting
+    drop(log_guard);
+    process::exit(0);
 }
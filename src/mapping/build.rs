@@ -2,6 +2,10 @@ use std::collections::HashMap;
 
 use mapping::{Mapping, MappingProperty, FieldMapping, NestedMapping, FieldType, get_standard_analyzer};
 use index::metadata::IndexMetadata;
+use search::similarity::SimilarityModel;
+use search::knn::VectorSimilarity;
+use search::geo_shape::{DEFAULT_TREE_LEVELS, DEFAULT_DISTANCE_ERROR_PCT};
+use suggest::completion::DEFAULT_MAX_INPUT_LENGTH;
 
 
 #[derive(Debug, PartialEq)]
@@ -14,7 +18,20 @@ pub struct FieldMappingBuilder {
     pub boost: f64,
     pub base_analyzer: Option<String>,
     pub index_analyzer: Option<String>,
-    pub search_analyzer: Option<String>
+    pub search_analyzer: Option<String>,
+    pub similarity: Option<String>,
+
+    /// None means use the default, which is to have doc values on all non-analyzed fields
+    pub doc_values: Option<bool>,
+
+    pub dims: Option<usize>,
+    pub vector_similarity: VectorSimilarity,
+
+    pub completion_contexts: Vec<String>,
+    pub max_input_length: usize,
+
+    pub tree_levels: usize,
+    pub distance_error_pct: f64,
 }
 
 
@@ -30,6 +47,14 @@ impl Default for FieldMappingBuilder {
             base_analyzer: None,
             index_analyzer: None,
             search_analyzer: None,
+            similarity: None,
+            doc_values: None,
+            dims: None,
+            vector_similarity: VectorSimilarity::default(),
+            completion_contexts: Vec::new(),
+            max_input_length: DEFAULT_MAX_INPUT_LENGTH,
+            tree_levels: DEFAULT_TREE_LEVELS,
+            distance_error_pct: DEFAULT_DISTANCE_ERROR_PCT,
         }
     }
 }
@@ -81,15 +106,36 @@ impl FieldMappingBuilder {
             None
         };
 
+        let similarity_model = match self.similarity {
+            Some(ref similarity) => {
+                match index_metadata.settings.get_similarity(similarity) {
+                    Some(similarity_model) => similarity_model,
+                    // Unknown similarities are rejected when the mapping is parsed (see `check_similarities`)
+                    None => SimilarityModel::default(),
+                }
+            }
+            None => SimilarityModel::default(),
+        };
+
         FieldMapping {
             data_type: self.field_type,
             index_ref: None,
             is_indexed: self.is_indexed,
             is_stored: self.is_stored,
             is_in_all: self.is_in_all,
+            // Shapes are read from the source, they don't have doc values
+            has_doc_values: self.doc_values.unwrap_or(!self.is_analyzed && self.field_type != FieldType::GeoShape),
             boost: self.boost,
             index_analyzer: index_analyzer,
             search_analyzer: search_analyzer,
+            similarity: self.similarity.clone(),
+            similarity_model: similarity_model,
+            dims: self.dims,
+            vector_similarity: self.vector_similarity,
+            completion_contexts: self.completion_contexts.clone(),
+            max_input_length: self.max_input_length,
+            tree_levels: self.tree_levels,
+            distance_error_pct: self.distance_error_pct,
         }
     }
 }
@@ -175,6 +221,20 @@ impl MappingBuilder {
             ));
         }
 
+        // Insert _source field
+        if !properties.contains_key("_source") {
+            // Keeps a copy of the original document so it can be returned in search results
+            properties.insert("_source".to_string(), MappingProperty::Field(
+                FieldMapping {
+                    data_type: FieldType::String,
+                    is_indexed: false,
+                    is_stored: true,
+                    is_in_all: false,
+                    .. FieldMapping::default()
+                }
+            ));
+        }
+
         Mapping {
             properties: properties,
         }
@@ -189,6 +249,7 @@ mod tests {
     use analysis::filters::FilterSpec;
     use mapping::{Mapping, MappingProperty, FieldMapping, FieldType, get_standard_analyzer};
     use index::metadata::IndexMetadata;
+    use search::similarity::SimilarityModel;
 
     use super::{MappingBuilder, MappingPropertyBuilder, FieldMappingBuilder};
 
@@ -212,6 +273,13 @@ mod tests {
 
         assert_eq!(mapping, Mapping {
             properties: hashmap! {
+                "_source".to_string() => MappingProperty::Field(FieldMapping {
+                    data_type: FieldType::String,
+                    is_indexed: false,
+                    is_stored: true,
+                    is_in_all: false,
+                    ..FieldMapping::default()
+                }),
                 "title".to_string() => MappingProperty::Field(FieldMapping {
                     data_type: FieldType::String,
                     is_in_all: true,
@@ -242,6 +310,13 @@ mod tests {
 
         assert_eq!(mapping, Mapping {
             properties: hashmap! {
+                "_source".to_string() => MappingProperty::Field(FieldMapping {
+                    data_type: FieldType::String,
+                    is_indexed: false,
+                    is_stored: true,
+                    is_in_all: false,
+                    ..FieldMapping::default()
+                }),
                 "_all".to_string() => MappingProperty::Field(FieldMapping {
                     data_type: FieldType::String,
                     is_in_all: false,
@@ -272,6 +347,13 @@ mod tests {
 
         assert_eq!(mapping, Mapping {
             properties: hashmap! {
+                "_source".to_string() => MappingProperty::Field(FieldMapping {
+                    data_type: FieldType::String,
+                    is_indexed: false,
+                    is_stored: true,
+                    is_in_all: false,
+                    ..FieldMapping::default()
+                }),
                 "_all".to_string() => MappingProperty::Field(FieldMapping {
                     data_type: FieldType::String,
                     boost: 2.0f64,
@@ -314,6 +396,7 @@ mod tests {
 
         assert_eq!(mapping, FieldMapping {
             data_type: FieldType::Integer,
+            has_doc_values: true,
             index_analyzer: None,
             search_analyzer: None,
             ..FieldMapping::default()
@@ -469,4 +552,31 @@ mod tests {
             ..FieldMapping::default()
         });
     }
+
+    #[test]
+    fn test_build_field_similarity() {
+        let mut index_metadata = IndexMetadata::default();
+        index_metadata.settings.similarities.insert("short_text".to_string(), SimilarityModel::Bm25 {
+            k1: 1.5,
+            b: 0.3,
+        });
+
+        let builder = FieldMappingBuilder {
+            field_type: FieldType::String,
+            similarity: Some("short_text".to_string()),
+            ..FieldMappingBuilder::default()
+        };
+
+        let mapping = builder.build(&index_metadata);
+        assert_eq!(mapping.get_search_options().similarity_model, SimilarityModel::Bm25 { k1: 1.5, b: 0.3 });
+
+        let builder = FieldMappingBuilder {
+            field_type: FieldType::String,
+            similarity: Some("classic".to_string()),
+            ..FieldMappingBuilder::default()
+        };
+
+        let mapping = builder.build(&index_metadata);
+        assert_eq!(mapping.get_search_options().similarity_model, SimilarityModel::TfIdf);
+    }
 }
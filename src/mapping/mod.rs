@@ -11,7 +11,11 @@ use search::{Term, Token};
 use search::term_vector::TermVector;
 use search::document::FieldValue;
 use search::similarity::SimilarityModel;
+use search::knn::VectorSimilarity;
+use search::geo::GeoPoint;
+use search::geo_shape::{GeoShape, DEFAULT_TREE_LEVELS, DEFAULT_DISTANCE_ERROR_PCT};
 use search::schema::FieldId;
+use suggest::completion::{parse_completion_value, DEFAULT_MAX_INPUT_LENGTH};
 
 use analysis::AnalyzerSpec;
 use analysis::tokenizers::TokenizerSpec;
@@ -36,6 +40,10 @@ pub enum FieldType {
     Integer,
     Boolean,
     Date,
+    DenseVector,
+    GeoPoint,
+    GeoShape,
+    Completion,
 }
 
 
@@ -53,6 +61,10 @@ impl ToString for FieldType {
             FieldType::Integer => "integer".to_string(),
             FieldType::Boolean => "boolean".to_string(),
             FieldType::Date => "date".to_string(),
+            FieldType::DenseVector => "dense_vector".to_string(),
+            FieldType::GeoPoint => "geo_point".to_string(),
+            FieldType::GeoShape => "geo_shape".to_string(),
+            FieldType::Completion => "completion".to_string(),
         }
     }
 }
@@ -69,10 +81,7 @@ impl Default for FieldSearchOptions {
     fn default() -> FieldSearchOptions {
         FieldSearchOptions {
             analyzer: Some(get_standard_analyzer()),
-            similarity_model: SimilarityModel::Bm25 {
-                k1: 1.2,
-                b: 0.75,
-            },
+            similarity_model: SimilarityModel::default(),
         }
     }
 }
@@ -89,9 +98,34 @@ pub struct FieldMapping {
     pub is_indexed: bool,
     pub is_stored: bool,
     pub is_in_all: bool,
+
+    /// Keep a per-document copy of the value so results can be sorted by it
+    pub has_doc_values: bool,
     boost: f64,
     index_analyzer: Option<AnalyzerSpec>,
     search_analyzer: Option<AnalyzerSpec>,
+
+    /// Name of the similarity model used to score the field (see `IndexSettings::get_similarity`)
+    similarity: Option<String>,
+    similarity_model: SimilarityModel,
+
+    /// Number of dimensions of a dense_vector field
+    pub dims: Option<usize>,
+
+    /// How dense_vector fields are compared in kNN searches
+    pub vector_similarity: VectorSimilarity,
+
+    /// Names of the category contexts that completion field inputs can have
+    pub completion_contexts: Vec<String>,
+
+    /// Completion field inputs are cut short after this many characters
+    pub max_input_length: usize,
+
+    /// The finest geohash level that geo_shape fields are split into cells down to
+    pub tree_levels: usize,
+
+    /// How small geo_shape cells get, as a fraction of the size of each shape
+    pub distance_error_pct: f64,
 }
 
 
@@ -103,9 +137,18 @@ impl Default for FieldMapping {
             is_indexed: true,
             is_stored: false,
             is_in_all: true,
+            has_doc_values: false,
             boost: 1.0f64,
             index_analyzer: None,
             search_analyzer: None,
+            similarity: None,
+            similarity_model: SimilarityModel::default(),
+            dims: None,
+            vector_similarity: VectorSimilarity::default(),
+            completion_contexts: Vec::new(),
+            max_input_length: DEFAULT_MAX_INPUT_LENGTH,
+            tree_levels: DEFAULT_TREE_LEVELS,
+            distance_error_pct: DEFAULT_DISTANCE_ERROR_PCT,
         }
     }
 }
@@ -125,17 +168,44 @@ impl Serialize for FieldMapping {
             }
         };
 
-        let json = json!({
+        let mut json = json!({
             "type": self.data_type.to_string(),
             "index": index,
             "store": self.is_stored,
             // TODO
             // "index_analyzer"
             // "search_analyzer"
-            "boost": self.boost,
-            "include_in_all": self.is_in_all
+            "include_in_all": self.is_in_all,
+            "doc_values": self.has_doc_values
         });
 
+        // Boost can only be set on indexed fields
+        if self.is_indexed {
+            json["boost"] = json!(self.boost);
+        }
+
+        if let Some(ref similarity) = self.similarity {
+            json["similarity"] = json!(similarity);
+        }
+
+        if self.data_type == FieldType::DenseVector {
+            json["dims"] = json!(self.dims);
+            json["similarity"] = json!(self.vector_similarity.name());
+        }
+
+        if self.data_type == FieldType::Completion {
+            json["max_input_length"] = json!(self.max_input_length);
+
+            if !self.completion_contexts.is_empty() {
+                json["contexts"] = json!(self.completion_contexts.iter().map(|name| json!({"name": name, "type": "category"})).collect::<Vec<_>>());
+            }
+        }
+
+        if self.data_type == FieldType::GeoShape {
+            json["tree_levels"] = json!(self.tree_levels);
+            json["distance_error_pct"] = json!(self.distance_error_pct);
+        }
+
         json.serialize(serializer)
     }
 }
@@ -161,7 +231,7 @@ impl FieldMapping {
     pub fn get_search_options(&self) -> FieldSearchOptions {
         FieldSearchOptions {
             analyzer: self.search_analyzer().cloned(),
-            .. FieldSearchOptions::default()
+            similarity_model: self.similarity_model.clone(),
         }
     }
 
@@ -262,6 +332,20 @@ impl FieldMapping {
                     _ => Err(FieldValueError),
                 }
             }
+
+            // Vectors are compared by kNN searches, they aren't indexed as terms
+            FieldType::DenseVector => Ok(None),
+
+            // Shapes are indexed as the geohash cells they cover
+            FieldType::GeoShape => {
+                let shape = parse_geo_shape(value).ok_or(FieldValueError)?;
+                let cells = shape.cells(shape.detail_level(self.tree_levels, self.distance_error_pct));
+                let tokens = cells.leaves.iter().enumerate().map(|(i, cell)| Token{term: Term::from_string(cell), position: i as u32 + 1}).collect::<Vec<Token>>();
+                Ok(Some(tokens.into()))
+            }
+
+            // Geo points and completion inputs are only read from doc values
+            FieldType::GeoPoint | FieldType::Completion => Ok(None),
         }
     }
 
@@ -329,6 +413,31 @@ impl FieldMapping {
                     _ => Err(FieldValueError)
                 }
             }
+            FieldType::DenseVector => {
+                let array = value.as_array().ok_or(FieldValueError)?;
+                let mut vector = Vec::with_capacity(array.len());
+
+                for item in array {
+                    vector.push(item.as_f64().ok_or(FieldValueError)? as f32);
+                }
+
+                if Some(vector.len()) != self.dims {
+                    return Err(FieldValueError);
+                }
+
+                Ok(Some(FieldValue::Vector(vector)))
+            }
+            FieldType::GeoPoint => parse_geo_point(value).map(|point| Some(FieldValue::GeoPoint(point))).ok_or(FieldValueError),
+            FieldType::GeoShape => {
+                // The shape is stored as the GeoJSON it was given as
+                parse_geo_shape(value).ok_or(FieldValueError)?;
+                Ok(Some(FieldValue::String(value.to_string())))
+            }
+            FieldType::Completion => {
+                // The inputs are kept as JSON, like the document source
+                let completion_inputs = parse_completion_value(value, &self.completion_contexts, self.max_input_length).ok_or(FieldValueError)?;
+                Ok(Some(FieldValue::String(serde_json::to_string(&completion_inputs).unwrap())))
+            }
         }
     }
 }
@@ -400,6 +509,26 @@ impl Serialize for Mapping {
 }
 
 
+/// Parses a geo point given as an object with "lat" and "lon", a "lat,lon" string or a [lon, lat] array
+pub fn parse_geo_point(json: &serde_json::Value) -> Option<GeoPoint> {
+    match *json {
+        serde_json::Value::Object(ref object) => GeoPoint::new(object.get("lat")?.as_f64()?, object.get("lon")?.as_f64()?),
+        serde_json::Value::String(ref string) => GeoPoint::parse(string),
+        serde_json::Value::Array(ref array) if array.len() == 2 => GeoPoint::new(array[1].as_f64()?, array[0].as_f64()?),
+        _ => None,
+    }
+}
+
+
+/// Parses a GeoJSON shape, or an array of them
+pub fn parse_geo_shape(json: &serde_json::Value) -> Option<GeoShape> {
+    match *json {
+        serde_json::Value::Array(ref array) => array.iter().map(parse_geo_shape).collect::<Option<_>>().map(GeoShape::Collection),
+        _ => GeoShape::from_geojson(json).ok(),
+    }
+}
+
+
 fn parse_boolean(json: &serde_json::Value) -> bool {
     match *json {
         serde_json::Value::Bool(val) => val,
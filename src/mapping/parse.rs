@@ -2,6 +2,10 @@ use std::collections::{HashMap, BTreeSet};
 
 use serde_json;
 
+use search::knn::VectorSimilarity;
+use search::geo::MAX_GEOHASH_PRECISION;
+use index::metadata::settings::IndexSettings;
+
 use mapping::FieldType;
 use mapping::build::{MappingBuilder, MappingPropertyBuilder, FieldMappingBuilder, NestedMappingBuilder};
 
@@ -29,6 +33,35 @@ pub enum FieldMappingParseError {
     // "boost" setting
     BoostOnlyAllowedOnIndexedFields,
     BoostMustBePositive,
+
+    // "similarity" setting
+    SimilarityOnlyAllowedOnIndexedFields,
+    UnrecognisedSimilarity(String),
+
+    // "doc_values" setting
+    DocValuesNotAllowedOnAnalyzedFields,
+
+    // dense_vector fields
+    DenseVectorCannotBeIndexed,
+    DimsOnlyAllowedOnDenseVectorType,
+    DimsOutOfRange,
+    UnrecognisedVectorSimilarity(String),
+
+    // geo_point fields
+    GeoPointCannotBeIndexed,
+
+    // geo_shape fields
+    DocValuesNotAllowedOnGeoShapeType,
+    TreeLevelsOnlyAllowedOnGeoShapeType,
+    TreeLevelsOutOfRange,
+    DistanceErrorPctOnlyAllowedOnGeoShapeType,
+    DistanceErrorPctOutOfRange,
+
+    // completion fields
+    CompletionCannotBeIndexed,
+    ContextsOnlyAllowedOnCompletionType,
+    UnrecognisedContextType(String),
+    MaxInputLengthOnlyAllowedOnCompletionType,
 }
 
 
@@ -45,6 +78,10 @@ pub enum MappingParseError {
 }
 
 
+/// The largest number of dimensions a dense_vector field can have
+const MAX_DIMS: u64 = 4096;
+
+
 fn parse_boolean(json: &serde_json::Value) -> Result<bool, FieldMappingParseError> {
     match *json {
         serde_json::Value::Bool(val) => Ok(val),
@@ -79,6 +116,10 @@ fn parse_field_type(field_type_str: &str) -> Result<FieldType, FieldMappingParse
         "integer" => Ok(FieldType::Integer),
         "boolean" => Ok(FieldType::Boolean),
         "date" => Ok(FieldType::Date),
+        "dense_vector" => Ok(FieldType::DenseVector),
+        "geo_point" => Ok(FieldType::GeoPoint),
+        "geo_shape" => Ok(FieldType::GeoShape),
+        "completion" => Ok(FieldType::Completion),
         _ => Err(FieldMappingParseError::UnrecognisedFieldType(field_type_str.to_string())),
     }
 }
@@ -99,6 +140,13 @@ fn parse_field(json: &serde_json::Value) -> Result<FieldMappingBuilder, FieldMap
         "search_analyzer".to_string(),
         "boost".to_string(),
         "include_in_all".to_string(),
+        "similarity".to_string(),
+        "doc_values".to_string(),
+        "dims".to_string(),
+        "contexts".to_string(),
+        "max_input_length".to_string(),
+        "tree_levels".to_string(),
+        "distance_error_pct".to_string(),
     ];
     let unrecognised_keys = provided_keys.difference(&allowed_keys).cloned().collect::<Vec<String>>();
 
@@ -116,6 +164,11 @@ fn parse_field(json: &serde_json::Value) -> Result<FieldMappingBuilder, FieldMap
         mapping_builder.is_analyzed = false;
     }
 
+    // Vectors, geo points and completion inputs aren't indexed as terms
+    if mapping_builder.field_type == FieldType::DenseVector || mapping_builder.field_type == FieldType::GeoPoint || mapping_builder.field_type == FieldType::Completion {
+        mapping_builder.is_indexed = false;
+    }
+
     // "index" setting
     if let Some(index_json) = field_object.get("index") {
         let index_str = index_json.as_str().ok_or(FieldMappingParseError::ExpectedString)?;
@@ -142,6 +195,18 @@ fn parse_field(json: &serde_json::Value) -> Result<FieldMappingBuilder, FieldMap
                 return Err(FieldMappingParseError::UnrecognisedIndexSetting(index_str.to_string()));
             }
         }
+
+        if mapping_builder.is_indexed && mapping_builder.field_type == FieldType::DenseVector {
+            return Err(FieldMappingParseError::DenseVectorCannotBeIndexed);
+        }
+
+        if mapping_builder.is_indexed && mapping_builder.field_type == FieldType::GeoPoint {
+            return Err(FieldMappingParseError::GeoPointCannotBeIndexed);
+        }
+
+        if mapping_builder.is_indexed && mapping_builder.field_type == FieldType::Completion {
+            return Err(FieldMappingParseError::CompletionCannotBeIndexed);
+        }
     }
 
     // "store" setting
@@ -209,6 +274,116 @@ fn parse_field(json: &serde_json::Value) -> Result<FieldMappingBuilder, FieldMap
         mapping_builder.is_in_all = include_in_all;
     }
 
+    // "similarity" setting
+    // On dense_vector fields, this is how vectors are compared instead of a scoring model
+    if let Some(similarity_json) = field_object.get("similarity") {
+        let similarity_str = similarity_json.as_str().ok_or(FieldMappingParseError::ExpectedString)?;
+
+        if mapping_builder.field_type == FieldType::DenseVector {
+            mapping_builder.vector_similarity = VectorSimilarity::from_name(similarity_str).ok_or_else(|| FieldMappingParseError::UnrecognisedVectorSimilarity(similarity_str.to_string()))?;
+        } else {
+            mapping_builder.similarity = Some(similarity_str.to_string());
+
+            if !mapping_builder.is_indexed {
+                return Err(FieldMappingParseError::SimilarityOnlyAllowedOnIndexedFields);
+            }
+        }
+    }
+
+    // "dims" setting
+    match field_object.get("dims") {
+        Some(dims_json) => {
+            if mapping_builder.field_type != FieldType::DenseVector {
+                return Err(FieldMappingParseError::DimsOnlyAllowedOnDenseVectorType);
+            }
+
+            let dims = dims_json.as_u64().ok_or(FieldMappingParseError::ExpectedNumber)?;
+            if dims == 0 || dims > MAX_DIMS {
+                return Err(FieldMappingParseError::DimsOutOfRange);
+            }
+
+            mapping_builder.dims = Some(dims as usize);
+        }
+        None => {
+            if mapping_builder.field_type == FieldType::DenseVector {
+                return Err(FieldMappingParseError::ExpectedKey("dims".to_string()));
+            }
+        }
+    }
+
+    // "contexts" setting
+    // Only category contexts are supported, their values are given with each input
+    if let Some(contexts_json) = field_object.get("contexts") {
+        if mapping_builder.field_type != FieldType::Completion {
+            return Err(FieldMappingParseError::ContextsOnlyAllowedOnCompletionType);
+        }
+
+        for context_json in contexts_json.as_array().ok_or(FieldMappingParseError::ExpectedObject)? {
+            let context_object = context_json.as_object().ok_or(FieldMappingParseError::ExpectedObject)?;
+            let name = context_object.get("name").ok_or(FieldMappingParseError::ExpectedKey("name".to_string()))?;
+            let context_type = context_object.get("type").ok_or(FieldMappingParseError::ExpectedKey("type".to_string()))?;
+            let context_type = context_type.as_str().ok_or(FieldMappingParseError::ExpectedString)?;
+
+            if context_type != "category" {
+                return Err(FieldMappingParseError::UnrecognisedContextType(context_type.to_string()));
+            }
+
+            mapping_builder.completion_contexts.push(name.as_str().ok_or(FieldMappingParseError::ExpectedString)?.to_string());
+        }
+    }
+
+    // "max_input_length" setting
+    if let Some(max_input_length_json) = field_object.get("max_input_length") {
+        if mapping_builder.field_type != FieldType::Completion {
+            return Err(FieldMappingParseError::MaxInputLengthOnlyAllowedOnCompletionType);
+        }
+
+        mapping_builder.max_input_length = max_input_length_json.as_u64().ok_or(FieldMappingParseError::ExpectedNumber)? as usize;
+    }
+
+    // "doc_values" setting
+    if let Some(doc_values_json) = field_object.get("doc_values") {
+        let doc_values = parse_boolean(doc_values_json)?;
+        mapping_builder.doc_values = Some(doc_values);
+
+        if doc_values && mapping_builder.is_analyzed {
+            return Err(FieldMappingParseError::DocValuesNotAllowedOnAnalyzedFields);
+        }
+
+        if doc_values && mapping_builder.field_type == FieldType::GeoShape {
+            return Err(FieldMappingParseError::DocValuesNotAllowedOnGeoShapeType);
+        }
+    }
+
+    // "tree_levels" setting
+    // The finest geohash level that shapes are split into cells down to
+    if let Some(tree_levels_json) = field_object.get("tree_levels") {
+        if mapping_builder.field_type != FieldType::GeoShape {
+            return Err(FieldMappingParseError::TreeLevelsOnlyAllowedOnGeoShapeType);
+        }
+
+        let tree_levels = tree_levels_json.as_u64().ok_or(FieldMappingParseError::ExpectedNumber)?;
+        if tree_levels == 0 || tree_levels > MAX_GEOHASH_PRECISION as u64 {
+            return Err(FieldMappingParseError::TreeLevelsOutOfRange);
+        }
+
+        mapping_builder.tree_levels = tree_levels as usize;
+    }
+
+    // "distance_error_pct" setting
+    if let Some(distance_error_pct_json) = field_object.get("distance_error_pct") {
+        if mapping_builder.field_type != FieldType::GeoShape {
+            return Err(FieldMappingParseError::DistanceErrorPctOnlyAllowedOnGeoShapeType);
+        }
+
+        let distance_error_pct = parse_float(distance_error_pct_json)?;
+        if !(0.0..=0.5).contains(&distance_error_pct) {
+            return Err(FieldMappingParseError::DistanceErrorPctOutOfRange);
+        }
+
+        mapping_builder.distance_error_pct = distance_error_pct;
+    }
+
     Ok(mapping_builder)
 }
 
@@ -316,12 +491,47 @@ pub fn parse(json: &serde_json::Value) -> Result<MappingBuilder, MappingParseErr
 }
 
 
+/// Checks that the similarities that the mapping's fields select are defined by the index
+///
+/// This can only be done once the index's settings are known, so it's separate from `parse`.
+pub fn check_similarities(mapping: &MappingBuilder, settings: &IndexSettings) -> Result<(), MappingParseError> {
+    check_property_similarities(&mapping.properties, settings)
+}
+
+
+fn check_property_similarities(properties: &HashMap<String, MappingPropertyBuilder>, settings: &IndexSettings) -> Result<(), MappingParseError> {
+    for (prop_name, property) in properties.iter() {
+        match *property {
+            MappingPropertyBuilder::Field(ref field) => {
+                if let Some(ref similarity) = field.similarity {
+                    if settings.get_similarity(similarity).is_none() {
+                        return Err(MappingParseError::FieldMappingParseError(prop_name.to_string(), FieldMappingParseError::UnrecognisedSimilarity(similarity.clone())));
+                    }
+                }
+            }
+            MappingPropertyBuilder::NestedMapping(ref nested_mapping) => {
+                if let Err(e) = check_property_similarities(&nested_mapping.properties, settings) {
+                    return Err(MappingParseError::NestedMappingParseError(prop_name.to_string(), Box::new(e)));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
 #[cfg(test)]
 mod tests {
+    use serde_json;
+
+    use search::knn::VectorSimilarity;
+    use search::similarity::SimilarityModel;
+    use index::metadata::IndexMetadata;
     use mapping::FieldType;
     use mapping::build::{FieldMappingBuilder, NestedMappingBuilder, MappingPropertyBuilder, MappingBuilder};
 
-    use super::{MappingParseError, FieldMappingParseError, parse, parse_field};
+    use super::{MappingParseError, FieldMappingParseError, parse, parse_field, check_similarities};
 
     #[test]
     fn test_parse() {
@@ -925,6 +1135,22 @@ mod tests {
         assert_eq!(mapping, Err(FieldMappingParseError::BoostOnlyAllowedOnIndexedFields));
     }
 
+    #[test]
+    fn test_parse_serialized_non_indexed_field() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "string",
+                "index": "no",
+                "store": true
+            }
+        )).unwrap().build(&IndexMetadata::default());
+
+        // Non-indexed fields must not be serialized with a boost, or they couldn't be loaded again
+        let json = serde_json::to_value(&mapping).unwrap();
+        assert_eq!(json.get("boost"), None);
+        assert_eq!(parse_field(&json).map(|builder| builder.is_indexed), Ok(false));
+    }
+
     #[test]
     fn test_parse_boost_negative() {
         let mapping = parse_field(&json!(
@@ -1000,4 +1226,165 @@ mod tests {
             ..FieldMappingBuilder::default()
         }));
     }
+
+    #[test]
+    fn test_parse_similarity() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "string",
+                "similarity": "classic"
+            }
+        ));
+
+        assert_eq!(mapping, Ok(FieldMappingBuilder {
+            field_type: FieldType::String,
+            similarity: Some("classic".to_string()),
+            ..FieldMappingBuilder::default()
+        }));
+    }
+
+    #[test]
+    fn test_check_similarities() {
+        let mut index_metadata = IndexMetadata::default();
+        index_metadata.settings.similarities.insert("short_text".to_string(), SimilarityModel::Bm25 { k1: 1.5, b: 0.3 });
+
+        let mapping = parse(&json!({
+            "properties": {
+                "title": {"type": "string", "similarity": "short_text"},
+                "body": {"type": "string", "similarity": "classic"},
+            }
+        })).unwrap();
+        assert_eq!(check_similarities(&mapping, &index_metadata.settings), Ok(()));
+
+        let mapping = parse(&json!({
+            "properties": {
+                "comments": {
+                    "type": "nested",
+                    "properties": {
+                        "text": {"type": "string", "similarity": "short_txt"},
+                    }
+                }
+            }
+        })).unwrap();
+        assert_eq!(check_similarities(&mapping, &index_metadata.settings), Err(MappingParseError::NestedMappingParseError("comments".to_string(), Box::new(MappingParseError::FieldMappingParseError("text".to_string(), FieldMappingParseError::UnrecognisedSimilarity("short_txt".to_string()))))));
+    }
+
+    #[test]
+    fn test_parse_similarity_non_indexed_field() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "string",
+                "index": "no",
+                "similarity": "classic"
+            }
+        ));
+
+        assert_eq!(mapping, Err(FieldMappingParseError::SimilarityOnlyAllowedOnIndexedFields));
+    }
+
+    #[test]
+    fn test_parse_doc_values() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "integer",
+                "doc_values": false
+            }
+        ));
+
+        assert_eq!(mapping, Ok(FieldMappingBuilder {
+            field_type: FieldType::Integer,
+            is_analyzed: false,
+            doc_values: Some(false),
+            ..FieldMappingBuilder::default()
+        }));
+    }
+
+    #[test]
+    fn test_parse_doc_values_on_analyzed_field() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "string",
+                "doc_values": true
+            }
+        ));
+
+        assert_eq!(mapping, Err(FieldMappingParseError::DocValuesNotAllowedOnAnalyzedFields));
+    }
+
+    #[test]
+    fn test_parse_dense_vector() {
+        let mapping = parse_field(&json!(
+            {
+                "type": "dense_vector",
+                "dims": 3,
+                "similarity": "dot_product"
+            }
+        ));
+
+        assert_eq!(mapping, Ok(FieldMappingBuilder {
+            field_type: FieldType::DenseVector,
+            is_indexed: false,
+            is_analyzed: false,
+            dims: Some(3),
+            vector_similarity: VectorSimilarity::DotProduct,
+            ..FieldMappingBuilder::default()
+        }));
+    }
+
+    #[test]
+    fn test_parse_geo_point() {
+        assert_eq!(parse_field(&json!({"type": "geo_point"})), Ok(FieldMappingBuilder {
+            field_type: FieldType::GeoPoint,
+            is_indexed: false,
+            is_analyzed: false,
+            ..FieldMappingBuilder::default()
+        }));
+
+        assert_eq!(parse_field(&json!({"type": "geo_point", "index": "not_analyzed"})), Err(FieldMappingParseError::GeoPointCannotBeIndexed));
+    }
+
+    #[test]
+    fn test_parse_geo_shape() {
+        assert_eq!(parse_field(&json!({"type": "geo_shape", "tree_levels": 6, "distance_error_pct": 0.1})), Ok(FieldMappingBuilder {
+            field_type: FieldType::GeoShape,
+            is_analyzed: false,
+            tree_levels: 6,
+            distance_error_pct: 0.1,
+            ..FieldMappingBuilder::default()
+        }));
+
+        assert_eq!(parse_field(&json!({"type": "geo_shape", "tree_levels": 13})), Err(FieldMappingParseError::TreeLevelsOutOfRange));
+        assert_eq!(parse_field(&json!({"type": "geo_shape", "distance_error_pct": 1.0})), Err(FieldMappingParseError::DistanceErrorPctOutOfRange));
+        assert_eq!(parse_field(&json!({"type": "geo_shape", "doc_values": true})), Err(FieldMappingParseError::DocValuesNotAllowedOnGeoShapeType));
+        assert_eq!(parse_field(&json!({"type": "geo_point", "tree_levels": 6})), Err(FieldMappingParseError::TreeLevelsOnlyAllowedOnGeoShapeType));
+    }
+
+    #[test]
+    fn test_parse_completion() {
+        assert_eq!(parse_field(&json!({
+            "type": "completion",
+            "max_input_length": 20,
+            "contexts": [{"name": "genre", "type": "category"}],
+        })), Ok(FieldMappingBuilder {
+            field_type: FieldType::Completion,
+            is_indexed: false,
+            is_analyzed: false,
+            completion_contexts: vec!["genre".to_string()],
+            max_input_length: 20,
+            ..FieldMappingBuilder::default()
+        }));
+
+        assert_eq!(parse_field(&json!({"type": "completion", "contexts": [{"name": "location", "type": "geo"}]})), Err(FieldMappingParseError::UnrecognisedContextType("geo".to_string())));
+        assert_eq!(parse_field(&json!({"type": "string", "contexts": []})), Err(FieldMappingParseError::ContextsOnlyAllowedOnCompletionType));
+        assert_eq!(parse_field(&json!({"type": "completion", "index": "not_analyzed"})), Err(FieldMappingParseError::CompletionCannotBeIndexed));
+    }
+
+    #[test]
+    fn test_parse_dense_vector_errors() {
+        assert_eq!(parse_field(&json!({"type": "dense_vector"})), Err(FieldMappingParseError::ExpectedKey("dims".to_string())));
+        assert_eq!(parse_field(&json!({"type": "dense_vector", "dims": 0})), Err(FieldMappingParseError::DimsOutOfRange));
+        assert_eq!(parse_field(&json!({"type": "dense_vector", "dims": 3, "index": "not_analyzed"})), Err(FieldMappingParseError::DenseVectorCannotBeIndexed));
+        assert_eq!(parse_field(&json!({"type": "dense_vector", "dims": 3, "similarity": "classic"})), Err(FieldMappingParseError::UnrecognisedVectorSimilarity("classic".to_string())));
+        assert_eq!(parse_field(&json!({"type": "integer", "dims": 3})), Err(FieldMappingParseError::DimsOnlyAllowedOnDenseVectorType));
+    }
 }
@@ -0,0 +1,231 @@
+//! Lets plugins add tokenizers, token filters, query types and API endpoints
+//!
+//! A plugin implements `Plugin` and returns a provider for each kind of thing it adds. Plugins
+//! are compiled in, and registered when the node starts (see `register_compiled_in`), before
+//! any index settings are read or the API starts. Anything built in takes precedence over a
+//! plugin's, so a plugin can't replace a built-in tokenizer, query type or route. Every node
+//! in a cluster needs the same plugins, as the settings and queries that use them are sent
+//! between the nodes as JSON.
+
+use std::sync::{Arc, RwLock};
+
+use hyper::Method;
+use serde_json::Value as Json;
+use slog::Logger;
+
+use analysis::tokenizers::Tokenizer;
+use analysis::filters::Filter;
+use query_parser::{QueryBuilder, QueryParseError};
+use api::{Request, Response, ApiResult};
+
+
+/// Builds the tokenizers and token filters of a plugin from their settings in an index's
+/// `analysis` section
+pub trait AnalyzerProvider: Send + Sync {
+    /// Returns None if the type isn't one of this plugin's tokenizers
+    fn tokenizer(&self, _tokenizer_type: &str, _settings: &Json) -> Option<Result<Arc<Tokenizer>, String>> {
+        None
+    }
+
+    /// Returns None if the type isn't one of this plugin's filters
+    fn filter(&self, _filter_type: &str, _settings: &Json) -> Option<Result<Arc<Filter>, String>> {
+        None
+    }
+}
+
+
+/// Parses the query types added by a plugin
+pub trait QueryParserProvider: Send + Sync {
+    /// Returns None if the type isn't one of this plugin's queries
+    fn parse(&self, query_type: &str, json: &Json) -> Option<Result<Box<QueryBuilder>, QueryParseError>>;
+}
+
+
+/// Handles the API endpoints added by a plugin
+///
+/// The endpoints need the `manage` cluster privilege, or the `admin` index privilege if the
+/// path starts with an index name (`read` for both if the method is GET or HEAD).
+pub trait RestHandlerProvider: Send + Sync {
+    /// The routes to add, such as `(Method::GET, "/_hello/:name")`
+    fn routes(&self) -> Vec<(Method, String)>;
+
+    /// Handles a request that matched one of the routes. The values of the route's
+    /// parameters are in `req.params`
+    fn handle(&self, pattern: &str, req: &mut Request) -> ApiResult<Response>;
+}
+
+
+pub trait Plugin: Send + Sync {
+    /// Must be unique among the node's plugins
+    fn name(&self) -> &str;
+
+    fn version(&self) -> &str;
+
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn analyzers(&self) -> Option<Arc<AnalyzerProvider>> {
+        None
+    }
+
+    fn queries(&self) -> Option<Arc<QueryParserProvider>> {
+        None
+    }
+
+    fn rest_handlers(&self) -> Option<Arc<RestHandlerProvider>> {
+        None
+    }
+}
+
+
+static PLUGINS: RwLock<Vec<Arc<Plugin>>> = RwLock::new(Vec::new());
+
+
+/// Adds a plugin to the node
+///
+/// Fails if there's already a plugin with the same name.
+pub fn register(plugin: Arc<Plugin>) -> Result<(), String> {
+    let mut plugins = PLUGINS.write().unwrap();
+
+    if plugins.iter().any(|registered| registered.name() == plugin.name()) {
+        return Err(format!("A plugin named {:?} has already been registered", plugin.name()));
+    }
+
+    plugins.push(plugin);
+    Ok(())
+}
+
+
+/// The plugins that have been registered, in the order they were registered in
+pub fn installed() -> Vec<Arc<Plugin>> {
+    PLUGINS.read().unwrap().clone()
+}
+
+
+/// The plugins that are built into this binary
+///
+/// To compile a plugin in, add its crate as an optional dependency behind a cargo feature,
+/// and add it here under `#[cfg(feature = "...")]`.
+fn compiled_in() -> Vec<Arc<Plugin>> {
+    Vec::new()
+}
+
+
+/// Registers the plugins that are built into this binary. Called once, when the node starts
+pub fn register_compiled_in(log: &Logger) {
+    for plugin in compiled_in() {
+        let name = plugin.name().to_string();
+        let version = plugin.version().to_string();
+
+        match register(plugin) {
+            Ok(()) => info!(log, "loaded plugin"; "plugin" => name, "version" => version),
+            Err(e) => warn!(log, "unable to load plugin"; "plugin" => name, "error" => e),
+        }
+    }
+}
+
+
+/// Builds a tokenizer with the first plugin that has one of the type
+///
+/// The plugins are copied out of the registry first, so they can't deadlock it by
+/// registering other plugins.
+pub fn build_tokenizer(tokenizer_type: &str, settings: &Json) -> Option<Result<Arc<Tokenizer>, String>> {
+    installed().iter()
+        .filter_map(|plugin| plugin.analyzers())
+        .filter_map(|analyzers| analyzers.tokenizer(tokenizer_type, settings))
+        .next()
+}
+
+
+/// Builds a token filter with the first plugin that has one of the type
+pub fn build_filter(filter_type: &str, settings: &Json) -> Option<Result<Arc<Filter>, String>> {
+    installed().iter()
+        .filter_map(|plugin| plugin.analyzers())
+        .filter_map(|analyzers| analyzers.filter(filter_type, settings))
+        .next()
+}
+
+
+/// Parses a query with the first plugin that has a query of the type
+pub fn parse_query(query_type: &str, json: &Json) -> Option<Result<Box<QueryBuilder>, QueryParseError>> {
+    installed().iter()
+        .filter_map(|plugin| plugin.queries())
+        .filter_map(|queries| queries.parse(query_type, json))
+        .next()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::Value as Json;
+    use search::{Term, Token};
+
+    use analysis::tokenizers::{Tokenizer, TokenizerSpec};
+    use index::metadata::parse::analysis_tokenizer::{TokenizerParseError, parse as parse_tokenizer};
+
+    use super::{Plugin, AnalyzerProvider, register};
+
+    /// Makes a single token of the whole input
+    struct KeywordTokenizer;
+
+    impl Tokenizer for KeywordTokenizer {
+        fn tokenize<'a>(&self, input: &'a str) -> Box<Iterator<Item=Token> + 'a> {
+            Box::new(Some(Token { term: Term::from_string(input), position: 1 }).into_iter())
+        }
+    }
+
+    struct KeywordPlugin;
+
+    impl AnalyzerProvider for KeywordPlugin {
+        fn tokenizer(&self, tokenizer_type: &str, settings: &Json) -> Option<Result<Arc<Tokenizer>, String>> {
+            if tokenizer_type != "test_keyword" {
+                return None;
+            }
+
+            if settings.get("buffer_size").is_some() {
+                return Some(Err("buffer_size isn't supported".to_string()));
+            }
+
+            Some(Ok(Arc::new(KeywordTokenizer)))
+        }
+    }
+
+    impl Plugin for KeywordPlugin {
+        fn name(&self) -> &str {
+            "test-keyword"
+        }
+
+        fn version(&self) -> &str {
+            "1.0.0"
+        }
+
+        fn analyzers(&self) -> Option<Arc<AnalyzerProvider>> {
+            Some(Arc::new(KeywordPlugin))
+        }
+    }
+
+    #[test]
+    fn test_plugin_tokenizer() {
+        // The registry is shared by every test, so this is the only one that registers
+        register(Arc::new(KeywordPlugin)).unwrap();
+        assert!(register(Arc::new(KeywordPlugin)).is_err());
+
+        let settings = json!({"type": "test_keyword"});
+        let tokenizer = parse_tokenizer(&settings).unwrap();
+        let tokens = tokenizer.initialise("Hello, world!").collect::<Vec<Token>>();
+        assert_eq!(tokens, vec![Token { term: Term::from_string("Hello, world!"), position: 1 }]);
+
+        // It's saved with the settings it was defined with
+        assert_eq!(::serde_json::to_value(&tokenizer).unwrap(), settings);
+        assert_eq!(tokenizer, parse_tokenizer(&settings).unwrap());
+
+        // Built-in types are still parsed as before
+        assert_eq!(parse_tokenizer(&json!({"type": "standard"})).unwrap(), TokenizerSpec::Standard);
+
+        assert_eq!(parse_tokenizer(&json!({"type": "test_keyword", "buffer_size": 256})).err(), Some(TokenizerParseError::InvalidPluginSettings("buffer_size isn't supported".to_string())));
+        assert_eq!(parse_tokenizer(&json!({"type": "foo"})).err(), Some(TokenizerParseError::UnrecognisedType("foo".to_string())));
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+
+
+/// Resource usage of the running process
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessStatistics {
+    /// Memory the process has in RAM, in bytes
+    pub resident_in_bytes: u64,
+
+    /// Total virtual memory mapped by the process, in bytes
+    pub virtual_in_bytes: u64,
+
+    pub threads: u64,
+
+    pub open_file_descriptors: u64,
+
+    /// CPU time used by the process in both user and kernel mode
+    pub cpu_time_in_millis: u64,
+}
+
+
+/// Reads a "<Name>: <value> [kB]" line from /proc/self/status
+#[cfg(target_os = "linux")]
+fn parse_status_field(status: &str, name: &str) -> Option<u64> {
+    for line in status.lines() {
+        let mut parts = line.splitn(2, ':');
+        if parts.next() != Some(name) {
+            continue;
+        }
+
+        let mut value = parts.next().unwrap_or("").split_whitespace();
+        let number = value.next().and_then(|number| number.parse::<u64>().ok());
+
+        return match value.next() {
+            Some("kB") => number.map(|number| number * 1024),
+            _ => number,
+        };
+    }
+
+    None
+}
+
+
+/// Works out the CPU time used from the contents of /proc/self/stat
+///
+/// utime and stime are the 14th and 15th fields. The 2nd field is the command name which may
+/// contain spaces, so the fields are counted from the end of it.
+#[cfg(target_os = "linux")]
+fn parse_cpu_time_ticks(stat: &str) -> Option<u64> {
+    let fields = match stat.rfind(')') {
+        Some(end_of_name) => stat[end_of_name + 1..].split_whitespace().collect::<Vec<_>>(),
+        None => return None,
+    };
+
+    let utime = fields.get(11).and_then(|utime| utime.parse::<u64>().ok());
+    let stime = fields.get(12).and_then(|stime| stime.parse::<u64>().ok());
+
+    match (utime, stime) {
+        (Some(utime), Some(stime)) => Some(utime + stime),
+        _ => None,
+    }
+}
+
+
+/// Returns the resource usage of this process. Returns None on platforms where this can't be
+/// worked out.
+#[cfg(target_os = "linux")]
+pub fn process_statistics() -> io::Result<Option<ProcessStatistics>> {
+    use std::fs;
+    use libc;
+
+    let status = fs::read_to_string("/proc/self/status")?;
+    let stat = fs::read_to_string("/proc/self/stat")?;
+    let open_file_descriptors = fs::read_dir("/proc/self/fd")?.count() as u64;
+
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let cpu_time_in_millis = match parse_cpu_time_ticks(&stat) {
+        Some(ticks) if ticks_per_second > 0 => ticks * 1000 / ticks_per_second as u64,
+        _ => 0,
+    };
+
+    Ok(Some(ProcessStatistics {
+        resident_in_bytes: parse_status_field(&status, "VmRSS").unwrap_or(0),
+        virtual_in_bytes: parse_status_field(&status, "VmSize").unwrap_or(0),
+        threads: parse_status_field(&status, "Threads").unwrap_or(0),
+        open_file_descriptors: open_file_descriptors,
+        cpu_time_in_millis: cpu_time_in_millis,
+    }))
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_statistics() -> io::Result<Option<ProcessStatistics>> {
+    Ok(None)
+}
+
+
+/// Returns the machine's physical memory in bytes, if it can be worked out
+#[cfg(target_os = "linux")]
+pub fn total_memory() -> Option<u64> {
+    use std::fs;
+
+    // /proc/meminfo has the same format as /proc/self/status
+    fs::read_to_string("/proc/meminfo").ok().and_then(|meminfo| parse_status_field(&meminfo, "MemTotal"))
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn total_memory() -> Option<u64> {
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::process_statistics;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_status_field() {
+        use super::parse_status_field;
+
+        let status = "Name:\trusticsearch\nVmSize:\t  123456 kB\nVmRSS:\t    2048 kB\nThreads:\t12\n";
+        assert_eq!(parse_status_field(status, "VmRSS"), Some(2048 * 1024));
+        assert_eq!(parse_status_field(status, "Threads"), Some(12));
+        assert_eq!(parse_status_field(status, "VmSwap"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_cpu_time_ticks() {
+        use super::parse_cpu_time_ticks;
+
+        let stat = "1234 (rustic search) S 1 1234 1234 0 -1 4194304 100 0 0 0 250 50 0 0 20 0 12 0 1000 0 0";
+        assert_eq!(parse_cpu_time_ticks(stat), Some(300));
+        assert_eq!(parse_cpu_time_ticks("garbage"), None);
+    }
+
+    #[test]
+    fn test_process_statistics() {
+        if let Some(stats) = process_statistics().unwrap() {
+            assert!(stats.resident_in_bytes > 0);
+            assert!(stats.threads >= 1);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Reads and writes the protocol buffers wire format
+//!
+//! This is used by the gRPC API. Only what its messages need is supported: varints, 32 and
+//! 64 bit values and length-delimited fields (strings, bytes and embedded messages). Messages
+//! are read a field at a time with `FieldReader`, skipping fields that aren't known, and
+//! written with `MessageWriter`, which leaves out fields that have their default value as
+//! proto3 does.
+
+use std::str;
+
+
+const WIRE_TYPE_VARINT: u8 = 0;
+const WIRE_TYPE_FIXED64: u8 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u8 = 2;
+const WIRE_TYPE_FIXED32: u8 = 5;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The message ended in the middle of a field
+    Truncated,
+
+    /// A varint was longer than the 10 bytes a 64 bit value needs
+    VarintTooLong,
+
+    /// Groups (wire types 3 and 4) aren't supported, and other types don't exist
+    UnsupportedWireType(u8),
+
+    /// A field has a different type than the message says it should
+    WrongType(u32),
+}
+
+
+/// The value of a field, as it was encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    LengthDelimited(&'a [u8]),
+    Fixed32(u32),
+}
+
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Varint(value) | Value::Fixed64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.as_u64().map(|value| value != 0)
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Value::Fixed32(bits) => Some(f32::from_bits(bits)),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::LengthDelimited(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns None if the value isn't length-delimited or isn't valid UTF-8
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|bytes| str::from_utf8(bytes).ok())
+    }
+}
+
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+
+    for shift in 0..10 {
+        let byte = *bytes.get(*position).ok_or(DecodeError::Truncated)?;
+        *position += 1;
+
+        value |= ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(DecodeError::VarintTooLong)
+}
+
+
+fn read_fixed(bytes: &[u8], position: &mut usize, length: usize) -> Result<u64, DecodeError> {
+    let end = *position + length;
+    if end > bytes.len() {
+        return Err(DecodeError::Truncated);
+    }
+
+    // Fixed width values are little endian
+    let value = bytes[*position..end].iter().rev().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+    *position = end;
+    Ok(value)
+}
+
+
+/// Reads the fields of a message in the order they were written, as (field number, value)
+///
+/// Reading stops after the first error.
+pub struct FieldReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+
+impl<'a> FieldReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> FieldReader<'a> {
+        FieldReader {
+            bytes: bytes,
+            position: 0,
+        }
+    }
+
+    fn read_field(&mut self) -> Result<(u32, Value<'a>), DecodeError> {
+        let key = read_varint(self.bytes, &mut self.position)?;
+        let field_number = (key >> 3) as u32;
+
+        let value = match (key & 0x7) as u8 {
+            WIRE_TYPE_VARINT => Value::Varint(read_varint(self.bytes, &mut self.position)?),
+            WIRE_TYPE_FIXED64 => Value::Fixed64(read_fixed(self.bytes, &mut self.position, 8)?),
+            WIRE_TYPE_LENGTH_DELIMITED => {
+                let length = read_varint(self.bytes, &mut self.position)? as usize;
+                let start = self.position;
+                if length > self.bytes.len() - start {
+                    return Err(DecodeError::Truncated);
+                }
+
+                self.position += length;
+                Value::LengthDelimited(&self.bytes[start..self.position])
+            }
+            WIRE_TYPE_FIXED32 => Value::Fixed32(read_fixed(self.bytes, &mut self.position, 4)? as u32),
+            wire_type => return Err(DecodeError::UnsupportedWireType(wire_type)),
+        };
+
+        Ok((field_number, value))
+    }
+}
+
+
+impl<'a> Iterator for FieldReader<'a> {
+    type Item = Result<(u32, Value<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Result<(u32, Value<'a>), DecodeError>> {
+        if self.position >= self.bytes.len() {
+            return None;
+        }
+
+        let result = self.read_field();
+        if result.is_err() {
+            self.position = self.bytes.len();
+        }
+
+        Some(result)
+    }
+}
+
+
+/// Writes the fields of a message
+///
+/// Fields with their default value (zero, false or empty) are left out, apart from embedded
+/// messages, which are always written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageWriter {
+    bytes: Vec<u8>,
+}
+
+
+impl MessageWriter {
+    pub fn new() -> MessageWriter {
+        MessageWriter::default()
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+
+        self.bytes.push(value as u8);
+    }
+
+    fn write_key(&mut self, field_number: u32, wire_type: u8) {
+        self.write_varint(((field_number as u64) << 3) | wire_type as u64);
+    }
+
+    fn write_length_delimited(&mut self, field_number: u32, bytes: &[u8]) {
+        self.write_key(field_number, WIRE_TYPE_LENGTH_DELIMITED);
+        self.write_varint(bytes.len() as u64);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn uint64(&mut self, field_number: u32, value: u64) {
+        if value != 0 {
+            self.write_key(field_number, WIRE_TYPE_VARINT);
+            self.write_varint(value);
+        }
+    }
+
+    pub fn bool(&mut self, field_number: u32, value: bool) {
+        if value {
+            self.write_key(field_number, WIRE_TYPE_VARINT);
+            self.write_varint(1);
+        }
+    }
+
+    pub fn float(&mut self, field_number: u32, value: f32) {
+        if value != 0.0 {
+            self.write_key(field_number, WIRE_TYPE_FIXED32);
+            let bits = value.to_bits();
+            self.bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+        }
+    }
+
+    pub fn bytes(&mut self, field_number: u32, value: &[u8]) {
+        if !value.is_empty() {
+            self.write_length_delimited(field_number, value);
+        }
+    }
+
+    pub fn string(&mut self, field_number: u32, value: &str) {
+        self.bytes(field_number, value.as_bytes());
+    }
+
+    pub fn message(&mut self, field_number: u32, message: &MessageWriter) {
+        self.write_length_delimited(field_number, &message.bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldReader, MessageWriter, Value, DecodeError};
+
+    #[test]
+    fn test_varint() {
+        let mut message = MessageWriter::new();
+        message.uint64(1, 150);
+        assert_eq!(message.into_bytes(), vec![0x08, 0x96, 0x01]);
+
+        let fields = FieldReader::new(&[0x08, 0x96, 0x01]).collect::<Vec<_>>();
+        assert_eq!(fields, vec![Ok((1, Value::Varint(150)))]);
+
+        let mut message = MessageWriter::new();
+        message.uint64(2, u64::max_value());
+        let bytes = message.into_bytes();
+        assert_eq!(bytes.len(), 11);
+        assert_eq!(FieldReader::new(&bytes).next(), Some(Ok((2, Value::Varint(u64::max_value())))));
+    }
+
+    #[test]
+    fn test_message() {
+        let mut hit = MessageWriter::new();
+        hit.string(1, "articles");
+        hit.float(3, 1.5);
+
+        let mut message = MessageWriter::new();
+        message.string(1, "testing");
+        message.uint64(2, 0);
+        message.bool(3, true);
+        message.message(4, &hit);
+        message.message(4, &MessageWriter::new());
+        let bytes = message.into_bytes();
+
+        let fields = FieldReader::new(&bytes).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0], (1, Value::LengthDelimited(b"testing")));
+        assert_eq!(fields[1].0, 3);
+        assert_eq!(fields[1].1.as_bool(), Some(true));
+        assert_eq!(fields[2].0, 4);
+        assert_eq!(fields[3], (4, Value::LengthDelimited(b"")));
+
+        let hit_fields = FieldReader::new(fields[2].1.as_bytes().unwrap()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(hit_fields[0].1.as_str(), Some("articles"));
+        assert_eq!(hit_fields[1], (3, Value::Fixed32(1.5f32.to_bits())));
+        assert_eq!(hit_fields[1].1.as_f32(), Some(1.5));
+    }
+
+    #[test]
+    fn test_invalid() {
+        // A string that's longer than the rest of the message
+        let fields = FieldReader::new(&[0x0a, 0x05, b'a']).collect::<Vec<_>>();
+        assert_eq!(fields, vec![Err(DecodeError::Truncated)]);
+
+        // Wire type 3 is the start of a group
+        let fields = FieldReader::new(&[0x0b, 0x08, 0x01]).collect::<Vec<_>>();
+        assert_eq!(fields, vec![Err(DecodeError::UnsupportedWireType(3))]);
+
+        let fields = FieldReader::new(&[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).collect::<Vec<_>>();
+        assert_eq!(fields, vec![Err(DecodeError::VarintTooLong)]);
+    }
+}
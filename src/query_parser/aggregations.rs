@@ -0,0 +1,1789 @@
+//! Parses the "aggs" element of a search request
+
+use std::f64;
+use std::cmp::Ordering;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value as Json;
+use search::schema::{Schema, FieldId};
+use search::aggregations::{Aggregation, ValueSource};
+use search::aggregations::metric::{Metric, MetricAggregation};
+use search::aggregations::range::{Range, RangeAggregation};
+use search::aggregations::filter::{BucketFilter, FilterAggregation, FiltersAggregation, AdjacencyMatrixAggregation, MAX_ADJACENCY_MATRIX_FILTERS};
+use search::aggregations::missing::MissingAggregation;
+use search::aggregations::global::GlobalAggregation;
+use search::aggregations::significant_terms::{SignificanceHeuristic, SignificantTermsAggregation, Background};
+use search::aggregations::composite::{CompositeAggregation, CompositeSource, CompositeSourceKind, CompositeValue, DateInterval, CalendarUnit};
+use search::aggregations::geo::{GeoDistance, GeohashGridAggregation};
+use search::aggregations::pipeline::{PipelineAggregation, GapPolicy, MovingAverageModel};
+use search::geo::{DistanceType, DistanceUnit, MAX_GEOHASH_PRECISION};
+use search::aggregations::median_absolute_deviation::MedianAbsoluteDeviationAggregation;
+use search::aggregations::weighted_avg::WeightedAvgAggregation;
+use search::aggregations::matrix_stats::{MatrixStatsAggregation, MatrixStatsField};
+use search::aggregations::cardinality::{CardinalityAggregation, DEFAULT_PRECISION_THRESHOLD, MAX_PRECISION_THRESHOLD};
+
+use index::metadata::IndexMetadata;
+use mapping::{FieldType, parse_geo_point};
+use query_parser::{QueryBuildContext, QueryParseError, parse as parse_query};
+use query_parser::script::parse as parse_script;
+use query_parser::utils::parse_string;
+
+
+/// Finds the doc values of a field, checking they can be aggregated
+fn parse_field(json: &Json, numeric: bool, index_metadata: &IndexMetadata) -> Result<FieldId, QueryParseError> {
+    let field_name = json.as_str().ok_or(QueryParseError::ExpectedString)?;
+
+    let field_mapping = match index_metadata.get_field_mapping(field_name) {
+        Some(field_mapping) => field_mapping,
+        None => return Err(QueryParseError::FieldDoesntExist(field_name.to_string())),
+    };
+
+    let is_numeric = match field_mapping.data_type {
+        FieldType::String | FieldType::DenseVector | FieldType::GeoPoint | FieldType::GeoShape => false,
+        FieldType::Integer | FieldType::Boolean | FieldType::Date => true,
+
+        // The doc values of completion fields are the encoded inputs
+        FieldType::Completion => return Err(QueryParseError::InvalidAggregation(format!("field {:?} is a completion field", field_name))),
+    };
+
+    if numeric && !is_numeric {
+        return Err(QueryParseError::InvalidAggregation(format!("field {:?} is not numeric", field_name)));
+    }
+
+    match field_mapping.index_ref {
+        Some(field_id) if field_mapping.has_doc_values => Ok(field_id),
+        _ => Err(QueryParseError::FieldHasNoDocValues(field_name.to_string())),
+    }
+}
+
+
+fn parse_metric(mut metric: Metric, json: &Json, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    // Values are counted rather than added up, so they don't need to be numeric
+    let numeric = metric != Metric::ValueCount;
+
+    let mut source = None;
+    let mut missing = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => source = Some(ValueSource::Field(parse_field(value, numeric, index_metadata)?)),
+            "script" => source = Some(ValueSource::Script(parse_script(value, index_metadata)?)),
+            "missing" => missing = Some(value.as_f64().ok_or(QueryParseError::ExpectedFloat)?),
+            "sigma" => {
+                match metric {
+                    Metric::ExtendedStats { ref mut sigma } => {
+                        match value.as_f64() {
+                            Some(value) if value >= 0.0 => *sigma = value,
+                            _ => return Err(QueryParseError::InvalidAggregation("sigma must not be negative".to_string())),
+                        }
+                    }
+                    _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+                }
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::Metric(MetricAggregation {
+        metric: metric,
+        source: source.ok_or(QueryParseError::ExpectedKey("field"))?,
+        missing: missing,
+    }))
+}
+
+
+fn parse_cardinality(json: &Json, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut source = None;
+    let mut precision_threshold = DEFAULT_PRECISION_THRESHOLD;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => source = Some(ValueSource::Field(parse_field(value, false, index_metadata)?)),
+            "script" => source = Some(ValueSource::Script(parse_script(value, index_metadata)?)),
+            "precision_threshold" => {
+                let threshold = value.as_u64().ok_or(QueryParseError::InvalidValue)?;
+                precision_threshold = threshold.min(MAX_PRECISION_THRESHOLD);
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::Cardinality(CardinalityAggregation {
+        source: source.ok_or(QueryParseError::ExpectedKey("field"))?,
+        precision_threshold: precision_threshold,
+    }))
+}
+
+
+fn parse_median_absolute_deviation(json: &Json, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut source = None;
+    let mut missing = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => source = Some(ValueSource::Field(parse_field(value, true, index_metadata)?)),
+            "script" => source = Some(ValueSource::Script(parse_script(value, index_metadata)?)),
+            "missing" => missing = Some(value.as_f64().ok_or(QueryParseError::ExpectedFloat)?),
+
+            // Every value is kept, so there's no accuracy to trade for memory
+            "compression" => {
+                value.as_f64().ok_or(QueryParseError::ExpectedFloat)?;
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::MedianAbsoluteDeviation(MedianAbsoluteDeviationAggregation {
+        source: source.ok_or(QueryParseError::ExpectedKey("field"))?,
+        missing: missing,
+    }))
+}
+
+
+/// Parses the "value" or "weight" of a weighted_avg aggregation, along with its missing value
+fn parse_weighted_avg_source(json: &Json, index_metadata: &IndexMetadata) -> Result<(ValueSource, Option<f64>), QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut source = None;
+    let mut missing = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => source = Some(ValueSource::Field(parse_field(value, true, index_metadata)?)),
+            "script" => source = Some(ValueSource::Script(parse_script(value, index_metadata)?)),
+            "missing" => missing = Some(value.as_f64().ok_or(QueryParseError::ExpectedFloat)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok((source.ok_or(QueryParseError::ExpectedKey("field"))?, missing))
+}
+
+
+fn parse_weighted_avg(json: &Json, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut value = None;
+    let mut weight = None;
+
+    for (key, inner) in object.iter() {
+        match key.as_ref() {
+            "value" => value = Some(parse_weighted_avg_source(inner, index_metadata)?),
+            "weight" => weight = Some(parse_weighted_avg_source(inner, index_metadata)?),
+            "format" => {
+                parse_string(inner)?;
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let (value, value_missing) = value.ok_or(QueryParseError::ExpectedKey("value"))?;
+    let (weight, weight_missing) = weight.ok_or(QueryParseError::ExpectedKey("weight"))?;
+
+    Ok(Aggregation::WeightedAvg(WeightedAvgAggregation {
+        value: value,
+        value_missing: value_missing,
+        weight: weight,
+        weight_missing: weight_missing,
+    }))
+}
+
+
+fn parse_matrix_stats(json: &Json, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut fields = None;
+    let mut missing = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "fields" => {
+                let fields_json = value.as_array().ok_or(QueryParseError::ExpectedArray)?;
+                let mut parsed_fields = Vec::with_capacity(fields_json.len());
+
+                for field_json in fields_json.iter() {
+                    parsed_fields.push(MatrixStatsField {
+                        name: parse_string(field_json)?,
+                        field: parse_field(field_json, true, index_metadata)?,
+                        missing: None,
+                    });
+                }
+
+                fields = Some(parsed_fields);
+            }
+            "missing" => missing = Some(value.as_object().ok_or(QueryParseError::ExpectedObject)?),
+
+            // Fields only have one value each, so there's nothing to combine
+            "mode" => {
+                match value.as_str() {
+                    Some("avg") | Some("min") | Some("max") | Some("sum") | Some("median") => {}
+                    _ => return Err(QueryParseError::InvalidValue),
+                }
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let mut fields = fields.ok_or(QueryParseError::ExpectedKey("fields"))?;
+    if fields.is_empty() {
+        return Err(QueryParseError::InvalidAggregation("no fields given".to_string()));
+    }
+
+    for (field_name, value) in missing.into_iter().flat_map(|missing| missing.iter()) {
+        let field = match fields.iter_mut().find(|field| field.name == *field_name) {
+            Some(field) => field,
+            None => return Err(QueryParseError::InvalidAggregation(format!("missing value given for field {:?}, which isn't in \"fields\"", field_name))),
+        };
+
+        field.missing = Some(value.as_f64().ok_or(QueryParseError::ExpectedFloat)?);
+    }
+
+    Ok(Aggregation::MatrixStats(MatrixStatsAggregation {
+        fields: fields,
+    }))
+}
+
+
+/// Parses a bound of a date range, which is either a date string or milliseconds since the epoch
+fn parse_date_bound(json: &Json) -> Result<f64, QueryParseError> {
+    if let Some(millis) = json.as_f64() {
+        return Ok(millis);
+    }
+
+    let string = json.as_str().ok_or(QueryParseError::ExpectedString)?;
+
+    // Dates without a time are taken to be midnight UTC
+    let date = match string.parse::<DateTime<Utc>>() {
+        Ok(date) => date,
+        Err(_) => match NaiveDate::parse_from_str(string, "%Y-%m-%d") {
+            Ok(date) => DateTime::from_utc(date.and_hms(0, 0, 0), Utc),
+            Err(_) => return Err(QueryParseError::InvalidAggregation(format!("can't parse date {:?}", string))),
+        },
+    };
+
+    Ok((date.timestamp() * 1000 + date.timestamp_subsec_millis() as i64) as f64)
+}
+
+
+fn parse_bound(json: &Json, dates: bool) -> Result<f64, QueryParseError> {
+    if dates {
+        parse_date_bound(json)
+    } else {
+        json.as_f64().ok_or(QueryParseError::ExpectedFloat)
+    }
+}
+
+
+/// Parses the "ranges" of a range aggregation, sorting them by their bounds
+fn parse_ranges(json: &Json, dates: bool) -> Result<Vec<Range>, QueryParseError> {
+    let ranges_json = json.as_array().ok_or(QueryParseError::ExpectedArray)?;
+    let mut ranges = Vec::with_capacity(ranges_json.len());
+
+    for range_json in ranges_json.iter() {
+        let range_object = range_json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+        let mut from = None;
+        let mut to = None;
+        let mut range_key = None;
+
+        for (key, value) in range_object.iter() {
+            match key.as_ref() {
+                "from" if !value.is_null() => from = Some(parse_bound(value, dates)?),
+                "to" if !value.is_null() => to = Some(parse_bound(value, dates)?),
+                "from" | "to" => {}
+                "key" => range_key = Some(parse_string(value)?),
+                _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+            }
+        }
+
+        let mut range = Range::new(from, to, dates);
+        if let Some(range_key) = range_key {
+            range.key = range_key;
+        }
+
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return Err(QueryParseError::InvalidAggregation("no ranges given".to_string()));
+    }
+
+    // Buckets are returned in order of their bounds, unbounded ends first
+    ranges.sort_by(|a, b| {
+        let from_a = a.from.unwrap_or(f64::NEG_INFINITY);
+        let from_b = b.from.unwrap_or(f64::NEG_INFINITY);
+        let to_a = a.to.unwrap_or(f64::INFINITY);
+        let to_b = b.to.unwrap_or(f64::INFINITY);
+        from_a.partial_cmp(&from_b).unwrap_or(Ordering::Equal).then(to_a.partial_cmp(&to_b).unwrap_or(Ordering::Equal))
+    });
+
+    Ok(ranges)
+}
+
+
+/// Finds the doc values of a geo_point field
+fn parse_geo_point_field(json: &Json, index_metadata: &IndexMetadata) -> Result<FieldId, QueryParseError> {
+    let field_id = parse_field(json, false, index_metadata)?;
+
+    if index_metadata.get_field_mapping_by_ref(field_id).map(|field_mapping| field_mapping.data_type) != Some(FieldType::GeoPoint) {
+        return Err(QueryParseError::InvalidAggregation(format!("field {:?} is not a geo_point", json.as_str().unwrap_or(""))));
+    }
+
+    Ok(field_id)
+}
+
+
+fn parse_geo_distance(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field = None;
+    let mut origin = None;
+    let mut unit = DistanceUnit::Meters;
+    let mut distance_type = DistanceType::Arc;
+    let mut ranges = None;
+    let mut keyed = false;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => field = Some(parse_geo_point_field(value, index_metadata)?),
+            "origin" => origin = Some(parse_geo_point(value).ok_or_else(|| QueryParseError::InvalidAggregation("invalid origin".to_string()))?),
+            "unit" => {
+                let unit_name = value.as_str().ok_or(QueryParseError::ExpectedString)?;
+                unit = DistanceUnit::from_name(unit_name).ok_or_else(|| QueryParseError::InvalidAggregation(format!("unrecognised unit {:?}", unit_name)))?;
+            }
+            "distance_type" => {
+                distance_type = match value.as_str() {
+                    Some("arc") => DistanceType::Arc,
+                    Some("plane") => DistanceType::Plane,
+                    _ => return Err(QueryParseError::InvalidValue),
+                };
+            }
+            "keyed" => keyed = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            "ranges" => ranges = Some(parse_ranges(value, false)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    // Distances are only converted to the unit, so the buckets are just a range aggregation
+    Ok(Aggregation::Range(RangeAggregation {
+        source: ValueSource::GeoDistance(GeoDistance {
+            field: field.ok_or(QueryParseError::ExpectedKey("field"))?,
+            origin: origin.ok_or(QueryParseError::ExpectedKey("origin"))?,
+            unit: unit,
+            distance_type: distance_type,
+        }),
+        missing: None,
+        ranges: ranges.ok_or(QueryParseError::ExpectedKey("ranges"))?,
+        dates: false,
+        keyed: keyed,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_geohash_grid(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field = None;
+    let mut precision = 5;
+    let mut size = 10000;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => field = Some(parse_geo_point_field(value, index_metadata)?),
+            "precision" => {
+                match value.as_u64() {
+                    Some(value) if value >= 1 && value <= MAX_GEOHASH_PRECISION as u64 => precision = value as usize,
+                    _ => return Err(QueryParseError::InvalidAggregation(format!("precision must be between 1 and {}", MAX_GEOHASH_PRECISION))),
+                }
+            }
+            "size" => size = value.as_u64().ok_or(QueryParseError::InvalidValue)? as usize,
+
+            // There's only one shard
+            "shard_size" => {}
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::GeohashGrid(GeohashGridAggregation {
+        field: field.ok_or(QueryParseError::ExpectedKey("field"))?,
+        precision: precision,
+        size: size,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_range(json: &Json, dates: bool, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut source = None;
+    let mut missing = None;
+    let mut ranges = None;
+    let mut keyed = false;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => {
+                let field_id = parse_field(value, true, index_metadata)?;
+
+                if dates && index_metadata.get_field_mapping_by_ref(field_id).map(|field_mapping| field_mapping.data_type) != Some(FieldType::Date) {
+                    return Err(QueryParseError::InvalidAggregation(format!("field {:?} is not a date", value.as_str().unwrap_or(""))));
+                }
+
+                source = Some(ValueSource::Field(field_id));
+            }
+            "script" => source = Some(ValueSource::Script(parse_script(value, index_metadata)?)),
+            "missing" => missing = Some(parse_bound(value, dates)?),
+            "keyed" => keyed = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            "ranges" => ranges = Some(parse_ranges(value, dates)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::Range(RangeAggregation {
+        source: source.ok_or(QueryParseError::ExpectedKey("field"))?,
+        missing: missing,
+        ranges: ranges.ok_or(QueryParseError::ExpectedKey("ranges"))?,
+        dates: dates,
+        keyed: keyed,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_filter(json: &Json, index_metadata: &IndexMetadata, schema: &Schema) -> Result<BucketFilter, QueryParseError> {
+    let query = parse_query(json)?;
+    Ok(BucketFilter::new(query.build(&QueryBuildContext::new().set_index_metadata(index_metadata).no_score(), schema)))
+}
+
+
+fn parse_filters(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut filters = None;
+    let mut keyed = true;
+    let mut other_bucket = false;
+    let mut other_bucket_key = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "filters" => {
+                let mut parsed_filters = Vec::new();
+
+                // Filters given in an array are anonymous, their buckets are returned in an array
+                match *value {
+                    Json::Object(ref filters_object) => {
+                        for (name, filter_json) in filters_object.iter() {
+                            parsed_filters.push((name.clone(), parse_filter(filter_json, index_metadata, schema)?));
+                        }
+                    }
+                    Json::Array(ref filters_array) => {
+                        keyed = false;
+
+                        for (i, filter_json) in filters_array.iter().enumerate() {
+                            parsed_filters.push((i.to_string(), parse_filter(filter_json, index_metadata, schema)?));
+                        }
+                    }
+                    _ => return Err(QueryParseError::ExpectedObject),
+                }
+
+                filters = Some(parsed_filters);
+            }
+            "other_bucket" => other_bucket = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            "other_bucket_key" => other_bucket_key = Some(parse_string(value)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    // Giving a key for the other bucket turns it on
+    if other_bucket && other_bucket_key.is_none() {
+        other_bucket_key = Some("_other_".to_string());
+    }
+
+    Ok(Aggregation::Filters(FiltersAggregation {
+        filters: filters.ok_or(QueryParseError::ExpectedKey("filters"))?,
+        keyed: keyed,
+        other_bucket_key: other_bucket_key,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_adjacency_matrix(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut filters = None;
+    let mut separator = "&".to_string();
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "filters" => {
+                let filters_object = value.as_object().ok_or(QueryParseError::ExpectedObject)?;
+                if filters_object.len() > MAX_ADJACENCY_MATRIX_FILTERS {
+                    return Err(QueryParseError::InvalidAggregation(format!("adjacency_matrix can't have more than {} filters", MAX_ADJACENCY_MATRIX_FILTERS)));
+                }
+
+                // The object's keys are sorted, so the filters are too
+                let mut parsed_filters = Vec::with_capacity(filters_object.len());
+                for (name, filter_json) in filters_object.iter() {
+                    parsed_filters.push((name.clone(), parse_filter(filter_json, index_metadata, schema)?));
+                }
+
+                filters = Some(parsed_filters);
+            }
+            "separator" => separator = parse_string(value)?,
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::AdjacencyMatrix(AdjacencyMatrixAggregation {
+        filters: filters.ok_or(QueryParseError::ExpectedKey("filters"))?,
+        separator: separator,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_missing(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => field = Some(parse_field(value, false, index_metadata)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::Missing(MissingAggregation {
+        field: field.ok_or(QueryParseError::ExpectedKey("field"))?,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_chi_square(json: &Json) -> Result<SignificanceHeuristic, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut include_negatives = false;
+    let mut background_is_superset = true;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "include_negatives" => include_negatives = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            "background_is_superset" => background_is_superset = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(SignificanceHeuristic::ChiSquare {
+        include_negatives: include_negatives,
+        background_is_superset: background_is_superset,
+    })
+}
+
+
+fn parse_significant_terms(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field = None;
+    let mut size = 10;
+    let mut min_doc_count = 3;
+    let mut heuristic = SignificanceHeuristic::Jlh;
+    let mut background_filter = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => field = Some(parse_field(value, false, index_metadata)?),
+            "size" => size = value.as_u64().ok_or(QueryParseError::InvalidValue)? as usize,
+            "min_doc_count" => min_doc_count = value.as_u64().ok_or(QueryParseError::InvalidValue)?,
+            "jlh" => {
+                if value.as_object().map_or(true, |object| !object.is_empty()) {
+                    return Err(QueryParseError::ExpectedObject);
+                }
+
+                heuristic = SignificanceHeuristic::Jlh;
+            }
+            "chi_square" => heuristic = parse_chi_square(value)?,
+            "background_filter" => background_filter = Some(parse_filter(value, index_metadata, schema)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Aggregation::SignificantTerms(SignificantTermsAggregation {
+        field: field.ok_or(QueryParseError::ExpectedKey("field"))?,
+        size: size,
+        min_doc_count: min_doc_count,
+        heuristic: heuristic,
+        background: Background {
+            filter: background_filter,
+            ..Background::default()
+        },
+        aggregations: aggregations,
+    }))
+}
+
+
+/// Parses a date histogram interval
+///
+/// Calendar intervals are given by name ("month") or as a single unit ("1M"). Fixed intervals
+/// are a number of milliseconds, or a number followed by "ms", "s", "m", "h" or "d"
+fn parse_date_interval(json: &Json, calendar: bool) -> Result<DateInterval, QueryParseError> {
+    if let Some(millis) = json.as_i64() {
+        if millis > 0 && !calendar {
+            return Ok(DateInterval::Fixed(millis));
+        }
+
+        return Err(QueryParseError::InvalidAggregation(format!("invalid interval {}", millis)));
+    }
+
+    let interval = json.as_str().ok_or(QueryParseError::ExpectedString)?;
+
+    let unit = match interval {
+        "minute" | "1m" => Some(CalendarUnit::Minute),
+        "hour" | "1h" => Some(CalendarUnit::Hour),
+        "day" | "1d" => Some(CalendarUnit::Day),
+        "week" | "1w" => Some(CalendarUnit::Week),
+        "month" | "1M" => Some(CalendarUnit::Month),
+        "quarter" | "1q" => Some(CalendarUnit::Quarter),
+        "year" | "1y" => Some(CalendarUnit::Year),
+        _ => None,
+    };
+
+    if calendar {
+        return unit.map(DateInterval::Calendar).ok_or_else(|| QueryParseError::InvalidAggregation(format!("invalid calendar interval {:?}", interval)));
+    }
+
+    let (number, millis_per_unit) = if interval.ends_with("ms") {
+        (&interval[..interval.len() - 2], 1)
+    } else if interval.ends_with('s') {
+        (&interval[..interval.len() - 1], 1000)
+    } else if interval.ends_with('m') {
+        (&interval[..interval.len() - 1], 60 * 1000)
+    } else if interval.ends_with('h') {
+        (&interval[..interval.len() - 1], 60 * 60 * 1000)
+    } else if interval.ends_with('d') {
+        (&interval[..interval.len() - 1], 24 * 60 * 60 * 1000)
+    } else {
+        (interval, 1)
+    };
+
+    match number.parse::<i64>() {
+        Ok(number) if number > 0 => Ok(DateInterval::Fixed(number * millis_per_unit)),
+        _ => Err(QueryParseError::InvalidAggregation(format!("invalid fixed interval {:?}", interval))),
+    }
+}
+
+
+fn parse_composite_source(name: &str, json: &Json, index_metadata: &IndexMetadata) -> Result<CompositeSource, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+    if object.len() != 1 {
+        return Err(QueryParseError::ExpectedSingleKey);
+    }
+
+    let (source_type, source_json) = object.iter().next().unwrap();
+    let source_object = source_json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field = None;
+    let mut descending = false;
+    let mut missing_bucket = false;
+    let mut interval = None;
+    let mut date_interval = None;
+
+    for (key, value) in source_object.iter() {
+        match (source_type.as_ref(), key.as_ref()) {
+            (_, "field") => field = Some(parse_field(value, source_type != "terms", index_metadata)?),
+            (_, "order") => {
+                descending = match value.as_str() {
+                    Some("asc") => false,
+                    Some("desc") => true,
+                    _ => return Err(QueryParseError::InvalidValue),
+                };
+            }
+            (_, "missing_bucket") => missing_bucket = value.as_bool().ok_or(QueryParseError::InvalidValue)?,
+            ("histogram", "interval") => {
+                match value.as_f64() {
+                    Some(value) if value > 0.0 => interval = Some(value),
+                    _ => return Err(QueryParseError::InvalidAggregation("histogram interval must be positive".to_string())),
+                }
+            }
+            ("date_histogram", "calendar_interval") => date_interval = Some(parse_date_interval(value, true)?),
+            ("date_histogram", "fixed_interval") => date_interval = Some(parse_date_interval(value, false)?),
+            ("date_histogram", "interval") => {
+                // The old way of giving either, calendar units are preferred
+                date_interval = Some(parse_date_interval(value, true).or_else(|_| parse_date_interval(value, false))?);
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let field = field.ok_or(QueryParseError::ExpectedKey("field"))?;
+
+    let kind = match source_type.as_ref() {
+        "terms" => CompositeSourceKind::Terms,
+        "histogram" => CompositeSourceKind::Histogram(interval.ok_or(QueryParseError::ExpectedKey("interval"))?),
+        "date_histogram" => {
+            if index_metadata.get_field_mapping_by_ref(field).map(|field_mapping| field_mapping.data_type) != Some(FieldType::Date) {
+                return Err(QueryParseError::InvalidAggregation(format!("source {:?} is not on a date field", name)));
+            }
+
+            CompositeSourceKind::DateHistogram(date_interval.ok_or(QueryParseError::ExpectedKey("calendar_interval"))?)
+        }
+        _ => return Err(QueryParseError::InvalidAggregation(format!("unrecognised composite source type {:?}", source_type))),
+    };
+
+    Ok(CompositeSource {
+        name: name.to_string(),
+        field: field,
+        kind: kind,
+        descending: descending,
+        missing_bucket: missing_bucket,
+    })
+}
+
+
+/// Parses the "after" key of a composite aggregation, which must have a value for each source
+fn parse_composite_after(json: &Json, sources: &[CompositeSource]) -> Result<Vec<CompositeValue>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+    let mut after = Vec::with_capacity(sources.len());
+
+    for source in sources.iter() {
+        let value = match object.get(&source.name) {
+            Some(value) => value,
+            None => return Err(QueryParseError::InvalidAggregation(format!("after key is missing {:?}", source.name))),
+        };
+
+        after.push(match (value, &source.kind) {
+            (&Json::Null, _) => CompositeValue::Null,
+            (value, &CompositeSourceKind::Histogram(_)) => CompositeValue::Float(value.as_f64().ok_or(QueryParseError::ExpectedFloat)?),
+            (&Json::Bool(value), _) => CompositeValue::Boolean(value),
+            (&Json::String(ref value), &CompositeSourceKind::Terms) => CompositeValue::String(value.clone()),
+            (&Json::Number(ref number), _) => {
+                match number.as_i64() {
+                    Some(value) => CompositeValue::Integer(value),
+                    None => CompositeValue::Float(number.as_f64().ok_or(QueryParseError::InvalidValue)?),
+                }
+            }
+            _ => return Err(QueryParseError::InvalidValue),
+        });
+    }
+
+    if object.len() != sources.len() {
+        return Err(QueryParseError::InvalidAggregation("after key has values for unknown sources".to_string()));
+    }
+
+    Ok(after)
+}
+
+
+fn parse_composite(json: &Json, aggregations: Vec<(String, Aggregation)>, index_metadata: &IndexMetadata) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut sources = None;
+    let mut size = 10;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "sources" => {
+                let sources_json = value.as_array().ok_or(QueryParseError::ExpectedArray)?;
+                let mut parsed_sources = Vec::with_capacity(sources_json.len());
+
+                for source_json in sources_json.iter() {
+                    let source_object = source_json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+                    if source_object.len() != 1 {
+                        return Err(QueryParseError::ExpectedSingleKey);
+                    }
+
+                    let (name, source_json) = source_object.iter().next().unwrap();
+                    parsed_sources.push(parse_composite_source(name, source_json, index_metadata)?);
+                }
+
+                if parsed_sources.is_empty() {
+                    return Err(QueryParseError::InvalidAggregation("no sources given".to_string()));
+                }
+
+                sources = Some(parsed_sources);
+            }
+            "size" => size = value.as_u64().ok_or(QueryParseError::InvalidValue)? as usize,
+            "after" => {}
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let sources = sources.ok_or(QueryParseError::ExpectedKey("sources"))?;
+
+    // The after key can only be read once the sources are known
+    let after = match object.get("after") {
+        Some(after_json) => Some(parse_composite_after(after_json, &sources)?),
+        None => None,
+    };
+
+    Ok(Aggregation::Composite(CompositeAggregation {
+        sources: sources,
+        size: size,
+        after: after,
+        aggregations: aggregations,
+    }))
+}
+
+
+fn parse_gap_policy(json: &Json) -> Result<GapPolicy, QueryParseError> {
+    match json.as_str() {
+        Some("skip") => Ok(GapPolicy::Skip),
+        Some("insert_zeros") => Ok(GapPolicy::InsertZeros),
+        Some(_) => Err(QueryParseError::InvalidValue),
+        None => Err(QueryParseError::ExpectedString),
+    }
+}
+
+
+fn parse_derivative(json: &Json) -> Result<PipelineAggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut buckets_path = None;
+    let mut gap_policy = GapPolicy::Skip;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "buckets_path" => buckets_path = Some(parse_string(value)?),
+            "gap_policy" => gap_policy = parse_gap_policy(value)?,
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(PipelineAggregation::Derivative {
+        buckets_path: buckets_path.ok_or(QueryParseError::ExpectedKey("buckets_path"))?,
+        gap_policy: gap_policy,
+    })
+}
+
+
+fn parse_cumulative_sum(json: &Json) -> Result<PipelineAggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut buckets_path = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "buckets_path" => buckets_path = Some(parse_string(value)?),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(PipelineAggregation::CumulativeSum {
+        buckets_path: buckets_path.ok_or(QueryParseError::ExpectedKey("buckets_path"))?,
+    })
+}
+
+
+fn parse_moving_avg(json: &Json) -> Result<PipelineAggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut buckets_path = None;
+    let mut window = 5;
+    let mut model_name = "simple";
+    let mut alpha = 0.3;
+    let mut gap_policy = GapPolicy::Skip;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "buckets_path" => buckets_path = Some(parse_string(value)?),
+            "window" => {
+                match value.as_u64() {
+                    Some(value) if value > 0 => window = value as usize,
+                    _ => return Err(QueryParseError::InvalidValue),
+                }
+            }
+            "model" => model_name = value.as_str().ok_or(QueryParseError::ExpectedString)?,
+            "settings" => {
+                let settings = value.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+                for (key, value) in settings.iter() {
+                    match key.as_ref() {
+                        "alpha" => {
+                            match value.as_f64() {
+                                Some(value) if value >= 0.0 && value <= 1.0 => alpha = value,
+                                _ => return Err(QueryParseError::InvalidAggregation("alpha must be between 0 and 1".to_string())),
+                            }
+                        }
+                        _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+                    }
+                }
+            }
+            "gap_policy" => gap_policy = parse_gap_policy(value)?,
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let model = match model_name {
+        "simple" => MovingAverageModel::Simple,
+        "linear" => MovingAverageModel::Linear,
+        "ewma" => MovingAverageModel::Ewma { alpha: alpha },
+        _ => return Err(QueryParseError::InvalidAggregation(format!("unrecognised moving average model {:?}", model_name))),
+    };
+
+    Ok(PipelineAggregation::MovingAvg {
+        buckets_path: buckets_path.ok_or(QueryParseError::ExpectedKey("buckets_path"))?,
+        window: window,
+        model: model,
+        gap_policy: gap_policy,
+    })
+}
+
+
+/// Parses a bucket_sort's sort, which is a list of paths. Each one is either on its own (and
+/// sorted ascending) or an object with the path as its key and the order as its value
+fn parse_bucket_sort_sort(json: &Json) -> Result<Vec<(String, bool)>, QueryParseError> {
+    let parse_item = |json: &Json| {
+        if let Some(path) = json.as_str() {
+            return Ok((path.to_string(), false));
+        }
+
+        let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+        if object.len() != 1 {
+            return Err(QueryParseError::ExpectedSingleKey);
+        }
+
+        let (path, order) = object.iter().next().unwrap();
+        let order = match *order {
+            Json::Object(ref order) => order.get("order").ok_or(QueryParseError::ExpectedKey("order"))?,
+            ref order => order,
+        };
+
+        match order.as_str() {
+            Some("asc") => Ok((path.clone(), false)),
+            Some("desc") => Ok((path.clone(), true)),
+            Some(_) => Err(QueryParseError::InvalidValue),
+            None => Err(QueryParseError::ExpectedString),
+        }
+    };
+
+    match *json {
+        Json::Array(ref items) => items.iter().map(parse_item).collect(),
+        ref item => Ok(vec![parse_item(item)?]),
+    }
+}
+
+
+fn parse_bucket_sort(json: &Json) -> Result<PipelineAggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut sort = Vec::new();
+    let mut from = 0;
+    let mut size = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "sort" => sort = parse_bucket_sort_sort(value)?,
+            "from" => from = value.as_u64().ok_or(QueryParseError::InvalidValue)? as usize,
+            "size" => size = Some(value.as_u64().ok_or(QueryParseError::InvalidValue)? as usize),
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(PipelineAggregation::BucketSort {
+        sort: sort,
+        from: from,
+        size: size,
+    })
+}
+
+
+/// Checks the pipelines in an aggregation's sub-aggregations can run on its buckets
+///
+/// Pipelines need a multi-bucket parent, and must read from "_count" or one of their siblings.
+fn check_pipelines(aggregation: &Aggregation, sub_aggregations: &[(String, Aggregation)]) -> Result<(), QueryParseError> {
+    for &(_, ref sub_aggregation) in sub_aggregations.iter() {
+        let pipeline = match *sub_aggregation {
+            Aggregation::Pipeline(ref pipeline) => pipeline,
+            _ => continue,
+        };
+
+        if !aggregation.is_multi_bucket() {
+            return Err(QueryParseError::InvalidAggregation("pipeline aggregations must be inside a multi-bucket aggregation".to_string()));
+        }
+
+        for path in pipeline.buckets_paths() {
+            let name = path.split(|c| c == '.' || c == '>').next().unwrap_or("");
+
+            if name != "_count" && !sub_aggregations.iter().any(|&(ref sibling_name, _)| sibling_name == name) {
+                return Err(QueryParseError::InvalidAggregation(format!("buckets_path {:?} doesn't refer to a sibling aggregation", path)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+fn parse_aggregation(json: &Json, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Aggregation, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut aggregation_type = None;
+    let mut sub_aggregations_json = None;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "aggs" | "aggregations" => sub_aggregations_json = Some(value),
+            "meta" => {}
+            _ => {
+                if aggregation_type.is_some() {
+                    return Err(QueryParseError::InvalidAggregation(format!("found two aggregation types: {:?}", key)));
+                }
+
+                aggregation_type = Some((key.as_ref(), value));
+            }
+        }
+    }
+
+    let (aggregation_type, aggregation_json) = match aggregation_type {
+        Some(aggregation_type) => aggregation_type,
+        None => return Err(QueryParseError::InvalidAggregation("missing aggregation type".to_string())),
+    };
+
+    // Nested objects aren't indexed as separate documents yet, so there would be nothing to aggregate
+    if aggregation_type == "nested" || aggregation_type == "reverse_nested" {
+        return Err(QueryParseError::InvalidAggregation(format!("{} aggregations aren't supported, as nested objects aren't indexed yet", aggregation_type)));
+    }
+
+    let sub_aggregations = match sub_aggregations_json {
+        Some(sub_aggregations_json) => parse_aggregations(sub_aggregations_json, index_metadata, schema)?,
+        None => Vec::new(),
+    };
+
+    // Metric aggregations don't have any buckets to run sub-aggregations in
+    let check_no_sub_aggregations = || {
+        if sub_aggregations_json.is_some() {
+            Err(QueryParseError::InvalidAggregation("metric aggregations can't have sub-aggregations".to_string()))
+        } else {
+            Ok(())
+        }
+    };
+
+    // Pipelines run on their parent's buckets, so don't have any of their own
+    let parse_pipeline = |parse: fn(&Json) -> Result<PipelineAggregation, QueryParseError>| {
+        if sub_aggregations_json.is_some() {
+            return Err(QueryParseError::InvalidAggregation("pipeline aggregations can't have sub-aggregations".to_string()));
+        }
+
+        Ok(Aggregation::Pipeline(parse(aggregation_json)?))
+    };
+
+    let aggregation = match aggregation_type {
+        "range" => parse_range(aggregation_json, false, sub_aggregations, index_metadata),
+        "date_range" => parse_range(aggregation_json, true, sub_aggregations, index_metadata),
+        "filter" => {
+            Ok(Aggregation::Filter(FilterAggregation {
+                filter: parse_filter(aggregation_json, index_metadata, schema)?,
+                aggregations: sub_aggregations,
+            }))
+        }
+        "filters" => parse_filters(aggregation_json, sub_aggregations, index_metadata, schema),
+        "adjacency_matrix" => parse_adjacency_matrix(aggregation_json, sub_aggregations, index_metadata, schema),
+        "missing" => parse_missing(aggregation_json, sub_aggregations, index_metadata),
+        "global" => {
+            if aggregation_json.as_object().map_or(true, |object| !object.is_empty()) {
+                return Err(QueryParseError::ExpectedObject);
+            }
+
+            Ok(Aggregation::Global(GlobalAggregation {
+                aggregations: sub_aggregations,
+            }))
+        }
+        "geo_distance" => parse_geo_distance(aggregation_json, sub_aggregations, index_metadata),
+        "geohash_grid" => parse_geohash_grid(aggregation_json, sub_aggregations, index_metadata),
+        "composite" => parse_composite(aggregation_json, sub_aggregations, index_metadata),
+        "significant_terms" => parse_significant_terms(aggregation_json, sub_aggregations, index_metadata, schema),
+        "derivative" => parse_pipeline(parse_derivative),
+        "cumulative_sum" => parse_pipeline(parse_cumulative_sum),
+        "moving_avg" => parse_pipeline(parse_moving_avg),
+        "bucket_sort" => parse_pipeline(parse_bucket_sort),
+        "cardinality" => {
+            check_no_sub_aggregations()?;
+            parse_cardinality(aggregation_json, index_metadata)
+        }
+        "median_absolute_deviation" => {
+            check_no_sub_aggregations()?;
+            parse_median_absolute_deviation(aggregation_json, index_metadata)
+        }
+        "weighted_avg" => {
+            check_no_sub_aggregations()?;
+            parse_weighted_avg(aggregation_json, index_metadata)
+        }
+        "matrix_stats" => {
+            check_no_sub_aggregations()?;
+            parse_matrix_stats(aggregation_json, index_metadata)
+        }
+        _ => {
+            let metric = match Metric::from_name(aggregation_type) {
+                Some(metric) => metric,
+                None => return Err(QueryParseError::UnrecognisedAggregationType(aggregation_type.to_string())),
+            };
+
+            check_no_sub_aggregations()?;
+            parse_metric(metric, aggregation_json, index_metadata)
+        }
+    }?;
+
+    // Global aggregations ignore the documents they're given, so only make sense at the top
+    if aggregation.sub_aggregations().iter().any(|&(_, ref sub_aggregation)| is_global(sub_aggregation)) {
+        return Err(QueryParseError::InvalidAggregation("global aggregations must be at the top level".to_string()));
+    }
+
+    check_pipelines(&aggregation, aggregation.sub_aggregations())?;
+    Ok(aggregation)
+}
+
+
+fn is_global(aggregation: &Aggregation) -> bool {
+    match *aggregation {
+        Aggregation::Global(_) => true,
+        _ => false,
+    }
+}
+
+
+fn parse_aggregations(json: &Json, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Vec<(String, Aggregation)>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+    let mut aggregations = Vec::with_capacity(object.len());
+
+    for (name, value) in object.iter() {
+        aggregations.push((name.clone(), parse_aggregation(value, index_metadata, schema)?));
+    }
+
+    Ok(aggregations)
+}
+
+
+/// Parses "aggs", which maps the name of each aggregation to its definition
+///
+/// The schema is used to build the queries of filter aggregations
+pub fn parse(json: &Json, index_metadata: &IndexMetadata, schema: &Schema) -> Result<Vec<(String, Aggregation)>, QueryParseError> {
+    let aggregations = parse_aggregations(json, index_metadata, schema)?;
+
+    let is_pipeline = |aggregation: &Aggregation| match *aggregation {
+        Aggregation::Pipeline(_) => true,
+        _ => false,
+    };
+
+    if aggregations.iter().any(|&(_, ref aggregation)| is_pipeline(aggregation)) {
+        return Err(QueryParseError::InvalidAggregation("pipeline aggregations must be inside a multi-bucket aggregation".to_string()));
+    }
+
+    Ok(aggregations)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::{Schema, FieldId};
+    use search::query::Query;
+    use search::script::{Script, BinaryOperator};
+    use search::aggregations::{Aggregation, ValueSource};
+    use search::aggregations::metric::{Metric, MetricAggregation};
+    use search::aggregations::range::{Range, RangeAggregation};
+    use search::aggregations::cardinality::CardinalityAggregation;
+    use search::aggregations::median_absolute_deviation::MedianAbsoluteDeviationAggregation;
+    use search::aggregations::weighted_avg::WeightedAvgAggregation;
+    use search::aggregations::matrix_stats::{MatrixStatsAggregation, MatrixStatsField};
+    use search::aggregations::filter::{BucketFilter, FilterAggregation, FiltersAggregation, AdjacencyMatrixAggregation};
+    use search::aggregations::missing::MissingAggregation;
+    use search::aggregations::global::GlobalAggregation;
+    use search::aggregations::significant_terms::{SignificanceHeuristic, SignificantTermsAggregation, Background};
+    use search::aggregations::composite::{CompositeAggregation, CompositeSource, CompositeSourceKind, CompositeValue, DateInterval, CalendarUnit};
+    use search::aggregations::geo::{GeoDistance, GeohashGridAggregation};
+    use search::aggregations::pipeline::{PipelineAggregation, GapPolicy, MovingAverageModel};
+    use search::geo::{GeoPoint, DistanceType, DistanceUnit};
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use query_parser::QueryParseError;
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::parse;
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut price_mapping = field_mapping(1, FieldType::Integer);
+        price_mapping.has_doc_values = true;
+
+        let mut tag_mapping = field_mapping(2, FieldType::String);
+        tag_mapping.has_doc_values = true;
+
+        let mut published_mapping = field_mapping(4, FieldType::Date);
+        published_mapping.has_doc_values = true;
+
+        let mut location_mapping = field_mapping(5, FieldType::GeoPoint);
+        location_mapping.has_doc_values = true;
+
+        index_metadata(vec![
+            ("price", price_mapping),
+            ("tag", tag_mapping),
+            ("title", field_mapping(3, FieldType::String)),
+            ("published", published_mapping),
+            ("location", location_mapping),
+        ])
+    }
+
+    #[test]
+    fn test_metrics() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "max_price": {"max": {"field": "price"}},
+            "total": {"sum": {"script": "doc['price'].value * 2", "missing": 1}},
+            "tags": {"value_count": {"field": "tag"}},
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("max_price".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::Max,
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+            })),
+            ("tags".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::ValueCount,
+                source: ValueSource::Field(FieldId(2)),
+                missing: None,
+            })),
+            ("total".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::Sum,
+                source: ValueSource::Script(Script::BinaryOp(BinaryOperator::Multiply, Box::new(Script::DocValue(FieldId(1))), Box::new(Script::Number(2.0)))),
+                missing: Some(1.0),
+            })),
+        ]));
+    }
+
+    #[test]
+    fn test_extended_stats() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "price_stats": {"extended_stats": {"field": "price", "sigma": 3}},
+            "price_spread": {"median_absolute_deviation": {"field": "price", "compression": 100}},
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("price_spread".to_string(), Aggregation::MedianAbsoluteDeviation(MedianAbsoluteDeviationAggregation {
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+            })),
+            ("price_stats".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::ExtendedStats { sigma: 3.0 },
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"stats": {"field": "price", "sigma": 3}}})), QueryParseError::UnrecognisedKey("sigma".to_string()));
+        assert_eq!(error(json!({"foo": {"extended_stats": {"field": "price", "sigma": -1}}})), QueryParseError::InvalidAggregation("sigma must not be negative".to_string()));
+        assert_eq!(error(json!({"foo": {"median_absolute_deviation": {"field": "tag"}}})), QueryParseError::InvalidAggregation("field \"tag\" is not numeric".to_string()));
+    }
+
+    #[test]
+    fn test_weighted_avg() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "avg_price": {
+                "weighted_avg": {
+                    "value": {"field": "price", "missing": 10},
+                    "weight": {"field": "published"},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("avg_price".to_string(), Aggregation::WeightedAvg(WeightedAvgAggregation {
+                value: ValueSource::Field(FieldId(1)),
+                value_missing: Some(10.0),
+                weight: ValueSource::Field(FieldId(4)),
+                weight_missing: None,
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"weighted_avg": {"value": {"field": "price"}}}})), QueryParseError::ExpectedKey("weight"));
+        assert_eq!(error(json!({"foo": {"weighted_avg": {"value": {"field": "price"}, "weight": {"field": "tag"}}}})), QueryParseError::InvalidAggregation("field \"tag\" is not numeric".to_string()));
+    }
+
+    #[test]
+    fn test_matrix_stats() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "statistics": {
+                "matrix_stats": {
+                    "fields": ["price", "published"],
+                    "missing": {"published": 0},
+                    "mode": "avg",
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("statistics".to_string(), Aggregation::MatrixStats(MatrixStatsAggregation {
+                fields: vec![
+                    MatrixStatsField {
+                        name: "price".to_string(),
+                        field: FieldId(1),
+                        missing: None,
+                    },
+                    MatrixStatsField {
+                        name: "published".to_string(),
+                        field: FieldId(4),
+                        missing: Some(0.0),
+                    },
+                ],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"matrix_stats": {"fields": []}}})), QueryParseError::InvalidAggregation("no fields given".to_string()));
+        assert_eq!(error(json!({"foo": {"matrix_stats": {"fields": ["price"], "missing": {"published": 0}}}})),
+                   QueryParseError::InvalidAggregation("missing value given for field \"published\", which isn't in \"fields\"".to_string()));
+    }
+
+    #[test]
+    fn test_range() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "prices": {
+                "range": {
+                    "field": "price",
+                    "ranges": [{"from": 100, "key": "expensive"}, {"to": 100}],
+                    "keyed": true,
+                },
+                "aggs": {
+                    "max_price": {"max": {"field": "price"}},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        // Ranges are sorted by their bounds
+        assert_eq!(aggregations, Ok(vec![
+            ("prices".to_string(), Aggregation::Range(RangeAggregation {
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+                ranges: vec![
+                    Range::new(None, Some(100.0), false),
+                    Range {
+                        key: "expensive".to_string(),
+                        from: Some(100.0),
+                        to: None,
+                    },
+                ],
+                dates: false,
+                keyed: true,
+                aggregations: vec![
+                    ("max_price".to_string(), Aggregation::Metric(MetricAggregation {
+                        metric: Metric::Max,
+                        source: ValueSource::Field(FieldId(1)),
+                        missing: None,
+                    })),
+                ],
+            })),
+        ]));
+    }
+
+    #[test]
+    fn test_date_range() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "published": {
+                "date_range": {
+                    "field": "published",
+                    "ranges": [{"from": "2015-01-01", "to": "2016-01-01T00:00:00Z"}, {"from": 1451606400000i64}],
+                },
+            },
+        }), &index_metadata, &Schema::new()).unwrap();
+
+        match aggregations[0].1 {
+            Aggregation::Range(ref range) => {
+                assert_eq!(range.ranges, vec![
+                    Range::new(Some(1420070400000.0), Some(1451606400000.0), true),
+                    Range::new(Some(1451606400000.0), None, true),
+                ]);
+                assert_eq!(range.ranges[0].key, "2015-01-01T00:00:00.000Z-2016-01-01T00:00:00.000Z");
+            }
+            ref aggregation => panic!("expected a range aggregation, got {:?}", aggregation),
+        }
+
+        assert_eq!(parse(&json!({"foo": {"date_range": {"field": "price", "ranges": [{"to": "now"}]}}}), &index_metadata, &Schema::new()), Err(QueryParseError::InvalidAggregation("field \"price\" is not a date".to_string())));
+        assert_eq!(parse(&json!({"foo": {"date_range": {"field": "published", "ranges": [{"to": "yesterday"}]}}}), &index_metadata, &Schema::new()), Err(QueryParseError::InvalidAggregation("can't parse date \"yesterday\"".to_string())));
+    }
+
+    #[test]
+    fn test_cardinality() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "tags": {"cardinality": {"field": "tag"}},
+            "prices": {"cardinality": {"field": "price", "precision_threshold": 100000}},
+        }), &index_metadata, &Schema::new());
+
+        // Thresholds are capped at 40000
+        assert_eq!(aggregations, Ok(vec![
+            ("prices".to_string(), Aggregation::Cardinality(CardinalityAggregation {
+                source: ValueSource::Field(FieldId(1)),
+                precision_threshold: 40000,
+            })),
+            ("tags".to_string(), Aggregation::Cardinality(CardinalityAggregation {
+                source: ValueSource::Field(FieldId(2)),
+                precision_threshold: 3000,
+            })),
+        ]));
+    }
+
+    #[test]
+    fn test_filter() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "everything": {
+                "filter": {"match_all": {}},
+                "aggs": {
+                    "max_price": {"max": {"field": "price"}},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("everything".to_string(), Aggregation::Filter(FilterAggregation {
+                filter: BucketFilter::new(Query::all()),
+                aggregations: vec![
+                    ("max_price".to_string(), Aggregation::Metric(MetricAggregation {
+                        metric: Metric::Max,
+                        source: ValueSource::Field(FieldId(1)),
+                        missing: None,
+                    })),
+                ],
+            })),
+        ]));
+    }
+
+    #[test]
+    fn test_filters() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "named": {
+                "filters": {
+                    "filters": {"all": {"match_all": {}}, "none": {"match_none": {}}},
+                    "other_bucket": true,
+                },
+            },
+            "anonymous": {
+                "filters": {
+                    "filters": [{"match_all": {}}],
+                    "other_bucket_key": "rest",
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("anonymous".to_string(), Aggregation::Filters(FiltersAggregation {
+                filters: vec![("0".to_string(), BucketFilter::new(Query::all()))],
+                keyed: false,
+                other_bucket_key: Some("rest".to_string()),
+                aggregations: vec![],
+            })),
+            ("named".to_string(), Aggregation::Filters(FiltersAggregation {
+                filters: vec![
+                    ("all".to_string(), BucketFilter::new(Query::all())),
+                    ("none".to_string(), BucketFilter::new(Query::None)),
+                ],
+                keyed: true,
+                other_bucket_key: Some("_other_".to_string()),
+                aggregations: vec![],
+            })),
+        ]));
+    }
+
+    #[test]
+    fn test_adjacency_matrix() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "interactions": {
+                "adjacency_matrix": {
+                    "filters": {"b": {"match_none": {}}, "a": {"match_all": {}}},
+                    "separator": "|",
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("interactions".to_string(), Aggregation::AdjacencyMatrix(AdjacencyMatrixAggregation {
+                filters: vec![
+                    ("a".to_string(), BucketFilter::new(Query::all())),
+                    ("b".to_string(), BucketFilter::new(Query::None)),
+                ],
+                separator: "|".to_string(),
+                aggregations: vec![],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"adjacency_matrix": {"filters": [{"match_all": {}}]}}})), QueryParseError::ExpectedObject);
+        assert_eq!(error(json!({"foo": {"adjacency_matrix": {}}})), QueryParseError::ExpectedKey("filters"));
+    }
+
+    #[test]
+    fn test_missing_and_global() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "all_products": {
+                "global": {},
+                "aggs": {
+                    "untagged": {"missing": {"field": "tag"}},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("all_products".to_string(), Aggregation::Global(GlobalAggregation {
+                aggregations: vec![
+                    ("untagged".to_string(), Aggregation::Missing(MissingAggregation {
+                        field: FieldId(2),
+                        aggregations: vec![],
+                    })),
+                ],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"missing": {"field": "tag"}, "aggs": {"bar": {"global": {}}}}})), QueryParseError::InvalidAggregation("global aggregations must be at the top level".to_string()));
+        assert_eq!(error(json!({"foo": {"global": {"field": "tag"}}})), QueryParseError::ExpectedObject);
+    }
+
+    #[test]
+    fn test_nested() {
+        let index_metadata = make_index_metadata();
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+
+        // Nested objects aren't indexed yet, so these are rejected rather than aggregating nothing
+        assert_eq!(error(json!({"foo": {"nested": {"path": "comments"}}})), QueryParseError::InvalidAggregation("nested aggregations aren't supported, as nested objects aren't indexed yet".to_string()));
+        assert_eq!(error(json!({"foo": {"reverse_nested": {}}})), QueryParseError::InvalidAggregation("reverse_nested aggregations aren't supported, as nested objects aren't indexed yet".to_string()));
+        assert_eq!(error(json!({"foo": {"missing": {"field": "tag"}, "aggs": {"bar": {"nested": {"path": "comments"}}}}})), QueryParseError::InvalidAggregation("nested aggregations aren't supported, as nested objects aren't indexed yet".to_string()));
+    }
+
+    #[test]
+    fn test_significant_terms() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "tags": {
+                "significant_terms": {
+                    "field": "tag",
+                    "size": 5,
+                    "chi_square": {"include_negatives": true},
+                    "background_filter": {"match_all": {}},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("tags".to_string(), Aggregation::SignificantTerms(SignificantTermsAggregation {
+                field: FieldId(2),
+                size: 5,
+                min_doc_count: 3,
+                heuristic: SignificanceHeuristic::ChiSquare {
+                    include_negatives: true,
+                    background_is_superset: true,
+                },
+                background: Background {
+                    filter: Some(BucketFilter::new(Query::all())),
+                    ..Background::default()
+                },
+                aggregations: vec![],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"significant_terms": {}}})), QueryParseError::ExpectedKey("field"));
+        assert_eq!(error(json!({"foo": {"significant_terms": {"field": "tag", "chi_square": {"foo": true}}}})), QueryParseError::UnrecognisedKey("foo".to_string()));
+    }
+
+    #[test]
+    fn test_composite() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "pages": {
+                "composite": {
+                    "size": 100,
+                    "sources": [
+                        {"tag": {"terms": {"field": "tag", "missing_bucket": true}}},
+                        {"price": {"histogram": {"field": "price", "interval": 5, "order": "desc"}}},
+                        {"month": {"date_histogram": {"field": "published", "calendar_interval": "month"}}},
+                        {"hour": {"date_histogram": {"field": "published", "fixed_interval": "12h"}}},
+                    ],
+                    "after": {"tag": null, "price": 10, "month": 1493596800000i64, "hour": 1495022400000i64},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        let source = |name: &str, field, kind, descending, missing_bucket| CompositeSource {
+            name: name.to_string(),
+            field: FieldId(field),
+            kind: kind,
+            descending: descending,
+            missing_bucket: missing_bucket,
+        };
+
+        assert_eq!(aggregations, Ok(vec![
+            ("pages".to_string(), Aggregation::Composite(CompositeAggregation {
+                sources: vec![
+                    source("tag", 2, CompositeSourceKind::Terms, false, true),
+                    source("price", 1, CompositeSourceKind::Histogram(5.0), true, false),
+                    source("month", 4, CompositeSourceKind::DateHistogram(DateInterval::Calendar(CalendarUnit::Month)), false, false),
+                    source("hour", 4, CompositeSourceKind::DateHistogram(DateInterval::Fixed(12 * 60 * 60 * 1000)), false, false),
+                ],
+                size: 100,
+                after: Some(vec![
+                    CompositeValue::Null,
+                    CompositeValue::Float(10.0),
+                    CompositeValue::Integer(1493596800000),
+                    CompositeValue::Integer(1495022400000),
+                ]),
+                aggregations: vec![],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"composite": {"sources": [{"d": {"date_histogram": {"field": "price", "fixed_interval": "1d"}}}]}}})), QueryParseError::InvalidAggregation("source \"d\" is not on a date field".to_string()));
+        assert_eq!(error(json!({"foo": {"composite": {"sources": [{"d": {"date_histogram": {"field": "published", "calendar_interval": "2d"}}}]}}})), QueryParseError::InvalidAggregation("invalid calendar interval \"2d\"".to_string()));
+        assert_eq!(error(json!({"foo": {"composite": {"sources": [{"t": {"terms": {"field": "tag"}}}], "after": {"x": 1}}}})), QueryParseError::InvalidAggregation("after key is missing \"t\"".to_string()));
+    }
+
+    #[test]
+    fn test_geo_distance() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "rings": {
+                "geo_distance": {
+                    "field": "location",
+                    "origin": "51.5, -0.12",
+                    "unit": "km",
+                    "ranges": [{"from": 100}, {"to": 100}],
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("rings".to_string(), Aggregation::Range(RangeAggregation {
+                source: ValueSource::GeoDistance(GeoDistance {
+                    field: FieldId(5),
+                    origin: GeoPoint::new(51.5, -0.12).unwrap(),
+                    unit: DistanceUnit::Kilometers,
+                    distance_type: DistanceType::Arc,
+                }),
+                missing: None,
+                ranges: vec![Range::new(None, Some(100.0), false), Range::new(Some(100.0), None, false)],
+                dates: false,
+                keyed: false,
+                aggregations: vec![],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"geo_distance": {"field": "price", "origin": [0, 0], "ranges": [{"to": 1}]}}})), QueryParseError::InvalidAggregation("field \"price\" is not a geo_point".to_string()));
+        assert_eq!(error(json!({"foo": {"geo_distance": {"field": "location", "origin": {"lat": 100, "lon": 0}, "ranges": [{"to": 1}]}}})), QueryParseError::InvalidAggregation("invalid origin".to_string()));
+        assert_eq!(error(json!({"foo": {"min": {"field": "location"}}})), QueryParseError::InvalidAggregation("field \"location\" is not numeric".to_string()));
+    }
+
+    #[test]
+    fn test_geohash_grid() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "cells": {"geohash_grid": {"field": "location", "precision": 3}},
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("cells".to_string(), Aggregation::GeohashGrid(GeohashGridAggregation {
+                field: FieldId(5),
+                precision: 3,
+                size: 10000,
+                aggregations: vec![],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"geohash_grid": {"field": "location", "precision": 13}}})), QueryParseError::InvalidAggregation("precision must be between 1 and 12".to_string()));
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let index_metadata = make_index_metadata();
+        let aggregations = parse(&json!({
+            "months": {
+                "composite": {
+                    "sources": [{"month": {"date_histogram": {"field": "published", "calendar_interval": "month"}}}],
+                },
+                "aggs": {
+                    "sales": {"sum": {"field": "price"}},
+                    "sales_change": {"derivative": {"buckets_path": "sales", "gap_policy": "insert_zeros"}},
+                    "total_sales": {"cumulative_sum": {"buckets_path": "sales"}},
+                    "trend": {"moving_avg": {"buckets_path": "_count", "window": 3, "model": "ewma", "settings": {"alpha": 0.5}}},
+                    "top": {"bucket_sort": {"sort": [{"sales": {"order": "desc"}}, "_count"], "size": 3}},
+                },
+            },
+        }), &index_metadata, &Schema::new());
+
+        assert_eq!(aggregations, Ok(vec![
+            ("months".to_string(), Aggregation::Composite(CompositeAggregation {
+                sources: vec![CompositeSource {
+                    name: "month".to_string(),
+                    field: FieldId(4),
+                    kind: CompositeSourceKind::DateHistogram(DateInterval::Calendar(CalendarUnit::Month)),
+                    descending: false,
+                    missing_bucket: false,
+                }],
+                size: 10,
+                after: None,
+                aggregations: vec![
+                    ("sales".to_string(), Aggregation::Metric(MetricAggregation {
+                        metric: Metric::Sum,
+                        source: ValueSource::Field(FieldId(1)),
+                        missing: None,
+                    })),
+                    ("sales_change".to_string(), Aggregation::Pipeline(PipelineAggregation::Derivative {
+                        buckets_path: "sales".to_string(),
+                        gap_policy: GapPolicy::InsertZeros,
+                    })),
+                    ("top".to_string(), Aggregation::Pipeline(PipelineAggregation::BucketSort {
+                        sort: vec![("sales".to_string(), true), ("_count".to_string(), false)],
+                        from: 0,
+                        size: Some(3),
+                    })),
+                    ("total_sales".to_string(), Aggregation::Pipeline(PipelineAggregation::CumulativeSum {
+                        buckets_path: "sales".to_string(),
+                    })),
+                    ("trend".to_string(), Aggregation::Pipeline(PipelineAggregation::MovingAvg {
+                        buckets_path: "_count".to_string(),
+                        window: 3,
+                        model: MovingAverageModel::Ewma { alpha: 0.5 },
+                        gap_policy: GapPolicy::Skip,
+                    })),
+                ],
+            })),
+        ]));
+
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+        assert_eq!(error(json!({"foo": {"cumulative_sum": {"buckets_path": "_count"}}})), QueryParseError::InvalidAggregation("pipeline aggregations must be inside a multi-bucket aggregation".to_string()));
+        assert_eq!(error(json!({"foo": {"filter": {"match_all": {}}, "aggs": {"bar": {"cumulative_sum": {"buckets_path": "_count"}}}}})), QueryParseError::InvalidAggregation("pipeline aggregations must be inside a multi-bucket aggregation".to_string()));
+        assert_eq!(error(json!({"foo": {"geohash_grid": {"field": "location"}, "aggs": {"bar": {"derivative": {"buckets_path": "missing.avg"}}}}})), QueryParseError::InvalidAggregation("buckets_path \"missing.avg\" doesn't refer to a sibling aggregation".to_string()));
+        assert_eq!(error(json!({"foo": {"geohash_grid": {"field": "location"}, "aggs": {"bar": {"moving_avg": {"buckets_path": "_count", "model": "holt"}}}}})), QueryParseError::InvalidAggregation("unrecognised moving average model \"holt\"".to_string()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let index_metadata = make_index_metadata();
+        let error = |json| parse(&json, &index_metadata, &Schema::new()).unwrap_err();
+
+        assert_eq!(error(json!({"foo": {"median": {"field": "price"}}})), QueryParseError::UnrecognisedAggregationType("median".to_string()));
+        assert_eq!(error(json!({"foo": {"min": {"field": "missing"}}})), QueryParseError::FieldDoesntExist("missing".to_string()));
+        assert_eq!(error(json!({"foo": {"min": {"field": "tag"}}})), QueryParseError::InvalidAggregation("field \"tag\" is not numeric".to_string()));
+        assert_eq!(error(json!({"foo": {"value_count": {"field": "title"}}})), QueryParseError::FieldHasNoDocValues("title".to_string()));
+        assert_eq!(error(json!({"foo": {"min": {}}})), QueryParseError::ExpectedKey("field"));
+        assert_eq!(error(json!({"foo": {}})), QueryParseError::InvalidAggregation("missing aggregation type".to_string()));
+        assert_eq!(error(json!({"foo": {"min": {"field": "price"}, "aggs": {}}})), QueryParseError::InvalidAggregation("metric aggregations can't have sub-aggregations".to_string()));
+    }
+}
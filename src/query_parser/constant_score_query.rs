@@ -20,6 +20,10 @@ impl QueryBuilder for ConstantScoreQueryBuilder {
             filter: Box::new(self.filter.build(&context.clone().no_score(), schema)),
         }
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        self.filter.check_fields(schema)
+    }
 }
 
 pub fn parse(json: &Json) -> Result<Box<QueryBuilder>, QueryParseError> {
@@ -26,6 +26,14 @@ impl QueryBuilder for FilteredQueryBuilder {
             filter: Box::new(self.filter.build(&context.clone().no_score(), schema)),
         }
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        if let Some(ref query) = self.query {
+            query.check_fields(schema)?;
+        }
+
+        self.filter.check_fields(schema)
+    }
 }
 
 
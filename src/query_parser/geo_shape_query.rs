@@ -0,0 +1,252 @@
+//! Parses "geo_shape" queries
+//!
+//! The query's shape is split into geohash cells in the same way as the shapes of the field
+//! (see `search::geo_shape`), and compared with the cells that each document was indexed with.
+
+use serde_json::Value as Json;
+use search::{Term, Query, MultiTermSelector, TermScorer};
+use search::schema::{Schema, FieldId};
+use search::geo_shape::{GeoShape, ShapeCells, DEFAULT_TREE_LEVELS, DEFAULT_DISTANCE_ERROR_PCT};
+
+use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
+use query_parser::utils::{parse_float, parse_string, check_field_exists};
+
+
+/// How the shapes of matching documents relate to the shape in the query
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SpatialRelation {
+    Intersects,
+    Disjoint,
+
+    /// The document's shape is inside the query's
+    Within,
+
+    /// The document's shape contains the query's
+    Contains,
+}
+
+
+#[derive(Debug)]
+struct GeoShapeQueryBuilder {
+    field: String,
+    shape: GeoShape,
+    relation: SpatialRelation,
+    boost: f32,
+}
+
+
+fn cell_query(field: FieldId, cell: &str) -> Query {
+    Query::term(field, Term::from_string(cell))
+}
+
+
+/// Matches documents with a cell inside one of `cells`
+fn cells_query(field: FieldId, cells: &[String]) -> Query {
+    Query::MultiTerm {
+        field: field,
+        term_selector: MultiTermSelector::Prefixes(cells.iter().cloned().collect()),
+        scorer: TermScorer::default(),
+    }
+}
+
+
+/// Matches documents with a cell inside one of the query's cells, or a larger cell that one of
+/// them was split from
+fn intersects_query(field: FieldId, cells: &ShapeCells) -> Query {
+    let mut queries = vec![cells_query(field, &cells.leaves)];
+    queries.extend(cells.ancestors.iter().map(|cell| cell_query(field, cell)));
+
+    Query::Disjunction {
+        queries: queries,
+    }
+}
+
+
+impl QueryBuilder for GeoShapeQueryBuilder {
+    fn build(&self, context: &QueryBuildContext, schema: &Schema) -> Query {
+        let field = match schema.get_field_by_name(&self.field) {
+            Some(field) => field,
+            None => return Query::None,
+        };
+
+        // Split the shape as finely as the field's shapes are
+        let field_mapping = context.index_metadata.and_then(|index_metadata| index_metadata.get_field_mapping(&self.field));
+        let (tree_levels, distance_error_pct) = field_mapping.map_or((DEFAULT_TREE_LEVELS, DEFAULT_DISTANCE_ERROR_PCT), |field_mapping| {
+            (field_mapping.tree_levels, field_mapping.distance_error_pct)
+        });
+        let cells = self.shape.cells(self.shape.detail_level(tree_levels, distance_error_pct));
+
+        let filter = match self.relation {
+            SpatialRelation::Intersects => intersects_query(field, &cells),
+            SpatialRelation::Disjoint => {
+                // Any document with a shape, less those that intersect
+                let has_shape = Query::MultiTerm {
+                    field: field,
+                    term_selector: MultiTermSelector::Prefix(String::new()),
+                    scorer: TermScorer::default(),
+                };
+
+                has_shape.exclude(intersects_query(field, &cells))
+            }
+            SpatialRelation::Within => {
+                // Leave out documents with cells outside the shape, or larger than the cells of its edges
+                let mut outside = vec![cells_query(field, &cells.outside)];
+                outside.extend(cells.ancestors.iter().map(|cell| cell_query(field, cell)));
+
+                intersects_query(field, &cells).exclude(Query::Disjunction {
+                    queries: outside,
+                })
+            }
+            SpatialRelation::Contains => {
+                // Each of the shape's cells must be one of the document's cells, or inside one of them
+                Query::Conjunction {
+                    queries: cells.leaves.iter().map(|cell| {
+                        Query::Disjunction {
+                            queries: cell.char_indices().map(|(i, c)| cell_query(field, &cell[..i + c.len_utf8()])).collect(),
+                        }
+                    }).collect(),
+                }
+            }
+        };
+
+        Query::Filter {
+            query: Box::new(Query::All{ score: self.boost }),
+            filter: Box::new(filter),
+        }
+    }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        check_field_exists(schema, &self.field)
+    }
+}
+
+
+fn parse_relation(json: &Json) -> Result<SpatialRelation, QueryParseError> {
+    match parse_string(json)?.as_ref() {
+        "intersects" => Ok(SpatialRelation::Intersects),
+        "disjoint" => Ok(SpatialRelation::Disjoint),
+        "within" => Ok(SpatialRelation::Within),
+        "contains" => Ok(SpatialRelation::Contains),
+        _ => Err(QueryParseError::InvalidValue),
+    }
+}
+
+
+pub fn parse(json: &Json) -> Result<Box<QueryBuilder>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let field_name = if object.len() == 1 {
+        object.keys().collect::<Vec<_>>()[0]
+    } else {
+        return Err(QueryParseError::ExpectedSingleKey)
+    };
+
+    let inner_object = object.get(field_name).unwrap().as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    // Get configuration
+    let mut shape = None;
+    let mut relation = SpatialRelation::Intersects;
+    let mut boost = 1.0f32;
+
+    for (key, value) in inner_object.iter() {
+        match key.as_ref() {
+            "shape" => {
+                shape = Some(GeoShape::from_geojson(value).map_err(QueryParseError::InvalidShape)?);
+            }
+            "relation" => {
+                relation = parse_relation(value)?;
+            }
+            "boost" => {
+                boost = parse_float(value)?;
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone()))
+        }
+    }
+
+    let shape = shape.ok_or(QueryParseError::ExpectedKey("shape"))?;
+    if shape.bounding_box().is_none() {
+        return Err(QueryParseError::InvalidShape("the shape is empty".to_string()));
+    }
+
+    Ok(Box::new(GeoShapeQueryBuilder {
+        field: field_name.clone(),
+        shape: shape,
+        relation: relation,
+        boost: boost,
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::{Term, Query, MultiTermSelector, TermScorer};
+    use search::schema::{Schema, FieldType, FIELD_INDEXED};
+
+    use query_parser::{QueryBuildContext, QueryParseError};
+
+    use super::parse;
+
+    #[test]
+    fn test_geo_shape_query() {
+        let mut schema = Schema::new();
+        let location_field = schema.add_field("location".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        let query = parse(&json!({
+            "location": {
+                "shape": {"type": "point", "coordinates": [10.40744, 57.64911]},
+                "boost": 2.0
+            }
+        })).map(|builder| builder.build(&QueryBuildContext::new(), &schema));
+
+        // The point is in one cell at the finest level, which documents can have that cell, a
+        // cell inside it, or one of the cells it's inside
+        let mut queries = vec![Query::MultiTerm {
+            field: location_field,
+            term_selector: MultiTermSelector::Prefixes(btreeset!{"u4pruydqq".to_string()}),
+            scorer: TermScorer::default(),
+        }];
+        for cell in &["u4pruydq", "u4pruyd", "u4pruy", "u4pru", "u4pr", "u4p", "u4", "u"] {
+            queries.push(Query::term(location_field, Term::from_string(cell)));
+        }
+
+        assert_eq!(query, Ok(Query::Filter {
+            query: Box::new(Query::All{ score: 2.0 }),
+            filter: Box::new(Query::Disjunction {
+                queries: queries,
+            }),
+        }));
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut schema = Schema::new();
+        let location_field = schema.add_field("location".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        let query = parse(&json!({
+            "location": {
+                "shape": {"type": "point", "coordinates": [10.40744, 57.64911]},
+                "relation": "contains"
+            }
+        })).map(|builder| builder.build(&QueryBuildContext::new(), &schema));
+
+        assert_eq!(query, Ok(Query::Filter {
+            query: Box::new(Query::All{ score: 1.0 }),
+            filter: Box::new(Query::Conjunction {
+                queries: vec![Query::Disjunction {
+                    queries: ["u", "u4", "u4p", "u4pr", "u4pru", "u4pruy", "u4pruyd", "u4pruydq", "u4pruydqq"].iter().map(|cell| {
+                        Query::term(location_field, Term::from_string(cell))
+                    }).collect(),
+                }],
+            }),
+        }));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(&json!({"location": {}})).err(), Some(QueryParseError::ExpectedKey("shape")));
+        assert_eq!(parse(&json!({"location": {"shape": {"type": "point", "coordinates": [0.0, 0.0]}, "relation": "touches"}})).err(), Some(QueryParseError::InvalidValue));
+        assert_eq!(parse(&json!({"location": {"shape": {"type": "point", "coordinates": [0.0, 0.0]}, "foo": "bar"}})).err(), Some(QueryParseError::UnrecognisedKey("foo".to_string())));
+        assert_eq!(parse(&json!({"location": {"shape": {"type": "geometrycollection", "geometries": []}}})).err(), Some(QueryParseError::InvalidShape("the shape is empty".to_string())));
+        assert!(parse(&json!({"location": {"shape": {"type": "point", "coordinates": [200.0, 0.0]}}})).is_err());
+    }
+}
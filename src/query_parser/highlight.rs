@@ -0,0 +1,156 @@
+//! Parses the "highlight" element of a search request
+
+use serde_json::Value as Json;
+
+use index::metadata::IndexMetadata;
+use highlight::{HighlightField, HighlightOptions};
+use query_parser::QueryParseError;
+use query_parser::utils::parse_string;
+
+
+fn parse_tag(json: &Json) -> Result<String, QueryParseError> {
+    match *json {
+        // Only a single tag is supported, so take the first one
+        Json::Array(ref array) => {
+            match array.first() {
+                Some(tag) => parse_string(tag),
+                None => Err(QueryParseError::InvalidValue),
+            }
+        }
+        _ => parse_string(json),
+    }
+}
+
+
+fn parse_usize(json: &Json) -> Result<usize, QueryParseError> {
+    match json.as_u64() {
+        Some(value) => Ok(value as usize),
+        None => Err(QueryParseError::InvalidValue),
+    }
+}
+
+
+/// Applies any options in the object. Returns false if a key wasn't an option
+fn parse_option(options: &mut HighlightOptions, key: &str, val: &Json) -> Result<bool, QueryParseError> {
+    match key {
+        "pre_tags" => options.pre_tag = parse_tag(val)?,
+        "post_tags" => options.post_tag = parse_tag(val)?,
+        "fragment_size" => options.fragment_size = parse_usize(val)?,
+        "number_of_fragments" => options.number_of_fragments = parse_usize(val)?,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+
+/// Parses a highlight definition
+///
+/// Options set at the top level apply to all fields, and can be overridden per field.
+/// Fields that don't store their values can't be highlighted, so they are skipped.
+pub fn parse(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<HighlightField>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut options = HighlightOptions::default();
+    for (key, val) in object.iter() {
+        if key != "fields" && !parse_option(&mut options, key, val)? {
+            return Err(QueryParseError::UnrecognisedKey(key.clone()));
+        }
+    }
+
+    let fields = match object.get("fields") {
+        Some(fields) => fields.as_object().ok_or(QueryParseError::ExpectedObject)?,
+        None => return Err(QueryParseError::ExpectedKey("fields")),
+    };
+
+    let mut highlight_fields = Vec::with_capacity(fields.len());
+    for (field_name, field_options) in fields.iter() {
+        let field_mapping = match index_metadata.get_field_mapping(field_name) {
+            Some(field_mapping) => field_mapping,
+            None => return Err(QueryParseError::FieldDoesntExist(field_name.clone())),
+        };
+
+        let field_ref = match field_mapping.index_ref {
+            Some(field_ref) if field_mapping.is_stored || field_mapping.has_doc_values => field_ref,
+            _ => continue,
+        };
+
+        let mut field_highlight_options = options.clone();
+        let field_options = field_options.as_object().ok_or(QueryParseError::ExpectedObject)?;
+        for (key, val) in field_options.iter() {
+            if !parse_option(&mut field_highlight_options, key, val)? {
+                return Err(QueryParseError::UnrecognisedKey(key.clone()));
+            }
+        }
+
+        highlight_fields.push(HighlightField {
+            name: field_name.clone(),
+            field_ref: field_ref,
+            analyzer: field_mapping.index_analyzer().cloned(),
+            options: field_highlight_options,
+        });
+    }
+
+    Ok(highlight_fields)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use search::schema::FieldId;
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use highlight::HighlightOptions;
+    use query_parser::QueryParseError;
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::parse;
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut title_mapping = field_mapping(1, FieldType::String);
+        title_mapping.is_stored = true;
+
+        index_metadata(vec![
+            ("title", title_mapping),
+            ("body", field_mapping(2, FieldType::String)),
+        ])
+    }
+
+    #[test]
+    fn test_highlight() {
+        let index_metadata = make_index_metadata();
+
+        let fields = parse(&json!({
+            "pre_tags": ["<b>"],
+            "post_tags": ["</b>"],
+            "fields": {
+                "title": {"number_of_fragments": 0},
+                "body": {},
+            }
+        }), &index_metadata).unwrap();
+
+        // body isn't stored so can't be highlighted
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].name, "title");
+        assert_eq!(fields[0].field_ref, FieldId(1));
+        assert_eq!(fields[0].options, HighlightOptions {
+            pre_tag: "<b>".to_string(),
+            post_tag: "</b>".to_string(),
+            number_of_fragments: 0,
+            ..HighlightOptions::default()
+        });
+    }
+
+    #[test]
+    fn test_highlight_errors() {
+        let index_metadata = make_index_metadata();
+
+        let fields = parse(&serde_json::from_str("{\"fields\": {\"foo\": {}}}").unwrap(), &index_metadata);
+        assert_eq!(fields, Err(QueryParseError::FieldDoesntExist("foo".to_string())));
+
+        let fields = parse(&serde_json::from_str("{\"fields\": {\"title\": {\"foo\": 1}}}").unwrap(), &index_metadata);
+        assert_eq!(fields, Err(QueryParseError::UnrecognisedKey("foo".to_string())));
+    }
+}
@@ -0,0 +1,67 @@
+//! Parses the "indices_boost" element of a search request
+
+use serde_json::Value as Json;
+
+use query_parser::QueryParseError;
+use query_parser::utils::parse_float;
+
+
+fn parse_object(json: &Json, indices_boost: &mut Vec<(String, f32)>) -> Result<(), QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    for (index_pattern, boost) in object.iter() {
+        indices_boost.push((index_pattern.clone(), parse_float(boost)?));
+    }
+
+    Ok(())
+}
+
+
+/// Parses a list of index name patterns and their boosts
+///
+/// This can be either an object (`{"index1": 1.4}`) or an array of objects
+/// (`[{"index1": 1.4}, {"index*": 1.3}]`). The array form keeps the order, which is
+/// used to decide which boost applies when more than one pattern matches.
+pub fn parse(json: &Json) -> Result<Vec<(String, f32)>, QueryParseError> {
+    let mut indices_boost = Vec::new();
+
+    match *json {
+        Json::Array(ref array) => {
+            for item in array.iter() {
+                parse_object(item, &mut indices_boost)?;
+            }
+        }
+        Json::Object(_) => parse_object(json, &mut indices_boost)?,
+        _ => return Err(QueryParseError::ExpectedObject),
+    }
+
+    Ok(indices_boost)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use query_parser::QueryParseError;
+
+    use super::parse;
+
+    #[test]
+    fn test_object() {
+        assert_eq!(parse(&json!({"index1": 1.4})), Ok(vec![("index1".to_string(), 1.4f32)]));
+    }
+
+    #[test]
+    fn test_array() {
+        assert_eq!(parse(&json!([{"index1": 1.4}, {"index*": 1.3}])), Ok(vec![
+            ("index1".to_string(), 1.4f32),
+            ("index*".to_string(), 1.3f32),
+        ]));
+    }
+
+    #[test]
+    fn test_gives_error_for_incorrect_type() {
+        assert_eq!(parse(&json!("index1")), Err(QueryParseError::ExpectedObject));
+        assert_eq!(parse(&json!([1.4])), Err(QueryParseError::ExpectedObject));
+        assert_eq!(parse(&json!({"index1": "foo"})), Err(QueryParseError::ExpectedFloat));
+    }
+}
@@ -0,0 +1,210 @@
+//! Parses the "knn" element of a search request
+
+use serde_json::Value as Json;
+use search::schema::{Schema, FieldId};
+use search::knn::{KnnSearch, VectorSimilarity};
+
+use index::metadata::IndexMetadata;
+use mapping::FieldType;
+use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder, parse as parse_query};
+use query_parser::utils::{parse_string, parse_float};
+
+
+/// The most candidates a kNN search can ask for
+const MAX_NUM_CANDIDATES: usize = 10000;
+
+
+#[derive(Debug)]
+pub struct KnnSearchBuilder {
+    field: FieldId,
+    query_vector: Vec<f32>,
+    similarity: VectorSimilarity,
+    k: usize,
+    num_candidates: usize,
+    filter: Option<Box<QueryBuilder>>,
+    boost: f32,
+}
+
+
+impl KnnSearchBuilder {
+    pub fn build(&self, context: &QueryBuildContext, schema: &Schema) -> KnnSearch {
+        KnnSearch {
+            field: self.field,
+            query_vector: self.query_vector.clone(),
+            similarity: self.similarity,
+            k: self.k,
+            num_candidates: self.num_candidates,
+            filter: self.filter.as_ref().map(|filter| filter.build(&context.clone().no_score(), schema)),
+            boost: self.boost,
+        }
+    }
+}
+
+
+fn parse_count(json: &Json, name: &str) -> Result<usize, QueryParseError> {
+    match json.as_u64() {
+        Some(count) if count > 0 => Ok(count as usize),
+        _ => Err(QueryParseError::InvalidKnn(format!("{} must be a positive integer", name))),
+    }
+}
+
+
+fn parse_knn_search(json: &Json, index_metadata: &IndexMetadata) -> Result<KnnSearchBuilder, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field_name = None;
+    let mut query_vector = None;
+    let mut k = None;
+    let mut num_candidates = None;
+    let mut filter = None;
+    let mut boost = 1.0f32;
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "field" => field_name = Some(parse_string(value)?),
+            "query_vector" => {
+                let array = value.as_array().ok_or(QueryParseError::ExpectedArray)?;
+                let mut vector = Vec::with_capacity(array.len());
+                for item in array.iter() {
+                    vector.push(parse_float(item)?);
+                }
+
+                query_vector = Some(vector);
+            }
+            "k" => k = Some(parse_count(value, "k")?),
+            "num_candidates" => num_candidates = Some(parse_count(value, "num_candidates")?),
+            "filter" => filter = Some(parse_query(value)?),
+            "boost" => boost = parse_float(value)?,
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let field_name = field_name.ok_or(QueryParseError::ExpectedKey("field"))?;
+    let query_vector = query_vector.ok_or(QueryParseError::ExpectedKey("query_vector"))?;
+
+    // Check the field is a vector field with the same number of dimensions as the query
+    let field_mapping = match index_metadata.get_field_mapping(&field_name) {
+        Some(field_mapping) => field_mapping,
+        None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+    };
+
+    if field_mapping.data_type != FieldType::DenseVector {
+        return Err(QueryParseError::InvalidKnn(format!("field {:?} is not a dense_vector field", field_name)));
+    }
+
+    if Some(query_vector.len()) != field_mapping.dims {
+        return Err(QueryParseError::InvalidKnn(format!("query_vector has {} dimensions but field {:?} has {}", query_vector.len(), field_name, field_mapping.dims.unwrap_or(0))));
+    }
+
+    let field = match field_mapping.index_ref {
+        Some(field_id) if field_mapping.has_doc_values => field_id,
+        _ => return Err(QueryParseError::FieldHasNoDocValues(field_name)),
+    };
+
+    // Defaults to 10 neighbours, considering half as many candidates again
+    let k = k.unwrap_or(10);
+    let num_candidates = num_candidates.unwrap_or_else(|| (k + k / 2).min(MAX_NUM_CANDIDATES).max(k));
+
+    if num_candidates < k {
+        return Err(QueryParseError::InvalidKnn("num_candidates must be at least k".to_string()));
+    }
+
+    if num_candidates > MAX_NUM_CANDIDATES {
+        return Err(QueryParseError::InvalidKnn(format!("num_candidates can't be more than {}", MAX_NUM_CANDIDATES)));
+    }
+
+    Ok(KnnSearchBuilder {
+        field: field,
+        query_vector: query_vector,
+        similarity: field_mapping.vector_similarity,
+        k: k,
+        num_candidates: num_candidates,
+        filter: filter,
+        boost: boost,
+    })
+}
+
+
+/// Parses one kNN search, or an array of them
+pub fn parse(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<KnnSearchBuilder>, QueryParseError> {
+    match *json {
+        Json::Array(ref array) => array.iter().map(|item| parse_knn_search(item, index_metadata)).collect(),
+        _ => Ok(vec![parse_knn_search(json, index_metadata)?]),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::{Schema, FieldId};
+    use search::knn::{KnnSearch, VectorSimilarity};
+    use search::query::Query;
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use query_parser::{QueryBuildContext, QueryParseError};
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::parse;
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut embedding_mapping = field_mapping(1, FieldType::DenseVector);
+        embedding_mapping.is_indexed = false;
+        embedding_mapping.has_doc_values = true;
+        embedding_mapping.dims = Some(3);
+        embedding_mapping.vector_similarity = VectorSimilarity::L2Norm;
+
+        index_metadata(vec![
+            ("embedding", embedding_mapping),
+            ("title", field_mapping(2, FieldType::String)),
+        ])
+    }
+
+    #[test]
+    fn test_knn() {
+        let index_metadata = make_index_metadata();
+        let knn = parse(&json!({
+            "field": "embedding",
+            "query_vector": [1, 0.5, 0],
+            "k": 5,
+            "num_candidates": 50,
+            "filter": {"match_all": {}},
+            "boost": 2.0,
+        }), &index_metadata).unwrap();
+
+        assert_eq!(knn.len(), 1);
+        assert_eq!(knn[0].build(&QueryBuildContext::new(), &Schema::new()), KnnSearch {
+            field: FieldId(1),
+            query_vector: vec![1.0, 0.5, 0.0],
+            similarity: VectorSimilarity::L2Norm,
+            k: 5,
+            num_candidates: 50,
+            filter: Some(Query::all()),
+            boost: 2.0,
+        });
+    }
+
+    #[test]
+    fn test_defaults() {
+        let index_metadata = make_index_metadata();
+        let knn = parse(&json!([{"field": "embedding", "query_vector": [1, 0, 0]}]), &index_metadata).unwrap();
+        let knn = knn[0].build(&QueryBuildContext::new(), &Schema::new());
+
+        assert_eq!(knn.k, 10);
+        assert_eq!(knn.num_candidates, 15);
+        assert_eq!(knn.filter, None);
+        assert_eq!(knn.boost, 1.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let index_metadata = make_index_metadata();
+        let error = |json| parse(&json, &index_metadata).unwrap_err();
+
+        assert_eq!(error(json!({"query_vector": [1, 0, 0]})), QueryParseError::ExpectedKey("field"));
+        assert_eq!(error(json!({"field": "missing", "query_vector": [1, 0, 0]})), QueryParseError::FieldDoesntExist("missing".to_string()));
+        assert_eq!(error(json!({"field": "title", "query_vector": [1, 0, 0]})), QueryParseError::InvalidKnn("field \"title\" is not a dense_vector field".to_string()));
+        assert_eq!(error(json!({"field": "embedding", "query_vector": [1, 0]})), QueryParseError::InvalidKnn("query_vector has 2 dimensions but field \"embedding\" has 3".to_string()));
+        assert_eq!(error(json!({"field": "embedding", "query_vector": [1, 0, 0], "k": 10, "num_candidates": 5})), QueryParseError::InvalidKnn("num_candidates must be at least k".to_string()));
+        assert_eq!(error(json!({"field": "embedding", "query_vector": [1, 0, 0], "k": 0})), QueryParseError::InvalidKnn("k must be a positive integer".to_string()));
+    }
+}
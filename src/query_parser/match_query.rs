@@ -7,7 +7,7 @@ use search::schema::Schema;
 use mapping::FieldSearchOptions;
 
 use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
-use query_parser::utils::{parse_string, parse_float, Operator, parse_operator};
+use query_parser::utils::{parse_string, parse_float, Operator, parse_operator, check_field_exists};
 
 
 #[derive(Debug)]
@@ -49,7 +49,7 @@ impl QueryBuilder for MatchQueryBuilder {
             sub_queries.push(Query::Term {
                 field: schema.get_field_by_name(&self.field).unwrap(),
                 term: token.term,
-                scorer: TermScorer::default(),
+                scorer: TermScorer::new(field_search_options.similarity_model.clone(), 1.0f32),
             });
         }
 
@@ -72,6 +72,10 @@ impl QueryBuilder for MatchQueryBuilder {
         // Add boost
         query.boost(self.boost)
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        check_field_exists(schema, &self.field)
+    }
 }
 
 
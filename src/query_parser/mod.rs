@@ -13,14 +13,27 @@ pub mod and_query;
 pub mod or_query;
 pub mod not_query;
 pub mod constant_score_query;
+pub mod geo_shape_query;
+pub mod sort;
+pub mod highlight;
+pub mod source_filter;
+pub mod indices_boost;
+pub mod script;
+pub mod knn;
+pub mod aggregations;
+pub mod suggest;
 
-use std::fmt::Debug;
+#[cfg(test)]
+pub mod test_utils;
+
+use std::fmt::{self, Debug};
 
 use serde_json::Value as Json;
 use search::Query;
 use search::schema::Schema;
 
 use index::metadata::IndexMetadata;
+use plugins;
 
 
 #[derive(Debug, Clone)]
@@ -66,11 +79,59 @@ pub enum QueryParseError {
     InvalidValue,
     ExpectedSingleKey,
     InvalidOperator,
+    FieldNotSortable(String),
+    FieldNotStored(String),
+    FieldHasNoDocValues(String),
+    InvalidScript(String),
+    InvalidKnn(String),
+    UnrecognisedAggregationType(String),
+    InvalidAggregation(String),
+    InvalidSuggester(String),
+    InvalidShape(String),
+
+    /// For queries added by plugins, which can't use the variants above
+    InvalidQuery(String),
+}
+
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryParseError::UnrecognisedQueryType(ref query_type) => write!(f, "unrecognised query type {:?}", query_type),
+            QueryParseError::FieldDoesntExist(ref field_name) => write!(f, "field {:?} doesn't exist", field_name),
+            QueryParseError::UnrecognisedKey(ref key) => write!(f, "unrecognised key {:?}", key),
+            QueryParseError::ExpectedKey(key) => write!(f, "expected key {:?}", key),
+            QueryParseError::ExpectedObject => write!(f, "expected an object"),
+            QueryParseError::ExpectedArray => write!(f, "expected an array"),
+            QueryParseError::ExpectedString => write!(f, "expected a string"),
+            QueryParseError::ExpectedFloat => write!(f, "expected a number"),
+            QueryParseError::ExpectedObjectOrString => write!(f, "expected an object or a string"),
+            QueryParseError::InvalidValue => write!(f, "invalid value"),
+            QueryParseError::ExpectedSingleKey => write!(f, "expected an object with a single key"),
+            QueryParseError::InvalidOperator => write!(f, "invalid operator"),
+            QueryParseError::FieldNotSortable(ref field_name) => write!(f, "field {:?} can't be sorted on", field_name),
+            QueryParseError::FieldNotStored(ref field_name) => write!(f, "field {:?} isn't stored", field_name),
+            QueryParseError::FieldHasNoDocValues(ref field_name) => write!(f, "field {:?} has no doc values", field_name),
+            QueryParseError::InvalidScript(ref message) => write!(f, "invalid script: {}", message),
+            QueryParseError::InvalidKnn(ref message) => write!(f, "invalid knn search: {}", message),
+            QueryParseError::UnrecognisedAggregationType(ref aggregation_type) => write!(f, "unrecognised aggregation type {:?}", aggregation_type),
+            QueryParseError::InvalidAggregation(ref message) => write!(f, "invalid aggregation: {}", message),
+            QueryParseError::InvalidSuggester(ref message) => write!(f, "invalid suggester: {}", message),
+            QueryParseError::InvalidShape(ref message) => write!(f, "invalid shape: {}", message),
+            QueryParseError::InvalidQuery(ref message) => write!(f, "invalid query: {}", message),
+        }
+    }
 }
 
 
 pub trait QueryBuilder: Debug {
     fn build(&self, context: &QueryBuildContext, schema: &Schema) -> Query;
+
+    /// Checks that every field the query reads is in the schema. `build` must only be
+    /// called with a schema that passes this
+    fn check_fields(&self, _schema: &Schema) -> Result<(), QueryParseError> {
+        Ok(())
+    }
 }
 
 
@@ -89,6 +150,7 @@ fn get_query_parser(query_name: &str) -> Option<fn(&Json) -> Result<Box<QueryBui
         "or" => Some(or_query::parse),
         "not" => Some(not_query::parse),
         "constant_score" => Some(constant_score_query::parse),
+        "geo_shape" => Some(geo_shape_query::parse),
         _ => None
     }
 }
@@ -103,8 +165,30 @@ pub fn parse(json: &Json) -> Result<Box<QueryBuilder>, QueryParseError> {
         return Err(QueryParseError::ExpectedSingleKey)
     };
 
+    let query = object.get(query_type).unwrap();
     match get_query_parser(&query_type) {
-        Some(parse) => parse(object.get(query_type).unwrap()),
-        None => Err(QueryParseError::UnrecognisedQueryType(query_type.clone())),
+        Some(parse) => parse(query),
+        None => {
+            plugins::parse_query(query_type, query).unwrap_or_else(|| Err(QueryParseError::UnrecognisedQueryType(query_type.clone())))
+        }
+    }
+}
+
+
+/// Builds a query that limits which documents can be read, such as an alias filter or a
+/// role query
+///
+/// These are checked when they're saved, but anything that can't be built matches nothing
+/// rather than the limit being ignored. This includes queries on fields that aren't mapped
+/// in the index yet.
+pub fn build_filter(json: &Json, index_metadata: &IndexMetadata, schema: &Schema) -> Query {
+    let filter = match parse(json) {
+        Ok(filter) => filter,
+        Err(_) => return Query::None,
+    };
+
+    match filter.check_fields(schema) {
+        Ok(()) => filter.build(&QueryBuildContext::new().set_index_metadata(index_metadata).no_score(), schema),
+        Err(_) => Query::None,
     }
 }
@@ -7,7 +7,7 @@ use search::schema::Schema;
 use mapping::FieldSearchOptions;
 
 use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
-use query_parser::utils::{parse_string, parse_float, Operator, parse_operator, parse_field_and_boost};
+use query_parser::utils::{parse_string, parse_float, Operator, parse_operator, parse_field_and_boost, check_field_exists};
 
 
 #[derive(Debug)]
@@ -51,7 +51,7 @@ impl QueryBuilder for MultiMatchQueryBuilder {
                 term_queries.push(Query::Term {
                     field: schema.get_field_by_name(field_name).unwrap(),
                     term: token.term,
-                    scorer: TermScorer::default(),
+                    scorer: TermScorer::new(field_search_options.similarity_model.clone(), 1.0f32),
                 });
             }
 
@@ -88,6 +88,14 @@ impl QueryBuilder for MultiMatchQueryBuilder {
         // Add boost
         query.boost(self.boost)
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        for &(ref field_name, _) in self.fields.iter() {
+            check_field_exists(schema, field_name)?;
+        }
+
+        Ok(())
+    }
 }
 
 
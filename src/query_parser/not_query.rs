@@ -20,6 +20,10 @@ impl QueryBuilder for NotQueryBuilder {
             exclude: Box::new(self.query.build(&context.clone().no_score(), schema)),
         }
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        self.query.check_fields(schema)
+    }
 }
 
 
@@ -64,6 +68,21 @@ mod tests {
         }))
     }
 
+    #[test]
+    fn test_check_fields() {
+        let schema = Schema::new();
+
+        // Fields are checked in the query being excluded
+        let builder = parse(&serde_json::from_str("
+        {
+            \"term\": {
+                \"test\":  \"foo\"
+            }
+        }
+        ").unwrap()).unwrap();
+        assert_eq!(builder.check_fields(&schema), Err(QueryParseError::FieldDoesntExist("test".to_string())));
+    }
+
     #[test]
     fn test_gives_error_for_incorrect_type() {
         // String
@@ -23,6 +23,14 @@ impl QueryBuilder for OrQueryBuilder {
 
         Query::Disjunction { queries: queries }
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        for query in self.queries.iter() {
+            query.check_fields(schema)?;
+        }
+
+        Ok(())
+    }
 }
 
 
@@ -5,7 +5,7 @@ use search::{Query, MultiTermSelector, TermScorer};
 use search::schema::Schema;
 
 use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
-use query_parser::utils::parse_float;
+use query_parser::utils::{parse_float, check_field_exists};
 
 
 #[derive(Debug)]
@@ -27,6 +27,10 @@ impl QueryBuilder for PrefixQueryBuilder {
         // Add boost
         query.boost(self.boost)
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        check_field_exists(schema, &self.field)
+    }
 }
 
 
@@ -0,0 +1,410 @@
+//! Parses scripts, as used by script-based sorting and "script_fields"
+//!
+//! Only simple expressions are supported, for example:
+//!
+//! ```text
+//! doc['price'].value * params.tax_rate
+//! Math.max(_score, doc['rating'].value / 2)
+//! ```
+
+use serde_json::Value as Json;
+use search::script::{Script, BinaryOperator, Function};
+
+use index::metadata::IndexMetadata;
+use mapping::FieldType;
+use query_parser::QueryParseError;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    String(String),
+    Symbol(char),
+}
+
+
+fn tokenize(source: &str) -> Result<Vec<Token>, QueryParseError> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < chars.len() {
+        let c = chars[position];
+
+        if c.is_whitespace() {
+            position += 1;
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(position + 1).map_or(false, |c| c.is_ascii_digit())) {
+            let start = position;
+            while position < chars.len() && (chars[position].is_ascii_digit() || chars[position] == '.') {
+                position += 1;
+            }
+
+            let number = chars[start..position].iter().collect::<String>();
+            match number.parse() {
+                Ok(number) => tokens.push(Token::Number(number)),
+                Err(_) => return Err(QueryParseError::InvalidScript(format!("invalid number {:?}", number))),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = position;
+            while position < chars.len() && (chars[position].is_alphanumeric() || chars[position] == '_') {
+                position += 1;
+            }
+
+            tokens.push(Token::Identifier(chars[start..position].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let start = position + 1;
+            position = start;
+            while position < chars.len() && chars[position] != c {
+                position += 1;
+            }
+
+            if position >= chars.len() {
+                return Err(QueryParseError::InvalidScript("unterminated string".to_string()));
+            }
+
+            tokens.push(Token::String(chars[start..position].iter().collect()));
+            position += 1;
+        } else if "+-*/%()[].,".contains(c) {
+            tokens.push(Token::Symbol(c));
+            position += 1;
+        } else {
+            return Err(QueryParseError::InvalidScript(format!("unexpected character {:?}", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+
+struct ScriptParser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    params: Option<&'a Json>,
+    index_metadata: &'a IndexMetadata,
+}
+
+
+impl<'a> ScriptParser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, QueryParseError> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => Err(QueryParseError::InvalidScript("unexpected end of script".to_string())),
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), QueryParseError> {
+        match self.next()? {
+            Token::Symbol(c) if c == symbol => Ok(()),
+            token => Err(QueryParseError::InvalidScript(format!("expected {:?}, found {:?}", symbol, token))),
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<String, QueryParseError> {
+        match self.next()? {
+            Token::Identifier(name) => Ok(name),
+            token => Err(QueryParseError::InvalidScript(format!("expected a name, found {:?}", token))),
+        }
+    }
+
+    /// Parses a name given either as `.name` or `['name']`
+    fn parse_member(&mut self) -> Result<String, QueryParseError> {
+        match self.next()? {
+            Token::Symbol('.') => self.expect_identifier(),
+            Token::Symbol('[') => {
+                let name = match self.next()? {
+                    Token::String(name) => name,
+                    token => return Err(QueryParseError::InvalidScript(format!("expected a string, found {:?}", token))),
+                };
+
+                self.expect_symbol(']')?;
+                Ok(name)
+            }
+            token => Err(QueryParseError::InvalidScript(format!("expected '.' or '[', found {:?}", token))),
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Script, QueryParseError> {
+        let mut script = self.parse_term()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Symbol('+')) => BinaryOperator::Add,
+                Some(&Token::Symbol('-')) => BinaryOperator::Subtract,
+                _ => return Ok(script),
+            };
+
+            self.position += 1;
+            script = Script::BinaryOp(operator, Box::new(script), Box::new(self.parse_term()?));
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Script, QueryParseError> {
+        let mut script = self.parse_unary()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Symbol('*')) => BinaryOperator::Multiply,
+                Some(&Token::Symbol('/')) => BinaryOperator::Divide,
+                Some(&Token::Symbol('%')) => BinaryOperator::Remainder,
+                _ => return Ok(script),
+            };
+
+            self.position += 1;
+            script = Script::BinaryOp(operator, Box::new(script), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Script, QueryParseError> {
+        match self.peek() {
+            Some(&Token::Symbol('-')) => {
+                self.position += 1;
+                Ok(Script::Negate(Box::new(self.parse_unary()?)))
+            }
+            Some(&Token::Symbol('+')) => {
+                self.position += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Script, QueryParseError> {
+        match self.next()? {
+            Token::Number(number) => Ok(Script::Number(number)),
+            Token::Symbol('(') => {
+                let script = self.parse_expression()?;
+                self.expect_symbol(')')?;
+                Ok(script)
+            }
+            Token::Identifier(ref name) if name == "_score" => Ok(Script::Score),
+            Token::Identifier(ref name) if name == "doc" => self.parse_doc_value(),
+            Token::Identifier(ref name) if name == "params" => self.parse_param(),
+            Token::Identifier(ref name) if name == "Math" => self.parse_function(),
+            token => Err(QueryParseError::InvalidScript(format!("unexpected {:?}", token))),
+        }
+    }
+
+    fn parse_doc_value(&mut self) -> Result<Script, QueryParseError> {
+        let field_name = self.parse_member()?;
+
+        // The ".value" suffix is optional
+        if self.peek() == Some(&Token::Symbol('.')) {
+            self.position += 1;
+            let member = self.expect_identifier()?;
+
+            if member != "value" {
+                return Err(QueryParseError::InvalidScript(format!("unsupported doc value member {:?}", member)));
+            }
+        }
+
+        let field_mapping = match self.index_metadata.get_field_mapping(&field_name) {
+            Some(field_mapping) => field_mapping,
+            None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+        };
+
+        if field_mapping.data_type == FieldType::String {
+            return Err(QueryParseError::InvalidScript(format!("field {:?} is not numeric", field_name)));
+        }
+
+        match field_mapping.index_ref {
+            Some(field_id) if field_mapping.has_doc_values => Ok(Script::DocValue(field_id)),
+            _ => Err(QueryParseError::FieldHasNoDocValues(field_name)),
+        }
+    }
+
+    fn parse_param(&mut self) -> Result<Script, QueryParseError> {
+        let name = self.parse_member()?;
+
+        match self.params.and_then(|params| params.get(&name)).and_then(|value| value.as_f64()) {
+            Some(value) => Ok(Script::Number(value)),
+            None => Err(QueryParseError::InvalidScript(format!("param {:?} is missing or not a number", name))),
+        }
+    }
+
+    fn parse_function(&mut self) -> Result<Script, QueryParseError> {
+        self.expect_symbol('.')?;
+        let name = self.expect_identifier()?;
+        let function = match Function::from_name(&name) {
+            Some(function) => function,
+            None => return Err(QueryParseError::InvalidScript(format!("unknown function Math.{}", name))),
+        };
+
+        self.expect_symbol('(')?;
+        let mut args = vec![self.parse_expression()?];
+        while self.peek() == Some(&Token::Symbol(',')) {
+            self.position += 1;
+            args.push(self.parse_expression()?);
+        }
+        self.expect_symbol(')')?;
+
+        if args.len() != function.arity() {
+            return Err(QueryParseError::InvalidScript(format!("Math.{} takes {} arguments", name, function.arity())));
+        }
+
+        Ok(Script::Function(function, args))
+    }
+}
+
+
+fn parse_source(source: &str, params: Option<&Json>, index_metadata: &IndexMetadata) -> Result<Script, QueryParseError> {
+    let mut parser = ScriptParser {
+        tokens: tokenize(source)?,
+        position: 0,
+        params: params,
+        index_metadata: index_metadata,
+    };
+
+    let script = parser.parse_expression()?;
+
+    if let Some(token) = parser.peek() {
+        return Err(QueryParseError::InvalidScript(format!("unexpected {:?}", token)));
+    }
+
+    Ok(script)
+}
+
+
+/// Parses a script, which can either be a string of source or an object with a "source"
+/// and optional "params"
+pub fn parse(json: &Json, index_metadata: &IndexMetadata) -> Result<Script, QueryParseError> {
+    match *json {
+        Json::String(ref source) => parse_source(source, None, index_metadata),
+        Json::Object(ref object) => {
+            let mut source = None;
+            let mut params = None;
+
+            for (key, val) in object.iter() {
+                match key.as_ref() {
+                    "source" | "inline" => source = Some(val.as_str().ok_or(QueryParseError::ExpectedString)?),
+                    "params" => {
+                        if !val.is_object() {
+                            return Err(QueryParseError::ExpectedObject);
+                        }
+
+                        params = Some(val);
+                    }
+                    "lang" => {
+                        match val.as_str() {
+                            Some("expression") | Some("painless") => {}
+                            _ => return Err(QueryParseError::InvalidValue),
+                        }
+                    }
+                    _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+                }
+            }
+
+            match source {
+                Some(source) => parse_source(source, params, index_metadata),
+                None => Err(QueryParseError::ExpectedKey("source")),
+            }
+        }
+        _ => Err(QueryParseError::ExpectedObjectOrString),
+    }
+}
+
+
+/// Parses "script_fields", which maps the name of each field to return to its script
+pub fn parse_script_fields(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<(String, Script)>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+    let mut script_fields = Vec::with_capacity(object.len());
+
+    for (name, val) in object.iter() {
+        let script_json = match val.get("script") {
+            Some(script_json) => script_json,
+            None => return Err(QueryParseError::ExpectedKey("script")),
+        };
+
+        script_fields.push((name.clone(), parse(script_json, index_metadata)?));
+    }
+
+    Ok(script_fields)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::script::{Script, BinaryOperator, Function};
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use query_parser::QueryParseError;
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::{parse, parse_script_fields};
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut price_mapping = field_mapping(1, FieldType::Integer);
+        price_mapping.has_doc_values = true;
+
+        let mut title_mapping = field_mapping(2, FieldType::String);
+        title_mapping.has_doc_values = true;
+
+        index_metadata(vec![
+            ("price", price_mapping),
+            ("title", title_mapping),
+        ])
+    }
+
+    #[test]
+    fn test_script() {
+        let index_metadata = make_index_metadata();
+        let script = parse(&json!({
+            "source": "doc['price'].value * params.tax + 1",
+            "params": {"tax": 1.5}
+        }), &index_metadata);
+
+        assert_eq!(script, Ok(Script::BinaryOp(
+            BinaryOperator::Add,
+            Box::new(Script::BinaryOp(
+                BinaryOperator::Multiply,
+                Box::new(Script::DocValue(FieldId(1))),
+                Box::new(Script::Number(1.5)),
+            )),
+            Box::new(Script::Number(1.0)),
+        )));
+    }
+
+    #[test]
+    fn test_functions() {
+        let index_metadata = make_index_metadata();
+
+        assert_eq!(parse(&json!("Math.max(_score, -(doc[\"price\"]))"), &index_metadata), Ok(Script::Function(
+            Function::Max,
+            vec![
+                Script::Score,
+                Script::Negate(Box::new(Script::DocValue(FieldId(1)))),
+            ],
+        )));
+    }
+
+    #[test]
+    fn test_script_fields() {
+        let index_metadata = make_index_metadata();
+
+        assert_eq!(parse_script_fields(&json!({"double_price": {"script": "doc['price'].value * 2"}}), &index_metadata), Ok(vec![
+            ("double_price".to_string(), Script::BinaryOp(BinaryOperator::Multiply, Box::new(Script::DocValue(FieldId(1))), Box::new(Script::Number(2.0)))),
+        ]));
+        assert_eq!(parse_script_fields(&json!({"double_price": "doc['price'].value * 2"}), &index_metadata), Err(QueryParseError::ExpectedKey("script")));
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let index_metadata = make_index_metadata();
+
+        assert_eq!(parse(&json!("doc['foo'].value"), &index_metadata), Err(QueryParseError::FieldDoesntExist("foo".to_string())));
+        assert_eq!(parse(&json!("doc['title'].value"), &index_metadata), Err(QueryParseError::InvalidScript("field \"title\" is not numeric".to_string())));
+        assert_eq!(parse(&json!("params.tax"), &index_metadata), Err(QueryParseError::InvalidScript("param \"tax\" is missing or not a number".to_string())));
+        assert_eq!(parse(&json!("Math.pow(2)"), &index_metadata), Err(QueryParseError::InvalidScript("Math.pow takes 2 arguments".to_string())));
+        assert_eq!(parse(&json!("1 +"), &index_metadata), Err(QueryParseError::InvalidScript("unexpected end of script".to_string())));
+        assert_eq!(parse(&json!("1 2"), &index_metadata), Err(QueryParseError::InvalidScript("unexpected Number(2.0)".to_string())));
+        assert_eq!(parse(&json!({"params": {}}), &index_metadata), Err(QueryParseError::ExpectedKey("source")));
+    }
+}
@@ -0,0 +1,352 @@
+//! Parses the "sort" element of a search request
+
+use serde_json::Value as Json;
+use chrono::{TimeZone, Utc};
+use search::document::FieldValue;
+use search::sort::{SortField, SortKey, SortOrder, MissingOrder, SortValue};
+
+use index::metadata::IndexMetadata;
+use mapping::FieldType;
+use query_parser::QueryParseError;
+use query_parser::utils::parse_string;
+use query_parser::script::parse as parse_script;
+
+
+fn parse_order(json: &Json) -> Result<SortOrder, QueryParseError> {
+    match parse_string(json)?.as_ref() {
+        "asc" => Ok(SortOrder::Asc),
+        "desc" => Ok(SortOrder::Desc),
+        _ => Err(QueryParseError::InvalidValue),
+    }
+}
+
+
+fn parse_missing(json: &Json) -> Result<MissingOrder, QueryParseError> {
+    match parse_string(json)?.as_ref() {
+        "_first" => Ok(MissingOrder::First),
+        "_last" => Ok(MissingOrder::Last),
+        _ => Err(QueryParseError::InvalidValue),
+    }
+}
+
+
+fn parse_sort_key(name: &str, index_metadata: &IndexMetadata) -> Result<SortKey, QueryParseError> {
+    match name {
+        "_score" => Ok(SortKey::Score),
+        "_doc" => Ok(SortKey::Doc),
+        _ => {
+            let field_mapping = match index_metadata.get_field_mapping(name) {
+                Some(field_mapping) => field_mapping,
+                None => return Err(QueryParseError::FieldDoesntExist(name.to_string())),
+            };
+
+            if !field_mapping.has_doc_values {
+                return Err(QueryParseError::FieldNotSortable(name.to_string()));
+            }
+
+            match field_mapping.index_ref {
+                Some(field_id) => Ok(SortKey::Field(field_id)),
+
+                // The field hasn't been added to the schema yet so no documents have it
+                None => Err(QueryParseError::FieldDoesntExist(name.to_string())),
+            }
+        }
+    }
+}
+
+
+/// Parses a "_script" sort, which orders documents by a number computed by a script
+fn parse_script_sort(json: &Json, index_metadata: &IndexMetadata) -> Result<SortField, QueryParseError> {
+    let options = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+    let mut script = None;
+    let mut order = SortOrder::Asc;
+
+    for (key, val) in options.iter() {
+        match key.as_ref() {
+            "script" => {
+                script = Some(parse_script(val, index_metadata)?);
+            }
+            "type" => {
+                // Scripts can only compute numbers
+                if parse_string(val)? != "number" {
+                    return Err(QueryParseError::InvalidValue);
+                }
+            }
+            "order" => {
+                order = parse_order(val)?;
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone()))
+        }
+    }
+
+    match script {
+        Some(script) => {
+            Ok(SortField {
+                key: SortKey::Script(script),
+                order: order,
+                missing: MissingOrder::Last,
+            })
+        }
+        None => Err(QueryParseError::ExpectedKey("script")),
+    }
+}
+
+
+fn parse_sort_field(json: &Json, index_metadata: &IndexMetadata) -> Result<SortField, QueryParseError> {
+    match *json {
+        Json::String(ref name) => Ok(SortField::new(parse_sort_key(name, index_metadata)?)),
+        Json::Object(ref object) => {
+            let name = if object.len() == 1 {
+                object.keys().collect::<Vec<_>>()[0]
+            } else {
+                return Err(QueryParseError::ExpectedSingleKey)
+            };
+
+            if name == "_script" {
+                return parse_script_sort(object.get(name).unwrap(), index_metadata);
+            }
+
+            let mut sort_field = SortField::new(parse_sort_key(name, index_metadata)?);
+
+            match *object.get(name).unwrap() {
+                Json::Object(ref options) => {
+                    for (key, val) in options.iter() {
+                        match key.as_ref() {
+                            "order" => {
+                                sort_field.order = parse_order(val)?;
+                            }
+                            "missing" => {
+                                sort_field.missing = parse_missing(val)?;
+                            }
+                            _ => return Err(QueryParseError::UnrecognisedKey(key.clone()))
+                        }
+                    }
+                }
+                ref order => {
+                    sort_field.order = parse_order(order)?;
+                }
+            }
+
+            Ok(sort_field)
+        }
+        _ => Err(QueryParseError::ExpectedObjectOrString),
+    }
+}
+
+
+/// Parses a sort definition, which can either be a single sort field or an array of them
+pub fn parse(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<SortField>, QueryParseError> {
+    match *json {
+        Json::Array(ref array) => {
+            let mut sort = Vec::with_capacity(array.len());
+
+            for item in array.iter() {
+                sort.push(parse_sort_field(item, index_metadata)?);
+            }
+
+            Ok(sort)
+        }
+        _ => Ok(vec![parse_sort_field(json, index_metadata)?]),
+    }
+}
+
+
+
+fn parse_search_after_value(json: &Json, sort_field: &SortField, index_metadata: &IndexMetadata) -> Result<SortValue, QueryParseError> {
+    match sort_field.key {
+        SortKey::Score => {
+            match json.as_f64() {
+                Some(score) => Ok(SortValue::Score(score as f32)),
+                None => Err(QueryParseError::ExpectedFloat),
+            }
+        }
+        SortKey::Doc => {
+            match json.as_u64() {
+                Some(doc_id) => Ok(SortValue::Doc(doc_id)),
+                None => Err(QueryParseError::InvalidValue),
+            }
+        }
+        SortKey::Script(_) => {
+            if json.is_null() {
+                return Ok(SortValue::Script(None));
+            }
+
+            match json.as_f64() {
+                Some(value) => Ok(SortValue::Script(Some(value))),
+                None => Err(QueryParseError::ExpectedFloat),
+            }
+        }
+        SortKey::Field(field_ref) => {
+            if json.is_null() {
+                return Ok(SortValue::Field(None));
+            }
+
+            let field_mapping = match index_metadata.get_field_mapping_by_ref(field_ref) {
+                Some(field_mapping) => field_mapping,
+                None => return Err(QueryParseError::InvalidValue),
+            };
+
+            // Dates are returned in the sort values as milliseconds since the epoch
+            if let (FieldType::Date, Some(millis)) = (field_mapping.data_type, json.as_i64()) {
+                let date = Utc.timestamp(millis.div_euclid(1000), (millis.rem_euclid(1000) * 1000000) as u32);
+                return Ok(SortValue::Field(Some(FieldValue::DateTime(date))));
+            }
+
+            match field_mapping.process_value_for_store(json) {
+                Ok(value) => Ok(SortValue::Field(value)),
+                Err(_) => Err(QueryParseError::InvalidValue),
+            }
+        }
+    }
+}
+
+
+/// Parses a "search_after" cursor, which has a value for each of the sort fields
+pub fn parse_search_after(json: &Json, sort: &[SortField], index_metadata: &IndexMetadata) -> Result<Vec<SortValue>, QueryParseError> {
+    let array = json.as_array().ok_or(QueryParseError::ExpectedArray)?;
+
+    if array.len() != sort.len() {
+        return Err(QueryParseError::InvalidValue);
+    }
+
+    let mut sort_values = Vec::with_capacity(array.len());
+    for (item, sort_field) in array.iter().zip(sort.iter()) {
+        sort_values.push(parse_search_after_value(item, sort_field, index_metadata)?);
+    }
+
+    Ok(sort_values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use chrono::{TimeZone, Utc};
+
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::sort::{SortField, SortKey, SortOrder, MissingOrder, SortValue};
+    use search::script::{Script, BinaryOperator};
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use query_parser::QueryParseError;
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::{parse, parse_search_after};
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut date_mapping = field_mapping(1, FieldType::Date);
+        date_mapping.has_doc_values = true;
+
+        index_metadata(vec![
+            ("date", date_mapping),
+            ("title", field_mapping(2, FieldType::String)),
+        ])
+    }
+
+    #[test]
+    fn test_sort() {
+        let index_metadata = make_index_metadata();
+
+        let sort = parse(&serde_json::from_str("
+        [
+            {\"date\": \"desc\"},
+            {\"date\": {\"order\": \"asc\", \"missing\": \"_first\"}},
+            \"_score\",
+            \"_doc\"
+        ]
+        ").unwrap(), &index_metadata);
+
+        assert_eq!(sort, Ok(vec![
+            SortField {
+                key: SortKey::Field(FieldId(1)),
+                order: SortOrder::Desc,
+                missing: MissingOrder::Last,
+            },
+            SortField {
+                key: SortKey::Field(FieldId(1)),
+                order: SortOrder::Asc,
+                missing: MissingOrder::First,
+            },
+            SortField::new(SortKey::Score),
+            SortField::new(SortKey::Doc),
+        ]));
+    }
+
+    #[test]
+    fn test_sort_single_field() {
+        let index_metadata = make_index_metadata();
+
+        let sort = parse(&serde_json::from_str("\"date\"").unwrap(), &index_metadata);
+
+        assert_eq!(sort, Ok(vec![SortField::new(SortKey::Field(FieldId(1)))]));
+    }
+
+    #[test]
+    fn test_sort_errors() {
+        let index_metadata = make_index_metadata();
+
+        let sort = parse(&serde_json::from_str("\"foo\"").unwrap(), &index_metadata);
+        assert_eq!(sort, Err(QueryParseError::FieldDoesntExist("foo".to_string())));
+
+        let sort = parse(&serde_json::from_str("\"title\"").unwrap(), &index_metadata);
+        assert_eq!(sort, Err(QueryParseError::FieldNotSortable("title".to_string())));
+
+        let sort = parse(&serde_json::from_str("{\"date\": \"up\"}").unwrap(), &index_metadata);
+        assert_eq!(sort, Err(QueryParseError::InvalidValue));
+    }
+
+    #[test]
+    fn test_script_sort() {
+        let index_metadata = make_index_metadata();
+
+        let sort = parse(&json!({
+            "_script": {
+                "type": "number",
+                "script": {
+                    "source": "doc['date'].value * params.factor",
+                    "params": {"factor": 2}
+                },
+                "order": "desc"
+            }
+        }), &index_metadata);
+
+        assert_eq!(sort, Ok(vec![SortField {
+            key: SortKey::Script(Script::BinaryOp(BinaryOperator::Multiply, Box::new(Script::DocValue(FieldId(1))), Box::new(Script::Number(2.0)))),
+            order: SortOrder::Desc,
+            missing: MissingOrder::Last,
+        }]));
+
+        let sort = parse(&json!({"_script": {"type": "string", "script": "1"}}), &index_metadata);
+        assert_eq!(sort, Err(QueryParseError::InvalidValue));
+
+        let sort = parse(&json!({"_script": {"type": "number"}}), &index_metadata);
+        assert_eq!(sort, Err(QueryParseError::ExpectedKey("script")));
+
+        let sort = vec![SortField::new(SortKey::Script(Script::Score))];
+        let search_after = parse_search_after(&json!([1.5]), &sort, &index_metadata);
+        assert_eq!(search_after, Ok(vec![SortValue::Script(Some(1.5))]));
+    }
+
+    #[test]
+    fn test_search_after() {
+        let index_metadata = make_index_metadata();
+        let sort = vec![
+            SortField::new(SortKey::Field(FieldId(1))),
+            SortField::new(SortKey::Score),
+            SortField::new(SortKey::Doc),
+        ];
+
+        let search_after = parse_search_after(&serde_json::from_str("[1500000000123, 1.5, 42]").unwrap(), &sort, &index_metadata);
+
+        assert_eq!(search_after, Ok(vec![
+            SortValue::Field(Some(FieldValue::DateTime(Utc.timestamp(1500000000, 123000000)))),
+            SortValue::Score(1.5),
+            SortValue::Doc(42),
+        ]));
+
+        // Must have a value for each sort field
+        let search_after = parse_search_after(&serde_json::from_str("[1500000000123]").unwrap(), &sort, &index_metadata);
+        assert_eq!(search_after, Err(QueryParseError::InvalidValue));
+    }
+}
@@ -0,0 +1,137 @@
+//! Parses the "_source" element of a search request
+
+use serde_json::Value as Json;
+use search::schema::FieldId;
+
+use index::metadata::IndexMetadata;
+use source_filter::SourceFilter;
+use query_parser::QueryParseError;
+use query_parser::utils::parse_string;
+
+
+/// Parses a single pattern or an array of them
+fn parse_patterns(json: &Json) -> Result<Vec<String>, QueryParseError> {
+    match *json {
+        Json::Array(ref array) => {
+            let mut patterns = Vec::with_capacity(array.len());
+
+            for item in array.iter() {
+                patterns.push(parse_string(item)?);
+            }
+
+            Ok(patterns)
+        }
+        _ => Ok(vec![parse_string(json)?]),
+    }
+}
+
+
+/// Parses a source filter, which can be a boolean, one or more include patterns, or an
+/// object with "includes" and "excludes" keys
+pub fn parse(json: &Json) -> Result<SourceFilter, QueryParseError> {
+    match *json {
+        Json::Bool(true) => Ok(SourceFilter::default()),
+        Json::Bool(false) => Ok(SourceFilter::Disabled),
+        Json::Object(ref object) => {
+            let mut includes = Vec::new();
+            let mut excludes = Vec::new();
+
+            for (key, val) in object.iter() {
+                match key.as_ref() {
+                    "includes" | "include" => includes = parse_patterns(val)?,
+                    "excludes" | "exclude" => excludes = parse_patterns(val)?,
+                    _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+                }
+            }
+
+            Ok(SourceFilter::Filter {
+                includes: includes,
+                excludes: excludes,
+            })
+        }
+        _ => {
+            Ok(SourceFilter::Filter {
+                includes: parse_patterns(json)?,
+                excludes: Vec::new(),
+            })
+        }
+    }
+}
+
+
+/// Parses the "stored_fields" element. "_none_" disables stored fields and the source
+pub fn parse_stored_fields(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<(String, FieldId)>, QueryParseError> {
+    let mut fields = Vec::new();
+
+    for field_name in parse_patterns(json)? {
+        if field_name == "_none_" {
+            continue;
+        }
+
+        let field_mapping = match index_metadata.get_field_mapping(&field_name) {
+            Some(field_mapping) => field_mapping,
+            None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+        };
+
+        match field_mapping.index_ref {
+            Some(field_ref) if field_mapping.is_stored => fields.push((field_name, field_ref)),
+            _ => return Err(QueryParseError::FieldNotStored(field_name)),
+        }
+    }
+
+    Ok(fields)
+}
+
+
+/// Parses the "docvalue_fields" element. Each item is a field name or an object with a "field" key
+pub fn parse_docvalue_fields(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<(String, FieldId)>, QueryParseError> {
+    let array = json.as_array().ok_or(QueryParseError::ExpectedArray)?;
+    let mut fields = Vec::with_capacity(array.len());
+
+    for item in array.iter() {
+        let field_name = match item.get("field") {
+            Some(field_name) => parse_string(field_name)?,
+            None => parse_string(item)?,
+        };
+
+        let field_mapping = match index_metadata.get_field_mapping(&field_name) {
+            Some(field_mapping) => field_mapping,
+            None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+        };
+
+        match field_mapping.index_ref {
+            Some(field_ref) if field_mapping.has_doc_values => fields.push((field_name, field_ref)),
+            _ => return Err(QueryParseError::FieldHasNoDocValues(field_name)),
+        }
+    }
+
+    Ok(fields)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use source_filter::SourceFilter;
+    use query_parser::QueryParseError;
+
+    use super::parse;
+
+    #[test]
+    fn test_source_filter() {
+        assert_eq!(parse(&json!(false)), Ok(SourceFilter::Disabled));
+        assert_eq!(parse(&json!("user.*")), Ok(SourceFilter::Filter {
+            includes: vec!["user.*".to_string()],
+            excludes: vec![],
+        }));
+        assert_eq!(parse(&json!({"includes": ["title", "user.*"], "excludes": "user.email"})), Ok(SourceFilter::Filter {
+            includes: vec!["title".to_string(), "user.*".to_string()],
+            excludes: vec!["user.email".to_string()],
+        }));
+    }
+
+    #[test]
+    fn test_source_filter_errors() {
+        assert_eq!(parse(&json!({"foo": "bar"})), Err(QueryParseError::UnrecognisedKey("foo".to_string())));
+        assert_eq!(parse(&json!(1)), Err(QueryParseError::ExpectedString));
+    }
+}
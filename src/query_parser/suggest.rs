@@ -0,0 +1,387 @@
+//! Parses the "suggest" element of a search request
+
+use serde_json::Value as Json;
+
+use index::metadata::IndexMetadata;
+use mapping::FieldType;
+use suggest::{Suggester, TermSuggester, TermSuggestOptions, SuggestMode, SuggestSort};
+use suggest::completion::{CompletionSuggester, FuzzyOptions, ContextQuery};
+use query_parser::QueryParseError;
+use query_parser::utils::{parse_string, parse_float};
+
+
+fn parse_usize(json: &Json, name: &str) -> Result<usize, QueryParseError> {
+    match json.as_u64() {
+        Some(value) => Ok(value as usize),
+        None => Err(QueryParseError::InvalidSuggester(format!("{} must be a positive integer", name))),
+    }
+}
+
+
+fn parse_term_suggester(name: &str, text: String, json: &Json, index_metadata: &IndexMetadata) -> Result<TermSuggester, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let mut field_name = None;
+    let mut options = TermSuggestOptions::default();
+
+    for (key, val) in object.iter() {
+        match key.as_ref() {
+            "field" => field_name = Some(parse_string(val)?),
+            "size" => options.size = parse_usize(val, "size")?,
+            "prefix_length" => options.prefix_length = parse_usize(val, "prefix_length")?,
+            "min_word_length" => options.min_word_length = parse_usize(val, "min_word_length")?,
+            "min_doc_freq" => options.min_doc_freq = parse_usize(val, "min_doc_freq")? as u64,
+            "max_edits" => {
+                options.max_edits = match val.as_u64() {
+                    Some(max_edits) if max_edits == 1 || max_edits == 2 => max_edits as usize,
+                    _ => return Err(QueryParseError::InvalidSuggester("max_edits must be 1 or 2".to_string())),
+                };
+            }
+            "suggest_mode" => {
+                options.suggest_mode = match val.as_str().and_then(SuggestMode::from_name) {
+                    Some(suggest_mode) => suggest_mode,
+                    None => return Err(QueryParseError::InvalidSuggester("suggest_mode must be missing, popular or always".to_string())),
+                };
+            }
+            "sort" => {
+                options.sort = match val.as_str().and_then(SuggestSort::from_name) {
+                    Some(sort) => sort,
+                    None => return Err(QueryParseError::InvalidSuggester("sort must be score or frequency".to_string())),
+                };
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    let field_name = field_name.ok_or(QueryParseError::ExpectedKey("field"))?;
+    let field_mapping = match index_metadata.get_field_mapping(&field_name) {
+        Some(field_mapping) => field_mapping,
+        None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+    };
+
+    let field_ref = match field_mapping.index_ref {
+        Some(field_ref) if field_mapping.is_indexed => field_ref,
+        _ => return Err(QueryParseError::InvalidSuggester(format!("field {:?} isn't indexed", field_name))),
+    };
+
+    Ok(TermSuggester {
+        name: name.to_string(),
+        text: text,
+        field_ref: field_ref,
+        analyzer: field_mapping.index_analyzer().cloned(),
+        options: options,
+    })
+}
+
+
+fn parse_boolean(json: &Json, name: &str) -> Result<bool, QueryParseError> {
+    match json.as_bool() {
+        Some(value) => Ok(value),
+        None => Err(QueryParseError::InvalidSuggester(format!("{} must be true or false", name))),
+    }
+}
+
+
+/// Parses "fuzziness", which is a number of edits from 0 to 2 or "AUTO"
+fn parse_fuzziness(json: &Json) -> Result<Option<usize>, QueryParseError> {
+    let fuzziness = match *json {
+        Json::String(ref fuzziness) if fuzziness == "AUTO" => return Ok(None),
+        Json::String(ref fuzziness) => fuzziness.parse::<u64>().ok(),
+        _ => json.as_u64(),
+    };
+
+    match fuzziness {
+        Some(fuzziness) if fuzziness <= 2 => Ok(Some(fuzziness as usize)),
+        _ => Err(QueryParseError::InvalidSuggester("fuzziness must be 0, 1, 2 or AUTO".to_string())),
+    }
+}
+
+
+fn parse_fuzzy_options(json: &Json) -> Result<Option<FuzzyOptions>, QueryParseError> {
+    let object = match *json {
+        Json::Bool(fuzzy) => return Ok(if fuzzy { Some(FuzzyOptions::default()) } else { None }),
+        Json::Object(ref object) => object,
+        _ => return Err(QueryParseError::ExpectedObject),
+    };
+
+    let mut fuzzy = FuzzyOptions::default();
+    for (key, val) in object.iter() {
+        match key.as_ref() {
+            "fuzziness" => fuzzy.fuzziness = parse_fuzziness(val)?,
+            "transpositions" => fuzzy.transpositions = parse_boolean(val, "transpositions")?,
+            "min_length" => fuzzy.min_length = parse_usize(val, "min_length")?,
+            "prefix_length" => fuzzy.prefix_length = parse_usize(val, "prefix_length")?,
+            "unicode_aware" => {}
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(Some(fuzzy))
+}
+
+
+/// Parses the values of one context. Each is either a string or an object with "context"
+/// and "boost"
+fn parse_context_queries(json: &Json) -> Result<Vec<ContextQuery>, QueryParseError> {
+    let items = match *json {
+        Json::Array(ref items) => items.iter().collect::<Vec<_>>(),
+        _ => vec![json],
+    };
+
+    let mut context_queries = Vec::new();
+    for item in items {
+        let context_query = match *item {
+            Json::String(ref value) => ContextQuery { value: value.clone(), boost: 1.0 },
+            Json::Object(ref object) => {
+                let mut context_query = ContextQuery { value: String::new(), boost: 1.0 };
+                let mut has_value = false;
+
+                for (key, val) in object.iter() {
+                    match key.as_ref() {
+                        "context" => {
+                            context_query.value = parse_string(val)?;
+                            has_value = true;
+                        }
+                        "boost" => context_query.boost = parse_float(val)? as f64,
+                        _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+                    }
+                }
+
+                if !has_value {
+                    return Err(QueryParseError::ExpectedKey("context"));
+                }
+
+                context_query
+            }
+            _ => return Err(QueryParseError::ExpectedObjectOrString),
+        };
+
+        context_queries.push(context_query);
+    }
+
+    Ok(context_queries)
+}
+
+
+fn parse_completion_suggester(name: &str, prefix: String, json: &Json, index_metadata: &IndexMetadata) -> Result<CompletionSuggester, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let field_name = match object.get("field") {
+        Some(field_name) => parse_string(field_name)?,
+        None => return Err(QueryParseError::ExpectedKey("field")),
+    };
+    let field_mapping = match index_metadata.get_field_mapping(&field_name) {
+        Some(field_mapping) => field_mapping,
+        None => return Err(QueryParseError::FieldDoesntExist(field_name)),
+    };
+
+    let field_ref = match field_mapping.index_ref {
+        Some(field_ref) if field_mapping.data_type == FieldType::Completion => field_ref,
+        _ => return Err(QueryParseError::InvalidSuggester(format!("field {:?} isn't a completion field", field_name))),
+    };
+
+    let mut suggester = CompletionSuggester::new(name.to_string(), prefix, field_ref);
+    suggester.source_field = index_metadata.get_field_mapping("_source").and_then(|field_mapping| field_mapping.index_ref);
+
+    for (key, val) in object.iter() {
+        match key.as_ref() {
+            "field" => {}
+            "size" => suggester.size = parse_usize(val, "size")?,
+            "skip_duplicates" => suggester.skip_duplicates = parse_boolean(val, "skip_duplicates")?,
+            "fuzzy" => suggester.fuzzy = parse_fuzzy_options(val)?,
+            "contexts" => {
+                let contexts_object = val.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+                for (context_name, context_json) in contexts_object.iter() {
+                    if !field_mapping.completion_contexts.contains(context_name) {
+                        return Err(QueryParseError::InvalidSuggester(format!("field {:?} doesn't have a context called {:?}", field_name, context_name)));
+                    }
+
+                    suggester.contexts.insert(context_name.clone(), parse_context_queries(context_json)?);
+                }
+            }
+            _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+        }
+    }
+
+    Ok(suggester)
+}
+
+
+/// Parses a suggest definition
+///
+/// Each key is the name of a suggester, apart from "text" which sets the text for any
+/// suggesters that don't have their own. Completion suggesters can take a "prefix"
+/// instead of text.
+pub fn parse(json: &Json, index_metadata: &IndexMetadata) -> Result<Vec<Suggester>, QueryParseError> {
+    let object = json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+    let global_text = match object.get("text") {
+        Some(text) => Some(parse_string(text)?),
+        None => None,
+    };
+
+    let mut suggesters = Vec::new();
+    for (name, suggester_json) in object.iter() {
+        if name == "text" {
+            continue;
+        }
+
+        let suggester_object = suggester_json.as_object().ok_or(QueryParseError::ExpectedObject)?;
+
+        let mut text = global_text.clone();
+        let mut prefix = None;
+        let mut term_json = None;
+        let mut completion_json = None;
+        for (key, val) in suggester_object.iter() {
+            match key.as_ref() {
+                "text" => text = Some(parse_string(val)?),
+                "prefix" => prefix = Some(parse_string(val)?),
+                "term" => term_json = Some(val),
+                "completion" => completion_json = Some(val),
+                "phrase" | "regex" => return Err(QueryParseError::InvalidSuggester(format!("{} suggesters aren't supported", key))),
+                _ => return Err(QueryParseError::UnrecognisedKey(key.clone())),
+            }
+        }
+
+        match (term_json, completion_json) {
+            (Some(term_json), None) => {
+                let text = text.ok_or(QueryParseError::ExpectedKey("text"))?;
+                suggesters.push(Suggester::Term(parse_term_suggester(name, text, term_json, index_metadata)?));
+            }
+            (None, Some(completion_json)) => {
+                let prefix = prefix.or(text).ok_or(QueryParseError::ExpectedKey("prefix"))?;
+                suggesters.push(Suggester::Completion(parse_completion_suggester(name, prefix, completion_json, index_metadata)?));
+            }
+            (Some(_), Some(_)) => return Err(QueryParseError::InvalidSuggester(format!("suggester {:?} must have one type", name))),
+            (None, None) => return Err(QueryParseError::ExpectedKey("term")),
+        }
+    }
+
+    Ok(suggesters)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use index::metadata::IndexMetadata;
+    use mapping::FieldType;
+    use suggest::{Suggester, TermSuggester, TermSuggestOptions, SuggestMode};
+    use suggest::completion::{CompletionSuggester, FuzzyOptions, ContextQuery};
+    use query_parser::QueryParseError;
+    use query_parser::test_utils::{index_metadata, field_mapping};
+
+    use super::parse;
+
+    fn make_index_metadata() -> IndexMetadata {
+        let mut suggest_mapping = field_mapping(2, FieldType::Completion);
+        suggest_mapping.completion_contexts = vec!["genre".to_string()];
+
+        index_metadata(vec![
+            ("title", field_mapping(1, FieldType::String)),
+            ("suggest", suggest_mapping),
+        ])
+    }
+
+    fn term_suggester(suggester: &Suggester) -> &TermSuggester {
+        match *suggester {
+            Suggester::Term(ref suggester) => suggester,
+            _ => panic!("expected a term suggester, got {:?}", suggester),
+        }
+    }
+
+    #[test]
+    fn test_suggest() {
+        let index_metadata = make_index_metadata();
+
+        let suggesters = parse(&json!({
+            "text": "helo wrld",
+            "first": {
+                "term": {"field": "title"},
+            },
+            "second": {
+                "text": "tset",
+                "term": {"field": "title", "max_edits": 1, "suggest_mode": "always", "size": 3},
+            },
+        }), &index_metadata).unwrap();
+
+        assert_eq!(suggesters.len(), 2);
+        assert_eq!(suggesters[0].name(), "first");
+        assert_eq!(term_suggester(&suggesters[0]).text, "helo wrld");
+        assert_eq!(term_suggester(&suggesters[0]).field_ref, FieldId(1));
+        assert_eq!(term_suggester(&suggesters[0]).options, TermSuggestOptions::default());
+        assert_eq!(term_suggester(&suggesters[1]).text, "tset");
+        assert_eq!(term_suggester(&suggesters[1]).options, TermSuggestOptions {
+            max_edits: 1,
+            suggest_mode: SuggestMode::Always,
+            size: 3,
+            ..TermSuggestOptions::default()
+        });
+    }
+
+    #[test]
+    fn test_suggest_errors() {
+        let index_metadata = make_index_metadata();
+
+        let suggesters = parse(&json!({"s": {"term": {"field": "title"}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::ExpectedKey("text")));
+
+        let suggesters = parse(&json!({"s": {"text": "foo", "term": {"field": "foo"}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::FieldDoesntExist("foo".to_string())));
+
+        let suggesters = parse(&json!({"s": {"text": "foo", "term": {"field": "title", "max_edits": 3}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::InvalidSuggester("max_edits must be 1 or 2".to_string())));
+    }
+
+    #[test]
+    fn test_completion_suggest() {
+        let index_metadata = make_index_metadata();
+
+        let suggesters = parse(&json!({
+            "song": {
+                "prefix": "nir",
+                "completion": {
+                    "field": "suggest",
+                    "size": 3,
+                    "skip_duplicates": true,
+                    "fuzzy": {"fuzziness": 1},
+                    "contexts": {"genre": ["rock", {"context": "grunge", "boost": 2}]},
+                },
+            },
+        }), &index_metadata).unwrap();
+
+        let mut expected = CompletionSuggester::new("song".to_string(), "nir".to_string(), FieldId(2));
+        expected.size = 3;
+        expected.skip_duplicates = true;
+        expected.fuzzy = Some(FuzzyOptions { fuzziness: Some(1), ..FuzzyOptions::default() });
+        expected.contexts.insert("genre".to_string(), vec![
+            ContextQuery { value: "rock".to_string(), boost: 1.0 },
+            ContextQuery { value: "grunge".to_string(), boost: 2.0 },
+        ]);
+        assert_eq!(suggesters, vec![Suggester::Completion(expected)]);
+
+        // The text can be used instead of a prefix
+        let suggesters = parse(&json!({"text": "nir", "song": {"completion": {"field": "suggest", "fuzzy": true}}}), &index_metadata).unwrap();
+        let mut expected = CompletionSuggester::new("song".to_string(), "nir".to_string(), FieldId(2));
+        expected.fuzzy = Some(FuzzyOptions::default());
+        assert_eq!(suggesters, vec![Suggester::Completion(expected)]);
+    }
+
+    #[test]
+    fn test_completion_suggest_errors() {
+        let index_metadata = make_index_metadata();
+
+        let suggesters = parse(&json!({"s": {"completion": {"field": "suggest"}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::ExpectedKey("prefix")));
+
+        let suggesters = parse(&json!({"s": {"prefix": "nir", "completion": {"field": "title"}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::InvalidSuggester("field \"title\" isn't a completion field".to_string())));
+
+        let suggesters = parse(&json!({"s": {"prefix": "nir", "completion": {"field": "suggest", "contexts": {"decade": "90s"}}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::InvalidSuggester("field \"suggest\" doesn't have a context called \"decade\"".to_string())));
+
+        let suggesters = parse(&json!({"s": {"prefix": "nir", "completion": {"field": "suggest", "fuzzy": {"fuzziness": 3}}}}), &index_metadata);
+        assert_eq!(suggesters, Err(QueryParseError::InvalidSuggester("fuzziness must be 0, 1, 2 or AUTO".to_string())));
+    }
+}
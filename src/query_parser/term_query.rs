@@ -5,7 +5,7 @@ use search::{Term, Query, TermScorer};
 use search::schema::Schema;
 
 use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
-use query_parser::utils::{parse_float, json_value_to_term};
+use query_parser::utils::{parse_float, json_value_to_term, check_field_exists};
 
 
 #[derive(Debug)]
@@ -27,6 +27,10 @@ impl QueryBuilder for TermQueryBuilder {
         // Add boost
         query.boost(self.boost)
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        check_field_exists(schema, &self.field)
+    }
 }
 
 
@@ -190,6 +194,26 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn test_check_fields() {
+        let mut schema = Schema::new();
+        schema.add_field("foo".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        let builder = parse(&serde_json::from_str("
+        {
+            \"foo\": \"bar\"
+        }
+        ").unwrap()).unwrap();
+        assert_eq!(builder.check_fields(&schema), Ok(()));
+
+        let builder = parse(&serde_json::from_str("
+        {
+            \"baz\": \"bar\"
+        }
+        ").unwrap()).unwrap();
+        assert_eq!(builder.check_fields(&schema), Err(QueryParseError::FieldDoesntExist("baz".to_string())));
+    }
+
     #[test]
     fn test_gives_error_for_incorrect_type() {
         // Array
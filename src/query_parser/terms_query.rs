@@ -5,7 +5,7 @@ use search::{Term, Query, TermScorer};
 use search::schema::Schema;
 
 use query_parser::{QueryBuildContext, QueryParseError, QueryBuilder};
-use query_parser::utils::json_value_to_term;
+use query_parser::utils::{json_value_to_term, check_field_exists};
 
 #[derive(Debug)]
 struct TermsQueryBuilder {
@@ -28,6 +28,10 @@ impl QueryBuilder for TermsQueryBuilder {
 
         Query::Disjunction { queries: queries }
     }
+
+    fn check_fields(&self, schema: &Schema) -> Result<(), QueryParseError> {
+        check_field_exists(schema, &self.field)
+    }
 }
 
 
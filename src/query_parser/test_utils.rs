@@ -0,0 +1,31 @@
+//! Helpers shared by the query parsers' tests
+
+use std::collections::HashMap;
+
+use search::schema::FieldId;
+use index::metadata::IndexMetadata;
+use mapping::{Mapping, MappingProperty, FieldMapping, FieldType};
+
+
+/// Makes a field mapping of the given type, linked to a field in the store
+pub fn field_mapping(field_id: u32, data_type: FieldType) -> FieldMapping {
+    let mut mapping = FieldMapping::default();
+    mapping.data_type = data_type;
+    mapping.index_ref = Some(FieldId(field_id));
+    mapping
+}
+
+
+/// Makes an index with a single mapping, called "test", that has the given fields
+pub fn index_metadata(fields: Vec<(&str, FieldMapping)>) -> IndexMetadata {
+    let mut properties = HashMap::new();
+    for (name, field) in fields {
+        properties.insert(name.to_string(), MappingProperty::Field(field));
+    }
+
+    let mut index_metadata = IndexMetadata::default();
+    index_metadata.mappings.insert("test".to_string(), Mapping {
+        properties: properties,
+    });
+    index_metadata
+}
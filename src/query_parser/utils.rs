@@ -1,5 +1,6 @@
 use serde_json::Value as Json;
 use search::term::Term;
+use search::schema::Schema;
 
 use query_parser::QueryParseError;
 
@@ -25,6 +26,14 @@ pub fn parse_float(json: &Json) -> Result<f32, QueryParseError> {
 }
 
 
+pub fn check_field_exists(schema: &Schema, field_name: &str) -> Result<(), QueryParseError> {
+    match schema.get_field_by_name(field_name) {
+        Some(_) => Ok(()),
+        None => Err(QueryParseError::FieldDoesntExist(field_name.to_string())),
+    }
+}
+
+
 #[derive(Debug)]
 pub enum Operator {
     Or,
@@ -0,0 +1,246 @@
+//! Scores search results against documents that have been rated by hand
+//!
+//! Each query in a rank evaluation request comes with ratings for some of the documents it
+//! should find. The query's top k hits are scored with one of the metrics below, and the
+//! overall score is the mean of the scores of the queries.
+
+use serde_json::Value as Json;
+
+
+/// How many hits are looked at if "k" isn't given
+pub const DEFAULT_K: usize = 10;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metric {
+    /// The fraction of the hits that are relevant
+    Precision {
+        k: usize,
+        relevant_rating_threshold: u64,
+
+        /// Leave unrated hits out of the count rather than treating them as irrelevant
+        ignore_unlabeled: bool,
+    },
+
+    /// One over the rank of the first relevant hit
+    MeanReciprocalRank {
+        k: usize,
+        relevant_rating_threshold: u64,
+    },
+
+    /// Discounted cumulative gain. When normalized, this is divided by the gain of the best
+    /// possible ordering of the rated documents
+    DiscountedCumulativeGain {
+        k: usize,
+        normalize: bool,
+    },
+}
+
+
+fn parse_k(json: &Json) -> Result<usize, String> {
+    match json.get("k") {
+        Some(k_json) => {
+            match k_json.as_u64() {
+                Some(k) if k > 0 => Ok(k as usize),
+                _ => Err("k must be a positive integer".to_string()),
+            }
+        }
+        None => Ok(DEFAULT_K),
+    }
+}
+
+
+fn parse_relevant_rating_threshold(json: &Json) -> Result<u64, String> {
+    match json.get("relevant_rating_threshold") {
+        Some(threshold_json) => threshold_json.as_u64().ok_or_else(|| "relevant_rating_threshold must be a positive integer".to_string()),
+        None => Ok(1),
+    }
+}
+
+
+fn parse_bool(json: &Json, key: &str) -> Result<bool, String> {
+    match json.get(key) {
+        Some(value) => value.as_bool().ok_or_else(|| format!("{} must be a boolean", key)),
+        None => Ok(false),
+    }
+}
+
+
+/// The gain of a hit at the (zero based) position
+fn discounted_gain(rating: u64, position: usize) -> f64 {
+    (2.0f64.powf(rating as f64) - 1.0) / ((position + 2) as f64).log2()
+}
+
+
+impl Metric {
+    /// Parses a metric, which is an object with the metric's name as its only key
+    pub fn from_json(json: &Json) -> Result<Metric, String> {
+        let object = match json.as_object() {
+            Some(object) if object.len() == 1 => object,
+            _ => return Err("metric must be an object with a single key".to_string()),
+        };
+        let (name, options) = object.iter().next().unwrap();
+
+        if !options.is_object() {
+            return Err(format!("{} must be an object", name));
+        }
+
+        match name.as_ref() {
+            "precision" => {
+                Ok(Metric::Precision {
+                    k: parse_k(options)?,
+                    relevant_rating_threshold: parse_relevant_rating_threshold(options)?,
+                    ignore_unlabeled: parse_bool(options, "ignore_unlabeled")?,
+                })
+            }
+            "mean_reciprocal_rank" => {
+                Ok(Metric::MeanReciprocalRank {
+                    k: parse_k(options)?,
+                    relevant_rating_threshold: parse_relevant_rating_threshold(options)?,
+                })
+            }
+            "dcg" => {
+                Ok(Metric::DiscountedCumulativeGain {
+                    k: parse_k(options)?,
+                    normalize: parse_bool(options, "normalize")?,
+                })
+            }
+            _ => Err(format!("Unrecognised metric {:?}", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Metric::Precision { .. } => "precision",
+            Metric::MeanReciprocalRank { .. } => "mean_reciprocal_rank",
+            Metric::DiscountedCumulativeGain { .. } => "dcg",
+        }
+    }
+
+    /// How many hits are scored
+    pub fn k(&self) -> usize {
+        match *self {
+            Metric::Precision { k, .. } | Metric::MeanReciprocalRank { k, .. } | Metric::DiscountedCumulativeGain { k, .. } => k,
+        }
+    }
+
+    /// Scores a query's hits
+    ///
+    /// `hit_ratings` has the rating of each hit in rank order, or None if the hit wasn't
+    /// rated. `ratings` has every rating given for the query. Returns the score along with
+    /// the numbers it was worked out from.
+    pub fn evaluate(&self, hit_ratings: &[Option<u64>], ratings: &[u64]) -> (f64, Json) {
+        let hit_ratings = &hit_ratings[..hit_ratings.len().min(self.k())];
+
+        match *self {
+            Metric::Precision { relevant_rating_threshold, ignore_unlabeled, .. } => {
+                let relevant = hit_ratings.iter().filter(|rating| rating.map_or(false, |rating| rating >= relevant_rating_threshold)).count();
+                let retrieved = if ignore_unlabeled {
+                    hit_ratings.iter().filter(|rating| rating.is_some()).count()
+                } else {
+                    hit_ratings.len()
+                };
+
+                let score = if retrieved > 0 { relevant as f64 / retrieved as f64 } else { 0.0 };
+                (score, json!({
+                    "relevant_docs_retrieved": relevant,
+                    "docs_retrieved": retrieved,
+                }))
+            }
+            Metric::MeanReciprocalRank { relevant_rating_threshold, .. } => {
+                match hit_ratings.iter().position(|rating| rating.map_or(false, |rating| rating >= relevant_rating_threshold)) {
+                    Some(position) => (1.0 / (position + 1) as f64, json!({"first_relevant": position + 1})),
+                    None => (0.0, json!({"first_relevant": -1})),
+                }
+            }
+            Metric::DiscountedCumulativeGain { normalize, .. } => {
+                let dcg = hit_ratings.iter().enumerate()
+                    .map(|(position, rating)| discounted_gain(rating.unwrap_or(0), position))
+                    .sum::<f64>();
+                let unrated = hit_ratings.iter().filter(|rating| rating.is_none()).count();
+
+                if !normalize {
+                    return (dcg, json!({"dcg": dcg, "unrated_docs": unrated}));
+                }
+
+                // The best possible ordering puts the highest rated documents first
+                let mut ideal_ratings = ratings.to_vec();
+                ideal_ratings.sort_by(|a, b| b.cmp(a));
+                let ideal_dcg = ideal_ratings.iter().take(self.k()).enumerate()
+                    .map(|(position, &rating)| discounted_gain(rating, position))
+                    .sum::<f64>();
+
+                let normalized_dcg = if ideal_dcg > 0.0 { dcg / ideal_dcg } else { 0.0 };
+                (normalized_dcg, json!({
+                    "dcg": dcg,
+                    "ideal_dcg": ideal_dcg,
+                    "normalized_dcg": normalized_dcg,
+                    "unrated_docs": unrated,
+                }))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Metric, DEFAULT_K};
+
+    #[test]
+    fn test_from_json() {
+        assert_eq!(Metric::from_json(&json!({"precision": {"k": 5, "ignore_unlabeled": true}})), Ok(Metric::Precision {
+            k: 5,
+            relevant_rating_threshold: 1,
+            ignore_unlabeled: true,
+        }));
+        assert_eq!(Metric::from_json(&json!({"mean_reciprocal_rank": {"relevant_rating_threshold": 2}})), Ok(Metric::MeanReciprocalRank {
+            k: DEFAULT_K,
+            relevant_rating_threshold: 2,
+        }));
+        assert_eq!(Metric::from_json(&json!({"dcg": {"normalize": true}})), Ok(Metric::DiscountedCumulativeGain {
+            k: DEFAULT_K,
+            normalize: true,
+        }));
+
+        assert!(Metric::from_json(&json!({"precision": {"k": 0}})).is_err());
+        assert!(Metric::from_json(&json!({"recall": {}})).is_err());
+        assert!(Metric::from_json(&json!({"precision": {}, "dcg": {}})).is_err());
+    }
+
+    #[test]
+    fn test_precision() {
+        let metric = Metric::Precision { k: 4, relevant_rating_threshold: 1, ignore_unlabeled: false };
+        let (score, details) = metric.evaluate(&[Some(1), None, Some(0), Some(2), Some(3)], &[]);
+        assert_eq!(score, 0.5);
+        assert_eq!(details, json!({"relevant_docs_retrieved": 2, "docs_retrieved": 4}));
+
+        let metric = Metric::Precision { k: 4, relevant_rating_threshold: 1, ignore_unlabeled: true };
+        let (score, _) = metric.evaluate(&[Some(1), None, Some(0), Some(2)], &[]);
+        assert!((score - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(metric.evaluate(&[], &[]).0, 0.0);
+    }
+
+    #[test]
+    fn test_mean_reciprocal_rank() {
+        let metric = Metric::MeanReciprocalRank { k: 3, relevant_rating_threshold: 2 };
+        assert_eq!(metric.evaluate(&[Some(1), None, Some(2)], &[]), (1.0 / 3.0, json!({"first_relevant": 3})));
+        assert_eq!(metric.evaluate(&[Some(1), None, Some(1), Some(2)], &[]), (0.0, json!({"first_relevant": -1})));
+    }
+
+    #[test]
+    fn test_dcg() {
+        let metric = Metric::DiscountedCumulativeGain { k: 10, normalize: false };
+        let (score, _) = metric.evaluate(&[Some(3), Some(2), None], &[3, 2, 1]);
+        assert!((score - (7.0 + 3.0 / 3.0f64.log2())).abs() < 1e-9);
+
+        // Swapping the top two hits is worse than the ideal ordering
+        let metric = Metric::DiscountedCumulativeGain { k: 10, normalize: true };
+        assert_eq!(metric.evaluate(&[Some(3), Some(2), Some(1)], &[1, 2, 3]).0, 1.0);
+        let (score, _) = metric.evaluate(&[Some(2), Some(3), Some(1)], &[1, 2, 3]);
+        assert!(score < 1.0 && score > 0.0);
+
+        assert_eq!(metric.evaluate(&[None], &[]).0, 0.0);
+    }
+}
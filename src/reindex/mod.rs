@@ -0,0 +1,406 @@
+//! Copies documents from one index into another
+//!
+//! The documents to copy are found up front by running the query against the source index,
+//! and the segments they are in are pinned so they can still be read if they are merged
+//! away while the copy is running. Documents are then copied over in batches, each of which
+//! locks the cluster metadata only for as long as it takes to write that batch. Every
+//! document is reprocessed using the destination's mapping, so this can be used to apply
+//! mapping and analysis changes.
+//!
+//! The source can also be an index on another Elasticsearch cluster (see `remote`), in
+//! which case each page of its search results is copied as a batch.
+//!
+//! The writes of each batch are sent on to the destination's replicas before the next batch
+//! locks the cluster metadata.
+
+pub mod remote;
+
+use std::cmp;
+use std::usize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde_json::{Map, Value as Json};
+use slog::Logger;
+use uuid::Uuid;
+
+use search::document::DocId;
+use search::schema::FieldId;
+use search::cancellation::SearchCancellation;
+use search::profile::duration_to_nanos;
+use search::backends::rocksdb::{WriteCondition, DocumentInsertError};
+use document::{DocumentSource, read_source_field};
+use index::refresh::RefreshPolicy;
+use system::System;
+use tasks::TaskStatus;
+use update::{UpdateScript, UpdateOperation};
+use replication::{ReplicaOperation, replicate};
+
+use self::remote::{RemoteHost, RemoteScroll};
+
+
+/// How many documents are copied each time the cluster metadata is locked, unless the
+/// request sets `source.size`
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+
+/// Counts what has happened to the documents so far
+///
+/// This is also used by the update and delete by query APIs, which go through documents in
+/// the same way.
+#[derive(Debug, Default)]
+pub struct ReindexStatus {
+    pub total: AtomicU64,
+    pub created: AtomicU64,
+    pub updated: AtomicU64,
+    pub deleted: AtomicU64,
+    pub batches: AtomicU64,
+    pub version_conflicts: AtomicU64,
+    pub noops: AtomicU64,
+}
+
+
+impl ReindexStatus {
+    pub fn version_conflicts(&self) -> u64 {
+        self.version_conflicts.load(Ordering::Relaxed)
+    }
+}
+
+
+impl TaskStatus for ReindexStatus {
+    fn to_json(&self) -> Json {
+        json!({
+            "total": self.total.load(Ordering::Relaxed),
+            "created": self.created.load(Ordering::Relaxed),
+            "updated": self.updated.load(Ordering::Relaxed),
+            "deleted": self.deleted.load(Ordering::Relaxed),
+            "batches": self.batches.load(Ordering::Relaxed),
+            "version_conflicts": self.version_conflicts.load(Ordering::Relaxed),
+            "noops": self.noops.load(Ordering::Relaxed),
+        })
+    }
+}
+
+
+/// Where the documents are copied from
+#[derive(Debug)]
+pub enum ReindexSource {
+    /// An index on this node
+    Local {
+        /// The id of the index that's being copied from
+        index_id: Uuid,
+
+        /// Keeps the segments the documents were found in from being purged. See `IndexReader::pin_segments`
+        pins: Vec<u64>,
+
+        /// The field the index stores each document's source in
+        source_field: FieldId,
+
+        /// The documents to copy, with their keys. The ids are from the index's `IndexReader`
+        docs: Vec<(u64, String)>,
+    },
+
+    /// An index on another Elasticsearch cluster. The documents are found as they're copied
+    Remote {
+        host: RemoteHost,
+        index_name: String,
+        query: Option<Json>,
+
+        /// Stop after copying this many documents
+        max_docs: Option<usize>,
+    },
+}
+
+
+/// A batch of documents to copy, with their keys. The source is None if it isn't stored
+type Batch = Vec<(String, Option<Map<String, Json>>)>;
+
+
+#[derive(Debug)]
+pub struct Reindex {
+    pub source: ReindexSource,
+
+    /// How many documents are copied each time the cluster metadata is locked
+    pub batch_size: usize,
+
+    pub dest_index_name: String,
+    pub dest_mapping_name: String,
+
+    /// Only create documents in the destination, counting existing ones as version conflicts
+    pub create_only: bool,
+
+    pub script: Option<UpdateScript>,
+
+    /// Carry on after a version conflict rather than stopping
+    pub proceed_on_conflicts: bool,
+
+    pub refresh_policy: RefreshPolicy,
+
+    pub status: Arc<ReindexStatus>,
+}
+
+
+impl Reindex {
+    /// Copies the documents, returning the response for the reindex API
+    ///
+    /// `log` is the logger of the request that started the reindex.
+    pub fn run(&self, system: &System, log: &Logger, cancellation: &SearchCancellation) -> Json {
+        let start_time = Instant::now();
+        let mut failures = Vec::new();
+        let mut cancelled = false;
+
+        match self.source {
+            ReindexSource::Local { index_id, source_field, ref docs, .. } => {
+                self.status.total.store(docs.len() as u64, Ordering::Relaxed);
+
+                for docs in docs.chunks(self.batch_size) {
+                    let batch = match read_local_batch(system, index_id, source_field, docs) {
+                        Ok(batch) => batch,
+                        Err(failure) => {
+                            failures.push(failure);
+                            break;
+                        }
+                    };
+
+                    if !self.copy_batch(system, batch, cancellation, &mut failures, &mut cancelled) {
+                        break;
+                    }
+                }
+            }
+            ReindexSource::Remote { ref host, ref index_name, ref query, max_docs } => {
+                let remote_failure = |e: String| json!({"index": index_name, "cause": format!("Couldn't read from the remote cluster: {}", e)});
+                let mut remaining = max_docs.unwrap_or(usize::MAX);
+
+                match RemoteScroll::start(host, index_name, query.as_ref(), cmp::min(self.batch_size, cmp::max(remaining, 1))) {
+                    Ok(mut scroll) => {
+                        self.status.total.store(cmp::min(scroll.total, remaining as u64), Ordering::Relaxed);
+
+                        while remaining > 0 && !cancellation.is_cancelled() {
+                            let mut batch: Batch = match scroll.next_page() {
+                                Ok(ref documents) if documents.is_empty() => break,
+                                Ok(documents) => documents.into_iter().map(|document| (document.key, document.source)).collect(),
+                                Err(e) => {
+                                    failures.push(remote_failure(e));
+                                    break;
+                                }
+                            };
+                            batch.truncate(remaining);
+                            remaining -= batch.len();
+
+                            if !self.copy_batch(system, batch, cancellation, &mut failures, &mut cancelled) {
+                                break;
+                            }
+                        }
+
+                        cancelled |= cancellation.is_cancelled();
+
+                        if let Err(e) = scroll.clear() {
+                            warn!(log, "failed to clear remote scroll"; "host" => &host.address, "error" => e);
+                        }
+                    }
+                    Err(e) => failures.push(remote_failure(e)),
+                }
+            }
+        }
+
+        // Release the source index's segments and make the copies visible
+        {
+            let cluster_metadata = system.metadata.read().unwrap();
+
+            if let ReindexSource::Local { index_id, ref pins, .. } = self.source {
+                if let Some(source_index) = cluster_metadata.indices.values().find(|index| *index.id() == index_id) {
+                    source_index.unpin_segments(pins);
+                }
+            }
+
+            if let Some(dest_index) = cluster_metadata.names.find_canonical(&self.dest_index_name).and_then(|index_ref| cluster_metadata.indices.get(&index_ref)) {
+                if let Err(e) = dest_index.apply_refresh_policy(self.refresh_policy) {
+                    error!(log, "index refresh failed"; "index" => dest_index.canonical_name(), "error" => e);
+                }
+            }
+        }
+
+        let mut response = self.status.to_json();
+        response["took"] = json!(duration_to_nanos(start_time.elapsed()) / 1_000_000);
+        response["timed_out"] = json!(false);
+        response["failures"] = json!(failures);
+
+        if cancelled {
+            response["canceled"] = json!("by user request");
+        }
+
+        response
+    }
+
+    /// Writes a batch of documents to the destination, then sends the writes on to its
+    /// replicas
+    ///
+    /// Returns false if the reindex should stop.
+    fn copy_batch(&self, system: &System, batch: Batch, cancellation: &SearchCancellation, failures: &mut Vec<Json>, cancelled: &mut bool) -> bool {
+        let mut operations = Vec::new();
+        let finished = self.write_batch(system, batch, cancellation, failures, cancelled, &mut operations);
+
+        replicate(system, &self.dest_index_name, &operations);
+
+        if finished {
+            self.status.batches.fetch_add(1, Ordering::Relaxed);
+        }
+
+        finished
+    }
+
+    /// Writes a batch of documents to the destination, adding the writes that were made to
+    /// `operations`
+    ///
+    /// Returns false if the batch was stopped part of the way through.
+    fn write_batch(&self, system: &System, batch: Batch, cancellation: &SearchCancellation, failures: &mut Vec<Json>, cancelled: &mut bool, operations: &mut Vec<ReplicaOperation>) -> bool {
+        let cluster_metadata = system.metadata.read().unwrap();
+
+        let dest_index = match cluster_metadata.names.find_canonical(&self.dest_index_name).and_then(|index_ref| cluster_metadata.indices.get(&index_ref)) {
+            Some(dest_index) => dest_index,
+            None => {
+                failures.push(json!({"index": self.dest_index_name, "cause": "The destination index has been closed or deleted"}));
+                return false;
+            }
+        };
+        let dest_metadata = dest_index.metadata.read().unwrap();
+
+        if dest_metadata.settings.blocks.blocks_write() {
+            failures.push(json!({"index": self.dest_index_name, "cause": "The destination index is blocked for write operations"}));
+            return false;
+        }
+
+        let mapping = match dest_metadata.mappings.get(&self.dest_mapping_name) {
+            Some(mapping) => mapping,
+            None => {
+                failures.push(json!({"index": self.dest_index_name, "cause": "The destination mapping has been removed"}));
+                return false;
+            }
+        };
+
+        for (doc_key, source) in batch {
+            if cancellation.is_cancelled() {
+                *cancelled = true;
+                return false;
+            }
+
+            let failure = |cause: String| json!({"index": self.dest_index_name, "type": self.dest_mapping_name, "id": doc_key, "cause": cause});
+
+            let mut source = match source {
+                Some(source) => source,
+                None => {
+                    failures.push(failure("The document's source isn't stored, so it can't be copied".to_string()));
+                    continue;
+                }
+            };
+
+            let operation = match self.script.as_ref().map(|script| script.run(&mut source)) {
+                Some(Ok(operation)) => operation,
+                Some(Err(e)) => {
+                    failures.push(failure(format!("Script error: {}", e)));
+                    continue;
+                }
+                None => UpdateOperation::Index,
+            };
+
+            match operation {
+                UpdateOperation::Index => {
+                    let doc = match (DocumentSource { key: &doc_key, data: &source }).prepare(mapping) {
+                        Ok(doc) => doc,
+                        Err(e) => {
+                            failures.push(failure(format!("Couldn't index document: {:?}", e)));
+                            continue;
+                        }
+                    };
+
+                    let condition = if self.create_only { Some(WriteCondition::NotExists) } else { None };
+                    let shard = dest_index.shard_number_for_routing(&doc_key);
+                    match dest_index.shards()[shard].insert_or_update_document_with_condition(&doc, condition.as_ref()) {
+                        Ok(write) => {
+                            operations.push(ReplicaOperation::index(shard, &doc_key, &self.dest_mapping_name, &source, write.version.version));
+
+                            if write.created {
+                                self.status.created.fetch_add(1, Ordering::Relaxed)
+                            } else {
+                                self.status.updated.fetch_add(1, Ordering::Relaxed)
+                            }
+                        }
+                        Err(DocumentInsertError::VersionConflict(conflict)) => {
+                            self.status.version_conflicts.fetch_add(1, Ordering::Relaxed);
+
+                            if !self.proceed_on_conflicts {
+                                failures.push(failure(format!("[{}][{}]: {}", self.dest_mapping_name, doc_key, conflict)));
+                                return false;
+                            }
+
+                            continue;
+                        }
+                        Err(e) => panic!("document insert failed: {:?}", e),
+                    };
+                }
+                UpdateOperation::Delete => {
+                    let shard = dest_index.shard_number_for_routing(&doc_key);
+                    match dest_index.shards()[shard].remove_document_by_key_with_condition(&doc_key, None) {
+                        Ok(Some(version)) => {
+                            operations.push(ReplicaOperation::delete(shard, &doc_key, version.version));
+                            self.status.deleted.fetch_add(1, Ordering::Relaxed)
+                        }
+                        Ok(None) => self.status.noops.fetch_add(1, Ordering::Relaxed),
+                        Err(e) => panic!("document delete failed: {:?}", e),
+                    };
+                }
+                UpdateOperation::Noop => {
+                    self.status.noops.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        true
+    }
+}
+
+
+/// Reads the sources of a batch of documents from an index on this node
+fn read_local_batch(system: &System, index_id: Uuid, source_field: FieldId, docs: &[(u64, String)]) -> Result<Batch, Json> {
+    let cluster_metadata = system.metadata.read().unwrap();
+
+    let source_index = match cluster_metadata.indices.values().find(|index| *index.id() == index_id) {
+        Some(source_index) => source_index,
+        None => return Err(json!({"cause": "The source index has been closed or deleted"})),
+    };
+    let source_reader = source_index.reader();
+
+    Ok(docs.iter().map(|&(doc_id, ref doc_key)| {
+        (doc_key.clone(), read_source_field(source_reader.shard_for_doc(doc_id), source_field, DocId::from_u64(doc_id)))
+    }).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use tasks::TaskStatus;
+
+    use super::ReindexStatus;
+
+    #[test]
+    fn test_status_to_json() {
+        let status = ReindexStatus::default();
+        status.total.store(5, Ordering::Relaxed);
+        status.created.fetch_add(3, Ordering::Relaxed);
+        status.version_conflicts.fetch_add(1, Ordering::Relaxed);
+
+        assert_eq!(status.version_conflicts(), 1);
+        assert_eq!(status.to_json(), json!({
+            "total": 5,
+            "created": 3,
+            "updated": 0,
+            "deleted": 0,
+            "batches": 0,
+            "version_conflicts": 1,
+            "noops": 0,
+        }));
+    }
+}
@@ -0,0 +1,277 @@
+//! Reads the documents of an index on another Elasticsearch cluster
+//!
+//! This is used by reindexes with a `remote` source, to migrate indices from Elasticsearch.
+//! The index is searched with a scroll, so its documents are read a page at a time while
+//! they're being copied, and the scroll is cleared once the reindex has finished. Only plain
+//! HTTP is supported, and the other cluster must take JSON bodies in the scroll API
+//! (Elasticsearch 2.1 or later).
+
+use std::time::Duration;
+
+use base64;
+use hyper::Method;
+use serde_json::{Map, Value as Json};
+use url::Url;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use lifecycle::parse_time_value;
+use cluster::transport::{Transport, TransportRequest, parse_json_response};
+
+
+const DEFAULT_SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the other cluster keeps the scroll open between pages
+const SCROLL_KEEP_ALIVE: &'static str = "5m";
+
+
+/// The cluster to reindex from, from the `source.remote` section of a reindex request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteHost {
+    /// "host:port" of a node of the cluster
+    pub address: String,
+
+    /// Put before the path of every request, for clusters behind a proxy. Has no trailing slash
+    pub path_prefix: String,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    /// Sent with every request
+    pub headers: Vec<(String, String)>,
+
+    /// How long to wait for each response
+    pub socket_timeout: Duration,
+
+    pub connect_timeout: Duration,
+}
+
+
+impl RemoteHost {
+    pub fn parse(json: &Json) -> Result<RemoteHost, String> {
+        let json = json.as_object().ok_or("source.remote must be an object")?;
+        let host = json.get("host").and_then(|host| host.as_str()).ok_or("source.remote.host must be a string")?;
+        let url = Url::parse(host).map_err(|e| format!("Invalid source.remote.host {:?}: {}", host, e))?;
+
+        if url.scheme() != "http" {
+            return Err(format!("Only http hosts can be reindexed from, not {:?}", host));
+        }
+
+        let address = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host_name), Some(port)) => format!("{}:{}", host_name, port),
+            _ => return Err(format!("source.remote.host must have a host name: {:?}", host)),
+        };
+
+        let mut remote = RemoteHost {
+            address: address,
+            path_prefix: url.path().trim_end_matches('/').to_string(),
+            username: None,
+            password: None,
+            headers: Vec::new(),
+            socket_timeout: DEFAULT_SOCKET_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        };
+
+        for (key, value) in json.iter() {
+            match key.as_ref() {
+                "host" => {}
+                "username" => remote.username = Some(value.as_str().ok_or("source.remote.username must be a string")?.to_string()),
+                "password" => remote.password = Some(value.as_str().ok_or("source.remote.password must be a string")?.to_string()),
+                "headers" => {
+                    let headers = value.as_object().ok_or("source.remote.headers must be an object")?;
+                    for (name, value) in headers.iter() {
+                        let value = value.as_str().ok_or_else(|| format!("source.remote.headers.{} must be a string", name))?;
+                        remote.headers.push((name.clone(), value.to_string()));
+                    }
+                }
+                "socket_timeout" => remote.socket_timeout = value.as_str().and_then(parse_time_value).ok_or("source.remote.socket_timeout must be a time value, such as 30s")?,
+                "connect_timeout" => remote.connect_timeout = value.as_str().and_then(parse_time_value).ok_or("source.remote.connect_timeout must be a time value, such as 30s")?,
+                _ => return Err(format!("Unrecognised key in source.remote: {:?}", key)),
+            }
+        }
+
+        Ok(remote)
+    }
+
+    fn request(&self, method: Method, path: &str, body: &Json) -> TransportRequest {
+        let mut request = TransportRequest::new(&self.address, method, &format!("{}{}", self.path_prefix, path)).with_json(body);
+
+        if let Some(ref username) = self.username {
+            let credentials = format!("{}:{}", username, self.password.as_ref().map_or("", |password| password.as_str()));
+            request.headers.push(("Authorization".to_string(), format!("Basic {}", base64::encode(&credentials))));
+        }
+
+        request.headers.extend(self.headers.iter().cloned());
+        request
+    }
+}
+
+
+/// A document read from the other cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteDocument {
+    pub key: String,
+
+    /// None if the index doesn't store the source of its documents
+    pub source: Option<Map<String, Json>>,
+}
+
+
+/// Reads the scroll id, total hits and documents from a page of search results
+///
+/// The total is an object in Elasticsearch 7 and later, and a number before that.
+fn parse_page(body: &Json) -> Result<(Option<String>, u64, Vec<RemoteDocument>), String> {
+    let scroll_id = body.get("_scroll_id").and_then(|scroll_id| scroll_id.as_str()).map(|scroll_id| scroll_id.to_string());
+    let hits = body.get("hits").ok_or("the response has no hits")?;
+    let total = match hits.get("total") {
+        Some(&Json::Object(ref total)) => total.get("value").and_then(|value| value.as_u64()),
+        Some(total) => total.as_u64(),
+        None => None,
+    }.unwrap_or(0);
+
+    let mut documents = Vec::new();
+    for hit in hits.get("hits").and_then(|hits| hits.as_array()).ok_or("the response has no hits")? {
+        let key = hit.get("_id").and_then(|key| key.as_str()).ok_or("a hit has no _id")?;
+
+        documents.push(RemoteDocument {
+            key: key.to_string(),
+            source: hit.get("_source").and_then(|source| source.as_object()).cloned(),
+        });
+    }
+
+    Ok((scroll_id, total, documents))
+}
+
+
+/// A scroll through an index on the other cluster
+pub struct RemoteScroll {
+    host: RemoteHost,
+    transport: Transport,
+    scroll_id: Option<String>,
+
+    /// The first page comes back with the search that starts the scroll
+    first_page: Option<Vec<RemoteDocument>>,
+
+    /// How many documents the search matched
+    pub total: u64,
+}
+
+
+impl RemoteScroll {
+    /// Starts scrolling through the documents that match the query, `size` at a time
+    pub fn start(host: &RemoteHost, index_name: &str, query: Option<&Json>, size: usize) -> Result<RemoteScroll, String> {
+        let transport = Transport::external(host.connect_timeout).map_err(|e| format!("couldn't start the transport: {}", e))?;
+        let path = format!("/{}/_search?scroll={}", utf8_percent_encode(index_name, PATH_SEGMENT_ENCODE_SET), SCROLL_KEEP_ALIVE);
+        let body = json!({
+            "size": size,
+            "query": query.cloned().unwrap_or_else(|| json!({"match_all": {}})),
+            "sort": ["_doc"],
+        });
+
+        let response = parse_json_response(transport.send(host.request(Method::POST, &path, &body), host.socket_timeout))?;
+        let (scroll_id, total, documents) = parse_page(&response)?;
+
+        Ok(RemoteScroll {
+            host: host.clone(),
+            transport: transport,
+            scroll_id: scroll_id,
+            first_page: Some(documents),
+            total: total,
+        })
+    }
+
+    /// Reads the next page of documents. This is empty once all of them have been read
+    pub fn next_page(&mut self) -> Result<Vec<RemoteDocument>, String> {
+        if let Some(documents) = self.first_page.take() {
+            return Ok(documents);
+        }
+
+        let scroll_id = match self.scroll_id {
+            Some(ref scroll_id) => scroll_id.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let body = json!({"scroll": SCROLL_KEEP_ALIVE, "scroll_id": scroll_id});
+        let response = parse_json_response(self.transport.send(self.host.request(Method::POST, "/_search/scroll", &body), self.host.socket_timeout))?;
+        let (scroll_id, _, documents) = parse_page(&response)?;
+
+        // The id can change between pages
+        if scroll_id.is_some() {
+            self.scroll_id = scroll_id;
+        }
+
+        Ok(documents)
+    }
+
+    /// Frees the scroll on the other cluster, rather than leaving it to expire
+    pub fn clear(&mut self) -> Result<(), String> {
+        let scroll_id = match self.scroll_id.take() {
+            Some(scroll_id) => scroll_id,
+            None => return Ok(()),
+        };
+
+        let body = json!({"scroll_id": [scroll_id]});
+        parse_json_response(self.transport.send(self.host.request(Method::DELETE, "/_search/scroll", &body), self.host.socket_timeout)).map(|_| ())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::Method;
+
+    use super::{RemoteHost, RemoteDocument, parse_page};
+
+    #[test]
+    fn test_parse_host() {
+        let host = RemoteHost::parse(&json!({
+            "host": "http://old.example.com:9200/es/",
+            "username": "migrate",
+            "password": "secret",
+            "headers": {"X-Team": "search"},
+            "socket_timeout": "1m",
+        })).unwrap();
+
+        assert_eq!(host.address, "old.example.com:9200");
+        assert_eq!(host.path_prefix, "/es");
+        assert_eq!(host.username, Some("migrate".to_string()));
+        assert_eq!(host.headers, vec![("X-Team".to_string(), "search".to_string())]);
+        assert_eq!(host.socket_timeout, Duration::from_secs(60));
+        assert_eq!(host.connect_timeout, Duration::from_secs(30));
+
+        let request = host.request(Method::POST, "/_search/scroll", &json!({}));
+        assert_eq!(request.path, "/es/_search/scroll");
+        assert!(request.headers.contains(&("Authorization".to_string(), "Basic bWlncmF0ZTpzZWNyZXQ=".to_string())));
+
+        assert_eq!(RemoteHost::parse(&json!({"host": "http://10.0.0.5"})).unwrap().address, "10.0.0.5:80");
+        assert!(RemoteHost::parse(&json!({"host": "https://old.example.com:9200"})).is_err());
+        assert!(RemoteHost::parse(&json!({"host": "old.example.com:9200"})).is_err());
+        assert!(RemoteHost::parse(&json!({"host": "http://old.example.com:9200", "timeout": "1m"})).is_err());
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let (scroll_id, total, documents) = parse_page(&json!({
+            "_scroll_id": "abc",
+            "hits": {
+                "total": {"value": 2, "relation": "eq"},
+                "hits": [
+                    {"_index": "logs", "_id": "1", "_source": {"message": "hello"}},
+                    {"_index": "logs", "_id": "2"},
+                ],
+            },
+        })).unwrap();
+
+        assert_eq!(scroll_id, Some("abc".to_string()));
+        assert_eq!(total, 2);
+        assert_eq!(documents[0].key, "1");
+        assert_eq!(documents[0].source.as_ref().unwrap()["message"], json!("hello"));
+        assert_eq!(documents[1], RemoteDocument { key: "2".to_string(), source: None });
+
+        // Before Elasticsearch 7, the total is a number
+        assert_eq!(parse_page(&json!({"hits": {"total": 5, "hits": []}})).unwrap().1, 5);
+        assert!(parse_page(&json!({"error": "index_not_found_exception"})).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! Copies writes from the primary copy of an index to its replicas
+//!
+//! Requests that write documents are run on the node with the index's primary (see
+//! `api::cluster_api::forward_to_node`). The documents the primary writes and deletes are
+//! then sent on to the replicas as `ReplicaOperation`s, and the request responds once every
+//! replica has applied them or failed. A replica that fails is reported to the master, which
+//! takes it out of the routing and assigns it again, so it's recovered from scratch.
+//!
+//! Operations carry the version the primary gave the document, and a replica skips the ones
+//! for documents it already has a newer version of. Deletions aren't remembered once they've
+//! been applied, except while the copy is being recovered.
+//!
+//! New replicas are recovered by the primary. It clears the replica's copy, then sends it
+//! every document it has in batches, followed by a request to say it's finished. Writes are
+//! sent to the replica the whole time, so it doesn't miss any that happen meanwhile.
+//!
+//! Replicas only take these requests from the node the routing says has the primary, and
+//! only recovery requests while they're initializing (see `check_replica_request`).
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::Method;
+use serde_json::{Map, Value as Json};
+
+use document::{DocumentSource, read_source_field};
+use index::Index;
+use index::metadata::IndexMetadata;
+use index::metadata::settings::{IndexSettings, ActiveShardCount};
+use system::System;
+use cluster::state::DiscoveryNode;
+use cluster::transport::{TransportRequest, parse_json_response};
+
+
+/// How long a replica has to apply a batch of operations
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many documents are sent at a time while recovering a replica
+const RECOVERY_BATCH_SIZE: usize = 500;
+
+pub const REPLICATION_PATH: &'static str = "/_internal/replication";
+
+/// Set to the primary's node id on the requests it sends to its replicas
+pub const PRIMARY_NODE_HEADER: &'static str = "X-Primary-Node";
+
+
+/// A write that the primary made, to be made on the replicas too
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ReplicaOperation {
+    Index {
+        shard: usize,
+        key: String,
+        mapping: String,
+        source: Json,
+        version: u64,
+    },
+    Delete {
+        shard: usize,
+        key: String,
+        version: u64,
+    },
+}
+
+
+impl ReplicaOperation {
+    pub fn index(shard: usize, key: &str, mapping: &str, source: &Map<String, Json>, version: u64) -> ReplicaOperation {
+        ReplicaOperation::Index {
+            shard: shard,
+            key: key.to_string(),
+            mapping: mapping.to_string(),
+            source: Json::Object(source.clone()),
+            version: version,
+        }
+    }
+
+    pub fn delete(shard: usize, key: &str, version: u64) -> ReplicaOperation {
+        ReplicaOperation::Delete {
+            shard: shard,
+            key: key.to_string(),
+            version: version,
+        }
+    }
+}
+
+
+/// How many copies of an index a write was made on, for the `_shards` section of responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShardsInfo {
+    pub total: usize,
+    pub successful: usize,
+    pub failed: usize,
+}
+
+
+impl ShardsInfo {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "total": self.total,
+            "successful": self.successful,
+            "failed": self.failed,
+        })
+    }
+}
+
+
+/// Checks there are enough active copies of an index for a write to go ahead
+///
+/// `wait_for_active_shards` is the request's parameter, if it gave one. Otherwise the index's
+/// setting is used.
+pub fn check_active_shards(system: &System, index_name: &str, settings: &IndexSettings, wait_for_active_shards: Option<ActiveShardCount>) -> Result<(), String> {
+    let wait_for_active_shards = wait_for_active_shards.unwrap_or(settings.wait_for_active_shards);
+    let required = wait_for_active_shards.required(settings.number_of_replicas);
+    let active = system.cluster.index_routing(index_name).active_nodes().len();
+
+    if active < required {
+        return Err(format!("Not enough active copies to meet shard count of [{}] (have {}, needed {})", wait_for_active_shards.to_string(), active, required));
+    }
+
+    Ok(())
+}
+
+
+/// Builds a request from the primary of an index to one of its replicas
+fn replica_request(system: &System, node: &DiscoveryNode, path: &str, body: &Json) -> TransportRequest {
+    let mut request = TransportRequest::new(&node.address, Method::POST, path).with_json(body);
+    request.headers.push((PRIMARY_NODE_HEADER.to_string(), system.cluster.local_node.id.clone()));
+    request
+}
+
+
+/// Checks that a request to change the copy of an index on this node can be taken
+///
+/// The copy must be a replica, and the request must come from the node that the routing says
+/// has the primary. Recovery requests empty the copy, so they're only taken while the replica
+/// is initializing.
+pub fn check_replica_request(system: &System, index_name: &str, primary_node_id: Option<&str>, recovery: bool) -> Result<(), String> {
+    let routing = system.cluster.index_routing(index_name);
+
+    match routing.replica_on(&system.cluster.local_node.id) {
+        Some(replica) if recovery && replica.started => {
+            return Err(format!("the replica of [{}] on this node has already been recovered", index_name));
+        }
+        Some(_) => {}
+        None => return Err(format!("this node doesn't have a replica of [{}]", index_name)),
+    }
+
+    match (routing.primary.as_ref(), primary_node_id) {
+        (Some(primary), Some(node_id)) if primary == node_id => Ok(()),
+        _ => Err(format!("the request didn't come from the primary of [{}]", index_name)),
+    }
+}
+
+
+/// Sends writes the primary has made to all of the index's replicas
+///
+/// Replicas that fail to apply them are reported to the master. This mustn't be called while
+/// holding the cluster metadata lock, as the master may need it to publish the change.
+pub fn replicate(system: &System, index_name: &str, operations: &[ReplicaOperation]) -> ShardsInfo {
+    let routing = system.cluster.index_routing(index_name);
+    let local_node_id = &system.cluster.local_node.id;
+    let replicas = routing.replica_nodes().into_iter()
+        .filter(|node_id| *node_id != local_node_id.as_str())
+        .filter_map(|node_id| system.cluster.node(node_id))
+        .collect::<Vec<_>>();
+
+    let mut shards = ShardsInfo {
+        total: replicas.len() + 1,
+        successful: 1,
+        failed: 0,
+    };
+
+    if operations.is_empty() || replicas.is_empty() {
+        shards.successful = shards.total;
+        return shards;
+    }
+
+    let body = json!({"operations": operations});
+    let path = format!("{}/{}", REPLICATION_PATH, index_name);
+    let requests = replicas.iter().map(|node| replica_request(system, node, &path, &body)).collect();
+
+    for (node, response) in replicas.iter().zip(system.cluster.transport().send_all(requests, REPLICATION_TIMEOUT)) {
+        match parse_json_response(response) {
+            Ok(_) => shards.successful += 1,
+            Err(e) => {
+                shards.failed += 1;
+                warn!(system.log, "failed to replicate writes"; "index" => index_name, "node" => &node.name, "error" => e.clone());
+
+                if let Err(e) = system.cluster.shard_failed(system, index_name, &node.id, &e) {
+                    error!(system.log, "failed to report failed replica"; "index" => index_name, "node" => &node.name, "error" => e);
+                }
+            }
+        }
+    }
+
+    shards
+}
+
+
+/// Finds an open index that's finished loading
+fn find_index(system: &System, index_name: &str) -> Result<Arc<Index>, String> {
+    if system.is_recovering(index_name) {
+        return Err(format!("index [{}] is still loading", index_name));
+    }
+
+    let cluster_metadata = system.metadata.read().unwrap();
+    cluster_metadata.names.find_canonical(index_name)
+        .and_then(|index_ref| cluster_metadata.indices.get(&index_ref))
+        .cloned()
+        .ok_or_else(|| format!("no such index [{}]", index_name))
+}
+
+
+/// Finds a mapping that a document can be indexed with
+///
+/// Documents don't record which mapping they were indexed with, so recovery uses the first
+/// one that has all of the document's fields.
+fn find_mapping(index_metadata: &IndexMetadata, key: &str, source: &Map<String, Json>) -> Option<String> {
+    let mut mapping_names = index_metadata.mappings.keys().collect::<Vec<_>>();
+    mapping_names.sort();
+
+    mapping_names.into_iter()
+        .find(|mapping_name| (DocumentSource { key: key, data: source }).prepare(&index_metadata.mappings[*mapping_name]).is_ok())
+        .cloned()
+}
+
+
+/// Keeps track of the copies of indices that are being recovered, on both ends
+pub struct Replication {
+    /// Replicas that this node is sending documents to, as (index name, node id)
+    recovering_replicas: Mutex<HashSet<(String, String)>>,
+
+    /// Versions of the documents that have been deleted from copies on this node while
+    /// they're being recovered, by index name and then (shard, key)
+    ///
+    /// These stop the recovery from bringing back documents that the primary has since
+    /// deleted.
+    tombstones: Mutex<HashMap<String, Arc<Mutex<HashMap<(usize, String), u64>>>>>,
+}
+
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication {
+            recovering_replicas: Mutex::new(HashSet::new()),
+            tombstones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies operations sent by an index's primary to the copy on this node
+    ///
+    /// Writes that the index's blocks don't allow are refused, apart from while the copy is
+    /// being recovered, as the documents the primary copies across were written before then.
+    pub fn apply_operations(&self, system: &System, index_name: &str, operations: &[ReplicaOperation]) -> Result<(), String> {
+        let index = find_index(system, index_name)?;
+        let index_metadata = index.metadata.read().unwrap();
+        let tombstones = self.tombstones.lock().unwrap().get(index_name).cloned();
+        let blocks = if tombstones.is_none() { Some(&index_metadata.settings.blocks) } else { None };
+
+        for operation in operations {
+            // Held while each operation is applied, so a deletion can't slip in between the
+            // recovery checking for a tombstone and writing the document
+            let mut tombstones = tombstones.as_ref().map(|tombstones| tombstones.lock().unwrap());
+
+            match *operation {
+                ReplicaOperation::Index { shard, ref key, ref mapping, ref source, version } => {
+                    if blocks.map_or(false, |blocks| blocks.blocks_write()) {
+                        return Err(format!("index [{}] is blocked for write operations", index_name));
+                    }
+
+                    let deleted_version = tombstones.as_ref().and_then(|tombstones| tombstones.get(&(shard, key.clone())).cloned());
+                    if deleted_version.map_or(false, |deleted_version| deleted_version >= version) {
+                        continue;
+                    }
+
+                    let store = index.shards().get(shard).ok_or_else(|| format!("no such shard [{}]", shard))?;
+                    let mapping = index_metadata.mappings.get(mapping).ok_or_else(|| format!("no such mapping [{}]", mapping))?;
+                    let source = source.as_object().ok_or_else(|| format!("the source of [{}] isn't an object", key))?;
+                    let doc = (DocumentSource { key: key, data: source }).prepare(mapping).map_err(|e| format!("couldn't index [{}]: {:?}", key, e))?;
+
+                    store.replicate_document(&doc, version).map_err(|e| format!("couldn't index [{}]: {:?}", key, e))?;
+                }
+                ReplicaOperation::Delete { shard, ref key, version } => {
+                    if blocks.map_or(false, |blocks| blocks.blocks_delete()) {
+                        return Err(format!("index [{}] is blocked for delete operations", index_name));
+                    }
+
+                    let store = index.shards().get(shard).ok_or_else(|| format!("no such shard [{}]", shard))?;
+                    store.replicate_deletion(key, version).map_err(|e| format!("couldn't delete [{}]: {:?}", key, e))?;
+
+                    if let Some(ref mut tombstones) = tombstones {
+                        let deleted_version = tombstones.entry((shard, key.clone())).or_insert(0);
+                        *deleted_version = cmp::max(*deleted_version, version);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Empties the copy of an index on this node, so it can be recovered from the primary
+    pub fn start_recovery(&self, system: &System, index_name: &str) -> Result<(), String> {
+        let index = find_index(system, index_name)?;
+        self.tombstones.lock().unwrap().insert(index_name.to_string(), Arc::new(Mutex::new(HashMap::new())));
+
+        for store in index.shards() {
+            let keys = store.reader().document_keys().into_iter().map(|(_, key)| key).collect::<Vec<_>>();
+            for key in keys {
+                store.remove_document_by_key(&key).map_err(|e| format!("couldn't delete [{}]: {:?}", key, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops keeping tombstones once the copy of an index on this node has been recovered
+    pub fn finish_recovery(&self, system: &System, index_name: &str) -> Result<(), String> {
+        self.tombstones.lock().unwrap().remove(index_name);
+
+        find_index(system, index_name)?.refresh()
+    }
+
+    /// Starts recovering the initializing replicas of the primaries on this node
+    ///
+    /// Each replica is recovered on the management pool. This is called every time the
+    /// coordinator runs, so replicas that couldn't be recovered are tried again.
+    pub fn recover_replicas(&self, system: &Arc<System>) {
+        let local_node_id = system.cluster.local_node.id.clone();
+        let state = system.cluster.state();
+
+        for (index_name, routing) in state.routing.iter() {
+            if routing.primary.as_ref() != Some(&local_node_id) || system.is_recovering(index_name) {
+                continue;
+            }
+
+            for replica in routing.replicas.iter().filter(|replica| !replica.started) {
+                let target = (index_name.clone(), replica.node.clone());
+                if !self.recovering_replicas.lock().unwrap().insert(target.clone()) {
+                    continue;
+                }
+
+                let task_system = system.clone();
+                let task_target = target.clone();
+                let spawned = system.thread_pools.management.spawn(move || {
+                    let system = task_system;
+                    let (ref index_name, ref node_id) = task_target;
+
+                    let result = recover_replica(&system, index_name, node_id);
+                    system.replication.recovering_replicas.lock().unwrap().remove(&task_target);
+
+                    if let Err(e) = result {
+                        warn!(system.log, "replica recovery failed"; "index" => index_name, "node" => node_id, "error" => e.clone());
+
+                        if let Err(e) = system.cluster.shard_failed(&system, index_name, node_id, &e) {
+                            error!(system.log, "failed to report failed replica"; "index" => index_name, "node" => node_id, "error" => e);
+                        }
+                    }
+                });
+
+                if spawned.is_err() {
+                    debug!(system.log, "management queue is full, skipping replica recovery"; "index" => index_name);
+                    self.recovering_replicas.lock().unwrap().remove(&target);
+                }
+            }
+        }
+    }
+}
+
+
+/// Copies every document in the index to a replica, then tells the master it's started
+fn recover_replica(system: &System, index_name: &str, node_id: &str) -> Result<(), String> {
+    let node = system.cluster.node(node_id).ok_or_else(|| format!("node [{}] has left the cluster", node_id))?;
+    let path = format!("{}/{}", REPLICATION_PATH, index_name);
+    let send = |path: &str, body: &Json| {
+        let request = replica_request(system, &node, path, body);
+        parse_json_response(system.cluster.transport().send(request, REPLICATION_TIMEOUT)).map(|_| ())
+    };
+
+    info!(system.log, "recovering replica"; "index" => index_name, "node" => &node.name);
+    send(&format!("{}/_recovery/start", path), &json!({}))?;
+
+    // Anything written after this is sent to the replica as it happens
+    let index = find_index(system, index_name)?;
+    let reader = index.reader();
+    let mut operations = Vec::new();
+    let mut documents = 0;
+
+    {
+        let index_metadata = index.metadata.read().unwrap();
+        let source_field = index_metadata.get_field_mapping("_source").and_then(|field| field.index_ref);
+
+        for (shard, shard_reader) in reader.shards().iter().enumerate() {
+            for (doc_id, key) in shard_reader.document_keys() {
+                let version = match shard_reader.get_document_by_key(&key) {
+                    Some((_, version)) => version.version,
+                    None => continue,
+                };
+                let source = source_field.and_then(|field| read_source_field(shard_reader, field, doc_id))
+                    .ok_or_else(|| format!("the source of [{}] isn't stored, so it can't be copied", key))?;
+                let mapping = find_mapping(&index_metadata, &key, &source)
+                    .ok_or_else(|| format!("none of the mappings can index [{}]", key))?;
+
+                operations.push(ReplicaOperation::index(shard, &key, &mapping, &source, version));
+                if operations.len() >= RECOVERY_BATCH_SIZE {
+                    send(&path, &json!({"operations": operations}))?;
+                    documents += operations.len();
+                    operations.clear();
+                }
+            }
+        }
+    }
+
+    if !operations.is_empty() {
+        send(&path, &json!({"operations": operations}))?;
+        documents += operations.len();
+    }
+
+    send(&format!("{}/_recovery/finish", path), &json!({}))?;
+    info!(system.log, "recovered replica"; "index" => index_name, "node" => &node.name, "documents" => documents);
+
+    system.cluster.shard_started(system, index_name, node_id)
+}
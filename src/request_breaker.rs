@@ -0,0 +1,122 @@
+//! The parent circuit breaker for API requests
+//!
+//! Each request reserves an estimate of the memory it needs, the size of its body, while it's
+//! being handled. Requests that would take the total over the limit are refused, so a burst
+//! of large searches or bulk requests can't run the node out of memory.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Stored as the limit when there isn't one
+const NO_LIMIT: usize = usize::MAX;
+
+
+#[derive(Debug)]
+pub struct RequestBreaker {
+    /// Can be changed while requests are running (see `set_limit`)
+    limit: AtomicUsize,
+    used: AtomicUsize,
+
+    /// How many requests have been refused
+    tripped: AtomicUsize,
+}
+
+
+/// Memory reserved by a request. It's given back when this is dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    breaker: &'a RequestBreaker,
+    bytes: usize,
+}
+
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.breaker.used.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+
+impl RequestBreaker {
+    pub fn new(limit: Option<usize>) -> RequestBreaker {
+        RequestBreaker {
+            limit: AtomicUsize::new(limit.unwrap_or(NO_LIMIT)),
+            used: AtomicUsize::new(0),
+            tripped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves memory for a request
+    ///
+    /// If this would go over the limit, nothing is reserved and the total that would have been
+    /// used is returned. Requests without a body are always allowed.
+    pub fn reserve<'a>(&'a self, bytes: usize) -> Result<Reservation<'a>, usize> {
+        let used = self.used.fetch_add(bytes, Ordering::SeqCst).saturating_add(bytes);
+
+        if let Some(limit) = self.limit() {
+            if bytes > 0 && used > limit {
+                self.used.fetch_sub(bytes, Ordering::SeqCst);
+                self.tripped.fetch_add(1, Ordering::SeqCst);
+                return Err(used);
+            }
+        }
+
+        Ok(Reservation {
+            breaker: self,
+            bytes: bytes,
+        })
+    }
+
+    /// None if there's no limit
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::SeqCst) {
+            NO_LIMIT => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Changes the limit. Requests that have already reserved memory keep it
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(NO_LIMIT), Ordering::SeqCst);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn tripped(&self) -> usize {
+        self.tripped.load(Ordering::SeqCst)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::RequestBreaker;
+
+    #[test]
+    fn test_request_breaker() {
+        let breaker = RequestBreaker::new(Some(100));
+
+        let first = breaker.reserve(60).unwrap();
+        assert_eq!(breaker.used(), 60);
+
+        assert_eq!(breaker.reserve(50).unwrap_err(), 110);
+        assert_eq!(breaker.used(), 60);
+        assert_eq!(breaker.tripped(), 1);
+
+        // Requests without a body aren't refused
+        assert!(breaker.reserve(0).is_ok());
+
+        drop(first);
+        assert_eq!(breaker.used(), 0);
+        assert!(breaker.reserve(100).is_ok());
+
+        let unlimited = RequestBreaker::new(None);
+        assert!(unlimited.reserve(1 << 40).is_ok());
+
+        unlimited.set_limit(Some(100));
+        assert_eq!(unlimited.limit(), Some(100));
+        assert!(unlimited.reserve(200).is_err());
+    }
+}
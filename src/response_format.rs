@@ -0,0 +1,187 @@
+//! Search hits as CSV or NDJSON, for piping into spreadsheets and shell tools
+//!
+//! In CSV, each hit is a row with its index and score, followed by the fields of its source.
+//! Objects are flattened into columns named with dots (`author.name`), and arrays are written
+//! as JSON. The columns are the fields that any hit on the page has, in the order they were
+//! first seen. In NDJSON, each hit is a line of JSON, as it would be in `hits.hits`.
+
+use serde_json::Value as Json;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+
+impl ResponseFormat {
+    /// Reads the name of a format, as given in the `format` URL parameter
+    pub fn from_name(name: &str) -> Option<ResponseFormat> {
+        match name {
+            "json" => Some(ResponseFormat::Json),
+            "csv" => Some(ResponseFormat::Csv),
+            "ndjson" => Some(ResponseFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    /// Picks the first format in an `Accept` header that can be returned. Defaults to JSON
+    ///
+    /// Quality values aren't taken into account.
+    pub fn from_accept(accept: &str) -> ResponseFormat {
+        for media_type in accept.split(',') {
+            let media_type = media_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+            match media_type.as_ref() {
+                "application/json" => return ResponseFormat::Json,
+                "text/csv" => return ResponseFormat::Csv,
+                "application/x-ndjson" | "application/ndjson" => return ResponseFormat::Ndjson,
+                _ => {}
+            }
+        }
+
+        ResponseFormat::Json
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::Csv => "text/csv; charset=UTF-8",
+            ResponseFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+
+/// Adds the fields of an object to `columns`, with nested objects flattened
+fn flatten<'a>(prefix: &str, json: &'a Json, columns: &mut Vec<(String, &'a Json)>) {
+    match *json {
+        Json::Object(ref object) => {
+            for (key, value) in object.iter() {
+                let name = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&name, value, columns);
+            }
+        }
+        _ => columns.push((prefix.to_string(), json)),
+    }
+}
+
+
+fn csv_value(json: &Json) -> String {
+    match *json {
+        Json::Null => String::new(),
+        Json::String(ref string) => string.clone(),
+        ref json => json.to_string(),
+    }
+}
+
+
+/// Quotes a CSV field if it has a comma, quote or line break in it
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+
+fn csv_row<I: Iterator<Item = String>>(output: &mut String, fields: I) {
+    let row = fields.map(|field| csv_field(&field)).collect::<Vec<_>>();
+    output.push_str(&row.join(","));
+    output.push('\n');
+}
+
+
+/// Writes hits as CSV, with a header row
+///
+/// Stored fields that were asked for are included alongside the source.
+pub fn hits_to_csv(hits: &[Json]) -> String {
+    let mut names = vec!["_index".to_string(), "_score".to_string()];
+    let mut rows = Vec::with_capacity(hits.len());
+
+    for hit in hits {
+        let mut columns = vec![
+            ("_index".to_string(), hit.get("_index").unwrap_or(&Json::Null)),
+            ("_score".to_string(), hit.get("_score").unwrap_or(&Json::Null)),
+        ];
+        for key in &["_source", "fields"] {
+            if let Some(fields) = hit.get(*key) {
+                flatten("", fields, &mut columns);
+            }
+        }
+
+        for (name, _) in columns.iter() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        rows.push(columns);
+    }
+
+    let mut output = String::new();
+    csv_row(&mut output, names.iter().cloned());
+
+    for columns in rows {
+        csv_row(&mut output, names.iter().map(|name| {
+            columns.iter().find(|(column, _)| column == name).map_or_else(String::new, |&(_, value)| csv_value(value))
+        }));
+    }
+
+    output
+}
+
+
+/// Writes hits as newline-delimited JSON
+pub fn hits_to_ndjson(hits: &[Json]) -> String {
+    let mut output = String::new();
+
+    for hit in hits {
+        output.push_str(&hit.to_string());
+        output.push('\n');
+    }
+
+    output
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ResponseFormat, hits_to_csv, hits_to_ndjson};
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(ResponseFormat::from_name("csv"), Some(ResponseFormat::Csv));
+        assert_eq!(ResponseFormat::from_name("xml"), None);
+
+        assert_eq!(ResponseFormat::from_accept("text/csv"), ResponseFormat::Csv);
+        assert_eq!(ResponseFormat::from_accept("text/html, application/x-ndjson;q=0.9"), ResponseFormat::Ndjson);
+        assert_eq!(ResponseFormat::from_accept("application/json, text/csv"), ResponseFormat::Json);
+        assert_eq!(ResponseFormat::from_accept("*/*"), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn test_csv() {
+        let hits = vec![
+            json!({"_index": "articles", "_score": 1.5, "_source": {"title": "Hello, world", "author": {"name": "Karl"}, "tags": ["a", "b"], "published": null}}),
+            json!({"_index": "articles", "_score": 0.5, "_source": {"title": "Say \"hi\"", "views": 10, "published": true}}),
+        ];
+
+        assert_eq!(hits_to_csv(&hits), "\
+_index,_score,author.name,published,tags,title,views
+articles,1.5,Karl,,\"[\"\"a\"\",\"\"b\"\"]\",\"Hello, world\",
+articles,0.5,,true,,\"Say \"\"hi\"\"\",10
+");
+
+        assert_eq!(hits_to_csv(&[]), "_index,_score\n");
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let hits = vec![json!({"_score": 1.0, "_source": {"title": "Hello"}}), json!({"_score": 0.5})];
+        assert_eq!(hits_to_ndjson(&hits), "{\"_score\":1.0,\"_source\":{\"title\":\"Hello\"}}\n{\"_score\":0.5}\n");
+    }
+}
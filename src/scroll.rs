@@ -0,0 +1,276 @@
+//! Keeps scrolled searches open between requests
+//!
+//! When a search is started with the `scroll` parameter, the readers it was run with are
+//! pinned (see `IndexReader::pin_segments`) and kept here along with the query and the last
+//! hit that was returned. Each call to the scroll API runs the query again on the pinned
+//! readers to find the next page of hits after the last one, so a scroll only holds on to
+//! one page of hits at a time. Scrolls stay open until they're cleared or they expire.
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+use search::query::Query;
+use search::sort::{SortField, SortValue};
+
+
+/// A hit found by a search, before the data to return with it is fetched
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrollHit {
+    pub doc_id: u64,
+    pub score: Option<f32>,
+    pub sort_values: Option<Vec<SortValue>>,
+}
+
+
+/// The parts of a scrolled search that don't change between pages
+#[derive(Debug)]
+pub struct ScrollSearch {
+    /// The id of the index that was searched
+    pub index_id: Uuid,
+
+    /// Keeps the readers the search was run with open, one pin per shard.
+    /// See `Index::pinned_reader`
+    pub pins: Vec<u64>,
+
+    /// The query, with any alias and role filters already applied
+    pub query: Query,
+
+    pub min_score: Option<f32>,
+
+    /// How the hits are sorted, if they aren't sorted by score
+    pub sort: Option<Vec<SortField>>,
+
+    /// The number of hits on each page
+    pub size: usize,
+
+    pub total_hits: u64,
+    pub max_score: Option<f32>,
+}
+
+
+#[derive(Debug)]
+pub struct ScrollContext {
+    pub search: Arc<ScrollSearch>,
+
+    /// The last hit that was returned. The next page starts after it
+    pub last_hit: Option<ScrollHit>,
+
+    /// Set once a page comes back short, as there's nothing left to search for
+    pub finished: bool,
+
+    expires: Instant,
+}
+
+
+impl ScrollContext {
+    /// `last_hit` is the last hit of the first page, which was returned with the search
+    pub fn new(search: ScrollSearch, last_hit: Option<ScrollHit>, keep_alive: Duration) -> ScrollContext {
+        ScrollContext {
+            finished: last_hit.is_none(),
+            search: Arc::new(search),
+            last_hit: last_hit,
+            expires: Instant::now() + keep_alive,
+        }
+    }
+
+    /// Records the hits of the page that was just returned
+    pub fn returned_page(&mut self, page: &[ScrollHit]) {
+        match page.last() {
+            Some(last_hit) => self.last_hit = Some(last_hit.clone()),
+            None => self.finished = true,
+        }
+
+        if page.len() < self.search.size {
+            self.finished = true;
+        }
+    }
+
+    /// Extends the scroll's lifetime from now
+    pub fn keep_alive(&mut self, keep_alive: Duration) {
+        self.expires = Instant::now() + keep_alive;
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires
+    }
+}
+
+
+/// Holds all of the scrolls that are open, by scroll id
+#[derive(Debug)]
+pub struct ScrollRegistry {
+    contexts: Mutex<HashMap<String, ScrollContext>>,
+}
+
+
+impl ScrollRegistry {
+    pub fn new() -> ScrollRegistry {
+        ScrollRegistry {
+            contexts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a new scroll, returning its id
+    ///
+    /// If `max_open` scrolls are already open, the scroll is given back so its pins can be
+    /// released. Expired scrolls that haven't been cleaned up yet still count.
+    pub fn insert(&self, context: ScrollContext, max_open: usize) -> Result<String, ScrollContext> {
+        let mut contexts = self.contexts.lock().unwrap();
+        if contexts.len() >= max_open {
+            return Err(context);
+        }
+
+        let scroll_id = Uuid::new_v4().simple().to_string();
+        contexts.insert(scroll_id.clone(), context);
+        Ok(scroll_id)
+    }
+
+    /// Runs a function on a scroll. Returns None if the scroll doesn't exist or has expired
+    pub fn with_context<R, F: FnOnce(&mut ScrollContext) -> R>(&self, scroll_id: &str, f: F) -> Option<R> {
+        let mut contexts = self.contexts.lock().unwrap();
+
+        match contexts.get_mut(scroll_id) {
+            Some(ref mut context) if !context.is_expired(Instant::now()) => Some(f(context)),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&self, scroll_id: &str) -> Option<ScrollContext> {
+        self.contexts.lock().unwrap().remove(scroll_id)
+    }
+
+    pub fn remove_all(&self) -> Vec<ScrollContext> {
+        self.contexts.lock().unwrap().drain().map(|(_, context)| context).collect()
+    }
+
+    /// Removes all scrolls that have passed their keep alive time
+    pub fn remove_expired(&self) -> Vec<ScrollContext> {
+        let now = Instant::now();
+        let mut contexts = self.contexts.lock().unwrap();
+        let expired = contexts.iter()
+            .filter(|&(_, context)| context.is_expired(now))
+            .map(|(scroll_id, _)| scroll_id.clone())
+            .collect::<Vec<_>>();
+
+        expired.iter().filter_map(|scroll_id| contexts.remove(scroll_id)).collect()
+    }
+
+    /// Removes all scrolls on an index. Used when the index is closed or deleted
+    pub fn remove_index(&self, index_id: &Uuid) -> Vec<ScrollContext> {
+        let mut contexts = self.contexts.lock().unwrap();
+        let removed = contexts.iter()
+            .filter(|&(_, context)| context.search.index_id == *index_id)
+            .map(|(scroll_id, _)| scroll_id.clone())
+            .collect::<Vec<_>>();
+
+        removed.iter().filter_map(|scroll_id| contexts.remove(scroll_id)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contexts.lock().unwrap().len()
+    }
+}
+
+
+/// Parses a scroll keep alive time such as "1m" or "30s"
+pub fn parse_keep_alive(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let (number, millis_per_unit) = if value.ends_with("ms") {
+        (&value[..value.len() - 2], 1)
+    } else if value.ends_with('s') {
+        (&value[..value.len() - 1], 1000)
+    } else if value.ends_with('m') {
+        (&value[..value.len() - 1], 60 * 1000)
+    } else if value.ends_with('h') {
+        (&value[..value.len() - 1], 60 * 60 * 1000)
+    } else {
+        (value, 1)
+    };
+
+    number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(millis_per_unit))
+        .map(Duration::from_millis)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+    use search::query::Query;
+
+    use super::{ScrollSearch, ScrollContext, ScrollHit, ScrollRegistry, parse_keep_alive};
+
+    fn make_search(size: usize) -> ScrollSearch {
+        ScrollSearch {
+            index_id: Uuid::new_v4(),
+            pins: vec![0],
+            query: Query::all(),
+            min_score: None,
+            sort: None,
+            size: size,
+            total_hits: 5,
+            max_score: Some(1.0f32),
+        }
+    }
+
+    fn make_hit(doc_id: u64) -> ScrollHit {
+        ScrollHit {
+            doc_id: doc_id,
+            score: Some(1.0f32),
+            sort_values: None,
+        }
+    }
+
+    #[test]
+    fn test_pages() {
+        let registry = ScrollRegistry::new();
+        let scroll_id = registry.insert(ScrollContext::new(make_search(2), Some(make_hit(1)), Duration::from_secs(60)), 10).unwrap();
+
+        let last_hit = |registry: &ScrollRegistry| registry.with_context(&scroll_id, |context| {
+            (context.last_hit.as_ref().map(|hit| hit.doc_id), context.finished)
+        });
+        assert_eq!(last_hit(&registry), Some((Some(1), false)));
+
+        registry.with_context(&scroll_id, |context| context.returned_page(&[make_hit(2), make_hit(3)]));
+        assert_eq!(last_hit(&registry), Some((Some(3), false)));
+
+        // A short page is the last one
+        registry.with_context(&scroll_id, |context| context.returned_page(&[make_hit(4)]));
+        assert_eq!(last_hit(&registry), Some((Some(4), true)));
+
+        assert!(registry.remove(&scroll_id).is_some());
+        assert_eq!(last_hit(&registry), None);
+    }
+
+    #[test]
+    fn test_max_open() {
+        let registry = ScrollRegistry::new();
+        assert!(registry.insert(ScrollContext::new(make_search(2), None, Duration::from_secs(60)), 1).is_ok());
+        assert!(registry.insert(ScrollContext::new(make_search(2), None, Duration::from_secs(60)), 1).is_err());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let registry = ScrollRegistry::new();
+        let scroll_id = registry.insert(ScrollContext::new(make_search(2), None, Duration::from_secs(0)), 10).unwrap();
+
+        assert_eq!(registry.with_context(&scroll_id, |_| ()), None);
+        assert_eq!(registry.remove_expired().len(), 1);
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_keep_alive() {
+        assert_eq!(parse_keep_alive("1m"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_keep_alive("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_keep_alive("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_keep_alive("foo"), None);
+        assert_eq!(parse_keep_alive("18446744073709551615h"), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Limits the memory the aggregations of a search can use
+//!
+//! Aggregators report the memory they allocate as they grow (mostly new buckets and the
+//! values they keep). Once the total goes over the limit the breaker trips, which stops the
+//! search and aggregators stop taking documents. The search then fails, rather than the node
+//! running out of memory. The sizes are estimates, they don't include allocator overhead.
+
+use std::cell::Cell;
+
+use search::cancellation::SearchCancellation;
+
+
+/// The memory the aggregations of a search can use, if it hasn't been configured
+pub const DEFAULT_AGGREGATION_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    limit: usize,
+    used: Cell<usize>,
+
+    /// Cancelled when the breaker trips, to stop the search
+    cancellation: Option<SearchCancellation>,
+}
+
+
+impl CircuitBreaker {
+    pub fn new(limit: usize) -> CircuitBreaker {
+        CircuitBreaker {
+            limit: limit,
+            used: Cell::new(0),
+            cancellation: None,
+        }
+    }
+
+    pub fn unlimited() -> CircuitBreaker {
+        CircuitBreaker::new(usize::max_value())
+    }
+
+    /// Cancels a search when the breaker trips
+    pub fn cancel_on_trip(mut self, cancellation: SearchCancellation) -> CircuitBreaker {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Adds to the memory used, tripping the breaker if this goes over the limit
+    pub fn add(&self, bytes: usize) {
+        let used = self.used.get().saturating_add(bytes);
+        self.used.set(used);
+
+        if used > self.limit {
+            if let Some(ref cancellation) = self.cancellation {
+                cancellation.cancel();
+            }
+        }
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.used.get() > self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::cancellation::SearchCancellation;
+
+    use super::CircuitBreaker;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cancellation = SearchCancellation::new();
+        let breaker = CircuitBreaker::new(100).cancel_on_trip(cancellation.clone());
+
+        breaker.add(60);
+        breaker.add(40);
+        assert!(!breaker.is_tripped());
+        assert!(!cancellation.is_cancelled());
+
+        breaker.add(1);
+        assert!(breaker.is_tripped());
+        assert!(cancellation.is_cancelled());
+        assert_eq!(breaker.used(), 101);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let breaker = CircuitBreaker::unlimited();
+        breaker.add(usize::max_value());
+        breaker.add(1);
+        assert!(!breaker.is_tripped());
+    }
+}
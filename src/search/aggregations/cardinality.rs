@@ -0,0 +1,263 @@
+//! The cardinality aggregation estimates the number of distinct values
+//!
+//! Values are hashed and counted exactly until there are more than the precision
+//! threshold, then the hashes are moved into a HyperLogLog sketch. The sketch uses a
+//! fixed amount of memory however many values it's given, at the cost of an error of
+//! roughly `1.04 / sqrt(2 ^ precision)`. As with HyperLogLog++, 64 bit hashes are used
+//! so the estimate doesn't need correcting for large cardinalities, and linear counting
+//! is used for small ones.
+
+use std::hash::Hasher;
+
+use fnv::{FnvHasher, FnvHashSet};
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::ValueSource;
+
+
+/// The precision threshold used if the request doesn't give one
+pub const DEFAULT_PRECISION_THRESHOLD: u64 = 3000;
+
+/// Higher thresholds are capped to this
+pub const MAX_PRECISION_THRESHOLD: u64 = 40000;
+
+const MIN_PRECISION: u32 = 4;
+const MAX_PRECISION: u32 = 18;
+
+
+/// Hashes a value, mixing the bits so they're evenly distributed
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    let mut hash = hasher.finish();
+
+    // The finaliser from MurmurHash3
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+
+/// Works out the precision of the sketch to use for a precision threshold
+///
+/// This gives the sketch roughly as many registers as the exact counter would have used
+/// bytes of memory when it was converted.
+pub fn precision_from_threshold(precision_threshold: u64) -> u32 {
+    let hash_table_entries = (precision_threshold as f64 / 0.75).ceil();
+    let precision = (hash_table_entries * 4.0).log2().ceil() as u32;
+    precision.max(MIN_PRECISION).min(MAX_PRECISION)
+}
+
+
+/// A HyperLogLog sketch
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u32,
+
+    /// For each register, the highest number of leading zeros plus one seen in the hashes
+    /// that were put in it
+    registers: Vec<u8>,
+}
+
+
+impl HyperLogLog {
+    pub fn new(precision: u32) -> HyperLogLog {
+        assert!(precision >= MIN_PRECISION && precision <= MAX_PRECISION);
+
+        HyperLogLog {
+            precision: precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn add_hash(&mut self, hash: u64) {
+        // The first bits pick the register, the rest of the hash gives the rank
+        let register = (hash >> (64 - self.precision)) as usize;
+        let rank = ((hash << self.precision) | (1 << (self.precision - 1))).leading_zeros() as u8 + 1;
+
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for &register in self.registers.iter() {
+            sum += 1.0 / (1u64 << register) as f64;
+
+            if register == 0 {
+                zeros += 1;
+            }
+        }
+
+        let estimate = alpha * m * m / sum;
+
+        // The raw estimate is biased for small cardinalities, linear counting is more accurate
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardinalityAggregation {
+    pub source: ValueSource,
+
+    /// Below this many distinct values, counts are exact
+    pub precision_threshold: u64,
+}
+
+
+#[derive(Debug)]
+enum Counter {
+    Exact(FnvHashSet<u64>),
+    Sketch(HyperLogLog),
+}
+
+
+#[derive(Debug)]
+pub struct CardinalityAggregator<'a> {
+    aggregation: &'a CardinalityAggregation,
+    counter: Counter,
+}
+
+
+impl<'a> CardinalityAggregator<'a> {
+    pub fn new(aggregation: &'a CardinalityAggregation) -> CardinalityAggregator<'a> {
+        CardinalityAggregator {
+            aggregation: aggregation,
+            counter: Counter::Exact(FnvHashSet::default()),
+        }
+    }
+
+    fn add_hash(&mut self, hash: u64) {
+        let sketch = match self.counter {
+            Counter::Exact(ref mut hashes) => {
+                hashes.insert(hash);
+
+                if hashes.len() as u64 <= self.aggregation.precision_threshold {
+                    return;
+                }
+
+                // Too many values to count exactly, switch to the sketch
+                let mut sketch = HyperLogLog::new(precision_from_threshold(self.aggregation.precision_threshold));
+                for &hash in hashes.iter() {
+                    sketch.add_hash(hash);
+                }
+
+                sketch
+            }
+            Counter::Sketch(ref mut sketch) => {
+                sketch.add_hash(hash);
+                return;
+            }
+        };
+
+        self.counter = Counter::Sketch(sketch);
+    }
+
+    pub fn collect<F: FnMut(FieldId) -> Option<FieldValue>>(&mut self, score: Option<f32>, read_value: &mut F) {
+        let hash = match self.aggregation.source {
+            ValueSource::Field(field_id) => {
+                match read_value(field_id) {
+                    Some(value) => hash_bytes(&value.to_bytes()),
+                    None => return,
+                }
+            }
+            ValueSource::Script(_) | ValueSource::GeoDistance(_) => {
+                match self.aggregation.source.read(score, read_value) {
+                    Some(value) => hash_bytes(&value.to_bits().to_le_bytes()),
+                    None => return,
+                }
+            }
+        };
+
+        self.add_hash(hash);
+    }
+
+    pub fn into_result(self) -> u64 {
+        match self.counter {
+            Counter::Exact(hashes) => hashes.len() as u64,
+            Counter::Sketch(sketch) => sketch.estimate(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::ValueSource;
+
+    use super::{CardinalityAggregation, CardinalityAggregator, HyperLogLog, hash_bytes, precision_from_threshold};
+
+    fn count_distinct(precision_threshold: u64, values: &[FieldValue]) -> u64 {
+        let aggregation = CardinalityAggregation {
+            source: ValueSource::Field(FieldId(1)),
+            precision_threshold: precision_threshold,
+        };
+
+        let mut aggregator = CardinalityAggregator::new(&aggregation);
+        for value in values.iter() {
+            aggregator.collect(None, &mut |_| Some(value.clone()));
+        }
+        aggregator.collect(None, &mut |_| None);
+
+        aggregator.into_result()
+    }
+
+    #[test]
+    fn test_exact() {
+        let values = vec![
+            FieldValue::String("foo".to_string()),
+            FieldValue::String("bar".to_string()),
+            FieldValue::String("foo".to_string()),
+            FieldValue::Integer(1),
+        ];
+
+        assert_eq!(count_distinct(100, &values), 3);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let values = (0..20000).map(|i| FieldValue::Integer(i % 10000)).collect::<Vec<_>>();
+
+        // Precise enough to be within a few percent
+        let estimate = count_distinct(1000, &values);
+        assert!(estimate > 9500 && estimate < 10500, "estimate was {}", estimate);
+    }
+
+    #[test]
+    fn test_small_cardinality_sketch() {
+        let mut sketch = HyperLogLog::new(14);
+        for i in 0..100u64 {
+            sketch.add_hash(hash_bytes(&i.to_le_bytes()));
+        }
+
+        assert_eq!(sketch.estimate(), 100);
+    }
+
+    #[test]
+    fn test_precision_from_threshold() {
+        assert_eq!(precision_from_threshold(0), 4);
+        assert_eq!(precision_from_threshold(3000), 14);
+        assert_eq!(precision_from_threshold(40000), 18);
+    }
+}
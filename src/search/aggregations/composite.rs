@@ -0,0 +1,404 @@
+//! The composite aggregation buckets documents by a combination of values, for paging through all buckets
+//!
+//! Each source gives one part of a bucket's key. Buckets are sorted by their keys and
+//! returned `size` at a time, the key of the last bucket can be passed back as `after` to
+//! get the next page.
+
+use std::mem;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, TimeZone, Utc};
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, AggregationResult, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+use search::aggregations::pipeline::run_pipelines;
+
+
+/// One part of the key of a composite bucket
+#[derive(Debug, Clone)]
+pub enum CompositeValue {
+    /// The document doesn't have a value for the source
+    Null,
+
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+
+impl CompositeValue {
+    fn from_field_value(value: FieldValue) -> Option<CompositeValue> {
+        match value {
+            FieldValue::String(string) => Some(CompositeValue::String(string)),
+            FieldValue::Integer(value) => Some(CompositeValue::Integer(value)),
+            FieldValue::Boolean(value) => Some(CompositeValue::Boolean(value)),
+            FieldValue::DateTime(value) => Some(CompositeValue::Integer(value.timestamp() * 1000 + value.timestamp_subsec_millis() as i64)),
+            FieldValue::Vector(_) | FieldValue::GeoPoint(_) => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match *self {
+            CompositeValue::Null => 0,
+            CompositeValue::Boolean(_) => 1,
+            CompositeValue::Integer(_) | CompositeValue::Float(_) => 2,
+            CompositeValue::String(_) => 3,
+        }
+    }
+
+    /// Roughly how much memory this uses
+    fn size(&self) -> usize {
+        match *self {
+            CompositeValue::String(ref string) => mem::size_of::<CompositeValue>() + string.len(),
+            _ => mem::size_of::<CompositeValue>(),
+        }
+    }
+}
+
+
+/// Missing values sort first, numbers are compared by value whether they're integers or floats
+impl Ord for CompositeValue {
+    fn cmp(&self, other: &CompositeValue) -> Ordering {
+        match (self, other) {
+            (&CompositeValue::Boolean(a), &CompositeValue::Boolean(b)) => a.cmp(&b),
+            (&CompositeValue::Integer(a), &CompositeValue::Integer(b)) => a.cmp(&b),
+            (&CompositeValue::Integer(a), &CompositeValue::Float(b)) => (a as f64).total_cmp(&b),
+            (&CompositeValue::Float(a), &CompositeValue::Integer(b)) => a.total_cmp(&(b as f64)),
+            (&CompositeValue::Float(a), &CompositeValue::Float(b)) => a.total_cmp(&b),
+            (&CompositeValue::String(ref a), &CompositeValue::String(ref b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+
+impl PartialOrd for CompositeValue {
+    fn partial_cmp(&self, other: &CompositeValue) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl PartialEq for CompositeValue {
+    fn eq(&self, other: &CompositeValue) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+
+impl Eq for CompositeValue {}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalendarUnit {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateInterval {
+    /// A fixed number of milliseconds
+    Fixed(i64),
+
+    /// A calendar unit, which may vary in length. Weeks start on Monday, all times are UTC
+    Calendar(CalendarUnit),
+}
+
+
+impl DateInterval {
+    /// Rounds a date, in milliseconds since the epoch, down to the start of its interval
+    pub fn round(&self, millis: i64) -> i64 {
+        const MINUTE: i64 = 60 * 1000;
+        const HOUR: i64 = 60 * MINUTE;
+        const DAY: i64 = 24 * HOUR;
+
+        let unit = match *self {
+            DateInterval::Fixed(interval) => return millis - millis.rem_euclid(interval),
+            DateInterval::Calendar(unit) => unit,
+        };
+
+        let start_of_day = millis - millis.rem_euclid(DAY);
+        let date = Utc.timestamp(start_of_day.div_euclid(1000), 0);
+
+        let start = match unit {
+            CalendarUnit::Minute => return millis - millis.rem_euclid(MINUTE),
+            CalendarUnit::Hour => return millis - millis.rem_euclid(HOUR),
+            CalendarUnit::Day => return start_of_day,
+            CalendarUnit::Week => return start_of_day - date.weekday().num_days_from_monday() as i64 * DAY,
+            CalendarUnit::Month => Utc.ymd(date.year(), date.month(), 1),
+            CalendarUnit::Quarter => Utc.ymd(date.year(), (date.month() - 1) / 3 * 3 + 1, 1),
+            CalendarUnit::Year => Utc.ymd(date.year(), 1, 1),
+        };
+
+        start.and_hms(0, 0, 0).timestamp() * 1000
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompositeSourceKind {
+    /// Uses the value of the field
+    Terms,
+
+    /// Rounds numbers down to a multiple of the interval
+    Histogram(f64),
+
+    /// Rounds dates down to the start of the interval
+    DateHistogram(DateInterval),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompositeSource {
+    pub name: String,
+    pub field: FieldId,
+    pub kind: CompositeSourceKind,
+    pub descending: bool,
+
+    /// Put documents without a value into buckets with a null key, instead of skipping them
+    pub missing_bucket: bool,
+}
+
+
+impl CompositeSource {
+    fn value(&self, value: Option<FieldValue>) -> Option<CompositeValue> {
+        let value = match value.and_then(CompositeValue::from_field_value) {
+            Some(value) => value,
+            None if self.missing_bucket => return Some(CompositeValue::Null),
+            None => return None,
+        };
+
+        match (&self.kind, value) {
+            (&CompositeSourceKind::Terms, value) => Some(value),
+            (&CompositeSourceKind::Histogram(interval), CompositeValue::Integer(value)) => Some(CompositeValue::Float((value as f64 / interval).floor() * interval)),
+            (&CompositeSourceKind::Histogram(interval), CompositeValue::Float(value)) => Some(CompositeValue::Float((value / interval).floor() * interval)),
+            (&CompositeSourceKind::DateHistogram(ref interval), CompositeValue::Integer(value)) => Some(CompositeValue::Integer(interval.round(value))),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct CompositeAggregation {
+    pub sources: Vec<CompositeSource>,
+
+    /// The number of buckets to return
+    pub size: usize,
+
+    /// Only return buckets with keys after this one
+    pub after: Option<Vec<CompositeValue>>,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+impl CompositeAggregation {
+    /// Compares two keys in the order the buckets are returned
+    fn compare_keys(&self, a: &[CompositeValue], b: &[CompositeValue]) -> Ordering {
+        for ((source, a), b) in self.sources.iter().zip(a.iter()).zip(b.iter()) {
+            let ordering = if source.descending {
+                b.cmp(a)
+            } else {
+                a.cmp(b)
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompositeBucket {
+    /// The name of each source and its part of the key
+    pub key: Vec<(String, CompositeValue)>,
+
+    pub doc_count: u64,
+    pub aggregations: Vec<(String, AggregationResult)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompositeResult {
+    pub buckets: Vec<CompositeBucket>,
+
+    /// The key of the last bucket, to pass as `after` to get the next page
+    pub after_key: Option<Vec<(String, CompositeValue)>>,
+}
+
+
+#[derive(Debug)]
+pub struct CompositeAggregator<'a> {
+    aggregation: &'a CompositeAggregation,
+    breaker: &'a CircuitBreaker,
+    buckets: BTreeMap<Vec<CompositeValue>, BucketCollector<'a>>,
+}
+
+
+impl<'a> CompositeAggregator<'a> {
+    pub fn new(aggregation: &'a CompositeAggregation, breaker: &'a CircuitBreaker) -> CompositeAggregator<'a> {
+        CompositeAggregator {
+            aggregation: aggregation,
+            breaker: breaker,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        let mut key = Vec::with_capacity(self.aggregation.sources.len());
+
+        for source in self.aggregation.sources.iter() {
+            match source.value(read_value(source.field, doc_id)) {
+                Some(value) => key.push(value),
+                None => return,
+            }
+        }
+
+        if let Some(ref after) = self.aggregation.after {
+            if self.aggregation.compare_keys(&key, after) != Ordering::Greater {
+                return;
+            }
+        }
+
+        let (aggregations, breaker) = (&self.aggregation.aggregations, self.breaker);
+        self.buckets.entry(key).or_insert_with_key(|key| {
+            breaker.add(key.iter().map(CompositeValue::size).sum());
+            BucketCollector::new(aggregations, breaker)
+        }).collect(doc_id, score, read_value);
+    }
+
+    pub fn into_result(self) -> CompositeResult {
+        let aggregation = self.aggregation;
+
+        let mut buckets = self.buckets.into_iter().collect::<Vec<_>>();
+        buckets.sort_by(|&(ref a, _), &(ref b, _)| aggregation.compare_keys(a, b));
+        buckets.truncate(aggregation.size);
+
+        let mut buckets = buckets.into_iter().map(|(key, bucket)| {
+            let bucket = bucket.into_bucket();
+
+            CompositeBucket {
+                key: aggregation.sources.iter().map(|source| source.name.clone()).zip(key.into_iter()).collect(),
+                doc_count: bucket.doc_count,
+                aggregations: bucket.aggregations,
+            }
+        }).collect::<Vec<_>>();
+
+        // The next page starts after the last bucket, even if bucket_sort reorders them
+        let after_key = buckets.last().map(|bucket| bucket.key.clone());
+        run_pipelines(&aggregation.aggregations, &mut buckets);
+
+        CompositeResult {
+            after_key: after_key,
+            buckets: buckets,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::breaker::CircuitBreaker;
+
+    use super::{CompositeValue, CompositeSource, CompositeSourceKind, CompositeAggregation, CompositeAggregator, DateInterval, CalendarUnit};
+
+    #[test]
+    fn test_date_interval_round() {
+        // 2017-05-17T13:45:30.500Z, a Wednesday
+        let millis = 1495028730500;
+
+        assert_eq!(DateInterval::Fixed(1000).round(millis), 1495028730000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Hour).round(millis), 1495026000000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Day).round(millis), 1494979200000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Week).round(millis), 1494806400000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Month).round(millis), 1493596800000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Quarter).round(millis), 1491004800000);
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Year).round(millis), 1483228800000);
+
+        // Before the epoch
+        assert_eq!(DateInterval::Calendar(CalendarUnit::Day).round(-1), -86400000);
+    }
+
+    fn make_aggregation(after: Option<Vec<CompositeValue>>) -> CompositeAggregation {
+        CompositeAggregation {
+            sources: vec![
+                CompositeSource {
+                    name: "tag".to_string(),
+                    field: FieldId(1),
+                    kind: CompositeSourceKind::Terms,
+                    descending: false,
+                    missing_bucket: true,
+                },
+                CompositeSource {
+                    name: "price".to_string(),
+                    field: FieldId(2),
+                    kind: CompositeSourceKind::Histogram(10.0),
+                    descending: true,
+                    missing_bucket: false,
+                },
+            ],
+            size: 2,
+            after: after,
+            aggregations: vec![],
+        }
+    }
+
+    /// Documents are tagged "a" if their id is odd and have no tag otherwise, their price is their id
+    fn read_value(field_id: FieldId, doc_id: u64) -> Option<FieldValue> {
+        match field_id {
+            FieldId(1) if doc_id % 2 == 1 => Some(FieldValue::String("a".to_string())),
+            FieldId(2) => Some(FieldValue::Integer(doc_id as i64)),
+            _ => None,
+        }
+    }
+
+    fn run(aggregation: &CompositeAggregation) -> Vec<(Vec<CompositeValue>, u64)> {
+        let breaker = CircuitBreaker::unlimited();
+        let mut aggregator = CompositeAggregator::new(aggregation, &breaker);
+        for doc_id in 0..25 {
+            aggregator.collect(doc_id, None, &mut read_value);
+        }
+
+        let result = aggregator.into_result();
+        let buckets = result.buckets.into_iter().map(|bucket| {
+            (bucket.key.into_iter().map(|(_, value)| value).collect::<Vec<_>>(), bucket.doc_count)
+        }).collect::<Vec<_>>();
+
+        assert_eq!(result.after_key.map(|key| key.into_iter().map(|(_, value)| value).collect::<Vec<_>>()), buckets.last().map(|bucket| bucket.0.clone()));
+        buckets
+    }
+
+    #[test]
+    fn test_composite_aggregator() {
+        // Missing tags come first, prices are in descending order
+        let aggregation = make_aggregation(None);
+        assert_eq!(run(&aggregation), vec![
+            (vec![CompositeValue::Null, CompositeValue::Float(20.0)], 3),
+            (vec![CompositeValue::Null, CompositeValue::Float(10.0)], 5),
+        ]);
+
+        // Get the next page
+        let aggregation = make_aggregation(Some(vec![CompositeValue::Null, CompositeValue::Float(10.0)]));
+        assert_eq!(run(&aggregation), vec![
+            (vec![CompositeValue::Null, CompositeValue::Float(0.0)], 5),
+            (vec![CompositeValue::String("a".to_string()), CompositeValue::Float(20.0)], 2),
+        ]);
+    }
+}
@@ -0,0 +1,346 @@
+//! The filter, filters and adjacency_matrix aggregations put documents into buckets by which
+//! queries they match
+
+use fnv::FnvHashMap;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::query::Query;
+use search::aggregations::{Aggregation, Bucket, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+use search::aggregations::pipeline::run_pipelines;
+
+
+/// A query that decides which documents go into a bucket
+#[derive(Debug, PartialEq)]
+pub struct BucketFilter {
+    pub query: Query,
+
+    /// The ids of the documents that match the query, sorted
+    ///
+    /// Aggregations only see the documents that matched the search, so these must be
+    /// found by running the query on its own before the search starts
+    pub matches: Vec<u64>,
+}
+
+
+impl BucketFilter {
+    pub fn new(query: Query) -> BucketFilter {
+        BucketFilter {
+            query: query,
+            matches: Vec::new(),
+        }
+    }
+
+    pub fn matches(&self, doc_id: u64) -> bool {
+        self.matches.binary_search(&doc_id).is_ok()
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct FilterAggregation {
+    pub filter: BucketFilter,
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct FiltersAggregation {
+    pub filters: Vec<(String, BucketFilter)>,
+
+    /// Return the buckets in an object keyed by the name of each filter, instead of an array
+    pub keyed: bool,
+
+    /// If set, documents that don't match any of the filters are put in a bucket with this key
+    pub other_bucket_key: Option<String>,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiltersResult {
+    pub buckets: Vec<(String, Bucket)>,
+    pub keyed: bool,
+}
+
+
+#[derive(Debug)]
+pub struct FilterAggregator<'a> {
+    aggregation: &'a FilterAggregation,
+    bucket: BucketCollector<'a>,
+}
+
+
+impl<'a> FilterAggregator<'a> {
+    pub fn new(aggregation: &'a FilterAggregation, breaker: &'a CircuitBreaker) -> FilterAggregator<'a> {
+        FilterAggregator {
+            aggregation: aggregation,
+            bucket: BucketCollector::new(&aggregation.aggregations, breaker),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        if self.aggregation.filter.matches(doc_id) {
+            self.bucket.collect(doc_id, score, read_value);
+        }
+    }
+
+    pub fn into_result(self) -> Bucket {
+        self.bucket.into_bucket()
+    }
+}
+
+
+#[derive(Debug)]
+pub struct FiltersAggregator<'a> {
+    aggregation: &'a FiltersAggregation,
+    buckets: Vec<BucketCollector<'a>>,
+    other_bucket: Option<BucketCollector<'a>>,
+}
+
+
+impl<'a> FiltersAggregator<'a> {
+    pub fn new(aggregation: &'a FiltersAggregation, breaker: &'a CircuitBreaker) -> FiltersAggregator<'a> {
+        FiltersAggregator {
+            aggregation: aggregation,
+            buckets: aggregation.filters.iter().map(|_| BucketCollector::new(&aggregation.aggregations, breaker)).collect(),
+            other_bucket: aggregation.other_bucket_key.as_ref().map(|_| BucketCollector::new(&aggregation.aggregations, breaker)),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        let mut matched = false;
+
+        for (&(_, ref filter), bucket) in self.aggregation.filters.iter().zip(self.buckets.iter_mut()) {
+            if filter.matches(doc_id) {
+                bucket.collect(doc_id, score, read_value);
+                matched = true;
+            }
+        }
+
+        if !matched {
+            if let Some(ref mut other_bucket) = self.other_bucket {
+                other_bucket.collect(doc_id, score, read_value);
+            }
+        }
+    }
+
+    pub fn into_result(self) -> FiltersResult {
+        let mut buckets = self.aggregation.filters.iter().zip(self.buckets.into_iter())
+            .map(|(&(ref name, _), bucket)| (name.clone(), bucket.into_bucket()))
+            .collect::<Vec<_>>();
+
+        if let (Some(other_bucket_key), Some(other_bucket)) = (self.aggregation.other_bucket_key.as_ref(), self.other_bucket) {
+            buckets.push((other_bucket_key.clone(), other_bucket.into_bucket()));
+        }
+
+        run_pipelines(&self.aggregation.aggregations, &mut buckets);
+
+        FiltersResult {
+            buckets: buckets,
+            keyed: self.aggregation.keyed,
+        }
+    }
+}
+
+
+/// The most filters an adjacency_matrix aggregation can have. The number of buckets grows
+/// with the square of this
+pub const MAX_ADJACENCY_MATRIX_FILTERS: usize = 100;
+
+
+#[derive(Debug, PartialEq)]
+pub struct AdjacencyMatrixAggregation {
+    /// Sorted by name
+    pub filters: Vec<(String, BucketFilter)>,
+
+    /// Joins the names of two filters to make the key of their intersection's bucket
+    pub separator: String,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdjacencyMatrixResult {
+    /// Each filter's bucket followed by its intersections with the filters after it. Empty
+    /// buckets are left out
+    pub buckets: Vec<(String, Bucket)>,
+}
+
+
+#[derive(Debug)]
+pub struct AdjacencyMatrixAggregator<'a> {
+    aggregation: &'a AdjacencyMatrixAggregation,
+    breaker: &'a CircuitBreaker,
+    buckets: Vec<BucketCollector<'a>>,
+
+    /// Intersections are only made once a document matches both filters
+    intersections: FnvHashMap<(usize, usize), BucketCollector<'a>>,
+}
+
+
+impl<'a> AdjacencyMatrixAggregator<'a> {
+    pub fn new(aggregation: &'a AdjacencyMatrixAggregation, breaker: &'a CircuitBreaker) -> AdjacencyMatrixAggregator<'a> {
+        AdjacencyMatrixAggregator {
+            aggregation: aggregation,
+            breaker: breaker,
+            buckets: aggregation.filters.iter().map(|_| BucketCollector::new(&aggregation.aggregations, breaker)).collect(),
+            intersections: FnvHashMap::default(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        let matched = self.aggregation.filters.iter().enumerate()
+            .filter(|&(_, &(_, ref filter))| filter.matches(doc_id))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        for (n, &i) in matched.iter().enumerate() {
+            self.buckets[i].collect(doc_id, score, read_value);
+
+            for &j in matched[n + 1..].iter() {
+                let (aggregations, breaker) = (&self.aggregation.aggregations, self.breaker);
+                self.intersections.entry((i, j)).or_insert_with(|| BucketCollector::new(aggregations, breaker)).collect(doc_id, score, read_value);
+            }
+        }
+    }
+
+    pub fn into_result(mut self) -> AdjacencyMatrixResult {
+        let filters = &self.aggregation.filters;
+        let mut buckets = Vec::new();
+
+        for (i, bucket) in self.buckets.into_iter().enumerate() {
+            let bucket = bucket.into_bucket();
+            if bucket.doc_count > 0 {
+                buckets.push((filters[i].0.clone(), bucket));
+            }
+
+            for j in i + 1..filters.len() {
+                if let Some(intersection) = self.intersections.remove(&(i, j)) {
+                    let key = format!("{}{}{}", filters[i].0, self.aggregation.separator, filters[j].0);
+                    buckets.push((key, intersection.into_bucket()));
+                }
+            }
+        }
+
+        run_pipelines(&self.aggregation.aggregations, &mut buckets);
+
+        AdjacencyMatrixResult {
+            buckets: buckets,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::query::Query;
+    use search::aggregations::{Aggregation, AggregationResult, Bucket, ValueSource};
+    use search::aggregations::breaker::CircuitBreaker;
+    use search::aggregations::metric::{Metric, MetricAggregation, MetricResult};
+
+    use super::{BucketFilter, FilterAggregation, FilterAggregator, FiltersAggregation, FiltersAggregator, AdjacencyMatrixAggregation, AdjacencyMatrixAggregator};
+
+    fn filter(matches: Vec<u64>) -> BucketFilter {
+        BucketFilter {
+            query: Query::all(),
+            matches: matches,
+        }
+    }
+
+    fn sum_aggregation() -> Vec<(String, Aggregation)> {
+        vec![
+            ("total".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::Sum,
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+            })),
+        ]
+    }
+
+    fn bucket(doc_count: u64, total: f64) -> Bucket {
+        Bucket {
+            doc_count: doc_count,
+            aggregations: vec![("total".to_string(), AggregationResult::Metric(MetricResult::Value(Some(total))))],
+        }
+    }
+
+    #[test]
+    fn test_filter_aggregator() {
+        let aggregation = FilterAggregation {
+            filter: filter(vec![1, 3]),
+            aggregations: sum_aggregation(),
+        };
+
+        // The value of each document is its id
+        let breaker = CircuitBreaker::unlimited();
+        let mut aggregator = FilterAggregator::new(&aggregation, &breaker);
+        for doc_id in 1..5 {
+            aggregator.collect(doc_id, None, &mut |_, doc_id| Some(FieldValue::Integer(doc_id as i64)));
+        }
+
+        assert_eq!(aggregator.into_result(), bucket(2, 4.0));
+    }
+
+    #[test]
+    fn test_filters_aggregator() {
+        let aggregation = FiltersAggregation {
+            filters: vec![
+                ("odd".to_string(), filter(vec![1, 3])),
+                ("small".to_string(), filter(vec![1, 2])),
+            ],
+            keyed: true,
+            other_bucket_key: Some("_other_".to_string()),
+            aggregations: sum_aggregation(),
+        };
+
+        let breaker = CircuitBreaker::unlimited();
+
+        let mut aggregator = FiltersAggregator::new(&aggregation, &breaker);
+        for doc_id in 1..6 {
+            aggregator.collect(doc_id, None, &mut |_, doc_id| Some(FieldValue::Integer(doc_id as i64)));
+        }
+
+        assert_eq!(aggregator.into_result().buckets, vec![
+            ("odd".to_string(), bucket(2, 4.0)),
+            ("small".to_string(), bucket(2, 3.0)),
+            ("_other_".to_string(), bucket(2, 9.0)),
+        ]);
+    }
+
+    #[test]
+    fn test_adjacency_matrix_aggregator() {
+        let aggregation = AdjacencyMatrixAggregation {
+            filters: vec![
+                ("a".to_string(), filter(vec![1, 2, 3])),
+                ("b".to_string(), filter(vec![2, 3])),
+                ("c".to_string(), filter(vec![3])),
+                ("d".to_string(), filter(vec![])),
+            ],
+            separator: "&".to_string(),
+            aggregations: sum_aggregation(),
+        };
+
+        let breaker = CircuitBreaker::unlimited();
+
+        let mut aggregator = AdjacencyMatrixAggregator::new(&aggregation, &breaker);
+        for doc_id in 1..5 {
+            aggregator.collect(doc_id, None, &mut |_, doc_id| Some(FieldValue::Integer(doc_id as i64)));
+        }
+
+        // Empty buckets aren't returned
+        assert_eq!(aggregator.into_result().buckets, vec![
+            ("a".to_string(), bucket(3, 6.0)),
+            ("a&b".to_string(), bucket(2, 5.0)),
+            ("a&c".to_string(), bucket(1, 3.0)),
+            ("b".to_string(), bucket(2, 5.0)),
+            ("b&c".to_string(), bucket(1, 3.0)),
+            ("c".to_string(), bucket(1, 3.0)),
+        ]);
+    }
+}
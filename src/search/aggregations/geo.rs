@@ -0,0 +1,163 @@
+//! Aggregations over geo_point fields
+//!
+//! The geo_distance aggregation is a range aggregation over the distance of each point from
+//! an origin, see `GeoDistance`. The geohash_grid aggregation puts points into buckets by
+//! the geohash cell they're in.
+
+use std::cmp::Ordering;
+
+use fnv::FnvHashMap;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::geo::{GeoPoint, DistanceType, DistanceUnit};
+use search::aggregations::{Aggregation, Bucket, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+use search::aggregations::pipeline::run_pipelines;
+
+
+/// The distance of a document's point from an origin
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoDistance {
+    pub field: FieldId,
+    pub origin: GeoPoint,
+    pub unit: DistanceUnit,
+    pub distance_type: DistanceType,
+}
+
+
+impl GeoDistance {
+    pub fn read<F: FnMut(FieldId) -> Option<FieldValue>>(&self, read_value: &mut F) -> Option<f64> {
+        match read_value(self.field) {
+            Some(FieldValue::GeoPoint(point)) => Some(self.unit.from_meters(self.origin.distance(&point, self.distance_type))),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct GeohashGridAggregation {
+    pub field: FieldId,
+
+    /// The length of the geohashes, larger numbers give smaller cells
+    pub precision: usize,
+
+    /// The maximum number of cells to return
+    pub size: usize,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeohashGridResult {
+    /// Each cell's geohash and bucket, with the fullest cells first
+    pub buckets: Vec<(String, Bucket)>,
+}
+
+
+#[derive(Debug)]
+pub struct GeohashGridAggregator<'a> {
+    aggregation: &'a GeohashGridAggregation,
+    breaker: &'a CircuitBreaker,
+    cells: FnvHashMap<String, BucketCollector<'a>>,
+}
+
+
+impl<'a> GeohashGridAggregator<'a> {
+    pub fn new(aggregation: &'a GeohashGridAggregation, breaker: &'a CircuitBreaker) -> GeohashGridAggregator<'a> {
+        GeohashGridAggregator {
+            aggregation: aggregation,
+            breaker: breaker,
+            cells: FnvHashMap::default(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        let geohash = match read_value(self.aggregation.field, doc_id) {
+            Some(FieldValue::GeoPoint(point)) => point.geohash(self.aggregation.precision),
+            _ => return,
+        };
+
+        let (aggregations, breaker) = (&self.aggregation.aggregations, self.breaker);
+        self.cells.entry(geohash).or_insert_with_key(|geohash| {
+            breaker.add(geohash.len());
+            BucketCollector::new(aggregations, breaker)
+        }).collect(doc_id, score, read_value);
+    }
+
+    pub fn into_result(self) -> GeohashGridResult {
+        let mut buckets = self.cells.into_iter().map(|(geohash, bucket)| (geohash, bucket.into_bucket())).collect::<Vec<_>>();
+
+        buckets.sort_by(|&(ref key_a, ref a), &(ref key_b, ref b)| {
+            match b.doc_count.cmp(&a.doc_count) {
+                Ordering::Equal => key_a.cmp(key_b),
+                ordering => ordering,
+            }
+        });
+        buckets.truncate(self.aggregation.size);
+        run_pipelines(&self.aggregation.aggregations, &mut buckets);
+
+        GeohashGridResult {
+            buckets: buckets,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::breaker::CircuitBreaker;
+    use search::geo::{GeoPoint, DistanceType, DistanceUnit};
+
+    use super::{GeoDistance, GeohashGridAggregation, GeohashGridAggregator};
+
+    fn point(lat: f64, lon: f64) -> FieldValue {
+        FieldValue::GeoPoint(GeoPoint::new(lat, lon).unwrap())
+    }
+
+    #[test]
+    fn test_geo_distance() {
+        let distance = GeoDistance {
+            field: FieldId(1),
+            origin: GeoPoint::new(51.5074, -0.1278).unwrap(),
+            unit: DistanceUnit::Kilometers,
+            distance_type: DistanceType::Arc,
+        };
+
+        let paris = distance.read(&mut |_| Some(point(48.8566, 2.3522))).unwrap();
+        assert!((paris - 343.5).abs() < 1.0, "distance was {}", paris);
+
+        assert_eq!(distance.read(&mut |_| Some(FieldValue::Integer(1))), None);
+    }
+
+    #[test]
+    fn test_geohash_grid_aggregator() {
+        let aggregation = GeohashGridAggregation {
+            field: FieldId(1),
+            precision: 3,
+            size: 2,
+            aggregations: vec![],
+        };
+
+        let points = vec![
+            point(51.5074, -0.1278),
+            point(51.5007, -0.1246),
+            point(48.8566, 2.3522),
+            point(52.5200, 13.4050),
+        ];
+
+        let breaker = CircuitBreaker::unlimited();
+
+        let mut aggregator = GeohashGridAggregator::new(&aggregation, &breaker);
+        for doc_id in 0..5 {
+            aggregator.collect(doc_id, None, &mut |_, doc_id| points.get(doc_id as usize).cloned());
+        }
+
+        let buckets = aggregator.into_result().buckets.into_iter().map(|(key, bucket)| (key, bucket.doc_count)).collect::<Vec<_>>();
+        assert_eq!(buckets, vec![("gcp".to_string(), 2), ("u09".to_string(), 1)]);
+    }
+}
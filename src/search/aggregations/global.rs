@@ -0,0 +1,67 @@
+//! The global aggregation runs its sub-aggregations on every document in the index
+//!
+//! It ignores the query, so it isn't run alongside it like the other aggregations. Once the
+//! search has finished, `GlobalAggregation::run` is called with the ids of every document.
+//! It can only be used at the top level.
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, Bucket, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+
+
+#[derive(Debug, PartialEq)]
+pub struct GlobalAggregation {
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+impl GlobalAggregation {
+    /// Runs the sub-aggregations on a list of documents. These aren't scored
+    ///
+    /// Stops early if the breaker trips.
+    pub fn run<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&self, all_doc_ids: &[u64], breaker: &CircuitBreaker, read_value: &mut F) -> Bucket {
+        let mut bucket = BucketCollector::new(&self.aggregations, breaker);
+
+        for &doc_id in all_doc_ids.iter() {
+            if breaker.is_tripped() {
+                break;
+            }
+
+            bucket.collect(doc_id, None, read_value);
+        }
+
+        bucket.into_bucket()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::{Aggregation, AggregationResult, ValueSource};
+    use search::aggregations::metric::{Metric, MetricAggregation, MetricResult};
+    use search::aggregations::breaker::CircuitBreaker;
+
+    use super::GlobalAggregation;
+
+    #[test]
+    fn test_global_aggregation() {
+        let aggregation = GlobalAggregation {
+            aggregations: vec![
+                ("max_price".to_string(), Aggregation::Metric(MetricAggregation {
+                    metric: Metric::Max,
+                    source: ValueSource::Field(FieldId(1)),
+                    missing: None,
+                })),
+            ],
+        };
+
+        let bucket = aggregation.run(&[1, 2, 3], &CircuitBreaker::unlimited(), &mut |_, doc_id| Some(FieldValue::Integer(doc_id as i64 * 10)));
+        assert_eq!(bucket.doc_count, 3);
+        assert_eq!(bucket.aggregations, vec![
+            ("max_price".to_string(), AggregationResult::Metric(MetricResult::Value(Some(30.0)))),
+        ]);
+    }
+}
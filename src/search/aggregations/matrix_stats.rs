@@ -0,0 +1,252 @@
+//! The matrix_stats aggregation computes statistics over several numeric fields together
+//!
+//! Along with the mean, variance, skewness and kurtosis of each field, this gives the
+//! covariance and correlation between every pair of fields. Only documents that have a value
+//! for every field are counted. The moments are updated as each document is added, which
+//! is more accurate than adding up powers of the values.
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::script::field_value_to_number;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixStatsField {
+    pub name: String,
+    pub field: FieldId,
+
+    /// The value to use for documents that don't have one. These documents are skipped if not set
+    pub missing: Option<f64>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixStatsAggregation {
+    pub fields: Vec<MatrixStatsField>,
+}
+
+
+/// The central moments of each field and the co-moments of each pair of fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixStats {
+    /// The names of the fields, in the order they were given
+    pub names: Vec<String>,
+
+    pub count: u64,
+    pub means: Vec<f64>,
+
+    /// The sums of the second, third and fourth powers of each value's distance from the mean
+    m2: Vec<f64>,
+    m3: Vec<f64>,
+    m4: Vec<f64>,
+
+    /// The sums of the products of each pair of values' distances from their means
+    comoments: Vec<Vec<f64>>,
+}
+
+
+impl MatrixStats {
+    fn new(names: Vec<String>) -> MatrixStats {
+        let fields = names.len();
+
+        MatrixStats {
+            names: names,
+            count: 0,
+            means: vec![0.0; fields],
+            m2: vec![0.0; fields],
+            m3: vec![0.0; fields],
+            m4: vec![0.0; fields],
+            comoments: vec![vec![0.0; fields]; fields],
+        }
+    }
+
+    fn add(&mut self, values: &[f64]) {
+        self.count += 1;
+        let n = self.count as f64;
+
+        let deltas = values.iter().zip(self.means.iter()).map(|(value, mean)| value - mean).collect::<Vec<_>>();
+
+        for (i, &delta) in deltas.iter().enumerate() {
+            let delta_n = delta / n;
+            let term = delta * delta_n * (n - 1.0);
+
+            self.m4[i] += term * delta_n * delta_n * (n * n - 3.0 * n + 3.0) + 6.0 * delta_n * delta_n * self.m2[i] - 4.0 * delta_n * self.m3[i];
+            self.m3[i] += term * delta_n * (n - 2.0) - 3.0 * delta_n * self.m2[i];
+            self.m2[i] += term;
+            self.means[i] += delta_n;
+        }
+
+        for i in 0..deltas.len() {
+            for j in 0..deltas.len() {
+                self.comoments[i][j] += deltas[i] * deltas[j] * (n - 1.0) / n;
+            }
+        }
+    }
+
+    /// The sample variance of a field. None if there are fewer than two documents
+    pub fn variance(&self, field: usize) -> Option<f64> {
+        self.covariance(field, field)
+    }
+
+    pub fn skewness(&self, field: usize) -> Option<f64> {
+        if self.count == 0 || self.m2[field] == 0.0 {
+            return None;
+        }
+
+        Some((self.count as f64).sqrt() * self.m3[field] / self.m2[field].powf(1.5))
+    }
+
+    pub fn kurtosis(&self, field: usize) -> Option<f64> {
+        if self.count == 0 || self.m2[field] == 0.0 {
+            return None;
+        }
+
+        Some(self.count as f64 * self.m4[field] / (self.m2[field] * self.m2[field]))
+    }
+
+    /// The sample covariance of two fields. None if there are fewer than two documents
+    pub fn covariance(&self, a: usize, b: usize) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+
+        Some(self.comoments[a][b] / (self.count - 1) as f64)
+    }
+
+    /// The Pearson correlation of two fields. None if either field doesn't vary
+    pub fn correlation(&self, a: usize, b: usize) -> Option<f64> {
+        if a == b {
+            return if self.count > 0 { Some(1.0) } else { None };
+        }
+
+        let denominator = (self.variance(a)? * self.variance(b)?).sqrt();
+        if denominator == 0.0 {
+            return None;
+        }
+
+        Some(self.covariance(a, b)? / denominator)
+    }
+}
+
+
+#[derive(Debug)]
+pub struct MatrixStatsAggregator<'a> {
+    aggregation: &'a MatrixStatsAggregation,
+    stats: MatrixStats,
+    values: Vec<f64>,
+}
+
+
+impl<'a> MatrixStatsAggregator<'a> {
+    pub fn new(aggregation: &'a MatrixStatsAggregation) -> MatrixStatsAggregator<'a> {
+        MatrixStatsAggregator {
+            aggregation: aggregation,
+            stats: MatrixStats::new(aggregation.fields.iter().map(|field| field.name.clone()).collect()),
+            values: Vec::with_capacity(aggregation.fields.len()),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId) -> Option<FieldValue>>(&mut self, read_value: &mut F) {
+        self.values.clear();
+
+        for field in self.aggregation.fields.iter() {
+            match read_value(field.field).and_then(|value| field_value_to_number(&value)).or(field.missing) {
+                Some(value) => self.values.push(value),
+                None => return,
+            }
+        }
+
+        self.stats.add(&self.values);
+    }
+
+    pub fn into_result(self) -> MatrixStats {
+        self.stats
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+
+    use super::{MatrixStatsAggregation, MatrixStatsField, MatrixStatsAggregator, MatrixStats};
+
+    fn field(name: &str, field_id: u32) -> MatrixStatsField {
+        MatrixStatsField {
+            name: name.to_string(),
+            field: FieldId(field_id),
+            missing: None,
+        }
+    }
+
+    fn aggregate(aggregation: &MatrixStatsAggregation, docs: &[(Option<i64>, Option<i64>)]) -> MatrixStats {
+        let mut aggregator = MatrixStatsAggregator::new(aggregation);
+        for &(a, b) in docs.iter() {
+            aggregator.collect(&mut |field_id| {
+                match field_id {
+                    FieldId(1) => a.map(FieldValue::Integer),
+                    _ => b.map(FieldValue::Integer),
+                }
+            });
+        }
+
+        aggregator.into_result()
+    }
+
+    fn assert_close(value: Option<f64>, expected: f64) {
+        let value = value.expect("expected a value");
+        assert!((value - expected).abs() < 1e-9, "expected {}, got {}", expected, value);
+    }
+
+    #[test]
+    fn test_matrix_stats() {
+        let aggregation = MatrixStatsAggregation {
+            fields: vec![field("a", 1), field("b", 2)],
+        };
+
+        let docs = [(Some(1), Some(2)), (Some(2), Some(4)), (Some(3), Some(5)), (Some(4), Some(4)), (Some(5), Some(5)), (Some(7), None)];
+        let stats = aggregate(&aggregation, &docs);
+
+        // The document without "b" isn't counted
+        assert_eq!(stats.count, 5);
+        assert_eq!(stats.names, vec!["a".to_string(), "b".to_string()]);
+        assert_close(Some(stats.means[0]), 3.0);
+        assert_close(Some(stats.means[1]), 4.0);
+        assert_close(stats.variance(0), 2.5);
+        assert_close(stats.variance(1), 1.5);
+        assert_close(stats.covariance(0, 1), 1.5);
+        assert_close(stats.covariance(1, 0), 1.5);
+        assert_close(stats.correlation(0, 1), 1.5 / (2.5f64 * 1.5).sqrt());
+        assert_close(stats.correlation(0, 0), 1.0);
+
+        // "a" is symmetric, "b" is skewed towards its larger values
+        assert_close(stats.skewness(0), 0.0);
+        assert!(stats.skewness(1).unwrap() < 0.0);
+        assert_close(stats.kurtosis(0), 1.7);
+    }
+
+    #[test]
+    fn test_missing() {
+        let aggregation = MatrixStatsAggregation {
+            fields: vec![field("a", 1), MatrixStatsField { missing: Some(0.0), ..field("b", 2) }],
+        };
+
+        let stats = aggregate(&aggregation, &[(Some(1), None), (Some(3), Some(4)), (None, Some(4))]);
+        assert_eq!(stats.count, 2);
+        assert_close(Some(stats.means[1]), 2.0);
+    }
+
+    #[test]
+    fn test_no_values() {
+        let aggregation = MatrixStatsAggregation {
+            fields: vec![field("a", 1), field("b", 2)],
+        };
+
+        let stats = aggregate(&aggregation, &[(Some(1), Some(1))]);
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.variance(0), None);
+        assert_eq!(stats.correlation(0, 1), None);
+        assert_eq!(stats.skewness(0), None);
+    }
+}
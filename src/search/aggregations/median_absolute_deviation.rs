@@ -0,0 +1,126 @@
+//! The median_absolute_deviation aggregation measures how spread out values are
+//!
+//! This is the median of each value's distance from the median of all the values. Unlike the
+//! standard deviation it isn't thrown off by a few outliers. The values are read once and kept,
+//! so the result is exact.
+
+use std::mem;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::ValueSource;
+use search::aggregations::breaker::CircuitBreaker;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MedianAbsoluteDeviationAggregation {
+    pub source: ValueSource,
+
+    /// The value to use for documents that don't have one. These documents are skipped if not set
+    pub missing: Option<f64>,
+}
+
+
+/// Finds the median of some values, reordering them. Returns None if there aren't any
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let middle = values.len() / 2;
+    let upper = *values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b)).1;
+
+    if values.len() % 2 == 1 {
+        return Some(upper);
+    }
+
+    // The largest value below the middle is the other half of an even median
+    let lower = values[..middle].iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    Some((lower + upper) / 2.0)
+}
+
+
+#[derive(Debug)]
+pub struct MedianAbsoluteDeviationAggregator<'a> {
+    aggregation: &'a MedianAbsoluteDeviationAggregation,
+    breaker: &'a CircuitBreaker,
+    values: Vec<f64>,
+}
+
+
+impl<'a> MedianAbsoluteDeviationAggregator<'a> {
+    pub fn new(aggregation: &'a MedianAbsoluteDeviationAggregation, breaker: &'a CircuitBreaker) -> MedianAbsoluteDeviationAggregator<'a> {
+        MedianAbsoluteDeviationAggregator {
+            aggregation: aggregation,
+            breaker: breaker,
+            values: Vec::new(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId) -> Option<FieldValue>>(&mut self, score: Option<f32>, read_value: &mut F) {
+        if let Some(value) = self.aggregation.source.read(score, read_value).or(self.aggregation.missing) {
+            if !value.is_nan() {
+                self.breaker.add(mem::size_of::<f64>());
+                self.values.push(value);
+            }
+        }
+    }
+
+    /// Returns None if there were no values
+    pub fn into_result(mut self) -> Option<f64> {
+        let center = median(&mut self.values)?;
+
+        for value in self.values.iter_mut() {
+            *value = (*value - center).abs();
+        }
+
+        median(&mut self.values)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::ValueSource;
+    use search::aggregations::breaker::CircuitBreaker;
+
+    use super::{MedianAbsoluteDeviationAggregation, MedianAbsoluteDeviationAggregator, median};
+
+    fn aggregate(values: &[i64]) -> Option<f64> {
+        let aggregation = MedianAbsoluteDeviationAggregation {
+            source: ValueSource::Field(FieldId(1)),
+            missing: None,
+        };
+
+        let breaker = CircuitBreaker::unlimited();
+
+        let mut aggregator = MedianAbsoluteDeviationAggregator::new(&aggregation, &breaker);
+        for value in values.iter() {
+            aggregator.collect(None, &mut |_| Some(FieldValue::Integer(*value)));
+        }
+        aggregator.collect(None, &mut |_| None);
+
+        aggregator.into_result()
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), Some(2.5));
+        assert_eq!(median(&mut []), None);
+    }
+
+    #[test]
+    fn test_median_absolute_deviation() {
+        // The median is 2, so the deviations are 1, 1, 0, 0, 2, 4, 7
+        assert_eq!(aggregate(&[1, 1, 2, 2, 4, 6, 9]), Some(1.0));
+
+        // An outlier doesn't change it much
+        assert_eq!(aggregate(&[1, 1, 2, 2, 4, 6, 9000]), Some(1.0));
+
+        assert_eq!(aggregate(&[5]), Some(0.0));
+        assert_eq!(aggregate(&[]), None);
+    }
+}
@@ -0,0 +1,315 @@
+//! Metric aggregations compute a single statistic over the values of the documents they're given
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::ValueSource;
+
+
+/// The number of standard deviations either side of the mean given by extended_stats, if the
+/// request doesn't say
+pub const DEFAULT_SIGMA: f64 = 2.0;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Min,
+    Max,
+    Sum,
+    Avg,
+
+    /// Counts the documents that have a value, which doesn't need to be numeric
+    ValueCount,
+
+    /// Computes all of the above at once
+    Stats,
+
+    /// Stats, along with the variance and standard deviation
+    ExtendedStats {
+        /// How many standard deviations from the mean the bounds are
+        sigma: f64,
+    },
+}
+
+
+impl Metric {
+    pub fn from_name(name: &str) -> Option<Metric> {
+        match name {
+            "min" => Some(Metric::Min),
+            "max" => Some(Metric::Max),
+            "sum" => Some(Metric::Sum),
+            "avg" => Some(Metric::Avg),
+            "value_count" => Some(Metric::ValueCount),
+            "stats" => Some(Metric::Stats),
+            "extended_stats" => Some(Metric::ExtendedStats { sigma: DEFAULT_SIGMA }),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricAggregation {
+    pub metric: Metric,
+    pub source: ValueSource,
+
+    /// The value to use for documents that don't have one. These documents are skipped if not set
+    pub missing: Option<f64>,
+}
+
+
+/// The count, sum, min and max of a set of values. Every metric can be worked out from these
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub count: u64,
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            count: 0,
+            sum: 0.0,
+            min: None,
+            max: None,
+        }
+    }
+}
+
+
+impl Stats {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+
+    pub fn avg(&self) -> Option<f64> {
+        if self.count > 0 {
+            Some(self.sum / self.count as f64)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Stats along with the sum of the squares of the values, which the variance is worked out from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtendedStats {
+    pub stats: Stats,
+    pub sum_of_squares: f64,
+    pub sigma: f64,
+}
+
+
+impl ExtendedStats {
+    pub fn variance_population(&self) -> Option<f64> {
+        let avg = self.stats.avg()?;
+
+        // Rounding can make this slightly negative when the values are all the same
+        Some((self.sum_of_squares / self.stats.count as f64 - avg * avg).max(0.0))
+    }
+
+    /// The variance with Bessel's correction, for when the values are a sample of a population
+    pub fn variance_sampling(&self) -> Option<f64> {
+        if self.stats.count < 2 {
+            return None;
+        }
+
+        let count = self.stats.count as f64;
+        Some(self.variance_population()? * count / (count - 1.0))
+    }
+
+    pub fn std_deviation_population(&self) -> Option<f64> {
+        self.variance_population().map(f64::sqrt)
+    }
+
+    pub fn std_deviation_sampling(&self) -> Option<f64> {
+        self.variance_sampling().map(f64::sqrt)
+    }
+
+    /// Returns the mean minus and plus sigma standard deviations
+    pub fn std_deviation_bounds(&self, std_deviation: Option<f64>) -> Option<(f64, f64)> {
+        let avg = self.stats.avg()?;
+        let std_deviation = std_deviation?;
+        Some((avg - self.sigma * std_deviation, avg + self.sigma * std_deviation))
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricResult {
+    /// The result of a single-value metric. None if there were no values to compute it from
+    Value(Option<f64>),
+
+    Stats(Stats),
+    ExtendedStats(ExtendedStats),
+}
+
+
+#[derive(Debug)]
+pub struct MetricAggregator<'a> {
+    aggregation: &'a MetricAggregation,
+    stats: Stats,
+
+    /// Only added up for extended_stats
+    sum_of_squares: f64,
+}
+
+
+impl<'a> MetricAggregator<'a> {
+    pub fn new(aggregation: &'a MetricAggregation) -> MetricAggregator<'a> {
+        MetricAggregator {
+            aggregation: aggregation,
+            stats: Stats::default(),
+            sum_of_squares: 0.0,
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId) -> Option<FieldValue>>(&mut self, score: Option<f32>, read_value: &mut F) {
+        if self.aggregation.metric == Metric::ValueCount {
+            if self.aggregation.missing.is_some() || self.aggregation.source.has_value(score, read_value) {
+                self.stats.count += 1;
+            }
+
+            return;
+        }
+
+        if let Some(value) = self.aggregation.source.read(score, read_value).or(self.aggregation.missing) {
+            self.stats.add(value);
+            self.sum_of_squares += value * value;
+        }
+    }
+
+    pub fn into_result(self) -> MetricResult {
+        let stats = self.stats;
+
+        match self.aggregation.metric {
+            Metric::Min => MetricResult::Value(stats.min),
+            Metric::Max => MetricResult::Value(stats.max),
+            Metric::Sum => MetricResult::Value(Some(stats.sum)),
+            Metric::Avg => MetricResult::Value(stats.avg()),
+            Metric::ValueCount => MetricResult::Value(Some(stats.count as f64)),
+            Metric::Stats => MetricResult::Stats(stats),
+            Metric::ExtendedStats { sigma } => {
+                MetricResult::ExtendedStats(ExtendedStats {
+                    stats: stats,
+                    sum_of_squares: self.sum_of_squares,
+                    sigma: sigma,
+                })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::script::{Script, BinaryOperator};
+
+    use search::aggregations::ValueSource;
+
+    use super::{Metric, MetricAggregation, MetricAggregator, MetricResult, Stats, ExtendedStats};
+
+    fn aggregate(aggregation: &MetricAggregation, values: &[Option<FieldValue>]) -> MetricResult {
+        let mut aggregator = MetricAggregator::new(aggregation);
+        for value in values.iter() {
+            aggregator.collect(None, &mut |_| value.clone());
+        }
+
+        aggregator.into_result()
+    }
+
+    fn metric(metric: Metric) -> MetricAggregation {
+        MetricAggregation {
+            metric: metric,
+            source: ValueSource::Field(FieldId(1)),
+            missing: None,
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let values = vec![
+            Some(FieldValue::Integer(3)),
+            None,
+            Some(FieldValue::Integer(1)),
+            Some(FieldValue::String("foo".to_string())),
+            Some(FieldValue::Integer(8)),
+        ];
+
+        assert_eq!(aggregate(&metric(Metric::Min), &values), MetricResult::Value(Some(1.0)));
+        assert_eq!(aggregate(&metric(Metric::Max), &values), MetricResult::Value(Some(8.0)));
+        assert_eq!(aggregate(&metric(Metric::Sum), &values), MetricResult::Value(Some(12.0)));
+        assert_eq!(aggregate(&metric(Metric::Avg), &values), MetricResult::Value(Some(4.0)));
+
+        // Counts every value, not just numeric ones
+        assert_eq!(aggregate(&metric(Metric::ValueCount), &values), MetricResult::Value(Some(4.0)));
+
+        assert_eq!(aggregate(&metric(Metric::Stats), &values), MetricResult::Stats(Stats {
+            count: 3,
+            sum: 12.0,
+            min: Some(1.0),
+            max: Some(8.0),
+        }));
+    }
+
+    #[test]
+    fn test_extended_stats() {
+        let values = [2, 4, 4, 4, 5, 5, 7, 9].iter().map(|&value| Some(FieldValue::Integer(value))).collect::<Vec<_>>();
+
+        let result = match aggregate(&metric(Metric::ExtendedStats { sigma: 2.0 }), &values) {
+            MetricResult::ExtendedStats(result) => result,
+            result => panic!("expected extended stats, got {:?}", result),
+        };
+
+        assert_eq!(result.sum_of_squares, 232.0);
+        assert_eq!(result.variance_population(), Some(4.0));
+        assert_eq!(result.std_deviation_population(), Some(2.0));
+        assert_eq!(result.variance_sampling(), Some(32.0 / 7.0));
+        assert_eq!(result.std_deviation_bounds(result.std_deviation_population()), Some((1.0, 9.0)));
+
+        // Nothing can be worked out without any values
+        let empty = ExtendedStats {
+            stats: Stats::default(),
+            sum_of_squares: 0.0,
+            sigma: 2.0,
+        };
+        assert_eq!(empty.variance_population(), None);
+        assert_eq!(empty.std_deviation_bounds(empty.std_deviation_population()), None);
+    }
+
+    #[test]
+    fn test_no_values() {
+        assert_eq!(aggregate(&metric(Metric::Min), &[]), MetricResult::Value(None));
+        assert_eq!(aggregate(&metric(Metric::Avg), &[None]), MetricResult::Value(None));
+        assert_eq!(aggregate(&metric(Metric::Sum), &[None]), MetricResult::Value(Some(0.0)));
+        assert_eq!(aggregate(&metric(Metric::ValueCount), &[None]), MetricResult::Value(Some(0.0)));
+    }
+
+    #[test]
+    fn test_missing() {
+        let aggregation = MetricAggregation {
+            missing: Some(10.0),
+            ..metric(Metric::Avg)
+        };
+
+        assert_eq!(aggregate(&aggregation, &[Some(FieldValue::Integer(2)), None]), MetricResult::Value(Some(6.0)));
+    }
+
+    #[test]
+    fn test_script() {
+        let aggregation = MetricAggregation {
+            source: ValueSource::Script(Script::BinaryOp(BinaryOperator::Multiply, Box::new(Script::DocValue(FieldId(1))), Box::new(Script::Number(2.0)))),
+            ..metric(Metric::Sum)
+        };
+
+        assert_eq!(aggregate(&aggregation, &[Some(FieldValue::Integer(2)), Some(FieldValue::Integer(5))]), MetricResult::Value(Some(14.0)));
+    }
+}
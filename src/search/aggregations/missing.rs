@@ -0,0 +1,67 @@
+//! The missing aggregation puts documents that don't have a value for a field into a bucket
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, Bucket, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+
+
+#[derive(Debug, PartialEq)]
+pub struct MissingAggregation {
+    pub field: FieldId,
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug)]
+pub struct MissingAggregator<'a> {
+    aggregation: &'a MissingAggregation,
+    bucket: BucketCollector<'a>,
+}
+
+
+impl<'a> MissingAggregator<'a> {
+    pub fn new(aggregation: &'a MissingAggregation, breaker: &'a CircuitBreaker) -> MissingAggregator<'a> {
+        MissingAggregator {
+            aggregation: aggregation,
+            bucket: BucketCollector::new(&aggregation.aggregations, breaker),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        if read_value(self.aggregation.field, doc_id).is_none() {
+            self.bucket.collect(doc_id, score, read_value);
+        }
+    }
+
+    pub fn into_result(self) -> Bucket {
+        self.bucket.into_bucket()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::breaker::CircuitBreaker;
+
+    use super::{MissingAggregation, MissingAggregator};
+
+    #[test]
+    fn test_missing_aggregator() {
+        let aggregation = MissingAggregation {
+            field: FieldId(1),
+            aggregations: vec![],
+        };
+
+        // Odd documents have a value
+        let breaker = CircuitBreaker::unlimited();
+        let mut aggregator = MissingAggregator::new(&aggregation, &breaker);
+        for doc_id in 0..5 {
+            aggregator.collect(doc_id, None, &mut |_, doc_id| if doc_id % 2 == 1 { Some(FieldValue::Integer(1)) } else { None });
+        }
+
+        assert_eq!(aggregator.into_result().doc_count, 3);
+    }
+}
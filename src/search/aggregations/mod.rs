@@ -0,0 +1,387 @@
+//! Aggregations summarise the documents that matched a search
+//!
+//! Aggregations are parsed in the query parser, which resolves field names, then run
+//! alongside the query by an `AggregationCollector`. Each aggregation is given the id,
+//! score and doc values of every document that matches, and produces an
+//! `AggregationResult` once the search has finished.
+//!
+//! Aggregations are nested by giving each bucket its own `Aggregators`, so any
+//! aggregation can be used beneath a bucket aggregation. The memory used by the buckets is
+//! counted by a `CircuitBreaker`, which stops the search if there are too many. Pipeline aggregations don't see
+//! documents, they run over the buckets of their parent aggregation once it's finished.
+
+pub mod metric;
+pub mod range;
+pub mod cardinality;
+pub mod median_absolute_deviation;
+pub mod weighted_avg;
+pub mod matrix_stats;
+pub mod filter;
+pub mod missing;
+pub mod global;
+pub mod significant_terms;
+pub mod composite;
+pub mod geo;
+pub mod pipeline;
+pub mod breaker;
+
+use std::mem;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::script::{Script, field_value_to_number};
+
+use self::metric::{MetricAggregation, MetricAggregator, MetricResult};
+use self::range::{RangeAggregation, RangeAggregator, RangeResult};
+use self::cardinality::{CardinalityAggregation, CardinalityAggregator};
+use self::median_absolute_deviation::{MedianAbsoluteDeviationAggregation, MedianAbsoluteDeviationAggregator};
+use self::weighted_avg::{WeightedAvgAggregation, WeightedAvgAggregator};
+use self::matrix_stats::{MatrixStatsAggregation, MatrixStatsAggregator, MatrixStats};
+use self::filter::{BucketFilter, FilterAggregation, FilterAggregator, FiltersAggregation, FiltersAggregator, FiltersResult};
+use self::filter::{AdjacencyMatrixAggregation, AdjacencyMatrixAggregator, AdjacencyMatrixResult};
+use self::missing::{MissingAggregation, MissingAggregator};
+use self::global::GlobalAggregation;
+use self::significant_terms::{Background, SignificantTermsAggregation, SignificantTermsAggregator, SignificantTermsResult};
+use self::composite::{CompositeAggregation, CompositeAggregator, CompositeResult};
+use self::geo::{GeoDistance, GeohashGridAggregation, GeohashGridAggregator, GeohashGridResult};
+use self::pipeline::PipelineAggregation;
+use self::breaker::CircuitBreaker;
+
+
+/// Where the values of an aggregation come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueSource {
+    /// The doc value of a field
+    Field(FieldId),
+
+    Script(Script),
+
+    /// The distance of a geo point field from an origin
+    GeoDistance(GeoDistance),
+}
+
+
+impl ValueSource {
+    pub fn read<F: FnMut(FieldId) -> Option<FieldValue>>(&self, score: Option<f32>, read_value: &mut F) -> Option<f64> {
+        match *self {
+            ValueSource::Field(field_id) => field_value_to_number(&read_value(field_id)?),
+            ValueSource::Script(ref script) => script.evaluate(score, read_value),
+            ValueSource::GeoDistance(ref distance) => distance.read(read_value),
+        }
+    }
+
+    pub fn has_value<F: FnMut(FieldId) -> Option<FieldValue>>(&self, score: Option<f32>, read_value: &mut F) -> bool {
+        match *self {
+            ValueSource::Field(field_id) => read_value(field_id).is_some(),
+            ValueSource::Script(ref script) => script.evaluate(score, read_value).is_some(),
+            ValueSource::GeoDistance(ref distance) => distance.read(read_value).is_some(),
+        }
+    }
+
+    pub fn needs_score(&self) -> bool {
+        match *self {
+            ValueSource::Field(_) | ValueSource::GeoDistance(_) => false,
+            ValueSource::Script(ref script) => script.needs_score(),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum Aggregation {
+    Metric(MetricAggregation),
+    Range(RangeAggregation),
+    Cardinality(CardinalityAggregation),
+    MedianAbsoluteDeviation(MedianAbsoluteDeviationAggregation),
+    WeightedAvg(WeightedAvgAggregation),
+    MatrixStats(MatrixStatsAggregation),
+    Filter(FilterAggregation),
+    Filters(FiltersAggregation),
+    AdjacencyMatrix(AdjacencyMatrixAggregation),
+    Missing(MissingAggregation),
+    Global(GlobalAggregation),
+    SignificantTerms(SignificantTermsAggregation),
+    Composite(CompositeAggregation),
+    GeohashGrid(GeohashGridAggregation),
+    Pipeline(PipelineAggregation),
+}
+
+
+impl Aggregation {
+    pub fn sub_aggregations(&self) -> &[(String, Aggregation)] {
+        match *self {
+            Aggregation::Metric(_) | Aggregation::Cardinality(_) | Aggregation::MedianAbsoluteDeviation(_) => &[],
+            Aggregation::WeightedAvg(_) | Aggregation::MatrixStats(_) => &[],
+            Aggregation::Pipeline(_) => &[],
+            Aggregation::Range(ref range) => &range.aggregations,
+            Aggregation::Filter(ref filter) => &filter.aggregations,
+            Aggregation::Filters(ref filters) => &filters.aggregations,
+            Aggregation::AdjacencyMatrix(ref adjacency_matrix) => &adjacency_matrix.aggregations,
+            Aggregation::Missing(ref missing) => &missing.aggregations,
+            Aggregation::Global(ref global) => &global.aggregations,
+            Aggregation::SignificantTerms(ref significant_terms) => &significant_terms.aggregations,
+            Aggregation::Composite(ref composite) => &composite.aggregations,
+            Aggregation::GeohashGrid(ref geohash_grid) => &geohash_grid.aggregations,
+        }
+    }
+
+    pub fn needs_score(&self) -> bool {
+        let needs_score = match *self {
+            Aggregation::Metric(ref metric) => metric.source.needs_score(),
+            Aggregation::Cardinality(ref cardinality) => cardinality.source.needs_score(),
+            Aggregation::MedianAbsoluteDeviation(ref median_absolute_deviation) => median_absolute_deviation.source.needs_score(),
+            Aggregation::WeightedAvg(ref weighted_avg) => weighted_avg.value.needs_score() || weighted_avg.weight.needs_score(),
+            Aggregation::MatrixStats(_) => false,
+            Aggregation::Range(ref range) => range.source.needs_score(),
+            Aggregation::Filter(_) | Aggregation::Filters(_) | Aggregation::AdjacencyMatrix(_) | Aggregation::Missing(_) => false,
+
+            // The documents aren't scored, as the global aggregation doesn't run alongside the query
+            Aggregation::Global(_) => return false,
+            Aggregation::SignificantTerms(_) | Aggregation::Composite(_) | Aggregation::GeohashGrid(_) => false,
+            Aggregation::Pipeline(_) => false,
+        };
+
+        needs_score || self.sub_aggregations().iter().any(|&(_, ref aggregation)| aggregation.needs_score())
+    }
+
+    /// Returns the filters of this aggregation and all of its sub-aggregations
+    ///
+    /// The documents that match each filter must be found before the aggregation runs.
+    pub fn filters_mut(&mut self) -> Vec<&mut BucketFilter> {
+        let (mut filters, sub_aggregations) = match *self {
+            Aggregation::Metric(_) | Aggregation::Cardinality(_) | Aggregation::MedianAbsoluteDeviation(_) => return Vec::new(),
+            Aggregation::WeightedAvg(_) | Aggregation::MatrixStats(_) => return Vec::new(),
+            Aggregation::Pipeline(_) => return Vec::new(),
+            Aggregation::Range(ref mut range) => (Vec::new(), &mut range.aggregations),
+            Aggregation::Filter(ref mut filter) => (vec![&mut filter.filter], &mut filter.aggregations),
+            Aggregation::Filters(ref mut filters) => {
+                (filters.filters.iter_mut().map(|&mut (_, ref mut filter)| filter).collect(), &mut filters.aggregations)
+            }
+            Aggregation::AdjacencyMatrix(ref mut adjacency_matrix) => {
+                (adjacency_matrix.filters.iter_mut().map(|&mut (_, ref mut filter)| filter).collect(), &mut adjacency_matrix.aggregations)
+            }
+            Aggregation::Missing(ref mut missing) => (Vec::new(), &mut missing.aggregations),
+            Aggregation::Global(ref mut global) => (Vec::new(), &mut global.aggregations),
+            Aggregation::SignificantTerms(ref mut significant_terms) => {
+                (significant_terms.background.filter.iter_mut().collect(), &mut significant_terms.aggregations)
+            }
+            Aggregation::Composite(ref mut composite) => (Vec::new(), &mut composite.aggregations),
+            Aggregation::GeohashGrid(ref mut geohash_grid) => (Vec::new(), &mut geohash_grid.aggregations),
+        };
+
+        for &mut (_, ref mut aggregation) in sub_aggregations.iter_mut() {
+            filters.extend(aggregation.filters_mut());
+        }
+
+        filters
+    }
+
+    /// Returns the background sets of the significant_terms aggregations in this aggregation and
+    /// all of its sub-aggregations, along with the field whose terms they count
+    ///
+    /// The terms in each background set must be counted before the aggregation runs.
+    pub fn backgrounds_mut(&mut self) -> Vec<(FieldId, &mut Background)> {
+        let (mut backgrounds, sub_aggregations) = match *self {
+            Aggregation::SignificantTerms(ref mut significant_terms) => {
+                (vec![(significant_terms.field, &mut significant_terms.background)], &mut significant_terms.aggregations)
+            }
+            Aggregation::Metric(_) | Aggregation::Cardinality(_) | Aggregation::MedianAbsoluteDeviation(_) => return Vec::new(),
+            Aggregation::WeightedAvg(_) | Aggregation::MatrixStats(_) => return Vec::new(),
+            Aggregation::Pipeline(_) => return Vec::new(),
+            Aggregation::Range(ref mut range) => (Vec::new(), &mut range.aggregations),
+            Aggregation::Filter(ref mut filter) => (Vec::new(), &mut filter.aggregations),
+            Aggregation::Filters(ref mut filters) => (Vec::new(), &mut filters.aggregations),
+            Aggregation::AdjacencyMatrix(ref mut adjacency_matrix) => (Vec::new(), &mut adjacency_matrix.aggregations),
+            Aggregation::Missing(ref mut missing) => (Vec::new(), &mut missing.aggregations),
+            Aggregation::Global(ref mut global) => (Vec::new(), &mut global.aggregations),
+            Aggregation::Composite(ref mut composite) => (Vec::new(), &mut composite.aggregations),
+            Aggregation::GeohashGrid(ref mut geohash_grid) => (Vec::new(), &mut geohash_grid.aggregations),
+        };
+
+        for &mut (_, ref mut aggregation) in sub_aggregations.iter_mut() {
+            backgrounds.extend(aggregation.backgrounds_mut());
+        }
+
+        backgrounds
+    }
+
+    /// Returns true if this aggregation puts documents into more than one bucket
+    ///
+    /// These are the aggregations that can have pipeline sub-aggregations.
+    pub fn is_multi_bucket(&self) -> bool {
+        match *self {
+            Aggregation::Range(_) | Aggregation::Filters(_) | Aggregation::AdjacencyMatrix(_) | Aggregation::SignificantTerms(_) => true,
+            Aggregation::Composite(_) | Aggregation::GeohashGrid(_) => true,
+            Aggregation::Metric(_) | Aggregation::Cardinality(_) | Aggregation::MedianAbsoluteDeviation(_) => false,
+            Aggregation::WeightedAvg(_) | Aggregation::MatrixStats(_) => false,
+            Aggregation::Filter(_) | Aggregation::Missing(_) | Aggregation::Global(_) => false,
+            Aggregation::Pipeline(_) => false,
+        }
+    }
+}
+
+
+/// A bucket's document count and the results of its sub-aggregations
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub doc_count: u64,
+    pub aggregations: Vec<(String, AggregationResult)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AggregationResult {
+    Metric(MetricResult),
+    Range(RangeResult),
+
+    /// The result of an aggregation that puts documents into one bucket
+    SingleBucket(Bucket),
+
+    Filters(FiltersResult),
+    AdjacencyMatrix(AdjacencyMatrixResult),
+    SignificantTerms(SignificantTermsResult),
+    Composite(CompositeResult),
+    GeohashGrid(GeohashGridResult),
+    MatrixStats(MatrixStats),
+}
+
+
+#[derive(Debug)]
+enum Aggregator<'a> {
+    Metric(MetricAggregator<'a>),
+    Range(RangeAggregator<'a>),
+    Cardinality(CardinalityAggregator<'a>),
+    MedianAbsoluteDeviation(MedianAbsoluteDeviationAggregator<'a>),
+    WeightedAvg(WeightedAvgAggregator<'a>),
+    MatrixStats(MatrixStatsAggregator<'a>),
+    Filter(FilterAggregator<'a>),
+    Filters(FiltersAggregator<'a>),
+    AdjacencyMatrix(AdjacencyMatrixAggregator<'a>),
+    Missing(MissingAggregator<'a>),
+    SignificantTerms(SignificantTermsAggregator<'a>),
+    Composite(CompositeAggregator<'a>),
+    GeohashGrid(GeohashGridAggregator<'a>),
+}
+
+
+impl<'a> Aggregator<'a> {
+    /// Returns None for pipeline aggregations, which are run by their parent aggregation, and
+    /// global aggregations, which are run separately on every document
+    fn new(aggregation: &'a Aggregation, breaker: &'a CircuitBreaker) -> Option<Aggregator<'a>> {
+        Some(match *aggregation {
+            Aggregation::Metric(ref metric) => Aggregator::Metric(MetricAggregator::new(metric)),
+            Aggregation::Range(ref range) => Aggregator::Range(RangeAggregator::new(range, breaker)),
+            Aggregation::Cardinality(ref cardinality) => Aggregator::Cardinality(CardinalityAggregator::new(cardinality)),
+            Aggregation::MedianAbsoluteDeviation(ref median_absolute_deviation) => {
+                Aggregator::MedianAbsoluteDeviation(MedianAbsoluteDeviationAggregator::new(median_absolute_deviation, breaker))
+            }
+            Aggregation::WeightedAvg(ref weighted_avg) => Aggregator::WeightedAvg(WeightedAvgAggregator::new(weighted_avg)),
+            Aggregation::MatrixStats(ref matrix_stats) => Aggregator::MatrixStats(MatrixStatsAggregator::new(matrix_stats)),
+            Aggregation::Filter(ref filter) => Aggregator::Filter(FilterAggregator::new(filter, breaker)),
+            Aggregation::Filters(ref filters) => Aggregator::Filters(FiltersAggregator::new(filters, breaker)),
+            Aggregation::AdjacencyMatrix(ref adjacency_matrix) => Aggregator::AdjacencyMatrix(AdjacencyMatrixAggregator::new(adjacency_matrix, breaker)),
+            Aggregation::Missing(ref missing) => Aggregator::Missing(MissingAggregator::new(missing, breaker)),
+            Aggregation::SignificantTerms(ref significant_terms) => Aggregator::SignificantTerms(SignificantTermsAggregator::new(significant_terms, breaker)),
+            Aggregation::Composite(ref composite) => Aggregator::Composite(CompositeAggregator::new(composite, breaker)),
+            Aggregation::GeohashGrid(ref geohash_grid) => Aggregator::GeohashGrid(GeohashGridAggregator::new(geohash_grid, breaker)),
+            Aggregation::Pipeline(_) | Aggregation::Global(_) => return None,
+        })
+    }
+
+    fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        match *self {
+            Aggregator::Metric(ref mut aggregator) => aggregator.collect(score, &mut |field_id| read_value(field_id, doc_id)),
+            Aggregator::Range(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::Cardinality(ref mut aggregator) => aggregator.collect(score, &mut |field_id| read_value(field_id, doc_id)),
+            Aggregator::MedianAbsoluteDeviation(ref mut aggregator) => aggregator.collect(score, &mut |field_id| read_value(field_id, doc_id)),
+            Aggregator::WeightedAvg(ref mut aggregator) => aggregator.collect(score, &mut |field_id| read_value(field_id, doc_id)),
+            Aggregator::MatrixStats(ref mut aggregator) => aggregator.collect(&mut |field_id| read_value(field_id, doc_id)),
+            Aggregator::Filter(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::Filters(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::AdjacencyMatrix(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::Missing(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::SignificantTerms(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::Composite(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+            Aggregator::GeohashGrid(ref mut aggregator) => aggregator.collect(doc_id, score, read_value),
+        }
+    }
+
+    fn into_result(self) -> AggregationResult {
+        match self {
+            Aggregator::Metric(aggregator) => AggregationResult::Metric(aggregator.into_result()),
+            Aggregator::Range(aggregator) => AggregationResult::Range(aggregator.into_result()),
+            Aggregator::Cardinality(aggregator) => AggregationResult::Metric(MetricResult::Value(Some(aggregator.into_result() as f64))),
+            Aggregator::MedianAbsoluteDeviation(aggregator) => AggregationResult::Metric(MetricResult::Value(aggregator.into_result())),
+            Aggregator::WeightedAvg(aggregator) => AggregationResult::Metric(MetricResult::Value(aggregator.into_result())),
+            Aggregator::MatrixStats(aggregator) => AggregationResult::MatrixStats(aggregator.into_result()),
+            Aggregator::Filter(aggregator) => AggregationResult::SingleBucket(aggregator.into_result()),
+            Aggregator::Filters(aggregator) => AggregationResult::Filters(aggregator.into_result()),
+            Aggregator::AdjacencyMatrix(aggregator) => AggregationResult::AdjacencyMatrix(aggregator.into_result()),
+            Aggregator::Missing(aggregator) => AggregationResult::SingleBucket(aggregator.into_result()),
+            Aggregator::SignificantTerms(aggregator) => AggregationResult::SignificantTerms(aggregator.into_result()),
+            Aggregator::Composite(aggregator) => AggregationResult::Composite(aggregator.into_result()),
+            Aggregator::GeohashGrid(aggregator) => AggregationResult::GeohashGrid(aggregator.into_result()),
+        }
+    }
+}
+
+
+/// Runs a list of named aggregations over the same documents
+#[derive(Debug)]
+pub struct Aggregators<'a> {
+    aggregators: Vec<(&'a str, Aggregator<'a>)>,
+}
+
+
+impl<'a> Aggregators<'a> {
+    pub fn new(aggregations: &'a [(String, Aggregation)], breaker: &'a CircuitBreaker) -> Aggregators<'a> {
+        breaker.add(aggregations.len() * mem::size_of::<(&str, Aggregator)>());
+
+        Aggregators {
+            aggregators: aggregations.iter().filter_map(|&(ref name, ref aggregation)| Some((name.as_ref(), Aggregator::new(aggregation, breaker)?))).collect(),
+        }
+    }
+
+    /// Adds a document to every aggregation. Doc values are read with `read_value`
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        for &mut (_, ref mut aggregator) in self.aggregators.iter_mut() {
+            aggregator.collect(doc_id, score, read_value);
+        }
+    }
+
+    pub fn into_results(self) -> Vec<(String, AggregationResult)> {
+        self.aggregators.into_iter().map(|(name, aggregator)| (name.to_string(), aggregator.into_result())).collect()
+    }
+}
+
+
+/// Counts the documents put into a bucket, running its sub-aggregations on them
+#[derive(Debug)]
+pub struct BucketCollector<'a> {
+    doc_count: u64,
+    aggregators: Aggregators<'a>,
+}
+
+
+impl<'a> BucketCollector<'a> {
+    pub fn new(aggregations: &'a [(String, Aggregation)], breaker: &'a CircuitBreaker) -> BucketCollector<'a> {
+        breaker.add(mem::size_of::<BucketCollector>());
+
+        BucketCollector {
+            doc_count: 0,
+            aggregators: Aggregators::new(aggregations, breaker),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        self.doc_count += 1;
+        self.aggregators.collect(doc_id, score, read_value);
+    }
+
+    pub fn into_bucket(self) -> Bucket {
+        Bucket {
+            doc_count: self.doc_count,
+            aggregations: self.aggregators.into_results(),
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Pipeline aggregations work on the output of other aggregations instead of documents
+//!
+//! They're given as sub-aggregations of a multi-bucket aggregation, and run once its buckets
+//! are finished. Each one reads a value from every bucket by a `buckets_path`, and either adds
+//! a new result to each bucket (derivative, cumulative_sum, moving_avg) or reorders and
+//! truncates the buckets (bucket_sort).
+
+use std::cmp::Ordering;
+
+use search::aggregations::{Aggregation, AggregationResult, Bucket};
+use search::aggregations::metric::MetricResult;
+use search::aggregations::range::RangeBucket;
+use search::aggregations::significant_terms::SignificantTermsBucket;
+use search::aggregations::composite::CompositeBucket;
+
+
+/// A multi-bucket aggregation's bucket, which pipelines can read from and add results to
+pub trait PipelineBucket {
+    fn doc_count(&self) -> u64;
+    fn aggregations(&self) -> &[(String, AggregationResult)];
+    fn aggregations_mut(&mut self) -> &mut Vec<(String, AggregationResult)>;
+}
+
+
+impl PipelineBucket for (String, Bucket) {
+    fn doc_count(&self) -> u64 {
+        self.1.doc_count
+    }
+
+    fn aggregations(&self) -> &[(String, AggregationResult)] {
+        &self.1.aggregations
+    }
+
+    fn aggregations_mut(&mut self) -> &mut Vec<(String, AggregationResult)> {
+        &mut self.1.aggregations
+    }
+}
+
+
+macro_rules! impl_pipeline_bucket {
+    ($bucket:ty) => {
+        impl PipelineBucket for $bucket {
+            fn doc_count(&self) -> u64 {
+                self.doc_count
+            }
+
+            fn aggregations(&self) -> &[(String, AggregationResult)] {
+                &self.aggregations
+            }
+
+            fn aggregations_mut(&mut self) -> &mut Vec<(String, AggregationResult)> {
+                &mut self.aggregations
+            }
+        }
+    }
+}
+
+
+impl_pipeline_bucket!(RangeBucket);
+impl_pipeline_bucket!(SignificantTermsBucket);
+impl_pipeline_bucket!(CompositeBucket);
+
+
+/// What to do with buckets that don't have a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapPolicy {
+    /// Leave the bucket out of the calculation
+    Skip,
+
+    /// Use zero as the value
+    InsertZeros,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovingAverageModel {
+    /// Every value in the window has the same weight
+    Simple,
+
+    /// Values are weighted by their position in the window, so the latest counts the most
+    Linear,
+
+    /// Weights decay exponentially with age. A higher alpha makes older values decay faster
+    Ewma {
+        alpha: f64,
+    },
+}
+
+
+impl MovingAverageModel {
+    fn average(&self, values: &[f64]) -> f64 {
+        match *self {
+            MovingAverageModel::Simple => values.iter().sum::<f64>() / values.len() as f64,
+            MovingAverageModel::Linear => {
+                let mut total = 0.0;
+                let mut total_weight = 0.0;
+
+                for (i, value) in values.iter().enumerate() {
+                    let weight = (i + 1) as f64;
+                    total += value * weight;
+                    total_weight += weight;
+                }
+
+                total / total_weight
+            }
+            MovingAverageModel::Ewma { alpha } => {
+                let mut average = values[0];
+
+                for value in values[1..].iter() {
+                    average = alpha * value + (1.0 - alpha) * average;
+                }
+
+                average
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineAggregation {
+    /// The change in a value from the previous bucket
+    Derivative {
+        buckets_path: String,
+        gap_policy: GapPolicy,
+    },
+
+    /// The total of a value in this and all of the previous buckets
+    CumulativeSum {
+        buckets_path: String,
+    },
+
+    /// The average of a value over the previous `window` buckets
+    MovingAvg {
+        buckets_path: String,
+        window: usize,
+        model: MovingAverageModel,
+        gap_policy: GapPolicy,
+    },
+
+    /// Sorts the buckets by the values at a list of paths, then keeps `size` of them after `from`
+    BucketSort {
+        /// The path of each value to sort by, and whether it's sorted in descending order
+        sort: Vec<(String, bool)>,
+        from: usize,
+        size: Option<usize>,
+    },
+}
+
+
+impl PipelineAggregation {
+    /// Returns the paths of the values this pipeline reads
+    pub fn buckets_paths(&self) -> Vec<&str> {
+        match *self {
+            PipelineAggregation::Derivative { ref buckets_path, .. } |
+            PipelineAggregation::CumulativeSum { ref buckets_path } |
+            PipelineAggregation::MovingAvg { ref buckets_path, .. } => vec![buckets_path],
+            PipelineAggregation::BucketSort { ref sort, .. } => sort.iter().map(|&(ref path, _)| path.as_ref()).collect(),
+        }
+    }
+}
+
+
+/// Reads the value of a metric from a list of results
+///
+/// Paths are the name of an aggregation, followed by the value to read from it if it has more
+/// than one (eg, "price_stats.avg"). Aggregations inside single-bucket aggregations are
+/// reached with ">" (eg, "sale>total"). "_count" is the document count.
+fn read_path(path: &str, doc_count: u64, aggregations: &[(String, AggregationResult)]) -> Option<f64> {
+    if path == "_count" {
+        return Some(doc_count as f64);
+    }
+
+    if let Some(separator) = path.find('>') {
+        let (name, rest) = (&path[..separator], &path[separator + 1..]);
+
+        return match aggregations.iter().find(|&&(ref result_name, _)| result_name == name) {
+            Some(&(_, AggregationResult::SingleBucket(ref bucket))) => read_path(rest, bucket.doc_count, &bucket.aggregations),
+            _ => None,
+        };
+    }
+
+    let mut parts = path.splitn(2, '.');
+    let name = parts.next().unwrap_or("");
+    let value_name = parts.next();
+
+    let result = aggregations.iter().find(|&&(ref result_name, _)| result_name == name).map(|&(_, ref result)| result)?;
+
+    match (result, value_name) {
+        (&AggregationResult::Metric(MetricResult::Value(value)), None) | (&AggregationResult::Metric(MetricResult::Value(value)), Some("value")) => value,
+        (&AggregationResult::Metric(MetricResult::Stats(ref stats)), Some(value_name)) => {
+            match value_name {
+                "count" => Some(stats.count as f64),
+                "sum" => Some(stats.sum),
+                "min" => stats.min,
+                "max" => stats.max,
+                "avg" => stats.avg(),
+                _ => None,
+            }
+        }
+        (&AggregationResult::Metric(MetricResult::ExtendedStats(ref extended_stats)), Some(value_name)) => {
+            match value_name {
+                "count" => Some(extended_stats.stats.count as f64),
+                "sum" => Some(extended_stats.stats.sum),
+                "min" => extended_stats.stats.min,
+                "max" => extended_stats.stats.max,
+                "avg" => extended_stats.stats.avg(),
+                "sum_of_squares" => Some(extended_stats.sum_of_squares),
+                "variance" | "variance_population" => extended_stats.variance_population(),
+                "variance_sampling" => extended_stats.variance_sampling(),
+                "std_deviation" | "std_deviation_population" => extended_stats.std_deviation_population(),
+                "std_deviation_sampling" => extended_stats.std_deviation_sampling(),
+                _ => None,
+            }
+        }
+        (&AggregationResult::SingleBucket(ref bucket), Some("_count")) => Some(bucket.doc_count as f64),
+        _ => None,
+    }
+}
+
+
+fn read_values<B: PipelineBucket>(path: &str, buckets: &[B]) -> Vec<Option<f64>> {
+    buckets.iter().map(|bucket| read_path(path, bucket.doc_count(), bucket.aggregations())).collect()
+}
+
+
+fn apply_gap_policy(value: Option<f64>, gap_policy: GapPolicy) -> Option<f64> {
+    match gap_policy {
+        GapPolicy::Skip => value.filter(|value| !value.is_nan()),
+        GapPolicy::InsertZeros => Some(value.filter(|value| !value.is_nan()).unwrap_or(0.0)),
+    }
+}
+
+
+/// Adds a value to each bucket. Buckets without a value are left alone
+fn add_values<B: PipelineBucket>(name: &str, values: Vec<Option<f64>>, buckets: &mut [B]) {
+    for (bucket, value) in buckets.iter_mut().zip(values.into_iter()) {
+        if let Some(value) = value {
+            bucket.aggregations_mut().push((name.to_string(), AggregationResult::Metric(MetricResult::Value(Some(value)))));
+        }
+    }
+}
+
+
+fn run_pipeline<B: PipelineBucket>(name: &str, pipeline: &PipelineAggregation, buckets: &mut Vec<B>) {
+    match *pipeline {
+        PipelineAggregation::Derivative { ref buckets_path, gap_policy } => {
+            let mut previous = None;
+            let values = read_values(buckets_path, buckets).into_iter().map(|value| {
+                let value = apply_gap_policy(value, gap_policy)?;
+                let derivative = previous.map(|previous| value - previous);
+                previous = Some(value);
+                derivative
+            }).collect();
+
+            add_values(name, values, buckets);
+        }
+        PipelineAggregation::CumulativeSum { ref buckets_path } => {
+            let mut total = 0.0;
+            let values = read_values(buckets_path, buckets).into_iter().map(|value| {
+                total += apply_gap_policy(value, GapPolicy::InsertZeros).unwrap_or(0.0);
+                Some(total)
+            }).collect();
+
+            add_values(name, values, buckets);
+        }
+        PipelineAggregation::MovingAvg { ref buckets_path, window, model, gap_policy } => {
+            let mut window_values: Vec<f64> = Vec::with_capacity(window + 1);
+            let values = read_values(buckets_path, buckets).into_iter().map(|value| {
+                // The bucket's own value isn't included in its average
+                let average = if window_values.is_empty() {
+                    None
+                } else {
+                    Some(model.average(&window_values))
+                };
+
+                if let Some(value) = apply_gap_policy(value, gap_policy) {
+                    window_values.push(value);
+                    if window_values.len() > window {
+                        window_values.remove(0);
+                    }
+                }
+
+                average
+            }).collect();
+
+            add_values(name, values, buckets);
+        }
+        PipelineAggregation::BucketSort { ref sort, from, size } => {
+            if !sort.is_empty() {
+                let mut keyed_buckets = buckets.drain(..).map(|bucket| {
+                    let keys = sort.iter().map(|&(ref path, _)| read_path(path, bucket.doc_count(), bucket.aggregations())).collect::<Vec<_>>();
+                    (keys, bucket)
+                }).collect::<Vec<_>>();
+
+                // Buckets without a value go last, whichever way they're sorted
+                keyed_buckets.sort_by(|&(ref keys_a, _), &(ref keys_b, _)| {
+                    for ((a, b), &(_, descending)) in keys_a.iter().zip(keys_b.iter()).zip(sort.iter()) {
+                        let ordering = match (*a, *b) {
+                            (Some(a), Some(b)) if descending => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+                            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                            (Some(_), None) => Ordering::Less,
+                            (None, Some(_)) => Ordering::Greater,
+                            (None, None) => Ordering::Equal,
+                        };
+
+                        if ordering != Ordering::Equal {
+                            return ordering;
+                        }
+                    }
+
+                    Ordering::Equal
+                });
+
+                buckets.extend(keyed_buckets.into_iter().map(|(_, bucket)| bucket));
+            }
+
+            let end = size.map_or(buckets.len(), |size| (from + size).min(buckets.len()));
+            let start = from.min(end);
+            buckets.truncate(end);
+            buckets.drain(..start);
+        }
+    }
+}
+
+
+/// Runs the pipelines in a list of sub-aggregations over a multi-bucket aggregation's buckets
+///
+/// Pipelines run in the order they're given, except that bucket_sort runs last so the
+/// others see every bucket.
+pub fn run_pipelines<B: PipelineBucket>(aggregations: &[(String, Aggregation)], buckets: &mut Vec<B>) {
+    let pipelines = aggregations.iter().filter_map(|&(ref name, ref aggregation)| {
+        match *aggregation {
+            Aggregation::Pipeline(ref pipeline) => Some((name, pipeline)),
+            _ => None,
+        }
+    });
+
+    let (sorts, others): (Vec<_>, Vec<_>) = pipelines.partition(|&(_, pipeline)| {
+        match *pipeline {
+            PipelineAggregation::BucketSort { .. } => true,
+            _ => false,
+        }
+    });
+
+    for (name, pipeline) in others.into_iter().chain(sorts.into_iter()) {
+        run_pipeline(name, pipeline, buckets);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::aggregations::{Aggregation, AggregationResult, Bucket};
+    use search::aggregations::metric::{MetricResult, Stats};
+
+    use super::{PipelineAggregation, GapPolicy, MovingAverageModel, run_pipelines, read_path};
+
+    fn value(value: Option<f64>) -> AggregationResult {
+        AggregationResult::Metric(MetricResult::Value(value))
+    }
+
+    /// Makes a bucket for each value, with the value in "sales"
+    fn make_buckets(values: &[Option<f64>]) -> Vec<(String, Bucket)> {
+        values.iter().enumerate().map(|(i, &sales)| {
+            (i.to_string(), Bucket {
+                doc_count: i as u64 + 1,
+                aggregations: vec![("sales".to_string(), value(sales))],
+            })
+        }).collect()
+    }
+
+    fn run(name: &str, pipeline: PipelineAggregation, buckets: &mut Vec<(String, Bucket)>) -> Vec<Option<f64>> {
+        run_pipelines(&[(name.to_string(), Aggregation::Pipeline(pipeline))], buckets);
+        buckets.iter().map(|bucket| read_path(name, 0, &bucket.1.aggregations)).collect()
+    }
+
+    #[test]
+    fn test_read_path() {
+        let aggregations = vec![
+            ("stats".to_string(), AggregationResult::Metric(MetricResult::Stats(Stats {
+                count: 2,
+                sum: 6.0,
+                min: Some(2.0),
+                max: Some(4.0),
+            }))),
+            ("sale".to_string(), AggregationResult::SingleBucket(Bucket {
+                doc_count: 3,
+                aggregations: vec![("total".to_string(), value(Some(5.0)))],
+            })),
+        ];
+
+        assert_eq!(read_path("_count", 7, &aggregations), Some(7.0));
+        assert_eq!(read_path("stats.avg", 7, &aggregations), Some(3.0));
+        assert_eq!(read_path("sale>total", 7, &aggregations), Some(5.0));
+        assert_eq!(read_path("sale>_count", 7, &aggregations), Some(3.0));
+        assert_eq!(read_path("missing", 7, &aggregations), None);
+    }
+
+    #[test]
+    fn test_derivative() {
+        let mut buckets = make_buckets(&[Some(1.0), Some(3.0), None, Some(10.0)]);
+        assert_eq!(run("deriv", PipelineAggregation::Derivative {
+            buckets_path: "sales".to_string(),
+            gap_policy: GapPolicy::Skip,
+        }, &mut buckets), vec![None, Some(2.0), None, Some(7.0)]);
+
+        let mut buckets = make_buckets(&[Some(1.0), Some(3.0), None, Some(10.0)]);
+        assert_eq!(run("deriv", PipelineAggregation::Derivative {
+            buckets_path: "sales".to_string(),
+            gap_policy: GapPolicy::InsertZeros,
+        }, &mut buckets), vec![None, Some(2.0), Some(-3.0), Some(10.0)]);
+    }
+
+    #[test]
+    fn test_cumulative_sum() {
+        let mut buckets = make_buckets(&[Some(1.0), None, Some(10.0)]);
+        assert_eq!(run("total", PipelineAggregation::CumulativeSum {
+            buckets_path: "sales".to_string(),
+        }, &mut buckets), vec![Some(1.0), Some(1.0), Some(11.0)]);
+    }
+
+    #[test]
+    fn test_moving_avg() {
+        let values = [Some(1.0), Some(2.0), Some(3.0), Some(6.0)];
+
+        let mut buckets = make_buckets(&values);
+        assert_eq!(run("avg", PipelineAggregation::MovingAvg {
+            buckets_path: "sales".to_string(),
+            window: 2,
+            model: MovingAverageModel::Simple,
+            gap_policy: GapPolicy::Skip,
+        }, &mut buckets), vec![None, Some(1.0), Some(1.5), Some(2.5)]);
+
+        let mut buckets = make_buckets(&values);
+        assert_eq!(run("avg", PipelineAggregation::MovingAvg {
+            buckets_path: "sales".to_string(),
+            window: 2,
+            model: MovingAverageModel::Linear,
+            gap_policy: GapPolicy::Skip,
+        }, &mut buckets), vec![None, Some(1.0), Some(5.0 / 3.0), Some(8.0 / 3.0)]);
+    }
+
+    #[test]
+    fn test_bucket_sort() {
+        let mut buckets = make_buckets(&[Some(1.0), None, Some(10.0), Some(5.0)]);
+        run_pipelines(&[("sort".to_string(), Aggregation::Pipeline(PipelineAggregation::BucketSort {
+            sort: vec![("sales".to_string(), true)],
+            from: 1,
+            size: Some(2),
+        }))], &mut buckets);
+
+        // Buckets without a value are sorted last
+        assert_eq!(buckets.iter().map(|&(ref key, _)| key.as_ref()).collect::<Vec<&str>>(), vec!["3", "0"]);
+    }
+}
@@ -0,0 +1,220 @@
+//! The range aggregation puts documents into buckets by which ranges their value falls in
+
+use chrono::{TimeZone, Utc};
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, AggregationResult, BucketCollector, ValueSource};
+use search::aggregations::breaker::CircuitBreaker;
+use search::aggregations::pipeline::run_pipelines;
+
+
+/// Formats a bucket bound. Dates are given as milliseconds since the epoch
+pub fn format_bound(value: f64, dates: bool) -> String {
+    if dates {
+        let millis = value as i64;
+        let date = Utc.timestamp(millis.div_euclid(1000), (millis.rem_euclid(1000) * 1000000) as u32);
+        date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub key: String,
+
+    /// Values must be greater than or equal to this
+    pub from: Option<f64>,
+
+    /// Values must be less than this
+    pub to: Option<f64>,
+}
+
+
+impl Range {
+    /// Creates a range with the default key, which is made from its bounds
+    pub fn new(from: Option<f64>, to: Option<f64>, dates: bool) -> Range {
+        let format = |bound: Option<f64>| bound.map_or("*".to_string(), |bound| format_bound(bound, dates));
+
+        Range {
+            key: format!("{}-{}", format(from), format(to)),
+            from: from,
+            to: to,
+        }
+    }
+
+    pub fn contains(&self, value: f64) -> bool {
+        self.from.map_or(true, |from| value >= from) && self.to.map_or(true, |to| value < to)
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct RangeAggregation {
+    pub source: ValueSource,
+
+    /// The value to use for documents that don't have one. These documents are skipped if not set
+    pub missing: Option<f64>,
+
+    /// Ranges can overlap, a document is put in every bucket its value falls in
+    pub ranges: Vec<Range>,
+
+    /// Set for "date_range". The bounds are also returned as dates
+    pub dates: bool,
+
+    /// Return the buckets in an object keyed by the key of each range, instead of an array
+    pub keyed: bool,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeBucket {
+    pub key: String,
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub doc_count: u64,
+    pub aggregations: Vec<(String, AggregationResult)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeResult {
+    pub buckets: Vec<RangeBucket>,
+    pub dates: bool,
+    pub keyed: bool,
+}
+
+
+#[derive(Debug)]
+pub struct RangeAggregator<'a> {
+    aggregation: &'a RangeAggregation,
+    buckets: Vec<BucketCollector<'a>>,
+}
+
+
+impl<'a> RangeAggregator<'a> {
+    pub fn new(aggregation: &'a RangeAggregation, breaker: &'a CircuitBreaker) -> RangeAggregator<'a> {
+        RangeAggregator {
+            aggregation: aggregation,
+            buckets: aggregation.ranges.iter().map(|_| BucketCollector::new(&aggregation.aggregations, breaker)).collect(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        let value = match self.aggregation.source.read(score, &mut |field_id| read_value(field_id, doc_id)).or(self.aggregation.missing) {
+            Some(value) => value,
+            None => return,
+        };
+
+        for (range, bucket) in self.aggregation.ranges.iter().zip(self.buckets.iter_mut()) {
+            if range.contains(value) {
+                bucket.collect(doc_id, score, read_value);
+            }
+        }
+    }
+
+    pub fn into_result(self) -> RangeResult {
+        let mut buckets = self.aggregation.ranges.iter().zip(self.buckets.into_iter()).map(|(range, bucket)| {
+            let bucket = bucket.into_bucket();
+
+            RangeBucket {
+                key: range.key.clone(),
+                from: range.from,
+                to: range.to,
+                doc_count: bucket.doc_count,
+                aggregations: bucket.aggregations,
+            }
+        }).collect();
+
+        run_pipelines(&self.aggregation.aggregations, &mut buckets);
+
+        RangeResult {
+            buckets: buckets,
+            dates: self.aggregation.dates,
+            keyed: self.aggregation.keyed,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::{Aggregation, AggregationResult, ValueSource};
+    use search::aggregations::breaker::CircuitBreaker;
+    use search::aggregations::metric::{Metric, MetricAggregation, MetricResult};
+
+    use super::{Range, RangeAggregation, RangeAggregator, RangeBucket, format_bound};
+
+    #[test]
+    fn test_range_keys() {
+        assert_eq!(Range::new(None, Some(100.0), false).key, "*-100.0");
+        assert_eq!(Range::new(Some(1.5), None, false).key, "1.5-*");
+        assert_eq!(Range::new(Some(1420070400000.0), None, true).key, "2015-01-01T00:00:00.000Z-*");
+        assert_eq!(format_bound(-1.0, true), "1969-12-31T23:59:59.999Z");
+    }
+
+    #[test]
+    fn test_range_aggregator() {
+        let aggregation = RangeAggregation {
+            source: ValueSource::Field(FieldId(1)),
+            missing: None,
+            ranges: vec![
+                Range::new(None, Some(10.0), false),
+                Range::new(Some(10.0), Some(20.0), false),
+                Range {
+                    key: "all".to_string(),
+                    from: None,
+                    to: None,
+                },
+            ],
+            dates: false,
+            keyed: false,
+            aggregations: vec![
+                ("total".to_string(), Aggregation::Metric(MetricAggregation {
+                    metric: Metric::Sum,
+                    source: ValueSource::Field(FieldId(1)),
+                    missing: None,
+                })),
+            ],
+        };
+
+        // The value of each document is its id
+        let breaker = CircuitBreaker::unlimited();
+        let mut aggregator = RangeAggregator::new(&aggregation, &breaker);
+        for doc_id in [5, 10, 15, 25].iter() {
+            aggregator.collect(*doc_id, None, &mut |_, doc_id| Some(FieldValue::Integer(doc_id as i64)));
+        }
+        aggregator.collect(30, None, &mut |_, _| None);
+
+        let total = |value| vec![("total".to_string(), AggregationResult::Metric(MetricResult::Value(Some(value))))];
+        assert_eq!(aggregator.into_result().buckets, vec![
+            RangeBucket {
+                key: "*-10.0".to_string(),
+                from: None,
+                to: Some(10.0),
+                doc_count: 1,
+                aggregations: total(5.0),
+            },
+            RangeBucket {
+                key: "10.0-20.0".to_string(),
+                from: Some(10.0),
+                to: Some(20.0),
+                doc_count: 2,
+                aggregations: total(25.0),
+            },
+            RangeBucket {
+                key: "all".to_string(),
+                from: None,
+                to: None,
+                doc_count: 4,
+                aggregations: total(55.0),
+            },
+        ]);
+    }
+}
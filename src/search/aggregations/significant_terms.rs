@@ -0,0 +1,337 @@
+//! The significant_terms aggregation finds terms that are unusually common in the matching documents
+//!
+//! Each term's frequency in the documents given to the aggregation (the foreground set) is
+//! compared with its frequency in the background set, which is either the whole index or
+//! the documents matching a background filter. The background counts must be found with
+//! `Background::count` before the search starts.
+
+use std::f64;
+use std::mem;
+use std::cmp::Ordering;
+
+use fnv::FnvHashMap;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, AggregationResult, BucketCollector};
+use search::aggregations::breaker::CircuitBreaker;
+use search::aggregations::pipeline::run_pipelines;
+use search::aggregations::filter::BucketFilter;
+
+
+/// How the significance of a term is scored
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignificanceHeuristic {
+    /// Multiplies the absolute and relative changes in the term's frequency
+    Jlh,
+
+    /// Pearson's chi-squared test
+    ChiSquare {
+        /// Also score terms that are less frequent in the foreground than the background
+        include_negatives: bool,
+
+        /// The foreground documents are part of the background set
+        background_is_superset: bool,
+    },
+}
+
+
+impl SignificanceHeuristic {
+    /// Scores a term that is in `subset_freq` of the `subset_size` foreground documents and
+    /// `superset_freq` of the `superset_size` background documents
+    pub fn score(&self, subset_freq: u64, subset_size: u64, superset_freq: u64, superset_size: u64) -> f64 {
+        if subset_size == 0 || superset_size == 0 {
+            return 0.0;
+        }
+
+        match *self {
+            SignificanceHeuristic::Jlh => {
+                // Terms that aren't in the background are treated as if they were in one document
+                let subset_probability = subset_freq as f64 / subset_size as f64;
+                let superset_probability = superset_freq.max(1) as f64 / superset_size as f64;
+
+                let absolute_change = subset_probability - superset_probability;
+                if absolute_change <= 0.0 {
+                    return 0.0;
+                }
+
+                absolute_change * (subset_probability / superset_probability)
+            }
+            SignificanceHeuristic::ChiSquare { include_negatives, background_is_superset } => {
+                let subset_freq = subset_freq as f64;
+                let subset_size = subset_size as f64;
+                let mut superset_freq = superset_freq as f64;
+                let mut superset_size = superset_size as f64;
+
+                // Remove the foreground from the background, so the two sets don't overlap
+                if background_is_superset {
+                    superset_freq = (superset_freq - subset_freq).max(0.0);
+                    superset_size = (superset_size - subset_size).max(0.0);
+                }
+
+                // The number of documents with (1) and without (0) the term, in the foreground
+                // (1) and background (0)
+                let n11 = subset_freq;
+                let n01 = subset_size - subset_freq;
+                let n10 = superset_freq;
+                let n00 = superset_size - superset_freq;
+
+                let n1_ = n11 + n10;
+                let n0_ = n01 + n00;
+                let n_1 = n11 + n01;
+                let n_0 = n10 + n00;
+                let n = n11 + n01 + n10 + n00;
+
+                if !include_negatives && n_0 > 0.0 && n11 / n_1 < n10 / n_0 {
+                    return f64::NEG_INFINITY;
+                }
+
+                let term = |observed: f64, row: f64, column: f64| {
+                    let expected = row * column / n;
+                    if expected == 0.0 {
+                        0.0
+                    } else {
+                        (observed - expected).powi(2) / expected
+                    }
+                };
+
+                term(n11, n1_, n_1) + term(n01, n0_, n_1) + term(n10, n1_, n_0) + term(n00, n0_, n_0)
+            }
+        }
+    }
+}
+
+
+/// The set of documents the foreground is compared with
+#[derive(Debug, Default, PartialEq)]
+pub struct Background {
+    /// Narrows the background set down to the documents matching a query
+    pub filter: Option<BucketFilter>,
+
+    /// The number of background documents that contain each term
+    pub term_doc_counts: FnvHashMap<Vec<u8>, u64>,
+
+    pub doc_count: u64,
+}
+
+
+impl Background {
+    /// Counts the terms of a field in the background set
+    ///
+    /// `all_doc_ids` are the ids of every document in the index. These are used as the
+    /// background set unless there's a filter.
+    pub fn count<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, field: FieldId, all_doc_ids: &[u64], read_value: &mut F) {
+        let mut term_doc_counts = FnvHashMap::default();
+
+        let doc_ids = match self.filter {
+            Some(ref filter) => &filter.matches,
+            None => all_doc_ids,
+        };
+
+        for &doc_id in doc_ids.iter() {
+            if let Some(value) = read_value(field, doc_id) {
+                *term_doc_counts.entry(value.to_bytes()).or_insert(0) += 1;
+            }
+        }
+
+        self.doc_count = doc_ids.len() as u64;
+        self.term_doc_counts = term_doc_counts;
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct SignificantTermsAggregation {
+    pub field: FieldId,
+
+    /// The maximum number of terms to return
+    pub size: usize,
+
+    /// Terms in fewer foreground documents than this aren't returned
+    pub min_doc_count: u64,
+
+    pub heuristic: SignificanceHeuristic,
+
+    pub background: Background,
+
+    pub aggregations: Vec<(String, Aggregation)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignificantTermsBucket {
+    pub key: FieldValue,
+    pub doc_count: u64,
+    pub bg_count: u64,
+    pub score: f64,
+    pub aggregations: Vec<(String, AggregationResult)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignificantTermsResult {
+    /// The number of foreground documents
+    pub doc_count: u64,
+
+    /// The number of background documents
+    pub bg_count: u64,
+
+    /// The most significant terms first
+    pub buckets: Vec<SignificantTermsBucket>,
+}
+
+
+#[derive(Debug)]
+pub struct SignificantTermsAggregator<'a> {
+    aggregation: &'a SignificantTermsAggregation,
+    breaker: &'a CircuitBreaker,
+    doc_count: u64,
+    terms: FnvHashMap<Vec<u8>, (FieldValue, BucketCollector<'a>)>,
+}
+
+
+impl<'a> SignificantTermsAggregator<'a> {
+    pub fn new(aggregation: &'a SignificantTermsAggregation, breaker: &'a CircuitBreaker) -> SignificantTermsAggregator<'a> {
+        SignificantTermsAggregator {
+            aggregation: aggregation,
+            breaker: breaker,
+            doc_count: 0,
+            terms: FnvHashMap::default(),
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId, u64) -> Option<FieldValue>>(&mut self, doc_id: u64, score: Option<f32>, read_value: &mut F) {
+        self.doc_count += 1;
+
+        let value = match read_value(self.aggregation.field, doc_id) {
+            Some(value) => value,
+            None => return,
+        };
+
+        let (aggregations, breaker) = (&self.aggregation.aggregations, self.breaker);
+        let &mut (_, ref mut bucket) = self.terms.entry(value.to_bytes()).or_insert_with_key(|term| {
+            // The term is kept twice, as the key of the map and the key of the bucket
+            breaker.add(term.len() * 2 + mem::size_of::<FieldValue>());
+            (value, BucketCollector::new(aggregations, breaker))
+        });
+        bucket.collect(doc_id, score, read_value);
+    }
+
+    pub fn into_result(self) -> SignificantTermsResult {
+        let aggregation = self.aggregation;
+        let doc_count = self.doc_count;
+
+        let mut buckets = self.terms.into_iter().filter_map(|(term, (key, bucket))| {
+            let bucket = bucket.into_bucket();
+            if bucket.doc_count < aggregation.min_doc_count {
+                return None;
+            }
+
+            let bg_count = aggregation.background.term_doc_counts.get(&term).cloned().unwrap_or(0);
+            let score = aggregation.heuristic.score(bucket.doc_count, doc_count, bg_count, aggregation.background.doc_count);
+
+            // Only terms that are more common than expected are significant
+            if score <= 0.0 || !score.is_finite() {
+                return None;
+            }
+
+            Some(SignificantTermsBucket {
+                key: key,
+                doc_count: bucket.doc_count,
+                bg_count: bg_count,
+                score: score,
+                aggregations: bucket.aggregations,
+            })
+        }).collect::<Vec<_>>();
+
+        // Most significant first, ties are broken by the key so the order is stable
+        buckets.sort_by(|a, b| {
+            match b.score.partial_cmp(&a.score) {
+                Some(Ordering::Equal) | None => a.key.to_bytes().cmp(&b.key.to_bytes()),
+                Some(ordering) => ordering,
+            }
+        });
+        buckets.truncate(aggregation.size);
+        run_pipelines(&aggregation.aggregations, &mut buckets);
+
+        SignificantTermsResult {
+            doc_count: doc_count,
+            bg_count: aggregation.background.doc_count,
+            buckets: buckets,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::breaker::CircuitBreaker;
+
+    use super::{SignificanceHeuristic, SignificantTermsAggregation, SignificantTermsAggregator, Background};
+
+    fn make_aggregation(heuristic: SignificanceHeuristic) -> SignificantTermsAggregation {
+        SignificantTermsAggregation {
+            field: FieldId(1),
+            size: 10,
+            min_doc_count: 1,
+            heuristic: heuristic,
+            background: Background::default(),
+            aggregations: vec![],
+        }
+    }
+
+    /// Documents 0-99 are tagged "common", except every tenth which is tagged "rare"
+    fn tag(doc_id: u64) -> FieldValue {
+        if doc_id % 10 == 0 {
+            FieldValue::String("rare".to_string())
+        } else {
+            FieldValue::String("common".to_string())
+        }
+    }
+
+    fn significant_terms(heuristic: SignificanceHeuristic) -> Vec<(FieldValue, u64, u64)> {
+        let mut aggregation = make_aggregation(heuristic);
+        let all_doc_ids = (0..100).collect::<Vec<u64>>();
+        aggregation.background.count(FieldId(1), &all_doc_ids, &mut |_, doc_id| Some(tag(doc_id)));
+        assert_eq!(aggregation.background.doc_count, 100);
+
+        // Half of the foreground is rare, compared to a tenth of the background
+        let breaker = CircuitBreaker::unlimited();
+        let mut aggregator = SignificantTermsAggregator::new(&aggregation, &breaker);
+        for doc_id in [0, 1, 10, 11, 20, 21].iter() {
+            aggregator.collect(*doc_id, None, &mut |_, doc_id| Some(tag(doc_id)));
+        }
+
+        let result = aggregator.into_result();
+        assert_eq!(result.doc_count, 6);
+        assert_eq!(result.bg_count, 100);
+        result.buckets.into_iter().map(|bucket| (bucket.key, bucket.doc_count, bucket.bg_count)).collect()
+    }
+
+    #[test]
+    fn test_jlh() {
+        assert_eq!(significant_terms(SignificanceHeuristic::Jlh), vec![(FieldValue::String("rare".to_string()), 3, 10)]);
+
+        // 50% of the foreground vs 10% of the background
+        let score = SignificanceHeuristic::Jlh.score(3, 6, 10, 100);
+        assert!((score - 2.0).abs() < 1e-9, "score was {}", score);
+    }
+
+    #[test]
+    fn test_chi_square() {
+        let heuristic = SignificanceHeuristic::ChiSquare {
+            include_negatives: false,
+            background_is_superset: true,
+        };
+        assert_eq!(significant_terms(heuristic), vec![(FieldValue::String("rare".to_string()), 3, 10)]);
+
+        // Negative changes are scored when asked for
+        let heuristic = SignificanceHeuristic::ChiSquare {
+            include_negatives: true,
+            background_is_superset: true,
+        };
+        assert_eq!(significant_terms(heuristic).len(), 2);
+    }
+}
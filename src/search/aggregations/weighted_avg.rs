@@ -0,0 +1,122 @@
+//! The weighted_avg aggregation averages values, giving each document a weight
+//!
+//! Each document gives a value and a weight, which can come from different fields or scripts.
+//! The result is the sum of each value multiplied by its weight, divided by the sum of the weights.
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::ValueSource;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedAvgAggregation {
+    pub value: ValueSource,
+
+    /// The value to use for documents that don't have one. These documents are skipped if not set
+    pub value_missing: Option<f64>,
+
+    pub weight: ValueSource,
+
+    /// The weight to use for documents that don't have one. These documents are skipped if not set
+    pub weight_missing: Option<f64>,
+}
+
+
+#[derive(Debug)]
+pub struct WeightedAvgAggregator<'a> {
+    aggregation: &'a WeightedAvgAggregation,
+    weighted_sum: f64,
+    weight_sum: f64,
+}
+
+
+impl<'a> WeightedAvgAggregator<'a> {
+    pub fn new(aggregation: &'a WeightedAvgAggregation) -> WeightedAvgAggregator<'a> {
+        WeightedAvgAggregator {
+            aggregation: aggregation,
+            weighted_sum: 0.0,
+            weight_sum: 0.0,
+        }
+    }
+
+    pub fn collect<F: FnMut(FieldId) -> Option<FieldValue>>(&mut self, score: Option<f32>, read_value: &mut F) {
+        let value = match self.aggregation.value.read(score, read_value).or(self.aggregation.value_missing) {
+            Some(value) => value,
+            None => return,
+        };
+
+        let weight = match self.aggregation.weight.read(score, read_value).or(self.aggregation.weight_missing) {
+            Some(weight) => weight,
+            None => return,
+        };
+
+        self.weighted_sum += value * weight;
+        self.weight_sum += weight;
+    }
+
+    /// Returns None if the weights add up to zero
+    pub fn into_result(self) -> Option<f64> {
+        if self.weight_sum != 0.0 {
+            Some(self.weighted_sum / self.weight_sum)
+        } else {
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::ValueSource;
+
+    use super::{WeightedAvgAggregation, WeightedAvgAggregator};
+
+    fn aggregate(aggregation: &WeightedAvgAggregation, docs: &[(Option<i64>, Option<i64>)]) -> Option<f64> {
+        let mut aggregator = WeightedAvgAggregator::new(aggregation);
+        for &(value, weight) in docs.iter() {
+            aggregator.collect(None, &mut |field_id| {
+                match field_id {
+                    FieldId(1) => value.map(FieldValue::Integer),
+                    _ => weight.map(FieldValue::Integer),
+                }
+            });
+        }
+
+        aggregator.into_result()
+    }
+
+    fn weighted_avg() -> WeightedAvgAggregation {
+        WeightedAvgAggregation {
+            value: ValueSource::Field(FieldId(1)),
+            value_missing: None,
+            weight: ValueSource::Field(FieldId(2)),
+            weight_missing: None,
+        }
+    }
+
+    #[test]
+    fn test_weighted_avg() {
+        // (2 * 1 + 4 * 3) / 4
+        assert_eq!(aggregate(&weighted_avg(), &[(Some(2), Some(1)), (Some(4), Some(3))]), Some(3.5));
+
+        // Documents without a value or a weight are skipped
+        assert_eq!(aggregate(&weighted_avg(), &[(Some(2), Some(1)), (None, Some(3)), (Some(4), None)]), Some(2.0));
+
+        assert_eq!(aggregate(&weighted_avg(), &[]), None);
+        assert_eq!(aggregate(&weighted_avg(), &[(Some(2), Some(0))]), None);
+    }
+
+    #[test]
+    fn test_missing() {
+        let aggregation = WeightedAvgAggregation {
+            value_missing: Some(10.0),
+            weight_missing: Some(2.0),
+            ..weighted_avg()
+        };
+
+        // (2 * 1 + 10 * 3 + 4 * 2) / 6
+        assert_eq!(aggregate(&aggregation, &[(Some(2), Some(1)), (None, Some(3)), (Some(4), None)]), Some(40.0 / 6.0));
+    }
+}
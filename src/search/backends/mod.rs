@@ -1 +1 @@
-pub mod rocksdb;
\ No newline at end of file
+pub mod rocksdb;
@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counters that keep track of one kind of operation on a store, such as indexing documents
+#[derive(Debug, Default)]
+pub struct OperationCounters {
+    current: AtomicUsize,
+    total: AtomicUsize,
+    failed: AtomicUsize,
+    time_in_millis: AtomicUsize,
+}
+
+/// A snapshot of a store's counters for one kind of operation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperationStatistics {
+    /// Number of operations currently running
+    pub current: usize,
+
+    /// Number of operations that have completed successfully
+    pub total: usize,
+
+    /// Number of operations that returned an error
+    pub failed: usize,
+
+    /// Time spent on completed operations, including failed ones
+    pub time_in_millis: usize,
+}
+
+impl OperationCounters {
+    /// Runs the operation, counting it as successful if it returns Ok
+    pub fn track<T, E, F: FnOnce() -> Result<T, E>>(&self, operation: F) -> Result<T, E> {
+        let started = Instant::now();
+        self.current.fetch_add(1, Ordering::SeqCst);
+
+        let result = operation();
+
+        self.current.fetch_sub(1, Ordering::SeqCst);
+
+        self.time_in_millis.fetch_add(duration_to_millis(started.elapsed()), Ordering::SeqCst);
+
+        if result.is_ok() {
+            self.total.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        result
+    }
+
+    pub fn statistics(&self) -> OperationStatistics {
+        OperationStatistics {
+            current: self.current.load(Ordering::SeqCst),
+            total: self.total.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+            time_in_millis: self.time_in_millis.load(Ordering::SeqCst),
+        }
+    }
+}
+
+fn duration_to_millis(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1000 + duration.subsec_nanos() as usize / 1000000
+}
+
+/// Counters for the bulk requests that have run actions on a store
+///
+/// The documents written by the actions are counted separately as index and delete operations.
+#[derive(Debug, Default)]
+pub struct BulkCounters {
+    total_operations: AtomicUsize,
+    total_time_in_millis: AtomicUsize,
+    total_size_in_bytes: AtomicUsize,
+}
+
+/// A snapshot of a store's bulk counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BulkStatistics {
+    /// Number of bulk requests that ran actions on the store
+    pub total_operations: usize,
+
+    /// Time spent running those actions
+    pub total_time_in_millis: usize,
+
+    /// Size of those actions in the request bodies, in bytes
+    pub total_size_in_bytes: usize,
+}
+
+impl BulkCounters {
+    /// Counts a bulk request, given the time spent on its actions for this store and their size
+    pub fn record(&self, time: Duration, size_in_bytes: usize) {
+        self.total_operations.fetch_add(1, Ordering::SeqCst);
+        self.total_time_in_millis.fetch_add(duration_to_millis(time), Ordering::SeqCst);
+        self.total_size_in_bytes.fetch_add(size_in_bytes, Ordering::SeqCst);
+    }
+
+    pub fn statistics(&self) -> BulkStatistics {
+        BulkStatistics {
+            total_operations: self.total_operations.load(Ordering::SeqCst),
+            total_time_in_millis: self.total_time_in_millis.load(Ordering::SeqCst),
+            total_size_in_bytes: self.total_size_in_bytes.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Counters for the documents written to, deleted from and searched in a store
+#[derive(Debug, Default)]
+pub struct ActivityCounters {
+    pub index: OperationCounters,
+    pub delete: OperationCounters,
+    pub query: OperationCounters,
+    pub bulk: BulkCounters,
+}
+
+/// A snapshot of a store's activity counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActivityStatistics {
+    pub index: OperationStatistics,
+    pub delete: OperationStatistics,
+    pub query: OperationStatistics,
+    pub bulk: BulkStatistics,
+}
+
+impl ActivityCounters {
+    pub fn statistics(&self) -> ActivityStatistics {
+        ActivityStatistics {
+            index: self.index.statistics(),
+            delete: self.delete.statistics(),
+            query: self.query.statistics(),
+            bulk: self.bulk.statistics(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{OperationCounters, BulkCounters};
+
+    #[test]
+    fn test_track() {
+        let counters = OperationCounters::default();
+
+        let result: Result<u32, ()> = counters.track(|| {
+            assert_eq!(counters.statistics().current, 1);
+            Ok(1)
+        });
+        assert_eq!(result, Ok(1));
+
+        let result: Result<u32, &str> = counters.track(|| Err("failed"));
+        assert_eq!(result, Err("failed"));
+
+        let stats = counters.statistics();
+        assert_eq!(stats.current, 0);
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.failed, 1);
+    }
+
+    #[test]
+    fn test_bulk() {
+        let counters = BulkCounters::default();
+        counters.record(Duration::from_millis(1500), 100);
+        counters.record(Duration::new(0, 999999), 20);
+
+        let stats = counters.statistics();
+        assert_eq!(stats.total_operations, 2);
+        assert_eq!(stats.total_time_in_millis, 1500);
+        assert_eq!(stats.total_size_in_bytes, 120);
+    }
+}
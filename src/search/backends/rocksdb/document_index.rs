@@ -1,6 +1,11 @@
-use std::sync::RwLock;
+use std::sync::{RwLock, RwLockWriteGuard, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::fmt;
+use std::mem;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rocksdb::{self, DB, WriteBatch};
 use roaring::RoaringBitmap;
@@ -12,9 +17,209 @@ use fnv::FnvHashMap;
 use super::key_builder::KeyBuilder;
 use super::segment_ops::SegmentMergeError;
 
+/// Primary terms aren't tracked, so every write has the same one. Replicas are given the
+/// primary's versions instead (see `RocksDBStore::replicate_document`)
+pub const PRIMARY_TERM: u64 = 1;
+
+/// Identifies a write to a document
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DocumentVersion {
+    /// Counts the writes to the document, starting at 1 when it's created
+    pub version: u64,
+
+    /// Position of the write among all writes to the store
+    pub seq_no: u64,
+
+    pub primary_term: u64,
+}
+
+/// The result of writing a document
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DocumentWrite {
+    pub version: DocumentVersion,
+
+    /// Set if there wasn't a document with the key before the write
+    pub created: bool,
+}
+
+/// A precondition on the current version of a document, for optimistic concurrency control
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteCondition {
+    /// The document must exist and be at this version
+    Version(u64),
+
+    /// The document's last write must have this seq_no and primary term
+    SeqNo {
+        seq_no: u64,
+        primary_term: u64,
+    },
+
+    /// The document mustn't exist yet
+    NotExists,
+
+    /// The document mustn't exist, or must be at an older version than this. Replicas use
+    /// this to skip writes from the primary that they already have a newer version of,
+    /// including ones older than a deletion (see `DocumentIndexWriter::check_condition`)
+    OlderThan(u64),
+}
+
+impl WriteCondition {
+    /// Checks the condition against the current version of a document (None if it doesn't exist)
+    pub fn check(&self, current: Option<DocumentVersion>) -> Result<(), VersionConflict> {
+        let matches = match (*self, current) {
+            (WriteCondition::NotExists, current) => current.is_none(),
+            (WriteCondition::OlderThan(version), current) => current.map_or(true, |current| current.version < version),
+            (WriteCondition::Version(version), Some(current)) => current.version == version,
+            (WriteCondition::SeqNo { seq_no, primary_term }, Some(current)) => current.seq_no == seq_no && current.primary_term == primary_term,
+            (_, None) => false,
+        };
+
+        if matches {
+            Ok(())
+        } else {
+            Err(VersionConflict {
+                condition: *self,
+                current: current,
+            })
+        }
+    }
+}
+
+/// Returned when a write's condition doesn't match the current version of the document
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionConflict {
+    pub condition: WriteCondition,
+    pub current: Option<DocumentVersion>,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.condition, self.current) {
+            (WriteCondition::NotExists, _) => write!(f, "version conflict, document already exists"),
+            (WriteCondition::OlderThan(version), current) => {
+                write!(f, "version conflict, current version [{}] is not older than the one provided [{}]", current.map_or(0, |current| current.version), version)
+            }
+            (WriteCondition::Version(version), Some(current)) => {
+                write!(f, "version conflict, current version [{}] is different than the one provided [{}]", current.version, version)
+            }
+            (WriteCondition::SeqNo { seq_no, primary_term }, Some(current)) => {
+                write!(f, "version conflict, required seqNo [{}], primary term [{}]. current document has seqNo [{}] and primary term [{}]", seq_no, primary_term, current.seq_no, current.primary_term)
+            }
+            (_, None) => write!(f, "version conflict, document does not exist"),
+        }
+    }
+}
+
+/// What the primary key index stores for each key
+#[derive(Debug, Clone, Copy)]
+struct PrimaryKeyEntry {
+    doc_id: DocId,
+    version: u64,
+    seq_no: u64,
+}
+
+impl PrimaryKeyEntry {
+    fn document_version(&self) -> DocumentVersion {
+        DocumentVersion {
+            version: self.version,
+            seq_no: self.seq_no,
+            primary_term: PRIMARY_TERM,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 22] {
+        let mut bytes = [0; 22];
+        LittleEndian::write_u32(&mut bytes, (self.doc_id.0).0);
+        LittleEndian::write_u16(&mut bytes[4..], self.doc_id.1);
+        LittleEndian::write_u64(&mut bytes[6..], self.version);
+        LittleEndian::write_u64(&mut bytes[14..], self.seq_no);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> PrimaryKeyEntry {
+        let segment = LittleEndian::read_u32(&bytes[0..4]);
+        let ord = LittleEndian::read_u16(&bytes[4..6]);
+
+        // Keys written before documents were versioned only have the document id
+        let (version, seq_no) = if bytes.len() >= 22 {
+            (LittleEndian::read_u64(&bytes[6..14]), LittleEndian::read_u64(&bytes[14..22]))
+        } else {
+            (1, 0)
+        };
+
+        PrimaryKeyEntry {
+            doc_id: DocId(SegmentId(segment), ord),
+            version: version,
+            seq_no: seq_no,
+        }
+    }
+}
+
+/// What's kept of a key after its document is deleted
+///
+/// This lets the version carry on from the deletion if the key is indexed again, and lets
+/// replicas skip writes that were made before the deletion. Tombstones are removed once
+/// they're older than the store's `gc_deletes` interval.
+#[derive(Debug, Clone, Copy)]
+struct Tombstone {
+    version: u64,
+    seq_no: u64,
+
+    /// Milliseconds since the epoch when the document was deleted
+    deleted_at: u64,
+}
+
+impl Tombstone {
+    fn document_version(&self) -> DocumentVersion {
+        DocumentVersion {
+            version: self.version,
+            seq_no: self.seq_no,
+            primary_term: PRIMARY_TERM,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 24] {
+        let mut bytes = [0; 24];
+        LittleEndian::write_u64(&mut bytes, self.version);
+        LittleEndian::write_u64(&mut bytes[8..], self.seq_no);
+        LittleEndian::write_u64(&mut bytes[16..], self.deleted_at);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Tombstone {
+        Tombstone {
+            version: LittleEndian::read_u64(&bytes[0..8]),
+            seq_no: LittleEndian::read_u64(&bytes[8..16]),
+            deleted_at: LittleEndian::read_u64(&bytes[16..24]),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0)
+}
+
+/// Reads the document id and version from a value in the primary key index
+pub fn parse_primary_key_value(value: &[u8]) -> (DocId, DocumentVersion) {
+    let entry = PrimaryKeyEntry::from_bytes(value);
+    (entry.doc_id, entry.document_version())
+}
+
 /// Manages the index's "document index"
 pub struct DocumentIndexManager {
-    primary_key_index: RwLock<HashMap<Vec<u8>, DocId>>,
+    primary_key_index: RwLock<HashMap<Vec<u8>, PrimaryKeyEntry>>,
+
+    /// Deletions that haven't been applied to the deletion lists yet
+    /// Only modified while primary_key_index is locked for writing
+    pending_deletions: Mutex<Vec<DocId>>,
+
+    /// Versions of deleted keys, until they're garbage collected
+    /// Only modified while primary_key_index is locked for writing
+    tombstones: Mutex<HashMap<Vec<u8>, Tombstone>>,
+
+    /// The seq_no to give the next write
+    /// Only modified while primary_key_index is locked for writing
+    next_seq_no: AtomicU64,
 }
 
 impl DocumentIndexManager {
@@ -22,6 +227,9 @@ impl DocumentIndexManager {
     pub fn new(_db: &DB) -> Result<DocumentIndexManager, rocksdb::Error> {
         Ok(DocumentIndexManager {
             primary_key_index: RwLock::new(HashMap::new()),
+            pending_deletions: Mutex::new(Vec::new()),
+            tombstones: Mutex::new(HashMap::new()),
+            next_seq_no: AtomicU64::new(0),
         })
     }
 
@@ -38,18 +246,58 @@ impl DocumentIndexManager {
                 break;
             }
 
-            let v = iter.value().unwrap();
-            let segment = LittleEndian::read_u32(&v[0..4]);
-            let ord = LittleEndian::read_u16(&v[4..6]);
-            let doc_id = DocId(SegmentId(segment), ord);
+            let entry = PrimaryKeyEntry::from_bytes(&iter.value().unwrap());
+            primary_key_index.insert(k[1..].to_vec(), entry);
+
+            iter.next();
+        }
+
+        // Read deletions that were waiting for a refresh
+        let mut pending_deletions = Vec::new();
+        let mut iter = db.raw_iterator();
+        iter.seek(b"p");
+        while iter.valid() {
+            let k = iter.key().unwrap();
+
+            if k[0] != b'p' {
+                break;
+            }
+
+            let mut parts_iter = k[1..].split(|b| *b == b'/').map(|s| str::from_utf8(s).unwrap());
+            let segment = parts_iter.next().unwrap().parse::<u32>().unwrap();
+            let ord = parts_iter.next().unwrap().parse::<u16>().unwrap();
+            pending_deletions.push(DocId(SegmentId(segment), ord));
+
+            iter.next();
+        }
+
+        // Read tombstones of deleted keys
+        let mut tombstones = HashMap::new();
+        let mut iter = db.raw_iterator();
+        iter.seek(b"r");
+        while iter.valid() {
+            let k = iter.key().unwrap();
+
+            if k[0] != b'r' {
+                break;
+            }
 
-            primary_key_index.insert(k[1..].to_vec(), doc_id);
+            tombstones.insert(k[1..].to_vec(), Tombstone::from_bytes(&iter.value().unwrap()));
 
             iter.next();
         }
 
+        // Stores created before documents were versioned don't have a seq_no counter
+        let next_seq_no = match try!(db.get(b".next_seq_no")) {
+            Some(next_seq_no) => next_seq_no.to_utf8().unwrap().parse::<u64>().unwrap(),
+            None => primary_key_index.values().map(|entry| entry.seq_no + 1).max().unwrap_or(0),
+        };
+
         Ok(DocumentIndexManager {
             primary_key_index: RwLock::new(primary_key_index),
+            pending_deletions: Mutex::new(pending_deletions),
+            tombstones: Mutex::new(tombstones),
+            next_seq_no: AtomicU64::new(next_seq_no),
         })
     }
 
@@ -68,45 +316,86 @@ impl DocumentIndexManager {
         Ok(())
     }
 
-    pub fn insert_or_replace_key(&self, db: &DB, key: &Vec<u8>, doc_id: DocId) -> Result<Option<DocId>, rocksdb::Error> {
-        // Update primary_key_index
-        let mut write_batch = WriteBatch::default();
-        let previous_doc_id = self.primary_key_index.write().unwrap().insert(key.clone(), doc_id);
+    /// Queues a document to be deleted on the next call to `commit_pending_deletions`
+    fn defer_deletion(&self, write_batch: &mut WriteBatch, doc_id: DocId) -> Result<(), rocksdb::Error> {
+        // Record the deletion on disk as well, so it isn't lost if we restart before the next refresh
+        let kb = KeyBuilder::pending_deletion((doc_id.0).0, doc_id.1);
+        try!(write_batch.put(&kb.key(), b""));
 
-        let kb = KeyBuilder::primary_key_index(key);
-        let mut doc_id_bytes = [0; 6];
-        LittleEndian::write_u32(&mut doc_id_bytes, (doc_id.0).0);
-        LittleEndian::write_u16(&mut doc_id_bytes[4..], doc_id.1);
-        try!(write_batch.put(&kb.key(), &doc_id_bytes));
+        self.pending_deletions.lock().unwrap().push(doc_id);
 
-        // If there was a document there previously, delete it
-        if let Some(previous_doc_id) = previous_doc_id {
-            try!(self.delete_document_by_id_unchecked(&mut write_batch, previous_doc_id));
+        Ok(())
+    }
+
+    /// Locks the primary key index so keys can be inserted, replaced or deleted
+    ///
+    /// Other writes and merges wait until the returned writer is dropped, so the current
+    /// version of a key can be checked and the key updated without anything else changing
+    /// it in between.
+    pub fn writer<'a>(&'a self) -> DocumentIndexWriter<'a> {
+        DocumentIndexWriter {
+            manager: self,
+            primary_key_index: self.primary_key_index.write().unwrap(),
         }
+    }
 
-        // Write document data
-        try!(db.write(write_batch));
+    /// Applies any deferred deletions to the deletion lists
+    ///
+    /// The deletions are written along with the given write batch
+    pub fn commit_pending_deletions(&self, db: &DB, mut write_batch: WriteBatch) -> Result<(), rocksdb::Error> {
+        // Lock the primary key index
+        // This prevents a merge from moving the documents while we are deleting them
+        let _primary_key_index = self.primary_key_index.write().unwrap();
+
+        let pending_deletions = mem::replace(&mut *self.pending_deletions.lock().unwrap(), Vec::new());
+        for doc_id in pending_deletions {
+            try!(self.delete_document_by_id_unchecked(&mut write_batch, doc_id));
 
-        Ok(previous_doc_id)
+            let kb = KeyBuilder::pending_deletion((doc_id.0).0, doc_id.1);
+            try!(write_batch.delete(&kb.key()));
+        }
+
+        db.write(write_batch)
     }
 
-    pub fn delete_document_by_key(&self, db: &DB, key: &Vec<u8>) -> Result<Option<DocId>, rocksdb::Error> {
-        // Remove document from index
-        let doc_id = self.primary_key_index.write().unwrap().remove(key);
+    /// Removes the tombstones of keys that were deleted longer ago than `max_age`
+    ///
+    /// Once its tombstone is removed, a key that's indexed again starts back at version 1.
+    pub fn gc_tombstones(&self, db: &DB, max_age: Duration) -> Result<(), rocksdb::Error> {
+        let _primary_key_index = self.primary_key_index.write().unwrap();
+        let mut tombstones = self.tombstones.lock().unwrap();
+
+        let max_age = max_age.as_secs() * 1000 + max_age.subsec_nanos() as u64 / 1_000_000;
+        let cutoff = now_millis().saturating_sub(max_age);
+        let expired = tombstones.iter().filter(|&(_, tombstone)| tombstone.deleted_at <= cutoff).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(());
+        }
 
-        if let Some(doc_id) = doc_id {
-            let mut write_batch = WriteBatch::default();
+        let mut write_batch = WriteBatch::default();
+        for key in expired.iter() {
+            let kb = KeyBuilder::tombstone(key);
+            try!(write_batch.delete(&kb.key()));
+        }
 
-            try!(self.delete_document_by_id_unchecked(&mut write_batch, doc_id));
+        try!(db.write(write_batch));
 
-            try!(db.write(write_batch));
+        for key in expired.iter() {
+            tombstones.remove(key);
         }
 
-        Ok(doc_id)
+        Ok(())
     }
 
-    pub fn contains_document_key(&self, key: &Vec<u8>) -> bool {
-        self.primary_key_index.read().unwrap().contains_key(key)
+    /// Estimates the memory used by the document index, in bytes
+    pub fn memory_usage(&self) -> usize {
+        let primary_key_index = self.primary_key_index.read().unwrap();
+        let entry_size = mem::size_of::<Vec<u8>>() + mem::size_of::<PrimaryKeyEntry>();
+        let tombstone_size = mem::size_of::<Vec<u8>>() + mem::size_of::<Tombstone>();
+        let pending_deletions = self.pending_deletions.lock().unwrap().len() * mem::size_of::<DocId>();
+        let tombstones = self.tombstones.lock().unwrap().keys().map(|key| tombstone_size + key.len()).sum::<usize>();
+
+        primary_key_index.keys().map(|key| entry_size + key.len()).sum::<usize>() + pending_deletions + tombstones
     }
 
     pub fn commit_segment_merge(&self, db: &DB, mut write_batch: WriteBatch, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u16>) -> Result<(), SegmentMergeError> {
@@ -114,24 +403,35 @@ impl DocumentIndexManager {
         let mut primary_key_index = self.primary_key_index.write().unwrap();
 
         // Update primary keys to point to their new locations
-        let mut keys_to_update: HashMap<Vec<u8>, DocId> = HashMap::with_capacity(doc_id_mapping.len());
-        for (key, doc_id) in primary_key_index.iter() {
-            if doc_id_mapping.contains_key(&doc_id) {
-                keys_to_update.insert(key.clone(), *doc_id);
+        // Moving a document doesn't change it, so the versions are kept
+        let mut keys_to_update: HashMap<Vec<u8>, PrimaryKeyEntry> = HashMap::with_capacity(doc_id_mapping.len());
+        for (key, entry) in primary_key_index.iter() {
+            if doc_id_mapping.contains_key(&entry.doc_id) {
+                keys_to_update.insert(key.clone(), *entry);
             }
         }
 
-        for (key, doc_id) in keys_to_update {
-            let new_doc_local_id = doc_id_mapping.get(&doc_id).unwrap();
-            let new_doc_id = DocId(SegmentId(dest_segment), *new_doc_local_id);
+        for (key, mut entry) in keys_to_update {
+            let new_doc_local_id = doc_id_mapping.get(&entry.doc_id).unwrap();
+            entry.doc_id = DocId(SegmentId(dest_segment), *new_doc_local_id);
 
             let kb = KeyBuilder::primary_key_index(&key);
-            let mut doc_id_bytes = [0; 6];
-            LittleEndian::write_u32(&mut doc_id_bytes, (new_doc_id.0).0);
-            LittleEndian::write_u16(&mut doc_id_bytes[4..], new_doc_id.1);
-            try!(write_batch.put(&kb.key(), &doc_id_bytes));
+            try!(write_batch.put(&kb.key(), &entry.to_bytes()));
+
+            primary_key_index.insert(key, entry);
+        }
+
+        // Move any deferred deletions of documents in the source segments to the new segment
+        for doc_id in self.pending_deletions.lock().unwrap().iter_mut() {
+            if let Some(new_doc_local_id) = doc_id_mapping.get(doc_id) {
+                let kb = KeyBuilder::pending_deletion((doc_id.0).0, doc_id.1);
+                try!(write_batch.delete(&kb.key()));
+
+                *doc_id = DocId(SegmentId(dest_segment), *new_doc_local_id);
 
-            primary_key_index.insert(key, new_doc_id);
+                let kb = KeyBuilder::pending_deletion(dest_segment, *new_doc_local_id);
+                try!(write_batch.put(&kb.key(), b""));
+            }
         }
 
         // Merge deletion lists
@@ -158,9 +458,139 @@ impl DocumentIndexManager {
         let kb = KeyBuilder::segment_del_list(dest_segment);
         try!(db.put(&kb.key(), &dl_vec));
 
-        // Commit!
-        try!(db.write_without_wal(write_batch));
+        // Commit! This goes through the write-ahead log, so the merge isn't lost if the
+        // process crashes before the write buffers are flushed
+        try!(db.write(write_batch));
 
         Ok(())
     }
 }
+
+/// Inserts, replaces and deletes keys while the primary key index is locked
+///
+/// See `DocumentIndexManager::writer`
+pub struct DocumentIndexWriter<'a> {
+    manager: &'a DocumentIndexManager,
+    primary_key_index: RwLockWriteGuard<'a, HashMap<Vec<u8>, PrimaryKeyEntry>>,
+}
+
+impl<'a> DocumentIndexWriter<'a> {
+    /// Returns the current version of the document with the key, if there is one
+    pub fn current_version(&self, key: &Vec<u8>) -> Option<DocumentVersion> {
+        self.primary_key_index.get(key).map(|entry| entry.document_version())
+    }
+
+    /// Returns the version of the key's deletion, if it was deleted and the tombstone
+    /// hasn't been garbage collected yet
+    pub fn deleted_version(&self, key: &Vec<u8>) -> Option<DocumentVersion> {
+        self.manager.tombstones.lock().unwrap().get(key).map(|tombstone| tombstone.document_version())
+    }
+
+    /// Checks a write's condition against the current version of the key
+    ///
+    /// `OlderThan` is also checked against the version of the key's deletion, so a replica
+    /// doesn't bring a deleted document back with a write that was made before it was deleted.
+    pub fn check_condition(&self, key: &Vec<u8>, condition: &WriteCondition) -> Result<(), VersionConflict> {
+        let current = match *condition {
+            WriteCondition::OlderThan(_) => self.current_version(key).or_else(|| self.deleted_version(key)),
+            _ => self.current_version(key),
+        };
+
+        condition.check(current)
+    }
+
+    /// Allocates a seq_no for a write, recording the new counter in the write batch
+    fn next_seq_no(&self, write_batch: &mut WriteBatch) -> Result<u64, rocksdb::Error> {
+        let seq_no = self.manager.next_seq_no.fetch_add(1, Ordering::SeqCst);
+        try!(write_batch.put(b".next_seq_no", (seq_no + 1).to_string().as_bytes()));
+        Ok(seq_no)
+    }
+
+    /// Points the key at a new document, deleting the document that was there previously
+    ///
+    /// If `defer_deletion` is set, the previous document is only deleted on the next call
+    /// to `commit_pending_deletions`. The document is given the next version, carrying on
+    /// from the key's deletion if it was deleted, unless `version` is set.
+    pub fn insert_or_replace_key(&mut self, db: &DB, key: &Vec<u8>, doc_id: DocId, version: Option<u64>, defer_deletion: bool) -> Result<DocumentWrite, rocksdb::Error> {
+        let mut write_batch = WriteBatch::default();
+        let previous_entry = self.primary_key_index.get(key).cloned();
+        let tombstone = self.manager.tombstones.lock().unwrap().get(key).cloned();
+        let previous_version = previous_entry.map(|entry| entry.version).or(tombstone.map(|tombstone| tombstone.version));
+
+        let entry = PrimaryKeyEntry {
+            doc_id: doc_id,
+            version: version.unwrap_or_else(|| previous_version.map(|version| version + 1).unwrap_or(1)),
+            seq_no: try!(self.next_seq_no(&mut write_batch)),
+        };
+
+        let kb = KeyBuilder::primary_key_index(key);
+        try!(write_batch.put(&kb.key(), &entry.to_bytes()));
+
+        if tombstone.is_some() {
+            let kb = KeyBuilder::tombstone(key);
+            try!(write_batch.delete(&kb.key()));
+        }
+
+        // If there was a document there previously, delete it
+        if let Some(previous_entry) = previous_entry {
+            if defer_deletion {
+                try!(self.manager.defer_deletion(&mut write_batch, previous_entry.doc_id));
+            } else {
+                try!(self.manager.delete_document_by_id_unchecked(&mut write_batch, previous_entry.doc_id));
+            }
+        }
+
+        // Write document data
+        try!(db.write(write_batch));
+
+        if tombstone.is_some() {
+            self.manager.tombstones.lock().unwrap().remove(key);
+        }
+
+        self.primary_key_index.insert(key.clone(), entry);
+        Ok(DocumentWrite {
+            version: entry.document_version(),
+            created: previous_entry.is_none(),
+        })
+    }
+
+    /// Removes the key and deletes the document it points to
+    ///
+    /// If `defer_deletion` is set, the document is only deleted on the next call to
+    /// `commit_pending_deletions`. The deletion is given the next version, unless `version`
+    /// is set. Returns its version, or None if the key doesn't exist.
+    pub fn delete_key(&mut self, db: &DB, key: &Vec<u8>, version: Option<u64>, defer_deletion: bool) -> Result<Option<DocumentVersion>, rocksdb::Error> {
+        let entry = match self.primary_key_index.get(key).cloned() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let mut write_batch = WriteBatch::default();
+
+        let kb = KeyBuilder::primary_key_index(key);
+        try!(write_batch.delete(&kb.key()));
+
+        if defer_deletion {
+            try!(self.manager.defer_deletion(&mut write_batch, entry.doc_id));
+        } else {
+            try!(self.manager.delete_document_by_id_unchecked(&mut write_batch, entry.doc_id));
+        }
+
+        // Deletions are writes too, so they get the next version and a seq_no. These are
+        // kept in a tombstone so the version carries on if the key is indexed again
+        let tombstone = Tombstone {
+            version: version.unwrap_or(entry.version + 1),
+            seq_no: try!(self.next_seq_no(&mut write_batch)),
+            deleted_at: now_millis(),
+        };
+
+        let kb = KeyBuilder::tombstone(key);
+        try!(write_batch.put(&kb.key(), &tombstone.to_bytes()));
+
+        try!(db.write(write_batch));
+
+        self.primary_key_index.remove(key);
+        self.manager.tombstones.lock().unwrap().insert(key.clone(), tombstone);
+        Ok(Some(tombstone.document_version()))
+    }
+}
@@ -0,0 +1,250 @@
+use std::sync::Mutex;
+use std::collections::{HashMap, BTreeMap};
+
+use roaring::RoaringBitmap;
+
+/// Identifies a cached bitset: the segment it was found in, and the query that found it
+pub type FilterCacheKey = (u32, String);
+
+/// Caches the documents in each segment that match frequently used filters
+///
+/// Postings lists are never modified after a segment is written, so a filter always
+/// matches the same documents in a segment. Deleted documents are removed after the
+/// bitset is read from the cache, so deletions don't invalidate it. Entries are removed
+/// when their segment is purged, or when the cache is full and they're the least
+/// recently used.
+pub struct FilterCache {
+    state: Mutex<FilterCacheState>,
+}
+
+struct FilterCacheEntry {
+    bitmap: RoaringBitmap,
+    memory: usize,
+    last_used: u64,
+}
+
+struct FilterCacheState {
+    entries: HashMap<FilterCacheKey, FilterCacheEntry>,
+
+    /// Entry keys ordered by when they were last used, oldest first
+    lru: BTreeMap<u64, FilterCacheKey>,
+    clock: u64,
+    memory: usize,
+    max_memory: usize,
+    counters: FilterCacheStatistics,
+}
+
+
+impl FilterCacheState {
+    /// Evicts least recently used entries until there's space for `memory` more bytes
+    fn make_space(&mut self, memory: usize) {
+        while self.memory + memory > self.max_memory {
+            let oldest = match self.lru.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            let oldest_key = self.lru.remove(&oldest).unwrap();
+            let evicted = self.entries.remove(&oldest_key).unwrap();
+            self.memory -= evicted.memory;
+            self.counters.evictions += 1;
+        }
+    }
+}
+
+/// A snapshot of a filter cache's counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterCacheStatistics {
+    /// Number of lookups that found a cached bitset
+    pub hit_count: usize,
+
+    /// Number of lookups that had to run the filter
+    pub miss_count: usize,
+
+    /// Number of bitsets that have been added to the cache
+    pub cache_count: usize,
+
+    /// Number of bitsets currently in the cache
+    pub cache_size: usize,
+
+    /// Number of bitsets removed to make space for new ones
+    pub evictions: usize,
+
+    /// Approximate memory used by the cached bitsets, in bytes
+    pub memory_size_in_bytes: usize,
+}
+
+impl FilterCache {
+    pub fn new(max_memory: usize) -> FilterCache {
+        FilterCache {
+            state: Mutex::new(FilterCacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                memory: 0,
+                max_memory: max_memory,
+                counters: FilterCacheStatistics::default(),
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &FilterCacheKey) -> Option<RoaringBitmap> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let last_used = match state.entries.get_mut(key) {
+            Some(entry) => {
+                let last_used = entry.last_used;
+                entry.last_used = clock;
+                Some((last_used, entry.bitmap.clone()))
+            }
+            None => None,
+        };
+
+        match last_used {
+            Some((last_used, bitmap)) => {
+                state.lru.remove(&last_used);
+                state.lru.insert(clock, key.clone());
+                state.counters.hit_count += 1;
+                Some(bitmap)
+            }
+            None => {
+                state.counters.miss_count += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: FilterCacheKey, bitmap: RoaringBitmap) {
+        let memory = bitmap.serialized_size() + key.1.len();
+
+        let mut state = self.state.lock().unwrap();
+        if memory > state.max_memory {
+            return;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some(previous) = state.entries.remove(&key) {
+            state.lru.remove(&previous.last_used);
+            state.memory -= previous.memory;
+        }
+
+        state.make_space(memory);
+
+        state.lru.insert(clock, key.clone());
+        state.entries.insert(key, FilterCacheEntry {
+            bitmap: bitmap,
+            memory: memory,
+            last_used: clock,
+        });
+        state.memory += memory;
+        state.counters.cache_count += 1;
+    }
+
+    /// Changes how much memory the cache can use, evicting entries if it's now over
+    pub fn set_max_memory(&self, max_memory: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_memory = max_memory;
+        state.make_space(0);
+    }
+
+    /// Removes all entries for the given segments. Called when the segments are purged
+    pub fn remove_segments(&self, segments: &[u32]) {
+        let mut state = self.state.lock().unwrap();
+
+        let removed = state.entries.keys().filter(|key| segments.contains(&key.0)).cloned().collect::<Vec<_>>();
+        for key in removed {
+            let entry = state.entries.remove(&key).unwrap();
+            state.lru.remove(&entry.last_used);
+            state.memory -= entry.memory;
+        }
+    }
+
+    pub fn statistics(&self) -> FilterCacheStatistics {
+        let state = self.state.lock().unwrap();
+
+        FilterCacheStatistics {
+            cache_size: state.entries.len(),
+            memory_size_in_bytes: state.memory,
+            ..state.counters.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use roaring::RoaringBitmap;
+
+    use super::FilterCache;
+
+    fn make_bitmap(docs: &[u32]) -> RoaringBitmap {
+        let mut bitmap = RoaringBitmap::new();
+        for doc in docs {
+            bitmap.insert(*doc);
+        }
+        bitmap
+    }
+
+    #[test]
+    fn test_get_and_insert() {
+        let cache = FilterCache::new(1024 * 1024);
+        let key = (1, "filter".to_string());
+
+        assert_eq!(cache.get(&key), None);
+        cache.insert(key.clone(), make_bitmap(&[1, 2, 3]));
+        assert_eq!(cache.get(&key), Some(make_bitmap(&[1, 2, 3])));
+
+        let stats = cache.statistics();
+        assert_eq!(stats.hit_count, 1);
+        assert_eq!(stats.miss_count, 1);
+        assert_eq!(stats.cache_size, 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let entry_size = make_bitmap(&[1]).serialized_size() + 1;
+        let cache = FilterCache::new(entry_size * 2);
+
+        cache.insert((1, "a".to_string()), make_bitmap(&[1]));
+        cache.insert((1, "b".to_string()), make_bitmap(&[1]));
+
+        // Use "a" so "b" is evicted instead
+        assert!(cache.get(&(1, "a".to_string())).is_some());
+        cache.insert((1, "c".to_string()), make_bitmap(&[1]));
+
+        assert!(cache.get(&(1, "a".to_string())).is_some());
+        assert!(cache.get(&(1, "b".to_string())).is_none());
+        assert!(cache.get(&(1, "c".to_string())).is_some());
+        assert_eq!(cache.statistics().evictions, 1);
+    }
+
+    #[test]
+    fn test_remove_segments() {
+        let cache = FilterCache::new(1024 * 1024);
+
+        cache.insert((1, "a".to_string()), make_bitmap(&[1]));
+        cache.insert((2, "a".to_string()), make_bitmap(&[1]));
+        cache.remove_segments(&[1]);
+
+        assert!(cache.get(&(1, "a".to_string())).is_none());
+        assert!(cache.get(&(2, "a".to_string())).is_some());
+        assert_eq!(cache.statistics().memory_size_in_bytes, make_bitmap(&[1]).serialized_size() + 1);
+    }
+
+    #[test]
+    fn test_set_max_memory() {
+        let entry_size = make_bitmap(&[1]).serialized_size() + 1;
+        let cache = FilterCache::new(entry_size * 2);
+
+        cache.insert((1, "a".to_string()), make_bitmap(&[1]));
+        cache.insert((1, "b".to_string()), make_bitmap(&[1]));
+
+        // Shrinking the cache evicts the least recently used entry
+        cache.set_max_memory(entry_size);
+        assert!(cache.get(&(1, "a".to_string())).is_none());
+        assert!(cache.get(&(1, "b".to_string())).is_some());
+        assert_eq!(cache.statistics().evictions, 1);
+    }
+}
@@ -43,6 +43,13 @@ impl KeyBuilder {
         kb
     }
 
+    pub fn tombstone(key: &[u8]) -> KeyBuilder {
+        let mut kb = KeyBuilder::with_capacity(1 + key.len());
+        kb.push_char(b'r');
+        kb.push_string(key);
+        kb
+    }
+
     pub fn term_dict_mapping(term: &[u8]) -> KeyBuilder {
         let mut kb = KeyBuilder::with_capacity(1 + term.len());
         kb.push_char(b't');
@@ -57,6 +64,22 @@ impl KeyBuilder {
         kb
     }
 
+    pub fn segment_pending(segment: u32) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'n');
+        kb.push_string(segment.to_string().as_bytes());
+        kb
+    }
+
+    pub fn pending_deletion(segment: u32, doc_local_id: u16) -> KeyBuilder {
+        let mut kb = KeyBuilder::new();
+        kb.push_char(b'p');
+        kb.push_string(segment.to_string().as_bytes());
+        kb.separator();
+        kb.push_string(doc_local_id.to_string().as_bytes());
+        kb
+    }
+
     pub fn segment_postings_list(segment: u32, field_id: u32, term_id: u32) -> KeyBuilder {
         let mut kb = KeyBuilder::new();
         kb.push_char(b'd');
@@ -7,33 +7,75 @@ mod segment_builder;
 mod term_dictionary;
 mod document_index;
 mod search;
+mod reader_tracker;
+mod filter_cache;
+mod request_cache;
+mod activity_counters;
 
 use std::str;
 use std::fmt;
 use std::path::Path;
-use std::sync::Arc;
-
-use rocksdb::{self, DB, WriteBatch, Options, MergeOperands, Snapshot};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::Cursor;
+use std::mem;
+use std::ops::Deref;
+use std::thread;
+use std::time::Duration;
+
+use rocksdb::{self, DB, WriteBatch, WriteOptions, Options, MergeOperands, Snapshot};
 use search::{Document, DocId, TermId};
 use search::document::FieldValue;
+use search::geo::GeoPoint;
 use search::schema::{Schema, FieldType, FieldFlags, FieldId, AddFieldError};
 use search::segment::SegmentId;
+use thread_pool::ThreadPool;
 use byteorder::{ByteOrder, LittleEndian};
 use chrono::{NaiveDateTime, DateTime, Utc};
 use fnv::FnvHashMap;
+use roaring::RoaringBitmap;
 use serde_json;
 
 use self::key_builder::KeyBuilder;
 use self::segment_manager::SegmentManager;
 use self::term_dictionary::TermDictionaryManager;
-use self::document_index::DocumentIndexManager;
+use self::document_index::{DocumentIndexManager, parse_primary_key_value};
+use self::reader_tracker::ReaderTracker;
+use self::segment_ops::MergeCounters;
+use self::filter_cache::FilterCache;
+use self::request_cache::RequestCache;
+use self::activity_counters::ActivityCounters;
+
+pub use self::segment_ops::MergeStatistics;
+pub use self::segment_stats::{SegmentStatistics, StoreStatistics};
+pub use self::filter_cache::FilterCacheStatistics;
+pub use self::request_cache::RequestCacheStatistics;
+pub use self::activity_counters::{ActivityStatistics, OperationStatistics, BulkStatistics};
+pub use self::document_index::{DocumentVersion, DocumentWrite, WriteCondition, VersionConflict};
 
 fn merge_keys(key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
     match key[0] {
-        b'd' | b'x' => {
+        b'x' => {
+            // Deletion list
+            // The value is a serialised roaring bitmap and each operand is a two byte document id
+            let mut deletion_list = match existing_val {
+                Some(existing_val) => RoaringBitmap::deserialize_from(Cursor::new(existing_val)).unwrap(),
+                None => RoaringBitmap::new(),
+            };
+
+            for op in operands {
+                for doc_id in op.chunks(2) {
+                    deletion_list.insert(LittleEndian::read_u16(doc_id) as u32);
+                }
+            }
+
+            let mut new_val = Vec::new();
+            deletion_list.serialize_into(&mut new_val).unwrap();
+            Some(new_val)
+        }
+        b'd' => {
             // Sequence of two byte document ids
             // d = postings list
-            // x = deletion list
 
             // Allocate vec for new Value
             let new_size = match existing_val {
@@ -89,6 +131,12 @@ pub enum DocumentInsertError {
 
     /// The segment is full
     SegmentFull,
+
+    /// Writes to the store are blocked (see `RocksDBStore::set_write_blocks`)
+    WriteBlocked,
+
+    /// The document's current version doesn't match the write's condition
+    VersionConflict(VersionConflict),
 }
 
 impl From<rocksdb::Error> for DocumentInsertError {
@@ -97,6 +145,12 @@ impl From<rocksdb::Error> for DocumentInsertError {
     }
 }
 
+impl From<VersionConflict> for DocumentInsertError {
+    fn from(e: VersionConflict) -> DocumentInsertError {
+        DocumentInsertError::VersionConflict(e)
+    }
+}
+
 impl From<segment_builder::DocumentInsertError> for DocumentInsertError {
     fn from(e: segment_builder::DocumentInsertError) -> DocumentInsertError {
         match e {
@@ -105,18 +159,203 @@ impl From<segment_builder::DocumentInsertError> for DocumentInsertError {
     }
 }
 
-pub struct RocksDBStore {
+#[derive(Debug)]
+pub enum DocumentDeleteError {
+    /// A RocksDB error occurred
+    RocksDBError(rocksdb::Error),
+
+    /// Deletes from the store are blocked (see `RocksDBStore::set_write_blocks`)
+    DeleteBlocked,
+
+    /// The document's current version doesn't match the delete's condition
+    VersionConflict(VersionConflict),
+}
+
+impl From<rocksdb::Error> for DocumentDeleteError {
+    fn from(e: rocksdb::Error) -> DocumentDeleteError {
+        DocumentDeleteError::RocksDBError(e)
+    }
+}
+
+impl From<VersionConflict> for DocumentDeleteError {
+    fn from(e: VersionConflict) -> DocumentDeleteError {
+        DocumentDeleteError::VersionConflict(e)
+    }
+}
+
+/// The stages of opening a store, reported by `RocksDBStore::open_with_progress`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreOpenStage {
+    /// Opening the database. RocksDB replays its write-ahead log at this point
+    ReplayingLog,
+
+    LoadingSegments,
+    LoadingTermDictionary,
+    LoadingDocumentIndex,
+
+    /// Activating segments and deletions that were waiting for a refresh
+    PublishingPendingChanges,
+}
+
+/// Default memory used by the in-memory write buffers
+const DEFAULT_INDEX_BUFFER_SIZE: usize = 128 * 1024 * 1024;
+
+/// Default maximum memory used by the filter cache
+const DEFAULT_FILTER_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Maximum number of counts kept in the request cache
+const REQUEST_CACHE_SIZE: usize = 1000;
+
+/// How long the versions of deleted documents are kept for, unless the store is told otherwise
+const DEFAULT_GC_DELETES: Duration = Duration::from_secs(60);
+
+/// Records when the store was last flushed
+const LAST_FLUSH_KEY: &'static [u8] = b".last_flush";
+
+/// Maximum number of write buffers kept in memory at once
+///
+/// One is written to while the other is being flushed to disk
+const MAX_WRITE_BUFFER_NUMBER: i32 = 2;
+
+/// Options that the store is opened with
+///
+/// These must be decided before the store is opened as RocksDB only reads them once.
+#[derive(Debug, Clone)]
+pub struct StoreOptions {
+    /// Memory used by the in-memory write buffers, in bytes
+    ///
+    /// This is split between two write buffers. Once one fills up, it's flushed to disk in
+    /// the background while the other is written to, so this caps the memory used by
+    /// indexing. If both fill up, writes are stalled until a flush completes.
+    pub index_buffer_size: usize,
+
+    /// Maximum number of threads used to search the segments of the store in parallel,
+    /// including the thread that made the request
+    ///
+    /// Defaults to the number of CPUs. Set to 1 to search segments one at a time on the
+    /// thread that made the request.
+    pub search_threads: usize,
+
+    /// Pool that segments are searched on alongside the thread that made the request
+    ///
+    /// This is the node's search pool, so parallel searches can't start more threads than
+    /// it has. Without a pool, segments are searched one at a time.
+    pub search_pool: Option<Arc<ThreadPool>>,
+
+    /// Maximum memory used to cache the results of filters, in bytes
+    pub filter_cache_size: usize,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        StoreOptions {
+            index_buffer_size: DEFAULT_INDEX_BUFFER_SIZE,
+            search_threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            search_pool: None,
+            filter_cache_size: DEFAULT_FILTER_CACHE_SIZE,
+        }
+    }
+}
+
+impl StoreOptions {
+    fn rocksdb_options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.set_merge_operator("merge operator", merge_keys, None);
+        opts.set_write_buffer_size(self.index_buffer_size / MAX_WRITE_BUFFER_NUMBER as usize);
+        opts.set_max_write_buffer_number(MAX_WRITE_BUFFER_NUMBER);
+
+        opts
+    }
+}
+
+/// A RocksDB snapshot that can be shared by readers and kept open between requests
+///
+/// It holds on to the database, so the snapshot can't outlive it.
+struct StoreSnapshot {
+    // Declared before `db` so the snapshot is released before the database can be closed
+    snapshot: Snapshot<'static>,
+    db: Arc<DB>,
+}
+
+impl StoreSnapshot {
+    fn new(db: &Arc<DB>) -> StoreSnapshot {
+        // SAFETY: the snapshot borrows the database, which is kept alive by the `Arc` in
+        // `db` until after the snapshot is dropped. The database is on the heap, so moving
+        // the store or this struct doesn't move it.
+        let snapshot = unsafe { mem::transmute::<Snapshot, Snapshot<'static>>(db.snapshot()) };
+
+        StoreSnapshot {
+            snapshot: snapshot,
+            db: db.clone(),
+        }
+    }
+}
+
+impl Deref for StoreSnapshot {
+    type Target = Snapshot<'static>;
+
+    fn deref(&self) -> &Snapshot<'static> {
+        &self.snapshot
+    }
+}
+
+// SAFETY: `Snapshot` is only `!Send` and `!Sync` because it holds a raw pointer to the
+// RocksDB snapshot. RocksDB snapshots are immutable, and can be read from and released on
+// any thread. rust-rocksdb makes new read options for every read through a `&Snapshot`, so
+// concurrent reads share nothing but the snapshot itself.
+unsafe impl Send for StoreSnapshot {}
+unsafe impl Sync for StoreSnapshot {}
+
+/// A reader's view of the store that's been kept by `RocksDBReader::pin_segments`
+struct PinnedReader {
     schema: Arc<Schema>,
-    db: DB,
+    snapshot: Arc<StoreSnapshot>,
+    generation: u64,
+
+    /// The store's refresh generation when the reader was opened, if results it reads
+    /// can be cached (see `RequestCache`)
+    refresh_generation: Option<u64>,
+}
+
+#[derive(Default)]
+struct PinnedReaders {
+    next_pin: u64,
+    readers: FnvHashMap<u64, PinnedReader>,
+}
+
+pub struct RocksDBStore {
+    /// Replaced rather than changed, so readers can keep the schema they started with
+    schema: RwLock<Arc<Schema>>,
+    db: Arc<DB>,
     term_dictionary: TermDictionaryManager,
     segments: SegmentManager,
     document_index: DocumentIndexManager,
+    readers: ReaderTracker,
+    pinned_readers: Mutex<PinnedReaders>,
+    deferred_refresh: AtomicBool,
+    pending_segments: Mutex<Vec<u32>>,
+    merge_counters: MergeCounters,
+    activity: ActivityCounters,
+    inserts_blocked: AtomicBool,
+    deletes_blocked: AtomicBool,
+    search_threads: usize,
+    search_pool: Option<Arc<ThreadPool>>,
+    filter_cache: FilterCache,
+    request_cache: RequestCache,
+
+    /// Counts the refreshes of the store, so results can be cached until the next one.
+    /// Locked for writing while changes are published, see `RocksDBStore::refresh`
+    refresh_generation: RwLock<u64>,
+    gc_deletes: Mutex<Duration>,
 }
 
 impl RocksDBStore {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, String> {
-        let mut opts = Options::default();
-        opts.set_merge_operator("merge operator", merge_keys, None);
+        RocksDBStore::create_with_options(path, &StoreOptions::default())
+    }
+
+    pub fn create_with_options<P: AsRef<Path>>(path: P, options: &StoreOptions) -> Result<RocksDBStore, String> {
+        let mut opts = options.rocksdb_options();
         opts.create_if_missing(true);
         let db = try!(DB::open(&opts, path));
 
@@ -138,17 +377,40 @@ impl RocksDBStore {
         let document_index = try!(DocumentIndexManager::new(&db));
 
         Ok(RocksDBStore {
-            schema: Arc::new(schema),
-            db: db,
+            schema: RwLock::new(Arc::new(schema)),
+            db: Arc::new(db),
             term_dictionary: term_dictionary,
             segments: segments,
             document_index: document_index,
+            readers: ReaderTracker::new(),
+            pinned_readers: Mutex::new(PinnedReaders::default()),
+            deferred_refresh: AtomicBool::new(false),
+            pending_segments: Mutex::new(Vec::new()),
+            merge_counters: MergeCounters::default(),
+            activity: ActivityCounters::default(),
+            inserts_blocked: AtomicBool::new(false),
+            deletes_blocked: AtomicBool::new(false),
+            search_threads: options.search_threads,
+            search_pool: options.search_pool.clone(),
+            filter_cache: FilterCache::new(options.filter_cache_size),
+            request_cache: RequestCache::new(REQUEST_CACHE_SIZE),
+            refresh_generation: RwLock::new(0),
+            gc_deletes: Mutex::new(DEFAULT_GC_DELETES),
         })
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksDBStore, String> {
-        let mut opts = Options::default();
-        opts.set_merge_operator("merge operator", merge_keys, None);
+        RocksDBStore::open_with_options(path, &StoreOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &StoreOptions) -> Result<RocksDBStore, String> {
+        RocksDBStore::open_with_progress(path, options, &|_| {})
+    }
+
+    /// Opens the store, calling `progress` as each stage of loading it begins
+    pub fn open_with_progress<P: AsRef<Path>>(path: P, options: &StoreOptions, progress: &Fn(StoreOpenStage)) -> Result<RocksDBStore, String> {
+        let opts = options.rocksdb_options();
+        progress(StoreOpenStage::ReplayingLog);
         let db = try!(DB::open(&opts, path));
 
         let schema = match try!(db.get(b".schema")) {
@@ -163,68 +425,230 @@ impl RocksDBStore {
         };
 
         // Segment manager
+        progress(StoreOpenStage::LoadingSegments);
         let segments = try!(SegmentManager::open(&db));
 
         // Term dictionary manager
+        progress(StoreOpenStage::LoadingTermDictionary);
         let term_dictionary = try!(TermDictionaryManager::open(&db));
 
         // Document index
+        progress(StoreOpenStage::LoadingDocumentIndex);
         let document_index = try!(DocumentIndexManager::open(&db));
 
-        Ok(RocksDBStore {
-            schema: Arc::new(schema),
-            db: db,
+        // Find segments that were waiting for a refresh when the store was last closed
+        let pending_segments = try!(segment_manager::read_pending_segments(&db));
+
+        let store = RocksDBStore {
+            schema: RwLock::new(Arc::new(schema)),
+            db: Arc::new(db),
             term_dictionary: term_dictionary,
             segments: segments,
             document_index: document_index,
-        })
+            readers: ReaderTracker::new(),
+            pinned_readers: Mutex::new(PinnedReaders::default()),
+            deferred_refresh: AtomicBool::new(false),
+            pending_segments: Mutex::new(pending_segments),
+            merge_counters: MergeCounters::default(),
+            activity: ActivityCounters::default(),
+            inserts_blocked: AtomicBool::new(false),
+            deletes_blocked: AtomicBool::new(false),
+            search_threads: options.search_threads,
+            search_pool: options.search_pool.clone(),
+            filter_cache: FilterCache::new(options.filter_cache_size),
+            request_cache: RequestCache::new(REQUEST_CACHE_SIZE),
+            refresh_generation: RwLock::new(0),
+            gc_deletes: Mutex::new(DEFAULT_GC_DELETES),
+        };
+
+        // Publish any changes that were waiting for a refresh
+        progress(StoreOpenStage::PublishingPendingChanges);
+        try!(store.refresh());
+
+        Ok(store)
     }
 
     pub fn path(&self) -> &Path {
         self.db.path()
     }
 
-    pub fn add_field(&mut self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
-        let mut schema_copy = (*self.schema).clone();
+    /// The current schema. Readers see the schema from when they were created
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.read().unwrap().clone()
+    }
+
+    pub fn add_field(&self, name: String, field_type: FieldType, field_flags: FieldFlags) -> Result<FieldId, AddFieldError> {
+        let mut schema = self.schema.write().unwrap();
+        let mut schema_copy = (**schema).clone();
         let field_id = try!(schema_copy.add_field(name, field_type, field_flags));
-        self.schema = Arc::new(schema_copy);
+        *schema = Arc::new(schema_copy);
 
         // FIXME: How do we throw this error?
-        self.db.put(b".schema", serde_json::to_string(&*self.schema).unwrap().as_bytes()).unwrap();
+        self.db.put(b".schema", serde_json::to_string(&**schema).unwrap().as_bytes()).unwrap();
 
         Ok(field_id)
     }
 
-    pub fn remove_field(&mut self, field_id: &FieldId) -> bool {
-        let mut schema_copy = (*self.schema).clone();
+    pub fn remove_field(&self, field_id: &FieldId) -> bool {
+        let mut schema = self.schema.write().unwrap();
+        let mut schema_copy = (**schema).clone();
         let field_removed = schema_copy.remove_field(field_id);
 
         if field_removed {
-            self.schema = Arc::new(schema_copy);
+            *schema = Arc::new(schema_copy);
 
             // FIXME: How do we throw this error?
-            self.db.put(b".schema", serde_json::to_string(&*self.schema).unwrap().as_bytes()).unwrap();
+            self.db.put(b".schema", serde_json::to_string(&**schema).unwrap().as_bytes()).unwrap();
         }
 
         field_removed
     }
 
-    pub fn insert_or_update_document(&self, doc: &Document) -> Result<(), DocumentInsertError> {
+    pub fn insert_or_update_document(&self, doc: &Document) -> Result<DocumentVersion, DocumentInsertError> {
+        Ok(try!(self.insert_or_update_document_with_condition(doc, None)).version)
+    }
+
+    /// Inserts or updates a document, returning its new version and whether it was created
+    ///
+    /// If a condition is given, the document is only written if its current version
+    /// matches it.
+    pub fn insert_or_update_document_with_condition(&self, doc: &Document, condition: Option<&WriteCondition>) -> Result<DocumentWrite, DocumentInsertError> {
+        self.activity.index.track(|| self.write_document(doc, condition, None))
+    }
+
+    /// Writes a document that was written to the primary copy of the index, giving it the
+    /// same version it has there
+    ///
+    /// Returns false without writing it if the store already has that version of the
+    /// document or a newer one.
+    pub fn replicate_document(&self, doc: &Document, version: u64) -> Result<bool, DocumentInsertError> {
+        match self.activity.index.track(|| self.write_document(doc, Some(&WriteCondition::OlderThan(version)), Some(version))) {
+            Ok(_) => Ok(true),
+            Err(DocumentInsertError::VersionConflict(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_document(&self, doc: &Document, condition: Option<&WriteCondition>, version: Option<u64>) -> Result<DocumentWrite, DocumentInsertError> {
+        if self.inserts_blocked.load(Ordering::SeqCst) {
+            return Err(DocumentInsertError::WriteBlocked);
+        }
+
         // Build segment in memory
         let mut builder = segment_builder::SegmentBuilder::new();
-        let doc_key = doc.key.clone();
+        let doc_key: Vec<u8> = doc.key.as_bytes().iter().cloned().collect();
         try!(builder.add_document(doc));
 
-        // Write the segment
-        let segment = try!(self.write_segment(&builder));
+        match condition {
+            Some(condition) => {
+                // Keep the document index locked while the segment is written, so the
+                // document can't change between checking the condition and replacing it
+                let mut document_index = self.document_index.writer();
+                try!(document_index.check_condition(&doc_key, condition));
+
+                let segment = try!(self.write_segment(&builder));
+                let doc_id = DocId(SegmentId(segment), 0);
+                Ok(try!(document_index.insert_or_replace_key(&self.db, &doc_key, doc_id, version, self.is_refresh_deferred())))
+            }
+            None => {
+                let segment = try!(self.write_segment(&builder));
+                let doc_id = DocId(SegmentId(segment), 0);
+                Ok(try!(self.document_index.writer().insert_or_replace_key(&self.db, &doc_key, doc_id, version, self.is_refresh_deferred())))
+            }
+        }
+    }
+
+    /// Blocks inserting and/or deleting documents
+    ///
+    /// Blocked operations return an error instead of changing the store. Merges and
+    /// refreshes still run, so pending changes are still published.
+    pub fn set_write_blocks(&self, block_inserts: bool, block_deletes: bool) {
+        self.inserts_blocked.store(block_inserts, Ordering::SeqCst);
+        self.deletes_blocked.store(block_deletes, Ordering::SeqCst);
+    }
+
+    /// Sets whether changes to the store are made searchable straight away
+    ///
+    /// When deferred, new segments aren't activated and deletions aren't applied until
+    /// `refresh` is called. This allows many writes to be published to readers at once.
+    /// If this is switched off, `refresh` must be called to publish any pending changes.
+    pub fn set_deferred_refresh(&self, deferred: bool) {
+        // Changes are published differently from now on, so results cached up to now
+        // can't be used any more
+        let mut refresh_generation = self.refresh_generation.write().unwrap();
+        *refresh_generation += 1;
+        self.request_cache.invalidate(*refresh_generation);
+
+        self.deferred_refresh.store(deferred, Ordering::SeqCst);
+    }
+
+    pub fn is_refresh_deferred(&self) -> bool {
+        self.deferred_refresh.load(Ordering::SeqCst)
+    }
+
+    /// Changes how much memory the filter cache can use, in bytes
+    pub fn set_filter_cache_size(&self, filter_cache_size: usize) {
+        self.filter_cache.set_max_memory(filter_cache_size);
+    }
+
+    /// Changes how long the versions of deleted documents are kept for
+    ///
+    /// Until then, a document that's indexed again carries on from the version of its
+    /// deletion. Expired versions are removed on the next refresh.
+    pub fn set_gc_deletes(&self, gc_deletes: Duration) {
+        *self.gc_deletes.lock().unwrap() = gc_deletes;
+    }
+
+    /// Writes the in-memory write buffers out to disk
+    ///
+    /// This makes all writes to the store so far safely on disk, including the ones that
+    /// skipped the write-ahead log, and means they don't need to be replayed from the log
+    /// when the store is next opened.
+    pub fn flush(&self) -> Result<(), rocksdb::Error> {
+        // The version of rust-rocksdb we use doesn't expose RocksDB's Flush. But a manual
+        // compaction first flushes the write buffers if they overlap the range being
+        // compacted, so compacting just the key that was written here flushes them without
+        // rewriting any other data.
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(true);
 
-        // Update document index
-        let doc_id = DocId(SegmentId(segment), 0);
-        try!(self.document_index.insert_or_replace_key(&self.db, &doc_key.as_bytes().iter().cloned().collect(), doc_id));
+        let mut write_batch = WriteBatch::default();
+        try!(write_batch.put(LAST_FLUSH_KEY, Utc::now().to_rfc3339().as_bytes()));
+        try!(self.db.write_opt(write_batch, &write_options));
 
+        self.db.compact_range(Some(LAST_FLUSH_KEY), Some(LAST_FLUSH_KEY));
         Ok(())
     }
 
+    /// Makes all changes since the last refresh visible to new readers
+    pub fn refresh(&self) -> Result<(), rocksdb::Error> {
+        // Stop new readers being opened until the changes are published and the refresh
+        // generation is incremented, so readers with the same generation see the same data
+        let mut refresh_generation = self.refresh_generation.write().unwrap();
+
+        let segments = mem::replace(&mut *self.pending_segments.lock().unwrap(), Vec::new());
+
+        // Activate new segments and apply deletions in a single write, so updated documents
+        // don't disappear or appear twice
+        let mut write_batch = WriteBatch::default();
+        for segment in segments {
+            let kb = KeyBuilder::segment_active(segment);
+            try!(write_batch.put(&kb.key(), b""));
+
+            let kb = KeyBuilder::segment_pending(segment);
+            try!(write_batch.delete(&kb.key()));
+        }
+
+        try!(self.document_index.commit_pending_deletions(&self.db, write_batch));
+
+        *refresh_generation += 1;
+        self.request_cache.invalidate(*refresh_generation);
+        drop(refresh_generation);
+
+        let gc_deletes = *self.gc_deletes.lock().unwrap();
+        self.document_index.gc_tombstones(&self.db, gc_deletes)
+    }
+
     pub fn write_segment(&self, builder: &segment_builder::SegmentBuilder) -> Result<u32, rocksdb::Error> {
         // Allocate a segment ID
         let segment = try!(self.segments.new_segment(&self.db));
@@ -233,9 +657,17 @@ impl RocksDBStore {
         let mut write_batch = WriteBatch::default();
 
         // Set segment active flag, this will activate the segment as soon as the
-        // write batch is written
-        let kb = KeyBuilder::segment_active(segment);
-        try!(write_batch.put(&kb.key(), b""));
+        // write batch is written. If refreshes are deferred, the segment is activated by
+        // the next refresh instead
+        let deferred = self.is_refresh_deferred();
+        if deferred {
+            // Record that the segment is pending so it can be activated after a restart
+            let kb = KeyBuilder::segment_pending(segment);
+            try!(write_batch.put(&kb.key(), b""));
+        } else {
+            let kb = KeyBuilder::segment_active(segment);
+            try!(write_batch.put(&kb.key(), b""));
+        }
 
         // Merge the term dictionary
         // Writes new terms to disk and generates mapping between the builder's term dictionary and the real one
@@ -264,6 +696,20 @@ impl RocksDBStore {
             try!(write_batch.put(&kb.key(), value));
         }
 
+        // Write term frequencies
+        for (&(field_id, doc_id, term_id), frequency) in builder.term_frequencies.iter() {
+            let new_term_id = term_dictionary_map.get(&term_id).expect("TermId not in term_dictionary_map");
+
+            let mut value_type = vec![b't', b'f'];
+            value_type.extend(new_term_id.0.to_string().as_bytes());
+
+            let mut value_bytes = [0; 8];
+            LittleEndian::write_i64(&mut value_bytes, *frequency);
+
+            let kb = KeyBuilder::stored_field_value(segment, doc_id, field_id.0, &value_type);
+            try!(write_batch.put(&kb.key(), &value_bytes));
+        }
+
         // Write statistics
         for (name, value) in builder.statistics.iter() {
             let kb = KeyBuilder::segment_stat(segment, name);
@@ -273,25 +719,138 @@ impl RocksDBStore {
             try!(write_batch.put(&kb.key(), &value_bytes));
         }
 
+        for (&(field_id, term_id), value) in builder.term_document_frequencies.iter() {
+            let new_term_id = term_dictionary_map.get(&term_id).expect("TermId not in term_dictionary_map");
+            let kb = KeyBuilder::segment_stat(segment, &KeyBuilder::segment_stat_term_doc_frequency_stat_name(field_id.0, new_term_id.0));
+
+            let mut value_bytes = [0; 8];
+            LittleEndian::write_i64(&mut value_bytes, *value);
+            try!(write_batch.put(&kb.key(), &value_bytes));
+        }
+
         // Write data
         try!(self.db.write(write_batch));
 
+        if deferred {
+            self.pending_segments.lock().unwrap().push(segment);
+        }
+
         Ok(segment)
     }
 
-    pub fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, rocksdb::Error> {
-        match try!(self.document_index.delete_document_by_key(&self.db, &doc_key.as_bytes().iter().cloned().collect())) {
-            Some(_doc_id) => Ok(true),
-            None => Ok(false),
+    pub fn remove_document_by_key(&self, doc_key: &str) -> Result<bool, DocumentDeleteError> {
+        Ok(try!(self.remove_document_by_key_with_condition(doc_key, None)).is_some())
+    }
+
+    /// Deletes a document, returning the version given to the deletion
+    ///
+    /// Returns None if the document doesn't exist. If a condition is given, the document
+    /// is only deleted if its current version matches it.
+    pub fn remove_document_by_key_with_condition(&self, doc_key: &str, condition: Option<&WriteCondition>) -> Result<Option<DocumentVersion>, DocumentDeleteError> {
+        self.activity.delete.track(|| self.delete_document(doc_key, condition, None))
+    }
+
+    /// Deletes a document that was deleted from the primary copy of the index, giving the
+    /// deletion the same version it has there
+    ///
+    /// Returns false if the store doesn't have the document, or has a version of it that's
+    /// at least as new as the deletion.
+    pub fn replicate_deletion(&self, doc_key: &str, version: u64) -> Result<bool, DocumentDeleteError> {
+        match self.activity.delete.track(|| self.delete_document(doc_key, Some(&WriteCondition::OlderThan(version)), Some(version))) {
+            Ok(deleted) => Ok(deleted.is_some()),
+            Err(DocumentDeleteError::VersionConflict(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Counts a bulk request that ran actions on the store, for the stats API
+    pub fn record_bulk_request(&self, time: Duration, size_in_bytes: usize) {
+        self.activity.bulk.record(time, size_in_bytes);
+    }
+
+    fn delete_document(&self, doc_key: &str, condition: Option<&WriteCondition>, version: Option<u64>) -> Result<Option<DocumentVersion>, DocumentDeleteError> {
+        if self.deletes_blocked.load(Ordering::SeqCst) {
+            return Err(DocumentDeleteError::DeleteBlocked);
+        }
+
+        let doc_key: Vec<u8> = doc_key.as_bytes().iter().cloned().collect();
+        let mut document_index = self.document_index.writer();
+
+        if let Some(condition) = condition {
+            try!(document_index.check_condition(&doc_key, condition));
         }
+
+        Ok(try!(document_index.delete_key(&self.db, &doc_key, version, self.is_refresh_deferred())))
     }
 
+    /// Opens a point-in-time reader
+    ///
+    /// The reader sees the index exactly as it was when it was opened: documents indexed,
+    /// deleted or merged afterwards are not visible to it. Segments that the reader may be
+    /// using aren't purged until it's dropped, so it should be held for the duration of
+    /// a request and no longer.
     pub fn reader<'a>(&'a self) -> RocksDBReader<'a> {
+        // Register the reader before taking the snapshot, so any segments retired after
+        // this point are guaranteed to stay around while the snapshot could see them
+        let generation = self.readers.acquire();
+
+        // Results can only be cached if changes are published by refreshes. Otherwise,
+        // every write changes them
+        let refresh_generation = self.refresh_generation.read().unwrap();
+
         RocksDBReader {
             store: &self,
-            snapshot: self.db.snapshot(),
+            schema: self.schema(),
+            snapshot: Arc::new(StoreSnapshot::new(&self.db)),
+            generation: generation,
+            refresh_generation: if self.is_refresh_deferred() { Some(*refresh_generation) } else { None },
         }
     }
+
+    /// Opens a reader on a view of the store that was kept with `RocksDBReader::pin_segments`
+    ///
+    /// The reader sees the store exactly as the reader that was pinned did. Returns None if
+    /// the pin has been released.
+    pub fn pinned_reader<'a>(&'a self, pin: u64) -> Option<RocksDBReader<'a>> {
+        let pinned_readers = self.pinned_readers.lock().unwrap();
+        let pinned = match pinned_readers.readers.get(&pin) {
+            Some(pinned) => pinned,
+            None => return None,
+        };
+
+        Some(RocksDBReader {
+            store: &self,
+            schema: pinned.schema.clone(),
+            snapshot: pinned.snapshot.clone(),
+            generation: self.readers.retain(pinned.generation),
+            refresh_generation: pinned.refresh_generation,
+        })
+    }
+
+    /// Releases a view of the store that was kept with `RocksDBReader::pin_segments`
+    pub fn unpin_segments(&self, pin: u64) {
+        let pinned = self.pinned_readers.lock().unwrap().readers.remove(&pin);
+
+        if let Some(pinned) = pinned {
+            self.readers.release(pinned.generation);
+        }
+    }
+
+    /// Returns the number of readers that are currently open on this store
+    pub fn num_open_readers(&self) -> usize {
+        self.readers.num_readers()
+    }
+
+    /// Purges segments that were replaced by merges and are no longer used by any reader
+    pub fn purge_retired_segments(&self) -> Result<(), rocksdb::Error> {
+        let segments = self.readers.take_purgeable();
+
+        if !segments.is_empty() {
+            try!(self.purge_segments(&segments));
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Debug for RocksDBStore {
@@ -300,6 +859,15 @@ impl fmt::Debug for RocksDBStore {
     }
 }
 
+/// Decodes a stored dense vector. Returns None if the value isn't a whole number of floats
+fn decode_vector(value: &[u8]) -> Option<Vec<f32>> {
+    if value.len() % 4 != 0 {
+        return None;
+    }
+
+    Some(value.chunks(4).map(LittleEndian::read_f32).collect())
+}
+
 pub enum StoredFieldReadError {
     /// The provided FieldId wasn't valid for this index
     InvalidFieldId(FieldId),
@@ -315,6 +883,12 @@ pub enum StoredFieldReadError {
 
     /// An integer/datetime field was read but the value wasn't 8 bytes
     IntegerFieldValueSizeError(usize),
+
+    /// A dense vector field was read but the value wasn't a whole number of floats
+    VectorFieldValueSizeError(usize),
+
+    /// A geo point field was read but the value wasn't 16 bytes
+    GeoPointFieldValueSizeError(usize),
 }
 
 impl From<rocksdb::Error> for StoredFieldReadError {
@@ -325,17 +899,86 @@ impl From<rocksdb::Error> for StoredFieldReadError {
 
 pub struct RocksDBReader<'a> {
     store: &'a RocksDBStore,
-    snapshot: Snapshot<'a>
+    schema: Arc<Schema>,
+    snapshot: Arc<StoreSnapshot>,
+    generation: u64,
+
+    /// The store's refresh generation when the reader was opened, if results it reads
+    /// can be cached (see `RequestCache`)
+    refresh_generation: Option<u64>,
 }
 
 impl<'a> RocksDBReader<'a> {
     pub fn schema(&self) -> &Schema {
-        &self.store.schema
+        &self.schema
+    }
+
+    /// Keeps this reader's view of the store, including the segments it can see, after
+    /// it's dropped
+    ///
+    /// This allows later requests to carry on reading from the same point in time, with
+    /// `RocksDBStore::pinned_reader`. The returned value must be passed to
+    /// `RocksDBStore::unpin_segments` when finished.
+    pub fn pin_segments(&self) -> u64 {
+        let generation = self.store.readers.retain(self.generation);
+
+        let mut pinned_readers = self.store.pinned_readers.lock().unwrap();
+        let pin = pinned_readers.next_pin;
+        pinned_readers.next_pin += 1;
+        pinned_readers.readers.insert(pin, PinnedReader {
+            schema: self.schema.clone(),
+            snapshot: self.snapshot.clone(),
+            generation: generation,
+            refresh_generation: self.refresh_generation,
+        });
+
+        pin
     }
 
     pub fn contains_document_key(&self, doc_key: &str) -> bool {
-        // TODO: use snapshot
-        self.store.document_index.contains_document_key(&doc_key.as_bytes().iter().cloned().collect())
+        let kb = KeyBuilder::primary_key_index(doc_key.as_bytes());
+
+        match self.snapshot.get(&kb.key()) {
+            Ok(value) => value.is_some(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn get_document_id_by_key(&self, doc_key: &str) -> Option<DocId> {
+        self.get_document_by_key(doc_key).map(|(doc_id, _)| doc_id)
+    }
+
+    /// Finds the id and version of the document with the key
+    pub fn get_document_by_key(&self, doc_key: &str) -> Option<(DocId, DocumentVersion)> {
+        let kb = KeyBuilder::primary_key_index(doc_key.as_bytes());
+
+        match self.snapshot.get(&kb.key()) {
+            Ok(Some(value)) => Some(parse_primary_key_value(&value)),
+            _ => None,
+        }
+    }
+
+    /// Finds the keys of all documents, by their ids
+    pub fn document_keys(&self) -> FnvHashMap<DocId, String> {
+        let mut keys = FnvHashMap::default();
+        let mut iter = self.snapshot.raw_iterator();
+        iter.seek(b"k");
+        while iter.valid() {
+            let k = iter.key().unwrap();
+
+            if k[0] != b'k' {
+                break;
+            }
+
+            if let Ok(key) = String::from_utf8(k[1..].to_vec()) {
+                let (doc_id, _) = parse_primary_key_value(&iter.value().unwrap());
+                keys.insert(doc_id, key);
+            }
+
+            iter.next();
+        }
+
+        keys
     }
 
     pub fn read_stored_field(&self, field_id: FieldId, doc_id: DocId) -> Result<Option<FieldValue>, StoredFieldReadError> {
@@ -387,6 +1030,22 @@ impl<'a> RocksDBReader<'a> {
                         let datetime = NaiveDateTime::from_timestamp(timestamp, nanos as u32);
                         Ok(Some(FieldValue::DateTime(DateTime::from_utc(datetime, Utc))))
                     }
+                    FieldType::DenseVector => {
+                        match decode_vector(&value) {
+                            Some(vector) => Ok(Some(FieldValue::Vector(vector))),
+                            None => Err(StoredFieldReadError::VectorFieldValueSizeError(value.len())),
+                        }
+                    }
+                    FieldType::GeoPoint => {
+                        if value.len() != 16 {
+                            return Err(StoredFieldReadError::GeoPointFieldValueSizeError(value.len()));
+                        }
+
+                        Ok(Some(FieldValue::GeoPoint(GeoPoint {
+                            lat: LittleEndian::read_f64(&value[..8]),
+                            lon: LittleEndian::read_f64(&value[8..]),
+                        })))
+                    }
                 }
             }
             None => Ok(None),
@@ -394,10 +1053,18 @@ impl<'a> RocksDBReader<'a> {
     }
 }
 
+impl<'a> Drop for RocksDBReader<'a> {
+    fn drop(&mut self) {
+        self.store.readers.release(self.generation);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs::remove_dir_all;
+    use std::fs::{remove_dir_all, read_dir};
     use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
 
     use rocksdb::DB;
     use fnv::FnvHashMap;
@@ -406,9 +1073,14 @@ mod tests {
     use search::schema::{FieldType, FIELD_INDEXED, FIELD_STORED};
     use search::query::Query;
     use search::query::term_scorer::TermScorer;
+    use search::cancellation::SearchCancellation;
+    use search::knn::{KnnSearch, VectorSimilarity};
+    use search::geo::GeoPoint;
     use search::collectors::top_score::TopScoreCollector;
+    use search::collectors::total_count::TotalCountCollector;
+    use thread_pool::ThreadPool;
 
-    use super::RocksDBStore;
+    use super::{RocksDBStore, StoreOptions, DocumentInsertError, DocumentDeleteError, DocumentVersion, DocumentWrite, WriteCondition};
 
     fn remove_dir_all_ignore_error<P: AsRef<Path>>(path: P) {
         match remove_dir_all(&path) {
@@ -441,8 +1113,27 @@ mod tests {
         assert!(store.is_ok());
     }
 
+    #[test]
+    fn test_add_field_while_reading() {
+        remove_dir_all_ignore_error("test_indices/test_add_field_while_reading");
+
+        let store = RocksDBStore::create("test_indices/test_add_field_while_reading").unwrap();
+        let reader = store.reader();
+        store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        // Readers keep the schema they were created with
+        assert!(reader.schema().get_field_by_name("title").is_none());
+        assert!(store.reader().schema().get_field_by_name("title").is_some());
+
+        // The new schema is saved with the store
+        drop(reader);
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_add_field_while_reading").unwrap();
+        assert!(store.schema().get_field_by_name("title").is_some());
+    }
+
     fn make_test_store(path: &str) -> RocksDBStore {
-        let mut store = RocksDBStore::create(path).unwrap();
+        let store = RocksDBStore::create(path).unwrap();
         let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
         let body_field = store.add_field("body".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
         let pk_field = store.add_field("pk".to_string(), FieldType::I64, FIELD_STORED).unwrap();
@@ -546,7 +1237,7 @@ mod tests {
         make_test_store("test_indices/test");
 
         let store = RocksDBStore::open("test_indices/test").unwrap();
-        let title_field = store.schema.get_field_by_name("title").unwrap();
+        let title_field = store.schema().get_field_by_name("title").unwrap();
 
         let index_reader = store.reader();
 
@@ -579,4 +1270,631 @@ mod tests {
         let docs = collector.into_sorted_vec();
         println!("{:?}", docs);
     }
+
+    #[test]
+    fn test_pinned_reader() {
+        remove_dir_all_ignore_error("test_indices/test_pinned_reader");
+
+        let store = make_test_store("test_indices/test_pinned_reader");
+        let pin = store.reader().pin_segments();
+        assert_eq!(store.num_open_readers(), 1);
+
+        // Later requests keep seeing the store as it was when the reader was pinned
+        store.remove_document_by_key("test_doc").unwrap();
+        let pinned_reader = store.pinned_reader(pin).unwrap();
+        assert!(pinned_reader.contains_document_key("test_doc"));
+        assert!(!store.reader().contains_document_key("test_doc"));
+        drop(pinned_reader);
+
+        store.unpin_segments(pin);
+        assert_eq!(store.num_open_readers(), 0);
+        assert!(store.pinned_reader(pin).is_none());
+    }
+
+    #[test]
+    fn test_reader_is_point_in_time() {
+        remove_dir_all_ignore_error("test_indices/test_reader_is_point_in_time");
+
+        make_test_store("test_indices/test_reader_is_point_in_time");
+
+        let store = RocksDBStore::open("test_indices/test_reader_is_point_in_time").unwrap();
+        let query = Query::All {
+            score: 1.0f32,
+        };
+
+        let index_reader = store.reader();
+        assert_eq!(store.num_open_readers(), 1);
+
+        // Delete a document after the reader was opened
+        store.remove_document_by_key("test_doc").unwrap();
+
+        // The existing reader shouldn't see the delete
+        assert!(index_reader.contains_document_key("test_doc"));
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 2);
+
+        drop(index_reader);
+        assert_eq!(store.num_open_readers(), 0);
+
+        // But new readers should
+        let index_reader = store.reader();
+        assert!(!index_reader.contains_document_key("test_doc"));
+        let mut collector = TotalCountCollector::new();
+        index_reader.search(&mut collector, &query).unwrap();
+        assert_eq!(collector.get_total_count(), 1);
+    }
+
+    #[test]
+    fn test_write_blocks() {
+        remove_dir_all_ignore_error("test_indices/test_write_blocks");
+
+        let store = make_test_store("test_indices/test_write_blocks");
+        let doc = Document {
+            key: "new_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        };
+
+        // Block inserts but allow deletes
+        store.set_write_blocks(true, false);
+
+        match store.insert_or_update_document(&doc) {
+            Err(DocumentInsertError::WriteBlocked) => {}
+            result => panic!("expected WriteBlocked error, got {:?}", result),
+        }
+        assert!(!store.reader().contains_document_key("new_doc"));
+        assert!(store.remove_document_by_key("test_doc").unwrap());
+
+        // Block deletes as well
+        store.set_write_blocks(true, true);
+
+        match store.remove_document_by_key("another_test_doc") {
+            Err(DocumentDeleteError::DeleteBlocked) => {}
+            result => panic!("expected DeleteBlocked error, got {:?}", result),
+        }
+
+        // Lift the blocks
+        store.set_write_blocks(false, false);
+        store.insert_or_update_document(&doc).unwrap();
+        assert!(store.reader().contains_document_key("new_doc"));
+    }
+
+    #[test]
+    fn test_deferred_refresh() {
+        remove_dir_all_ignore_error("test_indices/test_deferred_refresh");
+
+        make_test_store("test_indices/test_deferred_refresh");
+
+        let store = RocksDBStore::open("test_indices/test_deferred_refresh").unwrap();
+        store.set_deferred_refresh(true);
+
+        let query = Query::All {
+            score: 1.0f32,
+        };
+        let count = |store: &RocksDBStore| {
+            let mut collector = TotalCountCollector::new();
+            store.reader().search(&mut collector, &query).unwrap();
+            collector.get_total_count()
+        };
+
+        // Index a new document, it shouldn't be visible until the store is refreshed
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        }).unwrap();
+        assert_eq!(count(&store), 2);
+
+        store.refresh().unwrap();
+        assert_eq!(count(&store), 3);
+
+        // Same for deletions
+        store.remove_document_by_key("test_doc").unwrap();
+        assert_eq!(count(&store), 3);
+
+        store.refresh().unwrap();
+        assert_eq!(count(&store), 2);
+
+        // Updating a document shouldn't make it disappear or appear twice before the refresh
+        store.insert_or_update_document(&Document {
+            key: "new_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        }).unwrap();
+        assert_eq!(count(&store), 2);
+
+        store.refresh().unwrap();
+        assert_eq!(count(&store), 2);
+    }
+
+    #[test]
+    fn test_pending_changes_published_on_open() {
+        remove_dir_all_ignore_error("test_indices/test_pending_changes_published_on_open");
+
+        make_test_store("test_indices/test_pending_changes_published_on_open");
+
+        {
+            let store = RocksDBStore::open("test_indices/test_pending_changes_published_on_open").unwrap();
+            store.set_deferred_refresh(true);
+
+            store.insert_or_update_document(&Document {
+                key: "new_doc".to_string(),
+                indexed_fields: FnvHashMap::default(),
+                stored_fields: FnvHashMap::default(),
+            }).unwrap();
+            store.remove_document_by_key("test_doc").unwrap();
+        }
+
+        // Changes that were pending when the store was closed must not be lost
+        let store = RocksDBStore::open("test_indices/test_pending_changes_published_on_open").unwrap();
+        let mut collector = TotalCountCollector::new();
+        store.reader().search(&mut collector, &Query::All { score: 1.0f32 }).unwrap();
+        assert_eq!(collector.get_total_count(), 2);
+        assert!(store.reader().contains_document_key("new_doc"));
+        assert!(!store.reader().contains_document_key("test_doc"));
+    }
+
+    #[test]
+    fn test_document_versions() {
+        remove_dir_all_ignore_error("test_indices/test_document_versions");
+
+        let store = make_test_store("test_indices/test_document_versions");
+        let doc = Document {
+            key: "test_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        };
+        let version = |version, seq_no| DocumentVersion { version: version, seq_no: seq_no, primary_term: 1 };
+
+        // Versions are kept when documents are merged
+        assert_eq!(store.reader().get_document_by_key("test_doc").map(|(_, version)| version), Some(version(1, 0)));
+        assert_eq!(store.reader().get_document_by_key("another_test_doc").map(|(_, version)| version), Some(version(1, 1)));
+
+        assert_eq!(store.insert_or_update_document(&doc).unwrap(), version(2, 2));
+
+        // Writes with a stale condition are rejected without changing the document
+        match store.insert_or_update_document_with_condition(&doc, Some(&WriteCondition::Version(1))) {
+            Err(DocumentInsertError::VersionConflict(conflict)) => assert_eq!(conflict.current, Some(version(2, 2))),
+            result => panic!("expected VersionConflict error, got {:?}", result),
+        }
+        let mut collector = TotalCountCollector::new();
+        store.reader().search(&mut collector, &Query::All { score: 1.0f32 }).unwrap();
+        assert_eq!(collector.get_total_count(), 2);
+
+        let condition = WriteCondition::SeqNo { seq_no: 2, primary_term: 1 };
+        assert_eq!(store.insert_or_update_document_with_condition(&doc, Some(&condition)).unwrap(), DocumentWrite { version: version(3, 3), created: false });
+
+        // Deletes
+        match store.remove_document_by_key_with_condition("test_doc", Some(&WriteCondition::Version(2))) {
+            Err(DocumentDeleteError::VersionConflict(conflict)) => assert_eq!(conflict.current, Some(version(3, 3))),
+            result => panic!("expected VersionConflict error, got {:?}", result),
+        }
+        assert_eq!(store.remove_document_by_key_with_condition("test_doc", Some(&WriteCondition::Version(3))).unwrap(), Some(version(4, 4)));
+
+        // Conditions never match documents that don't exist
+        match store.insert_or_update_document_with_condition(&doc, Some(&WriteCondition::Version(4))) {
+            Err(DocumentInsertError::VersionConflict(conflict)) => assert_eq!(conflict.current, None),
+            result => panic!("expected VersionConflict error, got {:?}", result),
+        }
+
+        // Creates are rejected if the document already exists
+        let another_doc = Document {
+            key: "another_test_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        };
+        match store.insert_or_update_document_with_condition(&another_doc, Some(&WriteCondition::NotExists)) {
+            Err(DocumentInsertError::VersionConflict(conflict)) => assert_eq!(conflict.current, Some(version(1, 1))),
+            result => panic!("expected VersionConflict error, got {:?}", result),
+        }
+
+        // Seq_nos and the versions of deleted documents carry on from where they were after reopening
+        drop(store);
+        let store = RocksDBStore::open("test_indices/test_document_versions").unwrap();
+        assert_eq!(store.insert_or_update_document_with_condition(&doc, None).unwrap(), DocumentWrite { version: version(5, 5), created: true });
+        assert_eq!(store.reader().get_document_by_key("another_test_doc").map(|(_, version)| version), Some(version(1, 1)));
+
+        // Versions restart once the deletion has been garbage collected
+        assert_eq!(store.remove_document_by_key_with_condition("test_doc", None).unwrap(), Some(version(6, 6)));
+        store.set_gc_deletes(Duration::from_secs(0));
+        store.refresh().unwrap();
+        assert_eq!(store.insert_or_update_document(&doc).unwrap(), version(1, 7));
+    }
+
+    #[test]
+    fn test_replicate_document() {
+        remove_dir_all_ignore_error("test_indices/test_replicate_document");
+
+        let store = make_test_store("test_indices/test_replicate_document");
+        let doc = Document {
+            key: "test_doc".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: FnvHashMap::default(),
+        };
+        let current_version = |store: &RocksDBStore| store.reader().get_document_by_key("test_doc").map(|(_, version)| version.version);
+
+        // Writes take the primary's version, and older ones are skipped
+        assert_eq!(store.replicate_document(&doc, 5).unwrap(), true);
+        assert_eq!(current_version(&store), Some(5));
+        assert_eq!(store.replicate_document(&doc, 4).unwrap(), false);
+        assert_eq!(store.replicate_document(&doc, 5).unwrap(), false);
+        assert_eq!(current_version(&store), Some(5));
+
+        // So are deletions
+        assert_eq!(store.replicate_deletion("test_doc", 5).unwrap(), false);
+        assert_eq!(store.replicate_deletion("test_doc", 6).unwrap(), true);
+        assert_eq!(current_version(&store), None);
+        assert_eq!(store.replicate_deletion("test_doc", 7).unwrap(), false);
+
+        // Writes made before the deletion don't bring the document back
+        assert_eq!(store.replicate_document(&doc, 6).unwrap(), false);
+        assert_eq!(current_version(&store), None);
+
+        // Documents that don't exist are created at the version they're given
+        assert_eq!(store.replicate_document(&doc, 7).unwrap(), true);
+        assert_eq!(current_version(&store), Some(7));
+    }
+
+    #[test]
+    fn test_document_keys() {
+        remove_dir_all_ignore_error("test_indices/test_document_keys");
+
+        let store = make_test_store("test_indices/test_document_keys");
+        let index_reader = store.reader();
+        let keys = index_reader.document_keys();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.get(&index_reader.get_document_id_by_key("test_doc").unwrap()).map(String::as_str), Some("test_doc"));
+        assert_eq!(keys.get(&index_reader.get_document_id_by_key("another_test_doc").unwrap()).map(String::as_str), Some("another_test_doc"));
+
+        // Deleted documents aren't included
+        store.remove_document_by_key("test_doc").unwrap();
+        assert_eq!(store.reader().document_keys().values().collect::<Vec<_>>(), vec!["another_test_doc"]);
+    }
+
+    #[test]
+    fn test_flush() {
+        remove_dir_all_ignore_error("test_indices/test_flush");
+
+        let store = make_test_store("test_indices/test_flush");
+        assert!(store.flush().is_ok());
+
+        // The write buffers are written out to table files
+        let mut files = read_dir("test_indices/test_flush").unwrap();
+        assert!(files.any(|entry| entry.unwrap().path().extension().map_or(false, |extension| extension == "sst")));
+    }
+
+    #[test]
+    fn test_explain() {
+        remove_dir_all_ignore_error("test_indices/test_explain");
+
+        let store = make_test_store("test_indices/test_explain");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let index_reader = store.reader();
+
+        let query = Query::Term {
+            field: title_field,
+            term: Term::from_string("howdy"),
+            scorer: TermScorer::default_with_boost(2.0f32),
+        };
+
+        let mut collector = TopScoreCollector::new(10);
+        index_reader.search(&mut collector, &query).unwrap();
+        let score = collector.into_sorted_vec()[0].score().unwrap();
+
+        // The explanation must add up to the same score as the search
+        let doc_id = index_reader.get_document_id_by_key("another_test_doc").unwrap();
+        let explanation = index_reader.explain(&query, doc_id).unwrap().unwrap();
+        assert_eq!(explanation.value, score);
+        assert_eq!(explanation.description, "weight(title:howdy), product of:");
+
+        // Documents that don't match have no explanation
+        let doc_id = index_reader.get_document_id_by_key("test_doc").unwrap();
+        assert_eq!(index_reader.explain(&query, doc_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_profile() {
+        remove_dir_all_ignore_error("test_indices/test_profile");
+
+        let store = make_test_store("test_indices/test_profile");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let index_reader = store.reader();
+
+        let query = Query::Disjunction {
+            queries: vec![
+                Query::term(title_field, Term::from_string("howdy")),
+                Query::term(title_field, Term::from_string("hello")),
+            ]
+        };
+
+        let profile = index_reader.profile(&query, true).unwrap();
+        assert_eq!(profile.query_type, "Disjunction");
+        assert_eq!(profile.breakdown.match_count, 2);
+        assert_eq!(profile.children.len(), 2);
+        assert_eq!(profile.children[0].description, "title:howdy");
+        assert_eq!(profile.children[0].breakdown.match_count, 1);
+    }
+
+    #[test]
+    fn test_parallel_search() {
+        remove_dir_all_ignore_error("test_indices/test_parallel_search");
+
+        let options = StoreOptions {
+            search_threads: 4,
+            search_pool: Some(Arc::new(ThreadPool::new("search", 3, 10).unwrap())),
+            ..StoreOptions::default()
+        };
+
+        let store = RocksDBStore::create_with_options("test_indices/test_parallel_search", &options).unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+
+        // Each insert creates a new segment
+        for i in 0..10 {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(
+                title_field,
+                (0..i + 1).map(|position| Token { term: Term::from_string("hello"), position: position }).collect::<Vec<_>>().into()
+            );
+
+            store.insert_or_update_document(&Document {
+                key: format!("doc_{}", i),
+                indexed_fields: indexed_fields,
+                stored_fields: FnvHashMap::default(),
+            }).unwrap();
+        }
+
+        let query = Query::term(title_field, Term::from_string("hello"));
+        let search = |store: &RocksDBStore| {
+            let index_reader = store.reader();
+
+            let mut collector = TotalCountCollector::new();
+            index_reader.search(&mut collector, &query).unwrap();
+            assert_eq!(collector.get_total_count(), 10);
+
+            // Matches that didn't make the top 5 of their segment are still counted
+            let mut collector = TopScoreCollector::new(5);
+            index_reader.search(&mut collector, &query).unwrap();
+            assert_eq!(collector.total_hits(), 10);
+            collector.into_sorted_vec().iter().map(|doc| (doc.doc_id(), doc.score())).collect::<Vec<_>>()
+        };
+
+        let parallel_results = search(&store);
+        drop(store);
+
+        // Searching one segment at a time must give the same results
+        let options = StoreOptions {
+            search_threads: 1,
+            ..StoreOptions::default()
+        };
+        let store = RocksDBStore::open_with_options("test_indices/test_parallel_search", &options).unwrap();
+
+        assert_eq!(parallel_results.len(), 5);
+        assert_eq!(search(&store), parallel_results);
+    }
+
+    #[test]
+    fn test_filter_cache() {
+        remove_dir_all_ignore_error("test_indices/test_filter_cache");
+
+        let store = make_test_store("test_indices/test_filter_cache");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let body_field = store.schema().get_field_by_name("body").unwrap();
+        let index_reader = store.reader();
+
+        let query = Query::term(body_field, Term::from_string("lorem"))
+            .filter(Query::term(title_field, Term::from_string("hello")));
+
+        let search = || {
+            let mut collector = TopScoreCollector::new(10);
+            index_reader.search(&mut collector, &query).unwrap();
+            collector.into_sorted_vec().iter().map(|doc| (doc.doc_id(), doc.score())).collect::<Vec<_>>()
+        };
+
+        // The first search populates the cache, one entry per segment
+        let results = search();
+        assert_eq!(results.len(), 1);
+
+        let stats = store.get_store_statistics().unwrap().filter_cache;
+        let segment_count = stats.miss_count;
+        assert!(segment_count > 0);
+        assert_eq!(stats.hit_count, 0);
+        assert_eq!(stats.cache_size, segment_count);
+
+        // The second search must use the cached filter and give the same results
+        assert_eq!(search(), results);
+
+        let stats = store.get_store_statistics().unwrap().filter_cache;
+        assert_eq!(stats.hit_count, segment_count);
+        assert_eq!(stats.miss_count, segment_count);
+    }
+
+    #[test]
+    fn test_request_cache() {
+        remove_dir_all_ignore_error("test_indices/test_request_cache");
+
+        let store = make_test_store("test_indices/test_request_cache");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let query = Query::term(title_field, Term::from_string("hello"));
+
+        // Counts aren't cached while every write is published straight away
+        assert_eq!(store.reader().count(&query), Ok(1));
+        assert_eq!(store.get_store_statistics().unwrap().request_cache.cache_size, 0);
+
+        store.set_deferred_refresh(true);
+        assert_eq!(store.reader().count(&query), Ok(1));
+        assert_eq!(store.reader().count(&query), Ok(1));
+
+        let stats = store.get_store_statistics().unwrap().request_cache;
+        assert_eq!(stats.hit_count, 1);
+        assert_eq!(stats.cache_size, 1);
+
+        // Refreshing publishes the deletion and clears the cache
+        store.remove_document_by_key("test_doc").unwrap();
+        assert_eq!(store.reader().count(&query), Ok(1));
+        store.refresh().unwrap();
+        assert_eq!(store.get_store_statistics().unwrap().request_cache.cache_size, 0);
+        assert_eq!(store.reader().count(&query), Ok(0));
+
+        // Pinned readers share the counts cached for the generation they were opened at
+        let pin = store.reader().pin_segments();
+        assert_eq!(store.pinned_reader(pin).unwrap().count(&query), Ok(0));
+        assert_eq!(store.get_store_statistics().unwrap().request_cache.hit_count, 3);
+        store.unpin_segments(pin);
+    }
+
+    #[test]
+    fn test_cancelled_search() {
+        remove_dir_all_ignore_error("test_indices/test_cancelled_search");
+
+        let store = make_test_store("test_indices/test_cancelled_search");
+        let index_reader = store.reader();
+
+        let mut collector = TotalCountCollector::new();
+        assert_eq!(index_reader.search_cancellable(&mut collector, &Query::all(), &SearchCancellation::new()), Ok(true));
+        assert_eq!(collector.get_total_count(), 2);
+
+        // A search that has already been cancelled stops before collecting anything
+        let cancellation = SearchCancellation::new();
+        cancellation.cancel();
+
+        let mut collector = TotalCountCollector::new();
+        assert_eq!(index_reader.search_cancellable(&mut collector, &Query::all(), &cancellation), Ok(false));
+        assert_eq!(collector.get_total_count(), 0);
+    }
+
+    #[test]
+    fn test_read_geo_point() {
+        remove_dir_all_ignore_error("test_indices/test_read_geo_point");
+
+        let store = RocksDBStore::create("test_indices/test_read_geo_point").unwrap();
+        let location_field = store.add_field("location".to_string(), FieldType::GeoPoint, FIELD_STORED).unwrap();
+
+        let mut stored_fields = FnvHashMap::default();
+        stored_fields.insert(location_field, FieldValue::GeoPoint(GeoPoint::new(51.5074, -0.1278).unwrap()));
+
+        store.insert_or_update_document(&Document {
+            key: "london".to_string(),
+            indexed_fields: FnvHashMap::default(),
+            stored_fields: stored_fields,
+        }).unwrap();
+
+        let index_reader = store.reader();
+        let doc_id = index_reader.get_document_id_by_key("london").unwrap();
+        assert_eq!(index_reader.read_stored_field(location_field, doc_id).ok(), Some(Some(FieldValue::GeoPoint(GeoPoint::new(51.5074, -0.1278).unwrap()))));
+    }
+
+    #[test]
+    fn test_knn_search() {
+        remove_dir_all_ignore_error("test_indices/test_knn_search");
+
+        let store = RocksDBStore::create("test_indices/test_knn_search").unwrap();
+        let title_field = store.add_field("title".to_string(), FieldType::Text, FIELD_INDEXED).unwrap();
+        let vector_field = store.add_field("vector".to_string(), FieldType::DenseVector, FIELD_STORED).unwrap();
+
+        let vectors = vec![
+            ("a", "hello", vec![1.0f32, 0.0]),
+            ("b", "hello", vec![0.0, 1.0]),
+            ("c", "world", vec![0.9, 0.1]),
+            ("d", "world", vec![-1.0, 0.0]),
+        ];
+
+        for (key, title, vector) in vectors {
+            let mut indexed_fields = FnvHashMap::default();
+            indexed_fields.insert(title_field, vec![Token { term: Term::from_string(title), position: 1 }].into());
+
+            let mut stored_fields = FnvHashMap::default();
+            stored_fields.insert(vector_field, FieldValue::Vector(vector));
+
+            store.insert_or_update_document(&Document {
+                key: key.to_string(),
+                indexed_fields: indexed_fields,
+                stored_fields: stored_fields,
+            }).unwrap();
+        }
+
+        let index_reader = store.reader();
+        let mut knn = KnnSearch {
+            field: vector_field,
+            query_vector: vec![1.0, 0.0],
+            similarity: VectorSimilarity::Cosine,
+            k: 2,
+            num_candidates: 2,
+            filter: None,
+            boost: 1.0,
+        };
+
+        let doc_ids = |keys: &[&str]| {
+            keys.iter().map(|key| index_reader.get_document_id_by_key(key).unwrap().as_u64()).collect::<Vec<_>>()
+        };
+        let get_doc_ids = |neighbours: Vec<(u64, f32)>| {
+            neighbours.iter().map(|&(doc_id, _)| doc_id).collect::<Vec<_>>()
+        };
+
+        // The closest vectors are returned first
+        let neighbours = index_reader.knn_search(&knn).unwrap();
+        assert_eq!(neighbours[0].1, 1.0);
+        assert_eq!(get_doc_ids(neighbours), doc_ids(&["a", "c"]));
+
+        // Only documents that match the filter are considered
+        knn.filter = Some(Query::term(title_field, Term::from_string("world")));
+        knn.k = 10;
+        assert_eq!(get_doc_ids(index_reader.knn_search(&knn).unwrap()), doc_ids(&["c", "d"]));
+    }
+
+    #[test]
+    fn test_matching_documents() {
+        remove_dir_all_ignore_error("test_indices/test_matching_documents");
+
+        let store = make_test_store("test_indices/test_matching_documents");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let index_reader = store.reader();
+
+        let test_doc = index_reader.get_document_id_by_key("test_doc").unwrap().as_u64();
+        let another_test_doc = index_reader.get_document_id_by_key("another_test_doc").unwrap().as_u64();
+
+        let mut all_docs = vec![test_doc, another_test_doc];
+        all_docs.sort();
+
+        assert_eq!(index_reader.matching_documents(&Query::all()), Ok(all_docs));
+        assert_eq!(index_reader.matching_documents(&Query::term(title_field, Term::from_string("hello"))), Ok(vec![test_doc]));
+        assert_eq!(index_reader.matching_documents(&Query::None), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_document_matches() {
+        remove_dir_all_ignore_error("test_indices/test_document_matches");
+
+        let store = make_test_store("test_indices/test_document_matches");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let index_reader = store.reader();
+
+        let test_doc = index_reader.get_document_id_by_key("test_doc").unwrap();
+        let another_test_doc = index_reader.get_document_id_by_key("another_test_doc").unwrap();
+        let query = Query::term(title_field, Term::from_string("hello"));
+
+        assert_eq!(index_reader.document_matches(&query, test_doc), Ok(true));
+        assert_eq!(index_reader.document_matches(&query, another_test_doc), Ok(false));
+        assert_eq!(index_reader.document_matches(&Query::None, test_doc), Ok(false));
+    }
+
+    #[test]
+    fn test_term_document_frequency() {
+        remove_dir_all_ignore_error("test_indices/test_term_document_frequency");
+
+        let store = make_test_store("test_indices/test_term_document_frequency");
+        let title_field = store.schema().get_field_by_name("title").unwrap();
+        let body_field = store.schema().get_field_by_name("body").unwrap();
+        let index_reader = store.reader();
+
+        assert_eq!(index_reader.term_document_frequency(title_field, &Term::from_string("howdy")), Ok(1));
+        assert_eq!(index_reader.term_document_frequency(title_field, &Term::from_string("hello")), Ok(1));
+        assert_eq!(index_reader.term_document_frequency(body_field, &Term::from_string("lorem")), Ok(2));
+        assert_eq!(index_reader.term_document_frequency(body_field, &Term::from_string("hello")), Ok(0));
+
+        let mut terms = index_reader.find_field_terms(title_field, |term| term.starts_with('h')).unwrap();
+        terms.sort();
+        assert_eq!(terms, vec![("hello".to_string(), 1), ("howdy".to_string(), 1)]);
+    }
 }
@@ -0,0 +1,155 @@
+use std::sync::Mutex;
+use std::collections::BTreeMap;
+
+/// Keeps track of which readers are open so data isn't freed while it's being read
+///
+/// Every time the set of active segments changes (eg, after a merge), the "generation" is
+/// incremented. Each reader is registered under the generation that was current when it
+/// was opened. Segments that have been replaced can only be purged once all readers from
+/// earlier generations have been released, as they may still be reading them.
+pub struct ReaderTracker {
+    state: Mutex<ReaderTrackerState>,
+}
+
+struct ReaderTrackerState {
+    generation: u64,
+
+    /// Number of open readers per generation
+    readers: BTreeMap<u64, usize>,
+
+    /// Segments waiting to be purged, along with the generation they were replaced in
+    pending_purges: Vec<(u64, Vec<u32>)>,
+}
+
+impl ReaderTracker {
+    pub fn new() -> ReaderTracker {
+        ReaderTracker {
+            state: Mutex::new(ReaderTrackerState {
+                generation: 0,
+                readers: BTreeMap::new(),
+                pending_purges: Vec::new(),
+            }),
+        }
+    }
+
+    /// Registers a new reader, returning the generation it must be released with
+    ///
+    /// This must be called before the reader takes its snapshot
+    pub fn acquire(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        *state.readers.entry(generation).or_insert(0) += 1;
+        generation
+    }
+
+    /// Registers another reader under the generation of a reader that's already open
+    ///
+    /// This lets a reader's view of the segments be kept after the reader itself is released
+    pub fn retain(&self, generation: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state.readers.entry(generation).or_insert(0) += 1;
+        generation
+    }
+
+    /// Releases a reader that was registered with `acquire`
+    pub fn release(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        let remove = match state.readers.get_mut(&generation) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            state.readers.remove(&generation);
+        }
+    }
+
+    /// Returns the number of readers that are currently open
+    pub fn num_readers(&self) -> usize {
+        self.state.lock().unwrap().readers.values().sum()
+    }
+
+    /// Marks the given segments as replaced
+    ///
+    /// This must be called after the change has been committed. The segments will be
+    /// returned by `take_purgeable` once all readers that could see them are released.
+    pub fn retire_segments(&self, segments: Vec<u32>) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let generation = state.generation;
+        state.pending_purges.push((generation, segments));
+    }
+
+    /// Takes the list of retired segments that are no longer visible to any reader
+    pub fn take_purgeable(&self) -> Vec<u32> {
+        let mut state = self.state.lock().unwrap();
+
+        // Readers registered at or after this generation can't see any retired segments
+        // from this generation or before
+        let oldest_reader = state.readers.keys().next().cloned().unwrap_or(state.generation);
+
+        let mut purgeable = Vec::new();
+        state.pending_purges.retain(|&(generation, ref segments)| {
+            if generation <= oldest_reader {
+                purgeable.extend(segments.iter().cloned());
+                false
+            } else {
+                true
+            }
+        });
+
+        purgeable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReaderTracker;
+
+    #[test]
+    fn test_purge_without_readers() {
+        let tracker = ReaderTracker::new();
+        tracker.retire_segments(vec![1, 2]);
+
+        assert_eq!(tracker.take_purgeable(), vec![1, 2]);
+        assert_eq!(tracker.take_purgeable(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_purge_waits_for_old_readers() {
+        let tracker = ReaderTracker::new();
+        let old_reader = tracker.acquire();
+
+        tracker.retire_segments(vec![1, 2]);
+
+        // Readers opened after the merge don't hold back the purge
+        let new_reader = tracker.acquire();
+        assert_eq!(tracker.num_readers(), 2);
+        assert_eq!(tracker.take_purgeable(), Vec::<u32>::new());
+
+        tracker.release(old_reader);
+        assert_eq!(tracker.take_purgeable(), vec![1, 2]);
+
+        tracker.release(new_reader);
+        assert_eq!(tracker.num_readers(), 0);
+    }
+
+    #[test]
+    fn test_retain() {
+        let tracker = ReaderTracker::new();
+        let reader = tracker.acquire();
+        let pin = tracker.retain(reader);
+
+        tracker.retire_segments(vec![1]);
+
+        // The retained generation keeps the segments after the reader is gone
+        tracker.release(reader);
+        assert_eq!(tracker.take_purgeable(), Vec::<u32>::new());
+
+        tracker.release(pin);
+        assert_eq!(tracker.take_purgeable(), vec![1]);
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+use std::collections::{HashMap, BTreeMap};
+use std::mem;
+
+/// Identifies a cached count: the refresh generation of the reader that ran the query,
+/// and the query itself
+pub type RequestCacheKey = (u64, String);
+
+/// Caches the number of documents matched by frequently repeated queries
+///
+/// Unlike the filter cache, this caches whole results, which change whenever documents
+/// are indexed or deleted. Stores that defer refreshes only publish changes when they're
+/// refreshed, so results are keyed by the "refresh generation" of the reader and the
+/// cache is cleared each time the generation changes. When it's full, the least recently
+/// used entries are evicted.
+pub struct RequestCache {
+    state: Mutex<RequestCacheState>,
+}
+
+struct RequestCacheEntry {
+    count: u64,
+    last_used: u64,
+}
+
+struct RequestCacheState {
+    /// Refresh generation that entries are currently being cached for
+    generation: u64,
+    entries: HashMap<RequestCacheKey, RequestCacheEntry>,
+
+    /// Entry keys ordered by when they were last used, oldest first
+    lru: BTreeMap<u64, RequestCacheKey>,
+    clock: u64,
+    max_entries: usize,
+    counters: RequestCacheStatistics,
+}
+
+/// A snapshot of a request cache's counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestCacheStatistics {
+    /// Number of lookups that found a cached count
+    pub hit_count: usize,
+
+    /// Number of lookups that had to run the query
+    pub miss_count: usize,
+
+    /// Number of counts currently in the cache
+    pub cache_size: usize,
+
+    /// Number of counts removed to make space for new ones
+    pub evictions: usize,
+
+    /// Approximate memory used by the cached counts, in bytes
+    pub memory_size_in_bytes: usize,
+}
+
+impl RequestCache {
+    pub fn new(max_entries: usize) -> RequestCache {
+        RequestCache {
+            state: Mutex::new(RequestCacheState {
+                generation: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                max_entries: max_entries,
+                counters: RequestCacheStatistics::default(),
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &RequestCacheKey) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let found = match state.entries.get_mut(key) {
+            Some(entry) => {
+                let last_used = entry.last_used;
+                entry.last_used = clock;
+                Some((last_used, entry.count))
+            }
+            None => None,
+        };
+
+        match found {
+            Some((last_used, count)) => {
+                state.lru.remove(&last_used);
+                state.lru.insert(clock, key.clone());
+                state.counters.hit_count += 1;
+                Some(count)
+            }
+            None => {
+                state.counters.miss_count += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches a count. Counts from readers that were opened before the last refresh are
+    /// ignored, as nothing will look them up
+    pub fn insert(&self, key: RequestCacheKey, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        if key.0 != state.generation || state.max_entries == 0 {
+            return;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some(previous) = state.entries.remove(&key) {
+            state.lru.remove(&previous.last_used);
+        }
+
+        while state.entries.len() >= state.max_entries {
+            let oldest = match state.lru.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            let oldest_key = state.lru.remove(&oldest).unwrap();
+            state.entries.remove(&oldest_key);
+            state.counters.evictions += 1;
+        }
+
+        state.lru.insert(clock, key.clone());
+        state.entries.insert(key, RequestCacheEntry {
+            count: count,
+            last_used: clock,
+        });
+    }
+
+    /// Removes all entries, and starts caching counts for the given refresh generation.
+    /// Called whenever the store is refreshed
+    pub fn invalidate(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.generation = generation;
+        state.entries.clear();
+        state.lru.clear();
+    }
+
+    pub fn statistics(&self) -> RequestCacheStatistics {
+        let state = self.state.lock().unwrap();
+
+        RequestCacheStatistics {
+            cache_size: state.entries.len(),
+            memory_size_in_bytes: state.entries.keys().map(|key| mem::size_of::<RequestCacheKey>() + mem::size_of::<RequestCacheEntry>() + key.1.len()).sum(),
+            ..state.counters.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestCache;
+
+    #[test]
+    fn test_get_and_insert() {
+        let cache = RequestCache::new(10);
+        let key = (0, "query".to_string());
+
+        assert_eq!(cache.get(&key), None);
+        cache.insert(key.clone(), 42);
+        assert_eq!(cache.get(&key), Some(42));
+
+        let stats = cache.statistics();
+        assert_eq!(stats.hit_count, 1);
+        assert_eq!(stats.miss_count, 1);
+        assert_eq!(stats.cache_size, 1);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = RequestCache::new(10);
+        cache.insert((0, "query".to_string()), 42);
+
+        cache.invalidate(1);
+        assert_eq!(cache.get(&(0, "query".to_string())), None);
+        assert_eq!(cache.statistics().cache_size, 0);
+
+        // Counts from readers opened before the refresh aren't cached
+        cache.insert((0, "query".to_string()), 42);
+        assert_eq!(cache.statistics().cache_size, 0);
+
+        cache.insert((1, "query".to_string()), 43);
+        assert_eq!(cache.get(&(1, "query".to_string())), Some(43));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = RequestCache::new(2);
+
+        cache.insert((0, "a".to_string()), 1);
+        cache.insert((0, "b".to_string()), 2);
+
+        // Use "a" so "b" is evicted instead
+        assert!(cache.get(&(0, "a".to_string())).is_some());
+        cache.insert((0, "c".to_string()), 3);
+
+        assert!(cache.get(&(0, "a".to_string())).is_some());
+        assert!(cache.get(&(0, "b".to_string())).is_none());
+        assert!(cache.get(&(0, "c".to_string())).is_some());
+        assert_eq!(cache.statistics().evictions, 1);
+    }
+}
@@ -0,0 +1,46 @@
+use search::query::Query;
+use search::segment::Segment;
+use search::knn::KnnSearch;
+use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+
+use super::super::{RocksDBReader, decode_vector};
+use super::run_plan;
+use super::planner::plan_query;
+
+impl<'a> RocksDBReader<'a> {
+    /// Finds the `k` documents with vectors most similar to the query vector
+    ///
+    /// Returns the ids and similarity scores of the neighbours, most similar first.
+    /// Every document that matches the filter is compared with the query vector, so the
+    /// neighbours are exact. Documents without a vector, or with a vector of a different
+    /// length, are skipped.
+    pub fn knn_search(&self, knn: &KnnSearch) -> Result<Vec<(u64, f32)>, String> {
+        let filter = match knn.filter {
+            Some(ref filter) => filter,
+            None => &Query::All { score: 1.0f32 },
+        };
+        let plan = plan_query(&self, filter, false);
+
+        let mut collector = TopScoreCollector::new(knn.k);
+        for segment in self.store.segments.iter_active(&self) {
+            let matches = try!(run_plan(&plan, &self.store.filter_cache, &segment));
+
+            for doc in matches.iter() {
+                let vector = match try!(segment.load_stored_field_value_raw(doc as u16, knn.field, b"val")).and_then(|value| decode_vector(&value)) {
+                    Some(vector) => vector,
+                    None => continue,
+                };
+
+                if vector.len() != knn.query_vector.len() {
+                    continue;
+                }
+
+                let score = knn.similarity.score(&knn.query_vector, &vector);
+                collector.collect(DocumentMatch::new_scored(segment.doc_id(doc as u16).as_u64(), score));
+            }
+        }
+
+        Ok(collector.into_sorted_vec().iter().map(|doc_match| (doc_match.doc_id(), doc_match.score().unwrap_or(0.0f32))).collect())
+    }
+}
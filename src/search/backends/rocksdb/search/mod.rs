@@ -1,19 +1,36 @@
 mod statistics;
 mod planner;
+mod knn;
+mod terms;
+
+use std::cmp;
+use std::time::Instant;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use roaring::RoaringBitmap;
 use search::segment::Segment;
 use search::query::Query;
 use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+use search::collectors::total_count::TotalCountCollector;
+use search::schema::FieldId;
+use search::term::TermId;
+use search::document::DocId;
+use search::explanation::Explanation;
+use search::profile::{QueryProfile, ProfileBreakdown, duration_to_nanos};
+use search::cancellation::SearchCancellation;
 use byteorder::{ByteOrder, LittleEndian};
 
 use super::RocksDBReader;
+use super::segment::RocksDBSegment;
+use super::filter_cache::FilterCache;
 use self::statistics::{StatisticsReader, RocksDBStatisticsReader};
 use self::planner::{SearchPlan, plan_query};
-use self::planner::boolean_query::BooleanQueryOp;
+use self::planner::boolean_query::{BooleanQueryOp, CachedQuery};
 use self::planner::score_function::{CombinatorScorer, ScoreFunctionOp};
 
-fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated: bool, segment: &S) -> Result<RoaringBitmap, String> {
+fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated: bool, cached_queries: &[CachedQuery], filter_cache: &FilterCache, segment: &S) -> Result<RoaringBitmap, String> {
     // Execute boolean query
     let mut stack = Vec::new();
     for op in boolean_query.iter() {
@@ -27,12 +44,36 @@ fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated
                     None => stack.push(RoaringBitmap::new()),
                 }
             }
+            BooleanQueryOp::PushDocumentList(ref doc_ids) => {
+                let mut doc_id_set = RoaringBitmap::new();
+                for doc_id in doc_ids.iter() {
+                    let doc_id = DocId::from_u64(*doc_id);
+                    if doc_id.0 == segment.id() {
+                        doc_id_set.insert(doc_id.1 as u32);
+                    }
+                }
+
+                stack.push(doc_id_set);
+            }
             BooleanQueryOp::PushDeletionList => {
                     match try!(segment.load_deletion_list()) {
                     Some(doc_id_set) => stack.push(doc_id_set),
                     None => stack.push(RoaringBitmap::new()),
                 }
             }
+            BooleanQueryOp::PushCachedQuery(index) => {
+                let cached_query = &cached_queries[index];
+                let key = (segment.id().0, cached_query.key.clone());
+
+                match filter_cache.get(&key) {
+                    Some(doc_id_set) => stack.push(doc_id_set),
+                    None => {
+                        let doc_id_set = try!(run_boolean_query(&cached_query.boolean_query, cached_query.is_negated, &[], filter_cache, segment));
+                        filter_cache.insert(key, doc_id_set.clone());
+                        stack.push(doc_id_set);
+                    }
+                }
+            }
             BooleanQueryOp::And => {
                 let b = stack.pop().expect("boolean query executor: stack underflow");
                 let a = stack.last_mut().expect("boolean query executor: stack underflow");
@@ -76,42 +117,63 @@ fn run_boolean_query<S: Segment>(boolean_query: &Vec<BooleanQueryOp>, is_negated
     Ok(matches)
 }
 
+/// Reads the frequency of a term in a document, along with the length of the field
+///
+/// Returns None if the document doesn't contain the term
+fn read_term_frequency<S: Segment>(doc_id: u16, field_id: FieldId, term_id: TermId, segment: &S) -> Result<Option<(u32, f32)>, String> {
+    // TODO: Check this isn't really slow
+    match try!(segment.load_postings_list(field_id, term_id)) {
+        Some(postings) => {
+            if !postings.contains(doc_id as u32) {
+                return Ok(None);
+            }
+        }
+        None => return Ok(None),
+    }
+
+    // Read field length
+    // TODO: we only need this for BM25
+    let field_length_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, b"len"));
+    let field_length = match field_length_raw {
+        Some(value) => {
+            let length_sqrt = (value[0] as f32) / 3.0 + 1.0;
+            length_sqrt * length_sqrt
+        }
+        None => 1.0
+    };
+
+    // Read term frequency
+    let mut value_type = vec![b't', b'f'];
+    value_type.extend(term_id.0.to_string().as_bytes());
+    let term_frequency_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, &value_type));
+    let term_frequency = match term_frequency_raw {
+        Some(value) => LittleEndian::read_i64(&value),
+        None => 1,
+    };
+
+    Ok(Some((term_frequency as u32, field_length)))
+}
+
+/// Finds a document's score in a list of document ids and scores. Documents that aren't in the list score 0
+fn lookup_document_score(scores: &[(u64, f32)], doc_id: DocId) -> f32 {
+    match scores.binary_search_by_key(&doc_id.as_u64(), |&(doc_id, _)| doc_id) {
+        Ok(index) => scores[index].1,
+        Err(_) => 0.0f32,
+    }
+}
+
 fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<ScoreFunctionOp>, segment: &S, stats: &mut R) -> Result<f32, String> {
     // Execute score function
     let mut stack = Vec::new();
     for op in score_function.iter() {
         match *op {
             ScoreFunctionOp::Literal(val) => stack.push(val),
+            ScoreFunctionOp::DocumentScore(ref scores) => stack.push(lookup_document_score(scores, segment.doc_id(doc_id))),
             ScoreFunctionOp::TermScorer(field_id, term_id, ref scorer) => {
-                // TODO: Check this isn't really slow
-                match try!(segment.load_postings_list(field_id, term_id)) {
-                    Some(postings) => {
-                        if postings.contains(doc_id as u32) {
-                            // Read field length
-                            // TODO: we only need this for BM25
-                            let field_length_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, b"len"));
-                            let field_length = match field_length_raw {
-                                Some(value) => {
-                                    let length_sqrt = (value[0] as f32) / 3.0 + 1.0;
-                                    length_sqrt * length_sqrt
-                                }
-                                None => 1.0
-                            };
-
-                            // Read term frequency
-                            let mut value_type = vec![b't', b'f'];
-                            value_type.extend(term_id.0.to_string().as_bytes());
-                            let term_frequency_raw = try!(segment.load_stored_field_value_raw(doc_id, field_id, &value_type));
-                            let term_frequency = match term_frequency_raw {
-                                Some(value) => LittleEndian::read_i64(&value),
-                                None => 1,
-                            };
-
-                            let score = scorer.similarity_model.score(term_frequency as u32, field_length, try!(stats.total_tokens(field_id)) as u64, try!(stats.total_docs(field_id)) as u64, try!(stats.term_document_frequency(field_id, term_id)) as u64);
-                            stack.push(score * scorer.boost);
-                        } else {
-                            stack.push(0.0f32);
-                        }
+                match try!(read_term_frequency(doc_id, field_id, term_id, segment)) {
+                    Some((term_frequency, field_length)) => {
+                        let score = scorer.similarity_model.score(term_frequency, field_length, try!(stats.total_tokens(field_id)) as u64, try!(stats.total_docs(field_id)) as u64, try!(stats.term_document_frequency(field_id, term_id)) as u64);
+                        stack.push(score * scorer.boost);
                     }
                     None => stack.push(0.0f32),
                 }
@@ -154,11 +216,70 @@ fn score_doc<S: Segment, R: StatisticsReader>(doc_id: u16, score_function: &Vec<
     Ok(stack.pop().expect("document scorer: stack underflow"))
 }
 
-fn search_segment<C: Collector, S: Segment, R: StatisticsReader>(collector: &mut C, plan: &SearchPlan, segment: &S, stats: &mut R) -> Result<(), String> {
-    let matches = try!(run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, segment));
+/// Runs the score function in the same way as `score_doc`, but builds an explanation of each step
+fn explain_doc<S: Segment, R: StatisticsReader, D: Fn(FieldId, TermId) -> String>(doc_id: u16, score_function: &Vec<ScoreFunctionOp>, segment: &S, stats: &mut R, describe_term: D) -> Result<Explanation, String> {
+    let mut stack: Vec<Explanation> = Vec::new();
+    for op in score_function.iter() {
+        match *op {
+            ScoreFunctionOp::Literal(val) => stack.push(Explanation::new(val, "constant score")),
+            ScoreFunctionOp::DocumentScore(ref scores) => stack.push(Explanation::new(lookup_document_score(scores, segment.doc_id(doc_id)), "precomputed document score")),
+            ScoreFunctionOp::TermScorer(field_id, term_id, ref scorer) => {
+                let description = format!("weight({})", describe_term(field_id, term_id));
+
+                match try!(read_term_frequency(doc_id, field_id, term_id, segment)) {
+                    Some((term_frequency, field_length)) => {
+                        let explanation = scorer.similarity_model.explain(term_frequency, field_length, try!(stats.total_tokens(field_id)) as u64, try!(stats.total_docs(field_id)) as u64, try!(stats.term_document_frequency(field_id, term_id)) as u64);
+                        let value = explanation.value * scorer.boost;
+
+                        stack.push(Explanation::with_details(value, format!("{}, product of:", description), vec![
+                            Explanation::new(scorer.boost, "boost"),
+                            explanation,
+                        ]));
+                    }
+                    None => stack.push(Explanation::new(0.0f32, format!("{}, no matching term", description))),
+                }
+            }
+            ScoreFunctionOp::CombinatorScorer(num_vals, ref scorer) => {
+                let mut details = Vec::with_capacity(num_vals as usize);
+                for _ in 0..num_vals {
+                    details.push(stack.pop().expect("document explainer: stack underflow"));
+                }
+                details.reverse();
+
+                let explanation = match *scorer {
+                    CombinatorScorer::Avg => {
+                        let total_score = details.iter().fold(0.0f32, |total, detail| total + detail.value);
+                        Explanation::with_details(total_score / num_vals as f32, "avg of:", details)
+                    }
+                    CombinatorScorer::Max => {
+                        let max_score = details.iter().fold(0.0f32, |max, detail| if detail.value > max { detail.value } else { max });
+                        Explanation::with_details(max_score, "max of:", details)
+                    }
+                };
+
+                stack.push(explanation);
+            }
+        }
+    }
+
+    Ok(stack.pop().expect("document explainer: stack underflow"))
+}
+
+/// Finds the documents in the segment that match the plan's boolean query
+fn run_plan<S: Segment>(plan: &SearchPlan, filter_cache: &FilterCache, segment: &S) -> Result<RoaringBitmap, String> {
+    run_boolean_query(&plan.boolean_query, plan.boolean_query_is_negated, &plan.cached_queries, filter_cache, segment)
+}
+
+/// Searches a segment, passing matches to the collector. Returns false if the search was stopped before it finished
+fn search_segment<C: Collector, S: Segment, R: StatisticsReader>(collector: &mut C, plan: &SearchPlan, filter_cache: &FilterCache, segment: &S, stats: &mut R, cancellation: &SearchCancellation) -> Result<bool, String> {
+    let matches = try!(run_plan(plan, filter_cache, segment));
 
     // Score documents and pass to collector
     for doc in matches.iter() {
+        if cancellation.should_stop() {
+            return Ok(false);
+        }
+
         let score = try!(score_doc(doc as u16, &plan.score_function, segment, stats));
 
         let doc_id = segment.doc_id(doc as u16);
@@ -166,22 +287,294 @@ fn search_segment<C: Collector, S: Segment, R: StatisticsReader>(collector: &mut
         collector.collect(doc_match);
     }
 
-    Ok(())
+    Ok(true)
+}
+
+/// Holds the matches found in a segment by a search thread, until they can be passed to the real collector
+///
+/// If the real collector only keeps the highest scoring documents, only that many are kept
+/// from each segment too, so segments with a lot of matches don't need them all in memory.
+enum SegmentMatches {
+    All {
+        needs_score: bool,
+        matches: Vec<DocumentMatch>,
+    },
+    Top(TopScoreCollector),
+}
+
+impl SegmentMatches {
+    /// `top_docs` and `needs_score` are taken from the real collector
+    fn new(top_docs: Option<&TopScoreCollector>, needs_score: bool) -> SegmentMatches {
+        match top_docs {
+            Some(top_docs) => SegmentMatches::Top(top_docs.clone()),
+            None => SegmentMatches::All {
+                needs_score: needs_score,
+                matches: Vec::new(),
+            },
+        }
+    }
+
+    /// Passes the matches on to the real collector
+    fn merge_into<C: Collector>(self, collector: &mut C) {
+        match self {
+            SegmentMatches::All{matches, ..} => {
+                for doc_match in matches {
+                    collector.collect(doc_match);
+                }
+            }
+            SegmentMatches::Top(top) => {
+                let total_hits = top.total_hits();
+                let max_score = top.max_score();
+                let matches = top.into_sorted_vec();
+                collector.collect_skipped(total_hits - matches.len() as u64, max_score);
+
+                for doc_match in matches {
+                    collector.collect(doc_match);
+                }
+            }
+        }
+    }
+}
+
+impl Collector for SegmentMatches {
+    fn needs_score(&self) -> bool {
+        match *self {
+            SegmentMatches::All{needs_score, ..} => needs_score,
+            SegmentMatches::Top(ref top) => top.needs_score(),
+        }
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        match *self {
+            SegmentMatches::All{ref mut matches, ..} => matches.push(doc),
+            SegmentMatches::Top(ref mut top) => top.collect(doc),
+        }
+    }
 }
 
 impl<'a> RocksDBReader<'a> {
     pub fn search<C: Collector>(&self, collector: &mut C, query: &Query) -> Result<(), String> {
+        try!(self.search_cancellable(collector, query, &SearchCancellation::new()));
+        Ok(())
+    }
+
+    /// Runs a search that can be stopped early by a timeout or cancellation
+    ///
+    /// Returns false if the search was stopped. The collector will have been given the
+    /// documents that were matched up to that point.
+    pub fn search_cancellable<C: Collector>(&self, collector: &mut C, query: &Query, cancellation: &SearchCancellation) -> Result<bool, String> {
+        self.store.activity.query.track(|| self.search_segments(collector, query, cancellation))
+    }
+
+    /// Counts the documents that match a query
+    ///
+    /// Counts are cached until the store is next refreshed, so repeating the same count
+    /// (eg, of a tenant or status filter) only runs the query once per refresh.
+    pub fn count(&self, query: &Query) -> Result<u64, String> {
+        let key = self.refresh_generation.map(|refresh_generation| (refresh_generation, format!("{:?}", query)));
+
+        if let Some(ref key) = key {
+            if let Some(count) = self.store.request_cache.get(key) {
+                return Ok(count);
+            }
+        }
+
+        let mut collector = TotalCountCollector::new();
+        try!(self.search(&mut collector, query));
+        let count = collector.get_total_count();
+
+        if let Some(key) = key {
+            self.store.request_cache.insert(key, count);
+        }
+
+        Ok(count)
+    }
+
+    fn search_segments<C: Collector>(&self, collector: &mut C, query: &Query, cancellation: &SearchCancellation) -> Result<bool, String> {
         // Plan query
         let plan = plan_query(&self, query, collector.needs_score());
 
-        // Initialise statistics reader
+        let segments = self.store.segments.iter_active(&self).map(|segment| segment.id().0).collect::<Vec<_>>();
+        let num_threads = cmp::min(self.store.search_threads, segments.len());
+
+        let search_pool = match self.store.search_pool {
+            Some(ref search_pool) if num_threads > 1 => search_pool,
+            _ => {
+                // Initialise statistics reader
+                let mut stats = RocksDBStatisticsReader::new(&self);
+
+                // Run query on each segment
+                for segment_id in segments {
+                    if !try!(search_segment(collector, &plan, &self.store.filter_cache, &RocksDBSegment::new(&self, segment_id), &mut stats, cancellation)) {
+                        return Ok(false);
+                    }
+                }
+
+                return Ok(true);
+            }
+        };
+
+        // Search the segments in parallel on the search pool. Each thread takes the next
+        // segment that hasn't been searched yet until there are none left
+        let top_docs = collector.top_docs();
+        let needs_score = collector.needs_score();
+        let next_segment = AtomicUsize::new(0);
+        let stopped = AtomicBool::new(false);
+        let segment_results = Mutex::new(Vec::with_capacity(segments.len()));
+        let error = Mutex::new(None);
+        let search_next_segments = || {
+            let mut stats = RocksDBStatisticsReader::new(&self);
+
+            while !stopped.load(Ordering::SeqCst) {
+                let segment_index = next_segment.fetch_add(1, Ordering::SeqCst);
+                let segment_id = match segments.get(segment_index) {
+                    Some(segment_id) => *segment_id,
+                    None => break,
+                };
+
+                let mut segment_matches = SegmentMatches::new(top_docs.as_ref(), needs_score);
+                match search_segment(&mut segment_matches, &plan, &self.store.filter_cache, &RocksDBSegment::new(&self, segment_id), &mut stats, cancellation) {
+                    Ok(segment_finished) => {
+                        segment_results.lock().unwrap().push((segment_index, segment_matches));
+
+                        if !segment_finished {
+                            stopped.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    Err(e) => {
+                        *error.lock().unwrap() = Some(e);
+                        stopped.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+        };
+        search_pool.run_with_helpers(num_threads - 1, &search_next_segments);
+
+        if let Some(e) = error.into_inner().unwrap() {
+            return Err(e);
+        }
+
+        // Merge the matches into the collector in segment order, so results are the same as a serial search
+        let mut segment_results = segment_results.into_inner().unwrap();
+        segment_results.sort_by_key(|&(segment_index, _)| segment_index);
+
+        for (_, segment_matches) in segment_results {
+            segment_matches.merge_into(collector);
+        }
+
+        Ok(!stopped.into_inner())
+    }
+
+    /// Returns the ids of all documents that match the query, sorted
+    pub fn matching_documents(&self, query: &Query) -> Result<Vec<u64>, String> {
+        let plan = plan_query(&self, query, false);
+
+        let mut doc_ids = Vec::new();
+        for segment in self.store.segments.iter_active(&self) {
+            let matches = try!(run_plan(&plan, &self.store.filter_cache, &segment));
+            doc_ids.extend(matches.iter().map(|doc| segment.doc_id(doc as u16).as_u64()));
+        }
+
+        doc_ids.sort();
+        Ok(doc_ids)
+    }
+
+    /// Checks whether a document matches the query, without scoring it
+    pub fn document_matches(&self, query: &Query, doc_id: DocId) -> Result<bool, String> {
+        let plan = plan_query(&self, query, false);
+
+        let segment = match self.store.segments.iter_active(&self).find(|segment| segment.id() == doc_id.0) {
+            Some(segment) => segment,
+            None => return Ok(false),
+        };
+
+        let matches = try!(run_plan(&plan, &self.store.filter_cache, &segment));
+        Ok(matches.contains(doc_id.1 as u32))
+    }
+
+    /// Explains how the query scores a document
+    ///
+    /// Returns None if the document doesn't match the query
+    pub fn explain(&self, query: &Query, doc_id: DocId) -> Result<Option<Explanation>, String> {
+        let plan = plan_query(&self, query, true);
+        let mut stats = RocksDBStatisticsReader::new(&self);
+
+        let segment = match self.store.segments.iter_active(&self).find(|segment| segment.id() == doc_id.0) {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+
+        let matches = try!(run_plan(&plan, &self.store.filter_cache, &segment));
+        if !matches.contains(doc_id.1 as u32) {
+            return Ok(None);
+        }
+
+        let explanation = try!(explain_doc(doc_id.1, &plan.score_function, &segment, &mut stats, |field_id, term_id| {
+            let field_name = self.schema().get(&field_id).map(|field_info| field_info.name().to_string()).unwrap_or_else(|| field_id.0.to_string());
+            let term = match self.store.term_dictionary.get_term(term_id) {
+                Some(term) => String::from_utf8_lossy(term.as_bytes()).into_owned(),
+                None => term_id.0.to_string(),
+            };
+
+            format!("{}:{}", field_name, term)
+        }));
+
+        Ok(Some(explanation))
+    }
+
+    /// Times each node of the query, from the root down
+    ///
+    /// Each node is planned and run on its own against every segment, so the timings of
+    /// a node include the nodes beneath it
+    pub fn profile(&self, query: &Query, score: bool) -> Result<QueryProfile, String> {
+        let mut breakdown = ProfileBreakdown::default();
+
+        let start = Instant::now();
+        let plan = plan_query(&self, query, score);
+        breakdown.build = duration_to_nanos(start.elapsed());
+
         let mut stats = RocksDBStatisticsReader::new(&self);
 
-        // Run query on each segment
         for segment in self.store.segments.iter_active(&self) {
-            try!(search_segment(collector, &plan, &segment, &mut stats));
+            let start = Instant::now();
+            let matches = try!(run_plan(&plan, &self.store.filter_cache, &segment));
+            breakdown.match_ += duration_to_nanos(start.elapsed());
+            breakdown.match_count += matches.len() as u64;
+
+            if score {
+                let start = Instant::now();
+                for doc in matches.iter() {
+                    try!(score_doc(doc as u16, &plan.score_function, &segment, &mut stats));
+                }
+                breakdown.score += duration_to_nanos(start.elapsed());
+            }
         }
 
-        Ok(())
+        let mut children = Vec::new();
+        for child in query.children() {
+            children.push(try!(self.profile(child, score)));
+        }
+
+        Ok(QueryProfile {
+            query_type: query.type_name().to_string(),
+            description: self.describe_query(query),
+            time_in_nanos: breakdown.build + breakdown.match_ + breakdown.score,
+            breakdown: breakdown,
+            children: children,
+        })
+    }
+
+    fn describe_query(&self, query: &Query) -> String {
+        let field_name = |field_id: FieldId| {
+            self.schema().get(&field_id).map(|field_info| field_info.name().to_string()).unwrap_or_else(|| field_id.0.to_string())
+        };
+
+        match *query {
+            Query::All{..} => "*:*".to_string(),
+            Query::None => "".to_string(),
+            Query::Term{field, ref term, ..} => format!("{}:{}", field_name(field), String::from_utf8_lossy(term.as_bytes())),
+            Query::MultiTerm{field, ref term_selector, ..} => format!("{}:{:?}", field_name(field), term_selector),
+            _ => format!("{} clauses", query.children().len()),
+        }
     }
 }
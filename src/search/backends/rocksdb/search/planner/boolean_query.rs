@@ -11,6 +11,12 @@ pub enum BooleanQueryOp {
     PushEmpty,
     PushPostingsList(FieldId, TermId),
     PushDeletionList,
+
+    /// Pushes the documents matching one of the plan's cached queries. See `CachedQuery`
+    PushCachedQuery(usize),
+
+    /// Pushes the documents in the list that are in the segment being searched
+    PushDocumentList(Vec<u64>),
     And,
     Or,
     AndNot,
@@ -73,17 +79,62 @@ impl BooleanQueryBlock {
     }
 }
 
+/// A part of a query whose matches in each segment are kept in the store's filter cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedQuery {
+    /// Identifies the query in the cache
+    pub key: String,
+    pub boolean_query: Vec<BooleanQueryOp>,
+    pub is_negated: bool,
+}
+
 pub struct BooleanQueryBuilder {
     stack: Vec<Rc<BooleanQueryBlock>>,
+
+    /// Queries to run through the filter cache. None if caching is disabled
+    cached_queries: Option<Vec<CachedQuery>>,
 }
 
 impl BooleanQueryBuilder {
     pub fn new() -> BooleanQueryBuilder {
         BooleanQueryBuilder {
             stack: Vec::new(),
+            cached_queries: None,
+        }
+    }
+
+    /// Creates a builder that plans filters so their results can be cached
+    pub fn with_cache() -> BooleanQueryBuilder {
+        BooleanQueryBuilder {
+            stack: Vec::new(),
+            cached_queries: Some(Vec::new()),
         }
     }
 
+    /// Returns the queries that need to be run through the filter cache
+    pub fn cached_queries(&self) -> Vec<CachedQuery> {
+        self.cached_queries.clone().unwrap_or_else(Vec::new)
+    }
+
+    pub fn push_cached_query(&mut self, cached_query: CachedQuery) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        let index = match self.cached_queries {
+            Some(ref mut cached_queries) => {
+                cached_queries.push(cached_query);
+                cached_queries.len() - 1
+            }
+            None => panic!("push_cached_query called on a builder without a cache"),
+        };
+
+        self.stack.push(Rc::new(Leaf{
+            op: PushCachedQuery(index),
+            return_type: Sparse,
+        }));
+    }
+
     pub fn push_empty(&mut self) {
         use self::BooleanQueryOp::*;
         use self::BooleanQueryBlock::*;
@@ -117,6 +168,22 @@ impl BooleanQueryBuilder {
         }));
     }
 
+    pub fn push_document_list(&mut self, doc_ids: Vec<u64>) {
+        use self::BooleanQueryOp::*;
+        use self::BooleanQueryBlock::*;
+        use self::BooleanQueryBlockReturnType::*;
+
+        if doc_ids.is_empty() {
+            self.push_empty();
+            return;
+        }
+
+        self.stack.push(Rc::new(Leaf{
+            op: PushDocumentList(doc_ids),
+            return_type: Sparse,
+        }));
+    }
+
     pub fn push_deletion_list(&mut self) {
         use self::BooleanQueryOp::*;
         use self::BooleanQueryBlock::*;
@@ -353,6 +420,31 @@ fn plan_boolean_query_combinator<J: Fn(&mut BooleanQueryBuilder) -> ()> (index_r
     }
 }
 
+/// Plans a query that doesn't affect scoring, running it through the filter cache if it's enabled
+pub fn plan_filter(index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, filter: &Query) {
+    if builder.cached_queries.is_none() {
+        plan_boolean_query(index_reader, &mut builder, filter);
+        return;
+    }
+
+    let mut filter_builder = BooleanQueryBuilder::new();
+    plan_boolean_query(index_reader, &mut filter_builder, filter);
+
+    // Filters that match everything or nothing are cheaper to run than to cache
+    match filter_builder.stack.last().map(|block| block.return_type()) {
+        Some(BooleanQueryBlockReturnType::Full) => builder.push_full(),
+        Some(BooleanQueryBlockReturnType::Empty) => builder.push_empty(),
+        _ => {
+            let (boolean_query, is_negated) = filter_builder.build();
+            builder.push_cached_query(CachedQuery {
+                key: format!("{:?}", filter),
+                boolean_query: boolean_query,
+                is_negated: is_negated,
+            });
+        }
+    }
+}
+
 pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut BooleanQueryBuilder, query: &Query) {
     match *query {
         Query::All{..} => {
@@ -393,7 +485,7 @@ pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut Boolea
         }
         Query::Filter{ref query, ref filter} => {
             plan_boolean_query(index_reader, &mut builder, query);
-            plan_boolean_query(index_reader, &mut builder, filter);
+            plan_filter(index_reader, &mut builder, filter);
             builder.and_combinator();
         }
         Query::Exclude{ref query, ref exclude} => {
@@ -401,6 +493,9 @@ pub fn plan_boolean_query(index_reader: &RocksDBReader, mut builder: &mut Boolea
             plan_boolean_query(index_reader, &mut builder, exclude);
             builder.andnot_combinator();
         }
+        Query::DocumentScores{ref scores} => {
+            builder.push_document_list(scores.iter().map(|&(doc_id, _)| doc_id).collect());
+        }
     }
 }
 
@@ -4,13 +4,14 @@ pub mod score_function;
 use search::Query;
 
 use super::super::RocksDBReader;
-use self::boolean_query::{BooleanQueryOp, BooleanQueryBuilder, plan_boolean_query};
+use self::boolean_query::{BooleanQueryOp, BooleanQueryBuilder, CachedQuery, plan_boolean_query, plan_filter};
 use self::score_function::{ScoreFunctionOp, plan_score_function};
 
 #[derive(Debug)]
 pub struct SearchPlan {
     pub boolean_query: Vec<BooleanQueryOp>,
     pub boolean_query_is_negated: bool,
+    pub cached_queries: Vec<CachedQuery>,
     pub score_function: Vec<ScoreFunctionOp>,
 }
 
@@ -19,6 +20,7 @@ impl SearchPlan {
         SearchPlan {
             boolean_query: Vec::new(),
             boolean_query_is_negated: false,
+            cached_queries: Vec::new(),
             score_function: Vec::new(),
         }
     }
@@ -28,8 +30,13 @@ pub fn plan_query(index_reader: &RocksDBReader, query: &Query, score: bool) -> S
     let mut plan = SearchPlan::new();
 
     // Plan boolean query
-    let mut builder = BooleanQueryBuilder::new();
-    plan_boolean_query(index_reader, &mut builder, query);
+    let mut builder = BooleanQueryBuilder::with_cache();
+    if score {
+        plan_boolean_query(index_reader, &mut builder, query);
+    } else {
+        // Nothing is scored, so the whole query can be cached like a filter. This speeds up repeated counts
+        plan_filter(index_reader, &mut builder, query);
+    }
 
     // Add operations to exclude deleted documents to boolean query
     builder.push_deletion_list();
@@ -38,6 +45,7 @@ pub fn plan_query(index_reader: &RocksDBReader, query: &Query, score: bool) -> S
     let (boolean_query, boolean_query_is_negated) = builder.build();
     plan.boolean_query = boolean_query;
     plan.boolean_query_is_negated = boolean_query_is_negated;
+    plan.cached_queries = builder.cached_queries();
 
     // Plan score function
     if score {
@@ -16,6 +16,9 @@ pub enum ScoreFunctionOp {
     Literal(f32),
     TermScorer(FieldId, TermId, TermScorer),
     CombinatorScorer(u32, CombinatorScorer),
+
+    /// Looks the document's score up in a list of document ids and scores, sorted by id
+    DocumentScore(Vec<(u64, f32)>),
 }
 
 fn plan_score_function_combinator(index_reader: &RocksDBReader, mut score_function: &mut Vec<ScoreFunctionOp>, queries: &Vec<Query>, scorer: CombinatorScorer) {
@@ -92,5 +95,8 @@ pub fn plan_score_function(index_reader: &RocksDBReader, mut score_function: &mu
         Query::Exclude{ref query, ..} => {
             plan_score_function(index_reader, &mut score_function, query);
         }
+        Query::DocumentScores{ref scores} => {
+            score_function.push(ScoreFunctionOp::DocumentScore(scores.clone()));
+        }
     }
 }
@@ -0,0 +1,55 @@
+use std::str;
+
+use search::term::Term;
+use search::schema::FieldId;
+
+use super::super::RocksDBReader;
+use super::statistics::{StatisticsReader, RocksDBStatisticsReader};
+
+impl<'a> RocksDBReader<'a> {
+    /// Returns the number of documents that contain the term in the field
+    pub fn term_document_frequency(&self, field_id: FieldId, term: &Term) -> Result<u64, String> {
+        let term_id = match self.store.term_dictionary.get(term) {
+            Some(term_id) => term_id,
+            None => return Ok(0),
+        };
+
+        let mut stats = RocksDBStatisticsReader::new(&self);
+        Ok(try!(stats.term_document_frequency(field_id, term_id)).max(0) as u64)
+    }
+
+    /// Returns the number of documents that have a value in the field, and the total number
+    /// of tokens in the field across all documents
+    pub fn field_statistics(&self, field_id: FieldId) -> Result<(u64, u64), String> {
+        let mut stats = RocksDBStatisticsReader::new(&self);
+        let total_docs = try!(stats.total_docs(field_id)).max(0) as u64;
+        let total_tokens = try!(stats.total_tokens(field_id)).max(0) as u64;
+
+        Ok((total_docs, total_tokens))
+    }
+
+    /// Finds the text terms in a field that the predicate returns true for, along with
+    /// the number of documents that contain each of them
+    ///
+    /// Terms that aren't valid UTF-8 or aren't in any document in the field are skipped.
+    pub fn find_field_terms<F: FnMut(&str) -> bool>(&self, field_id: FieldId, mut predicate: F) -> Result<Vec<(String, u64)>, String> {
+        let candidates = self.store.term_dictionary.filter(|term| {
+            match str::from_utf8(term.as_bytes()) {
+                Ok(text) => predicate(text),
+                Err(_) => false,
+            }
+        });
+
+        let mut stats = RocksDBStatisticsReader::new(&self);
+        let mut terms = Vec::new();
+        for (term, term_id) in candidates {
+            let document_frequency = try!(stats.term_document_frequency(field_id, term_id));
+
+            if document_frequency > 0 {
+                terms.push((String::from_utf8(term.as_bytes().to_vec()).unwrap(), document_frequency as u64));
+            }
+        }
+
+        Ok(terms)
+    }
+}
@@ -3,7 +3,6 @@ use std::collections::HashMap;
 use search::{Document, Term, TermId};
 use search::schema::FieldId;
 use search::segment::{SegmentId, Segment};
-use byteorder::{LittleEndian, WriteBytesExt};
 use roaring::RoaringBitmap;
 use fnv::FnvHashMap;
 
@@ -17,6 +16,11 @@ pub struct SegmentBuilder {
     pub postings_lists: FnvHashMap<(FieldId, TermId), RoaringBitmap>,
     pub statistics: FnvHashMap<Vec<u8>, i64>,
     pub stored_field_values: FnvHashMap<(FieldId, u16, Vec<u8>), Vec<u8>>,
+
+    /// Statistics and values that are keyed by term are kept separately, as the builder's
+    /// term ids must be replaced with the store's when the segment is written
+    pub term_document_frequencies: FnvHashMap<(FieldId, TermId), i64>,
+    pub term_frequencies: FnvHashMap<(FieldId, u16, TermId), i64>,
 }
 
 #[derive(Debug)]
@@ -34,6 +38,8 @@ impl SegmentBuilder {
             postings_lists: FnvHashMap::default(),
             statistics: FnvHashMap::default(),
             stored_field_values: FnvHashMap::default(),
+            term_document_frequencies: FnvHashMap::default(),
+            term_frequencies: FnvHashMap::default(),
         }
     }
 
@@ -79,18 +85,11 @@ impl SegmentBuilder {
                 // 1 is by far the most common frequency. At search time, we interpret a missing
                 // key as meaning there is a term frequency of 1
                 if frequency != 1 {
-                    let mut value_type = vec![b't', b'f'];
-                    value_type.extend(term_id.0.to_string().as_bytes());
-
-                    let mut frequency_bytes: Vec<u8> = Vec::new();
-                    frequency_bytes.write_i64::<LittleEndian>(frequency as i64).unwrap();
-
-                    self.stored_field_values.insert((*field_id, doc_id, value_type), frequency_bytes);
+                    self.term_frequencies.insert((*field_id, doc_id, term_id), frequency as i64);
                 }
 
                 // Increment term document frequency
-                let stat_name = KeyBuilder::segment_stat_term_doc_frequency_stat_name(field_id.0, term_id.0);
-                let stat = self.statistics.entry(stat_name).or_insert(0);
+                let stat = self.term_document_frequencies.entry((*field_id, term_id)).or_insert(0);
                 *stat += 1;
             }
 
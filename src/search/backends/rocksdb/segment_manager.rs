@@ -60,6 +60,26 @@ impl SegmentManager {
     }
 }
 
+/// Reads the list of segments that have been written but are waiting for a refresh
+pub fn read_pending_segments(db: &DB) -> Result<Vec<u32>, rocksdb::Error> {
+    let mut pending_segments = Vec::new();
+    let mut iter = db.raw_iterator();
+    iter.seek(b"n");
+    while iter.valid() {
+        let k = iter.key().unwrap();
+
+        if k[0] != b'n' {
+            break;
+        }
+
+        pending_segments.push(str::from_utf8(&k[1..]).unwrap().parse::<u32>().unwrap());
+
+        iter.next();
+    }
+
+    Ok(pending_segments)
+}
+
 pub struct ActiveSegmentsIterator<'a> {
     reader: &'a RocksDBReader<'a>,
     iter: DBRawIterator,
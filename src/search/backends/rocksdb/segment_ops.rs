@@ -1,5 +1,7 @@
 use std::str;
 use std::io::Cursor;
+use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rocksdb::{self, WriteBatch, WriteOptions};
 use roaring::RoaringBitmap;
@@ -32,7 +34,41 @@ impl From<SegmentMergeError> for String {
     }
 }
 
+/// Counters that keep track of merge activity in a store
+#[derive(Debug, Default)]
+pub struct MergeCounters {
+    current: AtomicUsize,
+    total: AtomicUsize,
+    total_docs: AtomicUsize,
+    total_time_in_millis: AtomicUsize,
+}
+
+/// A snapshot of a store's merge counters
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeStatistics {
+    /// Number of merges currently running
+    pub current: usize,
+
+    /// Number of merges that have completed
+    pub total: usize,
+
+    /// Number of documents that have been merged by completed merges
+    pub total_docs: usize,
+
+    /// Time spent on completed merges
+    pub total_time_in_millis: usize,
+}
+
 impl RocksDBStore {
+    pub fn merge_statistics(&self) -> MergeStatistics {
+        MergeStatistics {
+            current: self.merge_counters.current.load(Ordering::SeqCst),
+            total: self.merge_counters.total.load(Ordering::SeqCst),
+            total_docs: self.merge_counters.total_docs.load(Ordering::SeqCst),
+            total_time_in_millis: self.merge_counters.total_time_in_millis.load(Ordering::SeqCst),
+        }
+    }
+
     fn merge_segment_data(&self, source_segments: &Vec<u32>, dest_segment: u32, doc_id_mapping: &FnvHashMap<DocId, u16>) -> Result<(), SegmentMergeError> {
         // Put source_segments in a FnvHashSet as this is much faster for performing contains queries against
         let source_segments_btree = source_segments.iter().collect::<FnvHashSet<_>>();
@@ -235,6 +271,27 @@ impl RocksDBStore {
     }
 
     pub fn merge_segments(&self, source_segments: &Vec<u32>) -> Result<u32, SegmentMergeError> {
+        let started = Instant::now();
+        self.merge_counters.current.fetch_add(1, Ordering::SeqCst);
+
+        let result = self.merge_segments_inner(source_segments);
+
+        self.merge_counters.current.fetch_sub(1, Ordering::SeqCst);
+
+        if let Ok((_, num_docs)) = result {
+            let elapsed = started.elapsed();
+            let elapsed_millis = elapsed.as_secs() as usize * 1000 + elapsed.subsec_nanos() as usize / 1000000;
+
+            self.merge_counters.total.fetch_add(1, Ordering::SeqCst);
+            self.merge_counters.total_docs.fetch_add(num_docs as usize, Ordering::SeqCst);
+            self.merge_counters.total_time_in_millis.fetch_add(elapsed_millis, Ordering::SeqCst);
+        }
+
+        result.map(|(dest_segment, _)| dest_segment)
+    }
+
+    /// Merges the segments, returning the new segment's id and the number of documents in it
+    fn merge_segments_inner(&self, source_segments: &Vec<u32>) -> Result<(u32, u32), SegmentMergeError> {
         let dest_segment = try!(self.segments.new_segment(&self.db));
 
         // Generate a mapping between the ids of the documents in the old segments to the new one
@@ -276,6 +333,11 @@ impl RocksDBStore {
         // have to clean up.
         try!(self.merge_segment_data(&source_segments, dest_segment, &doc_id_mapping));
 
+        // The segment data skipped the write-ahead log, so it must be on disk before the
+        // merge is committed. Otherwise a crash could leave the new segment active without
+        // its data
+        try!(self.flush());
+
         // Commit the merge
         // This activates the new segment and updates the document index. Effectively committing
         // the merge.
@@ -285,13 +347,19 @@ impl RocksDBStore {
         // the new segment).
         try!(self.commit_segment_merge(&source_segments, dest_segment, &doc_id_mapping));
 
-        Ok(dest_segment)
+        // The source segments can be purged once no readers are using them
+        self.readers.retire_segments(source_segments.clone());
+
+        Ok((dest_segment, current_doc_id))
     }
 
     pub fn purge_segments(&self, segments: &Vec<u32>) -> Result<(), rocksdb::Error> {
         // Put segments in a FnvHashSet as this is much faster for performing contains queries against
         let segments_btree = segments.iter().collect::<FnvHashSet<_>>();
 
+        // Cached filter results for these segments are no longer needed
+        self.filter_cache.remove_segments(segments);
+
         let mut write_options = WriteOptions::default();
         write_options.set_sync(false);
         write_options.disable_wal(true);
@@ -1,6 +1,14 @@
+use std::str;
+use std::mem;
+
 use search::segment::Segment;
+use fnv::FnvHashMap;
 
 use super::RocksDBStore;
+use super::segment_ops::MergeStatistics;
+use super::filter_cache::FilterCacheStatistics;
+use super::request_cache::RequestCacheStatistics;
+use super::activity_counters::ActivityStatistics;
 
 #[derive(Debug)]
 pub struct SegmentStatistics {
@@ -30,6 +38,48 @@ impl SegmentStatistics {
     }
 }
 
+/// Statistics about a whole store, for reporting to operators
+#[derive(Debug)]
+pub struct StoreStatistics {
+    /// Statistics for each active segment
+    pub segments: Vec<(u32, SegmentStatistics)>,
+
+    /// Segments that have been written but are waiting for a refresh
+    pub pending_segments: Vec<u32>,
+
+    /// Size of each segment's data on disk, in bytes (before compression)
+    pub segment_sizes: FnvHashMap<u32, u64>,
+
+    /// Estimated memory used by the store's in-memory structures, in bytes
+    pub memory_in_bytes: usize,
+
+    pub merges: MergeStatistics,
+
+    pub filter_cache: FilterCacheStatistics,
+
+    pub request_cache: RequestCacheStatistics,
+
+    /// Documents indexed, deleted and searched since the store was opened
+    pub activity: ActivityStatistics,
+}
+
+/// Works out which segment a key belongs to, if any
+fn key_segment(key: &[u8]) -> Option<u32> {
+    let segment = match key[0] {
+        // v<segment>/... = stored values
+        // s<segment>/... = statistics
+        // x<segment> = deletion list
+        b'v' | b's' | b'x' => key[1..].split(|b| *b == b'/').next(),
+
+        // d<field>/<term>/<segment> = postings lists
+        b'd' => key[1..].split(|b| *b == b'/').nth(2),
+
+        _ => None,
+    };
+
+    segment.and_then(|segment| str::from_utf8(segment).ok()).and_then(|segment| segment.parse().ok())
+}
+
 impl RocksDBStore {
     pub fn get_segment_statistics(&self) -> Result<Vec<(u32, SegmentStatistics)>, String> {
         let mut segment_stats = Vec::new();
@@ -42,4 +92,60 @@ impl RocksDBStore {
 
         Ok(segment_stats)
     }
+
+    /// Calculates the amount of data stored for each segment
+    ///
+    /// This reads every key in the store so it shouldn't be called often
+    pub fn get_segment_sizes(&self) -> FnvHashMap<u32, u64> {
+        let mut sizes = FnvHashMap::default();
+        let reader = self.reader();
+
+        let mut iter = reader.snapshot.raw_iterator();
+        iter.seek_to_first();
+        while iter.valid() {
+            let k = iter.key().unwrap();
+
+            if let Some(segment) = key_segment(&k) {
+                let size = k.len() + iter.value().map(|v| v.len()).unwrap_or(0);
+                *sizes.entry(segment).or_insert(0) += size as u64;
+            }
+
+            iter.next();
+        }
+
+        sizes
+    }
+
+    /// Estimates the memory used by the term dictionary and document index
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<RocksDBStore>() + self.term_dictionary.memory_usage() + self.document_index.memory_usage()
+    }
+
+    pub fn get_store_statistics(&self) -> Result<StoreStatistics, String> {
+        Ok(StoreStatistics {
+            segments: try!(self.get_segment_statistics()),
+            pending_segments: self.pending_segments.lock().unwrap().clone(),
+            segment_sizes: self.get_segment_sizes(),
+            memory_in_bytes: self.memory_usage(),
+            merges: self.merge_statistics(),
+            filter_cache: self.filter_cache.statistics(),
+            request_cache: self.request_cache.statistics(),
+            activity: self.activity.statistics(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::key_segment;
+
+    #[test]
+    fn test_key_segment() {
+        assert_eq!(key_segment(b"v12/3/4/val"), Some(12));
+        assert_eq!(key_segment(b"s12/total_docs"), Some(12));
+        assert_eq!(key_segment(b"x12"), Some(12));
+        assert_eq!(key_segment(b"d1/2/12"), Some(12));
+        assert_eq!(key_segment(b"a12"), None);
+        assert_eq!(key_segment(b"kfoo"), None);
+    }
 }
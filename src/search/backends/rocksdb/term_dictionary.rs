@@ -2,6 +2,7 @@ use std::str;
 use std::sync::{Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::HashMap;
+use std::mem;
 
 use rocksdb::{self, DB};
 use search::{Term, TermId};
@@ -74,6 +75,15 @@ impl TermDictionaryManager {
         self.terms.read().unwrap().get(term).cloned()
     }
 
+    /// Finds the term with the given TermId
+    ///
+    /// This scans the whole dictionary so should only be used for diagnostics
+    pub fn get_term(&self, term_id: TermId) -> Option<Term> {
+        self.terms.read().unwrap().iter()
+            .find(|&(_term, id)| *id == term_id)
+            .map(|(term, _term_id)| term.clone())
+    }
+
     /// Iterates over terms in the dictionary which match the selector
     pub fn select(&self, term_selector: &MultiTermSelector) -> Vec<TermId> {
         self.terms.read().unwrap().iter()
@@ -84,6 +94,16 @@ impl TermDictionaryManager {
             .collect()
     }
 
+    /// Finds the terms in the dictionary that the predicate returns true for
+    ///
+    /// Terms aren't stored per field, so these may not exist in the field being searched
+    pub fn filter<F: FnMut(&Term) -> bool>(&self, mut predicate: F) -> Vec<(Term, TermId)> {
+        self.terms.read().unwrap().iter()
+            .filter(|&(term, _term_id)| predicate(term))
+            .map(|(term, term_id)| (term.clone(), *term_id))
+            .collect()
+    }
+
     /// Retrieves the TermId for the given term, adding the term to the
     /// dictionary if it doesn't exist
     pub fn get_or_create(&self, db: &DB, term: &Term) -> Result<TermId, rocksdb::Error> {
@@ -122,4 +142,12 @@ impl TermDictionaryManager {
 
         Ok(term_id)
     }
+
+    /// Estimates the memory used by the term dictionary, in bytes
+    pub fn memory_usage(&self) -> usize {
+        let terms = self.terms.read().unwrap();
+        let entry_size = mem::size_of::<Term>() + mem::size_of::<TermId>();
+
+        terms.keys().map(|term| entry_size + term.as_bytes().len()).sum()
+    }
 }
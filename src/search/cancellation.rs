@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Allows a running search to be stopped early
+///
+/// The search checks this while it's matching documents and stops once the deadline has
+/// passed or `cancel` has been called. Clones share the same cancellation flag, so a search
+/// can be cancelled from another thread.
+#[derive(Debug, Clone)]
+pub struct SearchCancellation {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+
+impl SearchCancellation {
+    pub fn new() -> SearchCancellation {
+        SearchCancellation {
+            deadline: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stops the search once the timeout has passed (counting from now)
+    pub fn with_timeout(timeout: Duration) -> SearchCancellation {
+        SearchCancellation {
+            deadline: Some(Instant::now() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Returns true if the search should stop
+    #[inline]
+    pub fn should_stop(&self) -> bool {
+        self.is_cancelled() || self.is_timed_out()
+    }
+}
+
+
+impl Default for SearchCancellation {
+    fn default() -> SearchCancellation {
+        SearchCancellation::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SearchCancellation;
+
+    #[test]
+    fn test_cancel() {
+        let cancellation = SearchCancellation::new();
+        let clone = cancellation.clone();
+        assert!(!cancellation.should_stop());
+
+        clone.cancel();
+        assert!(cancellation.is_cancelled());
+        assert!(cancellation.should_stop());
+    }
+
+    #[test]
+    fn test_timeout() {
+        assert!(!SearchCancellation::with_timeout(Duration::from_secs(60)).should_stop());
+
+        let cancellation = SearchCancellation::with_timeout(Duration::from_millis(0));
+        assert!(cancellation.is_timed_out());
+        assert!(!cancellation.is_cancelled());
+        assert!(cancellation.should_stop());
+    }
+}
@@ -0,0 +1,98 @@
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::aggregations::{Aggregation, Aggregators, AggregationResult};
+use search::aggregations::breaker::CircuitBreaker;
+use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+
+/// Wraps another collector, passing every document it's given to a list of aggregations
+///
+/// Doc values are read with `read_value`, which is given the field and the document id.
+/// Once the breaker trips, documents are only passed to the inner collector.
+pub struct AggregationCollector<'a, C: Collector, F: FnMut(FieldId, u64) -> Option<FieldValue>> {
+    inner: C,
+    aggregations: &'a [(String, Aggregation)],
+    aggregators: Aggregators<'a>,
+    breaker: &'a CircuitBreaker,
+    read_value: F,
+}
+
+impl<'a, C: Collector, F: FnMut(FieldId, u64) -> Option<FieldValue>> AggregationCollector<'a, C, F> {
+    pub fn new(inner: C, aggregations: &'a [(String, Aggregation)], breaker: &'a CircuitBreaker, read_value: F) -> AggregationCollector<'a, C, F> {
+        AggregationCollector {
+            inner: inner,
+            aggregations: aggregations,
+            aggregators: Aggregators::new(aggregations, breaker),
+            breaker: breaker,
+            read_value: read_value,
+        }
+    }
+
+    /// Returns the inner collector along with the result of each aggregation
+    pub fn into_inner(self) -> (C, Vec<(String, AggregationResult)>) {
+        (self.inner, self.aggregators.into_results())
+    }
+}
+
+impl<'a, C: Collector, F: FnMut(FieldId, u64) -> Option<FieldValue>> Collector for AggregationCollector<'a, C, F> {
+    fn needs_score(&self) -> bool {
+        self.inner.needs_score() || self.aggregations.iter().any(|&(_, ref aggregation)| aggregation.needs_score())
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        if !self.breaker.is_tripped() {
+            self.aggregators.collect(doc.doc_id(), doc.score(), &mut self.read_value);
+        }
+
+        self.inner.collect(doc);
+    }
+
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        // Aggregations need to see every match
+        if self.aggregations.is_empty() {
+            self.inner.top_docs()
+        } else {
+            None
+        }
+    }
+
+    fn collect_skipped(&mut self, count: u64, max_score: Option<f32>) {
+        self.inner.collect_skipped(count, max_score);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::aggregations::{Aggregation, AggregationResult, ValueSource};
+    use search::aggregations::metric::{Metric, MetricAggregation, MetricResult};
+    use search::aggregations::breaker::CircuitBreaker;
+    use search::collectors::{Collector, DocumentMatch};
+    use search::collectors::total_count::TotalCountCollector;
+    use super::AggregationCollector;
+
+    #[test]
+    fn test_aggregation_collector() {
+        let aggregations = vec![
+            ("max_price".to_string(), Aggregation::Metric(MetricAggregation {
+                metric: Metric::Max,
+                source: ValueSource::Field(FieldId(1)),
+                missing: None,
+            })),
+        ];
+
+        let breaker = CircuitBreaker::unlimited();
+        let mut collector = AggregationCollector::new(TotalCountCollector::new(), &aggregations, &breaker, |_, doc_id| Some(FieldValue::Integer(doc_id as i64 * 10)));
+        assert!(!collector.needs_score());
+
+        collector.collect(DocumentMatch::new_unscored(1));
+        collector.collect(DocumentMatch::new_unscored(3));
+
+        let (collector, results) = collector.into_inner();
+        assert_eq!(collector.get_total_count(), 2);
+        assert_eq!(results, vec![
+            ("max_price".to_string(), AggregationResult::Metric(MetricResult::Value(Some(30.0)))),
+        ]);
+    }
+}
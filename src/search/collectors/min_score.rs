@@ -0,0 +1,80 @@
+use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+
+/// Wraps another collector, dropping documents that scored lower than `min_score`
+///
+/// Documents that are dropped are not counted in the inner collector's total
+pub struct MinScoreCollector<C: Collector> {
+    inner: C,
+    min_score: Option<f32>,
+}
+
+impl<C: Collector> MinScoreCollector<C> {
+    pub fn new(inner: C, min_score: Option<f32>) -> MinScoreCollector<C> {
+        MinScoreCollector {
+            inner: inner,
+            min_score: min_score,
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Collector> Collector for MinScoreCollector<C> {
+    fn needs_score(&self) -> bool {
+        self.min_score.is_some() || self.inner.needs_score()
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        if let Some(min_score) = self.min_score {
+            if doc.score().map_or(true, |score| score < min_score) {
+                return;
+            }
+        }
+
+        self.inner.collect(doc);
+    }
+
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        // Skipped documents could have scored below the minimum, so must all be collected
+        match self.min_score {
+            Some(_) => None,
+            None => self.inner.top_docs(),
+        }
+    }
+
+    fn collect_skipped(&mut self, count: u64, max_score: Option<f32>) {
+        self.inner.collect_skipped(count, max_score);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use search::collectors::{Collector, DocumentMatch};
+    use search::collectors::total_count::TotalCountCollector;
+    use super::MinScoreCollector;
+
+    #[test]
+    fn test_min_score_collector() {
+        let mut collector = MinScoreCollector::new(TotalCountCollector::new(), Some(1.0f32));
+        assert!(collector.needs_score());
+
+        collector.collect(DocumentMatch::new_scored(0, 0.5f32));
+        collector.collect(DocumentMatch::new_scored(1, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(2, 1.5f32));
+
+        assert_eq!(collector.into_inner().get_total_count(), 2);
+    }
+
+    #[test]
+    fn test_min_score_collector_disabled() {
+        let mut collector = MinScoreCollector::new(TotalCountCollector::new(), None);
+        assert!(!collector.needs_score());
+
+        collector.collect(DocumentMatch::new_unscored(0));
+
+        assert_eq!(collector.into_inner().get_total_count(), 1);
+    }
+}
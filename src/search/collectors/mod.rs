@@ -1,5 +1,15 @@
 pub mod total_count;
 pub mod top_score;
+pub mod top_field;
+pub mod profile;
+pub mod min_score;
+pub mod aggregation;
+
+use self::top_score::TopScoreCollector;
+
+/// Most documents that a collector reserves room for up front. Larger pages grow as
+/// documents are collected, so a huge "size" doesn't allocate memory that is never used
+pub const MAX_PREALLOCATED_DOCS: usize = 1024;
 
 #[derive(Debug)]
 pub struct DocumentMatch {
@@ -36,4 +46,17 @@ impl DocumentMatch {
 pub trait Collector {
     fn needs_score(&self) -> bool;
     fn collect(&mut self, doc: DocumentMatch);
+
+    /// If the collector only keeps the highest scoring documents, returns an empty
+    /// `TopScoreCollector` that keeps the same ones
+    ///
+    /// Searches that run on several threads use this to only keep the best matches in each
+    /// segment, passing the number of others to `collect_skipped`.
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        None
+    }
+
+    /// Counts matches that weren't passed to `collect` because they couldn't be kept.
+    /// `max_score` is the highest score of those matches
+    fn collect_skipped(&mut self, _count: u64, _max_score: Option<f32>) {}
 }
@@ -0,0 +1,82 @@
+use std::time::Instant;
+
+use search::profile::{CollectorProfile, duration_to_nanos};
+use search::collectors::{Collector, DocumentMatch};
+use search::collectors::top_score::TopScoreCollector;
+
+/// Wraps another collector, recording the time spent collecting each match
+pub struct ProfileCollector<C: Collector> {
+    name: String,
+    inner: C,
+    time_in_nanos: u64,
+    collected: u64,
+}
+
+impl<C: Collector> ProfileCollector<C> {
+    pub fn new<N: Into<String>>(name: N, inner: C) -> ProfileCollector<C> {
+        ProfileCollector {
+            name: name.into(),
+            inner: inner,
+            time_in_nanos: 0,
+            collected: 0,
+        }
+    }
+
+    pub fn profile(&self) -> CollectorProfile {
+        CollectorProfile {
+            name: self.name.clone(),
+            time_in_nanos: self.time_in_nanos,
+            collected: self.collected,
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Collector> Collector for ProfileCollector<C> {
+    fn needs_score(&self) -> bool {
+        self.inner.needs_score()
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let start = Instant::now();
+        self.inner.collect(doc);
+        self.time_in_nanos += duration_to_nanos(start.elapsed());
+        self.collected += 1;
+    }
+
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        self.inner.top_docs()
+    }
+
+    fn collect_skipped(&mut self, count: u64, max_score: Option<f32>) {
+        self.inner.collect_skipped(count, max_score);
+        self.collected += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use search::collectors::{Collector, DocumentMatch};
+    use search::collectors::total_count::TotalCountCollector;
+    use super::ProfileCollector;
+
+    #[test]
+    fn test_profile_collector() {
+        let mut collector = ProfileCollector::new("TotalCountCollector", TotalCountCollector::new());
+
+        collector.collect(DocumentMatch::new_unscored(0));
+        collector.collect(DocumentMatch::new_unscored(1));
+
+        let profile = collector.profile();
+        assert_eq!(profile.name, "TotalCountCollector");
+        assert_eq!(profile.collected, 2);
+        assert_eq!(collector.into_inner().get_total_count(), 2);
+    }
+}
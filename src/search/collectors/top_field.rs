@@ -0,0 +1,258 @@
+use std::cmp::{self, Ordering};
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::sort::{SortField, SortKey, SortValue, compare_sort_values};
+use search::collectors::{Collector, DocumentMatch, MAX_PREALLOCATED_DOCS};
+
+/// A document that was kept by the TopFieldCollector, along with the values it was sorted by
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedDocument {
+    pub id: u64,
+    pub score: Option<f32>,
+    pub sort_values: Vec<SortValue>,
+}
+
+/// Collects the first documents in the order given by a list of sort fields
+///
+/// Field values are read with `read_value`, which is given the field and the document id.
+/// As with `TopScoreCollector`, only the first `offset + max_docs` documents are kept.
+///
+/// If `search_after` is set, only documents that sort after the given values are kept.
+/// This lets clients page through results without the cost of a large offset. Scrolls also
+/// give the id of the last document, so documents that sort the same aren't skipped.
+pub struct TopFieldCollector<F: FnMut(FieldId, u64) -> Option<FieldValue>> {
+    sort: Vec<SortField>,
+    offset: usize,
+    max_docs: usize,
+    search_after: Option<(Vec<SortValue>, Option<u64>)>,
+
+    /// Kept sorted, best first
+    docs: Vec<SortedDocument>,
+    total_hits: u64,
+    max_score: Option<f32>,
+    read_value: F,
+}
+
+impl<F: FnMut(FieldId, u64) -> Option<FieldValue>> TopFieldCollector<F> {
+    pub fn page(sort: Vec<SortField>, from: usize, size: usize, read_value: F) -> TopFieldCollector<F> {
+        let max_docs = from.saturating_add(size);
+
+        TopFieldCollector {
+            sort: sort,
+            offset: from,
+            max_docs: max_docs,
+            search_after: None,
+            docs: Vec::with_capacity(cmp::min(max_docs, MAX_PREALLOCATED_DOCS) + 1),
+            total_hits: 0,
+            max_score: None,
+            read_value: read_value,
+        }
+    }
+
+    /// Only keeps documents that sort after the given sort values
+    pub fn search_after(mut self, sort_values: Vec<SortValue>) -> TopFieldCollector<F> {
+        self.search_after = Some((sort_values, None));
+        self
+    }
+
+    /// Only keeps documents that come after the given one, which sorts with the given values
+    pub fn search_after_doc(mut self, sort_values: Vec<SortValue>, doc_id: u64) -> TopFieldCollector<F> {
+        self.search_after = Some((sort_values, Some(doc_id)));
+        self
+    }
+
+    /// Returns the number of documents that matched, including ones that were not kept
+    pub fn total_hits(&self) -> u64 {
+        self.total_hits
+    }
+
+    /// Returns the highest score of any document that matched
+    pub fn max_score(&self) -> Option<f32> {
+        self.max_score
+    }
+
+    /// Returns the requested page of documents in sorted order
+    pub fn into_page(self) -> Vec<SortedDocument> {
+        let offset = self.offset;
+        self.docs.into_iter().skip(offset).collect()
+    }
+
+    fn compare(&self, a: &SortedDocument, b: &SortedDocument) -> Ordering {
+        // Documents that sort equally are ordered by id so results are stable across pages
+        compare_sort_values(&self.sort, &a.sort_values, &b.sort_values).then(a.id.cmp(&b.id))
+    }
+}
+
+impl<F: FnMut(FieldId, u64) -> Option<FieldValue>> Collector for TopFieldCollector<F> {
+    fn needs_score(&self) -> bool {
+        true
+    }
+
+    fn collect(&mut self, doc: DocumentMatch) {
+        let doc_id = doc.doc_id();
+        let score = doc.score();
+
+        self.total_hits += 1;
+
+        if let Some(score) = score {
+            if self.max_score.map_or(true, |max_score| score > max_score) {
+                self.max_score = Some(score);
+            }
+        }
+
+        if self.max_docs == 0 {
+            return;
+        }
+
+        let mut sort_values = Vec::with_capacity(self.sort.len());
+        let read_value = &mut self.read_value;
+        for field in self.sort.iter() {
+            sort_values.push(match field.key {
+                SortKey::Score => SortValue::Score(score.unwrap_or(0.0)),
+                SortKey::Doc => SortValue::Doc(doc_id),
+                SortKey::Field(field_id) => SortValue::Field(read_value(field_id, doc_id)),
+                SortKey::Script(ref script) => SortValue::Script(script.evaluate(score, &mut |field_id| read_value(field_id, doc_id))),
+            });
+        }
+
+        let sorted_document = SortedDocument {
+            id: doc_id,
+            score: score,
+            sort_values: sort_values,
+        };
+
+        if let Some((ref search_after, search_after_id)) = self.search_after {
+            let ordering = compare_sort_values(&self.sort, &sorted_document.sort_values, search_after);
+            let ordering = match search_after_id {
+                Some(search_after_id) => ordering.then(doc_id.cmp(&search_after_id)),
+                None => ordering,
+            };
+
+            if ordering != Ordering::Greater {
+                return;
+            }
+        }
+
+        // Once full, only insert documents that come before the last one
+        if self.docs.len() >= self.max_docs {
+            if self.compare(&sorted_document, self.docs.last().unwrap()) != Ordering::Less {
+                return;
+            }
+        }
+
+        let position = match self.docs.binary_search_by(|other| self.compare(other, &sorted_document)) {
+            Ok(position) | Err(position) => position,
+        };
+        self.docs.insert(position, sorted_document);
+        self.docs.truncate(self.max_docs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashMap;
+
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+    use search::sort::{SortField, SortKey, SortOrder, SortValue};
+    use search::script::{Script, BinaryOperator};
+    use search::collectors::{Collector, DocumentMatch};
+    use super::TopFieldCollector;
+
+    #[test]
+    fn test_sort_by_field() {
+        let mut values = FnvHashMap::default();
+        values.insert(0, FieldValue::Integer(30));
+        values.insert(1, FieldValue::Integer(10));
+        values.insert(3, FieldValue::Integer(20));
+
+        let sort = vec![SortField::new(SortKey::Field(FieldId(1)))];
+        let mut collector = TopFieldCollector::page(sort, 0, 3, |_, doc_id| values.get(&doc_id).cloned());
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+        collector.collect(DocumentMatch::new_scored(3, 1.5f32));
+
+        assert_eq!(collector.total_hits(), 4);
+        assert_eq!(collector.max_score(), Some(2.0f32));
+
+        // Document 2 has no value, so it goes last and is truncated off
+        let docs = collector.into_page();
+        assert_eq!(docs.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![1, 3, 0]);
+        assert_eq!(docs[0].sort_values, vec![SortValue::Field(Some(FieldValue::Integer(10)))]);
+    }
+
+    #[test]
+    fn test_sort_page() {
+        let sort = vec![SortField {
+            order: SortOrder::Desc,
+            ..SortField::new(SortKey::Doc)
+        }];
+        let mut collector = TopFieldCollector::page(sort, 1, 2, |_, _| None);
+
+        for doc_id in 0..5 {
+            collector.collect(DocumentMatch::new_scored(doc_id, 1.0f32));
+        }
+
+        let docs = collector.into_page();
+        assert_eq!(docs.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![3, 2]);
+    }
+
+    #[test]
+    fn test_search_after() {
+        let sort = vec![SortField::new(SortKey::Field(FieldId(1)))];
+        let mut collector = TopFieldCollector::page(sort, 0, 2, |_, doc_id| Some(FieldValue::Integer(doc_id as i64 * 10)))
+            .search_after(vec![SortValue::Field(Some(FieldValue::Integer(20)))]);
+
+        for doc_id in 0..5 {
+            collector.collect(DocumentMatch::new_scored(doc_id, 1.0f32));
+        }
+
+        // Documents before the cursor still count towards the total
+        assert_eq!(collector.total_hits(), 5);
+
+        let docs = collector.into_page();
+        assert_eq!(docs.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_search_after_doc() {
+        // Every document sorts the same, so only the id of the last one tells them apart
+        let sort = vec![SortField::new(SortKey::Field(FieldId(1)))];
+        let mut collector = TopFieldCollector::page(sort, 0, 2, |_, _| Some(FieldValue::Integer(10)))
+            .search_after_doc(vec![SortValue::Field(Some(FieldValue::Integer(10)))], 1);
+
+        for doc_id in 0..5 {
+            collector.collect(DocumentMatch::new_scored(doc_id, 1.0f32));
+        }
+
+        let docs = collector.into_page();
+        assert_eq!(docs.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_sort_by_script() {
+        // Sort by the value of field 1 minus the score
+        let script = Script::BinaryOp(BinaryOperator::Subtract, Box::new(Script::DocValue(FieldId(1))), Box::new(Script::Score));
+        let sort = vec![SortField::new(SortKey::Script(script))];
+        let mut collector = TopFieldCollector::page(sort, 0, 3, |_, doc_id| {
+            if doc_id == 2 {
+                None
+            } else {
+                Some(FieldValue::Integer(10))
+            }
+        });
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 3.0f32));
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+
+        // Document 2 has no value, so the script has no result and it goes last
+        let docs = collector.into_page();
+        assert_eq!(docs.iter().map(|doc| doc.id).collect::<Vec<_>>(), vec![1, 0, 2]);
+        assert_eq!(docs[0].sort_values, vec![SortValue::Script(Some(7.0))]);
+        assert_eq!(docs[2].sort_values, vec![SortValue::Script(None)]);
+    }
+}
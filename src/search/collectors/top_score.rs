@@ -1,7 +1,7 @@
-use std::cmp::Ordering;
+use std::cmp::{self, Ordering};
 use std::collections::BinaryHeap;
 
-use search::collectors::{Collector, DocumentMatch};
+use search::collectors::{Collector, DocumentMatch, MAX_PREALLOCATED_DOCS};
 
 /// An f32 that cannot be NaN.
 /// We need to order documents by score but NaN cannot be ordered, so we convert all scores into
@@ -35,7 +35,8 @@ struct ScoredDocument {
 
 impl Ord for ScoredDocument {
     fn cmp(&self, other: &ScoredDocument) -> Ordering {
-        self.score.cmp(&other.score)
+        // Documents with equal scores are ordered by id so results are stable across pages
+        self.score.cmp(&other.score).then(self.id.cmp(&other.id))
     }
 }
 
@@ -45,20 +46,64 @@ impl PartialOrd for ScoredDocument {
     }
 }
 
-#[derive(Debug)]
+/// Collects the highest scoring documents
+///
+/// Only the best `offset + max_docs` documents are kept in memory at any time (in a heap
+/// ordered so the worst of them is on top), so the cost of a search doesn't depend on how
+/// many documents match.
+///
+/// If `search_after` is set, only documents that come after the given one are kept. Scrolls
+/// use this to carry on from the last hit of the previous page.
+#[derive(Debug, Clone)]
 pub struct TopScoreCollector {
+    offset: usize,
     max_docs: usize,
+    search_after: Option<ScoredDocument>,
     heap: BinaryHeap<ScoredDocument>,
+    total_hits: u64,
+    max_score: Option<f32>,
 }
 
 impl TopScoreCollector {
     pub fn new(max_docs: usize) -> TopScoreCollector {
+        TopScoreCollector::page(0, max_docs)
+    }
+
+    /// Creates a collector for a page of results, skipping the first `from` documents
+    pub fn page(from: usize, size: usize) -> TopScoreCollector {
+        let max_docs = from.saturating_add(size);
+
         TopScoreCollector {
+            offset: from,
             max_docs: max_docs,
-            heap: BinaryHeap::with_capacity(max_docs + 1),
+            search_after: None,
+            heap: BinaryHeap::with_capacity(cmp::min(max_docs, MAX_PREALLOCATED_DOCS) + 1),
+            total_hits: 0,
+            max_score: None,
         }
     }
 
+    /// Only keeps documents that score lower than the given one, or that score the same and
+    /// have a higher id
+    pub fn search_after(mut self, score: f32, doc_id: u64) -> TopScoreCollector {
+        self.search_after = RealF32::new(-score).map(|score| ScoredDocument {
+            id: doc_id,
+            score: score,
+        });
+        self
+    }
+
+    /// Returns the number of documents that matched, including ones that were not kept
+    pub fn total_hits(&self) -> u64 {
+        self.total_hits
+    }
+
+    /// Returns the highest score of any document that matched
+    pub fn max_score(&self) -> Option<f32> {
+        self.max_score
+    }
+
+    /// Returns all the documents that were kept, best first
     pub fn into_sorted_vec(self) -> Vec<DocumentMatch> {
         self.heap.into_sorted_vec().iter()
             .map(|scored_document| {
@@ -66,6 +111,12 @@ impl TopScoreCollector {
             })
             .collect()
     }
+
+    /// Returns the requested page of documents, best first
+    pub fn into_page(self) -> Vec<DocumentMatch> {
+        let offset = self.offset;
+        self.into_sorted_vec().into_iter().skip(offset).collect()
+    }
 }
 
 impl Collector for TopScoreCollector {
@@ -73,6 +124,27 @@ impl Collector for TopScoreCollector {
         true
     }
 
+    fn top_docs(&self) -> Option<TopScoreCollector> {
+        Some(TopScoreCollector {
+            offset: 0,
+            max_docs: self.max_docs,
+            search_after: self.search_after,
+            heap: BinaryHeap::with_capacity(cmp::min(self.max_docs, MAX_PREALLOCATED_DOCS) + 1),
+            total_hits: 0,
+            max_score: None,
+        })
+    }
+
+    fn collect_skipped(&mut self, count: u64, max_score: Option<f32>) {
+        self.total_hits += count;
+
+        if let Some(score) = max_score {
+            if self.max_score.map_or(true, |max_score| score > max_score) {
+                self.max_score = Some(score);
+            }
+        }
+    }
+
     fn collect(&mut self, doc: DocumentMatch) {
         let doc_id = doc.doc_id();
         let score = doc.score();
@@ -99,6 +171,27 @@ impl Collector for TopScoreCollector {
             }
         };
 
+        self.total_hits += 1;
+
+        let score = -scored_document.score.0;
+        if self.max_score.map_or(true, |max_score| score > max_score) {
+            self.max_score = Some(score);
+        }
+
+        if let Some(search_after) = self.search_after {
+            if scored_document <= search_after {
+                return;
+            }
+        }
+
+        // Once the heap is full, only insert documents that are better than the worst one in it
+        if self.heap.len() >= self.max_docs {
+            match self.heap.peek() {
+                Some(worst) if scored_document < *worst => {}
+                _ => return,
+            }
+        }
+
         // Now insert the document into the heap
         self.heap.push(scored_document);
 
@@ -159,4 +252,89 @@ mod tests {
         assert_eq!(docs[0].id, 2);
         assert_eq!(docs[1].id, 0);
     }
+
+    #[test]
+    fn test_top_score_collector_page() {
+        let mut collector = TopScoreCollector::page(1, 2);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 0.5f32));
+        collector.collect(DocumentMatch::new_scored(2, 2.0f32));
+        collector.collect(DocumentMatch::new_scored(3, 1.5f32));
+
+        assert_eq!(collector.total_hits(), 4);
+        assert_eq!(collector.max_score(), Some(2.0f32));
+
+        let docs = collector.into_page();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].id, 3);
+        assert_eq!(docs[1].id, 0);
+    }
+
+    #[test]
+    fn test_top_score_collector_ties() {
+        let mut collector = TopScoreCollector::new(2);
+
+        collector.collect(DocumentMatch::new_scored(2, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 1.0f32));
+
+        // Documents with the same score are ordered by id
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].id, 0);
+        assert_eq!(docs[1].id, 1);
+    }
+
+    #[test]
+    fn test_top_score_collector_zero_size() {
+        let mut collector = TopScoreCollector::new(0);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+
+        assert_eq!(collector.total_hits(), 1);
+        assert_eq!(collector.into_sorted_vec().len(), 0);
+    }
+
+    #[test]
+    fn test_top_score_collector_skipped() {
+        let mut collector = TopScoreCollector::page(1, 2);
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+        collector.collect_skipped(5, Some(0.5f32));
+
+        assert_eq!(collector.total_hits(), 6);
+        assert_eq!(collector.max_score(), Some(1.0f32));
+
+        collector.collect_skipped(1, Some(2.0f32));
+        assert_eq!(collector.max_score(), Some(2.0f32));
+    }
+
+    #[test]
+    fn test_top_score_collector_search_after() {
+        let mut collector = TopScoreCollector::new(2).search_after(1.0f32, 1);
+
+        collector.collect(DocumentMatch::new_scored(0, 2.0f32));
+        collector.collect(DocumentMatch::new_scored(1, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(2, 1.0f32));
+        collector.collect(DocumentMatch::new_scored(3, 0.5f32));
+
+        // Documents before the one that was given are counted, but not kept
+        assert_eq!(collector.total_hits(), 4);
+
+        let docs = collector.into_sorted_vec();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].id, 2);
+        assert_eq!(docs[1].id, 3);
+    }
+
+    #[test]
+    fn test_top_score_collector_huge_page() {
+        let mut collector = TopScoreCollector::page(usize::max_value(), usize::max_value());
+
+        collector.collect(DocumentMatch::new_scored(0, 1.0f32));
+
+        assert_eq!(collector.total_hits(), 1);
+        assert_eq!(collector.into_page().len(), 0);
+    }
 }
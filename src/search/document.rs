@@ -5,6 +5,7 @@ use fnv::FnvHashMap;
 use search::term_vector::TermVector;
 use search::schema::FieldId;
 use search::segment::SegmentId;
+use search::geo::GeoPoint;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct DocId(pub SegmentId, pub u16);
@@ -21,12 +22,14 @@ impl DocId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FieldValue {
     String(String),
     Integer(i64),
     Boolean(bool),
     DateTime(DateTime<Utc>),
+    Vector(Vec<f32>),
+    GeoPoint(GeoPoint),
 }
 
 impl FieldValue {
@@ -61,6 +64,21 @@ impl FieldValue {
                 bytes.write_i64::<LittleEndian>(timestamp_with_micros).unwrap();
                 bytes
             }
+            FieldValue::Vector(ref vector) => {
+                let mut bytes = Vec::with_capacity(vector.len() * 4);
+
+                for value in vector.iter() {
+                    bytes.write_f32::<LittleEndian>(*value).unwrap();
+                }
+
+                bytes
+            }
+            FieldValue::GeoPoint(ref point) => {
+                let mut bytes = Vec::with_capacity(16);
+                bytes.write_f64::<LittleEndian>(point.lat).unwrap();
+                bytes.write_f64::<LittleEndian>(point.lon).unwrap();
+                bytes
+            }
         }
     }
 }
@@ -0,0 +1,27 @@
+/// Describes how a document's score was calculated
+///
+/// The value of each node is calculated from the values of its details
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Explanation {
+    pub value: f32,
+    pub description: String,
+    pub details: Vec<Explanation>,
+}
+
+impl Explanation {
+    pub fn new<D: Into<String>>(value: f32, description: D) -> Explanation {
+        Explanation {
+            value: value,
+            description: description.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn with_details<D: Into<String>>(value: f32, description: D, details: Vec<Explanation>) -> Explanation {
+        Explanation {
+            value: value,
+            description: description.into(),
+            details: details,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Points on the Earth's surface, and the distances between them
+
+use std::f64::consts::PI;
+
+
+/// The mean radius of the Earth, in metres
+const EARTH_RADIUS: f64 = 6371008.7714;
+
+pub const GEOHASH_ALPHABET: &'static [u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Geohashes longer than this are more precise than the points they're made from
+pub const MAX_GEOHASH_PRECISION: usize = 12;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+
+impl GeoPoint {
+    /// Returns None if the latitude or longitude are out of range
+    pub fn new(lat: f64, lon: f64) -> Option<GeoPoint> {
+        if lat >= -90.0 && lat <= 90.0 && lon >= -180.0 && lon <= 180.0 {
+            Some(GeoPoint {
+                lat: lat,
+                lon: lon,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Parses a point given as "lat,lon"
+    pub fn parse(string: &str) -> Option<GeoPoint> {
+        let mut parts = string.splitn(2, ',');
+        let lat = parts.next()?.trim().parse().ok()?;
+        let lon = parts.next()?.trim().parse().ok()?;
+        GeoPoint::new(lat, lon)
+    }
+
+    /// The distance to another point, in metres
+    pub fn distance(&self, other: &GeoPoint, distance_type: DistanceType) -> f64 {
+        let lat_a = self.lat * PI / 180.0;
+        let lat_b = other.lat * PI / 180.0;
+        let delta_lat = lat_b - lat_a;
+        let delta_lon = (other.lon - self.lon) * PI / 180.0;
+
+        match distance_type {
+            DistanceType::Arc => {
+                // The haversine formula
+                let h = (delta_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (delta_lon / 2.0).sin().powi(2);
+                2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+            }
+            DistanceType::Plane => {
+                // Pythagoras on an equirectangular projection, accurate over short distances
+                let x = delta_lon * ((lat_a + lat_b) / 2.0).cos();
+                EARTH_RADIUS * (x * x + delta_lat * delta_lat).sqrt()
+            }
+        }
+    }
+
+    /// Returns the geohash of the cell this point is in
+    ///
+    /// The precision is the length of the geohash, from 1 (cells thousands of kilometres across)
+    /// to 12 (a few centimetres across)
+    pub fn geohash(&self, precision: usize) -> String {
+        let mut lat_range = (-90.0, 90.0);
+        let mut lon_range = (-180.0, 180.0);
+        let mut geohash = String::with_capacity(precision);
+        let mut even_bit = true;
+
+        // Bits alternate between longitude and latitude, starting with longitude
+        for _ in 0..precision {
+            let mut index = 0;
+
+            for _ in 0..5 {
+                let (value, range) = if even_bit {
+                    (self.lon, &mut lon_range)
+                } else {
+                    (self.lat, &mut lat_range)
+                };
+
+                let middle = (range.0 + range.1) / 2.0;
+                index <<= 1;
+                if value >= middle {
+                    index |= 1;
+                    range.0 = middle;
+                } else {
+                    range.1 = middle;
+                }
+
+                even_bit = !even_bit;
+            }
+
+            geohash.push(GEOHASH_ALPHABET[index] as char);
+        }
+
+        geohash
+    }
+}
+
+
+/// How distances are calculated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceType {
+    /// Along the surface of a sphere. This is the most accurate
+    Arc,
+
+    /// Treats the Earth as flat. Faster but inaccurate over long distances and near the poles
+    Plane,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceUnit {
+    Millimeters,
+    Centimeters,
+    Meters,
+    Kilometers,
+    Inches,
+    Feet,
+    Yards,
+    Miles,
+    NauticalMiles,
+}
+
+
+impl DistanceUnit {
+    pub fn from_name(name: &str) -> Option<DistanceUnit> {
+        match name {
+            "mm" | "millimeters" => Some(DistanceUnit::Millimeters),
+            "cm" | "centimeters" => Some(DistanceUnit::Centimeters),
+            "m" | "meters" => Some(DistanceUnit::Meters),
+            "km" | "kilometers" => Some(DistanceUnit::Kilometers),
+            "in" | "inch" => Some(DistanceUnit::Inches),
+            "ft" | "feet" => Some(DistanceUnit::Feet),
+            "yd" | "yards" => Some(DistanceUnit::Yards),
+            "mi" | "miles" => Some(DistanceUnit::Miles),
+            "nmi" | "NM" => Some(DistanceUnit::NauticalMiles),
+            _ => None,
+        }
+    }
+
+    /// Converts a distance in metres to this unit
+    pub fn from_meters(&self, meters: f64) -> f64 {
+        let meters_per_unit = match *self {
+            DistanceUnit::Millimeters => 0.001,
+            DistanceUnit::Centimeters => 0.01,
+            DistanceUnit::Meters => 1.0,
+            DistanceUnit::Kilometers => 1000.0,
+            DistanceUnit::Inches => 0.0254,
+            DistanceUnit::Feet => 0.3048,
+            DistanceUnit::Yards => 0.9144,
+            DistanceUnit::Miles => 1609.344,
+            DistanceUnit::NauticalMiles => 1852.0,
+        };
+
+        meters / meters_per_unit
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{GeoPoint, DistanceType, DistanceUnit};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(GeoPoint::parse("51.5, -0.12"), GeoPoint::new(51.5, -0.12));
+        assert_eq!(GeoPoint::parse("91,0"), None);
+        assert_eq!(GeoPoint::parse("51.5"), None);
+    }
+
+    #[test]
+    fn test_distance() {
+        let london = GeoPoint::new(51.5074, -0.1278).unwrap();
+        let paris = GeoPoint::new(48.8566, 2.3522).unwrap();
+
+        let arc = DistanceUnit::Kilometers.from_meters(london.distance(&paris, DistanceType::Arc));
+        assert!((arc - 343.5).abs() < 1.0, "distance was {}", arc);
+
+        let plane = DistanceUnit::Kilometers.from_meters(london.distance(&paris, DistanceType::Plane));
+        assert!((plane - arc).abs() < 1.0, "distance was {}", plane);
+
+        assert_eq!(london.distance(&london, DistanceType::Arc), 0.0);
+    }
+
+    #[test]
+    fn test_geohash() {
+        let point = GeoPoint::new(57.64911, 10.40744).unwrap();
+        assert_eq!(point.geohash(11), "u4pruydqqvj");
+        assert_eq!(point.geohash(1), "u");
+    }
+}
@@ -0,0 +1,467 @@
+//! Shapes on the Earth's surface, and the geohash cells that cover them
+//!
+//! geo_shape fields are indexed as the cells that their shape touches. Cells that are entirely
+//! inside the shape are kept whole, and the others are split into their 32 children down to a
+//! level that depends on the size of the shape (see `GeoShape::detail_level`). Queries find the
+//! cells of their own shape in the same way and compare them with the indexed cells by prefix,
+//! so matches along the edges of shapes are approximate.
+//!
+//! Coordinates are treated as a flat grid of longitudes and latitudes, so shapes can't cross
+//! the antimeridian.
+
+use serde_json::Value as Json;
+
+use search::geo::{GeoPoint, GEOHASH_ALPHABET, MAX_GEOHASH_PRECISION};
+
+
+/// The finest level that shapes are split down to, unless the mapping says otherwise. Cells at
+/// this level are about 5 metres across
+pub const DEFAULT_TREE_LEVELS: usize = 9;
+
+/// How small cells get relative to the size of a shape, unless the mapping says otherwise
+pub const DEFAULT_DISTANCE_ERROR_PCT: f64 = 0.025;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoShape {
+    Point(GeoPoint),
+    LineString(Vec<GeoPoint>),
+
+    /// The outer ring followed by any holes. The first and last points of each ring are the same
+    Polygon(Vec<Vec<GeoPoint>>),
+
+    /// Multi points, lines and polygons, and geometry collections
+    Collection(Vec<GeoShape>),
+}
+
+
+/// A box of longitudes and latitudes, such as a geohash cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+
+impl Rect {
+    fn contains(&self, point: &GeoPoint) -> bool {
+        point.lon >= self.min_lon && point.lon <= self.max_lon && point.lat >= self.min_lat && point.lat <= self.max_lat
+    }
+
+    fn corners(&self) -> [GeoPoint; 4] {
+        [
+            GeoPoint { lat: self.min_lat, lon: self.min_lon },
+            GeoPoint { lat: self.min_lat, lon: self.max_lon },
+            GeoPoint { lat: self.max_lat, lon: self.max_lon },
+            GeoPoint { lat: self.max_lat, lon: self.min_lon },
+        ]
+    }
+
+    fn center(&self) -> GeoPoint {
+        GeoPoint {
+            lat: (self.min_lat + self.max_lat) / 2.0,
+            lon: (self.min_lon + self.max_lon) / 2.0,
+        }
+    }
+
+    fn extend(&mut self, point: &GeoPoint) {
+        self.min_lon = self.min_lon.min(point.lon);
+        self.min_lat = self.min_lat.min(point.lat);
+        self.max_lon = self.max_lon.max(point.lon);
+        self.max_lat = self.max_lat.max(point.lat);
+    }
+
+    fn diagonal(&self) -> f64 {
+        (self.max_lon - self.min_lon).hypot(self.max_lat - self.min_lat)
+    }
+}
+
+
+/// Returns the box that a geohash cell covers
+pub fn cell_rect(geohash: &str) -> Rect {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut even_bit = true;
+
+    for byte in geohash.bytes() {
+        let index = GEOHASH_ALPHABET.iter().position(|&c| c == byte).unwrap_or(0);
+
+        for bit in (0..5).rev() {
+            let range = if even_bit { &mut lon_range } else { &mut lat_range };
+            let middle = (range.0 + range.1) / 2.0;
+            if (index >> bit) & 1 == 1 {
+                range.0 = middle;
+            } else {
+                range.1 = middle;
+            }
+
+            even_bit = !even_bit;
+        }
+    }
+
+    Rect {
+        min_lon: lon_range.0,
+        min_lat: lat_range.0,
+        max_lon: lon_range.1,
+        max_lat: lat_range.1,
+    }
+}
+
+
+/// The length of the diagonal of the cells at a level, in degrees
+fn cell_diagonal(level: usize) -> f64 {
+    // The bits of a geohash alternate between longitude and latitude, starting with longitude
+    let lon_bits = (level * 5).div_ceil(2);
+    let lat_bits = level * 5 / 2;
+    (360.0 / (1u64 << lon_bits) as f64).hypot(180.0 / (1u64 << lat_bits) as f64)
+}
+
+
+/// Which side of the line through `a` and `b` the point `c` is on. Zero if it's on the line
+fn orientation(a: &GeoPoint, b: &GeoPoint, c: &GeoPoint) -> f64 {
+    (b.lon - a.lon) * (c.lat - a.lat) - (b.lat - a.lat) * (c.lon - a.lon)
+}
+
+
+/// Checks if a point that's on the line through `a` and `b` is between them
+fn on_segment(a: &GeoPoint, b: &GeoPoint, point: &GeoPoint) -> bool {
+    point.lon >= a.lon.min(b.lon) && point.lon <= a.lon.max(b.lon) && point.lat >= a.lat.min(b.lat) && point.lat <= a.lat.max(b.lat)
+}
+
+
+fn segments_intersect(a: &GeoPoint, b: &GeoPoint, c: &GeoPoint, d: &GeoPoint) -> bool {
+    let d1 = orientation(c, d, a);
+    let d2 = orientation(c, d, b);
+    let d3 = orientation(a, b, c);
+    let d4 = orientation(a, b, d);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        return true;
+    }
+
+    // Segments that touch or overlap
+    (d1 == 0.0 && on_segment(c, d, a)) || (d2 == 0.0 && on_segment(c, d, b)) || (d3 == 0.0 && on_segment(a, b, c)) || (d4 == 0.0 && on_segment(a, b, d))
+}
+
+
+fn segment_intersects_rect(a: &GeoPoint, b: &GeoPoint, rect: &Rect) -> bool {
+    if rect.contains(a) || rect.contains(b) {
+        return true;
+    }
+
+    let corners = rect.corners();
+    (0..4).any(|i| segments_intersect(a, b, &corners[i], &corners[(i + 1) % 4]))
+}
+
+
+/// Checks if a point is inside a closed ring, by counting how many of its edges a line from
+/// the point crosses
+fn ring_contains(ring: &[GeoPoint], point: &GeoPoint) -> bool {
+    let mut inside = false;
+
+    for edge in ring.windows(2) {
+        let (a, b) = (&edge[0], &edge[1]);
+        if (a.lat > point.lat) != (b.lat > point.lat) && point.lon < (b.lon - a.lon) * (point.lat - a.lat) / (b.lat - a.lat) + a.lon {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+
+/// How a shape covers a cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellRelation {
+    Disjoint,
+
+    /// The shape covers part of the cell
+    Intersects,
+
+    /// The cell is entirely inside the shape
+    Within,
+}
+
+
+/// The cells of a shape, found by `GeoShape::cells`
+#[derive(Debug, Default, PartialEq)]
+pub struct ShapeCells {
+    /// Cells that are inside the shape, or that the shape partly covers at the finest level.
+    /// These are what's indexed
+    pub leaves: Vec<String>,
+
+    /// Cells that the shape partly covers, which were split into smaller cells
+    pub ancestors: Vec<String>,
+
+    /// Top level cells and children of `ancestors` that the shape doesn't touch
+    pub outside: Vec<String>,
+}
+
+
+impl GeoShape {
+    /// Reads a shape from GeoJSON. Envelopes, given by their top left and bottom right corners,
+    /// are read as polygons
+    pub fn from_geojson(json: &Json) -> Result<GeoShape, String> {
+        let object = json.as_object().ok_or("a shape must be a GeoJSON object")?;
+        let shape_type = object.get("type").and_then(|shape_type| shape_type.as_str()).ok_or("a shape must have a type")?.to_lowercase();
+
+        if shape_type == "geometrycollection" {
+            let geometries = object.get("geometries").and_then(|geometries| geometries.as_array()).ok_or("a geometrycollection must have an array of geometries")?;
+            return Ok(GeoShape::Collection(geometries.iter().map(GeoShape::from_geojson).collect::<Result<_, _>>()?));
+        }
+
+        let coordinates = object.get("coordinates").ok_or_else(|| format!("a {} must have coordinates", shape_type))?;
+        match shape_type.as_ref() {
+            "point" => Ok(GeoShape::Point(parse_position(coordinates)?)),
+            "multipoint" => Ok(GeoShape::Collection(parse_positions(coordinates)?.into_iter().map(GeoShape::Point).collect())),
+            "linestring" => Ok(GeoShape::LineString(parse_line(coordinates)?)),
+            "multilinestring" => {
+                let lines = coordinates.as_array().ok_or("a multilinestring must have an array of lines")?;
+                Ok(GeoShape::Collection(lines.iter().map(|line| parse_line(line).map(GeoShape::LineString)).collect::<Result<_, _>>()?))
+            }
+            "polygon" => Ok(GeoShape::Polygon(parse_polygon(coordinates)?)),
+            "multipolygon" => {
+                let polygons = coordinates.as_array().ok_or("a multipolygon must have an array of polygons")?;
+                Ok(GeoShape::Collection(polygons.iter().map(|polygon| parse_polygon(polygon).map(GeoShape::Polygon)).collect::<Result<_, _>>()?))
+            }
+            "envelope" => {
+                let corners = parse_positions(coordinates)?;
+                if corners.len() != 2 {
+                    return Err("an envelope must have a top left and a bottom right corner".to_string());
+                }
+
+                let mut rect = Rect { min_lon: corners[0].lon, min_lat: corners[0].lat, max_lon: corners[0].lon, max_lat: corners[0].lat };
+                rect.extend(&corners[1]);
+                let corners = rect.corners();
+                Ok(GeoShape::Polygon(vec![vec![corners[0], corners[1], corners[2], corners[3], corners[0]]]))
+            }
+            _ => Err(format!("unrecognised shape type {:?}", shape_type)),
+        }
+    }
+
+    pub fn relate(&self, rect: &Rect) -> CellRelation {
+        match *self {
+            GeoShape::Point(ref point) => {
+                if rect.contains(point) { CellRelation::Intersects } else { CellRelation::Disjoint }
+            }
+            GeoShape::LineString(ref points) => {
+                if points.windows(2).any(|segment| segment_intersects_rect(&segment[0], &segment[1], rect)) {
+                    CellRelation::Intersects
+                } else {
+                    CellRelation::Disjoint
+                }
+            }
+            GeoShape::Polygon(ref rings) => {
+                if rings.iter().any(|ring| ring.windows(2).any(|edge| segment_intersects_rect(&edge[0], &edge[1], rect))) {
+                    return CellRelation::Intersects;
+                }
+
+                // No edges touch the cell, so the cell is either entirely inside the polygon or outside it
+                let center = rect.center();
+                if ring_contains(&rings[0], &center) && !rings[1..].iter().any(|hole| ring_contains(hole, &center)) {
+                    CellRelation::Within
+                } else {
+                    CellRelation::Disjoint
+                }
+            }
+            GeoShape::Collection(ref shapes) => {
+                let mut relation = CellRelation::Disjoint;
+                for shape in shapes {
+                    match shape.relate(rect) {
+                        CellRelation::Within => return CellRelation::Within,
+                        CellRelation::Intersects => relation = CellRelation::Intersects,
+                        CellRelation::Disjoint => {}
+                    }
+                }
+                relation
+            }
+        }
+    }
+
+    fn extend_bounds(&self, bounds: &mut Option<Rect>) {
+        let mut extend = |point: &GeoPoint| {
+            match *bounds {
+                Some(ref mut rect) => rect.extend(point),
+                None => *bounds = Some(Rect { min_lon: point.lon, min_lat: point.lat, max_lon: point.lon, max_lat: point.lat }),
+            }
+        };
+
+        match *self {
+            GeoShape::Point(ref point) => extend(point),
+            GeoShape::LineString(ref points) => points.iter().for_each(extend),
+            GeoShape::Polygon(ref rings) => rings[0].iter().for_each(extend),
+            GeoShape::Collection(ref shapes) => {
+                for shape in shapes {
+                    shape.extend_bounds(bounds);
+                }
+            }
+        }
+    }
+
+    /// Returns the smallest box around the shape. None for an empty collection
+    pub fn bounding_box(&self) -> Option<Rect> {
+        let mut bounds = None;
+        self.extend_bounds(&mut bounds);
+        bounds
+    }
+
+    /// Returns the geohash length to split the shape's cells down to
+    ///
+    /// Cells stop being split once they're smaller than `distance_error_pct` of the size of the
+    /// shape, so large shapes don't need a huge number of cells. Points always go down to
+    /// `tree_levels`
+    pub fn detail_level(&self, tree_levels: usize, distance_error_pct: f64) -> usize {
+        let tree_levels = tree_levels.clamp(1, MAX_GEOHASH_PRECISION);
+        let size = match self.bounding_box() {
+            Some(bounds) => bounds.diagonal() * distance_error_pct,
+            None => return tree_levels,
+        };
+
+        (1..tree_levels).find(|&level| cell_diagonal(level) <= size).unwrap_or(tree_levels)
+    }
+
+    /// Finds the cells that cover the shape, splitting them down to `level`
+    pub fn cells(&self, level: usize) -> ShapeCells {
+        let mut cells = ShapeCells::default();
+        for &c in GEOHASH_ALPHABET {
+            self.visit_cell((c as char).to_string(), level, &mut cells);
+        }
+        cells
+    }
+
+    fn visit_cell(&self, cell: String, level: usize, cells: &mut ShapeCells) {
+        match self.relate(&cell_rect(&cell)) {
+            CellRelation::Disjoint => cells.outside.push(cell),
+            CellRelation::Within => cells.leaves.push(cell),
+            CellRelation::Intersects if cell.len() >= level => cells.leaves.push(cell),
+            CellRelation::Intersects => {
+                for &c in GEOHASH_ALPHABET {
+                    let mut child = cell.clone();
+                    child.push(c as char);
+                    self.visit_cell(child, level, cells);
+                }
+                cells.ancestors.push(cell);
+            }
+        }
+    }
+}
+
+
+/// Parses a [lon, lat] position. An altitude after them is ignored
+fn parse_position(json: &Json) -> Result<GeoPoint, String> {
+    let array = json.as_array().ok_or("expected a [lon, lat] position")?;
+    if array.len() < 2 {
+        return Err("expected a [lon, lat] position".to_string());
+    }
+
+    let lon = array[0].as_f64().ok_or("longitudes must be numbers")?;
+    let lat = array[1].as_f64().ok_or("latitudes must be numbers")?;
+    GeoPoint::new(lat, lon).ok_or_else(|| format!("[{}, {}] is out of range", lon, lat))
+}
+
+
+fn parse_positions(json: &Json) -> Result<Vec<GeoPoint>, String> {
+    json.as_array().ok_or("expected an array of positions")?.iter().map(parse_position).collect()
+}
+
+
+fn parse_line(json: &Json) -> Result<Vec<GeoPoint>, String> {
+    let points = parse_positions(json)?;
+    if points.len() < 2 {
+        return Err("a linestring must have at least two positions".to_string());
+    }
+    Ok(points)
+}
+
+
+fn parse_polygon(json: &Json) -> Result<Vec<Vec<GeoPoint>>, String> {
+    let rings = json.as_array().ok_or("a polygon must have an array of rings")?.iter().map(parse_positions).collect::<Result<Vec<_>, _>>()?;
+    if rings.is_empty() {
+        return Err("a polygon must have at least one ring".to_string());
+    }
+
+    for ring in rings.iter() {
+        if ring.len() < 4 || ring.first() != ring.last() {
+            return Err("polygon rings must be closed, with at least four positions".to_string());
+        }
+    }
+
+    Ok(rings)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::geo::GeoPoint;
+
+    use super::{GeoShape, CellRelation, cell_rect};
+
+    fn square() -> GeoShape {
+        GeoShape::from_geojson(&json!({
+            "type": "Polygon",
+            "coordinates": [
+                [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]],
+                [[4.0, 4.0], [6.0, 4.0], [6.0, 6.0], [4.0, 6.0], [4.0, 4.0]]
+            ]
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_from_geojson() {
+        assert_eq!(GeoShape::from_geojson(&json!({"type": "point", "coordinates": [-0.12, 51.5]})), Ok(GeoShape::Point(GeoPoint::new(51.5, -0.12).unwrap())));
+        assert_eq!(GeoShape::from_geojson(&json!({"type": "envelope", "coordinates": [[0.0, 10.0], [10.0, 0.0]]})), GeoShape::from_geojson(&json!({
+            "type": "polygon",
+            "coordinates": [[[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]]]
+        })));
+
+        assert!(GeoShape::from_geojson(&json!({"type": "point", "coordinates": [0.0, 91.0]})).is_err());
+        assert!(GeoShape::from_geojson(&json!({"type": "linestring", "coordinates": [[0.0, 0.0]]})).is_err());
+        assert!(GeoShape::from_geojson(&json!({"type": "polygon", "coordinates": [[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]]})).is_err());
+        assert!(GeoShape::from_geojson(&json!({"type": "circle", "coordinates": [0.0, 0.0]})).is_err());
+    }
+
+    #[test]
+    fn test_relate() {
+        let square = square();
+        let rect = |min_lon, min_lat, max_lon, max_lat| super::Rect { min_lon, min_lat, max_lon, max_lat };
+
+        assert_eq!(square.relate(&rect(1.0, 1.0, 2.0, 2.0)), CellRelation::Within);
+        assert_eq!(square.relate(&rect(9.0, 9.0, 11.0, 11.0)), CellRelation::Intersects);
+        assert_eq!(square.relate(&rect(-5.0, -5.0, 15.0, 15.0)), CellRelation::Intersects);
+        assert_eq!(square.relate(&rect(20.0, 20.0, 21.0, 21.0)), CellRelation::Disjoint);
+
+        // Inside the hole
+        assert_eq!(square.relate(&rect(4.5, 4.5, 5.5, 5.5)), CellRelation::Disjoint);
+
+        let line = GeoShape::from_geojson(&json!({"type": "linestring", "coordinates": [[0.0, 0.0], [10.0, 10.0]]})).unwrap();
+        assert_eq!(line.relate(&rect(4.0, 4.0, 6.0, 6.0)), CellRelation::Intersects);
+        assert_eq!(line.relate(&rect(0.0, 5.0, 1.0, 6.0)), CellRelation::Disjoint);
+    }
+
+    #[test]
+    fn test_cells() {
+        let point = GeoShape::Point(GeoPoint::new(57.64911, 10.40744).unwrap());
+        let cells = point.cells(point.detail_level(11, 0.025));
+        assert_eq!(cells.leaves, vec!["u4pruydqqvj".to_string()]);
+        assert_eq!(cells.ancestors.len(), 10);
+        assert!(cells.outside.iter().all(|cell| !"u4pruydqqvj".starts_with(cell.as_str())));
+
+        // Points inside the square are in one of its cells, and points outside it aren't
+        let square = square();
+        let level = square.detail_level(9, 0.025);
+        assert_eq!(level, 5);
+        let cells = square.cells(level);
+        assert!(cells.leaves.iter().all(|cell| square.relate(&cell_rect(cell)) != CellRelation::Disjoint));
+
+        let covered = |lat, lon| {
+            let geohash = GeoPoint::new(lat, lon).unwrap().geohash(9);
+            cells.leaves.iter().any(|cell| geohash.starts_with(cell.as_str()))
+        };
+        assert!(covered(2.0, 2.0));
+        assert!(covered(8.0, 3.0));
+        assert!(!covered(5.0, 5.0));
+        assert!(!covered(20.0, 20.0));
+    }
+}
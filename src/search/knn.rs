@@ -0,0 +1,166 @@
+//! k-nearest neighbour search over dense vector fields
+//!
+//! A kNN search finds the `k` documents whose vectors are most similar to a query vector.
+//! The neighbours are found before the query runs, then added to it as a
+//! `Query::DocumentScores` so they're scored, sorted and paged like any other match.
+
+use search::schema::FieldId;
+use search::query::Query;
+
+
+/// How the similarity between two vectors is measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorSimilarity {
+    Cosine,
+
+    /// Same as cosine but faster. Only gives sensible scores if all vectors have a length of 1
+    DotProduct,
+
+    /// Euclidean distance
+    L2Norm,
+}
+
+
+impl Default for VectorSimilarity {
+    fn default() -> VectorSimilarity {
+        VectorSimilarity::Cosine
+    }
+}
+
+
+fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum()
+}
+
+
+impl VectorSimilarity {
+    pub fn from_name(name: &str) -> Option<VectorSimilarity> {
+        match name {
+            "cosine" => Some(VectorSimilarity::Cosine),
+            "dot_product" => Some(VectorSimilarity::DotProduct),
+            "l2_norm" => Some(VectorSimilarity::L2Norm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VectorSimilarity::Cosine => "cosine",
+            VectorSimilarity::DotProduct => "dot_product",
+            VectorSimilarity::L2Norm => "l2_norm",
+        }
+    }
+
+    /// Scores how similar two vectors are
+    ///
+    /// Scores are between 0 and 1 with more similar vectors scoring higher, so they can
+    /// be combined with the scores of other queries
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        match *self {
+            VectorSimilarity::Cosine => {
+                let magnitude = dot_product(a, a).sqrt() * dot_product(b, b).sqrt();
+                if magnitude == 0.0 {
+                    // The angle to a zero vector isn't defined, treat it as perpendicular
+                    return 0.5;
+                }
+
+                (1.0 + dot_product(a, b) / magnitude) / 2.0
+            }
+            VectorSimilarity::DotProduct => ((1.0 + dot_product(a, b)) / 2.0).max(0.0),
+            VectorSimilarity::L2Norm => {
+                let squared_distance: f32 = a.iter().zip(b.iter()).map(|(a, b)| (a - b) * (a - b)).sum();
+                1.0 / (1.0 + squared_distance)
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct KnnSearch {
+    /// The dense vector field to search
+    pub field: FieldId,
+
+    pub query_vector: Vec<f32>,
+    pub similarity: VectorSimilarity,
+
+    /// The number of neighbours to find
+    pub k: usize,
+
+    /// The number of candidates to consider when finding the neighbours
+    ///
+    /// Vectors aren't indexed yet, so every document is compared with the query vector
+    /// and the neighbours are always exact. This is accepted so requests don't need to
+    /// change once an approximate index is added.
+    pub num_candidates: usize,
+
+    /// Only documents that match this query are considered
+    pub filter: Option<Query>,
+
+    /// Multiplies the score of each neighbour
+    pub boost: f32,
+}
+
+
+impl KnnSearch {
+    /// Converts the neighbours found by the search into a query that matches them
+    pub fn to_query(&self, neighbours: &[(u64, f32)]) -> Query {
+        let mut scores = neighbours.to_vec();
+        scores.sort_by_key(|&(doc_id, _)| doc_id);
+
+        Query::DocumentScores {
+            scores: scores,
+        }.boost(self.boost)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::query::Query;
+
+    use super::{VectorSimilarity, KnnSearch};
+
+    #[test]
+    fn test_cosine() {
+        let similarity = VectorSimilarity::Cosine;
+        assert_eq!(similarity.score(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(similarity.score(&[1.0, 0.0], &[0.0, 3.0]), 0.5);
+        assert_eq!(similarity.score(&[1.0, 0.0], &[-1.0, 0.0]), 0.0);
+        assert_eq!(similarity.score(&[1.0, 0.0], &[0.0, 0.0]), 0.5);
+    }
+
+    #[test]
+    fn test_dot_product() {
+        let similarity = VectorSimilarity::DotProduct;
+        assert_eq!(similarity.score(&[1.0, 0.0], &[1.0, 0.0]), 1.0);
+        assert_eq!(similarity.score(&[1.0, 0.0], &[0.0, 1.0]), 0.5);
+        assert_eq!(similarity.score(&[2.0, 0.0], &[-2.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_l2_norm() {
+        let similarity = VectorSimilarity::L2Norm;
+        assert_eq!(similarity.score(&[1.0, 2.0], &[1.0, 2.0]), 1.0);
+        assert_eq!(similarity.score(&[0.0, 0.0], &[1.0, 1.0]), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_to_query() {
+        let knn = KnnSearch {
+            field: FieldId(1),
+            query_vector: vec![1.0, 0.0],
+            similarity: VectorSimilarity::Cosine,
+            k: 2,
+            num_candidates: 2,
+            filter: None,
+            boost: 2.0,
+        };
+
+        // Scores are sorted by document id so they can be looked up quickly
+        assert_eq!(knn.to_query(&[(5, 0.9), (2, 0.8)]), Query::DocumentScores {
+            scores: vec![(2, 1.6), (5, 1.8)],
+        });
+    }
+}
@@ -5,6 +5,15 @@ pub mod schema;
 pub mod document;
 pub mod segment;
 pub mod similarity;
+pub mod explanation;
+pub mod profile;
+pub mod cancellation;
+pub mod script;
+pub mod knn;
+pub mod geo;
+pub mod geo_shape;
+pub mod aggregations;
+pub mod sort;
 pub mod query;
 pub mod collectors;
 pub mod backends;
@@ -0,0 +1,47 @@
+//! Timings recorded while running a search with profiling enabled
+
+use std::time::Duration;
+
+
+/// How long each stage of running a query took, in nanoseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProfileBreakdown {
+    /// Converting the query into a search plan
+    pub build: u64,
+
+    /// Finding the documents that match
+    #[serde(rename = "match")]
+    pub match_: u64,
+
+    /// Scoring the documents that matched
+    pub score: u64,
+
+    /// The number of documents that matched
+    pub match_count: u64,
+}
+
+
+/// The timings of a query node and all of the nodes beneath it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryProfile {
+    #[serde(rename = "type")]
+    pub query_type: String,
+    pub description: String,
+    pub time_in_nanos: u64,
+    pub breakdown: ProfileBreakdown,
+    pub children: Vec<QueryProfile>,
+}
+
+
+/// The time spent passing matches to a collector
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CollectorProfile {
+    pub name: String,
+    pub time_in_nanos: u64,
+    pub collected: u64,
+}
+
+
+pub fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
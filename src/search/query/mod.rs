@@ -6,7 +6,7 @@ use search::schema::FieldId;
 use search::query::multi_term_selector::MultiTermSelector;
 use search::query::term_scorer::TermScorer;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Query {
     /// Matches all documents, assigning the specified score to each one
     All {
@@ -73,6 +73,13 @@ pub enum Query {
         query: Box<Query>,
         exclude: Box<Query>
     },
+
+    /// Matches a fixed set of documents, giving each one the score next to it
+    /// Used to add the neighbours found by a kNN search to a query
+    DocumentScores {
+        /// Document ids and scores, sorted by document id
+        scores: Vec<(u64, f32)>,
+    },
 }
 
 impl Query {
@@ -109,6 +116,90 @@ impl Query {
         }
     }
 
+    /// Checks if the query would match the given term in the given field
+    ///
+    /// Terms in the "filter" and "exclude" parts of queries are not considered. This is
+    /// used to find the parts of a document to highlight
+    pub fn matches_term(&self, field: FieldId, term: &Term) -> bool {
+        match *self {
+            Query::All{..} | Query::None | Query::DocumentScores{..} => false,
+            Query::Term{field: query_field, term: ref query_term, ..} => {
+                query_field == field && query_term == term
+            }
+            Query::MultiTerm{field: query_field, ref term_selector, ..} => {
+                query_field == field && term_selector.matches(term)
+            }
+            Query::Conjunction{ref queries} |
+            Query::Disjunction{ref queries} |
+            Query::DisjunctionMax{ref queries} => {
+                queries.iter().any(|query| query.matches_term(field, term))
+            }
+            Query::Filter{ref query, ..} | Query::Exclude{ref query, ..} => {
+                query.matches_term(field, term)
+            }
+        }
+    }
+
+    /// Returns the name of the query's variant. Used in search profiles
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Query::All{..} => "All",
+            Query::None => "None",
+            Query::Term{..} => "Term",
+            Query::MultiTerm{..} => "MultiTerm",
+            Query::Conjunction{..} => "Conjunction",
+            Query::Disjunction{..} => "Disjunction",
+            Query::DisjunctionMax{..} => "DisjunctionMax",
+            Query::Filter{..} => "Filter",
+            Query::Exclude{..} => "Exclude",
+            Query::DocumentScores{..} => "DocumentScores",
+        }
+    }
+
+    /// Returns the queries that this query is made from
+    pub fn children(&self) -> Vec<&Query> {
+        match *self {
+            Query::All{..} | Query::None | Query::Term{..} | Query::MultiTerm{..} | Query::DocumentScores{..} => vec![],
+            Query::Conjunction{ref queries} |
+            Query::Disjunction{ref queries} |
+            Query::DisjunctionMax{ref queries} => queries.iter().collect(),
+            Query::Filter{ref query, ref filter} => vec![query, filter],
+            Query::Exclude{ref query, ref exclude} => vec![query, exclude],
+        }
+    }
+
+    /// Returns a copy of the query with the ids in its `DocumentScores` queries passed through `f`
+    ///
+    /// Documents that `f` returns None for are left out. This is used to search each shard
+    /// of an index with the kNN neighbours that were found across all of them
+    pub fn map_document_ids<F: Fn(u64) -> Option<u64>>(&self, f: &F) -> Query {
+        let mut query = self.clone();
+        query.map_document_ids_mut(f);
+        query
+    }
+
+    fn map_document_ids_mut<F: Fn(u64) -> Option<u64>>(&mut self, f: &F) {
+        match *self {
+            Query::All{..} | Query::None | Query::Term{..} | Query::MultiTerm{..} => {}
+            Query::Conjunction{ref mut queries} |
+            Query::Disjunction{ref mut queries} |
+            Query::DisjunctionMax{ref mut queries} => {
+                for query in queries.iter_mut() {
+                    query.map_document_ids_mut(f);
+                }
+            }
+            Query::Filter{ref mut query, filter: ref mut other} |
+            Query::Exclude{ref mut query, exclude: ref mut other} => {
+                query.map_document_ids_mut(f);
+                other.map_document_ids_mut(f);
+            }
+            Query::DocumentScores{ref mut scores} => {
+                let mapped = scores.iter().filter_map(|&(doc_id, score)| f(doc_id).map(|doc_id| (doc_id, score))).collect();
+                *scores = mapped;
+            }
+        }
+    }
+
     #[inline]
     /// Multiplies the score of documents that match the query by the specified "boost" value
     pub fn boost(mut self, boost: f32) -> Query {
@@ -154,6 +245,11 @@ impl Query {
             Query::Exclude{ref mut query, ..} => {
                 query.add_boost(add_boost);
             }
+            Query::DocumentScores{ref mut scores} => {
+                for &mut (_, ref mut score) in scores.iter_mut() {
+                    *score *= add_boost;
+                }
+            }
         }
     }
 }
@@ -1,8 +1,15 @@
+use std::collections::BTreeSet;
+use std::str;
+
 use search::term::Term;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MultiTermSelector {
     Prefix(String),
+
+    /// Matches terms that start with any of the prefixes
+    /// Used to find the geohash cells inside other cells
+    Prefixes(BTreeSet<String>),
 }
 
 impl MultiTermSelector {
@@ -11,6 +18,15 @@ impl MultiTermSelector {
             MultiTermSelector::Prefix(ref prefix) => {
                 return term.as_bytes().starts_with(prefix.as_bytes());
             }
+            MultiTermSelector::Prefixes(ref prefixes) => {
+                // Look up each prefix of the term, rather than checking the term against each prefix
+                let term = match str::from_utf8(term.as_bytes()) {
+                    Ok(term) => term,
+                    Err(_) => return false,
+                };
+
+                term.char_indices().any(|(i, c)| prefixes.contains(&term[..i + c.len_utf8()]))
+            }
         }
     }
 }
@@ -7,12 +7,16 @@ pub struct TermScorer {
 }
 
 impl TermScorer {
+    pub fn new(similarity_model: SimilarityModel, boost: f32) -> TermScorer {
+        TermScorer {
+            similarity_model: similarity_model,
+            boost: boost,
+        }
+    }
+
     pub fn default_with_boost(boost: f32) -> TermScorer {
         TermScorer {
-            similarity_model: SimilarityModel::Bm25 {
-                k1: 1.2,
-                b: 0.75,
-            },
+            similarity_model: SimilarityModel::default(),
             boost: boost,
         }
     }
@@ -75,6 +75,8 @@ pub enum FieldType {
     I64,
     Boolean,
     DateTime,
+    DenseVector,
+    GeoPoint,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +94,10 @@ impl FieldInfo {
             field_flags: field_flags,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
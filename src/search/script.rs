@@ -0,0 +1,199 @@
+//! A small expression language for computing values from a document's doc values
+//!
+//! Scripts are used by script-based sorting and `script_fields`. They're parsed in the
+//! query parser, which resolves field names and params, so evaluating a script only
+//! needs to read the doc values it refers to.
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Abs,
+    Sqrt,
+    Log,
+    Log10,
+    Exp,
+    Floor,
+    Ceil,
+    Round,
+    Pow,
+    Min,
+    Max,
+}
+
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "sqrt" => Some(Function::Sqrt),
+            "log" => Some(Function::Log),
+            "log10" => Some(Function::Log10),
+            "exp" => Some(Function::Exp),
+            "floor" => Some(Function::Floor),
+            "ceil" => Some(Function::Ceil),
+            "round" => Some(Function::Round),
+            "pow" => Some(Function::Pow),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of arguments the function takes
+    pub fn arity(&self) -> usize {
+        match *self {
+            Function::Pow | Function::Min | Function::Max => 2,
+            _ => 1,
+        }
+    }
+
+    fn apply(&self, args: &[f64]) -> f64 {
+        match *self {
+            Function::Abs => args[0].abs(),
+            Function::Sqrt => args[0].sqrt(),
+            Function::Log => args[0].ln(),
+            Function::Log10 => args[0].log10(),
+            Function::Exp => args[0].exp(),
+            Function::Floor => args[0].floor(),
+            Function::Ceil => args[0].ceil(),
+            Function::Round => args[0].round(),
+            Function::Pow => args[0].powf(args[1]),
+            Function::Min => args[0].min(args[1]),
+            Function::Max => args[0].max(args[1]),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    Number(f64),
+
+    /// The score of the document being evaluated
+    Score,
+
+    /// The value of a field in the document being evaluated
+    DocValue(FieldId),
+
+    Negate(Box<Script>),
+    BinaryOp(BinaryOperator, Box<Script>, Box<Script>),
+    Function(Function, Vec<Script>),
+}
+
+
+/// Converts a doc value into a number. Dates are given as milliseconds since the epoch
+pub fn field_value_to_number(value: &FieldValue) -> Option<f64> {
+    match *value {
+        FieldValue::Integer(value) => Some(value as f64),
+        FieldValue::Boolean(value) => Some(if value { 1.0 } else { 0.0 }),
+        FieldValue::DateTime(ref value) => Some((value.timestamp() * 1000 + value.timestamp_subsec_millis() as i64) as f64),
+        FieldValue::String(_) | FieldValue::Vector(_) | FieldValue::GeoPoint(_) => None,
+    }
+}
+
+
+impl Script {
+    /// Evaluates the script for a document
+    ///
+    /// Doc values are read with `read_value`. Returns None if the document is missing a
+    /// value the script needs, or the result isn't a finite number
+    pub fn evaluate<F: FnMut(FieldId) -> Option<FieldValue>>(&self, score: Option<f32>, read_value: &mut F) -> Option<f64> {
+        let value = match *self {
+            Script::Number(value) => value,
+            Script::Score => score.unwrap_or(0.0) as f64,
+            Script::DocValue(field_id) => field_value_to_number(&read_value(field_id)?)?,
+            Script::Negate(ref script) => -script.evaluate(score, read_value)?,
+            Script::BinaryOp(operator, ref left, ref right) => {
+                let left = left.evaluate(score, read_value)?;
+                let right = right.evaluate(score, read_value)?;
+
+                match operator {
+                    BinaryOperator::Add => left + right,
+                    BinaryOperator::Subtract => left - right,
+                    BinaryOperator::Multiply => left * right,
+                    BinaryOperator::Divide => left / right,
+                    BinaryOperator::Remainder => left % right,
+                }
+            }
+            Script::Function(function, ref args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    values.push(arg.evaluate(score, read_value)?);
+                }
+
+                function.apply(&values)
+            }
+        };
+
+        if value.is_finite() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the script uses the document's score
+    pub fn needs_score(&self) -> bool {
+        match *self {
+            Script::Number(_) | Script::DocValue(_) => false,
+            Script::Score => true,
+            Script::Negate(ref script) => script.needs_score(),
+            Script::BinaryOp(_, ref left, ref right) => left.needs_score() || right.needs_score(),
+            Script::Function(_, ref args) => args.iter().any(|arg| arg.needs_score()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+
+    use super::{Script, BinaryOperator, Function};
+
+    fn price_with_tax() -> Script {
+        Script::BinaryOp(
+            BinaryOperator::Multiply,
+            Box::new(Script::DocValue(FieldId(1))),
+            Box::new(Script::Number(1.2)),
+        )
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let script = price_with_tax();
+        assert_eq!(script.evaluate(None, &mut |_| Some(FieldValue::Integer(10))), Some(12.0));
+        assert!(!script.needs_score());
+
+        let script = Script::Function(Function::Max, vec![Script::Score, Script::Number(2.0)]);
+        assert_eq!(script.evaluate(Some(3.0), &mut |_| None), Some(3.0));
+        assert_eq!(script.evaluate(Some(1.0), &mut |_| None), Some(2.0));
+        assert!(script.needs_score());
+    }
+
+    #[test]
+    fn test_missing_value() {
+        assert_eq!(price_with_tax().evaluate(None, &mut |_| None), None);
+        assert_eq!(price_with_tax().evaluate(None, &mut |_| Some(FieldValue::String("foo".to_string()))), None);
+    }
+
+    #[test]
+    fn test_not_finite() {
+        let script = Script::BinaryOp(BinaryOperator::Divide, Box::new(Script::Number(1.0)), Box::new(Script::Number(0.0)));
+        assert_eq!(script.evaluate(None, &mut |_| None), None);
+    }
+}
@@ -1,9 +1,26 @@
+use search::explanation::Explanation;
+
+/// Default BM25 term frequency saturation parameter
+pub const DEFAULT_BM25_K1: f32 = 1.2;
+
+/// Default BM25 length normalisation parameter
+pub const DEFAULT_BM25_B: f32 = 0.75;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum SimilarityModel {
     TfIdf,
     Bm25{k1: f32, b: f32},
 }
 
+impl Default for SimilarityModel {
+    fn default() -> SimilarityModel {
+        SimilarityModel::Bm25 {
+            k1: DEFAULT_BM25_K1,
+            b: DEFAULT_BM25_B,
+        }
+    }
+}
+
 /// tf(term_frequency) = log(term_frequency + 1.0) + 1.0
 #[inline]
 fn tf(term_frequency: u32) -> f32 {
@@ -16,7 +33,21 @@ fn idf(term_docs: u64, total_docs: u64) -> f32 {
     ((total_docs as f32 + 1.0) / (term_docs as f32 + 1.0)).ln() + 1.0
 }
 
+/// bm25_idf(term_docs, total_docs) = log(1.0 + (total_docs - term_docs + 0.5) / (term_docs + 0.5))
+#[inline]
+fn bm25_idf(term_docs: u64, total_docs: u64) -> f32 {
+    let term_docs = term_docs as f32;
+    let total_docs = if (total_docs as f32) < term_docs { term_docs } else { total_docs as f32 };
+
+    (1.0 + (total_docs - term_docs + 0.5) / (term_docs + 0.5)).ln()
+}
+
 impl SimilarityModel {
+    /// Scores a term in a document
+    ///
+    /// `length` is the number of tokens in the document's field. `total_tokens` and
+    /// `total_docs` are the totals for the field across the index, and
+    /// `total_docs_with_term` is the number of documents the term appears in.
     pub fn score(&self, term_frequency: u32, length: f32, total_tokens: u64, total_docs: u64, total_docs_with_term: u64) -> f32 {
         match *self {
             SimilarityModel::TfIdf => {
@@ -26,11 +57,61 @@ impl SimilarityModel {
                 tf * idf
             }
             SimilarityModel::Bm25{k1, b} => {
-                let tf = tf(term_frequency);
-                let idf = idf(total_docs_with_term, total_docs);
-                let average_length = (total_tokens as f32 + 1.0f32) / (total_docs as f32 + 1.0f32);
+                if term_frequency == 0 {
+                    return 0.0;
+                }
+
+                let tf = term_frequency as f32;
+                let idf = bm25_idf(total_docs_with_term, total_docs);
+                let average_length = if total_docs > 0 && total_tokens > 0 {
+                    total_tokens as f32 / total_docs as f32
+                } else {
+                    1.0
+                };
+                let length_norm = k1 * ((1.0 - b) + b * length / average_length);
+
+                idf * (tf * (k1 + 1.0)) / (tf + length_norm)
+            }
+        }
+    }
 
-                idf * (k1 + 1.0) * (tf / (tf + (k1 * ((1.0 - b) + b * length.sqrt() / average_length.sqrt())) + 1.0f32))
+    /// Explains the score that `score` would give with the same arguments
+    pub fn explain(&self, term_frequency: u32, length: f32, total_tokens: u64, total_docs: u64, total_docs_with_term: u64) -> Explanation {
+        let value = self.score(term_frequency, length, total_tokens, total_docs, total_docs_with_term);
+
+        match *self {
+            SimilarityModel::TfIdf => {
+                Explanation::with_details(value, "score(freq), product of:", vec![
+                    Explanation::with_details(tf(term_frequency), "tf, computed as log(freq + 1) + 1 from:", vec![
+                        Explanation::new(term_frequency as f32, "freq, occurrences of term within document"),
+                    ]),
+                    Explanation::with_details(idf(total_docs_with_term, total_docs), "idf, computed as log((N + 1) / (n + 1)) + 1 from:", vec![
+                        Explanation::new(total_docs_with_term as f32, "n, number of documents containing term"),
+                        Explanation::new(total_docs as f32, "N, total number of documents with field"),
+                    ]),
+                ])
+            }
+            SimilarityModel::Bm25{k1, b} => {
+                let average_length = if total_docs > 0 && total_tokens > 0 {
+                    total_tokens as f32 / total_docs as f32
+                } else {
+                    1.0
+                };
+                let idf = bm25_idf(total_docs_with_term, total_docs);
+
+                Explanation::with_details(value, "score(freq), computed as idf * tf from:", vec![
+                    Explanation::with_details(idf, "idf, computed as log(1 + (N - n + 0.5) / (n + 0.5)) from:", vec![
+                        Explanation::new(total_docs_with_term as f32, "n, number of documents containing term"),
+                        Explanation::new(total_docs as f32, "N, total number of documents with field"),
+                    ]),
+                    Explanation::with_details(if idf > 0.0 { value / idf } else { 0.0 }, "tf, computed as freq * (k1 + 1) / (freq + k1 * (1 - b + b * dl / avgdl)) from:", vec![
+                        Explanation::new(term_frequency as f32, "freq, occurrences of term within document"),
+                        Explanation::new(k1, "k1, term saturation parameter"),
+                        Explanation::new(b, "b, length normalization parameter"),
+                        Explanation::new(length, "dl, length of field"),
+                        Explanation::new(average_length, "avgdl, average length of field"),
+                    ]),
+                ])
             }
         }
     }
@@ -115,6 +196,33 @@ mod tests {
         assert!(similarity.score(1, 40.0, 1000, 20, 5) > similarity.score(1, 40.0, 100, 20, 5));
     }
 
+    #[test]
+    fn test_bm25_term_frequency_saturates() {
+        let similarity = SimilarityModel::default();
+
+        // The score approaches idf * (k1 + 1) as the term frequency increases
+        let limit = similarity.score(1000000, 40.0, 400, 10, 5);
+        assert!(similarity.score(100, 40.0, 400, 10, 5) < limit);
+        assert!(limit < (1.0f32 + 5.5 / 5.5).ln() * 2.2 + 0.0001);
+    }
+
+    #[test]
+    fn test_bm25_b_zero_ignores_field_length() {
+        let similarity = SimilarityModel::Bm25 {
+            k1: 1.2,
+            b: 0.0,
+        };
+
+        assert!(similarity.score(1, 100.0, 100, 20, 5) == similarity.score(1, 40.0, 100, 20, 5));
+    }
+
+    #[test]
+    fn test_bm25_term_in_every_doc_scores_positive() {
+        let similarity = SimilarityModel::default();
+
+        assert!(similarity.score(1, 10.0, 100, 10, 10) > 0.0);
+    }
+
     #[test]
     fn test_bm25_handles_zeros() {
         let similarity = SimilarityModel::Bm25 {
@@ -124,4 +232,14 @@ mod tests {
 
         assert!(similarity.score(0, 0.0, 0, 0, 0).is_finite());
     }
+
+    #[test]
+    fn test_explain_matches_score() {
+        let similarity = SimilarityModel::default();
+        let explanation = similarity.explain(2, 40.0, 100, 10, 5);
+
+        assert_eq!(explanation.value, similarity.score(2, 40.0, 100, 10, 5));
+        assert_eq!(explanation.details.len(), 2);
+        assert!((explanation.details[0].value * explanation.details[1].value - explanation.value).abs() < 0.0001);
+    }
 }
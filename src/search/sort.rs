@@ -0,0 +1,192 @@
+use std::cmp::Ordering;
+
+use search::schema::FieldId;
+use search::document::FieldValue;
+use search::script::Script;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+
+/// Where documents without a value for the sort field are placed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingOrder {
+    First,
+    Last,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortKey {
+    /// Sort by relevance
+    Score,
+
+    /// Sort by internal document id. This is the cheapest order to sort by
+    Doc,
+
+    /// Sort by the doc values of a field
+    Field(FieldId),
+
+    /// Sort by a value computed from the document by a script
+    Script(Script),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortField {
+    pub key: SortKey,
+    pub order: SortOrder,
+    pub missing: MissingOrder,
+}
+
+
+impl SortField {
+    /// Creates a sort field with the default order for the key
+    ///
+    /// Scores are sorted in descending order, everything else is ascending
+    pub fn new(key: SortKey) -> SortField {
+        let order = match key {
+            SortKey::Score => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        };
+
+        SortField {
+            key: key,
+            order: order,
+            missing: MissingOrder::Last,
+        }
+    }
+}
+
+
+/// A document's value for one of the sort fields
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortValue {
+    Score(f32),
+    Doc(u64),
+    Field(Option<FieldValue>),
+    Script(Option<f64>),
+}
+
+
+fn compare_field_values(a: &FieldValue, b: &FieldValue) -> Ordering {
+    match (a, b) {
+        (&FieldValue::String(ref a), &FieldValue::String(ref b)) => a.cmp(b),
+        (&FieldValue::Integer(a), &FieldValue::Integer(b)) => a.cmp(&b),
+        (&FieldValue::Boolean(a), &FieldValue::Boolean(b)) => a.cmp(&b),
+        (&FieldValue::DateTime(ref a), &FieldValue::DateTime(ref b)) => a.cmp(b),
+
+        // A field only has one type, so this can only happen if the mapping changed
+        _ => Ordering::Equal,
+    }
+}
+
+
+fn compare_sort_value(field: &SortField, a: &SortValue, b: &SortValue) -> Ordering {
+    let ordering = match (a, b) {
+        (&SortValue::Score(a), &SortValue::Score(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        (&SortValue::Doc(a), &SortValue::Doc(b)) => a.cmp(&b),
+        (&SortValue::Field(Some(ref a)), &SortValue::Field(Some(ref b))) => compare_field_values(a, b),
+        (&SortValue::Script(Some(a)), &SortValue::Script(Some(b))) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+
+        // Missing values go in the same place whatever the sort order
+        (&SortValue::Field(None), &SortValue::Field(None)) |
+        (&SortValue::Script(None), &SortValue::Script(None)) => return Ordering::Equal,
+        (&SortValue::Field(None), &SortValue::Field(Some(_))) |
+        (&SortValue::Script(None), &SortValue::Script(Some(_))) => {
+            return match field.missing {
+                MissingOrder::First => Ordering::Less,
+                MissingOrder::Last => Ordering::Greater,
+            };
+        }
+        (&SortValue::Field(Some(_)), &SortValue::Field(None)) |
+        (&SortValue::Script(Some(_)), &SortValue::Script(None)) => {
+            return match field.missing {
+                MissingOrder::First => Ordering::Greater,
+                MissingOrder::Last => Ordering::Less,
+            };
+        }
+
+        // Values from different sort keys can't be compared
+        _ => Ordering::Equal,
+    };
+
+    match field.order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+
+/// Compares two documents' sort values
+///
+/// Returns `Ordering::Less` if `a` should come before `b` in the results
+pub fn compare_sort_values(sort: &[SortField], a: &[SortValue], b: &[SortValue]) -> Ordering {
+    for (field, (a, b)) in sort.iter().zip(a.iter().zip(b.iter())) {
+        let ordering = compare_sort_value(field, a, b);
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use search::schema::FieldId;
+    use search::document::FieldValue;
+
+    use super::{SortField, SortKey, SortOrder, MissingOrder, SortValue, compare_sort_values};
+
+    #[test]
+    fn test_default_orders() {
+        assert_eq!(SortField::new(SortKey::Score).order, SortOrder::Desc);
+        assert_eq!(SortField::new(SortKey::Doc).order, SortOrder::Asc);
+        assert_eq!(SortField::new(SortKey::Field(FieldId(1))).order, SortOrder::Asc);
+    }
+
+    #[test]
+    fn test_multiple_keys() {
+        let sort = vec![
+            SortField::new(SortKey::Field(FieldId(1))),
+            SortField::new(SortKey::Score),
+        ];
+
+        let a = vec![SortValue::Field(Some(FieldValue::Integer(1))), SortValue::Score(1.0)];
+        let b = vec![SortValue::Field(Some(FieldValue::Integer(1))), SortValue::Score(2.0)];
+        let c = vec![SortValue::Field(Some(FieldValue::Integer(0))), SortValue::Score(0.5)];
+
+        // Equal on the first key, so the higher score wins
+        assert_eq!(compare_sort_values(&sort, &a, &b), Ordering::Greater);
+
+        // The first key takes precedence
+        assert_eq!(compare_sort_values(&sort, &c, &b), Ordering::Less);
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let mut sort = vec![SortField {
+            key: SortKey::Field(FieldId(1)),
+            order: SortOrder::Desc,
+            missing: MissingOrder::Last,
+        }];
+
+        let present = vec![SortValue::Field(Some(FieldValue::String("foo".to_string())))];
+        let missing = vec![SortValue::Field(None)];
+
+        // Missing values are last, even though the order is descending
+        assert_eq!(compare_sort_values(&sort, &missing, &present), Ordering::Greater);
+
+        sort[0].missing = MissingOrder::First;
+        assert_eq!(compare_sort_values(&sort, &missing, &present), Ordering::Less);
+    }
+}
@@ -0,0 +1,166 @@
+//! Works out which privilege an API request needs from its method and path
+//!
+//! This is checked before the request is routed, so the APIs that read index names from the
+//! request body (bulk, multi get, reindex and aliases) check those indices themselves.
+
+use security::roles::{ClusterPrivilege, IndexPrivilege};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequiredPrivilege {
+    /// Any authenticated request is allowed, or the handler checks the privileges itself
+    None,
+
+    Cluster(ClusterPrivilege),
+
+    /// A privilege on a comma-separated list of index names or patterns
+    Index(String, IndexPrivilege),
+}
+
+
+/// Endpoints that read documents
+const READ_ENDPOINTS: &'static [&'static str] = &["_search", "_count", "_validate", "_explain", "_termvectors", "_rank_eval"];
+
+/// Endpoints that change documents
+const WRITE_ENDPOINTS: &'static [&'static str] = &["_create", "_update", "_update_by_query", "_delete_by_query"];
+
+/// Endpoints that check the indices in the request body themselves
+const SELF_CHECKING_ENDPOINTS: &'static [&'static str] = &["_bulk", "_mget"];
+
+/// Endpoints that can follow a mapping name in the path, e.g. "/index/mapping/_bulk"
+const MAPPING_ENDPOINTS: &'static [&'static str] = &["_bulk", "_mget", "_termvectors", "_update_by_query"];
+
+
+/// Privileges for the APIs that don't start with an index name
+fn cluster_api_privilege(method: &str, path: &[&str]) -> RequiredPrivilege {
+    let read_only = method == "GET" || method == "HEAD";
+    let all_indices = |privilege| RequiredPrivilege::Index("*".to_string(), privilege);
+
+    match (path[0], path.get(1).cloned()) {
+        // Scrolls can only be continued by whoever knows the scroll id
+        ("_search", Some("scroll")) => RequiredPrivilege::None,
+        ("_search", _) | ("_count", _) | ("_validate", _) | ("_rank_eval", _) => all_indices(IndexPrivilege::Read),
+        ("_forcemerge", _) => all_indices(IndexPrivilege::Admin),
+        ("_bulk", _) | ("_mget", _) | ("_reindex", _) | ("_aliases", _) => RequiredPrivilege::None,
+        ("_security", Some("_authenticate")) => RequiredPrivilege::None,
+
+        // Keys get the roles of whoever creates them, unless roles are given
+        ("_security", Some("api_key")) if path.len() == 2 && (method == "PUT" || method == "POST") => RequiredPrivilege::None,
+        ("_security", _) => RequiredPrivilege::Cluster(ClusterPrivilege::ManageSecurity),
+        ("_cluster", _) | ("_cat", _) | ("_nodes", _) | ("_stats", _) | ("_alias", _) if read_only => RequiredPrivilege::Cluster(ClusterPrivilege::Monitor),
+        ("_tasks", _) | ("_scripts", _) | ("_ilm", _) if read_only => RequiredPrivilege::Cluster(ClusterPrivilege::Monitor),
+        _ => RequiredPrivilege::Cluster(ClusterPrivilege::Manage),
+    }
+}
+
+
+/// Returns the privilege needed to make a request
+///
+/// `path` is the URL path split on slashes. Documents can be read with the read privilege and
+/// changed with write. Everything else on an index needs admin to change and read to view.
+pub fn required_privilege(method: &str, path: &[&str]) -> RequiredPrivilege {
+    let read_only = method == "GET" || method == "HEAD";
+
+    let index_name = match path.first() {
+        Some(&"") | None => return RequiredPrivilege::None,
+        Some(first) if first.starts_with('_') && *first != "_all" => return cluster_api_privilege(method, path),
+        Some(first) => *first,
+    };
+    let on_index = |privilege| RequiredPrivilege::Index(index_name.to_string(), privilege);
+
+    // The endpoint is either straight after the index name, or after the mapping name
+    // and document id. "_doc" is a mapping name, not an endpoint
+    let endpoint = match path.get(1) {
+        None => return on_index(if read_only { IndexPrivilege::Read } else { IndexPrivilege::Admin }),
+        Some(second) if second.starts_with('_') && *second != "_doc" => *second,
+        Some(_) => {
+            match (path.get(2), path.get(3)) {
+                (_, Some(fourth)) => *fourth,
+                (Some(third), None) if MAPPING_ENDPOINTS.contains(third) => *third,
+                _ => "",
+            }
+        }
+    };
+
+    if endpoint.is_empty() {
+        // A document, or a mapping when posting a document without an id
+        on_index(if read_only { IndexPrivilege::Read } else { IndexPrivilege::Write })
+    } else if SELF_CHECKING_ENDPOINTS.contains(&endpoint) {
+        RequiredPrivilege::None
+    } else if READ_ENDPOINTS.contains(&endpoint) {
+        on_index(IndexPrivilege::Read)
+    } else if WRITE_ENDPOINTS.contains(&endpoint) {
+        on_index(IndexPrivilege::Write)
+    } else if read_only {
+        on_index(IndexPrivilege::Read)
+    } else {
+        on_index(IndexPrivilege::Admin)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use security::roles::{ClusterPrivilege, IndexPrivilege};
+
+    use super::{RequiredPrivilege, required_privilege};
+
+    fn privilege(method: &str, path: &str) -> RequiredPrivilege {
+        required_privilege(method, &path.split('/').skip(1).collect::<Vec<_>>())
+    }
+
+    fn index(name: &str, privilege: IndexPrivilege) -> RequiredPrivilege {
+        RequiredPrivilege::Index(name.to_string(), privilege)
+    }
+
+    #[test]
+    fn test_index_apis() {
+        assert_eq!(privilege("GET", "/logs"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("PUT", "/logs"), index("logs", IndexPrivilege::Admin));
+        assert_eq!(privilege("DELETE", "/logs,metrics"), index("logs,metrics", IndexPrivilege::Admin));
+        assert_eq!(privilege("POST", "/logs/_search"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("GET", "/_all/_search/template"), index("_all", IndexPrivilege::Read));
+        assert_eq!(privilege("POST", "/logs/_refresh"), index("logs", IndexPrivilege::Admin));
+        assert_eq!(privilege("GET", "/logs/_settings"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("PUT", "/logs/_settings"), index("logs", IndexPrivilege::Admin));
+        assert_eq!(privilege("PUT", "/logs/_mapping/doc"), index("logs", IndexPrivilege::Admin));
+        assert_eq!(privilege("PUT", "/logs/_alias/current"), index("logs", IndexPrivilege::Admin));
+        assert_eq!(privilege("POST", "/logs/_delete_by_query"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("POST", "/logs/_bulk"), RequiredPrivilege::None);
+        assert_eq!(privilege("POST", "/logs/doc/_mget"), RequiredPrivilege::None);
+    }
+
+    #[test]
+    fn test_document_apis() {
+        assert_eq!(privilege("GET", "/logs/doc/1"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("HEAD", "/logs/doc/1"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("PUT", "/logs/doc/1"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("PUT", "/logs/_doc/1"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("POST", "/logs/doc"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("DELETE", "/logs/doc/1"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("POST", "/logs/doc/1/_update"), index("logs", IndexPrivilege::Write));
+        assert_eq!(privilege("POST", "/logs/doc/1/_explain"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("POST", "/logs/doc/_termvectors"), index("logs", IndexPrivilege::Read));
+        assert_eq!(privilege("POST", "/logs/doc/_update_by_query"), index("logs", IndexPrivilege::Write));
+    }
+
+    #[test]
+    fn test_cluster_apis() {
+        assert_eq!(privilege("GET", "/"), RequiredPrivilege::None);
+        assert_eq!(privilege("POST", "/_search"), index("*", IndexPrivilege::Read));
+        assert_eq!(privilege("POST", "/_search/scroll"), RequiredPrivilege::None);
+        assert_eq!(privilege("POST", "/_forcemerge"), index("*", IndexPrivilege::Admin));
+        assert_eq!(privilege("POST", "/_bulk"), RequiredPrivilege::None);
+        assert_eq!(privilege("POST", "/_reindex"), RequiredPrivilege::None);
+        assert_eq!(privilege("GET", "/_cluster/health"), RequiredPrivilege::Cluster(ClusterPrivilege::Monitor));
+        assert_eq!(privilege("GET", "/_cat/indices/logs"), RequiredPrivilege::Cluster(ClusterPrivilege::Monitor));
+        assert_eq!(privilege("GET", "/_scripts/score"), RequiredPrivilege::Cluster(ClusterPrivilege::Monitor));
+        assert_eq!(privilege("PUT", "/_scripts/score"), RequiredPrivilege::Cluster(ClusterPrivilege::Manage));
+        assert_eq!(privilege("POST", "/_tasks/1/_cancel"), RequiredPrivilege::Cluster(ClusterPrivilege::Manage));
+        assert_eq!(privilege("GET", "/_security/_authenticate"), RequiredPrivilege::None);
+        assert_eq!(privilege("PUT", "/_security/api_key"), RequiredPrivilege::None);
+        assert_eq!(privilege("DELETE", "/_security/api_key"), RequiredPrivilege::Cluster(ClusterPrivilege::ManageSecurity));
+        assert_eq!(privilege("PUT", "/_security/api_key/abc"), RequiredPrivilege::Cluster(ClusterPrivilege::ManageSecurity));
+        assert_eq!(privilege("GET", "/_security/role"), RequiredPrivilege::Cluster(ClusterPrivilege::ManageSecurity));
+    }
+}
@@ -0,0 +1,346 @@
+//! Authentication of API requests
+//!
+//! Requests can authenticate with HTTP Basic auth, using the users in the config file, or
+//! with an API key in an `Authorization: ApiKey <credentials>` header. The credentials are
+//! the base64 encoding of the key's id and secret, joined by a colon.
+//!
+//! API keys either come from the config file, where the id is the key's name, or are created
+//! through the `_security/api_key` API. Created keys are kept in memory and written to
+//! "api_keys.json" in the data directory whenever they change.
+//!
+//! What each principal is then allowed to do is decided by its roles (see roles.rs).
+
+pub mod roles;
+pub mod authorization;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json::{self, Value as Json};
+use atomicwrites::{AtomicFile, AllowOverwrite};
+use base64;
+use uuid::Uuid;
+
+use settings::Settings;
+
+
+/// How a request was authenticated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Principal {
+    Anonymous,
+    User(String),
+
+    /// Keys from the config file use their name as their id
+    ApiKey { id: String, name: String },
+}
+
+
+impl Principal {
+    pub fn name(&self) -> &str {
+        match *self {
+            Principal::Anonymous => "_anonymous",
+            Principal::User(ref name) => name,
+            Principal::ApiKey { ref name, .. } => name,
+        }
+    }
+
+    pub fn authentication_type(&self) -> &'static str {
+        match *self {
+            Principal::Anonymous => "anonymous",
+            Principal::User(_) => "realm",
+            Principal::ApiKey { .. } => "api_key",
+        }
+    }
+}
+
+
+/// Credentials read from the `Authorization` header of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    ApiKey { id: String, key: String },
+}
+
+
+/// Decodes "base64(first:second)", as used by both Basic auth and API keys
+fn decode_pair(encoded: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let colon = decoded.find(':')?;
+
+    Some((decoded[..colon].to_string(), decoded[colon + 1..].to_string()))
+}
+
+
+impl Credentials {
+    /// Parses the value of an `Authorization` header. Returns None if the scheme isn't
+    /// supported or the value is malformed
+    pub fn parse(header: &str) -> Option<Credentials> {
+        let space = header.find(' ')?;
+        let (scheme, value) = (&header[..space], &header[space + 1..]);
+        let (first, second) = decode_pair(value)?;
+
+        match scheme {
+            "Basic" => Some(Credentials::Basic { username: first, password: second }),
+            "ApiKey" => Some(Credentials::ApiKey { id: first, key: second }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Compares two secrets, taking the same time wherever they differ
+pub fn secrets_equal(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes().zip(b.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+
+/// An API key created through the `_security/api_key` API
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key: String,
+
+    /// Milliseconds since the epoch
+    pub creation: u64,
+
+    /// Milliseconds since the epoch after which the key can't be used
+    pub expiration: Option<u64>,
+}
+
+
+impl ApiKey {
+    /// The value to send in the `Authorization` header, after "ApiKey "
+    pub fn encoded(&self) -> String {
+        base64::encode(format!("{}:{}", self.id, self.key))
+    }
+
+    fn to_json(&self) -> Json {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "api_key": self.key,
+            "creation": self.creation,
+            "expiration": self.expiration,
+        })
+    }
+
+    fn from_json(json: &Json) -> Result<ApiKey, String> {
+        let get_string = |key: &str| json.get(key).and_then(|value| value.as_str()).map(|value| value.to_string()).ok_or_else(|| format!("missing {}", key));
+
+        Ok(ApiKey {
+            id: get_string("id")?,
+            name: get_string("name")?,
+            key: get_string("api_key")?,
+            creation: json.get("creation").and_then(|creation| creation.as_u64()).unwrap_or(0),
+            expiration: json.get("expiration").and_then(|expiration| expiration.as_u64()),
+        })
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ApiKeyRegistry {
+    keys: RwLock<BTreeMap<String, ApiKey>>,
+}
+
+
+impl ApiKeyRegistry {
+    pub fn new() -> ApiKeyRegistry {
+        ApiKeyRegistry {
+            keys: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Loads the keys from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load api keys: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load api keys: {}", e))?;
+
+        let json: Vec<Json> = serde_json::from_str(&s).map_err(|e| format!("failed to load api keys: {}", e))?;
+        let mut keys = BTreeMap::new();
+        for key_json in json {
+            let key = ApiKey::from_json(&key_json).map_err(|e| format!("failed to load api key: {}", e))?;
+            keys.insert(key.id.clone(), key);
+        }
+
+        *self.keys.write().unwrap() = keys;
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, keys: &BTreeMap<String, ApiKey>) -> Result<(), String> {
+        let json = keys.values().map(|key| key.to_json()).collect::<Vec<_>>();
+        let s = serde_json::to_string(&json).map_err(|e| format!("failed to save api keys: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save api keys: {}", e))?;
+
+        Ok(())
+    }
+
+    /// Creates a key with a random id and secret, saving all of the keys to the file
+    pub fn create<P: AsRef<Path>>(&self, path: P, name: String, creation: u64, expiration: Option<u64>) -> Result<ApiKey, String> {
+        let key = ApiKey {
+            id: Uuid::new_v4().simple().to_string(),
+            name: name,
+            key: Uuid::new_v4().simple().to_string(),
+            creation: creation,
+            expiration: expiration,
+        };
+
+        let mut keys = self.keys.write().unwrap();
+        keys.insert(key.id.clone(), key.clone());
+
+        if let Err(e) = self.save(path.as_ref(), &keys) {
+            keys.remove(&key.id);
+            return Err(e);
+        }
+
+        Ok(key)
+    }
+
+    /// Removes the keys that the predicate returns true for, saving the rest to the file
+    ///
+    /// Returns the ids of the keys that were removed
+    pub fn invalidate<P: AsRef<Path>, F: Fn(&ApiKey) -> bool>(&self, path: P, predicate: F) -> Result<Vec<String>, String> {
+        let mut keys = self.keys.write().unwrap();
+        let ids = keys.values().filter(|key| predicate(key)).map(|key| key.id.clone()).collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(ids);
+        }
+
+        let previous = keys.clone();
+        for id in ids.iter() {
+            keys.remove(id);
+        }
+
+        if let Err(e) = self.save(path.as_ref(), &keys) {
+            *keys = previous;
+            return Err(e);
+        }
+
+        Ok(ids)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.keys.read().unwrap().contains_key(id)
+    }
+
+    /// Finds the key with the given id and secret, if it hasn't expired
+    pub fn authenticate(&self, id: &str, secret: &str, now: u64) -> Option<ApiKey> {
+        let keys = self.keys.read().unwrap();
+        let key = keys.get(id)?;
+
+        if !secrets_equal(&key.key, secret) || key.expiration.map_or(false, |expiration| now >= expiration) {
+            return None;
+        }
+
+        Some(key.clone())
+    }
+}
+
+
+/// Works out who a request is from
+///
+/// Requests without credentials are anonymous, which is only allowed if the settings allow
+/// it. Returns None if the request must be refused.
+pub fn authenticate(settings: &Settings, api_keys: &ApiKeyRegistry, credentials: Option<&Credentials>, now: u64) -> Option<Principal> {
+    match credentials {
+        None if settings.anonymous_access => Some(Principal::Anonymous),
+        None => None,
+        Some(&Credentials::Basic { ref username, ref password }) => {
+            match settings.users.get(username) {
+                Some(expected) if secrets_equal(expected, password) => Some(Principal::User(username.clone())),
+                _ => None,
+            }
+        }
+        Some(&Credentials::ApiKey { ref id, ref key }) => {
+            // Keys in the config file are identified by their name
+            if let Some(expected) = settings.api_keys.get(id) {
+                return if secrets_equal(expected, key) { Some(Principal::ApiKey { id: id.clone(), name: id.clone() }) } else { None };
+            }
+
+            api_keys.authenticate(id, key, now).map(|api_key| Principal::ApiKey { id: api_key.id, name: api_key.name })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use settings::Settings;
+
+    use super::{ApiKeyRegistry, Credentials, Principal, authenticate};
+
+    #[test]
+    fn test_parse_credentials() {
+        // "admin:pa:ss"
+        assert_eq!(Credentials::parse("Basic YWRtaW46cGE6c3M="), Some(Credentials::Basic {
+            username: "admin".to_string(),
+            password: "pa:ss".to_string(),
+        }));
+        assert_eq!(Credentials::parse("ApiKey YWRtaW46cGE6c3M="), Some(Credentials::ApiKey {
+            id: "admin".to_string(),
+            key: "pa:ss".to_string(),
+        }));
+        assert_eq!(Credentials::parse("Bearer YWRtaW46cGE6c3M="), None);
+        assert_eq!(Credentials::parse("Basic !!!"), None);
+        assert_eq!(Credentials::parse("Basic"), None);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_api_keys.json";
+        let _ = fs::remove_file(path);
+
+        let mut settings = Settings::default();
+        settings.users.insert("admin".to_string(), "secret".to_string());
+        settings.api_keys.insert("ci".to_string(), "ci-secret".to_string());
+
+        let api_keys = ApiKeyRegistry::new();
+        let key = api_keys.create(path, "reporting".to_string(), 1000, Some(2000)).unwrap();
+        assert_eq!(Credentials::parse(&format!("ApiKey {}", key.encoded())), Some(Credentials::ApiKey { id: key.id.clone(), key: key.key.clone() }));
+
+        let basic = |username: &str, password: &str| Credentials::Basic { username: username.to_string(), password: password.to_string() };
+        let api_key = |id: &str, key: &str| Credentials::ApiKey { id: id.to_string(), key: key.to_string() };
+
+        assert_eq!(authenticate(&settings, &api_keys, None, 1500), Some(Principal::Anonymous));
+        assert_eq!(authenticate(&settings, &api_keys, Some(&basic("admin", "secret")), 1500), Some(Principal::User("admin".to_string())));
+        assert_eq!(authenticate(&settings, &api_keys, Some(&basic("admin", "secrets")), 1500), None);
+        assert_eq!(authenticate(&settings, &api_keys, Some(&basic("nobody", "")), 1500), None);
+        assert_eq!(authenticate(&settings, &api_keys, Some(&api_key("ci", "ci-secret")), 1500), Some(Principal::ApiKey { id: "ci".to_string(), name: "ci".to_string() }));
+        assert_eq!(authenticate(&settings, &api_keys, Some(&api_key(&key.id, &key.key)), 1500), Some(Principal::ApiKey { id: key.id.clone(), name: "reporting".to_string() }));
+        assert_eq!(authenticate(&settings, &api_keys, Some(&api_key(&key.id, "wrong")), 1500), None);
+
+        // Expired
+        assert_eq!(authenticate(&settings, &api_keys, Some(&api_key(&key.id, &key.key)), 2000), None);
+
+        settings.anonymous_access = false;
+        assert_eq!(authenticate(&settings, &api_keys, None, 1500), None);
+
+        // Keys are saved
+        let loaded = ApiKeyRegistry::new();
+        loaded.load(path).unwrap();
+        assert_eq!(loaded.authenticate(&key.id, &key.key, 1500), Some(key.clone()));
+
+        assert_eq!(loaded.invalidate(path, |api_key| api_key.name == "reporting"), Ok(vec![key.id.clone()]));
+        assert_eq!(loaded.authenticate(&key.id, &key.key, 1500), None);
+    }
+}
@@ -0,0 +1,650 @@
+//! Roles, and the privileges they grant
+//!
+//! A role grants cluster privileges, which cover the APIs that aren't about particular
+//! indices, and index privileges on the indices whose names match a set of patterns.
+//!
+//! Roles are assigned to users and API keys through the `_security` API. Principals that
+//! haven't been assigned any roles fall back to:
+//!
+//!  - Anonymous requests get the roles in the `anonymous_roles` setting
+//!  - Users and API keys get the built-in "superuser" role, so setups from before roles
+//!    existed keep working
+//!
+//! Index privileges can be given a query, which limits the documents that searches and gets
+//! can read through them to those that match it. This lets tenants share an index without
+//! seeing each other's documents.
+//!
+//! Roles and assignments are kept in memory and written to "roles.json" in the data directory
+//! whenever they change.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json::{self, Map, Value as Json};
+use atomicwrites::{AtomicFile, AllowOverwrite};
+
+use settings::Settings;
+use source_filter::wildcard_match;
+use search::Query;
+use search::schema::Schema;
+use index::metadata::IndexMetadata;
+use query_parser::{parse as parse_query, build_filter};
+use security::Principal;
+
+
+/// Built-in role that grants every privilege. It can't be changed or deleted
+pub const SUPERUSER_ROLE: &'static str = "superuser";
+
+const CLUSTER_PRIVILEGES: &'static [&'static str] = &["all", "monitor", "manage", "manage_security"];
+const INDEX_PRIVILEGES: &'static [&'static str] = &["all", "read", "write", "admin"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClusterPrivilege {
+    /// Read-only APIs such as health, stats, cat and tasks
+    Monitor,
+
+    /// Stored scripts, lifecycle policies and cancelling tasks
+    Manage,
+
+    /// Roles, role assignments and API keys
+    ManageSecurity,
+}
+
+
+impl ClusterPrivilege {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ClusterPrivilege::Monitor => "monitor",
+            ClusterPrivilege::Manage => "manage",
+            ClusterPrivilege::ManageSecurity => "manage_security",
+        }
+    }
+
+    /// Whether a role with the named privilege has this one. "manage" includes "monitor"
+    fn granted_by(&self, name: &str) -> bool {
+        name == "all" || name == self.name() || (*self == ClusterPrivilege::Monitor && name == "manage")
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexPrivilege {
+    /// Searching and getting documents, and reading the index's settings and mappings
+    Read,
+
+    /// Indexing, updating and deleting documents
+    Write,
+
+    /// Creating and deleting the index, and changing its settings, mappings and aliases
+    Admin,
+}
+
+
+impl IndexPrivilege {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            IndexPrivilege::Read => "read",
+            IndexPrivilege::Write => "write",
+            IndexPrivilege::Admin => "admin",
+        }
+    }
+
+    fn granted_by(&self, name: &str) -> bool {
+        name == "all" || name == self.name()
+    }
+}
+
+
+/// Privileges on the indices that match any of the patterns in `names`
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexPermission {
+    pub names: Vec<String>,
+    pub privileges: Vec<String>,
+
+    /// Only documents that match this query can be read through the permission
+    pub query: Option<Json>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    pub cluster: Vec<String>,
+    pub indices: Vec<IndexPermission>,
+}
+
+
+fn parse_string_list(json: &Json, key: &str, allowed: Option<&[&str]>) -> Result<Vec<String>, String> {
+    let items = match json.get(key) {
+        Some(&Json::Array(ref items)) => items,
+        None => return Ok(Vec::new()),
+        Some(_) => return Err(format!("{} must be an array of strings", key)),
+    };
+
+    let mut strings = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        let string = item.as_str().ok_or_else(|| format!("{} must be an array of strings", key))?;
+
+        if let Some(allowed) = allowed {
+            if !allowed.contains(&string) {
+                return Err(format!("unrecognised privilege in {}: {:?}", key, string));
+            }
+        }
+
+        strings.push(string.to_string());
+    }
+
+    Ok(strings)
+}
+
+
+impl Role {
+    /// The built-in "superuser" role
+    pub fn superuser() -> Role {
+        Role {
+            cluster: vec!["all".to_string()],
+            indices: vec![IndexPermission {
+                names: vec!["*".to_string()],
+                privileges: vec!["all".to_string()],
+                query: None,
+            }],
+        }
+    }
+
+    pub fn from_json(json: &Json) -> Result<Role, String> {
+        let data = json.as_object().ok_or("role must be an object")?;
+
+        for key in data.keys() {
+            if key != "cluster" && key != "indices" {
+                return Err(format!("unrecognised key in role: {:?}", key));
+            }
+        }
+
+        let mut indices = Vec::new();
+        match json.get("indices") {
+            Some(&Json::Array(ref permissions)) => {
+                for permission in permissions.iter() {
+                    if !permission.is_object() {
+                        return Err("indices must be an array of objects".to_string());
+                    }
+
+                    let names = parse_string_list(permission, "names", None)?;
+                    if names.is_empty() {
+                        return Err("names is required for each entry in indices".to_string());
+                    }
+
+                    for key in permission.as_object().unwrap().keys() {
+                        if key != "names" && key != "privileges" && key != "query" {
+                            return Err(format!("unrecognised key in indices: {:?}", key));
+                        }
+                    }
+
+                    let query = match permission.get("query") {
+                        Some(query) => {
+                            parse_query(query).map_err(|e| format!("invalid query in indices: {:?}", e))?;
+                            Some(query.clone())
+                        }
+                        None => None,
+                    };
+
+                    indices.push(IndexPermission {
+                        names: names,
+                        privileges: parse_string_list(permission, "privileges", Some(INDEX_PRIVILEGES))?,
+                        query: query,
+                    });
+                }
+            }
+            None => {}
+            Some(_) => return Err("indices must be an array of objects".to_string()),
+        }
+
+        Ok(Role {
+            cluster: parse_string_list(json, "cluster", Some(CLUSTER_PRIVILEGES))?,
+            indices: indices,
+        })
+    }
+
+    pub fn to_json(&self) -> Json {
+        json!({
+            "cluster": self.cluster,
+            "indices": self.indices.iter().map(|permission| {
+                let mut json = json!({
+                    "names": permission.names,
+                    "privileges": permission.privileges,
+                });
+                if let Some(ref query) = permission.query {
+                    json["query"] = query.clone();
+                }
+                json
+            }).collect::<Vec<_>>(),
+        })
+    }
+}
+
+
+/// What a principal is allowed to do, from all of its roles
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    roles: Vec<Role>,
+}
+
+
+impl Permissions {
+    pub fn new(roles: Vec<Role>) -> Permissions {
+        Permissions {
+            roles: roles,
+        }
+    }
+
+    pub fn allows_cluster(&self, privilege: ClusterPrivilege) -> bool {
+        self.roles.iter().any(|role| role.cluster.iter().any(|name| privilege.granted_by(name)))
+    }
+
+    /// Whether the privilege is granted on every index in a comma-separated list of index
+    /// names, aliases or wildcard patterns
+    ///
+    /// Each name is checked as it's written, so "logs-*" is only allowed by roles with
+    /// a pattern that matches the string "logs-*", such as "logs-*" or "*".
+    pub fn allows_index(&self, index_expression: &str, privilege: IndexPrivilege) -> bool {
+        index_expression.split(',').all(|name| {
+            let name = if name == "_all" { "*" } else { name };
+
+            self.roles.iter().flat_map(|role| role.indices.iter()).any(|permission| {
+                permission.privileges.iter().any(|privilege_name| privilege.granted_by(privilege_name))
+                    && permission.names.iter().any(|pattern| wildcard_match(pattern, name))
+            })
+        })
+    }
+
+    /// The queries that limit which documents of an index can be read
+    ///
+    /// `names` are the names the index is being read through, such as an alias and the index's
+    /// own name. Documents can be read if they match any of the queries. Returns None if there's
+    /// no limit, because a permission that matches one of the names has no query, or because
+    /// no permission matches them at all (that's left to `allows_index`).
+    pub fn document_queries(&self, names: &[&str]) -> Option<Vec<Json>> {
+        let mut queries = Vec::new();
+
+        for permission in self.roles.iter().flat_map(|role| role.indices.iter()) {
+            let applies = permission.privileges.iter().any(|privilege_name| IndexPrivilege::Read.granted_by(privilege_name))
+                && permission.names.iter().any(|pattern| names.iter().any(|name| wildcard_match(pattern, name)));
+            if !applies {
+                continue;
+            }
+
+            match permission.query {
+                Some(ref query) => queries.push(query.clone()),
+                None => return None,
+            }
+        }
+
+        if queries.is_empty() {
+            None
+        } else {
+            Some(queries)
+        }
+    }
+
+    /// Builds the filter that the queries from `document_queries` put on an index's documents
+    pub fn document_filter(&self, names: &[&str], index_metadata: &IndexMetadata, schema: &Schema) -> Option<Query> {
+        let queries = self.document_queries(names)?;
+
+        Some(Query::Disjunction {
+            queries: queries.iter().map(|query_json| build_filter(query_json, index_metadata, schema)).collect(),
+        })
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct RoleState {
+    roles: BTreeMap<String, Role>,
+
+    /// Role names, by username
+    users: BTreeMap<String, Vec<String>>,
+
+    /// Role names, by API key id
+    api_keys: BTreeMap<String, Vec<String>>,
+}
+
+
+fn roles_to_json(assignments: &BTreeMap<String, Vec<String>>) -> Json {
+    Json::Object(assignments.iter().map(|(name, roles)| (name.clone(), json!(roles))).collect())
+}
+
+
+fn roles_from_json(json: Option<&Json>) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut assignments = BTreeMap::new();
+
+    if let Some(json) = json {
+        let json = json.as_object().ok_or("role assignments must be an object")?;
+        for (name, roles) in json.iter() {
+            let mut wrapped = Map::new();
+            wrapped.insert("roles".to_string(), roles.clone());
+            assignments.insert(name.clone(), parse_string_list(&Json::Object(wrapped), "roles", None)?);
+        }
+    }
+
+    Ok(assignments)
+}
+
+
+#[derive(Debug)]
+pub struct RoleRegistry {
+    state: RwLock<RoleState>,
+}
+
+
+impl RoleRegistry {
+    pub fn new() -> RoleRegistry {
+        RoleRegistry {
+            state: RwLock::new(RoleState::default()),
+        }
+    }
+
+    /// Loads the roles and assignments from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load roles: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load roles: {}", e))?;
+
+        let json: Json = serde_json::from_str(&s).map_err(|e| format!("failed to load roles: {}", e))?;
+        let mut state = RoleState::default();
+
+        if let Some(roles) = json.get("roles").and_then(|roles| roles.as_object()) {
+            for (name, role) in roles.iter() {
+                let role = Role::from_json(role).map_err(|e| format!("failed to load role {:?}: {}", name, e))?;
+                state.roles.insert(name.clone(), role);
+            }
+        }
+
+        state.users = roles_from_json(json.get("users")).map_err(|e| format!("failed to load roles: {}", e))?;
+        state.api_keys = roles_from_json(json.get("api_keys")).map_err(|e| format!("failed to load roles: {}", e))?;
+
+        *self.state.write().unwrap() = state;
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, state: &RoleState) -> Result<(), String> {
+        let json = json!({
+            "roles": Json::Object(state.roles.iter().map(|(name, role)| (name.clone(), role.to_json())).collect()),
+            "users": roles_to_json(&state.users),
+            "api_keys": roles_to_json(&state.api_keys),
+        });
+        let s = serde_json::to_string(&json).map_err(|e| format!("failed to save roles: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save roles: {}", e))?;
+
+        Ok(())
+    }
+
+    /// Changes the state and saves it to the file, undoing the change if it couldn't be saved
+    fn update<T, F: FnOnce(&mut RoleState) -> T>(&self, path: &Path, f: F) -> Result<T, String> {
+        let mut state = self.state.write().unwrap();
+        let previous = state.clone();
+        let result = f(&mut state);
+
+        if let Err(e) = self.save(path, &state) {
+            *state = previous;
+            return Err(e);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the role with the given name, including the built-in ones
+    pub fn get_role(&self, name: &str) -> Option<Role> {
+        if name == SUPERUSER_ROLE {
+            return Some(Role::superuser());
+        }
+
+        self.state.read().unwrap().roles.get(name).cloned()
+    }
+
+    /// All roles by name, including the built-in ones
+    pub fn roles(&self) -> BTreeMap<String, Role> {
+        let mut roles = self.state.read().unwrap().roles.clone();
+        roles.insert(SUPERUSER_ROLE.to_string(), Role::superuser());
+        roles
+    }
+
+    /// Creates or replaces a role. Returns true if it was created
+    pub fn put_role<P: AsRef<Path>>(&self, path: P, name: &str, role: Role) -> Result<bool, String> {
+        self.update(path.as_ref(), |state| state.roles.insert(name.to_string(), role).is_none())
+    }
+
+    /// Deletes a role. Returns false if it didn't exist
+    ///
+    /// Users and API keys that were assigned the role keep the assignment, but it doesn't
+    /// grant anything unless a role with the same name is created again.
+    pub fn delete_role<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<bool, String> {
+        if !self.state.read().unwrap().roles.contains_key(name) {
+            return Ok(false);
+        }
+
+        self.update(path.as_ref(), |state| state.roles.remove(name).is_some())
+    }
+
+    pub fn assign_user_roles<P: AsRef<Path>>(&self, path: P, username: &str, roles: Vec<String>) -> Result<(), String> {
+        self.update(path.as_ref(), |state| {
+            state.users.insert(username.to_string(), roles);
+        })
+    }
+
+    pub fn assign_api_key_roles<P: AsRef<Path>>(&self, path: P, id: &str, roles: Vec<String>) -> Result<(), String> {
+        self.update(path.as_ref(), |state| {
+            state.api_keys.insert(id.to_string(), roles);
+        })
+    }
+
+    /// Forgets the roles of API keys that have been invalidated
+    pub fn remove_api_keys<P: AsRef<Path>>(&self, path: P, ids: &[String]) -> Result<(), String> {
+        self.update(path.as_ref(), |state| {
+            for id in ids.iter() {
+                state.api_keys.remove(id);
+            }
+        })
+    }
+
+    /// The names of the roles a principal has
+    pub fn principal_roles(&self, settings: &Settings, principal: &Principal) -> Vec<String> {
+        let state = self.state.read().unwrap();
+        let assigned = match *principal {
+            Principal::Anonymous => return settings.anonymous_roles.clone(),
+            Principal::User(ref username) => state.users.get(username),
+            Principal::ApiKey { ref id, .. } => state.api_keys.get(id),
+        };
+
+        match assigned {
+            Some(roles) => roles.clone(),
+            None => vec![SUPERUSER_ROLE.to_string()],
+        }
+    }
+
+    /// What a principal is allowed to do. Roles that don't exist are ignored
+    pub fn permissions(&self, settings: &Settings, principal: &Principal) -> Permissions {
+        let roles = self.principal_roles(settings, principal).iter().filter_map(|name| self.get_role(name)).collect();
+
+        Permissions::new(roles)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json;
+
+    use settings::Settings;
+    use security::Principal;
+    use search::{Query, Term};
+    use search::schema::{Schema, FieldType, FIELD_INDEXED};
+    use index::metadata::IndexMetadata;
+
+    use super::{Role, RoleRegistry, Permissions, ClusterPrivilege, IndexPrivilege};
+
+    #[test]
+    fn test_parse_role() {
+        let json = json!({
+            "cluster": ["monitor"],
+            "indices": [
+                {"names": ["logs-*", "metrics"], "privileges": ["read", "write"]}
+            ]
+        });
+        let role = Role::from_json(&json).unwrap();
+        assert_eq!(role.cluster, vec!["monitor".to_string()]);
+        assert_eq!(role.indices[0].names, vec!["logs-*".to_string(), "metrics".to_string()]);
+        assert_eq!(Role::from_json(&role.to_json()), Ok(role));
+
+        assert!(Role::from_json(&json!({"cluster": ["everything"]})).is_err());
+        assert!(Role::from_json(&json!({"indices": [{"names": ["logs"], "privileges": ["delete"]}]})).is_err());
+        assert!(Role::from_json(&json!({"indices": [{"privileges": ["read"]}]})).is_err());
+        assert!(Role::from_json(&json!({"run_as": []})).is_err());
+        assert!(Role::from_json(&json!({"indices": [{"names": ["logs"], "fields": ["title"]}]})).is_err());
+        assert!(Role::from_json(&json!({"indices": [{"names": ["logs"], "query": {"foo": {}}}]})).is_err());
+        assert!(Role::from_json(&serde_json::from_str("[]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_permissions() {
+        let role = Role::from_json(&json!({
+            "cluster": ["manage"],
+            "indices": [
+                {"names": ["logs-*"], "privileges": ["read"]},
+                {"names": ["metrics"], "privileges": ["all"]}
+            ]
+        })).unwrap();
+        let permissions = Permissions::new(vec![role]);
+
+        assert!(permissions.allows_cluster(ClusterPrivilege::Monitor));
+        assert!(permissions.allows_cluster(ClusterPrivilege::Manage));
+        assert!(!permissions.allows_cluster(ClusterPrivilege::ManageSecurity));
+
+        assert!(permissions.allows_index("logs-2017", IndexPrivilege::Read));
+        assert!(permissions.allows_index("logs-*", IndexPrivilege::Read));
+        assert!(!permissions.allows_index("logs-2017", IndexPrivilege::Write));
+        assert!(permissions.allows_index("metrics", IndexPrivilege::Admin));
+        assert!(permissions.allows_index("logs-2017,metrics", IndexPrivilege::Read));
+        assert!(!permissions.allows_index("logs-2017,other", IndexPrivilege::Read));
+        assert!(!permissions.allows_index("*", IndexPrivilege::Read));
+        assert!(!permissions.allows_index("_all", IndexPrivilege::Read));
+
+        let superuser = Permissions::new(vec![Role::superuser()]);
+        assert!(superuser.allows_cluster(ClusterPrivilege::ManageSecurity));
+        assert!(superuser.allows_index("_all", IndexPrivilege::Admin));
+
+        let nothing = Permissions::default();
+        assert!(!nothing.allows_cluster(ClusterPrivilege::Monitor));
+        assert!(!nothing.allows_index("logs", IndexPrivilege::Read));
+    }
+
+    #[test]
+    fn test_document_queries() {
+        let role = Role::from_json(&json!({
+            "indices": [
+                {"names": ["shared"], "privileges": ["read"], "query": {"term": {"tenant": "acme"}}},
+                {"names": ["shared"], "privileges": ["write"]},
+                {"names": ["logs-*"], "privileges": ["read"]}
+            ]
+        })).unwrap();
+        assert_eq!(Role::from_json(&role.to_json()), Ok(role.clone()));
+        let tenant = Role::from_json(&json!({
+            "indices": [{"names": ["shared"], "privileges": ["all"], "query": {"term": {"tenant": "globex"}}}]
+        })).unwrap();
+
+        let permissions = Permissions::new(vec![role.clone()]);
+        assert_eq!(permissions.document_queries(&["shared"]), Some(vec![json!({"term": {"tenant": "acme"}})]));
+        assert_eq!(permissions.document_queries(&["current", "shared"]), Some(vec![json!({"term": {"tenant": "acme"}})]));
+        assert_eq!(permissions.document_queries(&["logs-2017"]), None);
+        assert_eq!(permissions.document_queries(&["other"]), None);
+
+        // Documents matching any of the queries can be read
+        let permissions = Permissions::new(vec![role.clone(), tenant]);
+        assert_eq!(permissions.document_queries(&["shared"]), Some(vec![json!({"term": {"tenant": "acme"}}), json!({"term": {"tenant": "globex"}})]));
+
+        // A role without a query lets every document be read
+        let permissions = Permissions::new(vec![role, Role::superuser()]);
+        assert_eq!(permissions.document_queries(&["shared"]), None);
+    }
+
+    #[test]
+    fn test_document_filter() {
+        let role = Role::from_json(&json!({
+            "indices": [{"names": ["shared"], "privileges": ["read"], "query": {"term": {"tenant": "acme"}}}]
+        })).unwrap();
+        let permissions = Permissions::new(vec![role]);
+        let index_metadata = IndexMetadata::default();
+
+        let mut schema = Schema::new();
+        assert_eq!(permissions.document_filter(&["other"], &index_metadata, &schema), None);
+
+        // Nothing can be read until the field the query uses is mapped
+        assert_eq!(permissions.document_filter(&["shared"], &index_metadata, &schema), Some(Query::Disjunction {
+            queries: vec![Query::None],
+        }));
+
+        let tenant_field = schema.add_field("tenant".to_string(), FieldType::PlainString, FIELD_INDEXED).unwrap();
+        assert_eq!(permissions.document_filter(&["shared"], &index_metadata, &schema), Some(Query::Disjunction {
+            queries: vec![Query::term(tenant_field, Term::from_string("acme"))],
+        }));
+    }
+
+    #[test]
+    fn test_registry() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_roles.json";
+        let _ = fs::remove_file(path);
+
+        let mut settings = Settings::default();
+        settings.anonymous_roles = vec!["reader".to_string()];
+
+        let registry = RoleRegistry::new();
+        let reader = Role::from_json(&json!({"indices": [{"names": ["*"], "privileges": ["read"]}]})).unwrap();
+        assert_eq!(registry.put_role(path, "reader", reader.clone()), Ok(true));
+        assert_eq!(registry.put_role(path, "reader", reader.clone()), Ok(false));
+        registry.assign_user_roles(path, "alice", vec!["reader".to_string(), "missing".to_string()]).unwrap();
+
+        let alice = Principal::User("alice".to_string());
+        let bob = Principal::User("bob".to_string());
+        let key = Principal::ApiKey { id: "abc".to_string(), name: "ci".to_string() };
+
+        // Principals without roles fall back to superuser, apart from anonymous requests
+        assert_eq!(registry.principal_roles(&settings, &alice), vec!["reader".to_string(), "missing".to_string()]);
+        assert_eq!(registry.principal_roles(&settings, &bob), vec!["superuser".to_string()]);
+        assert_eq!(registry.principal_roles(&settings, &key), vec!["superuser".to_string()]);
+        assert_eq!(registry.principal_roles(&settings, &Principal::Anonymous), vec!["reader".to_string()]);
+
+        let permissions = registry.permissions(&settings, &alice);
+        assert!(permissions.allows_index("logs", IndexPrivilege::Read));
+        assert!(!permissions.allows_index("logs", IndexPrivilege::Write));
+
+        registry.assign_api_key_roles(path, "abc", vec![]).unwrap();
+        assert!(!registry.permissions(&settings, &key).allows_index("logs", IndexPrivilege::Read));
+
+        // Roles and assignments are saved
+        let loaded = RoleRegistry::new();
+        loaded.load(path).unwrap();
+        assert_eq!(loaded.get_role("reader"), Some(reader));
+        assert_eq!(loaded.principal_roles(&settings, &alice), vec!["reader".to_string(), "missing".to_string()]);
+        assert_eq!(loaded.principal_roles(&settings, &key), Vec::<String>::new());
+
+        loaded.remove_api_keys(path, &["abc".to_string()]).unwrap();
+        assert_eq!(loaded.principal_roles(&settings, &key), vec!["superuser".to_string()]);
+
+        assert_eq!(loaded.delete_role(path, "reader"), Ok(true));
+        assert_eq!(loaded.delete_role(path, "reader"), Ok(false));
+        assert!(!loaded.permissions(&settings, &alice).allows_index("logs", IndexPrivilege::Read));
+        assert!(loaded.get_role("superuser").is_some());
+    }
+}
@@ -0,0 +1,877 @@
+//! Node settings, read at startup
+//!
+//! Each setting is taken from the first of these that has it:
+//!
+//!  - Command line arguments, e.g. `--port 9201` or `--port=9201`
+//!  - Environment variables, e.g. `RUSTICSEARCH_PORT=9201`
+//!  - The config file, e.g. `port = 9201`
+//!  - The defaults
+//!
+//! The config file is TOML. It's read from the path given by `--config` or
+//! `RUSTICSEARCH_CONFIG`, or from "rusticsearch.toml" in the working directory if it exists.
+//!
+//! Users and API keys (see security/mod.rs) can only be set in the config file, in `[users]`
+//! and `[api_keys]` tables that map names to passwords and secrets. CORS is set in a `[cors]`
+//! table, apart from the allowed origins which can also be given as an option. The thread
+//! pools are set in `[thread_pool.search]`, `[thread_pool.write]` and `[thread_pool.management]`
+//! tables, which each take a `size` and a `queue_size`. The secret that the nodes of a
+//! cluster share (see cluster/coordinator.rs) can also only be set in the config file.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use slog::Level;
+use toml;
+
+use security::roles::SUPERUSER_ROLE;
+use source_filter::wildcard_match;
+use lifecycle::parse_byte_size;
+
+
+const DEFAULT_CONFIG_PATH: &'static str = "rusticsearch.toml";
+const DEFAULT_DATA_DIR: &'static str = "data/";
+const DEFAULT_BIND_HOST: &'static str = "localhost";
+const DEFAULT_PORT: u16 = 9200;
+const DEFAULT_MAX_CONTENT_LENGTH: u64 = 100 * 1024 * 1024;
+const DEFAULT_IN_FLIGHT_REQUESTS_LIMIT: MemoryLimit = MemoryLimit::Fraction(0.5);
+const DEFAULT_INDEX_BUFFER_SIZE: u64 = 128 * 1024 * 1024;
+const DEFAULT_CLUSTER_NAME: &'static str = "rusticsearch";
+
+/// Prefix of the environment variables that override the config file
+const ENV_PREFIX: &'static str = "RUSTICSEARCH_";
+
+pub const USAGE: &'static str = "Usage: rusticsearch [options]
+
+Options:
+    --config PATH       Read settings from a TOML file (default: rusticsearch.toml, if it exists)
+    --data-dir PATH     Directory to store indices in (default: data/)
+    --bind HOST         Address to listen on (default: localhost)
+    --port PORT         Port to listen on (default: 9200)
+    --grpc-port PORT    Port to serve the gRPC API on (default: none, gRPC is disabled)
+    --log-level LEVEL   One of critical, error, warning, info, debug or trace (default: info)
+    --anonymous-access BOOL
+                        Allow requests without credentials (default: true)
+    --anonymous-roles ROLES
+                        Comma-separated roles of requests without credentials
+                        (default: superuser)
+    --max-content-length SIZE
+                        Largest request body that's accepted, e.g. 50mb (default: 100mb)
+    --in-flight-requests-limit LIMIT
+                        Memory the bodies of the requests being handled can use before
+                        more are refused, as a size or a percentage of the machine's
+                        memory (default: 50%)
+    --index-buffer-size SIZE
+                        Memory each shard can buffer writes in before they're flushed
+                        to disk, e.g. 64mb (default: 128mb)
+    --cors-allow-origin ORIGINS
+                        Comma-separated origins that browsers may make requests from,
+                        which can use * as a wildcard (default: none, CORS is disabled)
+    --cluster-name NAME Nodes only form a cluster with nodes of the same name
+                        (default: rusticsearch)
+    --node-name NAME    Name of this node (default: the start of its id)
+    --discovery-seed-hosts HOSTS
+                        Comma-separated addresses of other nodes to form a cluster with,
+                        e.g. 10.0.0.2:9200 (default: none, the node runs on its own)
+    --minimum-master-nodes COUNT
+                        How many nodes must be able to see each other to elect a
+                        master (default: 1)
+    --publish-host HOST Address the other nodes reach this one at (default: the bind
+                        address)
+    --reindex-remote-whitelist HOSTS
+                        Comma-separated host:port of other clusters that documents can
+                        be reindexed from, which can use * as a wildcard (default: none)
+    --audit-log PATH    File to record authentication failures, refused requests and
+                        changes to indices and security in, as JSON lines (default:
+                        none, auditing is disabled)
+    --help              Show this message
+
+Each option can also be set with an environment variable, e.g. RUSTICSEARCH_DATA_DIR.";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Where indices, stored scripts and lifecycle policies are kept
+    pub data_dir: PathBuf,
+
+    /// Host name or address the API server listens on
+    pub bind_host: String,
+
+    /// Port the API server listens on
+    pub port: u16,
+
+    /// Port the gRPC API is served on, on the bind host. It isn't served if this is None
+    pub grpc_port: Option<u16>,
+
+    /// Messages below this level aren't logged
+    pub log_level: Level,
+
+    /// `http.max_content_length`. Requests with larger bodies are refused
+    pub max_content_length: u64,
+
+    /// Requests are refused once the bodies of the requests being handled would add up to
+    /// more than this
+    pub in_flight_requests_limit: MemoryLimit,
+
+    /// Bytes of writes each shard buffers in memory. Writes are flushed to disk in the
+    /// background as the buffer fills up, and stalled if it's full
+    pub index_buffer_size: u64,
+
+    /// Whether requests without credentials are allowed
+    pub anonymous_access: bool,
+
+    /// Names of the roles that requests without credentials have
+    pub anonymous_roles: Vec<String>,
+
+    /// Passwords for HTTP Basic auth, by username
+    pub users: BTreeMap<String, String>,
+
+    /// Secrets of the API keys that aren't created through the API, by name
+    pub api_keys: BTreeMap<String, String>,
+
+    pub cors: CorsSettings,
+
+    pub thread_pool: ThreadPoolsSettings,
+
+    /// `cluster.name`. Nodes only join nodes with the same cluster name
+    pub cluster_name: String,
+
+    /// `node.name`. Defaults to the start of the node's id
+    pub node_name: Option<String>,
+
+    /// `discovery.seed_hosts`. Addresses of other nodes to form a cluster with, as
+    /// "host:port". The node runs on its own if there aren't any
+    pub discovery_seed_hosts: Vec<String>,
+
+    /// `discovery.zen.minimum_master_nodes`. How many nodes must be able to see each other
+    /// before one of them can be elected master
+    pub minimum_master_nodes: usize,
+
+    /// Host name or address the other nodes reach this one at. Defaults to the bind host
+    pub publish_host: Option<String>,
+
+    /// Shared by the nodes of a cluster, which send it when they talk to each other
+    pub cluster_secret: Option<String>,
+
+    /// `reindex.remote.whitelist`. Addresses of the clusters that documents can be reindexed
+    /// from, as "host:port" patterns that can use `*`. Nothing can be if this is empty
+    pub reindex_remote_whitelist: Vec<String>,
+
+    /// File that audit events are appended to (see `audit`). Nothing is audited if this is None
+    pub audit_log: Option<PathBuf>,
+}
+
+
+/// An amount of memory, either in bytes or as a fraction of the machine's memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryLimit {
+    Bytes(u64),
+    Fraction(f64),
+}
+
+
+impl MemoryLimit {
+    /// Parses a byte size such as "512mb" or a percentage such as "50%"
+    pub fn parse(value: &str) -> Result<MemoryLimit, String> {
+        let value = value.trim();
+
+        if value.ends_with('%') {
+            return match value[..value.len() - 1].trim().parse::<f64>() {
+                Ok(percentage) if percentage > 0.0 && percentage <= 100.0 => Ok(MemoryLimit::Fraction(percentage / 100.0)),
+                _ => Err(format!("invalid percentage: {:?}", value)),
+            };
+        }
+
+        parse_byte_size(value).map(MemoryLimit::Bytes).ok_or_else(|| format!("invalid byte size: {:?}", value))
+    }
+
+    /// The limit in bytes, given the machine's memory. A fraction of an unknown amount of
+    /// memory isn't a limit
+    pub fn to_bytes(&self, total_memory: Option<u64>) -> Option<u64> {
+        match *self {
+            MemoryLimit::Bytes(bytes) => Some(bytes),
+            MemoryLimit::Fraction(fraction) => total_memory.map(|total_memory| (total_memory as f64 * fraction) as u64),
+        }
+    }
+
+    /// Formats the limit the way it's parsed, such as "50%"
+    pub fn format(&self) -> String {
+        match *self {
+            MemoryLimit::Bytes(bytes) => format!("{}b", bytes),
+            MemoryLimit::Fraction(fraction) => format!("{}%", fraction * 100.0),
+        }
+    }
+}
+
+
+/// Which cross-origin requests browsers are allowed to make
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsSettings {
+    /// Origins, such as "https://dashboard.example.com", or patterns using `*`. CORS is
+    /// disabled if this is empty
+    pub allow_origin: Vec<String>,
+
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+
+    /// Whether requests can include cookies and `Authorization` headers
+    pub allow_credentials: bool,
+
+    /// How long browsers can cache the response to a preflight request, in seconds
+    pub max_age: u64,
+}
+
+
+impl Default for CorsSettings {
+    fn default() -> CorsSettings {
+        CorsSettings {
+            allow_origin: Vec::new(),
+            allow_methods: ["OPTIONS", "HEAD", "GET", "POST", "PUT", "DELETE"].iter().map(|method| method.to_string()).collect(),
+            allow_headers: ["X-Requested-With", "Content-Type", "Content-Length", "Authorization"].iter().map(|header| header.to_string()).collect(),
+            allow_credentials: false,
+            max_age: 1_728_000,
+        }
+    }
+}
+
+
+impl CorsSettings {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow_origin.is_empty()
+    }
+
+    /// The `Access-Control-Allow-Origin` header to send to a request from `origin`, or None
+    /// if the origin isn't allowed
+    ///
+    /// "*" is only sent if any origin is allowed and credentials aren't, as browsers don't
+    /// accept it with credentials.
+    pub fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.allow_origin.iter().any(|pattern| pattern == "*") && !self.allow_credentials {
+            return Some("*".to_string());
+        }
+
+        if self.allow_origin.iter().any(|pattern| wildcard_match(pattern, origin)) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+
+/// The number of threads in a thread pool and how much work can wait for them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThreadPoolSettings {
+    pub size: usize,
+    pub queue_size: usize,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadPoolsSettings {
+    pub search: ThreadPoolSettings,
+    pub write: ThreadPoolSettings,
+    pub management: ThreadPoolSettings,
+}
+
+
+impl Default for ThreadPoolsSettings {
+    /// Searches wait on locks and their parallel segment searches, so they get more threads
+    /// than there are CPUs. Indexing keeps its threads busy, so it gets one per CPU
+    fn default() -> ThreadPoolsSettings {
+        let cpus = thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+
+        ThreadPoolsSettings {
+            search: ThreadPoolSettings {
+                size: cpus * 3 / 2 + 1,
+                queue_size: 1000,
+            },
+            write: ThreadPoolSettings {
+                size: cpus,
+                queue_size: 10000,
+            },
+            management: ThreadPoolSettings {
+                size: 5,
+                queue_size: 1000,
+            },
+        }
+    }
+}
+
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            bind_host: DEFAULT_BIND_HOST.to_string(),
+            port: DEFAULT_PORT,
+            grpc_port: None,
+            log_level: Level::Info,
+            max_content_length: DEFAULT_MAX_CONTENT_LENGTH,
+            in_flight_requests_limit: DEFAULT_IN_FLIGHT_REQUESTS_LIMIT,
+            index_buffer_size: DEFAULT_INDEX_BUFFER_SIZE,
+            anonymous_access: true,
+            anonymous_roles: vec![SUPERUSER_ROLE.to_string()],
+            users: BTreeMap::new(),
+            api_keys: BTreeMap::new(),
+            cors: CorsSettings::default(),
+            thread_pool: ThreadPoolsSettings::default(),
+            cluster_name: DEFAULT_CLUSTER_NAME.to_string(),
+            node_name: None,
+            discovery_seed_hosts: Vec::new(),
+            minimum_master_nodes: 1,
+            publish_host: None,
+            cluster_secret: None,
+            reindex_remote_whitelist: Vec::new(),
+            audit_log: None,
+        }
+    }
+}
+
+
+/// The contents of a config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    data_dir: Option<PathBuf>,
+    bind: Option<String>,
+    port: Option<u16>,
+    grpc_port: Option<u16>,
+    log_level: Option<String>,
+    max_content_length: Option<String>,
+    in_flight_requests_limit: Option<String>,
+    index_buffer_size: Option<String>,
+    anonymous_access: Option<bool>,
+    anonymous_roles: Option<Vec<String>>,
+    users: Option<BTreeMap<String, String>>,
+    api_keys: Option<BTreeMap<String, String>>,
+    cors: Option<CorsConfig>,
+    thread_pool: Option<ThreadPoolsConfig>,
+    cluster_name: Option<String>,
+    node_name: Option<String>,
+    discovery_seed_hosts: Option<Vec<String>>,
+    minimum_master_nodes: Option<usize>,
+    publish_host: Option<String>,
+    cluster_secret: Option<String>,
+    reindex_remote_whitelist: Option<Vec<String>>,
+    audit_log: Option<PathBuf>,
+}
+
+
+/// The `[cors]` table of a config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorsConfig {
+    allow_origin: Option<Vec<String>>,
+    allow_methods: Option<Vec<String>>,
+    allow_headers: Option<Vec<String>>,
+    allow_credentials: Option<bool>,
+    max_age: Option<u64>,
+}
+
+
+/// The `[thread_pool]` tables of a config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThreadPoolsConfig {
+    search: Option<ThreadPoolConfig>,
+    write: Option<ThreadPoolConfig>,
+    management: Option<ThreadPoolConfig>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThreadPoolConfig {
+    size: Option<usize>,
+    queue_size: Option<usize>,
+}
+
+
+impl ThreadPoolConfig {
+    fn apply(&self, name: &str, settings: &mut ThreadPoolSettings) -> Result<(), String> {
+        if let Some(size) = self.size {
+            if size == 0 {
+                return Err(format!("thread_pool.{}.size must be at least 1", name));
+            }
+
+            settings.size = size;
+        }
+
+        if let Some(queue_size) = self.queue_size {
+            settings.queue_size = queue_size;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Splits a comma-separated option into its values
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()).map(|item| item.to_string()).collect()
+}
+
+
+fn parse_log_level(value: &str) -> Result<Level, String> {
+    match value.to_lowercase().as_ref() {
+        "critical" | "crit" => Ok(Level::Critical),
+        "error" => Ok(Level::Error),
+        "warning" | "warn" => Ok(Level::Warning),
+        "info" => Ok(Level::Info),
+        "debug" => Ok(Level::Debug),
+        "trace" => Ok(Level::Trace),
+        _ => Err(format!("unrecognised log level: {:?}", value)),
+    }
+}
+
+
+/// Splits command line arguments into option names and values
+///
+/// Values can either follow the option (`--port 9201`) or be joined to it with an equals sign
+/// (`--port=9201`). `--help` is returned with an empty value.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Vec<(String, String)>, String> {
+    let mut options = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument: {:?}", arg));
+        }
+
+        let arg = &arg[2..];
+        if arg == "help" {
+            options.push(("help".to_string(), String::new()));
+            continue;
+        }
+
+        match arg.find('=') {
+            Some(equals) => options.push((arg[..equals].to_string(), arg[equals + 1..].to_string())),
+            None => {
+                let value = args.next().ok_or_else(|| format!("missing value for --{}", arg))?;
+                options.push((arg.to_string(), value));
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+
+impl Settings {
+    /// Sets an option from a command line argument or environment variable
+    ///
+    /// `name` is the name of the command line argument without the leading dashes.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "data-dir" => self.data_dir = PathBuf::from(value),
+            "bind" => self.bind_host = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| format!("invalid port: {:?}", value))?,
+            "grpc-port" => self.grpc_port = Some(value.parse().map_err(|_| format!("invalid grpc port: {:?}", value))?),
+            "log-level" => self.log_level = parse_log_level(value)?,
+            "max-content-length" => self.max_content_length = parse_byte_size(value).ok_or_else(|| format!("invalid byte size: {:?}", value))?,
+            "in-flight-requests-limit" => self.in_flight_requests_limit = MemoryLimit::parse(value)?,
+            "index-buffer-size" => {
+                self.index_buffer_size = match parse_byte_size(value) {
+                    Some(size) if size > 0 => size,
+                    _ => return Err(format!("invalid byte size: {:?}", value)),
+                };
+            }
+            "anonymous-access" => {
+                self.anonymous_access = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(format!("anonymous-access must be true or false: {:?}", value)),
+                };
+            }
+            "anonymous-roles" => self.anonymous_roles = parse_list(value),
+            "cors-allow-origin" => self.cors.allow_origin = parse_list(value),
+            "cluster-name" => self.cluster_name = value.to_string(),
+            "node-name" => self.node_name = Some(value.to_string()),
+            "discovery-seed-hosts" => self.discovery_seed_hosts = parse_list(value),
+            "minimum-master-nodes" => self.minimum_master_nodes = value.parse().map_err(|_| format!("invalid node count: {:?}", value))?,
+            "publish-host" => self.publish_host = Some(value.to_string()),
+            "reindex-remote-whitelist" => self.reindex_remote_whitelist = parse_list(value),
+            "audit-log" => self.audit_log = Some(PathBuf::from(value)),
+            _ => return Err(format!("unrecognised option: --{}", name)),
+        }
+
+        Ok(())
+    }
+
+    fn apply_config_file(&mut self, config: &str) -> Result<(), String> {
+        let config: ConfigFile = toml::from_str(config).map_err(|e| e.to_string())?;
+
+        if let Some(data_dir) = config.data_dir {
+            self.data_dir = data_dir;
+        }
+
+        if let Some(bind_host) = config.bind {
+            self.bind_host = bind_host;
+        }
+
+        if let Some(port) = config.port {
+            self.port = port;
+        }
+
+        if let Some(grpc_port) = config.grpc_port {
+            self.grpc_port = Some(grpc_port);
+        }
+
+        if let Some(log_level) = config.log_level {
+            self.log_level = parse_log_level(&log_level)?;
+        }
+
+        if let Some(max_content_length) = config.max_content_length {
+            self.set("max-content-length", &max_content_length)?;
+        }
+
+        if let Some(in_flight_requests_limit) = config.in_flight_requests_limit {
+            self.set("in-flight-requests-limit", &in_flight_requests_limit)?;
+        }
+
+        if let Some(index_buffer_size) = config.index_buffer_size {
+            self.set("index-buffer-size", &index_buffer_size)?;
+        }
+
+        if let Some(anonymous_access) = config.anonymous_access {
+            self.anonymous_access = anonymous_access;
+        }
+
+        if let Some(anonymous_roles) = config.anonymous_roles {
+            self.anonymous_roles = anonymous_roles;
+        }
+
+        if let Some(users) = config.users {
+            self.users = users;
+        }
+
+        if let Some(api_keys) = config.api_keys {
+            self.api_keys = api_keys;
+        }
+
+        if let Some(cors) = config.cors {
+            if let Some(allow_origin) = cors.allow_origin {
+                self.cors.allow_origin = allow_origin;
+            }
+
+            if let Some(allow_methods) = cors.allow_methods {
+                self.cors.allow_methods = allow_methods;
+            }
+
+            if let Some(allow_headers) = cors.allow_headers {
+                self.cors.allow_headers = allow_headers;
+            }
+
+            if let Some(allow_credentials) = cors.allow_credentials {
+                self.cors.allow_credentials = allow_credentials;
+            }
+
+            if let Some(max_age) = cors.max_age {
+                self.cors.max_age = max_age;
+            }
+        }
+
+        if let Some(cluster_name) = config.cluster_name {
+            self.cluster_name = cluster_name;
+        }
+
+        if let Some(node_name) = config.node_name {
+            self.node_name = Some(node_name);
+        }
+
+        if let Some(discovery_seed_hosts) = config.discovery_seed_hosts {
+            self.discovery_seed_hosts = discovery_seed_hosts;
+        }
+
+        if let Some(minimum_master_nodes) = config.minimum_master_nodes {
+            self.minimum_master_nodes = minimum_master_nodes;
+        }
+
+        if let Some(publish_host) = config.publish_host {
+            self.publish_host = Some(publish_host);
+        }
+
+        if let Some(cluster_secret) = config.cluster_secret {
+            self.cluster_secret = Some(cluster_secret);
+        }
+
+        if let Some(reindex_remote_whitelist) = config.reindex_remote_whitelist {
+            self.reindex_remote_whitelist = reindex_remote_whitelist;
+        }
+
+        if let Some(audit_log) = config.audit_log {
+            self.audit_log = Some(audit_log);
+        }
+
+        if let Some(thread_pool) = config.thread_pool {
+            if let Some(ref search) = thread_pool.search {
+                search.apply("search", &mut self.thread_pool.search)?;
+            }
+
+            if let Some(ref write) = thread_pool.write {
+                write.apply("write", &mut self.thread_pool.write)?;
+            }
+
+            if let Some(ref management) = thread_pool.management {
+                management.apply("management", &mut self.thread_pool.management)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the settings from the command line arguments (not including the program name),
+    /// the environment and the config file
+    ///
+    /// Returns None if `--help` was given.
+    pub fn load<I, E>(args: I, get_env: E) -> Result<Option<Settings>, String>
+        where I: IntoIterator<Item = String>,
+              E: Fn(&str) -> Option<String>
+    {
+        let args = parse_args(args)?;
+        if args.iter().any(|&(ref name, _)| name == "help") {
+            return Ok(None);
+        }
+
+        let mut settings = Settings::default();
+
+        // Config file. Only the default path is allowed to be missing
+        let config_path = args.iter().rev().find(|&&(ref name, _)| name == "config").map(|&(_, ref path)| path.clone())
+            .or_else(|| get_env(&format!("{}CONFIG", ENV_PREFIX)));
+        let (config_path, required) = match config_path {
+            Some(config_path) => (config_path, true),
+            None => (DEFAULT_CONFIG_PATH.to_string(), false),
+        };
+
+        match File::open(Path::new(&config_path)) {
+            Ok(mut file) => {
+                let mut config = String::new();
+                file.read_to_string(&mut config).map_err(|e| format!("could not read config file {:?}: {}", config_path, e))?;
+                settings.apply_config_file(&config).map_err(|e| format!("could not read config file {:?}: {}", config_path, e))?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => {}
+            Err(e) => return Err(format!("could not open config file {:?}: {}", config_path, e)),
+        }
+
+        // Environment variables
+        for name in &["data-dir", "bind", "port", "grpc-port", "log-level", "max-content-length", "in-flight-requests-limit", "anonymous-access", "anonymous-roles", "cors-allow-origin",
+                      "cluster-name", "node-name", "discovery-seed-hosts", "minimum-master-nodes", "publish-host", "reindex-remote-whitelist", "audit-log"] {
+            let env_name = format!("{}{}", ENV_PREFIX, name.to_uppercase().replace('-', "_"));
+            if let Some(value) = get_env(&env_name) {
+                settings.set(name, &value).map_err(|e| format!("{}: {}", env_name, e))?;
+            }
+        }
+
+        // Command line arguments
+        for (name, value) in args {
+            if name != "config" {
+                settings.set(&name, &value)?;
+            }
+        }
+
+        // Anyone who could reach the node could otherwise change the cluster state
+        if !settings.discovery_seed_hosts.is_empty() && settings.cluster_secret.is_none() {
+            return Err("cluster_secret must be set in the config file when discovery_seed_hosts is".to_string());
+        }
+
+        Ok(Some(settings))
+    }
+
+    /// The address the API server listens on, e.g. "localhost:9200"
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.bind_host, self.port)
+    }
+
+    /// The address the other nodes of a cluster reach this one at
+    pub fn publish_address(&self) -> String {
+        format!("{}:{}", self.publish_host.as_ref().unwrap_or(&self.bind_host), self.port)
+    }
+
+    /// The seed hosts as "host:port". Hosts without a port use the default one
+    pub fn seed_addresses(&self) -> Vec<String> {
+        self.discovery_seed_hosts.iter().map(|host| {
+            if host.contains(':') {
+                host.clone()
+            } else {
+                format!("{}:{}", host, DEFAULT_PORT)
+            }
+        }).collect()
+    }
+
+    /// Checks if documents can be reindexed from the cluster at an address ("host:port")
+    pub fn allows_remote_reindex(&self, address: &str) -> bool {
+        self.reindex_remote_whitelist.iter().any(|pattern| wildcard_match(pattern, address))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use slog::Level;
+
+    use super::{Settings, MemoryLimit, ThreadPoolsSettings, ThreadPoolSettings};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = Settings::load(args(&["--config", "test_indices/missing.toml"]), |_| None);
+        assert!(settings.is_err());
+
+        let settings = Settings::load(vec![], |_| None).unwrap().unwrap();
+        assert_eq!(settings, Settings::default());
+        assert_eq!(settings.bind_address(), "localhost:9200");
+    }
+
+    #[test]
+    fn test_precedence() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_settings.toml";
+        File::create(path).unwrap().write_all(b"data_dir = \"/var/lib/rusticsearch\"\nport = 9201\nbind = \"0.0.0.0\"\nlog_level = \"warning\"\nanonymous_access = false\nanonymous_roles = [\"reader\"]\n\n[users]\nadmin = \"secret\"\n").unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("RUSTICSEARCH_CONFIG", path);
+        env.insert("RUSTICSEARCH_PORT", "9202");
+        env.insert("RUSTICSEARCH_GRPC_PORT", "9300");
+        env.insert("RUSTICSEARCH_LOG_LEVEL", "debug");
+        let get_env = |name: &str| env.get(name).map(|value| value.to_string());
+
+        let settings = Settings::load(args(&["--log-level=trace"]), get_env).unwrap().unwrap();
+        let mut users = BTreeMap::new();
+        users.insert("admin".to_string(), "secret".to_string());
+        assert_eq!(settings, Settings {
+            data_dir: PathBuf::from("/var/lib/rusticsearch"),
+            bind_host: "0.0.0.0".to_string(),
+            port: 9202,
+            grpc_port: Some(9300),
+            log_level: Level::Trace,
+            anonymous_access: false,
+            anonymous_roles: vec!["reader".to_string()],
+            users: users,
+            ..Settings::default()
+        });
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Settings::load(args(&["--port", "http"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--port"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--grpc-port", "70000"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--color", "red"]), |_| None).is_err());
+        assert!(Settings::load(args(&["data"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--log-level", "loud"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--max-content-length", "big"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--in-flight-requests-limit", "150%"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--index-buffer-size", "0b"]), |_| None).is_err());
+        assert!(Settings::load(vec![], |name| if name == "RUSTICSEARCH_PORT" { Some("0x1".to_string()) } else { None }).is_err());
+        assert_eq!(Settings::load(args(&["--help"]), |_| None), Ok(None));
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let settings = Settings::load(args(&["--max-content-length", "10mb", "--in-flight-requests-limit", "1gb", "--index-buffer-size", "32mb"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.max_content_length, 10 * 1024 * 1024);
+        assert_eq!(settings.index_buffer_size, 32 * 1024 * 1024);
+        assert_eq!(settings.in_flight_requests_limit, MemoryLimit::Bytes(1 << 30));
+        assert_eq!(settings.in_flight_requests_limit.to_bytes(None), Some(1 << 30));
+
+        let settings = Settings::load(args(&["--in-flight-requests-limit", "25%"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.in_flight_requests_limit, MemoryLimit::Fraction(0.25));
+        assert_eq!(settings.in_flight_requests_limit.to_bytes(Some(1000)), Some(250));
+        assert_eq!(settings.in_flight_requests_limit.to_bytes(None), None);
+    }
+
+    #[test]
+    fn test_cors() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_cors.toml";
+        File::create(path).unwrap().write_all(b"[cors]\nallow_origin = [\"https://*.example.com\"]\nallow_credentials = true\nmax_age = 60\n").unwrap();
+
+        let settings = Settings::load(args(&["--config", path]), |_| None).unwrap().unwrap();
+        assert!(settings.cors.is_enabled());
+        assert!(settings.cors.allow_credentials);
+        assert_eq!(settings.cors.max_age, 60);
+        assert_eq!(settings.cors.allowed_origin("https://dashboard.example.com"), Some("https://dashboard.example.com".to_string()));
+        assert_eq!(settings.cors.allowed_origin("https://example.org"), None);
+
+        // Credentials can't be used with "*", so the origin is sent back instead
+        let settings = Settings::load(args(&["--config", path, "--cors-allow-origin", "*"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.cors.allowed_origin("https://example.org"), Some("https://example.org".to_string()));
+
+        let settings = Settings::load(args(&["--cors-allow-origin", "*"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.cors.allowed_origin("https://example.org"), Some("*".to_string()));
+
+        let settings = Settings::load(vec![], |_| None).unwrap().unwrap();
+        assert!(!settings.cors.is_enabled());
+        assert_eq!(settings.cors.allowed_origin("https://example.org"), None);
+    }
+
+    #[test]
+    fn test_cluster() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_cluster.toml";
+        File::create(path).unwrap().write_all(b"cluster_name = \"logging\"\ndiscovery_seed_hosts = [\"10.0.0.2\", \"10.0.0.3:9201\"]\ncluster_secret = \"shared\"\n").unwrap();
+
+        let settings = Settings::load(args(&["--config", path, "--node-name", "first", "--minimum-master-nodes", "2", "--publish-host", "10.0.0.1"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.cluster_name, "logging");
+        assert_eq!(settings.node_name, Some("first".to_string()));
+        assert_eq!(settings.seed_addresses(), vec!["10.0.0.2:9200".to_string(), "10.0.0.3:9201".to_string()]);
+        assert_eq!(settings.minimum_master_nodes, 2);
+        assert_eq!(settings.publish_address(), "10.0.0.1:9200");
+        assert_eq!(settings.cluster_secret, Some("shared".to_string()));
+
+        let settings = Settings::load(args(&["--config", path, "--discovery-seed-hosts", "10.0.0.2,10.0.0.3,10.0.0.4"]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.discovery_seed_hosts.len(), 3);
+        assert_eq!(settings.publish_address(), "localhost:9200");
+
+        // Nodes of a cluster must have a secret
+        assert!(Settings::load(args(&["--discovery-seed-hosts", "10.0.0.2,10.0.0.3"]), |_| None).is_err());
+        assert!(Settings::load(args(&["--minimum-master-nodes", "many"]), |_| None).is_err());
+    }
+
+    #[test]
+    fn test_reindex_remote_whitelist() {
+        let settings = Settings::load(args(&["--reindex-remote-whitelist", "10.0.0.5:9200,*.example.com:9200"]), |_| None).unwrap().unwrap();
+        assert!(settings.allows_remote_reindex("10.0.0.5:9200"));
+        assert!(settings.allows_remote_reindex("old.example.com:9200"));
+        assert!(!settings.allows_remote_reindex("10.0.0.5:9201"));
+        assert!(!settings.allows_remote_reindex("localhost:9200"));
+
+        let settings = Settings::load(vec![], |_| None).unwrap().unwrap();
+        assert!(!settings.allows_remote_reindex("10.0.0.5:9200"));
+    }
+
+    #[test]
+    fn test_thread_pools() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_thread_pools.toml";
+        File::create(path).unwrap().write_all(b"[thread_pool.search]\nsize = 4\n\n[thread_pool.write]\nqueue_size = 50\n").unwrap();
+
+        let defaults = ThreadPoolsSettings::default();
+        let settings = Settings::load(args(&["--config", path]), |_| None).unwrap().unwrap();
+        assert_eq!(settings.thread_pool, ThreadPoolsSettings {
+            search: ThreadPoolSettings {
+                size: 4,
+                queue_size: defaults.search.queue_size,
+            },
+            write: ThreadPoolSettings {
+                size: defaults.write.size,
+                queue_size: 50,
+            },
+            management: defaults.management,
+        });
+
+        File::create(path).unwrap().write_all(b"[thread_pool.management]\nsize = 0\n").unwrap();
+        assert!(Settings::load(args(&["--config", path]), |_| None).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Waiting for the signal to shut down
+//!
+//! SIGTERM and SIGINT start a graceful shutdown (see `System::shutdown`). If a second one
+//! arrives before the shutdown has finished, the process exits straight away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+
+#[cfg(unix)]
+extern "C" fn handle_signal(_: libc::c_int) {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe {
+            libc::_exit(1);
+        }
+    }
+}
+
+
+/// Handles SIGTERM and SIGINT by asking for a shutdown
+///
+/// On other platforms, this does nothing, so the process is killed as usual.
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGTERM, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
+
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+
+/// Blocks until a shutdown is asked for
+pub fn wait_for_shutdown() {
+    while !shutdown_requested() {
+        thread::sleep(Duration::from_millis(100));
+    }
+}
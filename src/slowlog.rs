@@ -0,0 +1,76 @@
+//! Logs searches and indexing operations that take longer than the thresholds in the index's
+//! settings (see `SlowLogSettings`)
+//!
+//! The query and fetch phases of a search have their own thresholds and are logged separately.
+
+use std::time::Duration;
+
+use serde_json::Value as Json;
+use slog::{Logger, Level};
+
+use index::metadata::settings::SlowLogSettings;
+
+
+/// Logs a message at a level that's only known at runtime. slog needs the level to be a constant
+macro_rules! log_at_level {
+    ($log:expr, $level:expr, $($args:tt)+) => {
+        match $level {
+            Level::Critical => crit!($log, $($args)+),
+            Level::Error => error!($log, $($args)+),
+            Level::Warning => warn!($log, $($args)+),
+            Level::Info => info!($log, $($args)+),
+            Level::Debug => debug!($log, $($args)+),
+            Level::Trace => trace!($log, $($args)+),
+        }
+    };
+}
+
+
+fn duration_to_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+
+/// Logs a search against one index if either of its phases was slow
+pub fn log_search(log: &Logger, index_name: &str, settings: &SlowLogSettings, query_took: Duration, fetch_took: Duration, total_hits: u64, source: &Json) {
+    let phases = [
+        ("index.search.slowlog.query", settings.query.level(query_took)),
+        ("index.search.slowlog.fetch", settings.fetch.level(fetch_took)),
+    ];
+
+    for &(name, level) in phases.iter() {
+        if let Some(level) = level {
+            log_at_level!(log, level, "slow search";
+                "slowlog" => name,
+                "index" => index_name,
+                "took_millis" => duration_to_millis(query_took + fetch_took),
+                "query_millis" => duration_to_millis(query_took),
+                "fetch_millis" => duration_to_millis(fetch_took),
+                "total_hits" => total_hits,
+                "source" => source.to_string());
+        }
+    }
+}
+
+
+/// Logs the indexing of a document if it was slow
+///
+/// The source is cut short to the number of characters in the settings.
+pub fn log_index(log: &Logger, index_name: &str, settings: &SlowLogSettings, took: Duration, doc_key: &str, source: &Json) {
+    let level = match settings.index.level(took) {
+        Some(level) => level,
+        None => return,
+    };
+
+    let source = match settings.index_source_chars {
+        Some(chars) => source.to_string().chars().take(chars).collect(),
+        None => source.to_string(),
+    };
+
+    log_at_level!(log, level, "slow indexing";
+        "slowlog" => "index.indexing.slowlog.index",
+        "index" => index_name,
+        "id" => doc_key,
+        "took_millis" => duration_to_millis(took),
+        "source" => source);
+}
@@ -0,0 +1,181 @@
+//! Picks out the parts of a document's source to return in search results
+
+use serde_json::{Map, Value as Json};
+
+
+/// Which parts of a document's source to return
+///
+/// Patterns are matched against dotted paths such as "user.name" and can contain `*`
+/// wildcards. If a pattern matches an object, everything inside that object matches too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceFilter {
+    /// Don't return the source
+    Disabled,
+
+    /// Return the fields matching an include pattern (or all fields if there are none)
+    /// that don't match an exclude pattern
+    Filter {
+        includes: Vec<String>,
+        excludes: Vec<String>,
+    },
+}
+
+
+impl Default for SourceFilter {
+    fn default() -> SourceFilter {
+        SourceFilter::Filter {
+            includes: Vec::new(),
+            excludes: Vec::new(),
+        }
+    }
+}
+
+
+/// Matches a string against a pattern where `*` matches any sequence of characters
+pub fn wildcard_match(pattern: &str, string: &str) -> bool {
+    match pattern.find('*') {
+        Some(star) => {
+            if !string.starts_with(&pattern[..star]) {
+                return false;
+            }
+
+            let rest = &pattern[star + 1..];
+            let string = &string[star..];
+            string.char_indices().map(|(i, _)| i).chain(Some(string.len()))
+                .any(|i| wildcard_match(rest, &string[i..]))
+        }
+        None => pattern == string,
+    }
+}
+
+
+fn filter_value(value: &Json, path: &str, included: bool, includes: &[String], excludes: &[String]) -> Option<Json> {
+    match *value {
+        Json::Object(ref object) => {
+            let filtered = filter_object(object, path, included, includes, excludes);
+
+            // Empty objects are only kept if they were included explicitly
+            if filtered.is_empty() && !(included && object.is_empty()) {
+                None
+            } else {
+                Some(Json::Object(filtered))
+            }
+        }
+        Json::Array(ref array) if array.iter().any(|item| item.is_object()) => {
+            let filtered = array.iter()
+                .filter_map(|item| filter_value(item, path, included, includes, excludes))
+                .collect::<Vec<_>>();
+
+            if filtered.is_empty() && !included {
+                None
+            } else {
+                Some(Json::Array(filtered))
+            }
+        }
+        _ => {
+            if included {
+                Some(value.clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+
+fn filter_object(object: &Map<String, Json>, prefix: &str, included: bool, includes: &[String], excludes: &[String]) -> Map<String, Json> {
+    let mut filtered = Map::new();
+
+    for (key, value) in object.iter() {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+
+        if excludes.iter().any(|pattern| wildcard_match(pattern, &path)) {
+            continue;
+        }
+
+        let included = included || includes.iter().any(|pattern| wildcard_match(pattern, &path));
+        if let Some(value) = filter_value(value, &path, included, includes, excludes) {
+            filtered.insert(key.clone(), value);
+        }
+    }
+
+    filtered
+}
+
+
+impl SourceFilter {
+    /// Filters the source. Returns None if the source shouldn't be returned at all
+    pub fn filter(&self, source: &Map<String, Json>) -> Option<Json> {
+        match *self {
+            SourceFilter::Disabled => None,
+            SourceFilter::Filter{ref includes, ref excludes} => {
+                Some(Json::Object(filter_object(source, "", includes.is_empty(), includes, excludes)))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{SourceFilter, wildcard_match};
+
+    fn make_source() -> serde_json::Map<String, serde_json::Value> {
+        json!({
+            "title": "Hello",
+            "user": {
+                "name": "Alice",
+                "email": "alice@example.com",
+            },
+            "tags": ["a", "b"],
+        }).as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("user.*", "user.name"));
+        assert!(wildcard_match("*.name", "user.name"));
+        assert!(wildcard_match("user.name", "user.name"));
+        assert!(wildcard_match("*", "title"));
+        assert!(!wildcard_match("user.*", "title"));
+        assert!(!wildcard_match("user", "user.name"));
+    }
+
+    #[test]
+    fn test_filter_includes() {
+        let filter = SourceFilter::Filter {
+            includes: vec!["title".to_string(), "user.name".to_string()],
+            excludes: vec![],
+        };
+
+        assert_eq!(filter.filter(&make_source()), Some(json!({
+            "title": "Hello",
+            "user": {
+                "name": "Alice",
+            },
+        })));
+    }
+
+    #[test]
+    fn test_filter_excludes() {
+        let filter = SourceFilter::Filter {
+            includes: vec!["user".to_string(), "tags".to_string()],
+            excludes: vec!["*.email".to_string()],
+        };
+
+        assert_eq!(filter.filter(&make_source()), Some(json!({
+            "user": {
+                "name": "Alice",
+            },
+            "tags": ["a", "b"],
+        })));
+    }
+
+    #[test]
+    fn test_filter_disabled() {
+        assert_eq!(SourceFilter::Disabled.filter(&make_source()), None);
+        assert_eq!(SourceFilter::default().filter(&make_source()), Some(json!(make_source())));
+    }
+}
@@ -0,0 +1,137 @@
+//! Scripts that are stored on the node and referred to by id
+//!
+//! Only mustache search templates can be stored. They're kept in memory and written to
+//! "scripts.json" in the data directory whenever they change.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde_json;
+use atomicwrites::{AtomicFile, AllowOverwrite};
+
+
+pub const MUSTACHE_LANG: &'static str = "mustache";
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredScript {
+    pub lang: String,
+    pub source: String,
+}
+
+
+#[derive(Debug)]
+pub struct StoredScriptRegistry {
+    scripts: RwLock<BTreeMap<String, StoredScript>>,
+}
+
+
+impl StoredScriptRegistry {
+    pub fn new() -> StoredScriptRegistry {
+        StoredScriptRegistry {
+            scripts: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Loads the scripts from a file. Does nothing if the file doesn't exist
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("failed to load stored scripts: {}", e)),
+        };
+
+        let mut s = String::new();
+        file.read_to_string(&mut s).map_err(|e| format!("failed to load stored scripts: {}", e))?;
+
+        let scripts = serde_json::from_str(&s).map_err(|e| format!("failed to load stored scripts: {}", e))?;
+        *self.scripts.write().unwrap() = scripts;
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, scripts: &BTreeMap<String, StoredScript>) -> Result<(), String> {
+        let s = serde_json::to_string(scripts).map_err(|e| format!("failed to save stored scripts: {}", e))?;
+
+        let file = AtomicFile::new(path, AllowOverwrite);
+        file.write(|f| f.write_all(s.as_bytes())).map_err(|e| format!("failed to save stored scripts: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<StoredScript> {
+        self.scripts.read().unwrap().get(id).cloned()
+    }
+
+    /// Adds or replaces a script, saving all of the scripts to the file
+    pub fn insert<P: AsRef<Path>>(&self, path: P, id: String, script: StoredScript) -> Result<(), String> {
+        let mut scripts = self.scripts.write().unwrap();
+        let previous = scripts.insert(id.clone(), script);
+
+        if let Err(e) = self.save(path.as_ref(), &scripts) {
+            // Keep what's in memory the same as what's on disk
+            match previous {
+                Some(previous) => scripts.insert(id, previous),
+                None => scripts.remove(&id),
+            };
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a script, saving the rest to the file. Returns false if there wasn't a script
+    /// with the id
+    pub fn remove<P: AsRef<Path>>(&self, path: P, id: &str) -> Result<bool, String> {
+        let mut scripts = self.scripts.write().unwrap();
+        let previous = match scripts.remove(id) {
+            Some(previous) => previous,
+            None => return Ok(false),
+        };
+
+        if let Err(e) = self.save(path.as_ref(), &scripts) {
+            scripts.insert(id.to_string(), previous);
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{StoredScriptRegistry, StoredScript, MUSTACHE_LANG};
+
+    #[test]
+    fn test_save_and_load() {
+        let _ = fs::create_dir_all("test_indices");
+        let path = "test_indices/test_stored_scripts.json";
+        let _ = fs::remove_file(path);
+
+        let script = StoredScript {
+            lang: MUSTACHE_LANG.to_string(),
+            source: "{\"query\": {\"match\": {\"title\": \"{{q}}\"}}}".to_string(),
+        };
+
+        let scripts = StoredScriptRegistry::new();
+        scripts.load(path).unwrap();
+        assert_eq!(scripts.get("search"), None);
+
+        scripts.insert(path, "search".to_string(), script.clone()).unwrap();
+        scripts.insert(path, "other".to_string(), script.clone()).unwrap();
+        assert_eq!(scripts.remove(path, "other"), Ok(true));
+        assert_eq!(scripts.remove(path, "other"), Ok(false));
+
+        let loaded = StoredScriptRegistry::new();
+        loaded.load(path).unwrap();
+        assert_eq!(loaded.get("search"), Some(script));
+        assert_eq!(loaded.get("other"), None);
+    }
+}
@@ -0,0 +1,450 @@
+//! Completes the prefix of what's being typed into a search box
+//!
+//! Completion fields hold a list of inputs for each document, each with a weight and
+//! optionally some category contexts. The suggester finds the inputs that start with the
+//! prefix, or nearly do when fuzzy matching is enabled, and returns the highest weighted.
+//!
+//! Inputs aren't indexed, every document's inputs are checked against the prefix.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json;
+use serde_json::Value as Json;
+use search::document::{DocId, FieldValue};
+use search::query::Query;
+use search::schema::FieldId;
+use index::reader::IndexReader;
+
+use analysis::lucene_asciifold::fold_to_ascii;
+use document::read_source_field;
+use super::prefix_edit_distance;
+
+
+pub const DEFAULT_MAX_INPUT_LENGTH: usize = 50;
+const DEFAULT_SIZE: usize = 5;
+const DEFAULT_FUZZY_MIN_LENGTH: usize = 3;
+const DEFAULT_FUZZY_PREFIX_LENGTH: usize = 1;
+
+
+/// A set of inputs from a document's completion field that share a weight and contexts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionInput {
+    pub input: Vec<String>,
+    pub weight: u64,
+
+    /// The values of each category context
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub contexts: BTreeMap<String, Vec<String>>,
+}
+
+
+fn parse_strings(json: &Json) -> Option<Vec<String>> {
+    match *json {
+        Json::String(ref string) => Some(vec![string.clone()]),
+        Json::Array(ref array) => array.iter().map(|item| item.as_str().map(|string| string.to_string())).collect(),
+        _ => None,
+    }
+}
+
+
+fn parse_completion_input(json: &Json, context_names: &[String]) -> Option<CompletionInput> {
+    let object = match *json {
+        Json::Object(ref object) => object,
+        _ => {
+            return Some(CompletionInput {
+                input: parse_strings(json)?,
+                weight: 1,
+                contexts: BTreeMap::new(),
+            });
+        }
+    };
+
+    let mut completion_input = CompletionInput {
+        input: Vec::new(),
+        weight: 1,
+        contexts: BTreeMap::new(),
+    };
+
+    for (key, value) in object.iter() {
+        match key.as_ref() {
+            "input" => completion_input.input = parse_strings(value)?,
+            "weight" => completion_input.weight = value.as_u64()?,
+            "contexts" => {
+                for (context_name, context_values) in value.as_object()?.iter() {
+                    if !context_names.contains(context_name) {
+                        return None;
+                    }
+
+                    completion_input.contexts.insert(context_name.clone(), parse_strings(context_values)?);
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    if completion_input.input.is_empty() {
+        return None;
+    }
+
+    Some(completion_input)
+}
+
+
+/// Parses the value of a completion field in a document
+///
+/// This can be an input string, an array of them, an object with "input", "weight" and
+/// "contexts", or an array of those objects. Inputs longer than `max_input_length` characters
+/// are cut short. Returns None if the value isn't valid or uses a context that isn't in
+/// `context_names`.
+pub fn parse_completion_value(json: &Json, context_names: &[String], max_input_length: usize) -> Option<Vec<CompletionInput>> {
+    let mut completion_inputs = match *json {
+        Json::Array(ref array) if array.iter().all(|item| item.is_object()) => {
+            array.iter().map(|item| parse_completion_input(item, context_names)).collect::<Option<Vec<_>>>()?
+        }
+        _ => vec![parse_completion_input(json, context_names)?],
+    };
+
+    for completion_input in completion_inputs.iter_mut() {
+        for input in completion_input.input.iter_mut() {
+            if let Some((end, _)) = input.char_indices().nth(max_input_length) {
+                input.truncate(end);
+            }
+        }
+    }
+
+    Some(completion_inputs)
+}
+
+
+/// Reads the inputs of a completion field back from the value that was stored
+pub fn decode_completion_value(value: &FieldValue) -> Option<Vec<CompletionInput>> {
+    match *value {
+        FieldValue::String(ref string) => serde_json::from_str(string).ok(),
+        _ => None,
+    }
+}
+
+
+/// Inputs are compared ignoring case and accents
+fn normalize(input: &str) -> Vec<char> {
+    fold_to_ascii(&input.to_lowercase()).chars().collect()
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyOptions {
+    /// The most edits an input's prefix can be from the prefix. None picks it from the
+    /// length of the prefix
+    pub fuzziness: Option<usize>,
+
+    /// Count swapping two adjacent characters as one edit rather than two
+    pub transpositions: bool,
+
+    /// Prefixes shorter than this are only matched exactly
+    pub min_length: usize,
+
+    /// The number of characters at the start of the prefix that must match exactly
+    pub prefix_length: usize,
+}
+
+
+impl Default for FuzzyOptions {
+    fn default() -> FuzzyOptions {
+        FuzzyOptions {
+            fuzziness: None,
+            transpositions: true,
+            min_length: DEFAULT_FUZZY_MIN_LENGTH,
+            prefix_length: DEFAULT_FUZZY_PREFIX_LENGTH,
+        }
+    }
+}
+
+
+impl FuzzyOptions {
+    fn max_edits(&self, prefix_length: usize) -> usize {
+        match self.fuzziness {
+            Some(fuzziness) => fuzziness,
+            None if prefix_length < 3 => 0,
+            None if prefix_length < 6 => 1,
+            None => 2,
+        }
+    }
+}
+
+
+/// A context value to filter on, with how much to boost the inputs that have it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextQuery {
+    pub value: String,
+    pub boost: f64,
+}
+
+
+/// A "completion" suggester from the "suggest" section of a search request
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionSuggester {
+    /// The name the suggestions are returned under
+    pub name: String,
+
+    pub prefix: String,
+    pub field_ref: FieldId,
+
+    /// The field the document source is stored in, if the index has one
+    pub source_field: Option<FieldId>,
+
+    pub size: usize,
+
+    /// Only return the first document for each suggestion text
+    pub skip_duplicates: bool,
+
+    pub fuzzy: Option<FuzzyOptions>,
+
+    /// The values of each context to filter by. Inputs only have to match one of them
+    pub contexts: BTreeMap<String, Vec<ContextQuery>>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+struct CompletionMatch {
+    doc_id: u64,
+    text: String,
+    score: f64,
+    contexts: BTreeMap<String, Vec<String>>,
+}
+
+
+impl CompletionSuggester {
+    pub fn new(name: String, prefix: String, field_ref: FieldId) -> CompletionSuggester {
+        CompletionSuggester {
+            name: name,
+            prefix: prefix,
+            field_ref: field_ref,
+            source_field: None,
+            size: DEFAULT_SIZE,
+            skip_duplicates: false,
+            fuzzy: None,
+            contexts: BTreeMap::new(),
+        }
+    }
+
+    /// Works out how much to boost an input by its contexts. Returns None if the input is
+    /// filtered out by them
+    fn context_boost(&self, completion_input: &CompletionInput) -> Option<f64> {
+        if self.contexts.is_empty() {
+            return Some(1.0);
+        }
+
+        let mut boost = None;
+        for (context_name, context_queries) in self.contexts.iter() {
+            let values = match completion_input.contexts.get(context_name) {
+                Some(values) => values,
+                None => continue,
+            };
+
+            for context_query in context_queries.iter() {
+                if values.contains(&context_query.value) && boost.map_or(true, |boost| context_query.boost > boost) {
+                    boost = Some(context_query.boost);
+                }
+            }
+        }
+
+        boost
+    }
+
+    /// Counts the edits between the prefix and the start of the input. Returns None if it
+    /// doesn't match
+    fn match_input(&self, prefix: &[char], input: &[char]) -> Option<usize> {
+        if input.starts_with(prefix) {
+            return Some(0);
+        }
+
+        let fuzzy = self.fuzzy.as_ref()?;
+        if prefix.len() < fuzzy.min_length {
+            return None;
+        }
+
+        let exact_length = fuzzy.prefix_length.min(prefix.len());
+        if !input.starts_with(&prefix[..exact_length]) {
+            return None;
+        }
+
+        let edits = prefix_edit_distance(prefix, input, fuzzy.transpositions);
+        if edits <= fuzzy.max_edits(prefix.len()) {
+            Some(edits)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the best matching input in one document
+    ///
+    /// Inputs are scored by their weight, multiplied by the context boost. Fuzzy matches
+    /// are divided by one more than the number of edits so they rank below exact ones.
+    fn match_document(&self, prefix: &[char], doc_id: u64, completion_inputs: Vec<CompletionInput>) -> Option<CompletionMatch> {
+        let mut best_match: Option<CompletionMatch> = None;
+
+        for completion_input in completion_inputs {
+            let boost = match self.context_boost(&completion_input) {
+                Some(boost) => boost,
+                None => continue,
+            };
+
+            for input in completion_input.input.iter() {
+                let edits = match self.match_input(prefix, &normalize(input)) {
+                    Some(edits) => edits,
+                    None => continue,
+                };
+
+                let score = completion_input.weight as f64 * boost / (edits + 1) as f64;
+                if best_match.as_ref().map_or(true, |best_match| score > best_match.score) {
+                    best_match = Some(CompletionMatch {
+                        doc_id: doc_id,
+                        text: input.clone(),
+                        score: score,
+                        contexts: completion_input.contexts.clone(),
+                    });
+                }
+            }
+        }
+
+        best_match
+    }
+
+    fn find_matches(&self, index_reader: &IndexReader) -> Result<Vec<CompletionMatch>, String> {
+        let prefix = normalize(&self.prefix);
+        let mut matches = Vec::new();
+
+        for doc_id in index_reader.matching_documents(&Query::all())? {
+            let completion_inputs = match index_reader.read_stored_field(self.field_ref, doc_id) {
+                Ok(Some(value)) => decode_completion_value(&value),
+                _ => None,
+            };
+
+            if let Some(completion_match) = completion_inputs.and_then(|completion_inputs| self.match_document(&prefix, doc_id, completion_inputs)) {
+                matches.push(completion_match);
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Returns the completions of the prefix, in the format of the search response
+    pub fn run(&self, index_reader: &IndexReader, index_name: &str) -> Result<Json, String> {
+        let mut matches = self.find_matches(index_reader)?;
+        matches.sort_by(|a, b| {
+            b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)
+                .then_with(|| a.text.cmp(&b.text))
+                .then(a.doc_id.cmp(&b.doc_id))
+        });
+
+        if self.skip_duplicates {
+            let mut seen = HashSet::new();
+            matches.retain(|completion_match| seen.insert(completion_match.text.clone()));
+        }
+
+        matches.truncate(self.size);
+
+        let doc_keys = index_reader.document_keys();
+        let options = matches.into_iter().map(|completion_match| {
+            let doc_id = completion_match.doc_id;
+            let mut option = json!({
+                "text": completion_match.text,
+                "_index": index_name,
+                "_id": doc_keys.get(&doc_id),
+                "_score": completion_match.score,
+            });
+
+            if let Some(source) = self.source_field.and_then(|field_ref| read_source_field(index_reader.shard_for_doc(doc_id), field_ref, DocId::from_u64(doc_id))) {
+                option["_source"] = Json::Object(source);
+            }
+
+            if !completion_match.contexts.is_empty() {
+                option["contexts"] = json!(completion_match.contexts);
+            }
+
+            option
+        }).collect::<Vec<_>>();
+
+        Ok(json!([{
+            "text": self.prefix,
+            "offset": 0,
+            "length": self.prefix.chars().count(),
+            "options": options,
+        }]))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use search::schema::FieldId;
+
+    use super::{parse_completion_value, normalize, CompletionInput, CompletionSuggester, FuzzyOptions, ContextQuery};
+
+    fn input(inputs: &[&str], weight: u64) -> CompletionInput {
+        CompletionInput {
+            input: inputs.iter().map(|input| input.to_string()).collect(),
+            weight: weight,
+            contexts: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_completion_value() {
+        let context_names = vec!["genre".to_string()];
+
+        assert_eq!(parse_completion_value(&json!("Nirvana"), &context_names, 50), Some(vec![input(&["Nirvana"], 1)]));
+        assert_eq!(parse_completion_value(&json!(["Nevermind", "Nirvana"]), &context_names, 50), Some(vec![input(&["Nevermind", "Nirvana"], 1)]));
+        assert_eq!(parse_completion_value(&json!({"input": "Nirvana", "weight": 34}), &context_names, 50), Some(vec![input(&["Nirvana"], 34)]));
+        assert_eq!(parse_completion_value(&json!([{"input": "Nevermind", "weight": 10}, {"input": "Nirvana", "weight": 3}]), &context_names, 50), Some(vec![input(&["Nevermind"], 10), input(&["Nirvana"], 3)]));
+        assert_eq!(parse_completion_value(&json!("Nirvana"), &context_names, 3), Some(vec![input(&["Nir"], 1)]));
+
+        let mut with_contexts = input(&["Nirvana"], 1);
+        with_contexts.contexts.insert("genre".to_string(), vec!["rock".to_string(), "grunge".to_string()]);
+        assert_eq!(parse_completion_value(&json!({"input": "Nirvana", "contexts": {"genre": ["rock", "grunge"]}}), &context_names, 50), Some(vec![with_contexts]));
+
+        assert_eq!(parse_completion_value(&json!({"input": "Nirvana", "contexts": {"decade": "90s"}}), &context_names, 50), None);
+        assert_eq!(parse_completion_value(&json!({"input": "Nirvana", "weight": -1}), &context_names, 50), None);
+        assert_eq!(parse_completion_value(&json!({"weight": 1}), &context_names, 50), None);
+        assert_eq!(parse_completion_value(&json!(1), &context_names, 50), None);
+    }
+
+    #[test]
+    fn test_match_document() {
+        let mut suggester = CompletionSuggester::new("test".to_string(), "nri".to_string(), FieldId(1));
+        let prefix = normalize(&suggester.prefix);
+        let inputs = vec![input(&["Nevermind", "Nirvana"], 10)];
+
+        assert_eq!(suggester.match_document(&prefix, 1, inputs.clone()), None);
+
+        suggester.fuzzy = Some(FuzzyOptions::default());
+        let completion_match = suggester.match_document(&prefix, 1, inputs.clone()).unwrap();
+        assert_eq!(completion_match.text, "Nirvana");
+        assert_eq!(completion_match.score, 5.0);
+
+        let exact_match = suggester.match_document(&normalize("NÉV"), 1, inputs.clone()).unwrap();
+        assert_eq!(exact_match.text, "Nevermind");
+        assert_eq!(exact_match.score, 10.0);
+    }
+
+    #[test]
+    fn test_context_boost() {
+        let mut suggester = CompletionSuggester::new("test".to_string(), "n".to_string(), FieldId(1));
+        let mut rock = input(&["Nirvana"], 1);
+        rock.contexts.insert("genre".to_string(), vec!["rock".to_string()]);
+        let no_contexts = input(&["Nirvana"], 1);
+
+        assert_eq!(suggester.context_boost(&rock), Some(1.0));
+        assert_eq!(suggester.context_boost(&no_contexts), Some(1.0));
+
+        suggester.contexts.insert("genre".to_string(), vec![
+            ContextQuery { value: "pop".to_string(), boost: 1.0 },
+            ContextQuery { value: "rock".to_string(), boost: 2.0 },
+        ]);
+        assert_eq!(suggester.context_boost(&rock), Some(2.0));
+        assert_eq!(suggester.context_boost(&no_contexts), None);
+    }
+}
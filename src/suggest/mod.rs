@@ -0,0 +1,413 @@
+//! Suggests corrections for misspelt words from the terms in an index
+//!
+//! The text is split into words, which are analyzed with the field's analyzer. Each term is
+//! compared with the terms in the field, and any that are only a few edits away are
+//! suggested. Suggestions are ranked by how similar they are and how many documents
+//! contain them.
+//!
+//! Completion suggestions, for search-as-you-type, are in the `completion` module.
+
+pub mod completion;
+
+use std::cmp::{self, Ordering};
+
+use unicode_segmentation::UnicodeSegmentation;
+use serde_json::Value as Json;
+use search::Term;
+use search::schema::FieldId;
+use index::reader::IndexReader;
+
+use analysis::AnalyzerSpec;
+
+use self::completion::CompletionSuggester;
+
+
+const DEFAULT_MAX_EDITS: usize = 2;
+const DEFAULT_PREFIX_LENGTH: usize = 1;
+const DEFAULT_MIN_WORD_LENGTH: usize = 4;
+const DEFAULT_SIZE: usize = 5;
+
+
+/// Which terms get suggestions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuggestMode {
+    /// Only terms that aren't in the field
+    Missing,
+
+    /// Any term, but only with suggestions that are in more documents than the term is
+    Popular,
+
+    /// Any term, with any suggestions
+    Always,
+}
+
+
+impl SuggestMode {
+    pub fn from_name(name: &str) -> Option<SuggestMode> {
+        match name {
+            "missing" => Some(SuggestMode::Missing),
+            "popular" => Some(SuggestMode::Popular),
+            "always" => Some(SuggestMode::Always),
+            _ => None,
+        }
+    }
+}
+
+
+/// How the suggestions for each term are ordered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuggestSort {
+    /// Most similar first, then most frequent
+    Score,
+
+    /// Most frequent first, then most similar
+    Frequency,
+}
+
+
+impl SuggestSort {
+    pub fn from_name(name: &str) -> Option<SuggestSort> {
+        match name {
+            "score" => Some(SuggestSort::Score),
+            "frequency" => Some(SuggestSort::Frequency),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermSuggestOptions {
+    /// The most edits a suggestion can be from the term. Either 1 or 2
+    pub max_edits: usize,
+
+    /// The number of characters at the start of the term that suggestions must share
+    pub prefix_length: usize,
+
+    /// Terms with fewer characters than this don't get suggestions
+    pub min_word_length: usize,
+
+    /// The most suggestions to return for each term
+    pub size: usize,
+
+    pub suggest_mode: SuggestMode,
+    pub sort: SuggestSort,
+
+    /// Suggestions must be in at least this many documents
+    pub min_doc_freq: u64,
+}
+
+
+impl Default for TermSuggestOptions {
+    fn default() -> TermSuggestOptions {
+        TermSuggestOptions {
+            max_edits: DEFAULT_MAX_EDITS,
+            prefix_length: DEFAULT_PREFIX_LENGTH,
+            min_word_length: DEFAULT_MIN_WORD_LENGTH,
+            size: DEFAULT_SIZE,
+            suggest_mode: SuggestMode::Missing,
+            sort: SuggestSort::Score,
+            min_doc_freq: 0,
+        }
+    }
+}
+
+
+/// A "term" suggester from the "suggest" section of a search request
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermSuggester {
+    /// The name the suggestions are returned under
+    pub name: String,
+
+    pub text: String,
+    pub field_ref: FieldId,
+
+    /// The analyzer the field was indexed with
+    pub analyzer: Option<AnalyzerSpec>,
+
+    pub options: TermSuggestOptions,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermSuggestion {
+    pub text: String,
+
+    /// How similar the suggestion is to the term, between 0 and 1
+    pub score: f32,
+
+    /// The number of documents the suggestion is in
+    pub freq: u64,
+}
+
+
+/// A suggester from the "suggest" section of a search request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suggester {
+    Term(TermSuggester),
+    Completion(CompletionSuggester),
+}
+
+
+impl Suggester {
+    pub fn name(&self) -> &str {
+        match *self {
+            Suggester::Term(ref suggester) => &suggester.name,
+            Suggester::Completion(ref suggester) => &suggester.name,
+        }
+    }
+
+    /// Returns the suggestions in the format of the search response
+    pub fn run(&self, index_reader: &IndexReader, index_name: &str) -> Result<Json, String> {
+        match *self {
+            Suggester::Term(ref suggester) => suggester.run(index_reader),
+            Suggester::Completion(ref suggester) => suggester.run(index_reader, index_name),
+        }
+    }
+}
+
+
+/// Works out the edit distances between `a` and each prefix of `b`
+///
+/// The value at index `n` is the distance between `a` and the first `n` characters of `b`.
+fn edit_distances(a: &[char], b: &[char], transpositions: bool) -> Vec<usize> {
+    // Only the last three rows of the table are needed at a time
+    let mut two_rows_back = vec![0; b.len() + 1];
+    let mut previous_row = (0..b.len() + 1).collect::<Vec<_>>();
+    let mut current_row = vec![0; b.len() + 1];
+
+    for i in 1..a.len() + 1 {
+        current_row[0] = i;
+
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current_row[j] = cmp::min(cmp::min(previous_row[j] + 1, current_row[j - 1] + 1), previous_row[j - 1] + cost);
+
+            if transpositions && i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current_row[j] = cmp::min(current_row[j], two_rows_back[j - 2] + 1);
+            }
+        }
+
+        // Rotate the rows. The old two_rows_back is overwritten on the next pass
+        let oldest_row = two_rows_back;
+        two_rows_back = previous_row;
+        previous_row = current_row;
+        current_row = oldest_row;
+    }
+
+    previous_row
+}
+
+
+/// Counts the insertions, deletions, substitutions and transpositions of adjacent characters
+/// it takes to turn one string into the other
+pub fn edit_distance(a: &[char], b: &[char]) -> usize {
+    edit_distances(a, b, true)[b.len()]
+}
+
+
+/// Counts the fewest edits it takes to turn `a` into any prefix of `b`
+pub fn prefix_edit_distance(a: &[char], b: &[char], transpositions: bool) -> usize {
+    edit_distances(a, b, transpositions).into_iter().min().unwrap_or(0)
+}
+
+
+/// Picks the candidates to suggest for a term, best first
+///
+/// The candidates are the terms in the field, with the number of documents they are in.
+fn rank_suggestions(term: &[char], term_freq: u64, candidates: Vec<(String, u64)>, options: &TermSuggestOptions) -> Vec<TermSuggestion> {
+    let mut suggestions = Vec::new();
+
+    for (text, freq) in candidates {
+        if freq < options.min_doc_freq || (options.suggest_mode == SuggestMode::Popular && freq <= term_freq) {
+            continue;
+        }
+
+        let candidate = text.chars().collect::<Vec<_>>();
+        let distance = edit_distance(term, &candidate);
+        if distance == 0 || distance > options.max_edits {
+            continue;
+        }
+
+        let score = 1.0 - distance as f32 / cmp::min(term.len(), candidate.len()).max(1) as f32;
+        suggestions.push(TermSuggestion {
+            text: text,
+            score: score,
+            freq: freq,
+        });
+    }
+
+    suggestions.sort_by(|a, b| {
+        let by_score = b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal);
+        let by_freq = b.freq.cmp(&a.freq);
+
+        match options.sort {
+            SuggestSort::Score => by_score.then(by_freq),
+            SuggestSort::Frequency => by_freq.then(by_score),
+        }.then_with(|| a.text.cmp(&b.text))
+    });
+
+    suggestions.truncate(options.size);
+    suggestions
+}
+
+
+/// Splits the text into words, returning the offset and length of each in characters
+fn words(text: &str) -> Vec<(usize, usize, &str)> {
+    let mut words = Vec::new();
+    let mut offset = 0;
+
+    for word in text.split_word_bounds() {
+        let length = word.chars().count();
+
+        if word.chars().any(|c| c.is_alphanumeric()) {
+            words.push((offset, length, word));
+        }
+
+        offset += length;
+    }
+
+    words
+}
+
+
+impl TermSuggester {
+    /// Finds the suggestions for one term
+    fn suggest_term(&self, index_reader: &IndexReader, term: &str) -> Result<Vec<TermSuggestion>, String> {
+        let options = &self.options;
+        let term_chars = term.chars().collect::<Vec<_>>();
+
+        if term_chars.len() < options.min_word_length {
+            return Ok(Vec::new());
+        }
+
+        let term_freq = index_reader.term_document_frequency(self.field_ref, &Term::from_string(term))?;
+        if options.suggest_mode == SuggestMode::Missing && term_freq > 0 {
+            return Ok(Vec::new());
+        }
+
+        // Skip terms that are obviously too different before working out the edit distance
+        let prefix = term_chars.iter().take(options.prefix_length).collect::<String>();
+        let candidates = index_reader.find_field_terms(self.field_ref, |candidate| {
+            if !candidate.starts_with(&prefix) {
+                return false;
+            }
+
+            let length = candidate.chars().count();
+            cmp::max(length, term_chars.len()) - cmp::min(length, term_chars.len()) <= options.max_edits
+        })?;
+
+        Ok(rank_suggestions(&term_chars, term_freq, candidates, options))
+    }
+
+    /// Returns the suggestions for each term in the text, in the format of the search response
+    pub fn run(&self, index_reader: &IndexReader) -> Result<Json, String> {
+        let mut entries = Vec::new();
+
+        // Fields that aren't analyzed are suggested for as a whole
+        let words = match self.analyzer {
+            Some(_) => words(&self.text),
+            None => vec![(0, self.text.chars().count(), &self.text[..])],
+        };
+
+        for (offset, length, word) in words {
+            let terms = match self.analyzer {
+                Some(ref analyzer) => analyzer.initialise(word).map(|token| token.term).collect(),
+                None => vec![Term::from_string(word)],
+            };
+
+            for term in terms {
+                let term = String::from_utf8_lossy(term.as_bytes()).into_owned();
+                let suggestions = self.suggest_term(index_reader, &term)?;
+
+                entries.push(json!({
+                    "text": term,
+                    "offset": offset,
+                    "length": length,
+                    "options": suggestions.iter().map(|suggestion| {
+                        json!({
+                            "text": suggestion.text,
+                            "score": suggestion.score,
+                            "freq": suggestion.freq,
+                        })
+                    }).collect::<Vec<_>>(),
+                }));
+            }
+        }
+
+        Ok(json!(entries))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, prefix_edit_distance, rank_suggestions, words, TermSuggestOptions, SuggestMode, SuggestSort};
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance(&chars("hello"), &chars("hello")), 0);
+        assert_eq!(edit_distance(&chars("hello"), &chars("helo")), 1);
+        assert_eq!(edit_distance(&chars("hello"), &chars("hellos")), 1);
+        assert_eq!(edit_distance(&chars("hello"), &chars("hallo")), 1);
+        assert_eq!(edit_distance(&chars("hello"), &chars("hlelo")), 1);
+        assert_eq!(edit_distance(&chars("hello"), &chars("world")), 4);
+        assert_eq!(edit_distance(&chars(""), &chars("abc")), 3);
+        assert_eq!(edit_distance(&chars("café"), &chars("cafe")), 1);
+    }
+
+    #[test]
+    fn test_prefix_edit_distance() {
+        assert_eq!(prefix_edit_distance(&chars("nir"), &chars("nirvana"), true), 0);
+        assert_eq!(prefix_edit_distance(&chars("inrv"), &chars("nirvana"), true), 1);
+        assert_eq!(prefix_edit_distance(&chars("inrv"), &chars("nirvana"), false), 2);
+        assert_eq!(prefix_edit_distance(&chars("nevr"), &chars("nirvana"), true), 2);
+        assert_eq!(prefix_edit_distance(&chars(""), &chars("nirvana"), true), 0);
+    }
+
+    #[test]
+    fn test_rank_suggestions() {
+        let candidates = vec![
+            ("rusty".to_string(), 10),
+            ("rust".to_string(), 20),
+            ("trust".to_string(), 50),
+            ("ruts".to_string(), 1),
+            ("rustic".to_string(), 3),
+        ];
+        let options = TermSuggestOptions {
+            max_edits: 1,
+            ..TermSuggestOptions::default()
+        };
+
+        let suggestions = rank_suggestions(&chars("rusts"), 0, candidates.clone(), &options);
+        assert_eq!(suggestions.iter().map(|s| &s.text[..]).collect::<Vec<_>>(), vec!["rusty", "rust", "ruts"]);
+        assert_eq!(suggestions[0].score, 0.8);
+        assert_eq!(suggestions[1].score, 0.75);
+
+        let options = TermSuggestOptions {
+            max_edits: 1,
+            sort: SuggestSort::Frequency,
+            ..TermSuggestOptions::default()
+        };
+        let suggestions = rank_suggestions(&chars("rusts"), 0, candidates.clone(), &options);
+        assert_eq!(suggestions.iter().map(|s| &s.text[..]).collect::<Vec<_>>(), vec!["rust", "rusty", "ruts"]);
+
+        let options = TermSuggestOptions {
+            max_edits: 1,
+            suggest_mode: SuggestMode::Popular,
+            ..TermSuggestOptions::default()
+        };
+        let suggestions = rank_suggestions(&chars("rust"), 2, candidates, &options);
+        assert_eq!(suggestions.iter().map(|s| &s.text[..]).collect::<Vec<_>>(), vec!["trust", "rusty"]);
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(words("hello, wrld!"), vec![(0, 5, "hello"), (7, 4, "wrld")]);
+        assert_eq!(words("naïve test"), vec![(0, 5, "naïve"), (6, 4, "test")]);
+    }
+}
@@ -1,51 +1,391 @@
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use slog::Logger;
-use search::backends::rocksdb::RocksDBStore;
+use serde_json::Value as Json;
+use search::backends::rocksdb::StoreOptions;
 use uuid::Uuid;
 
-use index::Index;
+use index::{self, Index, ClosedIndex};
+use dir_lock::{DirLock, DirLockError};
 use index::metadata::IndexMetadata;
-use cluster::metadata::ClusterMetadata;
+use index::recovery::{IndexRecovery, RecoverySource};
+use cluster::metadata::{ClusterMetadata, IndexRef};
+use cluster::health::{ClusterHealth, HealthStatus};
+use cluster::coordinator::Coordinator;
+use cluster::settings::{ClusterSettings, ClusterSettingsState, DynamicSettings};
+use replication::Replication;
+use gateway::{DanglingIndex, verify_index, find_dangling_indices};
+use audit::AuditLog;
+use disk_usage::{DiskWatermark, DiskBlocks, disk_usage};
+use scroll::{ScrollRegistry, ScrollContext};
+use tasks::TaskManager;
+use stored_scripts::StoredScriptRegistry;
+use lifecycle::registry::LifecyclePolicyRegistry;
+use security::ApiKeyRegistry;
+use security::roles::RoleRegistry;
+use settings::Settings;
+use request_breaker::RequestBreaker;
+use thread_pool::ThreadPools;
+use process_stats::total_memory;
+
+
+/// Default size limit of a bulk request body
+const DEFAULT_BULK_MAX_PAYLOAD_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Default time to wait for requests in flight to finish when shutting down
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+/// How long closing an index waits for the requests using it to finish
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Why a dangling index couldn't be imported or deleted
+#[derive(Debug, Clone, PartialEq)]
+pub enum DanglingIndexError {
+    /// There's no dangling index with the name
+    NotFound,
+
+    /// The name is used by another index or an alias
+    NameInUse,
+
+    Failed(String),
+}
+
+
+/// Checks that nothing else in the cluster metadata has the name of an index being loaded
+fn check_name_is_free(cluster_metadata: &ClusterMetadata, index_name: &str) -> Result<(), String> {
+    if cluster_metadata.names.find_canonical(index_name).is_some() || cluster_metadata.names.is_alias(index_name) {
+        return Err(format!("the name {:?} is already used by another index or an alias", index_name));
+    }
+
+    Ok(())
+}
+
+
+/// Why an index couldn't be closed
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloseIndexError {
+    /// Requests were still using it after `CLOSE_TIMEOUT`
+    InUse,
+
+    Failed(String),
+}
 
 
 pub struct System {
     pub log: Logger,
-    data_dir: PathBuf,
+    pub settings: Settings,
+    data_dir_lock: Mutex<Option<DirLock>>,
+    pub store_options: StoreOptions,
     pub metadata: RwLock<ClusterMetadata>,
+
+    /// Progress of loading each index's data, by index name
+    pub recoveries: RwLock<HashMap<String, Arc<IndexRecovery>>>,
+
+    /// Settings that can be changed while the node is running (see `cluster::settings`)
+    pub cluster_settings: ClusterSettings,
+
+    /// Searches that are being scrolled through
+    pub scrolls: ScrollRegistry,
+
+    /// Searches that are currently running
+    pub tasks: TaskManager,
+
+    /// Search templates that have been stored by id
+    pub scripts: StoredScriptRegistry,
+
+    /// Bytes a bulk request body can be. Bodies are streamed, so this doesn't bound memory usage
+    pub bulk_max_payload_size: usize,
+
+    /// Index lifecycle policies that have been stored by name
+    pub lifecycle_policies: LifecyclePolicyRegistry,
+
+    /// API keys that have been created through the API, by id
+    pub api_keys: ApiKeyRegistry,
+
+    /// Roles, and the roles that have been assigned to each user and API key
+    pub roles: RoleRegistry,
+
+    /// Number of API requests that are currently being handled
+    pub requests_in_flight: AtomicUsize,
+
+    /// Refuses requests when the bodies of the requests in flight would use too much memory
+    pub request_breaker: RequestBreaker,
+
+    /// How long a shutdown waits for the requests in flight to finish
+    pub shutdown_timeout: Duration,
+
+    /// Threads that API requests and background merges run on
+    pub thread_pools: ThreadPools,
+
+    /// Keeps the node in step with the other nodes of its cluster
+    pub cluster: Coordinator,
+
+    /// Replicas of indices that are being recovered
+    pub replication: Replication,
+
+    /// Where audit events are recorded, if auditing is enabled
+    pub audit_log: Option<AuditLog>,
+
+    /// The indices that `check_disk_usage` made read-only
+    pub disk_blocks: DiskBlocks,
 }
 
 
 impl System {
-    pub fn new(log: Logger, data_dir: PathBuf) -> System {
-        System {
+    /// Fails if the threads of the thread pools or the cluster transport couldn't be started,
+    /// or the audit log couldn't be opened
+    pub fn new(log: Logger, settings: Settings) -> io::Result<System> {
+        let cluster_settings = ClusterSettings::new(DynamicSettings::defaults(&settings));
+        let request_breaker_limit = settings.in_flight_requests_limit.to_bytes(total_memory()).map(|limit| limit as usize);
+        let index_buffer_size = settings.index_buffer_size as usize;
+        let thread_pools = ThreadPools::new(&settings.thread_pool)?;
+        let cluster = Coordinator::new(&settings)?;
+        let audit_log = match settings.audit_log {
+            Some(ref path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
+
+        Ok(System {
             log: log,
-            data_dir: data_dir,
+            settings: settings,
+            data_dir_lock: Mutex::new(None),
+            store_options: StoreOptions {
+                index_buffer_size: index_buffer_size,
+                search_pool: Some(thread_pools.search.clone()),
+                ..StoreOptions::default()
+            },
             metadata: RwLock::new(ClusterMetadata::new()),
+            recoveries: RwLock::new(HashMap::new()),
+            cluster_settings: cluster_settings,
+            scrolls: ScrollRegistry::new(),
+            tasks: TaskManager::new(),
+            scripts: StoredScriptRegistry::new(),
+            bulk_max_payload_size: DEFAULT_BULK_MAX_PAYLOAD_SIZE,
+            lifecycle_policies: LifecyclePolicyRegistry::new(),
+            api_keys: ApiKeyRegistry::new(),
+            roles: RoleRegistry::new(),
+            requests_in_flight: AtomicUsize::new(0),
+            request_breaker: RequestBreaker::new(request_breaker_limit),
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT),
+            thread_pools: thread_pools,
+            cluster: cluster,
+            replication: Replication::new(),
+            audit_log: audit_log,
+            disk_blocks: DiskBlocks::new(),
+        })
+    }
+
+    /// Takes an exclusive lock on the data directory
+    ///
+    /// This must be called before loading any indices, to make sure no other process is
+    /// using the same data directory.
+    pub fn lock_data_dir(&mut self) -> Result<(), DirLockError> {
+        let data_dir_lock = self.data_dir_lock.get_mut().unwrap();
+        if data_dir_lock.is_none() {
+            *data_dir_lock = Some(DirLock::acquire(&self.settings.data_dir)?);
         }
+
+        Ok(())
     }
 
     pub fn get_indices_dir(&self) -> PathBuf {
-        let mut dir = self.data_dir.clone();
+        let mut dir = self.settings.data_dir.clone();
         dir.push("indices");
         dir
     }
 
-    fn load_index(&self, id: Uuid, name: String, path: &Path) -> Result<Index, String> {
-        let store = RocksDBStore::open(path)?;
+    pub fn get_stored_scripts_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("scripts.json");
+        path
+    }
+
+    pub fn load_stored_scripts(&self) {
+        if let Err(e) = self.scripts.load(self.get_stored_scripts_path()) {
+            error!(self.log, "could not load stored scripts"; "error" => e);
+        }
+    }
+
+    pub fn get_lifecycle_policies_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("ilm_policies.json");
+        path
+    }
+
+    pub fn load_lifecycle_policies(&self) {
+        if let Err(e) = self.lifecycle_policies.load(self.get_lifecycle_policies_path()) {
+            error!(self.log, "could not load lifecycle policies"; "error" => e);
+        }
+    }
+
+    pub fn get_api_keys_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("api_keys.json");
+        path
+    }
+
+    pub fn load_api_keys(&self) {
+        if let Err(e) = self.api_keys.load(self.get_api_keys_path()) {
+            error!(self.log, "could not load api keys"; "error" => e);
+        }
+    }
+
+    pub fn get_roles_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("roles.json");
+        path
+    }
+
+    pub fn load_roles(&self) {
+        if let Err(e) = self.roles.load(self.get_roles_path()) {
+            error!(self.log, "could not load roles"; "error" => e);
+        }
+    }
+
+    pub fn get_disk_blocks_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("disk_blocks.json");
+        path
+    }
+
+    pub fn load_disk_blocks(&self) {
+        if let Err(e) = self.disk_blocks.load(self.get_disk_blocks_path()) {
+            error!(self.log, "could not load disk blocks"; "error" => e);
+        }
+    }
+
+    pub fn get_cluster_settings_path(&self) -> PathBuf {
+        let mut path = self.settings.data_dir.clone();
+        path.push("cluster_settings.json");
+        path
+    }
+
+    pub fn load_cluster_settings(&self) {
+        let previous = self.cluster_settings.current();
+
+        match self.cluster_settings.load(self.get_cluster_settings_path()) {
+            Ok(()) => self.apply_cluster_settings(&previous),
+            Err(e) => error!(self.log, "could not load cluster settings"; "error" => e),
+        }
+    }
+
+    /// Changes some of the cluster settings. Null values reset a setting to its default
+    ///
+    /// Only the master changes the settings this way. The other nodes get them with the
+    /// cluster state (see `replace_cluster_settings`).
+    pub fn update_cluster_settings(&self, persistent: &BTreeMap<String, Json>, transient: &BTreeMap<String, Json>) -> Result<(), String> {
+        let previous = self.cluster_settings.update(self.get_cluster_settings_path(), persistent, transient)?;
+        self.apply_cluster_settings(&previous);
+
+        let keys = persistent.keys().chain(transient.keys()).cloned().collect::<Vec<_>>();
+        info!(self.log, "updated cluster settings"; "settings" => keys.join(", "));
+        Ok(())
+    }
+
+    /// Replaces the cluster settings with the ones the master published
+    pub fn replace_cluster_settings(&self, state: ClusterSettingsState) {
+        if state == self.cluster_settings.state() {
+            return;
+        }
+
+        match self.cluster_settings.replace(self.get_cluster_settings_path(), state) {
+            Ok(previous) => self.apply_cluster_settings(&previous),
+            Err(e) => error!(self.log, "could not apply cluster settings"; "error" => e),
+        }
+    }
+
+    /// Makes the changes that settings which are held elsewhere need when they change
+    ///
+    /// Everything else reads the settings each time it uses them.
+    fn apply_cluster_settings(&self, previous: &DynamicSettings) {
+        let current = self.cluster_settings.current();
+
+        if current.in_flight_requests_limit != previous.in_flight_requests_limit {
+            self.request_breaker.set_limit(current.in_flight_requests_limit.to_bytes(total_memory()).map(|limit| limit as usize));
+        }
+
+        if current.filter_cache_size != previous.filter_cache_size {
+            let cluster_metadata = self.metadata.read().unwrap();
+            for index in cluster_metadata.indices.values() {
+                for shard in index.shards() {
+                    shard.set_filter_cache_size(current.filter_cache_size);
+                }
+            }
+        }
+    }
+
+    /// The options to open stores with, including the ones that come from cluster settings
+    fn current_store_options(&self) -> StoreOptions {
+        StoreOptions {
+            filter_cache_size: self.cluster_settings.current().filter_cache_size,
+            ..self.store_options.clone()
+        }
+    }
+
+    /// Returns true if the index with the given name is still being loaded
+    pub fn is_recovering(&self, index_name: &str) -> bool {
+        match self.recoveries.read().unwrap().get(index_name) {
+            Some(recovery) => !recovery.is_finished(),
+            None => false,
+        }
+    }
+
+    fn load_index(&self, id: Uuid, name: String, path: &Path, recovery: &IndexRecovery) -> Result<Index, String> {
+        let lock = DirLock::acquire(path)?;
 
         // Load metadata
         let mut metadata_path = path.to_path_buf();
         metadata_path.push("metadata.json");
         let metadata = IndexMetadata::load(metadata_path)?;
+        verify_index(path, &metadata)?;
+
+        // Open the shards' stores using the index's settings
+        let shards = index::open_shards(path, &metadata.settings, &self.current_store_options(), &|stage| recovery.store_progress(stage))?;
 
-        Ok(Index::new(id, name, metadata, store))
+        Ok(Index::new(id, name, metadata, shards, lock))
     }
 
+    fn load_closed_index(&self, id: Uuid, name: String, path: &Path) -> Result<ClosedIndex, String> {
+        let lock = DirLock::acquire(path)?;
+
+        let mut metadata_path = path.to_path_buf();
+        metadata_path.push("metadata.json");
+        let metadata = IndexMetadata::load(metadata_path)?;
+        verify_index(path, &metadata)?;
+
+        Ok(ClosedIndex::new(id, name, metadata, path.to_path_buf(), lock))
+    }
+
+    /// Adds the name and aliases of an index that was loaded from the data directory
+    ///
+    /// The index must already be in the cluster metadata, and its name must be free.
+    fn register_loaded_index(&self, cluster_metadata: &mut ClusterMetadata, index_name: &str, index_ref: IndexRef) {
+        cluster_metadata.names.insert_canonical(index_name.to_string(), index_ref).unwrap();
+        for alias_name in cluster_metadata.register_aliases(index_ref) {
+            warn!(self.log, "alias clashes with an index name"; "index" => index_name, "alias" => alias_name);
+        }
+    }
+
+    /// Loads all indices from the data directory
+    ///
+    /// Closed indices are loaded first, as they only need their metadata. The open indices
+    /// are then registered in `recoveries` before any of them are loaded, so the progress
+    /// of the whole process can be followed through the `_recovery` API. Indices that fail
+    /// to load are left on disk as dangling indices (see `gateway`).
     pub fn load_indices(&self) {
         let indices_dir = self.get_indices_dir();
+        let mut indices_to_load = Vec::new();
+        let mut closed = 0;
+        let mut failed = 0;
+
         match fs::read_dir(indices_dir.clone()) {
             Ok(files) => {
                 for file in files {
@@ -53,18 +393,33 @@ impl System {
                     if path.is_dir() {
                         let index_name: String = path.file_name().unwrap().to_str().unwrap().to_owned();
 
-                        match self.load_index(Uuid::new_v4(), index_name.clone().to_owned(), path.as_path()) {
-                            Ok(index) => {
+                        if index::is_closed(path.as_path()) {
+                            let result = self.load_closed_index(Uuid::new_v4(), index_name.clone(), path.as_path()).and_then(|index| {
                                 let mut cluster_metadata = self.metadata.write().unwrap();
-                                let index_ref = cluster_metadata.insert_index(index);
-                                cluster_metadata.names.insert_canonical(index_name.clone(), index_ref).unwrap();
+                                check_name_is_free(&cluster_metadata, &index_name)?;
 
-                                info!(self.log, "loaded index"; "index" => index_name);
-                            }
-                            Err(e) => {
-                                error!(self.log, "load index failed"; "index" => index_name, "error" => e);
+                                let index_ref = cluster_metadata.insert_closed_index(index);
+                                self.register_loaded_index(&mut cluster_metadata, &index_name, index_ref);
+                                Ok(())
+                            });
+
+                            match result {
+                                Ok(()) => {
+                                    closed += 1;
+                                    info!(self.log, "loaded closed index"; "index" => index_name);
+                                }
+                                Err(e) => {
+                                    failed += 1;
+                                    error!(self.log, "load index failed"; "index" => index_name, "error" => e);
+                                }
                             }
+
+                            continue;
                         }
+
+                        let recovery = Arc::new(IndexRecovery::new(RecoverySource::ExistingStore));
+                        self.recoveries.write().unwrap().insert(index_name.clone(), recovery.clone());
+                        indices_to_load.push((index_name, path, recovery));
                     }
                 }
             }
@@ -72,5 +427,466 @@ impl System {
                 error!(self.log, "could not open indices directory"; "dir" => indices_dir.to_str().unwrap(), "error" => format!("{}", error));
             }
         }
+
+        let mut open = 0;
+        for (index_name, path, recovery) in indices_to_load {
+            let result = self.load_index(Uuid::new_v4(), index_name.clone(), path.as_path(), &recovery).and_then(|index| {
+                let mut cluster_metadata = self.metadata.write().unwrap();
+                check_name_is_free(&cluster_metadata, &index_name)?;
+
+                let index_ref = cluster_metadata.insert_index(index);
+                self.register_loaded_index(&mut cluster_metadata, &index_name, index_ref);
+                Ok(())
+            });
+
+            match result {
+                Ok(()) => {
+                    recovery.finish();
+                    open += 1;
+
+                    info!(self.log, "loaded index"; "index" => index_name);
+                }
+                Err(e) => {
+                    recovery.fail(e.clone());
+                    failed += 1;
+
+                    error!(self.log, "load index failed"; "index" => index_name, "error" => e);
+                }
+            }
+        }
+
+        info!(self.log, "loaded indices"; "open" => open, "closed" => closed, "failed" => failed);
+    }
+
+    /// Lists the index directories in the data directory that aren't in the cluster metadata
+    pub fn find_dangling_indices(&self) -> Vec<DanglingIndex> {
+        let cluster_metadata = self.metadata.read().unwrap();
+        find_dangling_indices(&self.get_indices_dir(), &|index_name| {
+            cluster_metadata.names.find_canonical(index_name).is_some() || self.is_recovering(index_name)
+        })
+    }
+
+    /// Finds the directory of a dangling index. Only names of directories that aren't in use
+    /// are accepted, so the name can't lead outside of the indices directory
+    fn dangling_index_path(&self, cluster_metadata: &ClusterMetadata, index_name: &str) -> Result<PathBuf, DanglingIndexError> {
+        let is_dangling = !self.is_recovering(index_name) && cluster_metadata.names.find_canonical(index_name).is_none();
+        if !is_dangling || index_name.is_empty() || index_name == "." || index_name == ".." || index_name.contains('/') || index_name.contains('\\') {
+            return Err(DanglingIndexError::NotFound);
+        }
+
+        let mut path = self.get_indices_dir();
+        path.push(index_name);
+        if !path.join("metadata.json").is_file() {
+            return Err(DanglingIndexError::NotFound);
+        }
+
+        Ok(path)
+    }
+
+    /// Loads a dangling index and adds it to the cluster metadata, open or closed as it was
+    pub fn import_dangling_index(&self, cluster_metadata: &mut ClusterMetadata, index_name: &str) -> Result<IndexRef, DanglingIndexError> {
+        let path = self.dangling_index_path(cluster_metadata, index_name)?;
+        check_name_is_free(cluster_metadata, index_name).map_err(|_| DanglingIndexError::NameInUse)?;
+
+        let index_ref = if index::is_closed(&path) {
+            let index = self.load_closed_index(Uuid::new_v4(), index_name.to_string(), &path).map_err(DanglingIndexError::Failed)?;
+            cluster_metadata.insert_closed_index(index)
+        } else {
+            let recovery = Arc::new(IndexRecovery::new(RecoverySource::ExistingStore));
+            self.recoveries.write().unwrap().insert(index_name.to_string(), recovery.clone());
+
+            match self.load_index(Uuid::new_v4(), index_name.to_string(), &path, &recovery) {
+                Ok(index) => {
+                    recovery.finish();
+                    cluster_metadata.insert_index(index)
+                }
+                Err(e) => {
+                    recovery.fail(e.clone());
+                    return Err(DanglingIndexError::Failed(e));
+                }
+            }
+        };
+
+        self.register_loaded_index(cluster_metadata, index_name, index_ref);
+
+        info!(self.log, "imported dangling index"; "index" => index_name);
+        Ok(index_ref)
+    }
+
+    /// Deletes the data of a dangling index
+    pub fn delete_dangling_index(&self, cluster_metadata: &ClusterMetadata, index_name: &str) -> Result<(), DanglingIndexError> {
+        let path = self.dangling_index_path(cluster_metadata, index_name)?;
+
+        // Make sure nothing else has the directory open while it's deleted
+        let _lock = DirLock::acquire(&path).map_err(|e| DanglingIndexError::Failed(e.into()))?;
+        fs::remove_dir_all(&path).map_err(|e| DanglingIndexError::Failed(format!("failed to delete index data: {}", e)))?;
+        self.recoveries.write().unwrap().remove(index_name);
+
+        info!(self.log, "deleted dangling index"; "index" => index_name);
+        Ok(())
+    }
+
+    /// Creates a new, empty index and registers its name and aliases
+    ///
+    /// The caller must check the name and aliases are free first. The creation date is
+    /// filled in if the metadata doesn't already have one.
+    pub fn create_index(&self, cluster_metadata: &mut ClusterMetadata, index_name: &str, mut metadata: IndexMetadata) -> Result<IndexRef, String> {
+        if metadata.settings.creation_date.is_none() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000).unwrap_or(0);
+            metadata.settings.creation_date = Some(now);
+        }
+
+        let mut indices_dir = self.get_indices_dir();
+        indices_dir.push(index_name);
+        let lock = match DirLock::acquire(&indices_dir) {
+            Ok(lock) => lock,
+            Err(e) => {
+                let e = String::from(e);
+                error!(self.log, "failed to lock index directory"; "index" => index_name, "error" => e.clone());
+                return Err(e);
+            }
+        };
+        let shards = match index::create_shards(&indices_dir, &metadata.settings, &self.current_store_options()) {
+            Ok(shards) => shards,
+            Err(e) => {
+                error!(self.log, "failed to create index store"; "index" => index_name, "error" => e.clone());
+                return Err(e);
+            }
+        };
+        let index = Index::new(Uuid::new_v4(), index_name.to_owned(), metadata, shards, lock);
+        index.metadata.read().unwrap().save(index.metadata_path()).unwrap();
+        let index_ref = cluster_metadata.insert_index(index);
+
+        // Register canonical name and aliases
+        cluster_metadata.names.insert_canonical(index_name.to_owned(), index_ref).unwrap();
+        cluster_metadata.register_aliases(index_ref);
+        self.recoveries.write().unwrap().insert(index_name.to_string(), Arc::new(IndexRecovery::empty_store()));
+
+        info!(self.log, "created index"; "index" => index_name);
+
+        Ok(index_ref)
+    }
+
+    /// Removes an open or closed index, along with its data
+    ///
+    /// Aliases that were only pointing at the index are removed too
+    pub fn delete_index(&self, cluster_metadata: &mut ClusterMetadata, index_ref: IndexRef) {
+        // Get the index name
+        let index_name = match cluster_metadata.index_name(&index_ref) {
+            Some(index_name) => index_name.to_string(),
+            None => return,
+        };
+
+        // Remove index from array
+        self.scrolls.remove_index(index_ref.id());
+        cluster_metadata.indices.remove(&index_ref);
+        cluster_metadata.closed_indices.remove(&index_ref);
+
+        // Delete canonical name
+        cluster_metadata.names.delete_canonical(&index_name, index_ref).unwrap();
+        self.recoveries.write().unwrap().remove(&index_name);
+
+        // Delete file
+        let mut indices_dir = self.get_indices_dir();
+        indices_dir.push(&index_name);
+        match fs::remove_dir_all(&indices_dir) {
+            Ok(()) => {},
+            Err(e) => {
+                warn!(self.log, "failed to delete index data"; "index" => format!("{}", index_name), "error" => format!("{}", e));
+            }
+        }
+
+        info!(self.log, "deleted index"; "index" => index_name);
+
+        // Delete aliases
+        let alias_names = cluster_metadata.names.iter_index_aliases(index_ref).map(|n| n.to_string()).collect::<Vec<String>>();
+        for alias_name in alias_names {
+            let alias_deleted = cluster_metadata.names.delete_alias(&alias_name, index_ref).unwrap();
+
+            // If this was the only index being referenced by the alias, the alias would be deleted
+            if alias_deleted {
+                info!(self.log, "deleted alias"; "alias" => format!("{}", alias_name), "reason" => "no indices left");
+            }
+        }
+    }
+
+    /// Closes an open index, keeping its metadata. Closed indices are left alone
+    ///
+    /// New requests can't find the index while the cluster metadata is locked, but ones that
+    /// found it earlier may still be running, so this waits for them first.
+    pub fn close_index(&self, cluster_metadata: &mut ClusterMetadata, index_ref: IndexRef) -> Result<(), CloseIndexError> {
+        let index = match cluster_metadata.indices.remove(&index_ref) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let index_name = index.canonical_name().to_string();
+
+        let index = match Index::take_shared(index, CLOSE_TIMEOUT) {
+            Ok(index) => index,
+            Err(index) => {
+                cluster_metadata.indices.insert(index_ref, index);
+                return Err(CloseIndexError::InUse);
+            }
+        };
+
+        // This drops the store, along with any segments pinned by scrolls
+        self.scrolls.remove_index(index_ref.id());
+        match index.close() {
+            Ok(closed_index) => {
+                cluster_metadata.insert_closed_index(closed_index);
+            }
+            Err((index, e)) => {
+                error!(self.log, "failed to close index"; "index" => &index_name, "error" => format!("{}", e));
+                cluster_metadata.insert_index(index);
+                return Err(CloseIndexError::Failed(e.to_string()));
+            }
+        }
+
+        info!(self.log, "closed index"; "index" => index_name);
+        Ok(())
+    }
+
+    /// Reopens a closed index. Open indices are left alone
+    pub fn open_index(&self, cluster_metadata: &mut ClusterMetadata, index_ref: IndexRef) -> Result<(), String> {
+        let closed_index = match cluster_metadata.closed_indices.remove(&index_ref) {
+            Some(closed_index) => closed_index,
+            None => return Ok(()),
+        };
+        let index_name = closed_index.canonical_name().to_string();
+
+        let recovery = Arc::new(IndexRecovery::new(RecoverySource::ExistingStore));
+        self.recoveries.write().unwrap().insert(index_name.clone(), recovery.clone());
+
+        match closed_index.open(&self.current_store_options(), &recovery) {
+            Ok(index) => {
+                cluster_metadata.insert_index(index);
+                recovery.finish();
+            }
+            Err((closed_index, e)) => {
+                recovery.fail(e.clone());
+                error!(self.log, "failed to open index"; "index" => &index_name, "error" => e.clone());
+                cluster_metadata.insert_closed_index(closed_index);
+                return Err(e);
+            }
+        }
+
+        info!(self.log, "opened index"; "index" => index_name);
+        Ok(())
+    }
+
+    /// Works out the health of the node from the state of its indices and where the cluster
+    /// has put their copies
+    pub fn health(&self) -> ClusterHealth {
+        let cluster_metadata = self.metadata.read().unwrap();
+        let recoveries = self.recoveries.read().unwrap();
+
+        // Indices that are loading or failed to load aren't in the cluster metadata yet
+        let unloaded = recoveries.iter()
+            .filter(|&(index_name, _)| cluster_metadata.names.find_canonical(index_name).is_none())
+            .map(|(_, recovery)| recovery)
+            .collect::<Vec<_>>();
+        let initializing = unloaded.iter().filter(|recovery| !recovery.is_finished()).count();
+        let unassigned = unloaded.len() - initializing;
+
+        let mut health = ClusterHealth::new(cluster_metadata.indices.len(), initializing, unassigned);
+        health.closed_indices = cluster_metadata.closed_indices.len();
+        health.pending_tasks = self.tasks.len();
+        health.disk_watermarks = self.cluster.disk_watermarks();
+
+        let mut replicas = Vec::new();
+        for index in cluster_metadata.indices.values() {
+            replicas.push((index.canonical_name().to_string(), index.metadata.read().unwrap().settings.number_of_replicas as usize));
+
+            match index.get_store_statistics() {
+                Ok(shard_stats) => {
+                    for stats in shard_stats.iter() {
+                        let total_docs: i64 = stats.segments.iter().map(|&(_, ref s)| s.total_docs()).sum();
+                        let deleted_docs: i64 = stats.segments.iter().map(|&(_, ref s)| s.deleted_docs()).sum();
+                        health.docs += (total_docs - deleted_docs) as u64;
+                    }
+                }
+                Err(e) => {
+                    error!(self.log, "failed to read index statistics"; "index" => index.canonical_name(), "error" => e);
+                }
+            }
+        }
+
+        // The metadata and the cluster state are never locked at the same time
+        drop(recoveries);
+        drop(cluster_metadata);
+
+        for (index_name, number_of_replicas) in replicas {
+            let routing = self.cluster.index_routing(&index_name);
+            let started = routing.replicas.iter().filter(|replica| replica.started).count();
+            let mut unassigned = routing.number_of_unassigned(number_of_replicas);
+
+            if routing.primary.is_none() {
+                health.active_primary_shards -= 1;
+                health.active_shards -= 1;
+                health.unassigned_shards += 1;
+                health.status = HealthStatus::Red;
+                unassigned -= 1;
+            }
+
+            health.add_replicas(started, routing.replicas.len() - started, unassigned);
+        }
+
+        health
+    }
+
+    /// Checks how full the data disk is against the disk watermarks
+    ///
+    /// Changes of watermark are logged and reported to the master, which stops putting new
+    /// replicas on the node once it's over the low watermark and any new copies once it's
+    /// over the high one. Once the usage goes over the flood stage watermark, the
+    /// `index.blocks.read_only_allow_delete` setting is applied to every open index so
+    /// documents can still be deleted to free up space. The block is lifted once the usage
+    /// drops below the high watermark, or the watermarks are disabled, but only from the
+    /// indices that it was applied to here (see `DiskBlocks`).
+    pub fn check_disk_usage(&self) {
+        let usage = match disk_usage(&self.settings.data_dir) {
+            Ok(Some(usage)) => usage,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(self.log, "could not check disk usage"; "dir" => self.settings.data_dir.to_str().unwrap(), "error" => format!("{}", e));
+                return;
+            }
+        };
+
+        let watermark = DiskWatermark::exceeded(usage, &self.cluster_settings.current());
+        let previous = self.cluster.local_disk_watermark();
+        if watermark != previous {
+            let usage_percent = format!("{:.1}%", usage * 100.0);
+            match watermark {
+                Some(DiskWatermark::FloodStage) => {
+                    warn!(self.log, "disk usage exceeded flood stage watermark, indices will be made read-only"; "usage" => usage_percent);
+                }
+                Some(DiskWatermark::High) if watermark > previous => {
+                    warn!(self.log, "disk usage exceeded high watermark, no more copies of indices will be allocated to this node"; "usage" => usage_percent);
+                }
+                Some(DiskWatermark::Low) if watermark > previous => {
+                    warn!(self.log, "disk usage exceeded low watermark, no more replicas will be allocated to this node"; "usage" => usage_percent);
+                }
+                _ => {
+                    info!(self.log, "disk usage is back below watermark"; "watermark" => previous.map_or("", |previous| previous.name()), "usage" => usage_percent);
+                }
+            }
+
+            self.cluster.set_local_disk_watermark(watermark);
+        }
+
+        let block = match watermark {
+            Some(DiskWatermark::FloodStage) => true,
+
+            // Between the high and flood stage watermarks, leave the blocks as they are
+            Some(DiskWatermark::High) => return,
+            Some(DiskWatermark::Low) | None => false,
+        };
+
+        let disk_blocks_path = self.get_disk_blocks_path();
+        let cluster_metadata = self.metadata.read().unwrap();
+        for index in cluster_metadata.indices.values() {
+            let mut index_metadata = index.metadata.write().unwrap();
+
+            let blocked = index_metadata.settings.blocks.read_only_allow_delete;
+
+            // Blocks that were set through the settings API are left alone
+            if block && blocked {
+                continue;
+            }
+            if !block && !self.disk_blocks.contains(index.id()) {
+                continue;
+            }
+
+            if let Err(e) = self.disk_blocks.set(&disk_blocks_path, index.id(), block) {
+                error!(self.log, "failed to save disk blocks"; "index" => index.canonical_name(), "error" => e);
+            }
+
+            // The block may have already been removed through the settings API
+            if blocked == block {
+                continue;
+            }
+
+            index_metadata.settings.blocks.read_only_allow_delete = block;
+            index.apply_settings(&index_metadata.settings);
+
+            if let Err(e) = index_metadata.save(index.metadata_path()) {
+                error!(self.log, "failed to save index metadata"; "index" => index.canonical_name(), "error" => format!("{:?}", e));
+            }
+
+            if block {
+                warn!(self.log, "disk usage exceeded flood stage watermark, index has been made read-only"; "index" => index.canonical_name(), "usage" => format!("{:.1}%", usage * 100.0));
+            } else {
+                info!(self.log, "disk usage is below high watermark, read-only block removed"; "index" => index.canonical_name(), "usage" => format!("{:.1}%", usage * 100.0));
+            }
+        }
+    }
+
+    /// Releases the segments that were pinned by a scroll
+    ///
+    /// This must not be called while the cluster metadata is locked
+    pub fn release_scroll(&self, context: ScrollContext) {
+        let cluster_metadata = self.metadata.read().unwrap();
+
+        // If the index has since been closed or deleted, its segments were released with it
+        if let Some(index) = cluster_metadata.indices.values().find(|index| *index.id() == context.search.index_id) {
+            index.unpin_segments(&context.search.pins);
+        }
+    }
+
+    /// Releases any scrolls that haven't been used within their keep alive time
+    pub fn expire_scrolls(&self) {
+        for context in self.scrolls.remove_expired() {
+            self.release_scroll(context);
+        }
+    }
+
+    /// Waits for the requests in flight to finish, then flushes and releases every index
+    /// along with the lock on the data directory
+    ///
+    /// New requests must already be refused. Requests that are still running after
+    /// `shutdown_timeout` are abandoned. If one of them is holding the cluster metadata, the
+    /// indices are left as they are and their locks are taken over on the next start.
+    pub fn shutdown(&self) {
+        let deadline = Instant::now() + self.shutdown_timeout;
+
+        loop {
+            let requests_in_flight = self.requests_in_flight.load(Ordering::SeqCst);
+            if requests_in_flight == 0 {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                warn!(self.log, "gave up waiting for requests to finish"; "requests" => requests_in_flight);
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut cluster_metadata = loop {
+            match self.metadata.try_write() {
+                Ok(cluster_metadata) => break cluster_metadata,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(_) => {
+                    error!(self.log, "could not release indices, cluster metadata is in use");
+                    return;
+                }
+            }
+        };
+
+        for (_, index) in cluster_metadata.indices.drain() {
+            // Publish any pending changes and make sure they're on disk. The store is closed
+            // and the index's lock released when it's dropped
+            match index.refresh().and_then(|_| index.flush()) {
+                Ok(()) => info!(self.log, "flushed index"; "index" => index.canonical_name()),
+                Err(e) => error!(self.log, "failed to flush index"; "index" => index.canonical_name(), "error" => e),
+            }
+        }
+
+        cluster_metadata.closed_indices.clear();
+        drop(cluster_metadata);
+
+        self.data_dir_lock.lock().unwrap().take();
     }
 }
@@ -0,0 +1,268 @@
+//! Keeps track of the searches and other long running operations, so they can be listed and cancelled
+//!
+//! A task registers itself here while it's running. The returned handle removes the
+//! task again when it's dropped, so tasks can't be left behind if the request returns
+//! early or panics. Tasks that run in the background can store their result when they
+//! finish, so it can be looked up afterwards.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value as Json;
+
+use search::cancellation::SearchCancellation;
+
+
+/// Task ids are prefixed with this. There's only one node, so it's always the same
+pub const NODE_ID: &'static str = "rusticsearch";
+
+
+/// How many results of finished tasks to keep. The oldest are dropped first
+const MAX_COMPLETED_TASKS: usize = 100;
+
+
+/// Reports the progress of a running task
+pub trait TaskStatus: fmt::Debug + Send + Sync {
+    fn to_json(&self) -> Json;
+}
+
+
+#[derive(Debug)]
+pub struct Task {
+    /// The kind of task, eg "indices:data/read/search"
+    pub action: String,
+
+    pub description: String,
+
+    /// The `X-Opaque-Id` of the request that started the task
+    pub opaque_id: Option<String>,
+
+    /// Time the task was started, in milliseconds since the UNIX epoch
+    pub start_time_in_millis: u64,
+
+    started: Instant,
+    cancellation: SearchCancellation,
+    status: Option<Arc<TaskStatus>>,
+}
+
+
+impl Task {
+    pub fn running_time(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn status(&self) -> Option<Json> {
+        self.status.as_ref().map(|status| status.to_json())
+    }
+}
+
+
+/// A task that has finished, along with its result
+#[derive(Debug)]
+pub struct CompletedTask {
+    pub action: String,
+    pub description: String,
+    pub opaque_id: Option<String>,
+    pub start_time_in_millis: u64,
+    pub running_time: Duration,
+    pub result: Json,
+}
+
+
+/// Holds all of the tasks that are running, by task number
+#[derive(Debug)]
+pub struct TaskManager {
+    next_id: AtomicUsize,
+    tasks: Mutex<BTreeMap<u64, Task>>,
+    completed: Mutex<BTreeMap<u64, CompletedTask>>,
+}
+
+
+impl TaskManager {
+    pub fn new() -> TaskManager {
+        TaskManager {
+            next_id: AtomicUsize::new(1),
+            tasks: Mutex::new(BTreeMap::new()),
+            completed: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds a running task. It's removed when the returned handle is dropped
+    pub fn register<'a>(&'a self, action: &str, description: String, opaque_id: Option<&str>, cancellation: SearchCancellation) -> TaskHandle<'a> {
+        self.register_with_status(action, description, opaque_id, cancellation, None)
+    }
+
+    /// Adds a running task that reports its progress through `status`
+    pub fn register_with_status<'a>(&'a self, action: &str, description: String, opaque_id: Option<&str>, cancellation: SearchCancellation, status: Option<Arc<TaskStatus>>) -> TaskHandle<'a> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+        let start_time_in_millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000).unwrap_or(0);
+
+        self.tasks.lock().unwrap().insert(id, Task {
+            action: action.to_string(),
+            description: description,
+            opaque_id: opaque_id.map(|opaque_id| opaque_id.to_string()),
+            start_time_in_millis: start_time_in_millis,
+            started: Instant::now(),
+            cancellation: cancellation,
+            status: status,
+        });
+
+        TaskHandle {
+            manager: self,
+            id: id,
+        }
+    }
+
+    /// Cancels a running task. Returns false if the task doesn't exist
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.tasks.lock().unwrap().get(&id) {
+            Some(task) => {
+                task.cancellation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs a function on each running task, in the order they were started
+    pub fn with_tasks<R, F: FnMut(u64, &Task) -> R>(&self, mut f: F) -> Vec<R> {
+        self.tasks.lock().unwrap().iter().map(|(id, task)| f(*id, task)).collect()
+    }
+
+    /// Runs a function on a task. Returns None if the task doesn't exist
+    pub fn with_task<R, F: FnOnce(&Task) -> R>(&self, id: u64, f: F) -> Option<R> {
+        self.tasks.lock().unwrap().get(&id).map(f)
+    }
+
+    /// Runs a function on a finished task. Returns None if the task isn't finished or its result wasn't kept
+    pub fn with_completed_task<R, F: FnOnce(&CompletedTask) -> R>(&self, id: u64, f: F) -> Option<R> {
+        self.completed.lock().unwrap().get(&id).map(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+}
+
+
+/// Removes a task from the task manager when dropped
+#[derive(Debug)]
+pub struct TaskHandle<'a> {
+    manager: &'a TaskManager,
+    id: u64,
+}
+
+
+impl<'a> TaskHandle<'a> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Removes the task, keeping its result so it can be looked up after it's finished
+    pub fn finish(self, result: Json) {
+        let task = match self.manager.tasks.lock().unwrap().remove(&self.id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        let mut completed = self.manager.completed.lock().unwrap();
+        completed.insert(self.id, CompletedTask {
+            action: task.action,
+            description: task.description,
+            opaque_id: task.opaque_id,
+            start_time_in_millis: task.start_time_in_millis,
+            running_time: task.started.elapsed(),
+            result: result,
+        });
+
+        while completed.len() > MAX_COMPLETED_TASKS {
+            let oldest = *completed.keys().next().unwrap();
+            completed.remove(&oldest);
+        }
+    }
+}
+
+
+impl<'a> Drop for TaskHandle<'a> {
+    fn drop(&mut self) {
+        self.manager.tasks.lock().unwrap().remove(&self.id);
+    }
+}
+
+
+/// Formats a task number as a task id, eg "rusticsearch:1"
+pub fn format_task_id(id: u64) -> String {
+    format!("{}:{}", NODE_ID, id)
+}
+
+
+/// Parses a task id. The node id prefix is optional
+pub fn parse_task_id(task_id: &str) -> Option<u64> {
+    let number = match task_id.find(':') {
+        Some(position) if &task_id[..position] == NODE_ID => &task_id[position + 1..],
+        Some(_) => return None,
+        None => task_id,
+    };
+
+    number.parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::cancellation::SearchCancellation;
+
+    use super::{TaskManager, MAX_COMPLETED_TASKS, format_task_id, parse_task_id};
+
+    #[test]
+    fn test_register_and_cancel() {
+        let manager = TaskManager::new();
+        let cancellation = SearchCancellation::new();
+
+        {
+            let handle = manager.register("indices:data/read/search", "indices[test]".to_string(), Some("client-1"), cancellation.clone());
+            assert_eq!(manager.len(), 1);
+            assert_eq!(manager.with_task(handle.id(), |task| task.description.clone()), Some("indices[test]".to_string()));
+            assert_eq!(manager.with_task(handle.id(), |task| task.opaque_id.clone()), Some(Some("client-1".to_string())));
+
+            assert!(manager.cancel(handle.id()));
+            assert!(cancellation.is_cancelled());
+        }
+
+        // Dropping the handle removes the task
+        assert_eq!(manager.len(), 0);
+        assert!(!manager.cancel(1));
+    }
+
+    #[test]
+    fn test_finish() {
+        let manager = TaskManager::new();
+
+        let handle = manager.register("indices:data/write/reindex", "reindex".to_string(), Some("client-1"), SearchCancellation::new());
+        let id = handle.id();
+        assert_eq!(manager.with_completed_task(id, |task| task.result.clone()), None);
+
+        handle.finish(json!({"created": 1}));
+        assert_eq!(manager.len(), 0);
+        assert_eq!(manager.with_completed_task(id, |task| task.result.clone()), Some(json!({"created": 1})));
+        assert_eq!(manager.with_completed_task(id, |task| task.opaque_id.clone()), Some(Some("client-1".to_string())));
+
+        // Only the most recent results are kept
+        for _ in 0..MAX_COMPLETED_TASKS {
+            manager.register("indices:data/write/reindex", "reindex".to_string(), None, SearchCancellation::new()).finish(json!({}));
+        }
+        assert!(manager.with_completed_task(id, |_| ()).is_none());
+    }
+
+    #[test]
+    fn test_task_ids() {
+        assert_eq!(format_task_id(12), "rusticsearch:12");
+        assert_eq!(parse_task_id("rusticsearch:12"), Some(12));
+        assert_eq!(parse_task_id("12"), Some(12));
+        assert_eq!(parse_task_id("othernode:12"), None);
+        assert_eq!(parse_task_id("rusticsearch:foo"), None);
+    }
+}
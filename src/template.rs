@@ -0,0 +1,316 @@
+//! Renders mustache templates into search requests
+//!
+//! Supports variables (`{{name}}`, with dotted names to look inside objects and `{{.}}` for
+//! the current item), unescaped variables (`{{{name}}}`), sections (`{{#name}}...{{/name}}`),
+//! inverted sections (`{{^name}}...{{/name}}`) and comments (`{{! ...}}`). There are two
+//! helpers as well: `{{#toJson}}name{{/toJson}}` writes a parameter out as JSON and
+//! `{{#join}}name{{/join}}` joins an array with commas.
+//!
+//! The output is meant to be JSON, so variables are escaped to fit inside a JSON string.
+
+use std::fmt;
+
+use serde_json::{self, Value as Json};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    UnclosedTag,
+    UnclosedSection(String),
+    UnexpectedSectionEnd(String),
+
+    /// The rendered template isn't valid JSON
+    InvalidJson(String),
+}
+
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TemplateError::UnclosedTag => write!(f, "unclosed tag"),
+            TemplateError::UnclosedSection(ref name) => write!(f, "section {:?} isn't closed", name),
+            TemplateError::UnexpectedSectionEnd(ref name) => write!(f, "unexpected end of section {:?}", name),
+            TemplateError::InvalidJson(ref error) => write!(f, "rendered template isn't valid JSON: {}", error),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Variable { name: String, escape: bool },
+    Section { name: String, inverted: bool, children: Vec<Node> },
+    ToJson(String),
+    Join(String),
+}
+
+
+/// A parsed template that can be rendered many times
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+
+/// Splits off the next tag, returning the text before it, the tag's content and the rest
+fn next_tag(input: &str) -> Result<Option<(&str, &str, &str)>, TemplateError> {
+    let start = match input.find("{{") {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+
+    // Triple mustaches are closed by three braces
+    let (content_start, closer) = if input[start..].starts_with("{{{") { (start + 3, "}}}") } else { (start + 2, "}}") };
+    let end = input[content_start..].find(closer).ok_or(TemplateError::UnclosedTag)? + content_start;
+    let content = if closer == "}}}" { &input[start + 2..end + 1] } else { &input[content_start..end] };
+
+    Ok(Some((&input[..start], content, &input[end + closer.len()..])))
+}
+
+
+/// Parses nodes until the end of the input, or the end of the named section. Returns the
+/// nodes and the input after the end of the section
+fn parse_nodes<'a>(mut input: &'a str, section: Option<&str>) -> Result<(Vec<Node>, &'a str), TemplateError> {
+    let mut nodes = Vec::new();
+
+    loop {
+        let (text, tag, rest) = match next_tag(input)? {
+            Some(tag) => tag,
+            None => {
+                if let Some(section) = section {
+                    return Err(TemplateError::UnclosedSection(section.to_string()));
+                }
+
+                if !input.is_empty() {
+                    nodes.push(Node::Text(input.to_string()));
+                }
+
+                return Ok((nodes, ""));
+            }
+        };
+
+        if !text.is_empty() {
+            nodes.push(Node::Text(text.to_string()));
+        }
+        input = rest;
+
+        let name = tag[1..].trim();
+        match tag.chars().next() {
+            Some('!') => {}
+            Some('{') => {
+                let name = tag[1..tag.len() - 1].trim();
+                nodes.push(Node::Variable { name: name.to_string(), escape: false });
+            }
+            Some('&') => nodes.push(Node::Variable { name: name.to_string(), escape: false }),
+            Some('#') if name == "toJson" || name == "join" => {
+                let end_tag = format!("{{{{/{}}}}}", name);
+                let end = input.find(&end_tag).ok_or_else(|| TemplateError::UnclosedSection(name.to_string()))?;
+                let param = input[..end].trim().to_string();
+                nodes.push(if name == "toJson" { Node::ToJson(param) } else { Node::Join(param) });
+                input = &input[end + end_tag.len()..];
+            }
+            Some(c) if c == '#' || c == '^' => {
+                let (children, rest) = parse_nodes(input, Some(name))?;
+                nodes.push(Node::Section { name: name.to_string(), inverted: c == '^', children: children });
+                input = rest;
+            }
+            Some('/') => {
+                return match section {
+                    Some(section) if section == name => Ok((nodes, input)),
+                    _ => Err(TemplateError::UnexpectedSectionEnd(name.to_string())),
+                };
+            }
+            _ => nodes.push(Node::Variable { name: tag.trim().to_string(), escape: true }),
+        }
+    }
+}
+
+
+/// Finds a parameter, looking in the innermost section first
+fn lookup<'a>(stack: &[&'a Json], name: &str) -> Option<&'a Json> {
+    if name == "." {
+        return stack.last().cloned();
+    }
+
+    let mut parts = name.split('.');
+    let first = parts.next().unwrap_or("");
+
+    for context in stack.iter().rev() {
+        if let Some(mut value) = context.get(first) {
+            for part in parts {
+                value = match *value {
+                    Json::Array(ref array) => part.parse::<usize>().ok().and_then(|index| array.get(index))?,
+                    _ => value.get(part)?,
+                };
+            }
+
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+
+fn value_to_text(value: &Json) -> String {
+    match *value {
+        Json::String(ref string) => string.clone(),
+        Json::Null => String::new(),
+        _ => value.to_string(),
+    }
+}
+
+
+/// Escapes text so it can go inside a JSON string
+fn escape(text: &str) -> String {
+    let quoted = Json::String(text.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+
+fn is_truthy(value: &Json) -> bool {
+    match *value {
+        Json::Null => false,
+        Json::Bool(value) => value,
+        Json::String(ref string) => !string.is_empty(),
+        Json::Array(ref array) => !array.is_empty(),
+        _ => true,
+    }
+}
+
+
+fn render_nodes(nodes: &[Node], stack: &mut Vec<&Json>, output: &mut String) {
+    for node in nodes.iter() {
+        match *node {
+            Node::Text(ref text) => output.push_str(text),
+            Node::Variable { ref name, escape: should_escape } => {
+                let text = lookup(stack, name).map(value_to_text).unwrap_or_default();
+                output.push_str(&if should_escape { escape(&text) } else { text });
+            }
+            Node::ToJson(ref name) => output.push_str(&lookup(stack, name).unwrap_or(&Json::Null).to_string()),
+            Node::Join(ref name) => {
+                let text = match lookup(stack, name) {
+                    Some(&Json::Array(ref array)) => array.iter().map(value_to_text).collect::<Vec<_>>().join(","),
+                    Some(value) => value_to_text(value),
+                    None => String::new(),
+                };
+                output.push_str(&escape(&text));
+            }
+            Node::Section { ref name, inverted, ref children } => {
+                let value = lookup(stack, name);
+                let truthy = value.map_or(false, is_truthy);
+
+                if inverted {
+                    if !truthy {
+                        render_nodes(children, stack, output);
+                    }
+                } else if truthy {
+                    match value {
+                        Some(&Json::Array(ref array)) => {
+                            for item in array.iter() {
+                                stack.push(item);
+                                render_nodes(children, stack, output);
+                                stack.pop();
+                            }
+                        }
+                        Some(value) => {
+                            stack.push(value);
+                            render_nodes(children, stack, output);
+                            stack.pop();
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, TemplateError> {
+        let (nodes, _) = parse_nodes(source, None)?;
+
+        Ok(Template {
+            nodes: nodes,
+        })
+    }
+
+    pub fn render(&self, params: &Json) -> String {
+        let mut output = String::new();
+        render_nodes(&self.nodes, &mut vec![params], &mut output);
+        output
+    }
+}
+
+
+/// Takes the source of a search template, which is either a string or some JSON containing
+/// tags, and returns its text
+pub fn template_source_to_string(source: &Json) -> String {
+    match *source {
+        Json::String(ref source) => source.clone(),
+        _ => source.to_string(),
+    }
+}
+
+
+/// Renders a search template into the body of a search request
+pub fn render_search_template(source: &str, params: &Json) -> Result<Json, TemplateError> {
+    let rendered = Template::parse(source)?.render(params);
+    serde_json::from_str(&rendered).map_err(|e| TemplateError::InvalidJson(e.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{Template, TemplateError, render_search_template};
+
+    fn render(source: &str, params: serde_json::Value) -> String {
+        Template::parse(source).unwrap().render(&params)
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(render("Hello {{name}}!", json!({"name": "world"})), "Hello world!");
+        assert_eq!(render("{{ user.name }} is {{user.age}}", json!({"user": {"name": "Ann", "age": 30}})), "Ann is 30");
+        assert_eq!(render("[{{missing}}]", json!({})), "[]");
+        assert_eq!(render("{{q}}", json!({"q": "say \"hi\""})), "say \\\"hi\\\"");
+        assert_eq!(render("{{{q}}}", json!({"q": "say \"hi\""})), "say \"hi\"");
+        assert_eq!(render("a{{! comment }}b", json!({})), "ab");
+    }
+
+    #[test]
+    fn test_sections() {
+        assert_eq!(render("{{#show}}yes{{/show}}{{^show}}no{{/show}}", json!({"show": true})), "yes");
+        assert_eq!(render("{{#show}}yes{{/show}}{{^show}}no{{/show}}", json!({"show": false})), "no");
+        assert_eq!(render("{{#tags}}<{{.}}>{{/tags}}", json!({"tags": ["a", "b"]})), "<a><b>");
+        assert_eq!(render("{{#user}}{{name}} {{greeting}}{{/user}}", json!({"user": {"name": "Ann"}, "greeting": "hi"})), "Ann hi");
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(render("{{#toJson}}filter{{/toJson}}", json!({"filter": {"term": {"tag": "a"}}})), "{\"term\":{\"tag\":\"a\"}}");
+        assert_eq!(render("{{#join}}tags{{/join}}", json!({"tags": ["a", "b", "c"]})), "a,b,c");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Template::parse("{{name"), Err(TemplateError::UnclosedTag));
+        assert_eq!(Template::parse("{{#show}}yes"), Err(TemplateError::UnclosedSection("show".to_string())));
+        assert_eq!(Template::parse("yes{{/show}}"), Err(TemplateError::UnexpectedSectionEnd("show".to_string())));
+    }
+
+    #[test]
+    fn test_render_search_template() {
+        let source = r#"{"query": {"match": {"title": "{{query_string}}"}}, "size": {{size}}}"#;
+        assert_eq!(render_search_template(source, &json!({"query_string": "hello", "size": 5})), Ok(json!({
+            "query": {"match": {"title": "hello"}},
+            "size": 5,
+        })));
+
+        assert!(render_search_template(source, &json!({"query_string": "hello"})).is_err());
+    }
+}
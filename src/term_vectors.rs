@@ -0,0 +1,164 @@
+//! Works out the terms in a document's text fields for the term vectors API
+//!
+//! Term vectors aren't stored in the index, so they're rebuilt by analyzing the field's
+//! value with its index analyzer. Tokens don't record where they came from in the text,
+//! so offsets are found by matching each position to the word the tokenizer split out at
+//! that position. The ngram tokenizer produces several tokens per word, so offsets aren't
+//! given for fields that use it.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value as Json;
+
+use search::token::Token;
+use analysis::AnalyzerSpec;
+use analysis::tokenizers::TokenizerSpec;
+use highlight::word_indices;
+use mapping::{FieldMapping, FieldType};
+
+
+/// Where a term appears in a field's value
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermOccurrence {
+    /// Starts at zero
+    pub position: u32,
+
+    /// Character offsets of the word the term came from
+    pub offsets: Option<(usize, usize)>,
+}
+
+
+/// The terms in a field's value, along with where each of them appears
+pub type FieldTermVector = BTreeMap<String, Vec<TermOccurrence>>;
+
+
+fn analyze_text(text: &str, analyzer: Option<&AnalyzerSpec>, first_position: u32, first_offset: usize, terms: &mut FieldTermVector) -> u32 {
+    let analyzer = match analyzer {
+        Some(analyzer) => analyzer,
+        None => {
+            // The whole value is one term
+            terms.entry(text.to_string()).or_insert_with(Vec::new).push(TermOccurrence {
+                position: first_position,
+                offsets: Some((first_offset, first_offset + text.chars().count())),
+            });
+
+            return first_position + 1;
+        }
+    };
+
+    let words = match analyzer.tokenizer {
+        TokenizerSpec::Standard | TokenizerSpec::Lowercase => {
+            word_indices(text).map(|(start, word)| {
+                let start_offset = first_offset + text[..start].chars().count();
+                (start_offset, start_offset + word.chars().count())
+            }).collect::<Vec<_>>()
+        }
+        TokenizerSpec::NGram { .. } | TokenizerSpec::Plugin(_) => Vec::new(),
+    };
+
+    let mut next_position = first_position;
+    for Token { term, position } in analyzer.initialise(text) {
+        let term = match String::from_utf8(term.as_bytes().to_vec()) {
+            Ok(term) => term,
+            Err(_) => continue,
+        };
+
+        // Token positions start at one
+        terms.entry(term).or_insert_with(Vec::new).push(TermOccurrence {
+            position: first_position + position - 1,
+            offsets: words.get(position as usize - 1).cloned(),
+        });
+
+        next_position = next_position.max(first_position + position);
+    }
+
+    next_position
+}
+
+
+/// Analyzes the value of a text field
+///
+/// Returns None if the field isn't a text field. Arrays are treated as one long value, with
+/// the positions and offsets of each item following on from the one before.
+pub fn field_term_vector(field_mapping: &FieldMapping, value: &Json) -> Option<FieldTermVector> {
+    if field_mapping.data_type != FieldType::String {
+        return None;
+    }
+
+    let values = match *value {
+        Json::Array(ref array) => array.iter().collect::<Vec<_>>(),
+        _ => vec![value],
+    };
+
+    let mut terms = BTreeMap::new();
+    let mut position = 0;
+    let mut offset = 0;
+    for value in values {
+        let text = match *value {
+            Json::String(ref string) => string.clone(),
+            Json::Number(ref number) => number.to_string(),
+            _ => continue,
+        };
+
+        position = analyze_text(&text, field_mapping.index_analyzer(), position, offset, &mut terms);
+
+        // Leave a gap for the separator between values
+        offset += text.chars().count() + 1;
+    }
+
+    Some(terms)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use analysis::AnalyzerSpec;
+    use analysis::tokenizers::TokenizerSpec;
+    use analysis::filters::FilterSpec;
+    use mapping::{FieldMapping, FieldType};
+
+    use super::{field_term_vector, analyze_text, TermOccurrence};
+
+    #[test]
+    fn test_standard_analyzer() {
+        let analyzer = AnalyzerSpec {
+            tokenizer: TokenizerSpec::Standard,
+            filters: vec![FilterSpec::Lowercase, FilterSpec::ASCIIFolding],
+        };
+
+        let mut terms = Default::default();
+        assert_eq!(analyze_text("Hello wörld, hello!", Some(&analyzer), 0, 0, &mut terms), 3);
+
+        assert_eq!(terms.get("hello"), Some(&vec![
+            TermOccurrence { position: 0, offsets: Some((0, 5)) },
+            TermOccurrence { position: 2, offsets: Some((13, 18)) },
+        ]));
+        assert_eq!(terms.get("world"), Some(&vec![TermOccurrence { position: 1, offsets: Some((6, 11)) }]));
+    }
+
+    #[test]
+    fn test_ngram_tokenizer() {
+        let analyzer = AnalyzerSpec {
+            tokenizer: TokenizerSpec::NGram { min_size: 2, max_size: 2, edge: ::analysis::ngram_generator::Edge::Neither },
+            filters: vec![],
+        };
+
+        let mut terms = Default::default();
+        analyze_text("abc", Some(&analyzer), 0, 0, &mut terms);
+        assert_eq!(terms.get("ab").map(|occurrences| occurrences[0].offsets), Some(None));
+    }
+
+    #[test]
+    fn test_arrays() {
+        // Without an analyzer, each item is a single term
+        let field_mapping = FieldMapping::default();
+        let terms = field_term_vector(&field_mapping, &json!(["a b", "b", null])).unwrap();
+
+        assert_eq!(terms.get("a b"), Some(&vec![TermOccurrence { position: 0, offsets: Some((0, 3)) }]));
+        assert_eq!(terms.get("b"), Some(&vec![TermOccurrence { position: 1, offsets: Some((4, 5)) }]));
+
+        let mut field_mapping = FieldMapping::default();
+        field_mapping.data_type = FieldType::Integer;
+        assert_eq!(field_term_vector(&field_mapping, &json!(1)), None);
+    }
+}
@@ -0,0 +1,361 @@
+//! Fixed pools of threads for searching, indexing and management work
+//!
+//! Searching and indexing keep a thread busy until they're done, so they're kept off the API
+//! server's runtime, which only has a thread per core. Each kind of work has its own pool, so
+//! heavy indexing can't take the threads that searches need. Work waits in a bounded queue
+//! while every thread of its pool is busy, so an overloaded node refuses work rather than
+//! queueing it until it runs out of memory.
+
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+
+use futures::channel::oneshot;
+
+use settings::{ThreadPoolsSettings, ThreadPoolSettings};
+
+
+/// Returned when work can't be queued because the queue is full
+#[derive(Debug)]
+pub struct QueueFull;
+
+
+type Job = Box<FnOnce() + Send>;
+
+
+#[derive(Debug, Default)]
+struct Counters {
+    queue: AtomicUsize,
+    active: AtomicUsize,
+    largest: AtomicUsize,
+    completed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+
+/// A snapshot of what a pool is doing, as reported in `_nodes/stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadPoolStats {
+    pub threads: usize,
+    pub queue: usize,
+    pub active: usize,
+
+    /// The most threads that have been busy at once
+    pub largest: usize,
+
+    pub completed: usize,
+
+    /// How much work has been refused because the queue was full
+    pub rejected: usize,
+}
+
+
+#[derive(Debug)]
+pub struct ThreadPool {
+    name: String,
+    threads: usize,
+    queue_size: usize,
+    queue: SyncSender<Job>,
+    counters: Arc<Counters>,
+}
+
+
+impl ThreadPool {
+    /// Starts the threads. They stop once the pool is dropped and the queue is empty
+    pub fn new(name: &str, threads: usize, queue_size: usize) -> io::Result<ThreadPool> {
+        let (queue, jobs) = mpsc::sync_channel::<Job>(queue_size);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let counters = Arc::new(Counters::default());
+
+        for number in 0..threads {
+            let jobs = jobs.clone();
+            let counters = counters.clone();
+
+            thread::Builder::new().name(format!("{}-{}", name, number)).spawn(move || {
+                loop {
+                    // The lock is only held while waiting for the next job
+                    let job = match jobs.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+
+                    counters.queue.fetch_sub(1, Ordering::SeqCst);
+                    let active = counters.active.fetch_add(1, Ordering::SeqCst) + 1;
+                    counters.largest.fetch_max(active, Ordering::SeqCst);
+
+                    job();
+
+                    counters.active.fetch_sub(1, Ordering::SeqCst);
+                    counters.completed.fetch_add(1, Ordering::SeqCst);
+                }
+            })?;
+        }
+
+        Ok(ThreadPool {
+            name: name.to_string(),
+            threads: threads,
+            queue_size: queue_size,
+            queue: queue,
+            counters: counters,
+        })
+    }
+
+    fn from_settings(name: &str, settings: &ThreadPoolSettings) -> io::Result<ThreadPool> {
+        ThreadPool::new(name, settings.size, settings.queue_size)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How much work can wait for a thread before more is refused
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// Queues a function to run on one of the threads
+    ///
+    /// Returns a receiver for its result, which is an error if the function panicked.
+    pub fn spawn<F, T>(&self, f: F) -> Result<oneshot::Receiver<thread::Result<T>>, QueueFull>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job = Box::new(move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+
+        // Counted before it's sent so a thread that takes it straight away doesn't take the
+        // count below zero
+        self.counters.queue.fetch_add(1, Ordering::SeqCst);
+        if self.queue.try_send(job).is_err() {
+            self.counters.queue.fetch_sub(1, Ordering::SeqCst);
+            self.counters.rejected.fetch_add(1, Ordering::SeqCst);
+            return Err(QueueFull);
+        }
+
+        Ok(receiver)
+    }
+
+    /// Runs `work` on the calling thread, and at the same time on up to `helpers` of the
+    /// pool's threads
+    ///
+    /// Each call of `work` should take pieces of a shared job until there are none left, as
+    /// helpers are only used if they get a thread before the calling thread has finished the
+    /// job by itself. Returns once every call that was started has returned. As nothing waits
+    /// for a helper that hasn't started, this can be used from the pool's own threads.
+    pub fn run_with_helpers<F: Fn() + Sync>(&self, helpers: usize, work: &F) {
+        let state = Arc::new(HelperState::default());
+
+        // Waits for the helpers when this returns, even if `work` panics on this thread
+        let _guard = HelperGuard(&state);
+
+        let work: &(Fn() + Sync) = work;
+
+        // SAFETY: a helper only calls `work` after adding itself to `state.running` while
+        // `state.finished` is false. `HelperGuard` sets `finished` and then waits for `running`
+        // to drop back to zero before this function returns, so `work` isn't used after the
+        // borrow ends. Helpers that get a thread later see `finished` and drop the reference
+        // without using it.
+        let work: &'static (Fn() + Sync) = unsafe { mem::transmute(work) };
+
+        for _ in 0..helpers {
+            let state = state.clone();
+            let spawned = self.spawn(move || {
+                {
+                    let mut progress = state.progress.lock().unwrap();
+                    if progress.finished {
+                        return;
+                    }
+                    progress.running += 1;
+                }
+
+                let _running = RunningHelper(&state);
+                work();
+            });
+
+            // The calling thread does the work that a helper would have done
+            if spawned.is_err() {
+                break;
+            }
+        }
+
+        work();
+    }
+
+    pub fn stats(&self) -> ThreadPoolStats {
+        ThreadPoolStats {
+            threads: self.threads,
+            queue: self.counters.queue.load(Ordering::SeqCst),
+            active: self.counters.active.load(Ordering::SeqCst),
+            largest: self.counters.largest.load(Ordering::SeqCst),
+            completed: self.counters.completed.load(Ordering::SeqCst),
+            rejected: self.counters.rejected.load(Ordering::SeqCst),
+        }
+    }
+}
+
+
+#[derive(Debug, Default)]
+struct HelperProgress {
+    /// Set once the calling thread has finished, helpers that start after this do nothing
+    finished: bool,
+
+    /// Helpers that are calling the work function
+    running: usize,
+}
+
+
+/// Shared by the calling thread and the helpers of `ThreadPool::run_with_helpers`
+#[derive(Debug, Default)]
+struct HelperState {
+    progress: Mutex<HelperProgress>,
+    stopped: Condvar,
+}
+
+
+/// Marks the calling thread as finished, then waits for the helpers that are running
+struct HelperGuard<'a>(&'a HelperState);
+
+
+impl<'a> Drop for HelperGuard<'a> {
+    fn drop(&mut self) {
+        let mut progress = self.0.progress.lock().unwrap_or_else(|e| e.into_inner());
+        progress.finished = true;
+
+        while progress.running > 0 {
+            progress = self.0.stopped.wait(progress).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+
+/// Counts a helper as stopped when it returns or panics
+struct RunningHelper<'a>(&'a HelperState);
+
+
+impl<'a> Drop for RunningHelper<'a> {
+    fn drop(&mut self) {
+        let mut progress = self.0.progress.lock().unwrap_or_else(|e| e.into_inner());
+        progress.running -= 1;
+        self.0.stopped.notify_all();
+    }
+}
+
+
+/// The node's thread pools
+pub struct ThreadPools {
+    /// Searches and document gets. Stores also search their segments on it in parallel
+    pub search: Arc<ThreadPool>,
+
+    /// Indexing, updates and deletes
+    pub write: ThreadPool,
+
+    /// Everything else, including index administration and background merges
+    pub management: ThreadPool,
+}
+
+
+impl ThreadPools {
+    pub fn new(settings: &ThreadPoolsSettings) -> io::Result<ThreadPools> {
+        Ok(ThreadPools {
+            search: Arc::new(ThreadPool::from_settings("search", &settings.search)?),
+            write: ThreadPool::from_settings("write", &settings.write)?,
+            management: ThreadPool::from_settings("management", &settings.management)?,
+        })
+    }
+
+    pub fn all(&self) -> [&ThreadPool; 3] {
+        [&self.search, &self.write, &self.management]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    use super::ThreadPool;
+
+    #[test]
+    fn test_thread_pool() {
+        let pool = ThreadPool::new("test", 1, 1).unwrap();
+        assert_eq!(block_on(pool.spawn(|| 1 + 1).unwrap()).unwrap().unwrap(), 2);
+
+        // Keep the only thread busy, then fill the queue
+        let (started, wait_for_start) = mpsc::channel::<()>();
+        let (release, wait_for_release) = mpsc::channel::<()>();
+        let busy = pool.spawn(move || {
+            started.send(()).unwrap();
+            wait_for_release.recv().unwrap()
+        }).unwrap();
+        wait_for_start.recv().unwrap();
+
+        let queued = pool.spawn(|| panic!("failed")).unwrap();
+        assert!(pool.spawn(|| ()).is_err());
+
+        let stats = pool.stats();
+        assert_eq!((stats.threads, stats.queue, stats.active, stats.rejected), (1, 1, 1, 1));
+
+        release.send(()).unwrap();
+        block_on(busy).unwrap().unwrap();
+
+        // Panics are returned rather than taking down the thread
+        assert!(block_on(queued).unwrap().is_err());
+        assert_eq!(block_on(pool.spawn(|| "still running").unwrap()).unwrap().unwrap(), "still running");
+
+        // The result is sent just before the job is counted as completed
+        while pool.stats().active > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let stats = pool.stats();
+        assert_eq!((stats.queue, stats.largest, stats.completed, stats.rejected), (0, 1, 4, 1));
+    }
+
+    #[test]
+    fn test_run_with_helpers() {
+        let pool = ThreadPool::new("test", 2, 2).unwrap();
+        let next = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+
+        pool.run_with_helpers(2, &|| {
+            while next.fetch_add(1, Ordering::SeqCst) < 100 {
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_run_with_helpers_from_pool() {
+        // The pool's only thread is the one asking for help, so the calling thread has to do
+        // all of the work rather than waiting for a helper that can never start
+        let pool = Arc::new(ThreadPool::new("test", 1, 1).unwrap());
+        let inner_pool = pool.clone();
+        let result = pool.spawn(move || {
+            let next = AtomicUsize::new(0);
+            let done = AtomicUsize::new(0);
+
+            inner_pool.run_with_helpers(1, &|| {
+                while next.fetch_add(1, Ordering::SeqCst) < 10 {
+                    done.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+
+            done.load(Ordering::SeqCst)
+        }).unwrap();
+
+        assert_eq!(block_on(result).unwrap().unwrap(), 10);
+    }
+}
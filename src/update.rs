@@ -0,0 +1,768 @@
+//! Changes the source of a document, for the update API
+//!
+//! Partial documents are merged into the source, replacing values and merging objects.
+//! Update scripts are a small subset of Painless that assigns to fields of the source:
+//!
+//! ```text
+//! ctx._source.views += params.count; ctx._source.tags_text = 'a' + ctx._source.tag
+//! ctx._source.remove('draft')
+//! ctx.op = 'delete'
+//! ```
+
+use serde_json::{Map, Number, Value as Json};
+
+use search::backends::rocksdb::{DocumentVersion, WriteCondition, VersionConflict, DocumentInsertError, DocumentDeleteError};
+use document::{DocumentSource, PrepareDocumentError, read_source_field};
+use index::Index;
+use index::metadata::IndexMetadata;
+use mapping::Mapping;
+
+
+/// Merges a partial document into a source. Returns true if anything changed
+pub fn merge(source: &mut Map<String, Json>, doc: &Map<String, Json>) -> bool {
+    let mut changed = false;
+
+    for (key, value) in doc.iter() {
+        if let Json::Object(ref doc_object) = *value {
+            if let Some(&mut Json::Object(ref mut source_object)) = source.get_mut(key) {
+                changed |= merge(source_object, doc_object);
+                continue;
+            }
+        }
+
+        if source.get(key) != Some(value) {
+            source.insert(key.clone(), value.clone());
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+
+/// What to do with the document once its update script has run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpdateOperation {
+    Index,
+    Noop,
+    Delete,
+}
+
+
+/// The body of an update request
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateRequest {
+    /// A partial document to merge into the source
+    pub doc: Option<Map<String, Json>>,
+
+    /// The source to use if the document doesn't exist
+    pub upsert: Option<Map<String, Json>>,
+
+    pub script: Option<UpdateScript>,
+
+    /// Use the partial document as the source if the document doesn't exist
+    pub doc_as_upsert: bool,
+
+    /// Run the script against the upsert if the document doesn't exist
+    pub scripted_upsert: bool,
+
+    /// Don't write the document if merging the partial document didn't change it
+    pub detect_noop: bool,
+}
+
+
+/// Why an update couldn't be made
+#[derive(Debug)]
+pub enum UpdateError {
+    VersionConflict(VersionConflict),
+    DocumentMissing,
+    SourceNotStored,
+    ScriptError(String),
+    PrepareDocumentError(PrepareDocumentError),
+}
+
+
+/// What an update did to the document
+#[derive(Debug)]
+pub struct UpdateResult {
+    /// "created", "updated", "deleted" or "noop"
+    pub result: &'static str,
+
+    /// The document's new version. None if the document doesn't exist and was left that way
+    pub version: Option<DocumentVersion>,
+
+    /// The source the document was written with, so it can be sent on to replicas. None if
+    /// nothing was written, or the document was deleted
+    pub source: Option<Map<String, Json>>,
+}
+
+
+impl UpdateRequest {
+    pub fn parse(json: &Json) -> Result<UpdateRequest, String> {
+        let body = json.as_object().ok_or("Request body must be an object")?;
+        let mut request = UpdateRequest {
+            doc: None,
+            upsert: None,
+            script: None,
+            doc_as_upsert: false,
+            scripted_upsert: false,
+            detect_noop: true,
+        };
+
+        for (key, value) in body.iter() {
+            match key.as_ref() {
+                "doc" => request.doc = Some(value.as_object().ok_or("doc must be an object")?.clone()),
+                "upsert" => request.upsert = Some(value.as_object().ok_or("upsert must be an object")?.clone()),
+                "script" => request.script = Some(UpdateScript::parse(value).map_err(|e| format!("Script error: {}", e))?),
+                "doc_as_upsert" => request.doc_as_upsert = value.as_bool().ok_or("doc_as_upsert must be a boolean")?,
+                "scripted_upsert" => request.scripted_upsert = value.as_bool().ok_or("scripted_upsert must be a boolean")?,
+                "detect_noop" => request.detect_noop = value.as_bool().ok_or("detect_noop must be a boolean")?,
+                _ => return Err(format!("Unrecognised key: {:?}", key)),
+            }
+        }
+
+        match (request.doc.is_some(), request.script.is_some()) {
+            (true, true) => Err("doc and script can't be used together".to_string()),
+            (false, false) => Err("doc or script is required".to_string()),
+            _ => Ok(request),
+        }
+    }
+
+    /// Updates a document
+    ///
+    /// The document is read, changed and then written back with a condition on the version
+    /// that was read. If another write gets in first, the update is retried up to
+    /// `retry_on_conflict` times. Conflicts with `write_condition` are never retried.
+    ///
+    /// The document is looked for in the shard that `routing` leads to, or its key if that's None
+    pub fn run(&self, index: &Index, index_metadata: &IndexMetadata, mapping: &Mapping, doc_key: &str, routing: Option<&str>, write_condition: Option<&WriteCondition>, retry_on_conflict: u64) -> Result<UpdateResult, UpdateError> {
+        let source_field = index_metadata.get_field_mapping("_source").and_then(|field_mapping| field_mapping.index_ref);
+        let store = index.shard_for_routing(routing.unwrap_or(doc_key));
+        let mut attempts = 0;
+
+        loop {
+            let index_reader = store.reader();
+            let current = index_reader.get_document_by_key(doc_key);
+
+            if let Some(write_condition) = write_condition {
+                write_condition.check(current.map(|(_, version)| version)).map_err(UpdateError::VersionConflict)?;
+            }
+
+            // Work out the new source
+            let (mut source, condition) = match current {
+                Some((doc_id, version)) => {
+                    let source = source_field.and_then(|field_ref| read_source_field(&index_reader, field_ref, doc_id)).ok_or(UpdateError::SourceNotStored)?;
+                    (source, WriteCondition::SeqNo { seq_no: version.seq_no, primary_term: version.primary_term })
+                }
+                None => {
+                    let source = match (self.upsert.as_ref(), self.doc.as_ref()) {
+                        (Some(upsert), _) => upsert.clone(),
+                        (None, Some(doc)) if self.doc_as_upsert => doc.clone(),
+                        _ => return Err(UpdateError::DocumentMissing),
+                    };
+
+                    (source, WriteCondition::NotExists)
+                }
+            };
+
+            let run_script = current.is_some() || self.scripted_upsert;
+            let operation = match (self.doc.as_ref(), self.script.as_ref()) {
+                (Some(doc), _) if current.is_some() => {
+                    if merge(&mut source, doc) || !self.detect_noop {
+                        UpdateOperation::Index
+                    } else {
+                        UpdateOperation::Noop
+                    }
+                }
+                (None, Some(script)) if run_script => script.run(&mut source).map_err(UpdateError::ScriptError)?,
+                _ => UpdateOperation::Index,
+            };
+
+            // Write the document back
+            let written = match (operation, current) {
+                (UpdateOperation::Index, _) => {
+                    let doc = (DocumentSource { key: doc_key, data: &source }).prepare(mapping).map_err(UpdateError::PrepareDocumentError)?;
+
+                    match store.insert_or_update_document_with_condition(&doc, Some(&condition)) {
+                        Ok(write) => Ok((if write.created { "created" } else { "updated" }, write.version)),
+                        Err(DocumentInsertError::VersionConflict(conflict)) => Err(conflict),
+                        Err(e) => panic!("document insert failed: {:?}", e),
+                    }
+                }
+                (UpdateOperation::Delete, Some(_)) => {
+                    match store.remove_document_by_key_with_condition(doc_key, Some(&condition)) {
+                        Ok(Some(version)) => Ok(("deleted", version)),
+                        Ok(None) => unreachable!("the write condition requires the document to exist"),
+                        Err(DocumentDeleteError::VersionConflict(conflict)) => Err(conflict),
+                        Err(e) => panic!("document delete failed: {:?}", e),
+                    }
+                }
+                (UpdateOperation::Noop, Some((_, version))) => return Ok(UpdateResult { result: "noop", version: Some(version), source: None }),
+
+                // There's nothing to delete or leave as it is if the document doesn't exist
+                (_, None) => return Ok(UpdateResult { result: "noop", version: None, source: None }),
+            };
+
+            match written {
+                Ok((result, version)) => {
+                    return Ok(UpdateResult {
+                        result: result,
+                        version: Some(version),
+                        source: if result == "deleted" { None } else { Some(source) },
+                    });
+                }
+                Err(conflict) => {
+                    if attempts >= retry_on_conflict || write_condition.is_some() {
+                        return Err(UpdateError::VersionConflict(conflict));
+                    }
+
+                    attempts += 1;
+                }
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Value(Json),
+    Identifier(String),
+    Symbol(char),
+
+    /// `=`, or a compound assignment such as `+=`
+    Assign(Option<Operator>),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Value(Json),
+
+    /// A value in the source, given by its path
+    Source(Vec<String>),
+
+    Negate(Box<Expression>),
+    BinaryOp(Operator, Box<Expression>, Box<Expression>),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Assign(Vec<String>, Option<Operator>, Expression),
+    Remove(Vec<String>),
+    SetOperation(UpdateOperation),
+}
+
+
+fn symbol_operator(c: char) -> Option<Operator> {
+    match c {
+        '+' => Some(Operator::Add),
+        '-' => Some(Operator::Subtract),
+        '*' => Some(Operator::Multiply),
+        '/' => Some(Operator::Divide),
+        '%' => Some(Operator::Remainder),
+        _ => None,
+    }
+}
+
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while position < chars.len() {
+        let c = chars[position];
+
+        if c.is_whitespace() {
+            position += 1;
+        } else if c.is_ascii_digit() {
+            let start = position;
+            while position < chars.len() && (chars[position].is_ascii_digit() || chars[position] == '.') {
+                position += 1;
+            }
+
+            let number = chars[start..position].iter().collect::<String>();
+            let value = match number.parse::<i64>() {
+                Ok(number) => Json::from(number),
+                Err(_) => match number.parse::<f64>().ok().and_then(Number::from_f64) {
+                    Some(number) => Json::Number(number),
+                    None => return Err(format!("invalid number {:?}", number)),
+                },
+            };
+
+            tokens.push(Token::Value(value));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = position;
+            while position < chars.len() && (chars[position].is_alphanumeric() || chars[position] == '_') {
+                position += 1;
+            }
+
+            let identifier = chars[start..position].iter().collect::<String>();
+            tokens.push(match identifier.as_ref() {
+                "true" => Token::Value(Json::Bool(true)),
+                "false" => Token::Value(Json::Bool(false)),
+                "null" => Token::Value(Json::Null),
+                _ => Token::Identifier(identifier),
+            });
+        } else if c == '\'' || c == '"' {
+            let start = position + 1;
+            position = start;
+            while position < chars.len() && chars[position] != c {
+                position += 1;
+            }
+
+            if position >= chars.len() {
+                return Err("unterminated string".to_string());
+            }
+
+            tokens.push(Token::Value(Json::String(chars[start..position].iter().collect())));
+            position += 1;
+        } else if c == '=' {
+            tokens.push(Token::Assign(None));
+            position += 1;
+        } else if let Some(operator) = symbol_operator(c).filter(|_| chars.get(position + 1) == Some(&'=')) {
+            tokens.push(Token::Assign(Some(operator)));
+            position += 2;
+        } else if "+-*/%()[].;".contains(c) {
+            tokens.push(Token::Symbol(c));
+            position += 1;
+        } else {
+            return Err(format!("unexpected character {:?}", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+
+struct ScriptParser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    params: Option<&'a Map<String, Json>>,
+}
+
+
+impl<'a> ScriptParser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => Err("unexpected end of script".to_string()),
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(c) if c == symbol => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}", symbol, token)),
+        }
+    }
+
+    fn expect_string(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Value(Json::String(string)) => Ok(string),
+            token => Err(format!("expected a string, found {:?}", token)),
+        }
+    }
+
+    /// Parses a name given either as `.name` or `['name']`
+    fn parse_member(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Symbol('.') => {
+                match self.next()? {
+                    Token::Identifier(name) => Ok(name),
+                    token => Err(format!("expected a name, found {:?}", token)),
+                }
+            }
+            Token::Symbol('[') => {
+                let name = self.expect_string()?;
+                self.expect_symbol(']')?;
+                Ok(name)
+            }
+            token => Err(format!("expected '.' or '[', found {:?}", token)),
+        }
+    }
+
+    fn is_member_next(&self) -> bool {
+        match self.peek() {
+            Some(&Token::Symbol('.')) | Some(&Token::Symbol('[')) => true,
+            _ => false,
+        }
+    }
+
+    /// Parses the path after `ctx._source`
+    fn parse_source_path(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        while self.is_member_next() {
+            path.push(self.parse_member()?);
+
+            // Stop at a method call, so the caller can handle it
+            if self.peek() == Some(&Token::Symbol('(')) {
+                break;
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, String> {
+        match self.next()? {
+            Token::Identifier(ref name) if name == "ctx" => {}
+            token => return Err(format!("expected ctx, found {:?}", token)),
+        }
+
+        match self.parse_member()?.as_ref() {
+            "_source" => {}
+            "op" => {
+                match self.next()? {
+                    Token::Assign(None) => {}
+                    token => return Err(format!("expected '=', found {:?}", token)),
+                }
+
+                return match self.expect_string()?.as_ref() {
+                    "index" => Ok(Statement::SetOperation(UpdateOperation::Index)),
+                    "none" | "noop" => Ok(Statement::SetOperation(UpdateOperation::Noop)),
+                    "delete" => Ok(Statement::SetOperation(UpdateOperation::Delete)),
+                    operation => Err(format!("unsupported operation {:?}", operation)),
+                };
+            }
+            member => return Err(format!("unsupported ctx member {:?}", member)),
+        }
+
+        let mut path = self.parse_source_path()?;
+
+        if self.peek() == Some(&Token::Symbol('(')) {
+            if path.pop().as_deref() != Some("remove") {
+                return Err("only the remove method is supported".to_string());
+            }
+
+            self.expect_symbol('(')?;
+            path.push(self.expect_string()?);
+            self.expect_symbol(')')?;
+            return Ok(Statement::Remove(path));
+        }
+
+        if path.is_empty() {
+            return Err("ctx._source can't be replaced".to_string());
+        }
+
+        match self.next()? {
+            Token::Assign(operator) => Ok(Statement::Assign(path, operator, self.parse_expression()?)),
+            token => Err(format!("expected an assignment, found {:?}", token)),
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_term()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Symbol('+')) => Operator::Add,
+                Some(&Token::Symbol('-')) => Operator::Subtract,
+                _ => return Ok(expression),
+            };
+
+            self.position += 1;
+            expression = Expression::BinaryOp(operator, Box::new(expression), Box::new(self.parse_term()?));
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_unary()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Symbol('*')) => Operator::Multiply,
+                Some(&Token::Symbol('/')) => Operator::Divide,
+                Some(&Token::Symbol('%')) => Operator::Remainder,
+                _ => return Ok(expression),
+            };
+
+            self.position += 1;
+            expression = Expression::BinaryOp(operator, Box::new(expression), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        if self.peek() == Some(&Token::Symbol('-')) {
+            self.position += 1;
+            return Ok(Expression::Negate(Box::new(self.parse_unary()?)));
+        }
+
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, String> {
+        match self.next()? {
+            Token::Value(value) => Ok(Expression::Value(value)),
+            Token::Symbol('(') => {
+                let expression = self.parse_expression()?;
+                self.expect_symbol(')')?;
+                Ok(expression)
+            }
+            Token::Identifier(ref name) if name == "params" => {
+                let name = self.parse_member()?;
+
+                match self.params.and_then(|params| params.get(&name)) {
+                    Some(value) => Ok(Expression::Value(value.clone())),
+                    None => Err(format!("param {:?} is missing", name)),
+                }
+            }
+            Token::Identifier(ref name) if name == "ctx" => {
+                if self.parse_member()? != "_source" {
+                    return Err("only ctx._source can be read".to_string());
+                }
+
+                Ok(Expression::Source(self.parse_source_path()?))
+            }
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+}
+
+
+fn get_path<'a>(source: &'a Map<String, Json>, path: &[String]) -> Option<&'a Json> {
+    let (last, parents) = path.split_last()?;
+    let mut object = source;
+
+    for key in parents.iter() {
+        object = object.get(key)?.as_object()?;
+    }
+
+    object.get(last)
+}
+
+
+/// Finds the object holding the last key of a path, creating any objects that are missing
+fn get_parent_mut<'a>(source: &'a mut Map<String, Json>, path: &[String]) -> Result<&'a mut Map<String, Json>, String> {
+    let mut object = source;
+
+    for key in path[..path.len() - 1].iter() {
+        object = match *object.entry(key.clone()).or_insert_with(|| Json::Object(Map::new())) {
+            Json::Object(ref mut child) => child,
+            _ => return Err(format!("{:?} is not an object", key)),
+        };
+    }
+
+    Ok(object)
+}
+
+
+fn value_to_string(value: &Json) -> String {
+    match *value {
+        Json::String(ref string) => string.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+
+fn apply(operator: Operator, left: &Json, right: &Json) -> Result<Json, String> {
+    if operator == Operator::Add && (left.is_string() || right.is_string()) {
+        return Ok(Json::String(value_to_string(left) + &value_to_string(right)));
+    }
+
+    // Integers stay as integers, as they do in Painless
+    if let (Some(a), Some(b)) = (left.as_i64(), right.as_i64()) {
+        let result = match operator {
+            Operator::Add => a.checked_add(b),
+            Operator::Subtract => a.checked_sub(b),
+            Operator::Multiply => a.checked_mul(b),
+            Operator::Divide => a.checked_div(b),
+            Operator::Remainder => a.checked_rem(b),
+        };
+
+        return result.map(Json::from).ok_or_else(|| format!("{:?} of {} and {} overflowed or divided by zero", operator, a, b));
+    }
+
+    let (a, b) = match (left.as_f64(), right.as_f64()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(format!("can't apply {:?} to {} and {}", operator, left, right)),
+    };
+
+    let result = match operator {
+        Operator::Add => a + b,
+        Operator::Subtract => a - b,
+        Operator::Multiply => a * b,
+        Operator::Divide => a / b,
+        Operator::Remainder => a % b,
+    };
+
+    Number::from_f64(result).map(Json::Number).ok_or_else(|| format!("{:?} of {} and {} isn't a finite number", operator, a, b))
+}
+
+
+impl Expression {
+    fn evaluate(&self, source: &Map<String, Json>) -> Result<Json, String> {
+        match *self {
+            Expression::Value(ref value) => Ok(value.clone()),
+            Expression::Source(ref path) => Ok(get_path(source, path).cloned().unwrap_or(Json::Null)),
+            Expression::Negate(ref expression) => apply(Operator::Subtract, &Json::from(0), &expression.evaluate(source)?),
+            Expression::BinaryOp(operator, ref left, ref right) => apply(operator, &left.evaluate(source)?, &right.evaluate(source)?),
+        }
+    }
+}
+
+
+/// A parsed update script, with its params filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateScript {
+    statements: Vec<Statement>,
+}
+
+
+impl UpdateScript {
+    fn parse_source(source: &str, params: Option<&Map<String, Json>>) -> Result<UpdateScript, String> {
+        let mut parser = ScriptParser {
+            tokens: tokenize(source)?,
+            position: 0,
+            params: params,
+        };
+
+        let mut statements = Vec::new();
+        while parser.peek().is_some() {
+            if parser.peek() == Some(&Token::Symbol(';')) {
+                parser.position += 1;
+                continue;
+            }
+
+            statements.push(parser.parse_statement()?);
+
+            match parser.peek() {
+                Some(&Token::Symbol(';')) | None => {}
+                Some(token) => return Err(format!("unexpected {:?}", token)),
+            }
+        }
+
+        Ok(UpdateScript {
+            statements: statements,
+        })
+    }
+
+    /// Parses a script, which can either be a string of source or an object with a "source"
+    /// and optional "params"
+    pub fn parse(json: &Json) -> Result<UpdateScript, String> {
+        match *json {
+            Json::String(ref source) => UpdateScript::parse_source(source, None),
+            Json::Object(ref object) => {
+                let mut source = None;
+                let mut params = None;
+
+                for (key, value) in object.iter() {
+                    match key.as_ref() {
+                        "source" | "inline" => source = Some(value.as_str().ok_or("source must be a string")?),
+                        "params" => params = Some(value.as_object().ok_or("params must be an object")?),
+                        "lang" => {
+                            if value.as_str() != Some("painless") {
+                                return Err("only painless scripts are supported".to_string());
+                            }
+                        }
+                        _ => return Err(format!("unrecognised key {:?}", key)),
+                    }
+                }
+
+                UpdateScript::parse_source(source.ok_or("script has no source")?, params)
+            }
+            _ => Err("script must be a string or an object".to_string()),
+        }
+    }
+
+    /// Runs the script on a document's source, returning what should be done with the document
+    pub fn run(&self, source: &mut Map<String, Json>) -> Result<UpdateOperation, String> {
+        let mut operation = UpdateOperation::Index;
+
+        for statement in self.statements.iter() {
+            match *statement {
+                Statement::Assign(ref path, operator, ref expression) => {
+                    let mut value = expression.evaluate(source)?;
+                    if let Some(operator) = operator {
+                        value = apply(operator, get_path(source, path).unwrap_or(&Json::Null), &value)?;
+                    }
+
+                    get_parent_mut(source, path)?.insert(path[path.len() - 1].clone(), value);
+                }
+                Statement::Remove(ref path) => {
+                    get_parent_mut(source, path)?.remove(&path[path.len() - 1]);
+                }
+                Statement::SetOperation(new_operation) => operation = new_operation,
+            }
+        }
+
+        Ok(operation)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value as Json};
+
+    use super::{merge, UpdateScript, UpdateOperation};
+
+    fn object(json: Json) -> Map<String, Json> {
+        json.as_object().unwrap().clone()
+    }
+
+    fn run(script: Json, source: Json) -> Result<(UpdateOperation, Json), String> {
+        let mut source = object(source);
+        let operation = UpdateScript::parse(&script)?.run(&mut source)?;
+        Ok((operation, Json::Object(source)))
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut source = object(json!({"title": "foo", "meta": {"views": 1, "tags": ["a"]}}));
+
+        assert!(merge(&mut source, &object(json!({"meta": {"tags": ["b"], "draft": false}}))));
+        assert_eq!(Json::Object(source.clone()), json!({"title": "foo", "meta": {"views": 1, "tags": ["b"], "draft": false}}));
+
+        // Nothing changes if the values are already set
+        assert!(!merge(&mut source, &object(json!({"title": "foo", "meta": {"views": 1}}))));
+
+        // Objects replace other values
+        assert!(merge(&mut source, &object(json!({"title": {"en": "foo"}}))));
+        assert_eq!(source["title"], json!({"en": "foo"}));
+    }
+
+    #[test]
+    fn test_script() {
+        assert_eq!(run(json!({
+            "source": "ctx._source.views += params.count; ctx._source['title'] = 'Re: ' + ctx._source.title;",
+            "params": {"count": 4},
+        }), json!({"views": 1, "title": "foo"})), Ok((UpdateOperation::Index, json!({"views": 5, "title": "Re: foo"}))));
+
+        // Missing objects are created, numbers only become floats when they need to
+        assert_eq!(run(json!("ctx._source.stats.ratio = 3 / 2 * 1.0; ctx._source.stats.half = 3 * 0.5"), json!({})),
+                   Ok((UpdateOperation::Index, json!({"stats": {"ratio": 1.0, "half": 1.5}}))));
+
+        assert_eq!(run(json!("ctx._source.remove('draft')"), json!({"title": "foo", "draft": true})), Ok((UpdateOperation::Index, json!({"title": "foo"}))));
+        assert_eq!(run(json!("ctx.op = 'delete'"), json!({})), Ok((UpdateOperation::Delete, json!({}))));
+        assert_eq!(run(json!("ctx.op = 'none'"), json!({})), Ok((UpdateOperation::Noop, json!({}))));
+    }
+
+    #[test]
+    fn test_script_errors() {
+        assert_eq!(run(json!("ctx._source.views += 1"), json!({})), Err("can't apply Add to null and 1".to_string()));
+        assert_eq!(run(json!("ctx._source.views = 1 / 0"), json!({})), Err("Divide of 1 and 0 overflowed or divided by zero".to_string()));
+        assert_eq!(run(json!("ctx._source.views = params.count"), json!({})), Err("param \"count\" is missing".to_string()));
+        assert_eq!(run(json!("ctx._source = 1"), json!({})), Err("ctx._source can't be replaced".to_string()));
+        assert_eq!(run(json!("ctx._source.a = 1 ctx"), json!({})), Err("unexpected Identifier(\"ctx\")".to_string()));
+    }
+}